@@ -0,0 +1,88 @@
+/*
+ * WalDB C API, matching waldb_ffi.rs. Build the shared library with:
+ *   cargo rustc --release --lib --features capi --crate-type cdylib
+ *
+ * Strings are UTF-8 pointer and length pairs, not NUL-terminated (only error messages
+ * are). The library keeps no pointer the caller passes in. The caller owns two kinds of
+ * thing the library hands out: a handle, freed with waldb_close, and a value from
+ * waldb_get_owned, freed with waldb_free. Everything else, scan entries and error
+ * messages included, stays the library's.
+ *
+ * A handle may be used from several threads at once, but not closed while a call on it is
+ * running. Functions returning int32_t return one of the WALDB_ codes below; on
+ * WALDB_ERROR, waldb_last_error_message(handle, ...) on the same thread says why.
+ */
+
+#ifndef WALDB_H
+#define WALDB_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define WALDB_OK 0
+#define WALDB_NOT_FOUND 1
+#define WALDB_BUFFER_TOO_SMALL 2
+#define WALDB_ERROR (-1)
+
+typedef struct WaldbHandle WaldbHandle;
+
+/* Called once per entry by waldb_scan_prefix. key and value are only valid during the call.
+ * Return 0 to go on, anything else to stop. */
+typedef int32_t (*WaldbScanCallback)(void *context, const char *key, size_t key_len,
+                                     const char *value, size_t value_len);
+
+/* Open the store in the directory at path, creating it if needed. NULL on failure, with the
+ * reason in waldb_last_error_message(NULL, ...). */
+WaldbHandle *waldb_open(const char *path, size_t path_len);
+
+/* Close a store, syncing what it buffered. NULL is ignored. */
+void waldb_close(WaldbHandle *handle);
+
+/* Set key to value. The write's seq goes to seq_out unless it is NULL. */
+int32_t waldb_set(const WaldbHandle *handle, const char *key, size_t key_len,
+                  const char *value, size_t value_len, uint64_t *seq_out);
+
+/* Copy key's value into buf. value_len gets its length; if that is more than buf_len,
+ * nothing is copied and WALDB_BUFFER_TOO_SMALL is returned. WALDB_NOT_FOUND if the key has
+ * no value. */
+int32_t waldb_get(const WaldbHandle *handle, const char *key, size_t key_len,
+                  char *buf, size_t buf_len, size_t *value_len);
+
+/* Return key's value in a buffer the library allocates, to be freed with
+ * waldb_free(*value_out, *value_len). WALDB_NOT_FOUND, with *value_out NULL, if the key has
+ * no value. */
+int32_t waldb_get_owned(const WaldbHandle *handle, const char *key, size_t key_len,
+                        char **value_out, size_t *value_len);
+
+/* Free a value from waldb_get_owned with the length it came with. NULL is ignored. */
+void waldb_free(char *value, size_t len);
+
+/* Delete key's value, leaving what is under it. */
+int32_t waldb_delete(const WaldbHandle *handle, const char *key, size_t key_len, uint64_t *seq_out);
+
+/* Delete everything under prefix/ with one subtree tombstone. */
+int32_t waldb_delete_subtree(const WaldbHandle *handle, const char *prefix, size_t prefix_len,
+                             uint64_t *seq_out);
+
+/* Call callback for each live entry under prefix in key order, at most limit of them
+ * (SIZE_MAX for all), until it returns nonzero. The scan reads the store as it was when it
+ * started, so the callback may write to it. The number of calls goes to count_out unless it
+ * is NULL. */
+int32_t waldb_scan_prefix(const WaldbHandle *handle, const char *prefix, size_t prefix_len,
+                          size_t limit, WaldbScanCallback callback, void *context, size_t *count_out);
+
+/* The NUL-terminated message of the last WALDB_ERROR on handle on this thread (NULL for the
+ * last failed waldb_open), its length without the NUL in len_out unless that is NULL. NULL
+ * if there is none. Valid until the next failure on the same handle on this thread, or its
+ * close. */
+const char *waldb_last_error_message(const WaldbHandle *handle, size_t *len_out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WALDB_H */
@@ -13,43 +13,201 @@ declare module 'waldb' {
     /**
      * Open a WalDB database (async)
      * @param path Path to the database directory
+     * @param options.strictReads Reject gets and prefix scans of paths under a scalar with a
+     *   WalDbTreeSemanticsError naming the scalar, instead of finding nothing
      */
-    static open(path: string): Promise<WalDB>;
+    static open(path: string, options?: { strictReads?: boolean }): Promise<WalDB>;
+    
+    /**
+     * Open a WalDB database held entirely in memory (async). It behaves like one on disk
+     * until it is dropped, which loses everything; meant for test suites
+     * @param options.strictReads As for open()
+     */
+    static openInMemory(options?: { strictReads?: boolean }): Promise<WalDB>;
+    
+    /**
+     * The key for path segments, each one level whatever it holds: '%' and '/' in a segment
+     * are written %25 and %2F. Throws WalDbInvalidKeyError for [] or ['']
+     */
+    static pathKey(segments: string[]): string;
+    
+    /**
+     * The segments pathKey made a key from
+     */
+    static pathSegments(key: string): string[];
     
     /**
      * Set a value at the given path (async)
      * @param key The path to set
      * @param value The value to set (objects will be flattened)
-     * @param force Whether to force overwrite parent nodes
+     * @param force Legacy flag, same as options.replaceSubtree
+     * @param options.durable Resolve only once the write is fsynced to the WAL
+     * @param options.ifVersion Write an object only if getVersion(key) still returns this
+     * @returns The write's seq, durable once seqInfo().durableSeq reaches it; with ifVersion,
+     *   whether the write went ahead
      */
-    set(key: string, value: any, force?: boolean): Promise<void>;
+    set(key: string, value: object, options: SetOptions & { ifVersion: number }): Promise<boolean>;
+    set(key: string, value: any, force?: boolean, options?: SetOptions): Promise<number>;
+    set(key: string, value: any, options?: SetOptions): Promise<number>;
+    
+    /**
+     * Get the version of a key and everything under it (async): the seq of the newest write
+     * there, deletes included, or 0 if there was none
+     * @param key The object's path; '' for the whole store
+     */
+    getVersion(key: string): Promise<number>;
     
     /**
      * Get entries with decoded values (default) (async)
      * Returns array of [key, value] pairs with decoded values
      * @param key The path to get
+     * @param options.timeoutMs Reject with WalDbCancelledError if the scan runs longer
+     * @param options.maxBlockReads Reject with WalDbBudgetExceededError rather than read more
+     *   segment blocks from disk looking up key itself
      */
-    get(key: string): Promise<Array<[string, any]>>;
+    get(key: string, options?: GetOptions): Promise<Array<[string, any]>>;
     
     /**
      * Get raw entries with prefixed strings (async)
      * Returns array of [key, value] pairs with raw prefixed values like "n:42", "s:hello"
      * @param key The path to get
+     * @param options.timeoutMs Reject with WalDbCancelledError if the scan runs longer
+     * @param options.maxBlockReads As for get(); the error's candidate stays raw
+     */
+    getRaw(key: string, options?: GetOptions): Promise<Array<[string, string]>>;
+    
+    /**
+     * Get a value's stored bytes with no decoding, or null (async)
+     * set() values keep their type prefix; setBytes() values come back exactly as written
+     * @param key The path to get
+     */
+    getBytes(key: string): Promise<Buffer | null>;
+    
+    /**
+     * Store bytes exactly as given, including invalid UTF-8, with no type prefix (async)
+     * @param key The path to set
+     * @param data The bytes to store
+     */
+    setBytes(key: string, data: Buffer | ArrayBuffer | Uint8Array): Promise<void>;
+    
+    /**
+     * Get raw entries with each value as a Buffer of its stored bytes (async)
+     * @param key The path to get
+     * @param options.timeoutMs Reject with WalDbCancelledError if the scan runs longer
+     * @param options.maxBlockReads As for get(); the error's candidate stays raw
      */
-    getRaw(key: string): Promise<Array<[string, string]>>;
+    getBytesEntries(key: string, options?: GetOptions): Promise<Array<[string, Buffer]>>;
     
     /**
      * Get value or subtree as reconstructed object (async)
      * Returns the value or reconstructed object, null if not found
      * @param key The path to get
+     * @param options Read options, as for get()
      */
-    getObject(key: string): Promise<any>;
+    getObject(key: string, options?: GetOptions): Promise<any>;
     
     /**
      * Delete a path and all its children (async)
      * @param key The path to delete
+     * @returns The delete's seq
+     */
+    delete(key: string): Promise<number>;
+    
+    /**
+     * set() at WalDB.pathKey(segments), so ids holding '/' stay one level
+     */
+    setPath(segments: string[], value: any, options?: SetOptions): Promise<number>;
+    
+    /**
+     * getObject() at WalDB.pathKey(segments)
+     */
+    getPath(segments: string[]): Promise<any>;
+    
+    /**
+     * delete() at WalDB.pathKey(segments)
+     */
+    deletePath(segments: string[]): Promise<number>;
+    
+    /**
+     * scan() under WalDB.pathKey(segments), [] for everything, with each key as its segments
+     */
+    scanPath(segments: string[], options?: { batchSize?: number }): AsyncGenerator<[string[], any]>;
+    
+    /**
+     * Check if a path holds a scalar value, without fetching it (async)
+     * Objects (paths with only children) resolve to false
+     * @param key The path to check
+     */
+    has(key: string): Promise<boolean>;
+    
+    /**
+     * Check whether a path may hold a value, from memory alone (sync)
+     * Never reads a block; false means get() would find nothing, true may be wrong
+     * @param key The path to check
+     */
+    mayContain(key: string): boolean;
+    
+    /**
+     * Atomically add to an integer counter and return the new value (async)
+     * Counters are stored as plain decimal strings, not set()'s typed encoding
+     * @param key The counter's path (missing counts as 0)
+     * @param delta Safe integer to add, default 1
+     */
+    increment(key: string, delta?: number): Promise<number>;
+    
+    /**
+     * Add a primitive value to the queue under prefix and resolve to its key (async)
+     * Keys are prefix, '/', and a 20-digit number that grows with each push, across reopens
+     * @param prefix The queue's path
+     * @param value The value, encoded as set() would
+     */
+    push(prefix: string, value: any): Promise<string>;
+    
+    /**
+     * Remove and resolve to the entry with the smallest key under prefix, or null (async)
+     * Concurrent callers each get a different entry
+     * @param prefix The queue's path
+     */
+    popMin(prefix: string): Promise<[string, any] | null>;
+    
+    /**
+     * The entry popMin() would return, left in place, or null (async)
+     * @param prefix The queue's path
+     */
+    peekMin(prefix: string): Promise<[string, any] | null>;
+    
+    /**
+     * Count the entries under prefix, stopping at limit if given (async)
+     * @param prefix The queue's path
+     * @param limit The most to count
+     */
+    queueLength(prefix: string, limit?: number): Promise<number>;
+    
+    /**
+     * Atomically move a value and its subtree to a new path, replacing the destination (async)
+     * Resolves to how many keys moved, 0 if nothing was at from
+     * @param from The path to move
+     * @param to The destination path, not inside from
+     */
+    moveSubtree(from: string, to: string): Promise<number>;
+    
+    /**
+     * Copy a value and its subtree to a new path in chunked atomic batches (async)
+     * Resolves to how many keys were copied
+     * @param from The path to copy
+     * @param to The destination path, neither inside from nor containing it
+     * @param mode Keys already at the destination: 'fail' (default) rejects before writing,
+     *   'skip' keeps them, 'replace' clears the destination first
+     */
+    copySubtree(from: string, to: string, mode?: 'fail' | 'skip' | 'replace'): Promise<number>;
+    
+    /**
+     * Get a subtree as a JSON string without decoding values (async)
+     * Values are the raw stored strings, including this binding's type prefixes
+     * @param key The path to export
+     * @param options.detectTypes Emit canonical numbers, booleans and null unquoted
      */
-    delete(key: string): Promise<void>;
+    getJson(key: string, options?: { detectTypes?: boolean }): Promise<string | null>;
     
     /**
      * Check if a path exists (async)
@@ -60,8 +218,9 @@ declare module 'waldb' {
     /**
      * Get all values matching a pattern with * and ? wildcards (async)
      * @param pattern Pattern with wildcards
+     * @param options.timeoutMs Reject with WalDbCancelledError if the scan runs longer
      */
-    getPattern(pattern: string): Promise<Record<string, any>>;
+    getPattern(pattern: string, options?: ScanOptions): Promise<Record<string, any>>;
     
     /**
      * Get all values in a range (async)
@@ -76,6 +235,16 @@ declare module 'waldb' {
      */
     getPatternEntries(pattern: string): Promise<Array<[string, any]>>;
     
+    /**
+     * Get the matches of a pattern grouped by entity (async): a key's first groupDepth path
+     * segments are its group, the rest its field. Matches with nothing below their group are
+     * left out
+     * @param pattern Pattern with * and ? wildcards
+     * @param groupDepth Path segments in a group key, at least 1
+     * @param options.limit Most groups to return
+     */
+    getPatternGrouped(pattern: string, groupDepth: number, options?: { limit?: number }): Promise<Array<[string, Array<[string, any]>]>>;
+    
     /**
      * Get all key-value pairs in a range as entries array (async)
      * @param start Start key (inclusive)
@@ -83,11 +252,97 @@ declare module 'waldb' {
      */
     getRangeEntries(start: string, end: string): Promise<Array<[string, any]>>;
     
+    /**
+     * Stream the key-value pairs under a prefix in key order, batchSize per native call
+     * Sees the database as of the call; breaking out of the loop closes the cursor
+     * @param prefix Key prefix ('' for everything)
+     * @param options.batchSize Pairs fetched per native call (default 100)
+     */
+    scan(prefix: string, options?: { batchSize?: number }): AsyncGenerator<[string, any]>;
+    
+    /**
+     * Apply a batch of ops atomically, in order (async)
+     * A put with an object value replaces that subtree like set()
+     * @param ops The ops to apply
+     */
+    writeBatch(ops: BatchOp[]): Promise<void>;
+    
     /**
      * Flush pending writes to disk (async)
      */
     flush(): Promise<void>;
     
+    /**
+     * Delete everything in the database with one tombstone, resolving with its seq.
+     * delete('') rejects instead. On a namespace handle, empties just the namespace
+     */
+    clearAll(): Promise<number>;
+    
+    /**
+     * Run the storage self-test for a liveness probe; healthy is false if any check failed
+     * or was skipped for running past maxDurationMs
+     * @param options.writeProbe Also write, read back and delete a probe key (default false)
+     * @param options.maxDurationMs Time budget for all checks (default 1000)
+     */
+    healthCheck(options?: { writeProbe?: boolean; maxDurationMs?: number }): Promise<{
+      healthy: boolean;
+      checks: Array<{
+        name: 'memtable_read' | 'write_probe' | 'wal_buffer' | 'wal_flusher' | 'block_cache';
+        status: 'passed' | 'failed' | 'skipped';
+        durationMs: number;
+        detail?: string;
+      }>;
+    }>;
+    
+    /**
+     * Read segment blocks into the block cache ahead of traffic; complete is false if
+     * maxBytes ran out first. Prefixes are full keys, also on a namespace handle.
+     * @param options.levels Only segments on these levels, 3 being archives (default all)
+     * @param options.prefixes Only blocks that may hold keys under these (default whole segments)
+     * @param options.maxBytes Byte budget (default the block cache's size)
+     */
+    warmup(options?: { levels?: number[]; prefixes?: string[]; maxBytes?: number }): Promise<{
+      bytesLoaded: number;
+      blocksLoaded: number;
+      bytesPinned: number;
+      complete: boolean;
+    }>;
+    
+    /**
+     * Cap the key and value bytes under a prefix of the whole database, also on a namespace
+     * handle. Writes past it reject with WalDbQuotaExceededError; deletes always go through
+     * @param prefix Prefix to cap, as a full key
+     * @param maxBytes The cap, counting each key's full path and its value
+     */
+    setQuota(prefix: string, maxBytes: number): Promise<void>;
+    
+    /**
+     * Take a prefix's quota away, resolving false if it had none
+     */
+    removeQuota(prefix: string): Promise<boolean>;
+    
+    /**
+     * A prefix's quota and the bytes under it, or null if it has no quota
+     */
+    getQuotaUsage(prefix: string): Promise<{ usedBytes: number; maxBytes: number } | null>;
+    
+    /**
+     * Check every value set under a prefix with a built-in validator until close. Writes with
+     * a value it refuses reject with WalDbValidationFailedError and write nothing. Covers the
+     * whole database's own keys, not those written through a namespace handle
+     * @param prefix Prefix to check, as a full key
+     * @param name maxLength counts length as JS does; regex takes a pattern without flags,
+     * tested like RegExp.test against the value as a string
+     */
+    setValidator(prefix: string, name: 'maxLength', max: number): Promise<void>;
+    setValidator(prefix: string, name: 'jsonParseable'): Promise<void>;
+    setValidator(prefix: string, name: 'regex', pattern: string): Promise<void>;
+    
+    /**
+     * Take a prefix's validator away, resolving false if it had none
+     */
+    removeValidator(prefix: string): Promise<boolean>;
+    
     /**
      * Store a file with automatic compression and deduplication
      * @param path Path where to store the file
@@ -125,10 +380,13 @@ declare module 'waldb' {
       pattern: string;
       filters?: Array<{
         field: string;
-        op: '==' | '!=' | '>' | '<' | '>=' | '<=';
-        value: string | number | boolean;
+        op: '==' | '!=' | '>' | '<' | '>=' | '<=' | 'in' | 'contains';
+        value: string | number | boolean | Array<string | number>;
+        type?: 'auto' | 'string' | 'number';
       }>;
       limit?: number;
+      orderBy?: 'keyAsc' | 'keyDesc';
+      timeoutMs?: number;
     }): Promise<Array<Array<[string, any]>>>;
     
     /**
@@ -139,10 +397,12 @@ declare module 'waldb' {
       pattern: string;
       filters?: Array<{
         field: string;
-        op: '==' | '!=' | '>' | '<' | '>=' | '<=';
-        value: string | number | boolean;
+        op: '==' | '!=' | '>' | '<' | '>=' | '<=' | 'in' | 'contains';
+        value: string | number | boolean | Array<string | number>;
+        type?: 'auto' | 'string' | 'number';
       }>;
       limit?: number;
+      orderBy?: 'keyAsc' | 'keyDesc';
     }): Promise<Array<any>>;
     
     /**
@@ -178,8 +438,9 @@ declare module 'waldb' {
      * Set a vector embedding
      * @param path Path to store the vector
      * @param vector Array of numbers representing the vector
+     * @param options.normalize Scale to unit length before storing
      */
-    setVector(path: string, vector: number[]): Promise<void>;
+    setVector(path: string, vector: number[], options?: { normalize?: boolean }): Promise<void>;
     
     /**
      * Get a vector embedding
@@ -195,18 +456,22 @@ declare module 'waldb' {
       pattern: string;
       filters?: Array<{
         field: string;
-        op: '==' | '!=' | '>' | '<' | '>=' | '<=';
-        value: string | number | boolean;
+        op: '==' | '!=' | '>' | '<' | '>=' | '<=' | 'in' | 'contains';
+        value: string | number | boolean | Array<string | number>;
+        type?: 'auto' | 'string' | 'number';
       }>;
       vector?: {
         query: number[];
         field: string;
         threshold?: number;
+        metric?: 'cosine' | 'dot' | 'euclidean';
       };
       text?: {
         query: string;
         fields: string[];
         caseSensitive?: boolean;
+        anchor?: 'prefix' | 'contains' | 'exact';
+        fieldWeights?: Record<string, number>;
       };
       scoring?: {
         vector?: number;
@@ -214,6 +479,8 @@ declare module 'waldb' {
         filter?: number;
       };
       limit?: number;
+      orderBy?: 'keyAsc' | 'keyDesc';
+      timeoutMs?: number;
     }): Promise<Array<Array<[string, any]>>>;
     
     /**
@@ -224,18 +491,22 @@ declare module 'waldb' {
       pattern: string;
       filters?: Array<{
         field: string;
-        op: '==' | '!=' | '>' | '<' | '>=' | '<=';
-        value: string | number | boolean;
+        op: '==' | '!=' | '>' | '<' | '>=' | '<=' | 'in' | 'contains';
+        value: string | number | boolean | Array<string | number>;
+        type?: 'auto' | 'string' | 'number';
       }>;
       vector?: {
         query: number[];
         field: string;
         threshold?: number;
+        metric?: 'cosine' | 'dot' | 'euclidean';
       };
       text?: {
         query: string;
         fields: string[];
         caseSensitive?: boolean;
+        anchor?: 'prefix' | 'contains' | 'exact';
+        fieldWeights?: Record<string, number>;
       };
       scoring?: {
         vector?: number;
@@ -250,6 +521,26 @@ declare module 'waldb' {
      * @param path The path to reference
      */
     ref(path: string): Reference;
+    
+    /**
+     * Get a handle scoped to a namespace that shares this database's WAL and cache
+     * Keys are relative to the namespace; delete('') on the handle removes all of it
+     * @param name Namespace name
+     */
+    namespace(name: string): WalDB;
+    
+    /**
+     * Stop every scan in flight on this database and its namespaces
+     * Their promises reject with WalDbCancelledError; later scans run normally
+     */
+    cancelAll(): void;
+    
+    /**
+     * Where writes stand, for caches invalidated on write acknowledgment: a crash keeps every
+     * write up to durableSeq, and flushedSeq is where segments end. Both are at most seq.
+     * Covers the whole database, also on a namespace handle.
+     */
+    seqInfo(): { seq: number; durableSeq: number; flushedSeq: number };
   }
 
   /**
@@ -287,6 +578,79 @@ declare module 'waldb' {
     remove(): Promise<void>;
   }
 
+  /**
+   * Value of `err.name` on errors rejected by native operations
+   */
+  export type WalDBErrorName =
+    | 'WalDbIoError'
+    | 'WalDbTreeSemanticsError'
+    | 'WalDbCorruptionError'
+    | 'WalDbLockedError'
+    | 'WalDbInvalidKeyError'
+    | 'WalDbNewerFormatError'
+    | 'WalDbNotAnIntegerError'
+    | 'WalDbCancelledError'
+    | 'WalDbCollationMismatchError'
+    | 'WalDbSealedError'
+    | 'WalDbStorageFullError'
+    | 'WalDbReadOnlyError'
+    | 'WalDbHistoryUnavailableError'
+    | 'WalDbAlreadyExistsError'
+    | 'WalDbReplicaError'
+    | 'WalDbInvalidUtf8Error'
+    | 'WalDbSnapshotCorruptError'
+    | 'WalDbQuotaExceededError'
+    | 'WalDbValidationFailedError'
+    | 'WalDbBudgetExceededError'
+    | 'WalDbPanicError'
+    | 'WalDbPoisonedError';
+
+  /**
+   * Options for scanning reads
+   */
+  export interface ScanOptions {
+    timeoutMs?: number;
+  }
+
+  /**
+   * Options for get() and the reads built on it
+   */
+  export interface GetOptions extends ScanOptions {
+    maxBlockReads?: number;
+  }
+
+  /**
+   * Fields a WalDbBudgetExceededError carries besides name and message
+   */
+  export interface BudgetExceededError extends Error {
+    name: 'WalDbBudgetExceededError';
+    segmentsProbed: number;
+    blockReads: number;
+    /** The value found before giving up, null if none; a segment not yet probed may hold a newer one */
+    candidate: any;
+  }
+
+  /**
+   * Options for set()
+   */
+  export interface SetOptions {
+    durable?: boolean;
+    /** Delete the key's existing children first (what the old force flag did) */
+    replaceSubtree?: boolean;
+    /** Delete a scalar value at the parent path instead of rejecting the write */
+    convertScalarParent?: boolean;
+    /** Write an object only if getVersion(key) still returns this */
+    ifVersion?: number;
+  }
+
+  /**
+   * One op in a writeBatch() call
+   */
+  export type BatchOp =
+    | { type: 'put'; key: string; value: any }
+    | { type: 'delete'; key: string }
+    | { type: 'deleteSubtree'; key: string };
+
   // Default export
   const _default: typeof WalDB;
   export default _default;
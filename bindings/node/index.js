@@ -13,40 +13,111 @@ class WalDB {
     /**
      * Open a database (async)
      * @param {string} path - Path to the database directory
+     * @param {Object} [options] - Open options
+     * @param {boolean} [options.strictReads=false] - Reject gets and prefix scans of paths under
+     *   a scalar with a WalDbTreeSemanticsError naming the scalar, instead of finding nothing
      * @returns {Promise<WalDB>} Database instance
      */
-    static async open(path) {
+    static async open(path, options = {}) {
         // Real async from Rust - returns a boxed store
-        const store = await native.open(path);
+        const store = await native.open(path, options.strictReads === true);
         return new WalDB(store);
     }
     
+    /**
+     * Open a database held entirely in memory (async). It behaves like one on disk until it
+     * is dropped, which loses everything; meant for test suites that open many databases.
+     * @param {Object} [options] - Open options, as for open()
+     * @param {boolean} [options.strictReads=false] - As for open()
+     * @returns {Promise<WalDB>} Database instance
+     */
+    static async openInMemory(options = {}) {
+        const store = await native.openInMemory(options.strictReads === true);
+        return new WalDB(store);
+    }
+    
+    /**
+     * The key for an array of path segments, each one level whatever it holds: '%' and '/'
+     * in a segment are written %25 and %2F, so ids from users can't add levels or collide
+     * @param {string[]} segments - The path's segments, like ['users', id, 'name']
+     * @returns {string} The key, like 'users/a%2Fb/name'
+     */
+    static pathKey(segments) {
+        return native.pathKey(segments);
+    }
+    
+    /**
+     * The segments pathKey made a key from
+     * @param {string} key - A key, as scans return it
+     * @returns {string[]} Its segments, unescaped
+     */
+    static pathSegments(key) {
+        return native.pathSegments(key);
+    }
+    
     /**
      * Set a value at the given path (async)
      * @param {string} key - The path to set
      * @param {any} value - The value to set (objects will be flattened)
-     * @param {boolean} [force=false] - Whether to force overwrite parent nodes
+     * @param {boolean} [force=false] - Legacy flag, same as options.replaceSubtree
+     * @param {Object} [options] - Write options, may also be passed in place of force
+     * @param {boolean} [options.durable=false] - Resolve only once the write is fsynced to the WAL
+     * @param {boolean} [options.replaceSubtree=false] - Delete the key's existing children first
+     * @param {boolean} [options.convertScalarParent=false] - Delete a scalar value at the parent
+     *   path instead of rejecting the write
+     * @param {number} [options.ifVersion] - Write an object only if getVersion(key) still
+     *   returns this; two writers that read the same version can't both succeed
+     * @returns {Promise<number|boolean>} The write's seq, durable once seqInfo().durableSeq
+     *   reaches it; with ifVersion, whether the write went ahead
      */
-    async set(key, value, force = false) {
+    async set(key, value, force = false, options = {}) {
+        if (typeof force === 'object' && force !== null) {
+            options = force;
+            force = false;
+        }
+        const durable = options.durable === true;
+        const setOptions = {
+            replaceSubtree: force === true || options.replaceSubtree === true,
+            convertScalarParent: options.convertScalarParent === true,
+        };
         if (typeof value === 'object' && value !== null) {
+            if (setOptions.convertScalarParent) {
+                throw new TypeError('convertScalarParent is only supported for primitive values');
+            }
             // Flatten objects AND arrays into multiple key-value pairs
             const flattened = this._flattenObject(key, value);
             const replaceAt = key === '' ? null : key;
-            return native.setMany(this._store, flattened, replaceAt);
+            return native.setMany(this._store, flattened, replaceAt, durable, options.ifVersion);
         } else {
+            if (options.ifVersion !== undefined) {
+                throw new TypeError('ifVersion is only supported for object values');
+            }
             // Encode primitives only
             const encodedValue = this._encodeValue(value);
-            return native.set(this._store, key, encodedValue, force);
+            return native.set(this._store, key, encodedValue, setOptions, durable);
         }
     }
     
     /**
      * Get entries with decoded values (default) (async)
      * @param {string} key - The path to get
+     * @param {Object} [options] - Read options
+     * @param {number} [options.timeoutMs] - Reject with WalDbCancelledError if the scan runs longer
+     * @param {number} [options.maxBlockReads] - Reject with WalDbBudgetExceededError rather than
+     *   read more segment blocks from disk looking up key itself; cached blocks are free. The
+     *   error's candidate is the decoded value found before giving up, or null.
      * @returns {Promise<Array<[string, any]>>} Array of [key, value] pairs with decoded values
      */
-    async get(key) {
-        const entries = await native.getEntries(this._store, key);
+    async get(key, options = {}) {
+        let entries;
+        try {
+            entries = await native.getEntries(this._store, key, options.timeoutMs, options.maxBlockReads);
+        } catch (error) {
+            if (error.name === 'WalDbBudgetExceededError' && error.candidate !== null) {
+                error.candidate = WalDB._decodeValue(error.candidate);
+            }
+            throw error;
+        }
         // Decode values in the entries
         return entries.map(([k, v]) => [k, WalDB._decodeValue(v)]);
     }
@@ -54,19 +125,56 @@ class WalDB {
     /**
      * Get raw entries with prefixed strings (async)
      * @param {string} key - The path to get
+     * @param {Object} [options] - Read options
+     * @param {number} [options.timeoutMs] - Reject with WalDbCancelledError if the scan runs longer
+     * @param {number} [options.maxBlockReads] - As for get(); the error's candidate stays raw
      * @returns {Promise<Array<[string, string]>>} Array of [key, value] pairs with raw prefixed values
      */
-    async getRaw(key) {
-        return native.getEntries(this._store, key);
+    async getRaw(key, options = {}) {
+        return native.getEntries(this._store, key, options.timeoutMs, options.maxBlockReads);
+    }
+    
+    /**
+     * Get a value's stored bytes, with no decoding (async)
+     * Values written by set() keep their type prefix ("s:hello"); setBytes() values come
+     * back exactly as written, including bytes that aren't valid UTF-8
+     * @param {string} key - The path to get
+     * @returns {Promise<Buffer|null>} The bytes, or null if the key holds no value
+     */
+    async getBytes(key) {
+        return native.getRaw(this._store, key);
+    }
+    
+    /**
+     * Store bytes exactly as given, with no type prefix (async)
+     * @param {string} key - The path to set
+     * @param {Buffer|ArrayBuffer|Uint8Array} data - The bytes to store
+     */
+    async setBytes(key, data) {
+        const buffer = Buffer.isBuffer(data) ? data : Buffer.from(data);
+        return native.setRaw(this._store, key, buffer);
+    }
+    
+    /**
+     * Get raw entries like getRaw(), with each value as a Buffer of its stored bytes (async)
+     * @param {string} key - The path to get
+     * @param {Object} [options] - Read options
+     * @param {number} [options.timeoutMs] - Reject with WalDbCancelledError if the scan runs longer
+     * @param {number} [options.maxBlockReads] - As for get(); the error's candidate stays raw
+     * @returns {Promise<Array<[string, Buffer]>>} Array of [key, bytes] pairs
+     */
+    async getBytesEntries(key, options = {}) {
+        return native.getEntriesRaw(this._store, key, options.timeoutMs, options.maxBlockReads);
     }
     
     /**
      * Get value or subtree as reconstructed object (async)
      * @param {string} key - The path to get
+     * @param {Object} [options] - Read options, as for get()
      * @returns {Promise<any>} The value or reconstructed object, null if not found
      */
-    async getObject(key) {
-        const entries = await this.get(key);
+    async getObject(key, options = {}) {
+        const entries = await this.get(key, options);
         
         if (entries.length === 0) {
             return null;
@@ -84,11 +192,75 @@ class WalDB {
     /**
      * Delete a path and all its children (async)
      * @param {string} key - The path to delete
+     * @returns {Promise<number>} The delete's seq
      */
     async delete(key) {
         return native.delete(this._store, key);
     }
     
+    /**
+     * set() at the key pathKey() makes of segments (async)
+     * @param {string[]} segments - The path's segments, each one level
+     * @param {any} value - The value to set (objects will be flattened under the path)
+     * @param {Object} [options] - As for set()
+     */
+    async setPath(segments, value, options = {}) {
+        return this.set(native.pathKey(segments), value, options);
+    }
+    
+    /**
+     * getObject() at the key pathKey() makes of segments (async)
+     * @param {string[]} segments - The path's segments, each one level
+     */
+    async getPath(segments) {
+        return this.getObject(native.pathKey(segments));
+    }
+    
+    /**
+     * delete() at the key pathKey() makes of segments (async)
+     * @param {string[]} segments - The path's segments, each one level
+     */
+    async deletePath(segments) {
+        return this.delete(native.pathKey(segments));
+    }
+    
+    /**
+     * scan() of everything under a path, each key split back into its segments (async iterator)
+     * @param {string[]} segments - The path's segments; [] for everything
+     * @param {Object} [options] - As for scan()
+     * @returns {AsyncGenerator<[string[], any]>} [segments, value] pairs
+     */
+    async *scanPath(segments, options = {}) {
+        const prefix = segments.length === 0 ? '' : native.pathKey(segments) + '/';
+        for await (const [key, value] of this.scan(prefix, options)) {
+            yield [native.pathSegments(key), value];
+        }
+    }
+    
+    /**
+     * Apply a batch of ops atomically under one WAL frame (async)
+     * Ops are applied in order; a put with an object value replaces that subtree like set()
+     * @param {Array<{type: 'put'|'delete'|'deleteSubtree', key: string, value?: any}>} ops - The ops to apply
+     */
+    async writeBatch(ops) {
+        const encoded = [];
+        for (const op of ops) {
+            if (op.type === 'put' && typeof op.value === 'object' && op.value !== null) {
+                encoded.push({ type: 'delete', key: op.key });
+                encoded.push({ type: 'deleteSubtree', key: op.key });
+                const flattened = this._flattenObject(op.key, op.value);
+                for (const [key, value] of Object.entries(flattened)) {
+                    encoded.push({ type: 'put', key, value });
+                }
+            } else if (op.type === 'put') {
+                encoded.push({ type: 'put', key: op.key, value: this._encodeValue(op.value) });
+            } else {
+                encoded.push({ type: op.type, key: op.key });
+            }
+        }
+        return native.writeBatch(this._store, encoded);
+    }
+    
     /**
      * Flush memtable to disk (async)
      */
@@ -96,13 +268,103 @@ class WalDB {
         return native.flush(this._store);
     }
     
+    /**
+     * Delete everything in the database with one tombstone (async). delete('') refuses
+     * to, so an empty variable can't do it by accident. On a namespace handle, empties
+     * just the namespace
+     * @returns {Promise<number>} The seq of the delete
+     */
+    async clearAll() {
+        return native.clearAll(this._store);
+    }
+    
+    /**
+     * Run the storage self-test, for liveness probes (async). Covers the whole database,
+     * also when called on a namespace handle
+     * @param {Object} [options] - Health check options
+     * @param {boolean} [options.writeProbe=false] - Also write, read back and delete a probe key
+     * @param {number} [options.maxDurationMs=1000] - Checks not started by then are skipped
+     * @returns {Promise<Object>} { healthy, checks: [{ name, status, durationMs, detail? }] }
+     */
+    async healthCheck(options = {}) {
+        return native.healthCheck(this._store, !!options.writeProbe, options.maxDurationMs);
+    }
+    
+    /**
+     * Read segment blocks into the block cache ahead of traffic, as after a deploy. Covers the
+     * whole database, also when called on a namespace handle, so prefixes are full keys
+     * @param {Object} [options] - Warmup options
+     * @param {number[]} [options.levels] - Only segments on these levels (0-2, 3 for archives); all by default
+     * @param {string[]} [options.prefixes] - Only blocks that may hold keys under these; whole segments by default
+     * @param {number} [options.maxBytes] - Stop before loading more; the block cache's size by default
+     * @returns {Promise<Object>} { bytesLoaded, blocksLoaded, bytesPinned, complete }
+     */
+    async warmup(options = {}) {
+        const { levels = [], prefixes = [], maxBytes } = options;
+        return native.warmup(this._store, levels, prefixes.map(String), maxBytes);
+    }
+    
+    /**
+     * Cap the key and value bytes under a prefix (async). Writes that would take it past the
+     * cap reject with WalDbQuotaExceededError; deletes always go through. Covers the whole
+     * database, also when called on a namespace handle, so prefixes are full keys
+     * @param {string} prefix - Prefix to cap, like 'tenants/acme'
+     * @param {number} maxBytes - The cap, counting each key's full path and its value
+     */
+    async setQuota(prefix, maxBytes) {
+        return native.setQuota(this._store, prefix, maxBytes);
+    }
+    
+    /**
+     * Take a prefix's quota away (async)
+     * @param {string} prefix - Prefix the quota was set on
+     * @returns {Promise<boolean>} False if it had none
+     */
+    async removeQuota(prefix) {
+        return native.removeQuota(this._store, prefix);
+    }
+    
+    /**
+     * A prefix's quota and the bytes under it (async)
+     * @param {string} prefix - Prefix the quota was set on
+     * @returns {Promise<Object|null>} { usedBytes, maxBytes }, or null if it has no quota
+     */
+    async getQuotaUsage(prefix) {
+        return native.getQuotaUsage(this._store, prefix);
+    }
+    
+    /**
+     * Check every value set under a prefix with a built-in validator (async). A set
+     * or writeBatch with a value it refuses rejects with WalDbValidationFailedError and writes
+     * nothing. Nested prefixes each check their own, most specific first. Validators last
+     * until close and cover the whole database's own keys, not those written through a
+     * namespace handle, so prefixes are full keys
+     * @param {string} prefix - Prefix to check, like 'users'
+     * @param {string} name - 'maxLength', 'jsonParseable' or 'regex'
+     * @param {number|string} [option] - The length for maxLength, the pattern for regex
+     */
+    async setValidator(prefix, name, option) {
+        return native.setValidator(this._store, prefix, name, option);
+    }
+    
+    /**
+     * Take a prefix's validator away (async)
+     * @param {string} prefix - Prefix the validator was set on
+     * @returns {Promise<boolean>} False if it had none
+     */
+    async removeValidator(prefix) {
+        return native.removeValidator(this._store, prefix);
+    }
+    
     /**
      * Get all values matching a pattern (async)
      * @param {string} pattern - Pattern with * and ? wildcards
+     * @param {Object} [options] - Read options
+     * @param {number} [options.timeoutMs] - Reject with WalDbCancelledError if the scan runs longer
      * @returns {Promise<Object>} Object with matching key-value pairs
      */
-    async getPattern(pattern) {
-        const results = await native.getPattern(this._store, pattern);
+    async getPattern(pattern, options = {}) {
+        const results = await native.getPattern(this._store, pattern, options.timeoutMs);
         if (results && typeof results === 'object') {
             return this._decodeObject(results);
         }
@@ -134,6 +396,35 @@ class WalDB {
         return entries.map(([key, value]) => [key, WalDB._decodeValue(value)]);
     }
     
+    /**
+     * Get the version of a key and everything under it (async): the seq of the newest write
+     * there, deletes included, or 0 if there was none. Pass it back as set()'s ifVersion
+     * to update an object only if no one else changed it since it was read
+     * @param {string} key - The object's path; '' for the whole store
+     * @returns {Promise<number>} The subtree's version
+     */
+    async getVersion(key) {
+        return native.getVersion(this._store, key);
+    }
+    
+    /**
+     * Get the matches of a pattern grouped by entity (async). A key's first groupDepth path
+     * segments are its group and the rest its field, so with 2, users/1/name and users/1/age
+     * come back together under users/1. Matches with nothing below their group are left out
+     * @param {string} pattern - Pattern with * and ? wildcards
+     * @param {number} groupDepth - Path segments in a group key, at least 1
+     * @param {Object} [options] - Read options
+     * @param {number} [options.limit] - Most groups to return; every group's fields come whole
+     * @returns {Promise<Array<[string, Array<[string, any]>]>>} [groupKey, [[field, value], ...]]
+     *   pairs in key order, fields relative to the group
+     */
+    async getPatternGrouped(pattern, groupDepth, options = {}) {
+        const groups = await native.getPatternGrouped(this._store, pattern, groupDepth, options.limit);
+        return groups.map(([groupKey, fields]) =>
+            [groupKey, fields.map(([field, value]) => [field, WalDB._decodeValue(value)])]
+        );
+    }
+    
     /**
      * Get all key-value pairs in a range as entries array (async)
      * @param {string} start - Start key (inclusive)
@@ -146,6 +437,145 @@ class WalDB {
         return entries.map(([key, value]) => [key, WalDB._decodeValue(value)]);
     }
     
+    /**
+     * Stream the key-value pairs under a prefix in key order (async iterator), fetching
+     * batchSize at a time. Reads the database as of the call, whatever is written while
+     * iterating; leaving the loop early closes the cursor
+     * @param {string} prefix - Key prefix ('' for everything)
+     * @param {Object} [options] - Scan options
+     * @param {number} [options.batchSize=100] - Pairs fetched per native call
+     * @returns {AsyncGenerator<[string, any]>} [key, value] pairs
+     */
+    async *scan(prefix, options = {}) {
+        const cursor = native.scanOpen(this._store, prefix, options.batchSize || 100);
+        try {
+            for (;;) {
+                const batch = await native.scanNext(cursor);
+                if (batch.length === 0) {
+                    return;
+                }
+                for (const [key, value] of batch) {
+                    yield [key, WalDB._decodeValue(value)];
+                }
+            }
+        } finally {
+            native.scanClose(cursor);
+        }
+    }
+    
+    /**
+     * Check if a key holds a scalar value, without fetching it (async)
+     * @param {string} key - The path to check
+     * @returns {Promise<boolean>} True if get() would find a value at exactly this key
+     */
+    async has(key) {
+        return native.has(this._store, key);
+    }
+    
+    /**
+     * Check whether a key may hold a value, from memory alone (sync): never reads a block
+     * @param {string} key - The path to check
+     * @returns {boolean} False if get() would find nothing; true may be a false positive
+     */
+    mayContain(key) {
+        return native.mayContain(this._store, key);
+    }
+    
+    /**
+     * Atomically add to an integer counter and return the new value (async)
+     * Counters are stored as plain decimal strings rather than this binding's "n:" encoding,
+     * so read them back with increment(key, 0) or getRaw(); a value written by set() is not
+     * an integer to the store and rejects with WalDbNotAnIntegerError
+     * @param {string} key - The counter's path (missing counts as 0)
+     * @param {number} delta - Safe integer to add, default 1
+     * @returns {Promise<number>} The value after the increment
+     */
+    async increment(key, delta = 1) {
+        return native.increment(this._store, key, delta);
+    }
+    
+    /**
+     * Add a value to the queue under prefix and return its key (async)
+     * Keys are prefix, '/', and a 20-digit number past every key an earlier push gave the
+     * queue, before or after a reopen, so popMin() takes values in the order they were pushed
+     * @param {string} prefix - The queue's path
+     * @param {any} value - A primitive value, encoded as set() would
+     * @returns {Promise<string>} The key the value was written at
+     */
+    async push(prefix, value) {
+        return native.push(this._store, prefix, this._encodeValue(value));
+    }
+    
+    /**
+     * Remove and return the entry with the smallest key under prefix (async)
+     * Any number of callers can pop at once; each gets a different entry
+     * @param {string} prefix - The queue's path
+     * @returns {Promise<[string, any]|null>} [key, value], or null if the queue is empty
+     */
+    async popMin(prefix) {
+        const entry = await native.popMin(this._store, prefix);
+        return entry && [entry[0], WalDB._decodeValue(entry[1])];
+    }
+    
+    /**
+     * The entry popMin() would return, left in place (async)
+     * @param {string} prefix - The queue's path
+     * @returns {Promise<[string, any]|null>} [key, value], or null if the queue is empty
+     */
+    async peekMin(prefix) {
+        const entry = await native.peekMin(this._store, prefix);
+        return entry && [entry[0], WalDB._decodeValue(entry[1])];
+    }
+    
+    /**
+     * Count the entries under prefix (async)
+     * @param {string} prefix - The queue's path
+     * @param {number} [limit] - Stop counting here; counts everything if omitted
+     * @returns {Promise<number>} How many entries the queue holds, at most limit
+     */
+    async queueLength(prefix, limit) {
+        return native.queueLength(this._store, prefix, limit);
+    }
+    
+    /**
+     * Atomically move a value and everything under it to a new path (async)
+     * Whatever was at the destination is replaced; readers and crash recovery see the
+     * subtree at exactly one of the two paths
+     * @param {string} from - The path to move
+     * @param {string} to - The destination path, not inside from
+     * @returns {Promise<number>} How many keys moved, 0 if nothing was at from
+     */
+    async moveSubtree(from, to) {
+        return native.moveSubtree(this._store, from, to);
+    }
+    
+    /**
+     * Copy a value and everything under it to a new path, without reading it out (async)
+     * Written in chunks of 10,000 keys, each chunk atomic
+     * @param {string} from - The path to copy
+     * @param {string} to - The destination path, neither inside from nor containing it
+     * @param {string} mode - For keys already at the destination: 'fail' (default) rejects
+     *   with WalDbAlreadyExistsError before writing anything, 'skip' keeps them, 'replace'
+     *   clears the destination first
+     * @returns {Promise<number>} How many keys were copied
+     */
+    async copySubtree(from, to, mode = 'fail') {
+        return native.copySubtree(this._store, from, to, mode);
+    }
+    
+    /**
+     * Get a subtree as a JSON string, built natively without decoding values (async)
+     * Values are the raw stored strings, so ones written by this binding keep their
+     * type prefix ("n:42", "s:hello"); use getObject() for decoded values
+     * @param {string} key - The path to export
+     * @param {Object} options - Optional settings
+     * @param {boolean} options.detectTypes - Emit canonical numbers, booleans and null unquoted
+     * @returns {Promise<string|null>} JSON text, or null if nothing is stored under the key
+     */
+    async getJson(key, options = {}) {
+        return native.getJson(this._store, key, options.detectTypes === true);
+    }
+    
     /**
      * Check if a key exists (async)
      * @param {string} key - The path to check
@@ -165,6 +595,35 @@ class WalDB {
         return new Reference(this, path);
     }
     
+    /**
+     * Get a handle to a namespace: a keyspace of its own in this database, sharing its
+     * WAL and cache. Every method works the same on the handle, with keys relative to
+     * the namespace, and delete('') on it removes the whole namespace
+     * @param {string} name - Namespace name (any string)
+     * @returns {WalDB} Database instance scoped to the namespace
+     */
+    namespace(name) {
+        return new WalDB(native.namespace(this._store, name));
+    }
+    
+    /**
+     * Stop every scan in flight on this database, its namespaces included; their promises
+     * reject with WalDbCancelledError. Scans started afterwards run normally
+     */
+    cancelAll() {
+        native.cancelAll(this._store);
+    }
+    
+    /**
+     * Where writes stand, for caches invalidated on write acknowledgment. A crash keeps every
+     * write up to durableSeq; flushedSeq is where segments end and WAL replay starts. Covers
+     * the whole database, also when called on a namespace handle
+     * @returns {Object} { seq, durableSeq, flushedSeq }, with durableSeq and flushedSeq at most seq
+     */
+    seqInfo() {
+        return native.seqInfo(this._store);
+    }
+    
     
     // Private helper methods
     
@@ -360,22 +819,29 @@ class WalDB {
      * Search with filters, grouping results by subroot
      * @param {Object} options - Search options
      * @param {string} options.pattern - Pattern to match (e.g., 'users/*')
-     * @param {Array} [options.filters=[]] - Array of filters
-     * @param {number} [options.limit=100] - Maximum results
+     * @param {Array} [options.filters=[]] - Array of { field, op, value, type } filters. op is one of
+     *   ==, !=, >, >=, <, <=, 'in' (value is an array) or 'contains'. type is 'auto' (numbers when both
+     *   sides parse, else strings), 'string' or 'number' (non-numeric fields never match)
+     * @param {number} [options.limit=100] - Maximum results; the scan stops once it has this many
+     * @param {string} [options.orderBy='keyAsc'] - 'keyAsc' or 'keyDesc', which group keys come first
+     * @param {number} [options.timeoutMs] - Reject with WalDbCancelledError if the search runs longer
      * @returns {Promise<Array>} Grouped search results
      */
     async search(options) {
         const { 
             pattern, 
             filters = [], 
-            limit = 100 
+            limit = 100,
+            orderBy,
+            timeoutMs
         } = options;
         
         // Validate and normalize filters
         const normalizedFilters = filters.map(f => ({
             field: f.field,
             op: f.op || '==',
-            value: String(f.value)
+            value: Array.isArray(f.value) ? f.value.map(String) : String(f.value),
+            type: f.type || 'auto'
         }));
         
         // Call native search
@@ -383,7 +849,9 @@ class WalDB {
             this._store, 
             pattern, 
             normalizedFilters, 
-            limit
+            limit,
+            timeoutMs,
+            orderBy
         );
         
         // Decode values in the results
@@ -415,13 +883,15 @@ class WalDB {
      * Set a vector embedding
      * @param {string} path - Path to store the vector
      * @param {number[]} vector - Array of numbers representing the vector
+     * @param {Object} [options] - Write options
+     * @param {boolean} [options.normalize] - Scale to unit length before storing (default: false)
      * @returns {Promise<void>}
      */
-    async setVector(path, vector) {
+    async setVector(path, vector, options = {}) {
         if (!Array.isArray(vector) || !vector.every(v => typeof v === 'number')) {
             throw new Error('Vector must be an array of numbers');
         }
-        return native.setVector(this._store, path, vector);
+        return native.setVector(this._store, path, vector, options.normalize === true);
     }
     
     /**
@@ -441,16 +911,21 @@ class WalDB {
      * @param {Object} [options.vector] - Vector search options
      * @param {number[]} options.vector.query - Query vector for similarity search
      * @param {string} options.vector.field - Field containing vectors to search
-     * @param {number} [options.vector.threshold] - Minimum similarity threshold
+     * @param {number} [options.vector.threshold] - Minimum similarity, or maximum distance for euclidean
+     * @param {string} [options.vector.metric] - 'cosine' (default), 'dot' or 'euclidean'
      * @param {Object} [options.text] - Text search options
      * @param {string} options.text.query - Text query string
      * @param {string[]} options.text.fields - Fields to search in
      * @param {boolean} [options.text.caseSensitive] - Case sensitive search
+     * @param {string} [options.text.anchor] - Token match mode: 'prefix', 'contains' or 'exact'
+     * @param {Object<string, number>} [options.text.fieldWeights] - Relative weight per field (default: 1.0)
      * @param {Object} [options.scoring] - Scoring weights for hybrid search
      * @param {number} [options.scoring.vector] - Weight for vector similarity (default: 1.0)
      * @param {number} [options.scoring.text] - Weight for text relevance (default: 1.0)
      * @param {number} [options.scoring.filter] - Weight for filter matches (default: 1.0)
      * @param {number} [options.limit] - Maximum number of results
+     * @param {string} [options.orderBy='keyAsc'] - 'keyAsc' or 'keyDesc', for searches without vector or text
+     * @param {number} [options.timeoutMs] - Reject with WalDbCancelledError if the search runs longer
      * @returns {Promise<Array<Array<[string, any]>>>} Array of groups, each group is array of [key, value] pairs
      */
     async advancedSearch(options) {
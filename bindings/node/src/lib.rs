@@ -1,18 +1,71 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use neon::prelude::*;
 use neon::types::buffer::TypedArray;
-use std::sync::Arc;
+use neon::event::Channel;
+use neon::types::Deferred;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::path::Path;
+use std::time::Duration;
 
-// Include the core directly (no async wrapper)
-mod store {
-    include!("../../../waldb.rs");
-}
+mod validators;
 
-use store::Store;
+use waldb::{search_group_entries, Access, CancellationToken, CopyMode, HealthCheckOptions, HealthStatus, JsonOptions, Keyspace, RangeIter, ReadBudget, SearchGroup, SetOptions, Store, SubmissionOrder, Ticket, WalDbError, WriteBatch};
 
 // Wrapper struct that can be stored in JavaScript
 struct StoreWrapper {
     store: Arc<Store>,
+    namespace: Option<String>,  // Set on wrappers from namespace(); every export then works inside it
+    cancel: Arc<Mutex<CancellationToken>>,  // Shared by a store's namespaces; cancelAll() trips and replaces it
+    order: Arc<SubmissionOrder>,  // Shared by a store's namespaces too, so calls on either keep their order
+    poisoned: Arc<AtomicBool>,  // Set once a worker thread panicked; every later call rejects. Shared too.
+}
+
+impl StoreWrapper {
+    // Called on the JS thread, which takes the call's place in line
+    fn keyspace(&self, access: Access) -> KeyspaceHandle {
+        KeyspaceHandle {
+            store: Arc::clone(&self.store),
+            namespace: self.namespace.clone(),
+            cancel: self.cancel.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+            ticket: self.order.submit(access),
+        }
+    }
+}
+
+// What an export's worker thread needs to reach the wrapper's store or namespace
+struct KeyspaceHandle {
+    store: Arc<Store>,
+    namespace: Option<String>,
+    cancel: CancellationToken,  // Passed to the scans, which give up once it trips
+    ticket: Ticket,  // Dropped with the handle once the worker thread is done, letting later calls run
+}
+
+impl KeyspaceHandle {
+    fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        if let Some(timeout) = timeout {
+            self.cancel = self.cancel.with_timeout(timeout);
+        }
+        self
+    }
+    
+    // Namespace borrows the store, so it is made on the worker thread for the one call. Waits
+    // first for the calls made before this one that it has to follow.
+    fn with<T>(&self, f: impl FnOnce(&dyn Keyspace) -> T) -> T {
+        self.ticket.wait();
+        match &self.namespace {
+            Some(name) => f(&self.store.namespace(name)),
+            None => f(&*self.store),
+        }
+    }
+    
+    // The whole store, for the exports that work on it also from a namespace. Waits like with.
+    fn with_store<T>(&self, f: impl FnOnce(&Store) -> T) -> T {
+        self.ticket.wait();
+        f(&self.store)
+    }
 }
 
 // Implement Finalize for cleanup when JS object is GC'd
@@ -21,205 +74,1330 @@ impl Finalize for StoreWrapper {}
 // Type alias for convenience
 type BoxedStore = JsBox<StoreWrapper>;
 
+// JS error name for each core error variant so callers can branch on err.name
+fn error_name(e: &WalDbError) -> &'static str {
+    match e {
+        WalDbError::Io(_) => "WalDbIoError",
+        WalDbError::TreeSemantics { .. } => "WalDbTreeSemanticsError",
+        WalDbError::Corruption { .. } => "WalDbCorruptionError",
+        WalDbError::Locked(_) => "WalDbLockedError",
+        WalDbError::InvalidKey { .. } => "WalDbInvalidKeyError",
+        WalDbError::NewerFormat { .. } => "WalDbNewerFormatError",
+        WalDbError::NotAnInteger { .. } => "WalDbNotAnIntegerError",
+        WalDbError::Cancelled => "WalDbCancelledError",
+        WalDbError::CollationMismatch { .. } => "WalDbCollationMismatchError",
+        WalDbError::Sealed { .. } => "WalDbSealedError",
+        WalDbError::StorageFull => "WalDbStorageFullError",
+        WalDbError::ReadOnly => "WalDbReadOnlyError",
+        WalDbError::HistoryUnavailable { .. } => "WalDbHistoryUnavailableError",
+        WalDbError::AlreadyExists { .. } => "WalDbAlreadyExistsError",
+        WalDbError::Replica => "WalDbReplicaError",
+        WalDbError::InvalidUtf8 { .. } => "WalDbInvalidUtf8Error",
+        WalDbError::SnapshotCorrupt { .. } => "WalDbSnapshotCorruptError",
+        WalDbError::QuotaExceeded { .. } => "WalDbQuotaExceededError",
+        WalDbError::ValidationFailed { .. } => "WalDbValidationFailedError",
+        WalDbError::BudgetExceeded { .. } => "WalDbBudgetExceededError",
+    }
+}
+
+// Throw a JS Error carrying the variant name and the stable core message
+fn throw_store_error<'a, C: Context<'a>, T>(cx: &mut C, context: &str, e: WalDbError) -> NeonResult<T> {
+    let err = cx.error(format!("{}: {}", context, e))?;
+    let name = cx.string(error_name(&e));
+    err.set(cx, "name", name)?;
+    // What the get did before giving up, and the stored value it found by then, if any
+    if let WalDbError::BudgetExceeded { segments_probed, block_reads, candidate, .. } = e {
+        let segments_probed = cx.number(segments_probed as f64);
+        let block_reads = cx.number(block_reads as f64);
+        let candidate: Handle<JsValue> = match candidate {
+            Some(candidate) => cx.string(candidate).upcast(),
+            None => cx.null().upcast(),
+        };
+        err.set(cx, "segmentsProbed", segments_probed)?;
+        err.set(cx, "blockReads", block_reads)?;
+        err.set(cx, "candidate", candidate)?;
+    }
+    cx.throw(err)
+}
+
+// Thrown, or rejected with, once a worker thread panicked on the store
+fn poisoned_error<'a, C: Context<'a>>(cx: &mut C) -> JsResult<'a, JsError> {
+    let err = cx.error("store is poisoned after internal error")?;
+    let name = cx.string("WalDbPoisonedError");
+    err.set(cx, "name", name)?;
+    Ok(err)
+}
+
+fn throw_if_poisoned<'a, C: Context<'a>>(cx: &mut C, poisoned: &AtomicBool) -> NeonResult<()> {
+    if poisoned.load(Ordering::SeqCst) {
+        let err = poisoned_error(cx)?;
+        return cx.throw(err);
+    }
+    Ok(())
+}
+
+// A panic payload's message, when it is the &str or String panic! makes
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown panic".to_string(),
+        },
+    }
+}
+
+// Run work on its own thread, then settle the promise with what settle makes of its result.
+// A panic in work rejects the promise with a WalDbPanicError carrying the panic's message,
+// rather than leaving it pending, and poisons the store so later calls reject up front.
+fn spawn_worker<T, V>(
+    channel: Channel,
+    deferred: Deferred,
+    poisoned: Option<Arc<AtomicBool>>,
+    work: impl FnOnce() -> T + Send + 'static,
+    settle: impl FnOnce(TaskContext, T) -> JsResult<V> + Send + 'static,
+) where
+    T: Send + 'static,
+    V: Value,
+{
+    if poisoned.as_ref().is_some_and(|poisoned| poisoned.load(Ordering::SeqCst)) {
+        deferred.settle_with::<JsUndefined, _>(&channel, |mut cx| {
+            let err = poisoned_error(&mut cx)?;
+            cx.throw(err)
+        });
+        return;
+    }
+    
+    std::thread::spawn(move || {
+        match panic::catch_unwind(AssertUnwindSafe(work)) {
+            Ok(result) => {
+                deferred.settle_with(&channel, move |cx| settle(cx, result));
+            }
+            Err(payload) => {
+                if let Some(poisoned) = &poisoned {
+                    poisoned.store(true, Ordering::SeqCst);
+                }
+                let message = panic_message(&*payload);
+                deferred.settle_with::<JsUndefined, _>(&channel, move |mut cx| {
+                    let err = cx.error(format!("Internal error: {}", message))?;
+                    let name = cx.string("WalDbPanicError");
+                    err.set(&mut cx, "name", name)?;
+                    cx.throw(err)
+                });
+            }
+        }
+    });
+}
+
 // Open database - returns promise with boxed store
 fn open(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let path = cx.argument::<JsString>(0)?.value(&mut cx);
+    let strict_reads = cx.argument_opt(1)
+        .and_then(|arg| arg.downcast::<JsBoolean, _>(&mut cx).ok())
+        .map(|b| b.value(&mut cx))
+        .unwrap_or(false);
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    std::thread::spawn(move || {
-        let result = Store::open(Path::new(&path));
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(store) => {
-                    let wrapper = StoreWrapper {
-                        store: Arc::new(store),
-                    };
-                    Ok(cx.boxed(wrapper))
+    spawn_worker(channel, deferred, None, move || {
+        let options = waldb::StoreOptions { strict_reads, ..Default::default() };
+        Store::open_with_options(Path::new(&path), options).map(|(store, _)| store)
+    }, settle_open);
+    
+    Ok(promise)
+}
+
+// Open an in-memory database - no files and no background threads, for test suites
+fn open_in_memory(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let strict_reads = cx.argument_opt(0)
+        .and_then(|arg| arg.downcast::<JsBoolean, _>(&mut cx).ok())
+        .map(|b| b.value(&mut cx))
+        .unwrap_or(false);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    spawn_worker(channel, deferred, None, move || {
+        Store::open_in_memory_with_options(waldb::StoreOptions { strict_reads, ..Default::default() })
+    }, settle_open);
+    
+    Ok(promise)
+}
+
+fn settle_open(mut cx: TaskContext, result: waldb::Result<Store>) -> JsResult<BoxedStore> {
+    match result {
+        Ok(store) => {
+            let wrapper = StoreWrapper {
+                store: Arc::new(store),
+                namespace: None,
+                cancel: Arc::new(Mutex::new(CancellationToken::new())),
+                order: SubmissionOrder::new(),
+                poisoned: Arc::new(AtomicBool::new(false)),
+            };
+            Ok(cx.boxed(wrapper))
+        }
+        Err(e) => throw_store_error(&mut cx, "Failed to open store", e)
+    }
+}
+
+// Namespace handle - a boxed store every other export reads and writes inside the namespace
+fn namespace(mut cx: FunctionContext) -> JsResult<BoxedStore> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let name = cx.argument::<JsString>(1)?.value(&mut cx);
+    
+    let wrapper = StoreWrapper {
+        store: Arc::clone(&store.store),
+        namespace: Some(name),
+        cancel: Arc::clone(&store.cancel),
+        order: Arc::clone(&store.order),
+        poisoned: Arc::clone(&store.poisoned),
+    };
+    Ok(cx.boxed(wrapper))
+}
+
+// Seq info - the assigned, durable and flushed seqs, read without waiting on writes but for seq
+fn seq_info(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    throw_if_poisoned(&mut cx, &store.poisoned)?;
+    // Read before seq, so a write landing meanwhile can't put them past it
+    let (durable, flushed) = (store.store.durable_seq(), store.store.flushed_seq());
+    let seq = match store.store.seq() {
+        Ok(seq) => seq,
+        Err(e) => return throw_store_error(&mut cx, "SeqInfo failed", e),
+    };
+    let obj = cx.empty_object();
+    let seq = cx.number(seq as f64);
+    obj.set(&mut cx, "seq", seq)?;
+    let durable = cx.number(durable as f64);
+    obj.set(&mut cx, "durableSeq", durable)?;
+    let flushed = cx.number(flushed as f64);
+    obj.set(&mut cx, "flushedSeq", flushed)?;
+    Ok(obj)
+}
+
+// Cancel all - scans in flight on this store reject with WalDbCancelledError; later ones run
+fn cancel_all(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let mut current = store.cancel.lock().unwrap_or_else(|e| e.into_inner());
+    std::mem::take(&mut *current).cancel();
+    Ok(cx.undefined())
+}
+
+// A timeoutMs value; anything but a positive finite number means no timeout
+fn timeout_from_ms(ms: f64) -> Option<Duration> {
+    (ms > 0.0 && ms.is_finite()).then(|| Duration::from_secs_f64(ms / 1000.0))
+}
+
+fn timeout_arg(cx: &mut FunctionContext, i: usize) -> Option<Duration> {
+    let ms = cx.argument_opt(i)?.downcast::<JsNumber, _>(cx).ok()?.value(cx);
+    timeout_from_ms(ms)
+}
+
+// A maxBlockReads value: a budget for the get when it is a number, none when undefined
+fn read_budget_arg(cx: &mut FunctionContext, i: usize) -> NeonResult<Option<ReadBudget>> {
+    let Some(value) = cx.argument_opt(i) else { return Ok(None) };
+    if value.is_a::<JsUndefined, _>(cx) || value.is_a::<JsNull, _>(cx) {
+        return Ok(None);
+    }
+    let max = value.downcast_or_throw::<JsNumber, _>(cx)?.value(cx);
+    if !(max >= 0.0 && max.fract() == 0.0) {
+        return cx.throw_type_error("maxBlockReads must be a non-negative integer");
+    }
+    Ok(Some(ReadBudget { max_block_reads: Some(max as u64), ..Default::default() }))
+}
+
+// Get entries - returns array of [key, value] pairs
+fn get_entries(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
+    let timeout = timeout_arg(&mut cx, 2);
+    let budget = read_budget_arg(&mut cx, 3)?;
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Read).with_timeout(timeout);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        prefix_entries(&keyspace, &prefix, budget)
+    }, move |mut cx, result| {
+        match result {
+            Ok(entries) => {
+                let js_array = cx.empty_array();
+                for (i, (k, v)) in entries.into_iter().enumerate() {
+                    let pair = cx.empty_array();
+                    let js_key = cx.string(k);
+                    let js_value = cx.string(v);
+                    pair.set(&mut cx, 0, js_key)?;
+                    pair.set(&mut cx, 1, js_value)?;
+                    js_array.set(&mut cx, i as u32, pair)?;
                 }
-                Err(e) => cx.throw_error(format!("Failed to open store: {}", e))
+                Ok(js_array)
             }
-        });
+            Err(e) => throw_store_error(&mut cx, "Get failed", e)
+        }
     });
     
     Ok(promise)
 }
 
-// Get entries - returns array of [key, value] pairs
-fn get_entries(mut cx: FunctionContext) -> JsResult<JsPromise> {
+// The value at prefix if it holds one, otherwise everything under it. A budget bounds the
+// lookup of prefix itself, not the scan for its children.
+fn prefix_entries(keyspace: &KeyspaceHandle, prefix: &str, budget: Option<ReadBudget>) -> Result<Vec<(String, String)>, WalDbError> {
+    // Check for exact match first. A strict store fails this get for a path under a scalar.
+    let value = match budget {
+        Some(budget) => keyspace.with(|keys| keys.get_with_budget(prefix, budget))?,
+        None => keyspace.with(|keys| keys.get(prefix))?,
+    };
+    if let Some(value) = value {
+        return Ok(vec![(prefix.to_string(), value)]);
+    }
+    
+    // Use get_pattern with wildcard for prefix matching
+    let pattern = if prefix.is_empty() {
+        "*".to_string()
+    } else if prefix.ends_with('/') {
+        format!("{}*", prefix)
+    } else {
+        // No exact match, look for children
+        format!("{}/*", prefix)
+    };
+    
+    keyspace.with(|keys| keys.get_pattern_cancellable(&pattern, &keyspace.cancel))
+}
+
+// ==================== RAW BYTES ====================
+
+// Core values are strings, so bytes that aren't UTF-8 are stored base64-encoded behind this
+// marker, a third larger than the bytes. Valid UTF-8 is stored as is, unless it starts with the
+// marker itself; such values take the encoded form too so reading them back is unambiguous.
+const BINARY_MARKER: &str = "\0bin:";
+
+fn encode_raw(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(text) if !text.starts_with(BINARY_MARKER) => text,
+        Ok(text) => escape_bytes(text.as_bytes()),
+        Err(e) => escape_bytes(e.as_bytes()),
+    }
+}
+
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(BINARY_MARKER.len() + bytes.len().div_ceil(3) * 4);
+    escaped.push_str(BINARY_MARKER);
+    BASE64.encode_string(bytes, &mut escaped);
+    escaped
+}
+
+// Takes the stored string apart without copying unless it was escaped. A marked value that
+// isn't valid base64 wasn't written by encode_raw, so its bytes come back as they are.
+fn decode_raw(value: String) -> Vec<u8> {
+    if let Some(escaped) = value.strip_prefix(BINARY_MARKER) {
+        if let Ok(bytes) = BASE64.decode(escaped) {
+            return bytes;
+        }
+    }
+    value.into_bytes()
+}
+
+fn bytes_to_js<'a, C: Context<'a>>(cx: &mut C, bytes: &[u8]) -> JsResult<'a, JsBuffer> {
+    let mut buffer = cx.buffer(bytes.len())?;
+    buffer.as_mut_slice(cx).copy_from_slice(bytes);
+    Ok(buffer)
+}
+
+// GetRaw - resolves to the value's bytes as a Buffer, or null
+fn get_raw(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let key = cx.argument::<JsString>(1)?.value(&mut cx);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Read);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.get(&key)).map(|value| value.map(decode_raw))
+    }, move |mut cx, result| {
+        match result {
+            Ok(Some(bytes)) => Ok(bytes_to_js(&mut cx, &bytes)?.upcast::<JsValue>()),
+            Ok(None) => Ok(cx.null().upcast()),
+            Err(e) => throw_store_error(&mut cx, "GetRaw failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// GetVersion - the newest seq written at or under key, for set()'s ifVersion option
+fn get_version(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let key = cx.argument::<JsString>(1)?.value(&mut cx);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Read);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.subtree_version(&key))
+    }, move |mut cx, result| {
+        match result {
+            Ok(version) => Ok(cx.number(version as f64)),
+            Err(e) => throw_store_error(&mut cx, "GetVersion failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// SetRaw - stores a Buffer's bytes exactly, with no type prefix
+fn set_raw(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let key = cx.argument::<JsString>(1)?.value(&mut cx);
+    let buffer = cx.argument::<JsBuffer>(2)?;
+    let data = buffer.as_slice(&cx).to_vec();
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        let value = encode_raw(data);
+        keyspace.with(|keys| keys.set(&key, &value, false))
+    }, move |mut cx, result| {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_store_error(&mut cx, "SetRaw failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// GetEntriesRaw - getEntries with every value as a Buffer
+fn get_entries_raw(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let store = cx.argument::<BoxedStore>(0)?;
     let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
+    let timeout = timeout_arg(&mut cx, 2);
+    let budget = read_budget_arg(&mut cx, 3)?;
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Read).with_timeout(timeout);
     
-    std::thread::spawn(move || {
-        // Check for exact match first
-        if let Ok(Some(value)) = store_arc.get(&prefix) {
-            deferred.settle_with(&channel, move |mut cx| {
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        prefix_entries(&keyspace, &prefix, budget)
+            .map(|entries| entries.into_iter().map(|(k, v)| (k, decode_raw(v))).collect::<Vec<_>>())
+    }, move |mut cx, result| {
+        match result {
+            Ok(entries) => {
                 let js_array = cx.empty_array();
-                let pair = cx.empty_array();
-                let js_key = cx.string(prefix);
-                let js_value = cx.string(value);
-                pair.set(&mut cx, 0, js_key)?;
-                pair.set(&mut cx, 1, js_value)?;
-                js_array.set(&mut cx, 0, pair)?;
+                for (i, (k, v)) in entries.into_iter().enumerate() {
+                    let pair = cx.empty_array();
+                    let js_key = cx.string(k);
+                    let js_value = bytes_to_js(&mut cx, &v)?;
+                    pair.set(&mut cx, 0, js_key)?;
+                    pair.set(&mut cx, 1, js_value)?;
+                    js_array.set(&mut cx, i as u32, pair)?;
+                }
                 Ok(js_array)
-            });
-            return;
+            }
+            Err(e) => throw_store_error(&mut cx, "GetEntriesRaw failed", e)
         }
-        
-        // Use get_pattern with wildcard for prefix matching
-        let pattern = if prefix.is_empty() {
-            "*".to_string()
-        } else if prefix.ends_with('/') {
-            format!("{}*", prefix)
-        } else {
-            // No exact match, look for children
-            format!("{}/*", prefix)
+    });
+    
+    Ok(promise)
+}
+
+// Set value - returns promise
+fn bool_option<'a>(cx: &mut FunctionContext<'a>, obj: Handle<'a, JsObject>, name: &str) -> NeonResult<bool> {
+    let value: Handle<JsValue> = obj.get(cx, name)?;
+    Ok(value.downcast::<JsBoolean, _>(cx).map(|b| b.value(cx)).unwrap_or(false))
+}
+
+fn set(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let key = cx.argument::<JsString>(1)?.value(&mut cx);
+    let value = cx.argument::<JsString>(2)?.value(&mut cx);
+    // Either the legacy force boolean, which means replaceSubtree, or { replaceSubtree, convertScalarParent }
+    let mut options = SetOptions::default();
+    if let Some(arg) = cx.argument_opt(3) {
+        if let Ok(force) = arg.downcast::<JsBoolean, _>(&mut cx) {
+            options.replace_subtree = force.value(&mut cx);
+        } else if let Ok(obj) = arg.downcast::<JsObject, _>(&mut cx) {
+            options.replace_subtree = bool_option(&mut cx, obj, "replaceSubtree")?;
+            options.convert_scalar_parent = bool_option(&mut cx, obj, "convertScalarParent")?;
+        }
+    }
+    let durable = cx.argument_opt(4)
+        .and_then(|arg| arg.downcast::<JsBoolean, _>(&mut cx).ok())
+        .map(|b| b.value(&mut cx))
+        .unwrap_or(false);
+    
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        // Durable writes resolve only once the group commit has fsynced them
+        keyspace.with(|keys| keys.set_with_options(&key, &value, options)
+            .and_then(|seq| if durable { keys.wait_durable(seq).map(|_| seq) } else { Ok(seq) }))
+    }, move |mut cx, result| {
+        match result {
+            Ok(seq) => Ok(cx.number(seq as f64)),
+            Err(e) => throw_store_error(&mut cx, "Set failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// Delete - returns promise
+fn delete(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let key = cx.argument::<JsString>(1)?.value(&mut cx);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        // Delete key and subtree for Firebase compat; a subtree with nothing live under it
+        // needs no tombstone
+        keyspace.with(|keys| {
+            let deleted = keys.delete(&key);
+            match keys.has_live_children(&key) {
+                Ok(true) => keys.delete_subtree(&key),
+                Ok(false) => deleted,
+                Err(e) => Err(e),
+            }
+        })
+    }, move |mut cx, result| {
+        match result {
+            Ok(seq) => Ok(cx.number(seq as f64)),
+            Err(e) => throw_store_error(&mut cx, "Delete failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// A setMany value as read off the JS object, before it becomes stored strings
+#[derive(Debug, Clone, PartialEq)]
+enum InputValue {
+    String(String),
+    Number(f64),
+    Bool(bool),
+    Null,  // null or undefined: delete the key
+    Array,
+    Object(Vec<(String, InputValue)>),
+    Unsupported(&'static str),  // What it is, for the error
+}
+
+// What setMany does at one flattened path
+#[derive(Debug, PartialEq)]
+enum EntryOp {
+    Set(String),
+    Delete,
+}
+
+// Objects nest no deeper than this; a cyclic one would otherwise be walked forever
+const MAX_INPUT_DEPTH: usize = 64;
+
+fn input_value<'a>(cx: &mut FunctionContext<'a>, value: Handle<'a, JsValue>, depth: usize) -> NeonResult<InputValue> {
+    if let Ok(s) = value.downcast::<JsString, _>(cx) {
+        return Ok(InputValue::String(s.value(cx)));
+    }
+    if let Ok(n) = value.downcast::<JsNumber, _>(cx) {
+        return Ok(InputValue::Number(n.value(cx)));
+    }
+    if let Ok(b) = value.downcast::<JsBoolean, _>(cx) {
+        return Ok(InputValue::Bool(b.value(cx)));
+    }
+    if value.is_a::<JsNull, _>(cx) || value.is_a::<JsUndefined, _>(cx) {
+        return Ok(InputValue::Null);
+    }
+    if value.is_a::<JsArray, _>(cx) {
+        return Ok(InputValue::Array);
+    }
+    if value.is_a::<JsFunction, _>(cx) {
+        return Ok(InputValue::Unsupported("a function"));
+    }
+    if value.is_a::<JsBuffer, _>(cx) {
+        return Ok(InputValue::Unsupported("a Buffer"));
+    }
+    if value.is_a::<neon::types::JsDate, _>(cx) {
+        return Ok(InputValue::Unsupported("a Date"));
+    }
+    match value.downcast::<JsObject, _>(cx) {
+        Ok(_) if depth >= MAX_INPUT_DEPTH => Ok(InputValue::Unsupported("nested too deep")),
+        Ok(obj) => Ok(InputValue::Object(object_entries(cx, obj, depth + 1)?)),
+        Err(_) => Ok(InputValue::Unsupported("a symbol or bigint")),
+    }
+}
+
+fn object_entries<'a>(cx: &mut FunctionContext<'a>, obj: Handle<'a, JsObject>, depth: usize) -> NeonResult<Vec<(String, InputValue)>> {
+    let keys = obj.get_own_property_names(cx)?;
+    let mut entries = Vec::new();
+    for i in 0..keys.len(cx) {
+        let key: Handle<JsString> = keys.get(cx, i)?;
+        let value: Handle<JsValue> = obj.get(cx, key)?;
+        let key = key.value(cx);
+        entries.push((key, input_value(cx, value, depth)?));
+    }
+    Ok(entries)
+}
+
+// The digits JS's String(n) gives: shortest round-trip, exponent form outside [1e-6, 1e21),
+// and no trailing .0, so 1 and 1.0 store the same
+fn format_number(n: f64) -> Option<String> {
+    if !n.is_finite() {
+        return None;
+    }
+    if n == 0.0 {
+        return Some("0".to_string());  // -0 too, as in JS
+    }
+    let magnitude = n.abs();
+    if (1e-6..1e21).contains(&magnitude) {
+        return Some(format!("{}", n));
+    }
+    let formatted = format!("{:e}", n);
+    Some(match formatted.split_once('e') {
+        Some((digits, exp)) if !exp.starts_with('-') => format!("{}e+{}", digits, exp),
+        _ => formatted,
+    })
+}
+
+// Nested objects become child paths; strings, numbers and booleans are stored as text, and
+// null deletes. Errors name the path of the value that can't be stored.
+fn flatten_entries(entries: Vec<(String, InputValue)>) -> Result<Vec<(String, EntryOp)>, String> {
+    fn walk(path: String, value: InputValue, out: &mut Vec<(String, EntryOp)>) -> Result<(), String> {
+        let op = match value {
+            InputValue::String(s) => EntryOp::Set(s),
+            InputValue::Number(n) => match format_number(n) {
+                Some(text) => EntryOp::Set(text),
+                None => {
+                    let name = if n.is_nan() { "NaN" } else if n > 0.0 { "Infinity" } else { "-Infinity" };
+                    return Err(format!("Value at {:?} is {}, which has no stored form", path, name));
+                }
+            },
+            InputValue::Bool(b) => EntryOp::Set(b.to_string()),
+            InputValue::Null => EntryOp::Delete,
+            InputValue::Array => {
+                return Err(format!("Value at {:?} is an array; set its items under index paths, or store it as a JSON string", path));
+            }
+            InputValue::Object(children) => {
+                for (key, child) in children {
+                    walk(format!("{}/{}", path, key), child, out)?;
+                }
+                return Ok(());
+            }
+            InputValue::Unsupported(what) => return Err(format!("Value at {:?} is {}, which setMany can't store", path, what)),
         };
-        
-        let result = store_arc.get_pattern(&pattern);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(entries) => {
-                    let js_array = cx.empty_array();
-                    for (i, (k, v)) in entries.into_iter().enumerate() {
-                        let pair = cx.empty_array();
-                        let js_key = cx.string(k);
-                        let js_value = cx.string(v);
-                        pair.set(&mut cx, 0, js_key)?;
-                        pair.set(&mut cx, 1, js_value)?;
-                        js_array.set(&mut cx, i as u32, pair)?;
-                    }
-                    Ok(js_array)
+        out.push((path, op));
+        Ok(())
+    }
+    
+    let mut out = Vec::new();
+    for (key, value) in entries {
+        walk(key, value, &mut out)?;
+    }
+    Ok(out)
+}
+
+// Set many - returns promise. With an expected version (arg 4) it resolves to whether the
+// write went ahead instead of to its seq.
+fn set_many(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let entries_obj = cx.argument::<JsObject>(1)?;
+    let replace_subtree_at = cx.argument_opt(2)
+        .and_then(|arg| arg.downcast::<JsString, _>(&mut cx).ok())
+        .map(|s| s.value(&mut cx));
+    let durable = cx.argument_opt(3)
+        .and_then(|arg| arg.downcast::<JsBoolean, _>(&mut cx).ok())
+        .map(|b| b.value(&mut cx))
+        .unwrap_or(false);
+    let if_version = cx.argument_opt(4)
+        .and_then(|arg| arg.downcast::<JsNumber, _>(&mut cx).ok())
+        .map(|n| n.value(&mut cx));
+    
+    let entries = object_entries(&mut cx, entries_obj, 1)?;
+    let ops = match flatten_entries(entries) {
+        Ok(ops) => ops,
+        Err(message) => return cx.throw_type_error(format!("SetMany failed: {}", message)),
+    };
+    
+    let if_version = match if_version {
+        Some(version) if version < 0.0 || version.fract() != 0.0 => {
+            return cx.throw_range_error("ifVersion must be a non-negative integer");
+        }
+        Some(version) => {
+            // The replace already clears what a null under it would; one anywhere else
+            // would need a batch, which can't check a version
+            let mut entries = Vec::with_capacity(ops.len());
+            for (key, op) in &ops {
+                match op {
+                    EntryOp::Set(value) => entries.push((key.clone(), value.clone())),
+                    EntryOp::Delete if replace_subtree_at.as_deref().is_some_and(|base| key.starts_with(&format!("{}/", base))) => {}
+                    EntryOp::Delete => return cx.throw_type_error("SetMany failed: a null with ifVersion must be under the replaced key"),
                 }
-                Err(e) => cx.throw_error(format!("Get failed: {}", e))
+            }
+            Some((entries, version as u64))
+        }
+        None => None,
+    };
+    
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    if let Some((entries, version)) = if_version {
+        spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+            keyspace.with(|keys| {
+                let written = keys.set_many_if_version(entries, replace_subtree_at.as_deref(), version)?;
+                // The subtree's version is now at least the write's last seq
+                if written && durable {
+                    keys.wait_durable(keys.subtree_version(replace_subtree_at.as_deref().unwrap_or(""))?)?;
+                }
+                Ok::<_, WalDbError>(written)
+            })
+        }, move |mut cx, result| {
+            match result {
+                Ok(written) => Ok(cx.boolean(written)),
+                Err(e) => throw_store_error(&mut cx, "SetMany failed", e)
             }
         });
+        return Ok(promise);
+    }
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| {
+            // Deletes need a batch to stay atomic with the sets. A null removes the key and
+            // its children, as delete() does.
+            let seq = if ops.iter().any(|(_, op)| *op == EntryOp::Delete) {
+                let mut batch = WriteBatch::new();
+                if let Some(base_path) = &replace_subtree_at {
+                    batch.delete(base_path).delete_subtree(base_path);
+                }
+                for (key, op) in &ops {
+                    match op {
+                        EntryOp::Set(value) => batch.put(key, value),
+                        EntryOp::Delete => batch.delete(key).delete_subtree(key),
+                    };
+                }
+                keys.write(batch)
+            } else {
+                let entries = ops.into_iter()
+                    .filter_map(|(key, op)| match op {
+                        EntryOp::Set(value) => Some((key, value)),
+                        EntryOp::Delete => None,
+                    })
+                    .collect();
+                keys.set_many(entries, replace_subtree_at.as_deref())
+            };
+            seq.and_then(|seq| if durable { keys.wait_durable(seq).map(|_| seq) } else { Ok(seq) })
+        })
+    }, move |mut cx, result| {
+        match result {
+            Ok(seq) => Ok(cx.number(seq as f64)),
+            Err(e) => throw_store_error(&mut cx, "SetMany failed", e)
+        }
     });
     
     Ok(promise)
 }
 
-// Set value - returns promise
-fn set(mut cx: FunctionContext) -> JsResult<JsPromise> {
+// Write batch - takes an array of {type, key, value} ops, returns promise
+fn write_batch(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let ops = cx.argument::<JsArray>(1)?;
+    
+    // Encode ops into the batch while still on the JS thread
+    let batch = {
+        let mut batch = WriteBatch::new();
+        
+        for i in 0..ops.len(&mut cx) {
+            let op: Handle<JsObject> = ops.get(&mut cx, i)?;
+            let op_type: Handle<JsString> = op.get(&mut cx, "type")?;
+            let key: Handle<JsString> = op.get(&mut cx, "key")?;
+            let key = key.value(&mut cx);
+            
+            match op_type.value(&mut cx).as_str() {
+                "put" => {
+                    let value: Handle<JsString> = op.get(&mut cx, "value")?;
+                    let value = value.value(&mut cx);
+                    batch.put(&key, &value);
+                }
+                "delete" => {
+                    batch.delete(&key);
+                }
+                "deleteSubtree" => {
+                    batch.delete_subtree(&key);
+                }
+                other => return cx.throw_type_error(format!("Unknown batch op type: {}", other)),
+            }
+        }
+        
+        batch
+    };
+    
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.write(batch))
+    }, move |mut cx, result| {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_store_error(&mut cx, "WriteBatch failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// Has - resolves to whether the key holds a scalar, without marshalling the value
+fn has(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let key = cx.argument::<JsString>(1)?.value(&mut cx);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Read);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.contains(&key))
+    }, move |mut cx, result| {
+        match result {
+            Ok(found) => Ok(cx.boolean(found)),
+            Err(e) => throw_store_error(&mut cx, "Has failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// MayContain - synchronous, from memory alone; false means a get would find nothing
+fn may_contain(mut cx: FunctionContext) -> JsResult<JsBoolean> {
     let store = cx.argument::<BoxedStore>(0)?;
     let key = cx.argument::<JsString>(1)?.value(&mut cx);
+    throw_if_poisoned(&mut cx, &store.poisoned)?;
+    let found = match &store.namespace {
+        Some(name) => store.store.namespace(name).may_contain(&key),
+        None => store.store.may_contain(&key),
+    };
+    Ok(cx.boolean(found))
+}
+
+// Path key - the key for an array of path segments, each escaped to stay one level
+fn path_key(mut cx: FunctionContext) -> JsResult<JsString> {
+    let array = cx.argument::<JsArray>(0)?;
+    let mut segments = Vec::new();
+    for i in 0..array.len(&mut cx) {
+        let segment: Handle<JsValue> = array.get(&mut cx, i)?;
+        let segment = segment.downcast::<JsString, _>(&mut cx).or_else(|_| cx.throw_type_error("Path segments must be strings"))?;
+        segments.push(segment.value(&mut cx));
+    }
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    match waldb::path_key(&segments) {
+        Ok(key) => Ok(cx.string(key)),
+        Err(e) => throw_store_error(&mut cx, "Path key failed", e),
+    }
+}
+
+// Path segments - the segments pathKey made a key from
+fn path_segments(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let key = cx.argument::<JsString>(0)?.value(&mut cx);
+    let array = cx.empty_array();
+    for (i, segment) in waldb::path_segments(&key).into_iter().enumerate() {
+        let segment = cx.string(segment);
+        array.set(&mut cx, i as u32, segment)?;
+    }
+    Ok(array)
+}
+
+// Increment - resolves to the counter's new value
+fn increment(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let key = cx.argument::<JsString>(1)?.value(&mut cx);
+    let delta = cx.argument::<JsNumber>(2)?.value(&mut cx);
+    // Beyond 2^53 a JS number can't say which integer it means
+    if delta.fract() != 0.0 || delta.abs() > 9007199254740991.0 {
+        return cx.throw_type_error("Increment delta must be a safe integer");
+    }
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.increment(&key, delta as i64))
+    }, move |mut cx, result| {
+        match result {
+            Ok(value) => Ok(cx.number(value as f64)),
+            Err(e) => throw_store_error(&mut cx, "Increment failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// Push - resolves to the key the value was queued at
+fn push(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
     let value = cx.argument::<JsString>(2)?.value(&mut cx);
-    let force = cx.argument_opt(3)
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.push(&prefix, &value))
+    }, move |mut cx, result| {
+        match result {
+            Ok(key) => Ok(cx.string(key)),
+            Err(e) => throw_store_error(&mut cx, "Push failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// PopMin - resolves to the [key, value] taken off the front of the queue, or null
+fn pop_min(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.pop_min(&prefix))
+    }, move |mut cx, result| {
+        match result {
+            Ok(entry) => queue_entry_to_js(&mut cx, entry),
+            Err(e) => throw_store_error(&mut cx, "Pop failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// PeekMin - resolves to the [key, value] popMin would take, or null
+fn peek_min(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Read);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.peek_min(&prefix))
+    }, move |mut cx, result| {
+        match result {
+            Ok(entry) => queue_entry_to_js(&mut cx, entry),
+            Err(e) => throw_store_error(&mut cx, "Peek failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+fn queue_entry_to_js<'a, C: Context<'a>>(cx: &mut C, entry: Option<(String, String)>) -> JsResult<'a, JsValue> {
+    let Some((key, value)) = entry else { return Ok(cx.null().upcast()) };
+    let pair = cx.empty_array();
+    let js_key = cx.string(key);
+    let js_value = cx.string(value);
+    pair.set(cx, 0, js_key)?;
+    pair.set(cx, 1, js_value)?;
+    Ok(pair.upcast())
+}
+
+// QueueLength - resolves to how many entries the queue holds, counting no further than limit
+fn queue_length(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
+    let limit = cx.argument_opt(2)
+        .and_then(|arg| arg.downcast::<JsNumber, _>(&mut cx).ok())
+        .map(|n| n.value(&mut cx))
+        .filter(|n| *n >= 0.0)
+        .map_or(usize::MAX, |n| n as usize);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Read);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.queue_len(&prefix, limit))
+    }, move |mut cx, result| {
+        match result {
+            Ok(len) => Ok(cx.number(len as f64)),
+            Err(e) => throw_store_error(&mut cx, "Queue length failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// MoveSubtree - resolves to how many keys moved
+fn move_subtree(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let from = cx.argument::<JsString>(1)?.value(&mut cx);
+    let to = cx.argument::<JsString>(2)?.value(&mut cx);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.move_subtree(&from, &to))
+    }, move |mut cx, result| {
+        match result {
+            Ok(moved) => Ok(cx.number(moved as f64)),
+            Err(e) => throw_store_error(&mut cx, "Move failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// CopySubtree - resolves to how many keys were copied. The mode is 'fail' (the default),
+// 'skip' or 'replace'.
+fn copy_subtree(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let from = cx.argument::<JsString>(1)?.value(&mut cx);
+    let to = cx.argument::<JsString>(2)?.value(&mut cx);
+    let mode = match cx.argument_opt(3) {
+        Some(arg) if !arg.is_a::<JsUndefined, _>(&mut cx) => arg.downcast_or_throw::<JsString, _>(&mut cx)?.value(&mut cx),
+        _ => "fail".to_string(),
+    };
+    let mode = match mode.as_str() {
+        "fail" => CopyMode::Fail,
+        "skip" => CopyMode::Skip,
+        "replace" => CopyMode::Replace,
+        _ => return cx.throw_type_error(format!("Copy mode must be 'fail', 'skip' or 'replace', not {:?}", mode)),
+    };
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.copy_subtree(&from, &to, mode))
+    }, move |mut cx, result| {
+        match result {
+            Ok(copied) => Ok(cx.number(copied as f64)),
+            Err(e) => throw_store_error(&mut cx, "Copy failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// GetJson - resolves to the subtree as a JSON string, or null if nothing is there
+fn get_json(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let key = cx.argument::<JsString>(1)?.value(&mut cx);
+    let detect_types = cx.argument_opt(2)
         .and_then(|arg| arg.downcast::<JsBoolean, _>(&mut cx).ok())
         .map(|b| b.value(&mut cx))
         .unwrap_or(false);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Read);
     
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.get_subtree_json_with_options(&key, JsonOptions { detect_types }))
+    }, move |mut cx, result| {
+        match result {
+            Ok(Some(tree)) => Ok(cx.string(tree.json).upcast::<JsValue>()),
+            Ok(None) => Ok(cx.null().upcast::<JsValue>()),
+            Err(e) => throw_store_error(&mut cx, "GetJson failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// Flush - returns promise
+fn flush(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.flush())
+    }, move |mut cx, result| {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_store_error(&mut cx, "Flush failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// Clear all - returns promise with the tombstone's seq. On a namespace, empties just the
+// namespace
+fn clear_all(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.clear_all())
+    }, move |mut cx, result| {
+        match result {
+            Ok(seq) => Ok(cx.number(seq as f64)),
+            Err(e) => throw_store_error(&mut cx, "Clear failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// Health check - resolves with { healthy, checks: [{ name, status, durationMs, detail? }] }
+// for the whole store, also when called on a namespace
+fn health_check(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let write_probe = match cx.argument_opt(1) {
+        Some(arg) => arg.downcast::<JsBoolean, _>(&mut cx).map(|b| b.value(&mut cx)).unwrap_or(false),
+        None => false,
+    };
+    let mut options = HealthCheckOptions { write_probe, ..Default::default() };
+    if let Some(max_duration) = timeout_arg(&mut cx, 2) {
+        options.max_duration = max_duration;
+    }
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    // Not in submission order, so queued calls can't hold up the probe
+    let poisoned = Arc::clone(&store.poisoned);
+    let store = Arc::clone(&store.store);
+    
+    spawn_worker(channel, deferred, Some(poisoned), move || {
+        store.health_check(options)
+    }, move |mut cx, result| {
+        match result {
+            Ok(report) => {
+                let obj = cx.empty_object();
+                let healthy = cx.boolean(report.healthy());
+                obj.set(&mut cx, "healthy", healthy)?;
+                let checks = cx.empty_array();
+                for (i, check) in report.checks.into_iter().enumerate() {
+                    let js_check = cx.empty_object();
+                    let name = cx.string(check.name);
+                    js_check.set(&mut cx, "name", name)?;
+                    let (status, detail) = match check.status {
+                        HealthStatus::Passed => ("passed", None),
+                        HealthStatus::Failed(detail) => ("failed", Some(detail)),
+                        HealthStatus::Skipped => ("skipped", None),
+                    };
+                    let status = cx.string(status);
+                    js_check.set(&mut cx, "status", status)?;
+                    let duration = cx.number(check.duration.as_secs_f64() * 1000.0);
+                    js_check.set(&mut cx, "durationMs", duration)?;
+                    if let Some(detail) = detail {
+                        let detail = cx.string(detail);
+                        js_check.set(&mut cx, "detail", detail)?;
+                    }
+                    checks.set(&mut cx, i as u32, js_check)?;
+                }
+                obj.set(&mut cx, "checks", checks)?;
+                Ok(obj)
+            }
+            Err(e) => throw_store_error(&mut cx, "HealthCheck failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// Warmup - resolves with { bytesLoaded, blocksLoaded, bytesPinned, complete } for the whole
+// store, also when called on a namespace; prefixes are full keys
+fn warmup(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let levels_array = cx.argument::<JsArray>(1)?;
+    let prefixes_array = cx.argument::<JsArray>(2)?;
+    let max_bytes = match cx.argument_opt(3) {
+        Some(arg) => arg.downcast::<JsNumber, _>(&mut cx).map(|n| n.value(&mut cx)).unwrap_or(f64::INFINITY),
+        None => f64::INFINITY,
+    };
+    let mut options = waldb::WarmupOptions { max_bytes: max_bytes.max(0.0) as u64, ..Default::default() };
+    for i in 0..levels_array.len(&mut cx) {
+        let level: Handle<JsNumber> = levels_array.get(&mut cx, i)?;
+        options.levels.push(level.value(&mut cx) as usize);
+    }
+    for i in 0..prefixes_array.len(&mut cx) {
+        let prefix: Handle<JsString> = prefixes_array.get(&mut cx, i)?;
+        options.prefixes.push(prefix.value(&mut cx));
+    }
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    // Not in submission order: it only reads, and a deploy script waits on it anyway
+    let poisoned = Arc::clone(&store.poisoned);
+    let store = Arc::clone(&store.store);
+    
+    spawn_worker(channel, deferred, Some(poisoned), move || {
+        store.warmup(options)
+    }, move |mut cx, result| {
+        match result {
+            Ok(report) => {
+                let obj = cx.empty_object();
+                let bytes_loaded = cx.number(report.bytes_loaded as f64);
+                obj.set(&mut cx, "bytesLoaded", bytes_loaded)?;
+                let blocks_loaded = cx.number(report.blocks_loaded as f64);
+                obj.set(&mut cx, "blocksLoaded", blocks_loaded)?;
+                let bytes_pinned = cx.number(report.bytes_pinned as f64);
+                obj.set(&mut cx, "bytesPinned", bytes_pinned)?;
+                let complete = cx.boolean(report.complete);
+                obj.set(&mut cx, "complete", complete)?;
+                Ok(obj)
+            }
+            Err(e) => throw_store_error(&mut cx, "Warmup failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// Set quota - caps the bytes under a prefix of the whole store, also when called on a
+// namespace; prefixes are full keys
+fn set_quota(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
+    let max_bytes = cx.argument::<JsNumber>(2)?.value(&mut cx);
+    if max_bytes.fract() != 0.0 || !(0.0..=9007199254740991.0).contains(&max_bytes) {
+        return cx.throw_type_error("Quota must be a safe non-negative integer");
+    }
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with_store(|store| store.set_quota(&prefix, max_bytes as u64))
+    }, move |mut cx, result| {
+        match result {
+            Ok(()) => Ok(cx.undefined()),
+            Err(e) => throw_store_error(&mut cx, "Set quota failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// Remove quota - resolves to whether the prefix had one
+fn remove_quota(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Write);
     
-    std::thread::spawn(move || {
-        let result = store_arc.set(&key, &value, force);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(_) => Ok(cx.undefined()),
-                Err(e) => cx.throw_error(format!("Set failed: {}", e))
-            }
-        });
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with_store(|store| store.remove_quota(&prefix))
+    }, move |mut cx, result| {
+        match result {
+            Ok(removed) => Ok(cx.boolean(removed)),
+            Err(e) => throw_store_error(&mut cx, "Remove quota failed", e)
+        }
     });
     
     Ok(promise)
 }
 
-// Delete - returns promise
-fn delete(mut cx: FunctionContext) -> JsResult<JsPromise> {
+// Get quota usage - resolves with { usedBytes, maxBytes }, or null if the prefix has no quota
+fn get_quota_usage(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let store = cx.argument::<BoxedStore>(0)?;
-    let key = cx.argument::<JsString>(1)?.value(&mut cx);
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
-    
-    std::thread::spawn(move || {
-        // Delete key and subtree for Firebase compat
-        let _ = store_arc.delete(&key);
-        let result = store_arc.delete_subtree(&key);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(_) => Ok(cx.undefined()),
-                Err(e) => cx.throw_error(format!("Delete failed: {}", e))
+    let keyspace = store.keyspace(Access::Read);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with_store(|store| store.quota_usage(&prefix))
+    }, move |mut cx, result| {
+        match result {
+            Ok(Some(usage)) => {
+                let obj = cx.empty_object();
+                let used_bytes = cx.number(usage.used_bytes as f64);
+                obj.set(&mut cx, "usedBytes", used_bytes)?;
+                let max_bytes = cx.number(usage.max_bytes as f64);
+                obj.set(&mut cx, "maxBytes", max_bytes)?;
+                Ok(obj.upcast::<JsValue>())
             }
-        });
+            Ok(None) => Ok(cx.null().upcast()),
+            Err(e) => throw_store_error(&mut cx, "Get quota usage failed", e)
+        }
     });
     
     Ok(promise)
 }
 
-// Set many - returns promise
-fn set_many(mut cx: FunctionContext) -> JsResult<JsPromise> {
+// Set validator - checks every value set under a prefix of the whole store with the built-in
+// validator called name; prefixes are full keys
+fn set_validator(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let store = cx.argument::<BoxedStore>(0)?;
-    let entries_obj = cx.argument::<JsObject>(1)?;
-    let replace_subtree_at = cx.argument_opt(2)
-        .and_then(|arg| arg.downcast::<JsString, _>(&mut cx).ok())
-        .map(|s| s.value(&mut cx));
-    
-    // Convert JS object to Vec<(String, String)>
-    let entries = {
-        let keys = entries_obj.get_own_property_names(&mut cx)?;
-        let mut entries = Vec::new();
-        
-        for i in 0..keys.len(&mut cx) {
-            let key: Handle<JsString> = keys.get(&mut cx, i)?;
-            let key_str = key.value(&mut cx);
-            let value: Handle<JsValue> = entries_obj.get(&mut cx, key)?;
-            let value: Handle<JsString> = value.downcast_or_throw(&mut cx)?;
-            let value_str = value.value(&mut cx);
-            entries.push((key_str, value_str));
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
+    let name = cx.argument::<JsString>(2)?.value(&mut cx);
+    let option = match cx.argument_opt(3) {
+        Some(arg) if arg.is_a::<JsNumber, _>(&mut cx) => {
+            validators::ValidatorOption::Number(arg.downcast_or_throw::<JsNumber, _>(&mut cx)?.value(&mut cx))
         }
-        
-        entries
+        Some(arg) if arg.is_a::<JsString, _>(&mut cx) => {
+            validators::ValidatorOption::Text(arg.downcast_or_throw::<JsString, _>(&mut cx)?.value(&mut cx))
+        }
+        _ => validators::ValidatorOption::None,
+    };
+    let validator = match validators::builtin(&name, option) {
+        Ok(validator) => validator,
+        Err(reason) => return cx.throw_type_error(reason),
     };
-    
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Write);
     
-    std::thread::spawn(move || {
-        let result = store_arc.set_many(entries, replace_subtree_at.as_deref());
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(_) => Ok(cx.undefined()),
-                Err(e) => cx.throw_error(format!("SetMany failed: {}", e))
-            }
-        });
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with_store(|store| store.register_validator(&prefix, validator))
+    }, move |mut cx, result| {
+        match result {
+            Ok(()) => Ok(cx.undefined()),
+            Err(e) => throw_store_error(&mut cx, "Set validator failed", e)
+        }
     });
     
     Ok(promise)
 }
 
-// Flush - returns promise
-fn flush(mut cx: FunctionContext) -> JsResult<JsPromise> {
+// Remove validator - resolves to whether the prefix had one
+fn remove_validator(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let store = cx.argument::<BoxedStore>(0)?;
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Write);
     
-    std::thread::spawn(move || {
-        let result = store_arc.flush();
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(_) => Ok(cx.undefined()),
-                Err(e) => cx.throw_error(format!("Flush failed: {}", e))
-            }
-        });
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with_store(|store| store.remove_validator(&prefix))
+    }, move |mut cx, result| {
+        match result {
+            Ok(removed) => Ok(cx.boolean(removed)),
+            Err(e) => throw_store_error(&mut cx, "Remove validator failed", e)
+        }
     });
     
     Ok(promise)
@@ -229,28 +1407,27 @@ fn flush(mut cx: FunctionContext) -> JsResult<JsPromise> {
 fn get_pattern(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let store = cx.argument::<BoxedStore>(0)?;
     let pattern = cx.argument::<JsString>(1)?.value(&mut cx);
+    let timeout = timeout_arg(&mut cx, 2);
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
-    
-    std::thread::spawn(move || {
-        let result = store_arc.get_pattern(&pattern);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(matches) => {
-                    let obj = cx.empty_object();
-                    for (key, value) in matches {
-                        let js_key = cx.string(key);
-                        let js_value = cx.string(value);
-                        obj.set(&mut cx, js_key, js_value)?;
-                    }
-                    Ok(obj)
+    let keyspace = store.keyspace(Access::Read).with_timeout(timeout);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.get_pattern_cancellable(&pattern, &keyspace.cancel))
+    }, move |mut cx, result| {
+        match result {
+            Ok(matches) => {
+                let obj = cx.empty_object();
+                for (key, value) in matches {
+                    let js_key = cx.string(key);
+                    let js_value = cx.string(value);
+                    obj.set(&mut cx, js_key, js_value)?;
                 }
-                Err(e) => cx.throw_error(format!("GetPattern failed: {}", e))
+                Ok(obj)
             }
-        });
+            Err(e) => throw_store_error(&mut cx, "GetPattern failed", e)
+        }
     });
     
     Ok(promise)
@@ -264,25 +1441,23 @@ fn get_range(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
-    
-    std::thread::spawn(move || {
-        let result = store_arc.get_range(&start, &end);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(matches) => {
-                    let obj = cx.empty_object();
-                    for (key, value) in matches {
-                        let js_key = cx.string(key);
-                        let js_value = cx.string(value);
-                        obj.set(&mut cx, js_key, js_value)?;
-                    }
-                    Ok(obj)
+    let keyspace = store.keyspace(Access::Read);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.get_range_limit_cancellable(&start, &end, usize::MAX, &keyspace.cancel))
+    }, move |mut cx, result| {
+        match result {
+            Ok(matches) => {
+                let obj = cx.empty_object();
+                for (key, value) in matches {
+                    let js_key = cx.string(key);
+                    let js_value = cx.string(value);
+                    obj.set(&mut cx, js_key, js_value)?;
                 }
-                Err(e) => cx.throw_error(format!("GetRange failed: {}", e))
+                Ok(obj)
             }
-        });
+            Err(e) => throw_store_error(&mut cx, "GetRange failed", e)
+        }
     });
     
     Ok(promise)
@@ -295,28 +1470,73 @@ fn get_pattern_entries(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Read);
     
-    std::thread::spawn(move || {
-        let result = store_arc.get_pattern(&pattern);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(matches) => {
-                    let js_array = cx.empty_array();
-                    for (i, (key, value)) in matches.into_iter().enumerate() {
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.get_pattern_cancellable(&pattern, &keyspace.cancel))
+    }, move |mut cx, result| {
+        match result {
+            Ok(matches) => {
+                let js_array = cx.empty_array();
+                for (i, (key, value)) in matches.into_iter().enumerate() {
+                    let pair = cx.empty_array();
+                    let js_key = cx.string(key);
+                    let js_value = cx.string(value);
+                    pair.set(&mut cx, 0, js_key)?;
+                    pair.set(&mut cx, 1, js_value)?;
+                    js_array.set(&mut cx, i as u32, pair)?;
+                }
+                Ok(js_array)
+            }
+            Err(e) => throw_store_error(&mut cx, "GetPatternEntries failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// Get pattern grouped - returns array of [groupKey, [[field, value], ...]], fields relative to
+// the group; limit counts groups
+fn get_pattern_grouped(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let pattern = cx.argument::<JsString>(1)?.value(&mut cx);
+    let group_depth = cx.argument::<JsNumber>(2)?.value(&mut cx).max(0.0) as usize;
+    let limit = match cx.argument_opt(3) {
+        Some(arg) => arg.downcast::<JsNumber, _>(&mut cx).map(|n| n.value(&mut cx)).unwrap_or(f64::INFINITY),
+        None => f64::INFINITY,
+    };
+    let limit = limit.max(0.0) as usize;
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Read);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.get_pattern_grouped(&pattern, group_depth, limit))
+    }, move |mut cx, result| {
+        match result {
+            Ok(groups) => {
+                let js_groups = cx.empty_array();
+                for (i, (group_key, fields)) in groups.into_iter().enumerate() {
+                    let js_fields = cx.empty_array();
+                    for (j, (field, value)) in fields.into_iter().enumerate() {
                         let pair = cx.empty_array();
-                        let js_key = cx.string(key);
+                        let js_field = cx.string(field);
                         let js_value = cx.string(value);
-                        pair.set(&mut cx, 0, js_key)?;
+                        pair.set(&mut cx, 0, js_field)?;
                         pair.set(&mut cx, 1, js_value)?;
-                        js_array.set(&mut cx, i as u32, pair)?;
+                        js_fields.set(&mut cx, j as u32, pair)?;
                     }
-                    Ok(js_array)
+                    let group = cx.empty_array();
+                    let js_key = cx.string(group_key);
+                    group.set(&mut cx, 0, js_key)?;
+                    group.set(&mut cx, 1, js_fields)?;
+                    js_groups.set(&mut cx, i as u32, group)?;
                 }
-                Err(e) => cx.throw_error(format!("GetPatternEntries failed: {}", e))
+                Ok(js_groups)
             }
-        });
+            Err(e) => throw_store_error(&mut cx, "GetPatternGrouped failed", e)
+        }
     });
     
     Ok(promise)
@@ -330,33 +1550,112 @@ fn get_range_entries(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Read);
     
-    std::thread::spawn(move || {
-        let result = store_arc.get_range(&start, &end);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(matches) => {
-                    let js_array = cx.empty_array();
-                    for (i, (key, value)) in matches.into_iter().enumerate() {
-                        let pair = cx.empty_array();
-                        let js_key = cx.string(key);
-                        let js_value = cx.string(value);
-                        pair.set(&mut cx, 0, js_key)?;
-                        pair.set(&mut cx, 1, js_value)?;
-                        js_array.set(&mut cx, i as u32, pair)?;
-                    }
-                    Ok(js_array)
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.get_range_limit_cancellable(&start, &end, usize::MAX, &keyspace.cancel))
+    }, move |mut cx, result| {
+        match result {
+            Ok(matches) => {
+                let js_array = cx.empty_array();
+                for (i, (key, value)) in matches.into_iter().enumerate() {
+                    let pair = cx.empty_array();
+                    let js_key = cx.string(key);
+                    let js_value = cx.string(value);
+                    pair.set(&mut cx, 0, js_key)?;
+                    pair.set(&mut cx, 1, js_value)?;
+                    js_array.set(&mut cx, i as u32, pair)?;
                 }
-                Err(e) => cx.throw_error(format!("GetRangeEntries failed: {}", e))
+                Ok(js_array)
             }
-        });
+            Err(e) => throw_store_error(&mut cx, "GetRangeEntries failed", e)
+        }
+    });
+    
+    Ok(promise)
+}
+
+// ==================== CURSORS ====================
+
+// A scanOpen cursor over a core iterator, which reads the store as of the open and pins the
+// segments it reads until dropped
+struct CursorWrapper {
+    iter: Arc<Mutex<Option<RangeIter>>>,  // Held by a scanNext batch in flight; None once closed
+    batch_size: usize,
+    poisoned: Arc<AtomicBool>,  // The store's
+}
+
+// A cursor collected without scanClose drops its iterator here. If a batch is still being
+// read, its worker thread holds the last reference and drops it when done.
+impl Finalize for CursorWrapper {
+    fn finalize<'a, C: Context<'a>>(self, _cx: &mut C) {
+        if let Ok(mut iter) = self.iter.try_lock() {
+            iter.take();
+        }
+    }
+}
+
+type BoxedCursor = JsBox<CursorWrapper>;
+
+// Scan open - a boxed cursor over the live entries under prefix, batchSize per scanNext
+fn scan_open(mut cx: FunctionContext) -> JsResult<BoxedCursor> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let prefix = cx.argument::<JsString>(1)?.value(&mut cx);
+    let batch_size = cx.argument::<JsNumber>(2)?.value(&mut cx).max(1.0) as usize;
+    throw_if_poisoned(&mut cx, &store.poisoned)?;
+    
+    // Only copies the memtable's share of the range; segments are read by scanNext
+    match store.keyspace(Access::Read).with(|keys| keys.scan_prefix_iter(&prefix)) {
+        Ok(iter) => {
+            let poisoned = Arc::clone(&store.poisoned);
+            Ok(cx.boxed(CursorWrapper { iter: Arc::new(Mutex::new(Some(iter))), batch_size, poisoned }))
+        }
+        Err(e) => throw_store_error(&mut cx, "ScanOpen failed", e),
+    }
+}
+
+// Scan next - up to batchSize [key, value] pairs; an empty array once exhausted or closed
+fn scan_next(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let cursor = cx.argument::<BoxedCursor>(0)?;
+    let iter = Arc::clone(&cursor.iter);
+    let batch_size = cursor.batch_size;
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&cursor.poisoned)), move || {
+        match iter.lock().expect("Cursor lock should not be poisoned").as_mut() {
+            Some(iter) => iter.by_ref().take(batch_size).collect::<Result<Vec<_>, _>>(),
+            None => Ok(Vec::new()),
+        }
+    }, move |mut cx, result| {
+        match result {
+            Ok(entries) => {
+                let js_array = cx.empty_array();
+                for (i, (key, value)) in entries.into_iter().enumerate() {
+                    let pair = cx.empty_array();
+                    let js_key = cx.string(key);
+                    let js_value = cx.string(value);
+                    pair.set(&mut cx, 0, js_key)?;
+                    pair.set(&mut cx, 1, js_value)?;
+                    js_array.set(&mut cx, i as u32, pair)?;
+                }
+                Ok(js_array)
+            }
+            Err(e) => throw_store_error(&mut cx, "ScanNext failed", e)
+        }
     });
     
     Ok(promise)
 }
 
+// Scan close - drop the cursor's iterator so its segments can go; later scanNext calls get []
+fn scan_close(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let cursor = cx.argument::<BoxedCursor>(0)?;
+    // Poisoned if a scanNext panicked mid-batch; the iterator is dropped all the same
+    cursor.iter.lock().unwrap_or_else(|e| e.into_inner()).take();
+    Ok(cx.undefined())
+}
+
 // File operations
 fn set_file(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let store = cx.argument::<BoxedStore>(0)?;
@@ -366,20 +1665,18 @@ fn set_file(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Write);
     
     // Get buffer data as bytes
-    let data = buffer.as_slice(&mut cx).to_vec();
-    
-    std::thread::spawn(move || {
-        let result = store_arc.set_file(&path, &data);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(_) => Ok(cx.undefined()),
-                Err(e) => cx.throw_error(format!("SetFile failed: {}", e))
-            }
-        });
+    let data = buffer.as_slice(&cx).to_vec();
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.set_file(&path, &data))
+    }, move |mut cx, result| {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_store_error(&mut cx, "SetFile failed", e)
+        }
     });
     
     Ok(promise)
@@ -391,22 +1688,20 @@ fn get_file(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
-    
-    std::thread::spawn(move || {
-        let result = store_arc.get_file(&path);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(data) => {
-                    let mut buffer = cx.buffer(data.len())?;
-                    let slice = buffer.as_mut_slice(&mut cx);
-                    slice.copy_from_slice(&data);
-                    Ok(buffer)
-                }
-                Err(e) => cx.throw_error(format!("GetFile failed: {}", e))
+    let keyspace = store.keyspace(Access::Read);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.get_file(&path))
+    }, move |mut cx, result| {
+        match result {
+            Ok(data) => {
+                let mut buffer = cx.buffer(data.len())?;
+                let slice = buffer.as_mut_slice(&mut cx);
+                slice.copy_from_slice(&data);
+                Ok(buffer)
             }
-        });
+            Err(e) => throw_store_error(&mut cx, "GetFile failed", e)
+        }
     });
     
     Ok(promise)
@@ -418,28 +1713,83 @@ fn delete_file(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
     
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Write);
     
-    std::thread::spawn(move || {
-        let result = store_arc.delete_file(&path);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(_) => Ok(cx.undefined()),
-                Err(e) => cx.throw_error(format!("DeleteFile failed: {}", e))
-            }
-        });
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.delete_file(&path))
+    }, move |mut cx, result| {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_store_error(&mut cx, "DeleteFile failed", e)
+        }
     });
     
     Ok(promise)
 }
 
+// One { field, op, value, type? } filter. 'in' takes an array of values; the others take
+// one, stringified. type is 'auto' (default), 'string' or 'number'.
+fn filter_arg<'a>(cx: &mut FunctionContext<'a>, obj: Handle<'a, JsObject>) -> NeonResult<waldb::SearchFilter> {
+    let field: Handle<JsString> = obj.get(cx, "field")?;
+    let op: Handle<JsString> = obj.get(cx, "op")?;
+    let value: Handle<JsValue> = obj.get(cx, "value")?;
+    
+    let op = match op.value(cx).as_str() {
+        "==" => waldb::FilterOp::Eq,
+        "!=" => waldb::FilterOp::Ne,
+        ">" => waldb::FilterOp::Gt,
+        ">=" => waldb::FilterOp::Gte,
+        "<" => waldb::FilterOp::Lt,
+        "<=" => waldb::FilterOp::Lte,
+        "in" => {
+            let list = value.downcast::<JsArray, _>(cx).or_else(|_| cx.throw_type_error("'in' filter value must be an array"))?;
+            let mut values = Vec::new();
+            for i in 0..list.len(cx) {
+                let v: Handle<JsValue> = list.get(cx, i)?;
+                values.push(v.to_string(cx)?.value(cx));
+            }
+            waldb::FilterOp::In(values)
+        }
+        "contains" => waldb::FilterOp::Contains,
+        _ => return cx.throw_error("Invalid filter operator")
+    };
+    
+    let value_type = match obj.get_opt::<JsString, _, _>(cx, "type")?.map(|t| t.value(cx)).as_deref() {
+        None | Some("auto") => waldb::ValueType::Auto,
+        Some("string") => waldb::ValueType::String,
+        Some("number") => waldb::ValueType::Number,
+        Some(other) => return cx.throw_error(format!("Invalid filter type '{}'", other)),
+    };
+    
+    let value = if let waldb::FilterOp::In(_) = op {
+        String::new()
+    } else {
+        value.to_string(cx)?.value(cx)
+    };
+    
+    Ok(waldb::SearchFilter { field: field.value(cx), op, value, value_type })
+}
+
+// orderBy: 'keyAsc' (default) or 'keyDesc'; scored searches ignore it
+fn order_arg(cx: &mut FunctionContext, order: Option<String>) -> NeonResult<Option<waldb::SearchOrder>> {
+    match order.as_deref() {
+        None => Ok(None),
+        Some("keyAsc") => Ok(Some(waldb::SearchOrder::KeyAsc)),
+        Some("keyDesc") => Ok(Some(waldb::SearchOrder::KeyDesc)),
+        Some(other) => cx.throw_error(format!("Invalid orderBy '{}'", other)),
+    }
+}
+
 // Search operation
 fn search(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let store = cx.argument::<BoxedStore>(0)?;
     let pattern = cx.argument::<JsString>(1)?.value(&mut cx);
     let filters_array = cx.argument::<JsArray>(2)?;
     let limit = cx.argument::<JsNumber>(3)?.value(&mut cx) as usize;
+    let timeout = timeout_arg(&mut cx, 4);
+    let order = cx.argument_opt(5).and_then(|v| v.downcast::<JsString, _>(&mut cx).ok());
+    let order = order.map(|s| s.value(&mut cx));
+    let order_by = order_arg(&mut cx, order)?;
     
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
@@ -448,80 +1798,62 @@ fn search(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let mut filters = Vec::new();
     for i in 0..filters_array.len(&mut cx) {
         let filter_obj: Handle<JsObject> = filters_array.get(&mut cx, i)?;
-        
-        let field: Handle<JsString> = filter_obj.get(&mut cx, "field")?;
-        let op_str: Handle<JsString> = filter_obj.get(&mut cx, "op")?;
-        let value: Handle<JsString> = filter_obj.get(&mut cx, "value")?;
-        
-        let op = match op_str.value(&mut cx).as_str() {
-            "==" => store::FilterOp::Eq,
-            "!=" => store::FilterOp::Ne,
-            ">" => store::FilterOp::Gt,
-            ">=" => store::FilterOp::Gte,
-            "<" => store::FilterOp::Lt,
-            "<=" => store::FilterOp::Lte,
-            _ => store::FilterOp::Eq,
-        };
-        
-        filters.push(store::SearchFilter {
-            field: field.value(&mut cx),
-            op,
-            value: value.value(&mut cx),
-        });
+        filters.push(filter_arg(&mut cx, filter_obj)?);
     }
     
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Read).with_timeout(timeout);
     
-    std::thread::spawn(move || {
-        let search_options = store::SearchOptions {
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        let search_options = waldb::SearchOptions {
             pattern,
             filters: Some(filters),
             vector: None,
             text: None,
             scoring: None,
             limit: Some(limit),
+            order_by,
         };
-        let result = store_arc.search(search_options);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(groups) => {
-                    let js_array = cx.empty_array();
-                    
-                    for (idx, (group_key, fields)) in groups.into_iter().enumerate() {
-                        let group_array = cx.empty_array();
-                        
-                        // Add all fields as [key, value] pairs
-                        let mut field_idx = 0;
-                        for (field_name, field_value) in fields {
-                            let pair = cx.empty_array();
-                            let full_key = format!("{}/{}", group_key, field_name);
-                            let js_key = cx.string(full_key);
-                            let js_value = cx.string(field_value);
-                            pair.set(&mut cx, 0, js_key)?;
-                            pair.set(&mut cx, 1, js_value)?;
-                            group_array.set(&mut cx, field_idx, pair)?;
-                            field_idx += 1;
-                        }
-                        
-                        js_array.set(&mut cx, idx as u32, group_array)?;
-                    }
-                    
-                    Ok(js_array)
-                }
-                Err(e) => cx.throw_error(format!("Search failed: {}", e))
-            }
-        });
+        keyspace.with(|keys| keys.search_cancellable(search_options, &keyspace.cancel))
+    }, move |mut cx, result| {
+        match result {
+            Ok(groups) => groups_to_js(&mut cx, &groups),
+            Err(e) => throw_store_error(&mut cx, "Search failed", e)
+        }
     });
     
     Ok(promise)
 }
 
+// Search results as an array of groups, each an array of [fullKey, value] pairs; the core
+// builds the full keys, so both search exports render them the same way
+fn groups_to_js<'a, C: Context<'a>>(cx: &mut C, groups: &[SearchGroup]) -> JsResult<'a, JsArray> {
+    let js_array = cx.empty_array();
+    for (i, group) in groups.iter().enumerate() {
+        let group_array = cx.empty_array();
+        for (j, (key, value)) in search_group_entries(group).into_iter().enumerate() {
+            let pair = cx.empty_array();
+            let js_key = cx.string(key);
+            let js_value = cx.string(value);
+            pair.set(cx, 0, js_key)?;
+            pair.set(cx, 1, js_value)?;
+            group_array.set(cx, j as u32, pair)?;
+        }
+        js_array.set(cx, i as u32, group_array)?;
+    }
+    Ok(js_array)
+}
+
 // Set vector embedding
 fn set_vector(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let store = cx.argument::<BoxedStore>(0)?;
     let path = cx.argument::<JsString>(1)?.value(&mut cx);
     let vector_array = cx.argument::<JsArray>(2)?;
+    let normalize = match cx.argument_opt(3) {
+        Some(arg) if arg.is_a::<JsBoolean, _>(&mut cx) => {
+            arg.downcast::<JsBoolean, _>(&mut cx).or_throw(&mut cx)?.value(&mut cx)
+        }
+        _ => false,
+    };
     
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
@@ -533,17 +1865,15 @@ fn set_vector(mut cx: FunctionContext) -> JsResult<JsPromise> {
         vector.push(val.value(&mut cx) as f32);
     }
     
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Write);
     
-    std::thread::spawn(move || {
-        let result = store_arc.set_vector(&path, vector);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(_) => Ok(cx.undefined()),
-                Err(e) => cx.throw_error(format!("Failed to set vector: {}", e))
-            }
-        });
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.set_vector(&path, vector, normalize))
+    }, move |mut cx, result| {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_store_error(&mut cx, "Failed to set vector", e)
+        }
     });
     
     Ok(promise)
@@ -556,25 +1886,23 @@ fn get_vector(mut cx: FunctionContext) -> JsResult<JsPromise> {
     
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
-    let store_arc = Arc::clone(&store.store);
+    let keyspace = store.keyspace(Access::Read);
     
-    std::thread::spawn(move || {
-        let result = store_arc.get_vector(&path);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(Some(vector)) => {
-                    let js_array = cx.empty_array();
-                    for (i, &val) in vector.iter().enumerate() {
-                        let js_val = cx.number(val);
-                        js_array.set(&mut cx, i as u32, js_val)?;
-                    }
-                    Ok(js_array.upcast::<JsValue>())
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.get_vector(&path))
+    }, move |mut cx, result| {
+        match result {
+            Ok(Some(vector)) => {
+                let js_array = cx.empty_array();
+                for (i, &val) in vector.iter().enumerate() {
+                    let js_val = cx.number(val);
+                    js_array.set(&mut cx, i as u32, js_val)?;
                 }
-                Ok(None) => Ok(cx.null().upcast::<JsValue>()),
-                Err(e) => cx.throw_error(format!("Failed to get vector: {}", e))
+                Ok(js_array.upcast::<JsValue>())
             }
-        });
+            Ok(None) => Ok(cx.null().upcast::<JsValue>()),
+            Err(e) => throw_store_error(&mut cx, "Failed to get vector", e)
+        }
     });
     
     Ok(promise)
@@ -600,26 +1928,7 @@ fn advanced_search(mut cx: FunctionContext) -> JsResult<JsPromise> {
         let mut parsed_filters = Vec::new();
         for i in 0..filters_array.len(&mut cx) {
             let filter: Handle<JsObject> = filters_array.get(&mut cx, i)?;
-            
-            let field: Handle<JsString> = filter.get(&mut cx, "field")?;
-            let op: Handle<JsString> = filter.get(&mut cx, "op")?;
-            let value: Handle<JsString> = filter.get(&mut cx, "value")?;
-            
-            let op = match op.value(&mut cx).as_str() {
-                "==" => store::FilterOp::Eq,
-                "!=" => store::FilterOp::Ne,
-                ">" => store::FilterOp::Gt,
-                ">=" => store::FilterOp::Gte,
-                "<" => store::FilterOp::Lt,
-                "<=" => store::FilterOp::Lte,
-                _ => return cx.throw_error("Invalid filter operator")
-            };
-            
-            parsed_filters.push(store::SearchFilter {
-                field: field.value(&mut cx),
-                op,
-                value: value.value(&mut cx),
-            });
+            parsed_filters.push(filter_arg(&mut cx, filter)?);
         }
             if !parsed_filters.is_empty() {
                 filters = Some(parsed_filters);
@@ -652,10 +1961,27 @@ fn advanced_search(mut cx: FunctionContext) -> JsResult<JsPromise> {
             None
         };
         
-            vector_opts = Some(store::VectorSearchOptions {
+        let metric = if let Ok(metric_value) = vector_obj.get::<JsValue, _, _>(&mut cx, "metric") {
+            if !metric_value.is_a::<JsUndefined, _>(&mut cx) && !metric_value.is_a::<JsNull, _>(&mut cx) {
+                let metric_str = metric_value.downcast::<JsString, _>(&mut cx).or_throw(&mut cx)?;
+                match metric_str.value(&mut cx).as_str() {
+                    "cosine" => waldb::Metric::Cosine,
+                    "dot" => waldb::Metric::Dot,
+                    "euclidean" => waldb::Metric::Euclidean,
+                    other => return cx.throw_error(format!("Invalid vector metric: {}", other)),
+                }
+            } else {
+                waldb::Metric::default()
+            }
+        } else {
+            waldb::Metric::default()
+        };
+        
+            vector_opts = Some(waldb::VectorSearchOptions {
                 query,
                 field: field.value(&mut cx),
                 threshold,
+                metric,
             });
         }
     }
@@ -685,10 +2011,47 @@ fn advanced_search(mut cx: FunctionContext) -> JsResult<JsPromise> {
             None
         };
         
-            text_opts = Some(store::TextSearchOptions {
+        let anchor = if let Ok(anchor_value) = text_obj.get::<JsValue, _, _>(&mut cx, "anchor") {
+            if !anchor_value.is_a::<JsUndefined, _>(&mut cx) && !anchor_value.is_a::<JsNull, _>(&mut cx) {
+                let anchor_str = anchor_value.downcast::<JsString, _>(&mut cx).or_throw(&mut cx)?;
+                match anchor_str.value(&mut cx).as_str() {
+                    "prefix" => Some(waldb::TextAnchor::Prefix),
+                    "contains" => Some(waldb::TextAnchor::Contains),
+                    "exact" => Some(waldb::TextAnchor::Exact),
+                    other => return cx.throw_error(format!("Invalid text anchor: {}", other)),
+                }
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        
+        let field_weights = if let Ok(weights_value) = text_obj.get::<JsValue, _, _>(&mut cx, "fieldWeights") {
+            if !weights_value.is_a::<JsUndefined, _>(&mut cx) && !weights_value.is_a::<JsNull, _>(&mut cx) {
+                let weights_obj = weights_value.downcast::<JsObject, _>(&mut cx).or_throw(&mut cx)?;
+                let names = weights_obj.get_own_property_names(&mut cx)?;
+                let mut weights = std::collections::HashMap::new();
+                for i in 0..names.len(&mut cx) {
+                    let name: Handle<JsString> = names.get(&mut cx, i)?;
+                    let name = name.value(&mut cx);
+                    let weight: Handle<JsNumber> = weights_obj.get(&mut cx, name.as_str())?;
+                    weights.insert(name, weight.value(&mut cx) as f32);
+                }
+                Some(weights)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        
+            text_opts = Some(waldb::TextSearchOptions {
                 query: query.value(&mut cx),
                 fields,
                 case_sensitive,
+                anchor,
+                field_weights,
             });
         }
     }
@@ -731,7 +2094,7 @@ fn advanced_search(mut cx: FunctionContext) -> JsResult<JsPromise> {
             1.0
         };
         
-            scoring = Some(store::ScoringWeights { vector, text, filter });
+            scoring = Some(waldb::ScoringWeights { vector, text, filter });
         }
     }
     
@@ -747,67 +2110,99 @@ fn advanced_search(mut cx: FunctionContext) -> JsResult<JsPromise> {
         None
     };
     
-    let search_options = store::SearchOptions {
+    let order = options.get_opt::<JsString, _, _>(&mut cx, "orderBy")?.map(|s| s.value(&mut cx));
+    let order_by = order_arg(&mut cx, order)?;
+    
+    let search_options = waldb::SearchOptions {
         pattern,
         filters,
         vector: vector_opts,
         text: text_opts,
         scoring,
         limit,
+        order_by,
     };
     
-    let store_arc = Arc::clone(&store.store);
+    // Parse timeoutMs
+    let timeout_ms = options.get_opt::<JsNumber, _, _>(&mut cx, "timeoutMs")?.map(|ms| ms.value(&mut cx));
+    let timeout = timeout_ms.and_then(timeout_from_ms);
     
-    std::thread::spawn(move || {
-        let result = store_arc.search(search_options);
-        
-        deferred.settle_with(&channel, move |mut cx| {
-            match result {
-                Ok(groups) => {
-                    let js_array = cx.empty_array();
-                    
-                    for (i, (group_key, fields)) in groups.iter().enumerate() {
-                        let group_array = cx.empty_array();
-                        
-                        for (j, (key, value)) in fields.iter().enumerate() {
-                            let entry_array = cx.empty_array();
-                            let full_key = if group_key.is_empty() {
-                                key.clone()
-                            } else {
-                                format!("{}/{}", group_key, key)
-                            };
-                            let js_key = cx.string(full_key);
-                            let js_value = cx.string(value);
-                            entry_array.set(&mut cx, 0, js_key)?;
-                            entry_array.set(&mut cx, 1, js_value)?;
-                            group_array.set(&mut cx, j as u32, entry_array)?;
-                        }
-                        
-                        js_array.set(&mut cx, i as u32, group_array)?;
-                    }
-                    
-                    Ok(js_array)
-                }
-                Err(e) => cx.throw_error(format!("Advanced search failed: {}", e))
-            }
-        });
+    let keyspace = store.keyspace(Access::Read).with_timeout(timeout);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|keys| keys.search_cancellable(search_options, &keyspace.cancel))
+    }, move |mut cx, result| {
+        match result {
+            Ok(groups) => groups_to_js(&mut cx, &groups),
+            Err(e) => throw_store_error(&mut cx, "Advanced search failed", e)
+        }
     });
     
     Ok(promise)
 }
 
+// Test hook, exported only when WALDB_TEST_HOOKS is set: takes its place in line like a
+// write, then panics with message on its worker thread
+fn test_panic(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let store = cx.argument::<BoxedStore>(0)?;
+    let message = cx.argument::<JsString>(1)?.value(&mut cx);
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    
+    let keyspace = store.keyspace(Access::Write);
+    
+    spawn_worker(channel, deferred, Some(Arc::clone(&store.poisoned)), move || {
+        keyspace.with(|_| panic!("{}", message))
+    }, move |mut cx, ()| Ok(cx.undefined()));
+    
+    Ok(promise)
+}
+
 #[neon::main]
 fn main(mut cx: ModuleContext) -> NeonResult<()> {
     cx.export_function("open", open)?;
+    cx.export_function("openInMemory", open_in_memory)?;
+    cx.export_function("namespace", namespace)?;
+    cx.export_function("cancelAll", cancel_all)?;
+    cx.export_function("seqInfo", seq_info)?;
     cx.export_function("getEntries", get_entries)?;
+    cx.export_function("getRaw", get_raw)?;
+    cx.export_function("setRaw", set_raw)?;
+    cx.export_function("getEntriesRaw", get_entries_raw)?;
+    cx.export_function("has", has)?;
+    cx.export_function("mayContain", may_contain)?;
+    cx.export_function("increment", increment)?;
+    cx.export_function("push", push)?;
+    cx.export_function("popMin", pop_min)?;
+    cx.export_function("peekMin", peek_min)?;
+    cx.export_function("queueLength", queue_length)?;
+    cx.export_function("moveSubtree", move_subtree)?;
+    cx.export_function("copySubtree", copy_subtree)?;
+    cx.export_function("getJson", get_json)?;
     cx.export_function("set", set)?;
     cx.export_function("delete", delete)?;
     cx.export_function("setMany", set_many)?;
+    cx.export_function("getVersion", get_version)?;
+    cx.export_function("writeBatch", write_batch)?;
     cx.export_function("flush", flush)?;
+    cx.export_function("clearAll", clear_all)?;
+    cx.export_function("healthCheck", health_check)?;
+    cx.export_function("warmup", warmup)?;
+    cx.export_function("setQuota", set_quota)?;
+    cx.export_function("removeQuota", remove_quota)?;
+    cx.export_function("getQuotaUsage", get_quota_usage)?;
+    cx.export_function("setValidator", set_validator)?;
+    cx.export_function("pathKey", path_key)?;
+    cx.export_function("pathSegments", path_segments)?;
+    cx.export_function("removeValidator", remove_validator)?;
     cx.export_function("getPattern", get_pattern)?;
     cx.export_function("getRange", get_range)?;
     cx.export_function("getPatternEntries", get_pattern_entries)?;
+    cx.export_function("getPatternGrouped", get_pattern_grouped)?;
     cx.export_function("getRangeEntries", get_range_entries)?;
+    cx.export_function("scanOpen", scan_open)?;
+    cx.export_function("scanNext", scan_next)?;
+    cx.export_function("scanClose", scan_close)?;
     cx.export_function("setFile", set_file)?;
     cx.export_function("getFile", get_file)?;
     cx.export_function("deleteFile", delete_file)?;
@@ -815,6 +2210,106 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
     cx.export_function("setVector", set_vector)?;
     cx.export_function("getVector", get_vector)?;
     cx.export_function("advancedSearch", advanced_search)?;
+    if std::env::var_os("WALDB_TEST_HOOKS").is_some() {
+        cx.export_function("testPanic", test_panic)?;
+    }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn raw_values_round_trip() {
+        let cases: Vec<Vec<u8>> = vec![
+            b"plain text".to_vec(),
+            "ünïcødé ✓".as_bytes().to_vec(),
+            vec![0xff, 0xfe, 0x00, 0x80, b'a', 0xc3],  // Invalid UTF-8, including a cut-off sequence
+            vec![0xed, 0xa0, 0x80],                     // Encoded surrogate
+            b"\0bin:looks escaped".to_vec(),
+            Vec::new(),
+        ];
+        for bytes in cases {
+            assert_eq!(decode_raw(encode_raw(bytes.clone())), bytes);
+        }
+        
+        // Text is stored as is, so the string API reads it unchanged
+        assert_eq!(encode_raw(b"s:hello".to_vec()), "s:hello");
+        assert_eq!(decode_raw("\0bin:\u{263a}".to_string()), "\0bin:\u{263a}".as_bytes());
+    }
+    
+    fn set(value: &str) -> EntryOp {
+        EntryOp::Set(value.to_string())
+    }
+    
+    #[test]
+    fn set_many_flattens_nested_objects() {
+        let entries = vec![
+            ("user".to_string(), InputValue::Object(vec![
+                ("name".to_string(), InputValue::String("Ada".to_string())),
+                ("age".to_string(), InputValue::Number(36.0)),
+                ("address".to_string(), InputValue::Object(vec![
+                    ("city".to_string(), InputValue::String("London".to_string())),
+                    ("zip".to_string(), InputValue::Null),
+                ])),
+            ])),
+            ("active".to_string(), InputValue::Bool(true)),
+            ("stale".to_string(), InputValue::Null),
+        ];
+        assert_eq!(flatten_entries(entries).unwrap(), vec![
+            ("user/name".to_string(), set("Ada")),
+            ("user/age".to_string(), set("36")),
+            ("user/address/city".to_string(), set("London")),
+            ("user/address/zip".to_string(), EntryOp::Delete),
+            ("active".to_string(), set("true")),
+            ("stale".to_string(), EntryOp::Delete),
+        ]);
+        
+        // An empty object writes nothing
+        assert_eq!(flatten_entries(vec![("empty".to_string(), InputValue::Object(Vec::new()))]).unwrap(), Vec::new());
+    }
+    
+    #[test]
+    fn set_many_errors_name_the_path() {
+        let nested = |value: InputValue| vec![("a".to_string(), InputValue::Object(vec![("b".to_string(), value)]))];
+        
+        let error = flatten_entries(nested(InputValue::Array)).unwrap_err();
+        assert!(error.contains("\"a/b\" is an array"), "{}", error);
+        let error = flatten_entries(nested(InputValue::Number(f64::NAN))).unwrap_err();
+        assert!(error.contains("\"a/b\" is NaN"), "{}", error);
+        let error = flatten_entries(nested(InputValue::Number(f64::NEG_INFINITY))).unwrap_err();
+        assert!(error.contains("\"a/b\" is -Infinity"), "{}", error);
+        let error = flatten_entries(nested(InputValue::Unsupported("a function"))).unwrap_err();
+        assert!(error.contains("\"a/b\" is a function"), "{}", error);
+        let error = flatten_entries(vec![("list".to_string(), InputValue::Array)]).unwrap_err();
+        assert!(error.contains("\"list\""), "{}", error);
+    }
+    
+    #[test]
+    fn set_many_number_formatting_is_stable() {
+        let cases = [
+            (1.0, "1"),
+            (-0.0, "0"),
+            (0.1, "0.1"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (-42.5, "-42.5"),
+            (9007199254740993.0, "9007199254740992"),
+            (123456789012345680000.0, "123456789012345680000"),
+            (1e21, "1e+21"),
+            (1.5e300, "1.5e+300"),
+            (1e-6, "0.000001"),
+            (1e-7, "1e-7"),
+            (1.5e-8, "1.5e-8"),
+            (f64::MAX, "1.7976931348623157e+308"),
+            (5e-324, "5e-324"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(format_number(n).as_deref(), Some(expected), "{:?}", n);
+            // Reading the text back gives the same number
+            assert_eq!(expected.parse::<f64>().unwrap(), n);
+        }
+        assert_eq!(format_number(f64::INFINITY), None);
+    }
+}
@@ -0,0 +1,506 @@
+// The validators setValidator selects by name. JS functions can't run on the worker threads
+// writes validate on, so each is built here from its name and option.
+
+use waldb::ValueValidator;
+
+// Backtracking steps one regex test may take before the value is refused instead
+const REGEX_STEP_BUDGET: u64 = 1_000_000;
+
+// Nesting past this is refused by jsonParseable rather than risk the worker's stack
+const JSON_MAX_DEPTH: usize = 512;
+
+// What the JS option was, for the validators that take one
+pub enum ValidatorOption {
+    None,
+    Number(f64),
+    Text(String),
+}
+
+// The validator called name, or why it can't be made. It checks values as JS reads them, after
+// the type tag set() gives them, so numbers and booleans are checked as their text.
+pub fn builtin(name: &str, option: ValidatorOption) -> Result<ValueValidator, String> {
+    let check = checker(name, option)?;
+    Ok(Box::new(move |key, value| check(key, untagged(value))))
+}
+
+fn checker(name: &str, option: ValidatorOption) -> Result<ValueValidator, String> {
+    match (name, option) {
+        ("maxLength", ValidatorOption::Number(max)) => {
+            if max.fract() != 0.0 || !(0.0..=9007199254740991.0).contains(&max) {
+                return Err("maxLength needs a safe non-negative integer".to_string());
+            }
+            let max = max as usize;
+            // Counted as JS counts string length, in UTF-16 code units
+            Ok(Box::new(move |_, value| {
+                let len = value.encode_utf16().count();
+                if len > max { Err(format!("length {} is over maxLength {}", len, max)) } else { Ok(()) }
+            }))
+        }
+        ("maxLength", _) => Err("maxLength needs a number".to_string()),
+        ("jsonParseable", _) => Ok(Box::new(|_, value| check_json(value))),
+        ("regex", ValidatorOption::Text(pattern)) => {
+            let regex = Regex::parse(&pattern)?;
+            Ok(Box::new(move |_, value| {
+                match regex.test(value) {
+                    Some(true) => Ok(()),
+                    Some(false) => Err(format!("does not match /{}/", pattern)),
+                    None => Err(format!("took too long to match against /{}/", pattern)),
+                }
+            }))
+        }
+        ("regex", _) => Err("regex needs a pattern string".to_string()),
+        (name, _) => Err(format!("Unknown validator {:?}: expected maxLength, jsonParseable or regex", name)),
+    }
+}
+
+// value without the "s:", "n:", "b:" or "z:" set() puts before it
+fn untagged(value: &str) -> &str {
+    match value.as_bytes() {
+        [b's' | b'n' | b'b' | b'z', b':', ..] => &value[2..],
+        _ => value,
+    }
+}
+
+// ==================== JSON ====================
+
+// Ok if text is one JSON value, as JSON.parse would accept it
+fn check_json(text: &str) -> Result<(), String> {
+    let mut parser = JsonChecker { bytes: text.as_bytes(), pos: 0 };
+    parser.value(0)?;
+    parser.whitespace();
+    if parser.pos < parser.bytes.len() {
+        return Err(parser.error("unexpected text after the JSON value"));
+    }
+    Ok(())
+}
+
+struct JsonChecker<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl JsonChecker<'_> {
+    fn error(&self, what: &str) -> String {
+        format!("not JSON: {} at offset {}", what, self.pos)
+    }
+    
+    fn whitespace(&mut self) {
+        while matches!(self.bytes.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+    
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.bytes.get(self.pos) == Some(&byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+    
+    fn value(&mut self, depth: usize) -> Result<(), String> {
+        if depth > JSON_MAX_DEPTH {
+            return Err(self.error("nesting too deep"));
+        }
+        self.whitespace();
+        match self.bytes.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                self.whitespace();
+                if self.eat(b'}') {
+                    return Ok(());
+                }
+                loop {
+                    self.whitespace();
+                    self.string()?;
+                    self.whitespace();
+                    if !self.eat(b':') {
+                        return Err(self.error("expected ':'"));
+                    }
+                    self.value(depth + 1)?;
+                    self.whitespace();
+                    if self.eat(b'}') {
+                        return Ok(());
+                    }
+                    if !self.eat(b',') {
+                        return Err(self.error("expected ',' or '}'"));
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                self.whitespace();
+                if self.eat(b']') {
+                    return Ok(());
+                }
+                loop {
+                    self.value(depth + 1)?;
+                    self.whitespace();
+                    if self.eat(b']') {
+                        return Ok(());
+                    }
+                    if !self.eat(b',') {
+                        return Err(self.error("expected ',' or ']'"));
+                    }
+                }
+            }
+            Some(b'"') => self.string(),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => {
+                for word in [&b"true"[..], b"false", b"null"] {
+                    if self.bytes[self.pos..].starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(());
+                    }
+                }
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+    
+    fn string(&mut self) -> Result<(), String> {
+        if !self.eat(b'"') {
+            return Err(self.error("expected a string"));
+        }
+        loop {
+            match self.bytes.get(self.pos) {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.bytes.get(self.pos) {
+                        Some(b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => self.pos += 1,
+                        Some(b'u') if self.bytes.get(self.pos + 1..self.pos + 5).is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit)) => {
+                            self.pos += 5;
+                        }
+                        _ => return Err(self.error("bad escape")),
+                    }
+                }
+                Some(&byte) if byte < 0x20 => return Err(self.error("control character in string")),
+                Some(_) => self.pos += 1,
+            }
+        }
+    }
+    
+    fn number(&mut self) -> Result<(), String> {
+        self.eat(b'-');
+        if !self.eat(b'0') && self.digits() == 0 {
+            return Err(self.error("expected digits"));
+        }
+        if self.eat(b'.') && self.digits() == 0 {
+            return Err(self.error("expected digits after '.'"));
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            let _ = self.eat(b'+') || self.eat(b'-');
+            if self.digits() == 0 {
+                return Err(self.error("expected exponent digits"));
+            }
+        }
+        Ok(())
+    }
+    
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while self.bytes.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+}
+
+// ==================== REGEX ====================
+
+// The subset of JS regular expressions without flags that regex validators take: literals,
+// '.', classes like [a-z] and [^0-9], \d \w \s and their negations, groups, '|', the
+// quantifiers * + ? {n} {n,} {n,m}, and the anchors ^ and $. Like RegExp.test, a match
+// anywhere in the value passes unless anchored.
+struct Regex {
+    alternatives: Vec<Vec<Node>>,
+}
+
+enum Node {
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),  // Ranges, and whether it is negated
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+struct RegexParser<'p> {
+    chars: std::iter::Peekable<std::str::Chars<'p>>,
+}
+
+impl Regex {
+    fn parse(pattern: &str) -> Result<Regex, String> {
+        let mut parser = RegexParser { chars: pattern.chars().peekable() };
+        let alternatives = parser.alternatives()?;
+        if parser.chars.next().is_some() {
+            return Err("Bad regex: unmatched ')'".to_string());
+        }
+        Ok(Regex { alternatives })
+    }
+    
+    // Whether value matches, or None past REGEX_STEP_BUDGET
+    fn test(&self, value: &str) -> Option<bool> {
+        let chars: Vec<char> = value.chars().collect();
+        let mut matcher = Matcher { chars: &chars, steps: 0 };
+        for start in 0..=chars.len() {
+            if matcher.alternatives(&self.alternatives, start, &mut |_, _| true) {
+                return Some(true);
+            }
+            if matcher.steps > REGEX_STEP_BUDGET {
+                return None;
+            }
+        }
+        Some(false)
+    }
+}
+
+impl RegexParser<'_> {
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.chars.next_if_eq(&'|').is_some() {
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+    
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+        while let Some(&c) = self.chars.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            self.chars.next();
+            let node = match c {
+                '(' => {
+                    if self.chars.next_if_eq(&'?').is_some() && self.chars.next_if_eq(&':').is_none() {
+                        return Err("Bad regex: only (?:...) groups are supported".to_string());
+                    }
+                    let group = self.alternatives()?;
+                    if self.chars.next_if_eq(&')').is_none() {
+                        return Err("Bad regex: unterminated group".to_string());
+                    }
+                    Node::Group(group)
+                }
+                '[' => self.class()?,
+                '.' => Node::Any,
+                '^' => Node::Start,
+                '$' => Node::End,
+                '\\' => self.escape()?,
+                '*' | '+' | '?' | '{' => return Err(format!("Bad regex: nothing to repeat before '{}'", c)),
+                c => Node::Char(c),
+            };
+            nodes.push(self.quantified(node)?);
+        }
+        Ok(nodes)
+    }
+    
+    fn quantified(&mut self, node: Node) -> Result<Node, String> {
+        let (min, max) = match self.chars.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.chars.next();
+                let min = self.count().ok_or("Bad regex: expected a count after '{'")?;
+                let max = if self.chars.next_if_eq(&',').is_some() { self.count() } else { Some(min) };
+                if self.chars.next_if_eq(&'}').is_none() {
+                    return Err("Bad regex: unterminated {n,m}".to_string());
+                }
+                if max.is_some_and(|max| max < min) {
+                    return Err("Bad regex: {n,m} with m below n".to_string());
+                }
+                return Ok(Node::Repeat(Box::new(node), min, max));
+            }
+            _ => return Ok(node),
+        };
+        self.chars.next();
+        Ok(Node::Repeat(Box::new(node), min, max))
+    }
+    
+    fn count(&mut self) -> Option<usize> {
+        let mut digits = String::new();
+        while let Some(c) = self.chars.next_if(char::is_ascii_digit) {
+            digits.push(c);
+        }
+        digits.parse().ok()
+    }
+    
+    fn escape(&mut self) -> Result<Node, String> {
+        let c = self.chars.next().ok_or("Bad regex: trailing '\\'")?;
+        Ok(match shorthand_class(c) {
+            Some((ranges, negated)) => Node::Class(ranges, negated),
+            None => Node::Char(escaped_char(c)),
+        })
+    }
+    
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.chars.next_if_eq(&'^').is_some();
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.chars.next().ok_or("Bad regex: unterminated [...]")?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let low = match c {
+                '\\' => {
+                    let c = self.chars.next().ok_or("Bad regex: trailing '\\'")?;
+                    if let Some((shorthand, false)) = shorthand_class(c) {
+                        ranges.extend(shorthand);
+                        continue;
+                    }
+                    if shorthand_class(c).is_some() {
+                        return Err("Bad regex: \\D, \\W and \\S aren't supported inside [...]".to_string());
+                    }
+                    escaped_char(c)
+                }
+                c => c,
+            };
+            let high = match self.chars.peek() {
+                Some('-') => {
+                    self.chars.next();
+                    match self.chars.next_if(|&c| c != ']') {
+                        Some('\\') => escaped_char(self.chars.next().ok_or("Bad regex: trailing '\\'")?),
+                        Some(high) => high,
+                        None => {
+                            ranges.push(('-', '-'));
+                            low
+                        }
+                    }
+                }
+                _ => low,
+            };
+            if high < low {
+                return Err(format!("Bad regex: range {}-{} out of order", low, high));
+            }
+            ranges.push((low, high));
+        }
+        Ok(Node::Class(ranges, negated))
+    }
+}
+
+// The ranges \d, \w and \s stand for, and whether it is their negation \D, \W or \S
+fn shorthand_class(c: char) -> Option<(Vec<(char, char)>, bool)> {
+    let ranges = match c.to_ascii_lowercase() {
+        'd' => vec![('0', '9')],
+        'w' => vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')],
+        's' => vec![(' ', ' '), ('\t', '\r'), ('\u{a0}', '\u{a0}'), ('\u{2028}', '\u{2029}'), ('\u{feff}', '\u{feff}')],
+        _ => return None,
+    };
+    Some((ranges, c.is_ascii_uppercase()))
+}
+
+fn escaped_char(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        'f' => '\u{c}',
+        'v' => '\u{b}',
+        '0' => '\0',
+        c => c,
+    }
+}
+
+struct Matcher<'v> {
+    chars: &'v [char],
+    steps: u64,
+}
+
+impl Matcher<'_> {
+    // Whether one of alternatives matches from pos with then accepting where it ends
+    fn alternatives(&mut self, alternatives: &[Vec<Node>], pos: usize, then: &mut dyn FnMut(&mut Self, usize) -> bool) -> bool {
+        alternatives.iter().any(|sequence| self.sequence(sequence, pos, then))
+    }
+    
+    fn sequence(&mut self, nodes: &[Node], pos: usize, then: &mut dyn FnMut(&mut Self, usize) -> bool) -> bool {
+        match nodes.split_first() {
+            None => then(self, pos),
+            Some((node, rest)) => self.node(node, pos, &mut |matcher, pos| matcher.sequence(rest, pos, then)),
+        }
+    }
+    
+    fn node(&mut self, node: &Node, pos: usize, then: &mut dyn FnMut(&mut Self, usize) -> bool) -> bool {
+        self.steps += 1;
+        if self.steps > REGEX_STEP_BUDGET {
+            return false;
+        }
+        let next = self.chars.get(pos).copied();
+        match node {
+            Node::Char(c) => next == Some(*c) && then(self, pos + 1),
+            Node::Any => next.is_some_and(|c| !matches!(c, '\n' | '\r' | '\u{2028}' | '\u{2029}')) && then(self, pos + 1),
+            Node::Class(ranges, negated) => {
+                next.is_some_and(|c| ranges.iter().any(|&(low, high)| (low..=high).contains(&c)) != *negated) && then(self, pos + 1)
+            }
+            Node::Start => pos == 0 && then(self, pos),
+            Node::End => pos == self.chars.len() && then(self, pos),
+            Node::Group(alternatives) => self.alternatives(alternatives, pos, then),
+            Node::Repeat(node, min, max) => self.repeat(node, *min, *max, 0, pos, then),
+        }
+    }
+    
+    // Greedy: as many more of node as will still let the rest match, then fewer
+    fn repeat(&mut self, node: &Node, min: usize, max: Option<usize>, count: usize, pos: usize,
+              then: &mut dyn FnMut(&mut Self, usize) -> bool) -> bool {
+        if max.is_none_or(|max| count < max) {
+            // An empty match past min could repeat forever, so it ends the run
+            let more = self.node(node, pos, &mut |matcher, next| {
+                (next != pos || count < min) && matcher.repeat(node, min, max, count + 1, next, then)
+            });
+            if more {
+                return true;
+            }
+        }
+        count >= min && then(self, pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn json_checker_agrees_with_json_parse() {
+        for good in ["0", "-1.5e+3", " \"a\\u00e9\\n\" ", "[]", "{}", "[1, [2, {\"a\": null}], true, false]", "{\"a\":{\"b\":[]}}"] {
+            assert!(check_json(good).is_ok(), "{}", good);
+        }
+        for bad in ["", "01", "1.", "-", "[1,]", "{\"a\"}", "{a: 1}", "'a'", "\"\\x\"", "\"a\nb\"", "tru", "[] []", "NaN"] {
+            assert!(check_json(bad).is_err(), "{}", bad);
+        }
+        let deep = "[".repeat(JSON_MAX_DEPTH + 2) + &"]".repeat(JSON_MAX_DEPTH + 2);
+        assert!(check_json(&deep).is_err());
+    }
+    
+    #[test]
+    fn regex_matches_like_regexp_test() {
+        let cases = [
+            ("^[a-z]+$", "abc", true), ("^[a-z]+$", "abC", false), ("^[a-z]+$", "", false),
+            ("b", "abc", true), ("^\\d{3}-\\d{4}$", "555-1234", true), ("^\\d{3}-\\d{4}$", "555-123", false),
+            ("^(cat|dog)s?$", "dogs", true), ("^(?:cat|dog)s?$", "cow", false), ("^a.c$", "a\\nc", false),
+            ("^[^0-9]*$", "no digits", true), ("^[^0-9]*$", "d1", false), ("^\\w+@\\w+\\.com$", "a_1@b.com", true),
+            ("^a{2,}$", "a", false), ("^a{2,}$", "aaaa", true), ("^(a*)*b$", "aaab", true), ("^[-a]+$", "a-a", true),
+            ("x?$", "anything", true), ("^\\S+$", "no spaces", false),
+        ];
+        for (pattern, value, expected) in cases {
+            let value = value.replace("\\n", "\n");
+            assert_eq!(Regex::parse(pattern).unwrap().test(&value), Some(expected), "/{}/ on {:?}", pattern, value);
+        }
+        for bad in ["(a", "a)", "[a", "*a", "a{2", "a{3,1}", "[z-a]", "(?=a)", "\\"] {
+            assert!(Regex::parse(bad).is_err(), "{}", bad);
+        }
+    }
+    
+    #[test]
+    fn catastrophic_patterns_give_up() {
+        let regex = Regex::parse("^(a|a)*$").unwrap();
+        assert_eq!(regex.test(&("a".repeat(40) + "b")), None);
+    }
+}
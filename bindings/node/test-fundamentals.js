@@ -1,7 +1,9 @@
 #!/usr/bin/env node
 
 // Fundamental behavior tests - make sure basics work correctly
+process.env.WALDB_TEST_HOOKS = '1';  // Read when the native module loads, for testPanic
 const WalDB = require('./index.js');
+const native = require('./index.node');
 const assert = require('assert');
 const fs = require('fs');
 const path = require('path');
@@ -389,6 +391,548 @@ async function runTests() {
         assert.strictEqual(await db.getObject(longKey), 'value');
     });
 
+    // Test 16: Write batch
+    await test('Write batch', async () => {
+        const db = await WalDB.open(testDir + '/write_batch');
+        
+        await db.set('users/alice', { name: 'Alice', age: 30 });
+        await db.writeBatch([
+            { type: 'put', key: 'users/bob', value: { name: 'Bob', active: true } },
+            { type: 'deleteSubtree', key: 'users/alice' },
+            { type: 'put', key: 'users/alice/name', value: 'Alice Smith' },
+            { type: 'put', key: 'count', value: 2 },
+            { type: 'delete', key: 'count' },
+        ]);
+        
+        assert.deepStrictEqual(await db.getObject('users/alice'), { name: 'Alice Smith' });
+        assert.deepStrictEqual(await db.getObject('users/bob'), { name: 'Bob', active: true });
+        assert.strictEqual(await db.getObject('count'), null);
+        
+        // A tree violation rejects the whole batch
+        await assert.rejects(
+            db.writeBatch([
+                { type: 'put', key: 'other', value: 'x' },
+                { type: 'put', key: 'users/bob/name/first', value: 'B' },
+            ]),
+            err => err.name === 'WalDbTreeSemanticsError'
+        );
+        assert.strictEqual(await db.getObject('other'), null);
+    });
+
+    // Test 17: Durable writes
+    await test('Durable writes', async () => {
+        const db = await WalDB.open(testDir + '/durable');
+        
+        await db.set('config/mode', 'strict', { durable: true });
+        await db.set('config/limits', { max: 10 }, false, { durable: true });
+        assert.strictEqual(await db.getObject('config/mode'), 'strict');
+        assert.deepStrictEqual(await db.getObject('config/limits'), { max: 10 });
+    });
+    
+    // Test 18: has() without fetching values
+    await test('Has', async () => {
+        const db = await WalDB.open(testDir + '/has');
+        
+        await db.set('users/alice', { name: 'Alice', bio: 'x'.repeat(10000) });
+        await db.set('flag', false);
+        assert.strictEqual(await db.has('users/alice/bio'), true);
+        assert.strictEqual(await db.has('flag'), true);
+        assert.strictEqual(await db.has('users/alice'), false);  // An object, not a scalar
+        assert.strictEqual(await db.has('users/bob'), false);
+        
+        await db.delete('users/alice');
+        assert.strictEqual(await db.has('users/alice/name'), false);
+    });
+    
+    // Test 19: Subtree as JSON text
+    await test('Get JSON', async () => {
+        const db = await WalDB.open(testDir + '/get_json');
+        
+        await db.set('doc/title', 'say "hi"\n');
+        await db.set('doc/meta/count', 3);
+        assert.deepStrictEqual(JSON.parse(await db.getJson('doc')), {
+            meta: { count: 'n:3' },
+            title: 's:say "hi"\n',
+        });
+        assert.strictEqual(await db.getJson('missing'), null);
+        
+        // Prefixed values are never bare literals, so detectTypes leaves them quoted
+        await db.set('raw', { flag: true });
+        const typed = JSON.parse(await db.getJson('raw', { detectTypes: true }));
+        assert.strictEqual(typed.flag, 'b:true');
+    });
+    
+    // Test 20: Atomic counters
+    await test('Increment', async () => {
+        const db = await WalDB.open(testDir + '/increment');
+        
+        await Promise.all(Array.from({ length: 100 }, () => db.increment('stats/views')));
+        assert.strictEqual(await db.increment('stats/views', 0), 100);
+        assert.strictEqual(await db.increment('stats/views', -150), -50);
+        
+        // set() stores "n:5", which is not a plain integer
+        await db.set('typed', 5);
+        await assert.rejects(db.increment('typed'), err => err.name === 'WalDbNotAnIntegerError');
+        await assert.rejects(db.increment('stats/views', 1.5), TypeError);
+    });
+    
+    // Test 21: Namespaces
+    await test('Namespaces', async () => {
+        const db = await WalDB.open(testDir + '/namespaces');
+        const a = db.namespace('a');
+        const b = db.namespace('a/b');
+        
+        await a.set('user/1', 'in a');
+        await b.set('user/1', 'in b');
+        await db.set('user/1', 'plain');
+        assert.strictEqual(await a.getObject('user/1'), 'in a');
+        assert.strictEqual(await b.getObject('user/1'), 'in b');
+        assert.deepStrictEqual(await a.getPatternEntries('user/*'), [['user/1', 'in a']]);
+        
+        await a.delete('');
+        assert.strictEqual(await a.getObject('user/1'), null);
+        assert.strictEqual(await b.getObject('user/1'), 'in b');
+        assert.strictEqual(await db.getObject('user/1'), 'plain');
+        await assert.rejects(db.set('\u0000a/user/1', 'x'), err => err.name === 'WalDbInvalidKeyError');
+    });
+    
+    // Test 22: Cancelling long scans
+    await test('Scan Cancellation', async () => {
+        const db = await WalDB.open(testDir + '/cancel');
+        for (let batch = 0; batch < 20; batch++) {
+            const users = {};
+            for (let i = 0; i < 5000; i++) users[`u${i}`] = { name: 'x' };
+            await db.set(`users/${batch}`, users);
+            await db.flush();
+        }
+        
+        // A leading * reads every block, far longer than the timeout
+        await assert.rejects(db.getPattern('*sessions*', { timeoutMs: 10 }), err => err.name === 'WalDbCancelledError');
+        
+        const started = Date.now();
+        const scan = db.namespace('other').getPattern('*sessions*');
+        const plain = db.getPattern('*sessions*');
+        setTimeout(() => db.cancelAll(), 10);
+        await assert.rejects(scan, err => err.name === 'WalDbCancelledError');
+        await assert.rejects(plain, err => err.name === 'WalDbCancelledError');
+        assert.ok(Date.now() - started < 1000, 'Cancelled scans should stop promptly');
+        
+        // Later calls are unaffected
+        assert.strictEqual(await db.getObject('users/19/u4999/name'), 'x');
+        assert.deepStrictEqual(await db.getPattern('users/3/u1?/name'), Object.fromEntries(
+            Array.from({ length: 10 }, (_, i) => [`users/3/u1${i}/name`, 'x'])));
+    });
+    
+    // Test 23: Search groups with an empty group key
+    await test('Search Empty Group Key', async () => {
+        const db = await WalDB.open(testDir + '/search-root');
+        await db.set('/lead/name', 'Ada');
+        await db.set('/lead/role', 'admin');
+        
+        // Depth-1 pattern: the group key is "" and every full key keeps its leading '/'
+        const expected = [[['/lead/name', 'Ada'], ['/lead/role', 'admin']]];
+        assert.deepStrictEqual(await db.search({ pattern: '*' }), expected);
+        assert.deepStrictEqual(await db.advancedSearch({ pattern: '*' }), expected);
+    });
+    
+    // Test 23b: Search order and limit
+    await test('Search Order', async () => {
+        const db = await WalDB.open(testDir + '/search-order');
+        for (let i = 1; i <= 5; i++) {
+            await db.set(`users/u${i}`, { plan: i % 2 ? 'pro' : 'free' });
+        }
+        const keys = groups => groups.map(group => group[0][0]);
+        const pro = [{ field: 'plan', op: '==', value: 'pro' }];
+        
+        assert.deepStrictEqual(keys(await db.search({ pattern: 'users/*', limit: 2 })), ['users/u1/plan', 'users/u2/plan']);
+        assert.deepStrictEqual(keys(await db.search({ pattern: 'users/*', filters: pro, limit: 2, orderBy: 'keyDesc' })),
+            ['users/u5/plan', 'users/u3/plan']);
+        assert.deepStrictEqual(keys(await db.advancedSearch({ pattern: 'users/*', limit: 1, orderBy: 'keyDesc' })), ['users/u5/plan']);
+        await assert.rejects(db.search({ pattern: 'users/*', orderBy: 'newest' }), /Invalid orderBy/);
+    });
+    
+    // Test 24: Moving a subtree
+    await test('Move Subtree', async () => {
+        const db = await WalDB.open(testDir + '/move');
+        await db.set('users/alice', { name: 'Alice', city: 'Oslo' });
+        await db.set('archive/alice', { stale: true });
+        
+        assert.strictEqual(await db.moveSubtree('users/alice', 'archive/alice'), 2);
+        assert.deepStrictEqual(await db.getObject('archive/alice'), { name: 'Alice', city: 'Oslo' });
+        assert.strictEqual(await db.getObject('users/alice'), null);
+        assert.strictEqual(await db.moveSubtree('users/alice', 'users/bob'), 0);
+        
+        await assert.rejects(db.moveSubtree('archive', 'archive/alice/old'), err => err.name === 'WalDbInvalidKeyError');
+        
+        // Namespaced moves stay inside the namespace
+        const ns = db.namespace('tenant');
+        await ns.set('docs/1', 'x');
+        assert.strictEqual(await ns.moveSubtree('docs', 'old'), 1);
+        assert.strictEqual(await ns.getObject('old/1'), 'x');
+        assert.strictEqual(await db.getObject('old/1'), null);
+    });
+    
+    // Test 24b: Copying a subtree
+    await test('Copy Subtree', async () => {
+        const db = await WalDB.open(testDir + '/copy');
+        await db.set('templates/default', { theme: 'dark', limits: { users: 5 } });
+        await db.set('tenants/acme/theme', 'light');
+        
+        await assert.rejects(db.copySubtree('templates/default', 'tenants/acme'), err => err.name === 'WalDbAlreadyExistsError');
+        assert.strictEqual(await db.getObject('tenants/acme/limits/users'), null);
+        assert.strictEqual(await db.copySubtree('templates/default', 'tenants/acme', 'skip'), 1);
+        assert.deepStrictEqual(await db.getObject('tenants/acme'), { theme: 'light', limits: { users: 5 } });
+        assert.strictEqual(await db.copySubtree('templates/default', 'tenants/acme', 'replace'), 2);
+        assert.strictEqual(await db.getObject('tenants/acme/theme'), 'dark');
+        assert.deepStrictEqual(await db.getObject('templates/default'), { theme: 'dark', limits: { users: 5 } });
+        
+        await assert.rejects(db.copySubtree('templates', 'templates/default/copy'), err => err.name === 'WalDbInvalidKeyError');
+        await assert.rejects(db.copySubtree('templates', 'copy', 'merge'), TypeError);
+    });
+    
+    // Test 25: Byte values
+    await test('Byte Values', async () => {
+        const db = await WalDB.open(testDir + '/bytes');
+        const binary = Buffer.from([0xff, 0xfe, 0x00, 0x80, 0x61, 0xc3]);
+        
+        await db.setBytes('blobs/bin', binary);
+        await db.setBytes('blobs/text', Buffer.from('héllo'));
+        assert.deepStrictEqual(await db.getBytes('blobs/bin'), binary);
+        assert.strictEqual(await db.getBytes('blobs/missing'), null);
+        assert.deepStrictEqual(await db.getBytesEntries('blobs'), [
+            ['blobs/bin', binary],
+            ['blobs/text', Buffer.from('héllo')],
+        ]);
+        
+        // Text goes in as is, so the string API sees it; set() values show their prefix
+        assert.deepStrictEqual(await db.getRaw('blobs/text'), [['blobs/text', 'héllo']]);
+        assert.deepStrictEqual(await db.getRaw('blobs/bin'), [['blobs/bin', '\0bin:' + binary.toString('base64')]]);
+        await db.set('typed', 'hi');
+        assert.deepStrictEqual(await db.getBytes('typed'), Buffer.from('s:hi'));
+    });
+    
+    // Test 26: Set options
+    await test('Set Options', async () => {
+        const db = await WalDB.open(testDir + '/set_options');
+        
+        // The legacy boolean still means replaceSubtree
+        await db.set('r/child', 'old');
+        await db.set('r', 'v', true);
+        assert.deepStrictEqual(await db.get('r/child'), []);
+        
+        // replaceSubtree alone doesn't lift the scalar parent check
+        await db.set('p', 'scalar');
+        await assert.rejects(
+            db.set('p/c', 'x', { replaceSubtree: true }),
+            err => err.name === 'WalDbTreeSemanticsError'
+        );
+        
+        // convertScalarParent drops the parent's value so the child can be written
+        await db.set('p/c', 'x', { convertScalarParent: true });
+        assert.strictEqual(await db.getObject('p/c'), 'x');
+        assert.strictEqual(await db.has('p'), false);
+        
+        // Both together, on top of an existing grandchild
+        await db.set('q/c/deep', 1);
+        await db.set('q', 'scalar', true);
+        await db.set('q/c', 'y', { replaceSubtree: true, convertScalarParent: true });
+        assert.strictEqual(await db.getObject('q/c'), 'y');
+        assert.deepStrictEqual(await db.get('q/c/deep'), []);
+        
+        await assert.rejects(db.set('q/c/obj', { a: 1 }, { convertScalarParent: true }), TypeError);
+    });
+    
+    // Test 27: Warmup
+    await test('Warmup', async () => {
+        const db = await WalDB.open(testDir + '/warmup');
+        for (let i = 0; i < 100; i++) {
+            await db.set(`items/${i}`, 'x'.repeat(100));
+        }
+        await db.flush();
+        
+        const empty = await db.warmup({ maxBytes: 0 });
+        assert.deepStrictEqual(empty, { bytesLoaded: 0, blocksLoaded: 0, bytesPinned: 0, complete: false });
+        const report = await db.warmup({ prefixes: ['items/'] });
+        assert.ok(report.complete && report.bytesLoaded > 0 && report.blocksLoaded > 0);
+        assert.strictEqual((await db.warmup({ levels: [0], prefixes: ['items/'] })).bytesLoaded, 0);
+    });
+    
+    // Test 28: Strict reads
+    await test('Strict reads name the scalar ancestor', async () => {
+        const db = await WalDB.open(testDir + '/strict', { strictReads: true });
+        await db.set('config', 'v1');
+        
+        await assert.rejects(
+            () => db.get('config/child/leaf'),
+            err => err.name === 'WalDbTreeSemanticsError' && err.message.includes('config holds a scalar value')
+        );
+        await assert.rejects(() => db.getRaw('config/'), err => err.name === 'WalDbTreeSemanticsError');
+        await assert.rejects(() => db.has('config/child'), err => err.name === 'WalDbTreeSemanticsError');
+        assert.deepStrictEqual(await db.get('other/child'), []);
+        
+        // Without the option the same read just misses
+        const lenient = await WalDB.open(testDir + '/lenient');
+        await lenient.set('config', 'v1');
+        assert.deepStrictEqual(await lenient.get('config/child'), []);
+    });
+    
+    // Test 29: Native setMany with non-string values
+    await test('Native setMany coerces primitives and names bad paths', async () => {
+        const native = require('./index.node');
+        const db = await WalDB.open(testDir + '/set-many-native');
+        const stored = async key => {
+            const bytes = await db.getBytes(key);
+            return bytes === null ? null : bytes.toString();
+        };
+        
+        await native.setMany(db._store, { n: 1.0, f: 0.1, big: 1e21, b: false, doc: { title: 'x', meta: { v: 2 } } });
+        assert.strictEqual(await stored('n'), '1');
+        assert.strictEqual(await stored('f'), '0.1');
+        assert.strictEqual(await stored('big'), '1e+21');
+        assert.strictEqual(await stored('b'), 'false');
+        assert.strictEqual(await stored('doc/meta/v'), '2');
+        
+        // null and undefined delete, in the same batch as the sets
+        await native.setMany(db._store, { b: null, doc: undefined, n: 2 });
+        assert.strictEqual(await stored('b'), null);
+        assert.strictEqual(await stored('doc/title'), null);
+        assert.strictEqual(await stored('n'), '2');
+        
+        assert.throws(() => native.setMany(db._store, { a: { list: [1] } }),
+            err => err instanceof TypeError && err.message.includes('"a/list" is an array'));
+        assert.throws(() => native.setMany(db._store, { a: NaN }),
+            err => err instanceof TypeError && err.message.includes('"a" is NaN'));
+    });
+    
+    // Test 30: Seqs for cache invalidation
+    await test('Writes resolve with seqs that seqInfo tracks', async () => {
+        const db = await WalDB.open(testDir + '/seq-info');
+        const first = await db.set('a', 'one');
+        const second = await db.set('doc', { title: 'x' });
+        const deleted = await db.delete('a');
+        assert.ok(first < second && second < deleted);
+        
+        let info = db.seqInfo();
+        assert.strictEqual(info.seq, deleted);
+        assert.ok(info.durableSeq <= info.seq && info.flushedSeq <= info.seq);
+        
+        await db.set('b', 'two', { durable: true });
+        await db.flush();
+        info = db.seqInfo();
+        assert.strictEqual(info.flushedSeq, info.seq);
+        assert.ok(info.flushedSeq <= info.durableSeq);
+    });
+    
+    // Test 31: Clearing everything takes clearAll
+    await test('clearAll empties the database, delete of the root refuses to', async () => {
+        const db = await WalDB.open(testDir + '/clear-all');
+        await db.set('users/alice', { name: 'Alice' });
+        await db.namespace('t').set('config', 'x');
+        await assert.rejects(db.delete(''), err => err.message.includes('clear_all'));
+        assert.strictEqual(await db.getObject('users/alice/name'), 'Alice');
+        
+        const seq = await db.clearAll();
+        assert.strictEqual(db.seqInfo().seq, seq);
+        assert.strictEqual(await db.getObject('users/alice/name'), null);
+        assert.strictEqual(await db.namespace('t').getObject('config'), null);
+        
+        // Later writes are newer than the tombstone; a namespace clears only itself
+        await db.set('users/bob', 'Bob');
+        await db.namespace('t').set('config', 'y');
+        await db.namespace('t').clearAll();
+        assert.strictEqual(await db.getObject('users/bob'), 'Bob');
+        assert.strictEqual(await db.namespace('t').getObject('config'), null);
+    });
+    
+    // Test 32: Pattern matches grouped by entity
+    await test('getPatternGrouped groups matches under their entity', async () => {
+        const db = await WalDB.open(testDir + '/pattern-grouped');
+        await db.set('users/1', { name: 'Alice', city: 'Paris/Lyon' });
+        await db.set('users/2/name', 'Bob');
+        await db.set('users/3', 'scalar');
+        
+        assert.deepStrictEqual(await db.getPatternGrouped('users/*', 2), [
+            ['users/1', [['city', 'Paris/Lyon'], ['name', 'Alice']]],
+            ['users/2', [['name', 'Bob']]],
+        ]);
+        assert.deepStrictEqual(await db.getPatternGrouped('users/*', 2, { limit: 1 }),
+            [['users/1', [['city', 'Paris/Lyon'], ['name', 'Alice']]]]);
+        assert.deepStrictEqual(await db.namespace('t').getPatternGrouped('users/*', 2), []);
+    });
+    
+    // Test 33: A panic on a worker thread rejects instead of hanging or aborting
+    await test('A panic rejects its promise and poisons the store', async () => {
+        const db = await WalDB.open(testDir + '/panic');
+        await db.set('kept', 'yes');
+        
+        await assert.rejects(native.testPanic(db._store, 'injected failure'),
+            err => err.name === 'WalDbPanicError' && err.message.includes('injected failure'));
+        
+        // Every later call on the store or its namespaces fails fast, async or not
+        const poisoned = { name: 'WalDbPoisonedError', message: 'store is poisoned after internal error' };
+        await assert.rejects(db.get('kept'), poisoned);
+        await assert.rejects(db.set('after', 'x'), poisoned);
+        await assert.rejects(db.namespace('t').getObject('kept'), poisoned);
+        assert.throws(() => db.seqInfo(), poisoned);
+        
+        // Other stores are unaffected
+        const other = await WalDB.open(testDir + '/panic-other');
+        await other.set('a', '1');
+        assert.strictEqual(await other.getObject('a'), '1');
+    });
+    
+    // Test 34: Optimistic concurrency on whole objects
+    await test('set() with ifVersion lets one of two racing writers win', async () => {
+        const db = await WalDB.open(testDir + '/versions');
+        await db.set('users/1', { name: 'Alice', visits: 1 });
+        await db.set('users/10', { name: 'Bob' });
+        
+        // Both clients read the object at the same version; only the first write lands
+        for (let round = 0; round < 5; round++) {
+            const version = await db.getVersion('users/1');
+            assert.ok(version > 0);
+            const results = await Promise.all([
+                db.set('users/1', { name: 'A', round }, { ifVersion: version }),
+                db.set('users/1', { name: 'B', round }, { ifVersion: version }),
+            ]);
+            assert.strictEqual(results.filter(Boolean).length, 1);
+            assert.strictEqual(await db.getObject('users/1/round'), round);
+            if (round === 2) {
+                await db.flush();
+            }
+        }
+        
+        // A sibling's write doesn't move the version; a flush doesn't either
+        const version = await db.getVersion('users/1');
+        await db.set('users/10/name', 'Robert');
+        await db.flush();
+        assert.strictEqual(await db.getVersion('users/1'), version);
+        await db.delete('users/1/round');
+        assert.strictEqual(await db.set('users/1', { name: 'Stale' }, { ifVersion: version }), false);
+        assert.notStrictEqual(await db.getObject('users/1/name'), 'Stale');
+        
+        await assert.rejects(db.set('users/1/name', 'x', { ifVersion: version }), TypeError);
+    });
+    
+    await test('openInMemory behaves like a store on disk and leaves no files', async () => {
+        const db = await WalDB.openInMemory({ strictReads: true });
+        await db.set('users/alice', { name: 'Alice', age: 30 });
+        await db.flush();
+        await db.delete('users/alice/age');
+        await db.set('users/bob/name', 'Bob');
+        assert.deepStrictEqual(await db.getObject('users'), { alice: { name: 'Alice' }, bob: { name: 'Bob' } });
+        await assert.rejects(db.get('users/alice/name/first'));
+        
+        // Each one is its own store
+        const other = await WalDB.openInMemory();
+        assert.strictEqual(await other.getObject('users'), null);
+    });
+    
+    await test('setQuota rejects writes past the cap and counts deletes', async () => {
+        const db = await WalDB.openInMemory();
+        await db.setQuota('tenants/acme', 40);
+        await db.set('tenants/acme/a', 'x'.repeat(20));  // 14 bytes of path, 22 of 's:'-tagged value
+        assert.deepStrictEqual(await db.getQuotaUsage('tenants/acme'), { usedBytes: 36, maxBytes: 40 });
+        await assert.rejects(db.set('tenants/acme/b', 'x'), { name: 'WalDbQuotaExceededError' });
+        assert.strictEqual(await db.has('tenants/acme/b'), false);
+        await db.set('tenants/other/b', 'x'.repeat(100));
+        
+        await db.delete('tenants/acme/a');
+        await db.set('tenants/acme/b', 'x');
+        assert.strictEqual((await db.getQuotaUsage('tenants/acme')).usedBytes, 17);
+        assert.strictEqual(await db.removeQuota('tenants/acme'), true);
+        assert.strictEqual(await db.getQuotaUsage('tenants/acme'), null);
+    });
+    
+    await test('setValidator refuses whole writes and leaves deletes alone', async () => {
+        const db = await WalDB.openInMemory();
+        await assert.rejects(db.setValidator('users', 'maxLength'), TypeError);
+        await assert.rejects(db.setValidator('users', 'regex', '(a'), TypeError);
+        await assert.rejects(db.setValidator('users', 'unknown'), TypeError);
+        
+        await db.set('users/1/bio', 'written before any validator');
+        await db.setValidator('users', 'maxLength', 12);
+        await db.setValidator('users/1/names', 'regex', '^[a-z ]+$');
+        await db.setValidator('users/1/prefs', 'jsonParseable');
+        await db.set('users/1/names/first', 'ada');
+        await db.set('users/2/age', 1234);
+        await assert.rejects(db.set('users/1/names/first', 'Ada'), { name: 'WalDbValidationFailedError' });
+        await assert.rejects(db.set('users/1/names/last', 'a very long name'), { name: 'WalDbValidationFailedError' });
+        await assert.rejects(db.set('users/1', { names: { first: 'bob' }, prefs: { theme: '{"x":' } }), { name: 'WalDbValidationFailedError' });
+        assert.strictEqual(await db.getObject('users/1/names/first'), 'ada');
+        await db.set('users/1/prefs/theme', '{"x":[1]}');
+        
+        assert.strictEqual(await db.getObject('users/1/bio'), 'written before any validator');
+        await db.delete('users/1/bio');
+        assert.strictEqual(await db.removeValidator('users/1/names'), true);
+        assert.strictEqual(await db.removeValidator('users/1/names'), false);
+        await db.set('users/1/names/first', 'Ada');
+    });
+    
+    await test('setPath keeps slashes inside ids from splitting the key', async () => {
+        const db = await WalDB.openInMemory();
+        const ids = ['a/b', 'a', 'b', '.', '..', '', '李/明', '100%'];
+        for (const id of ids) {
+            await db.setPath(['users', id, 'name'], `name of ${id}`);
+        }
+        for (const id of ids) {
+            assert.strictEqual(await db.getPath(['users', id, 'name']), `name of ${id}`);
+        }
+        assert.strictEqual(WalDB.pathKey(['users', 'a/b']), 'users/a%2Fb');
+        assert.deepStrictEqual(WalDB.pathSegments('users/a%2Fb'), ['users', 'a/b']);
+        assert.throws(() => WalDB.pathKey([]), { name: 'WalDbInvalidKeyError' });
+        
+        const seen = [];
+        for await (const [segments, value] of db.scanPath(['users'])) {
+            assert.strictEqual(value, `name of ${segments[1]}`);
+            seen.push(segments[1]);
+        }
+        assert.deepStrictEqual(seen.sort(), [...ids].sort());
+        
+        await db.deletePath(['users', 'a/b', 'name']);
+        assert.strictEqual(await db.getPath(['users', 'a/b', 'name']), null);
+        assert.strictEqual(await db.getPath(['users', 'a', 'name']), 'name of a');
+    });
+        
+    await test('get with maxBlockReads rejects rather than read past the budget', async () => {
+        const db1 = await WalDB.open(testDir + '/budget');
+        await db1.set('users/1/name', 'Ada');
+        await db1.flush();
+        
+        // A fresh handle starts with nothing cached
+        const db = await WalDB.open(testDir + '/budget');
+        await assert.rejects(db.get('users/1/name', { maxBlockReads: 0 }), (error) => {
+            assert.strictEqual(error.name, 'WalDbBudgetExceededError');
+            assert.strictEqual(error.segmentsProbed, 1);
+            assert.strictEqual(error.blockReads, 0);
+            assert.strictEqual(error.candidate, null);
+            return true;
+        });
+        assert.deepStrictEqual(await db.get('users/1/name', { maxBlockReads: 1 }), await db.get('users/1/name'));
+        assert.strictEqual(await db.getObject('users/1/name', { maxBlockReads: 0 }), 'Ada');
+        assert.strictEqual(await db.getObject('users/2/name', { maxBlockReads: 0 }), null);
+        await assert.rejects(db.get('users/1/name', { maxBlockReads: -1 }), TypeError);
+    });
+    
+    await test('push and popMin hand each value to exactly one caller, in push order', async () => {
+        const db = await WalDB.open(testDir + '/queue');
+        const first = await db.push('jobs', { id: 1 });
+        const second = await db.push('jobs', 42);
+        assert.match(first, /^jobs\/\d{20}$/);
+        assert.ok(first < second);
+        assert.strictEqual(await db.queueLength('jobs'), 2);
+        assert.deepStrictEqual(await db.peekMin('jobs'), [first, '{"id":1}']);
+        assert.deepStrictEqual(await db.popMin('jobs'), [first, '{"id":1}']);
+        assert.deepStrictEqual(await db.popMin('jobs'), [second, 42]);
+        assert.strictEqual(await db.popMin('jobs'), null);
+        
+        await Promise.all(Array.from({ length: 50 }, (_, i) => db.push('work', i)));
+        assert.strictEqual(await db.queueLength('work', 10), 10);
+        const popped = await Promise.all(Array.from({ length: 50 }, () => db.popMin('work')));
+        assert.deepStrictEqual(popped.map(([, value]) => value).sort((a, b) => a - b), Array.from({ length: 50 }, (_, i) => i));
+        await assert.rejects(db.push('', 'x'), err => err.name === 'WalDbInvalidKeyError');
+    });
+    
     // Clean up
     console.log('\n✅ All fundamental behavior tests passed!');
     await cleanupTestDir(testDir);
@@ -5,11 +5,7 @@ use std::io::{self, Write};
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-mod waldb_store {
-    include!("../waldb.rs");
-}
-
-use waldb_store::Store;
+use waldb::Store;
 
 struct ChatApp {
     store: Store,
@@ -244,7 +240,7 @@ fn main() -> io::Result<()> {
         input.clear();
         io::stdin().read_line(&mut input)?;
         
-        let parts: Vec<&str> = input.trim().split_whitespace().collect();
+        let parts: Vec<&str> = input.split_whitespace().collect();
         if parts.is_empty() {
             continue;
         }
@@ -0,0 +1,319 @@
+// Randomized model checking for WalDB
+// Applies seeded sequences of writes, flushes, reopens and compactions to a Store and to a
+// BTreeMap model of the tree rules, and compares everything visible after every step.
+//
+// WALDB_FUZZ_SEED (first seed), WALDB_FUZZ_SEEDS and WALDB_FUZZ_OPS size the run.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use waldb::{Store, WalDbError};
+
+fn test_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("waldb_fuzz_test_{}_{}", name, std::process::id())).to_string_lossy().into_owned();
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn cleanup(dir: &str) {
+    let _ = fs::remove_dir_all(dir);
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(String, String),
+    Replace(String, String),
+    Delete(String),
+    DeleteSubtree(String),
+    SetMany(Vec<(String, String)>, Option<String>),
+    Flush,
+    Compact,
+    Reopen,
+}
+
+// What the store should hold: every visible key, with a write under a scalar parent refused
+#[derive(Default, Clone)]
+struct Model {
+    map: BTreeMap<String, String>,
+}
+
+impl Model {
+    fn parent_is_scalar(&self, path: &str) -> bool {
+        match path.rfind('/') {
+            Some(idx) if idx > 0 => self.map.contains_key(&path[..idx]),
+            _ => false,
+        }
+    }
+    
+    fn clear_under(&mut self, path: &str) {
+        let prefix = format!("{}/", path);
+        self.map.retain(|key, _| !key.starts_with(&prefix));
+    }
+    
+    // Applies op and returns whether the store should accept it
+    fn apply(&mut self, op: &Op) -> bool {
+        match op {
+            Op::Set(key, value) | Op::Replace(key, value) => {
+                if self.parent_is_scalar(key) {
+                    return false;
+                }
+                if matches!(op, Op::Replace(..)) {
+                    self.clear_under(key);
+                }
+                self.map.insert(key.clone(), value.clone());
+            }
+            Op::Delete(key) => {
+                self.map.remove(key);
+            }
+            Op::DeleteSubtree(key) => self.clear_under(key),
+            Op::SetMany(entries, at) => {
+                if entries.is_empty() {
+                    return true;
+                }
+                // All or nothing: the batch sees the replace and its own earlier entries
+                let mut next = self.clone();
+                if let Some(at) = at {
+                    next.map.remove(at);
+                    next.clear_under(at);
+                }
+                for (key, value) in entries {
+                    if next.parent_is_scalar(key) {
+                        return false;
+                    }
+                    next.map.insert(key.clone(), value.clone());
+                }
+                *self = next;
+            }
+            Op::Flush | Op::Compact | Op::Reopen => {}
+        }
+        true
+    }
+}
+
+// Paths from a few names that prefix one another, so "a" and "ab" subtrees sit side by side
+fn gen_path(rng: &mut StdRng, root: &str, max_depth: usize) -> String {
+    const NAMES: [&str; 4] = ["a", "b", "ab", "a0"];
+    let depth = rng.gen_range(1..=max_depth);
+    let parts: Vec<&str> = (0..depth).map(|_| NAMES[rng.gen_range(0..NAMES.len())]).collect();
+    format!("{}{}", root, parts.join("/"))
+}
+
+fn gen_value(rng: &mut StdRng) -> String {
+    format!("v{}", rng.gen_range(0..8))
+}
+
+fn gen_op(rng: &mut StdRng, root: &str, reopen: bool) -> Op {
+    match rng.gen_range(0..100) {
+        0..=34 => Op::Set(gen_path(rng, root, 3), gen_value(rng)),
+        35..=42 => Op::Replace(gen_path(rng, root, 2), gen_value(rng)),
+        43..=54 => Op::Delete(gen_path(rng, root, 3)),
+        55..=60 => Op::DeleteSubtree(gen_path(rng, root, 2)),
+        61..=79 => {
+            let at = rng.gen_bool(0.4).then(|| gen_path(rng, root, 2));
+            let entries = (0..rng.gen_range(0..=4))
+                .map(|_| {
+                    // Mostly under the replaced path, when there is one
+                    let key = match &at {
+                        Some(at) if rng.gen_bool(0.7) => gen_path(rng, &format!("{}/", at), 1),
+                        _ => gen_path(rng, root, 3),
+                    };
+                    (key, gen_value(rng))
+                })
+                .collect();
+            Op::SetMany(entries, at)
+        }
+        80..=89 => Op::Flush,
+        90..=95 => Op::Compact,
+        _ if reopen => Op::Reopen,
+        _ => Op::Flush,
+    }
+}
+
+fn gen_ops(seed: u64, count: usize) -> Vec<Op> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..count).map(|_| gen_op(&mut rng, "", true)).collect()
+}
+
+fn apply_op(store: &Store, op: &Op) -> Result<(), WalDbError> {
+    match op {
+        Op::Set(key, value) => store.set(key, value, false).map(drop),
+        Op::Replace(key, value) => store.set(key, value, true).map(drop),
+        Op::Delete(key) => store.delete(key).map(drop),
+        Op::DeleteSubtree(key) => store.delete_subtree(key).map(drop),
+        Op::SetMany(entries, at) => store.set_many(entries.clone(), at.as_deref()).map(drop),
+        Op::Flush => store.flush(),
+        Op::Compact => store.compact(),
+        Op::Reopen => Ok(()),
+    }
+}
+
+// Whether the outcome is the one the model expects, as an error message if not
+fn check_outcome(op: &Op, accepted: bool, outcome: Result<(), WalDbError>) -> Result<(), String> {
+    match (accepted, outcome) {
+        (true, Ok(())) | (false, Err(WalDbError::TreeSemantics { .. })) => Ok(()),
+        (true, Err(e)) => Err(format!("{:?} failed: {}", op, e)),
+        (false, Ok(())) => Err(format!("{:?} succeeded under a scalar parent", op)),
+        (false, Err(e)) => Err(format!("{:?} failed with {} instead of TreeSemantics", op, e)),
+    }
+}
+
+fn check_state(store: &Store, model: &BTreeMap<String, String>) -> Result<(), String> {
+    let actual: BTreeMap<String, String> = store.scan_prefix("", usize::MAX).map_err(|e| format!("Scan failed: {}", e))?.into_iter().collect();
+    if &actual == model {
+        return Ok(());
+    }
+    let mut diffs = Vec::new();
+    for (key, value) in &actual {
+        match model.get(key) {
+            None => diffs.push(format!("store has {}={}, model nothing", key, value)),
+            Some(expected) if expected != value => diffs.push(format!("store has {}={}, model {}", key, value, expected)),
+            _ => {}
+        }
+    }
+    diffs.extend(model.iter().filter(|(key, _)| !actual.contains_key(*key)).map(|(key, value)| format!("model has {}={}, store nothing", key, value)));
+    Err(diffs.join("; "))
+}
+
+// Runs ops against a fresh store in dir, returning the index of the first op after which the
+// store and the model disagree
+fn run(ops: &[Op], dir: &str) -> Result<(), (usize, String)> {
+    cleanup(dir);
+    let open = || Store::open(Path::new(dir)).map_err(|e| format!("Open failed: {}", e));
+    let mut store = open().map_err(|e| (0, e))?;
+    let mut model = Model::default();
+    for (i, op) in ops.iter().enumerate() {
+        let accepted = model.apply(op);
+        if let Op::Reopen = op {
+            drop(store);
+            store = open().map_err(|e| (i, e))?;
+        }
+        check_outcome(op, accepted, apply_op(&store, op)).map_err(|e| (i, e))?;
+        check_state(&store, &model.map).map_err(|e| (i, format!("After {:?}: {}", op, e)))?;
+    }
+    Ok(())
+}
+
+// The failing ops cut down to a list that still fails: everything after the failure goes, then
+// ever smaller chunks are dropped while the run keeps failing
+fn shrink(mut ops: Vec<Op>, failed_at: usize, dir: &str) -> (Vec<Op>, String) {
+    ops.truncate(failed_at + 1);
+    let mut message = String::new();
+    let mut chunk = ops.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        while start < ops.len() {
+            let end = (start + chunk).min(ops.len());
+            let candidate: Vec<Op> = ops[..start].iter().chain(&ops[end..]).cloned().collect();
+            match run(&candidate, dir) {
+                Err((at, error)) => {
+                    ops = candidate;
+                    ops.truncate(at + 1);
+                    message = error;
+                }
+                Ok(()) => start += chunk,
+            }
+        }
+        chunk /= 2;
+    }
+    if message.is_empty() {
+        message = run(&ops, dir).err().map(|(_, e)| e).unwrap_or_default();
+    }
+    (ops, message)
+}
+
+fn fuzz_seed(seed: u64, count: usize) {
+    let dir = test_dir(&format!("seed_{}", seed));
+    let ops = gen_ops(seed, count);
+    if let Err((at, error)) = run(&ops, &dir) {
+        println!("✗ Seed {} failed at op {}: {}", seed, at, error);
+        let (shrunk, message) = shrink(ops, at, &dir);
+        println!("Shrunk to {} ops ({}):", shrunk.len(), message);
+        for op in &shrunk {
+            println!("    {:?}", op);
+        }
+        cleanup(&dir);
+        panic!("Seed {} failed; rerun with WALDB_FUZZ_SEED={} WALDB_FUZZ_SEEDS=1 WALDB_FUZZ_OPS={}", seed, seed, count);
+    }
+    cleanup(&dir);
+}
+
+fn test_sequential(first: u64, seeds: u64, count: usize) {
+    println!("Testing {} seeds of {} ops against the model...", seeds, count);
+    for seed in first..first + seeds {
+        fuzz_seed(seed, count);
+    }
+    println!("✓ Sequential fuzz test passed");
+}
+
+// Threads write disjoint subtrees, so each one's outcomes follow its own model; only the
+// converged state is compared, then again after a reopen and a full compaction
+fn test_concurrent(first: u64, seeds: u64, count: usize) {
+    const THREADS: u64 = 4;
+    println!("Testing {} seeds of {} threads writing concurrently...", seeds, THREADS);
+    for seed in first..first + seeds {
+        let dir = test_dir(&format!("concurrent_{}", seed));
+        let store = Arc::new(Store::open(Path::new(&dir)).unwrap());
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed * THREADS + t);
+                    let root = format!("t{}/", t);
+                    let mut model = Model::default();
+                    for _ in 0..count {
+                        let op = gen_op(&mut rng, &root, false);
+                        let accepted = model.apply(&op);
+                        check_outcome(&op, accepted, apply_op(&store, &op)).map_err(|e| format!("Thread {}: {}", t, e))?;
+                    }
+                    Ok::<_, String>(model.map)
+                })
+            })
+            .collect();
+        let mut expected = BTreeMap::new();
+        for handle in handles {
+            match handle.join().unwrap() {
+                Ok(map) => expected.extend(map),
+                Err(e) => panic!("Seed {} failed: {}; rerun with WALDB_FUZZ_SEED={} WALDB_FUZZ_SEEDS=1", seed, e, seed),
+            }
+        }
+        
+        let verify = |store: &Store, stage: &str| {
+            if let Err(e) = check_state(store, &expected) {
+                panic!("Seed {} diverged {}: {}; rerun with WALDB_FUZZ_SEED={} WALDB_FUZZ_SEEDS=1", seed, stage, e, seed);
+            }
+        };
+        verify(&store, "once the threads finished");
+        drop(Arc::try_unwrap(store).ok().unwrap());
+        let store = Store::open(Path::new(&dir)).unwrap();
+        verify(&store, "after reopening");
+        store.compact().unwrap();
+        verify(&store, "after compacting");
+        
+        drop(store);
+        cleanup(&dir);
+    }
+    println!("✓ Concurrent fuzz test passed");
+}
+
+fn main() {
+    println!("Running WalDB Fuzz Tests");
+    println!("========================\n");
+    
+    let first = env_or("WALDB_FUZZ_SEED", 1);
+    let seeds = env_or("WALDB_FUZZ_SEEDS", 8);
+    let count = env_or("WALDB_FUZZ_OPS", 300) as usize;
+    test_sequential(first, seeds, count);
+    test_concurrent(first, seeds.min(4), count);
+    
+    println!("\n========================");
+    println!("All fuzz tests passed! ✅");
+}
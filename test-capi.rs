@@ -0,0 +1,247 @@
+// Tests for the C API, called through its extern "C" functions as C would call them.
+// Needs the capi feature: cargo test --features capi --test capi
+
+use std::ffi::{c_char, c_void, CStr};
+use std::fs;
+use std::ptr;
+use std::thread;
+
+use waldb::ffi::*;
+
+fn test_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("waldb_capi_test_{}_{}", name, std::process::id())).to_string_lossy().into_owned();
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn cleanup(dir: &str) {
+    let _ = fs::remove_dir_all(dir);
+}
+
+fn open(dir: &str) -> *mut WaldbHandle {
+    let handle = unsafe { waldb_open(dir.as_ptr() as *const c_char, dir.len()) };
+    assert!(!handle.is_null(), "open failed: {:?}", last_error(ptr::null()));
+    handle
+}
+
+fn set(handle: *const WaldbHandle, key: &str, value: &str) -> i32 {
+    unsafe { waldb_set(handle, key.as_ptr() as *const c_char, key.len(), value.as_ptr() as *const c_char, value.len(), ptr::null_mut()) }
+}
+
+// Through waldb_get_owned, freeing the value as a C caller has to
+fn get(handle: *const WaldbHandle, key: &str) -> Option<String> {
+    let (mut value, mut len) = (ptr::null_mut(), 0);
+    let code = unsafe { waldb_get_owned(handle, key.as_ptr() as *const c_char, key.len(), &mut value, &mut len) };
+    match code {
+        WALDB_OK => {
+            let bytes = unsafe { std::slice::from_raw_parts(value as *const u8, len) }.to_vec();
+            unsafe { waldb_free(value, len) };
+            Some(String::from_utf8(bytes).unwrap())
+        }
+        WALDB_NOT_FOUND => {
+            assert!(value.is_null());
+            None
+        }
+        code => panic!("get {} returned {}: {:?}", key, code, last_error(handle)),
+    }
+}
+
+fn last_error(handle: *const WaldbHandle) -> Option<String> {
+    let mut len = usize::MAX;
+    let message = unsafe { waldb_last_error_message(handle, &mut len) };
+    if message.is_null() {
+        assert_eq!(len, 0);
+        return None;
+    }
+    let message = unsafe { CStr::from_ptr(message) }.to_str().unwrap().to_string();
+    assert_eq!(message.len(), len);
+    Some(message)
+}
+
+unsafe extern "C" fn collect(context: *mut c_void, key: *const c_char, key_len: usize, value: *const c_char, value_len: usize) -> i32 {
+    let entries = &mut *(context as *mut Vec<(String, String)>);
+    let text = |data: *const c_char, len: usize| String::from_utf8(std::slice::from_raw_parts(data as *const u8, len).to_vec()).unwrap();
+    entries.push((text(key, key_len), text(value, value_len)));
+    0
+}
+
+unsafe extern "C" fn stop_after_two(context: *mut c_void, _key: *const c_char, _key_len: usize, _value: *const c_char, _value_len: usize) -> i32 {
+    let calls = &mut *(context as *mut usize);
+    *calls += 1;
+    (*calls == 2) as i32
+}
+
+fn scan(handle: *const WaldbHandle, prefix: &str, limit: usize) -> Vec<(String, String)> {
+    let mut entries: Vec<(String, String)> = Vec::new();
+    let mut count = 0;
+    let code = unsafe {
+        waldb_scan_prefix(handle, prefix.as_ptr() as *const c_char, prefix.len(), limit, Some(collect),
+                          &mut entries as *mut _ as *mut c_void, &mut count)
+    };
+    assert_eq!(code, WALDB_OK, "{:?}", last_error(handle));
+    assert_eq!(count, entries.len());
+    entries
+}
+
+fn test_round_trip() {
+    println!("Testing set, get, delete and scan...");
+    let dir = test_dir("round_trip");
+    let handle = open(&dir);
+
+    let mut seq = 0;
+    let (key, value) = ("users/alice/name", "Alice");
+    let code = unsafe { waldb_set(handle, key.as_ptr() as *const c_char, key.len(), value.as_ptr() as *const c_char, value.len(), &mut seq) };
+    assert_eq!(code, WALDB_OK);
+    assert!(seq > 0);
+    assert_eq!(set(handle, "users/alice/city", "Zürich"), WALDB_OK);
+    assert_eq!(set(handle, "users/bob/name", "Bob"), WALDB_OK);
+    assert_eq!(set(handle, "empty", ""), WALDB_OK);
+    assert_eq!(get(handle, "users/alice/city").as_deref(), Some("Zürich"));
+    assert_eq!(get(handle, "empty").as_deref(), Some(""));
+    assert_eq!(get(handle, "missing"), None);
+
+    // Into the caller's buffer: too small says how big, then the copy fits
+    let mut len = 0;
+    let mut small = [0u8; 3];
+    let code = unsafe { waldb_get(handle, key.as_ptr() as *const c_char, key.len(), small.as_mut_ptr() as *mut c_char, small.len(), &mut len) };
+    assert_eq!((code, len), (WALDB_BUFFER_TOO_SMALL, 5));
+    assert_eq!(small, [0; 3]);
+    let mut buf = vec![0u8; len];
+    let code = unsafe { waldb_get(handle, key.as_ptr() as *const c_char, key.len(), buf.as_mut_ptr() as *mut c_char, buf.len(), &mut len) };
+    assert_eq!(code, WALDB_OK);
+    assert_eq!(&buf[..len], b"Alice");
+    let code = unsafe { waldb_get(handle, b"nope".as_ptr() as *const c_char, 4, ptr::null_mut(), 0, &mut len) };
+    assert_eq!(code, WALDB_NOT_FOUND);
+
+    // Scans come in key order, up to the limit or until the callback says stop
+    assert_eq!(scan(handle, "users/", usize::MAX), vec![
+        ("users/alice/city".to_string(), "Zürich".to_string()),
+        ("users/alice/name".to_string(), "Alice".to_string()),
+        ("users/bob/name".to_string(), "Bob".to_string()),
+    ]);
+    assert_eq!(scan(handle, "users/", 1).len(), 1);
+    let (mut calls, mut count) = (0usize, 0);
+    let code = unsafe { waldb_scan_prefix(handle, b"users/".as_ptr() as *const c_char, 6, usize::MAX, Some(stop_after_two), &mut calls as *mut _ as *mut c_void, &mut count) };
+    assert_eq!((code, calls, count), (WALDB_OK, 2, 2));
+
+    // Deletes, then everything left survives a close and reopen
+    let mut seq = 0;
+    let code = unsafe { waldb_delete(handle, b"users/bob/name".as_ptr() as *const c_char, 14, &mut seq) };
+    assert_eq!(code, WALDB_OK);
+    assert!(seq > 0);
+    assert_eq!(get(handle, "users/bob/name"), None);
+    let code = unsafe { waldb_delete_subtree(handle, b"users/alice".as_ptr() as *const c_char, 11, ptr::null_mut()) };
+    assert_eq!(code, WALDB_OK);
+    assert!(scan(handle, "users/", usize::MAX).is_empty());
+    assert_eq!(set(handle, "kept", "yes"), WALDB_OK);
+    unsafe { waldb_close(handle) };
+
+    let handle = open(&dir);
+    assert_eq!(get(handle, "kept").as_deref(), Some("yes"));
+    assert_eq!(get(handle, "users/alice/name"), None);
+    unsafe { waldb_close(handle) };
+    unsafe { waldb_close(ptr::null_mut()) };
+    unsafe { waldb_free(ptr::null_mut(), 0) };
+
+    cleanup(&dir);
+    println!("  ✓ Set, get, delete and scan passed");
+}
+
+fn test_error_paths() {
+    println!("Testing error paths...");
+    let dir = test_dir("errors");
+
+    // A failed open returns null, with the reason under the null handle
+    fs::create_dir_all(std::env::temp_dir()).unwrap();
+    let file = format!("{}_file", dir);
+    fs::write(&file, b"not a directory").unwrap();
+    let handle = unsafe { waldb_open(file.as_ptr() as *const c_char, file.len()) };
+    assert!(handle.is_null());
+    assert!(last_error(ptr::null()).unwrap().starts_with("Open failed"));
+    let handle = unsafe { waldb_open(ptr::null(), 4) };
+    assert!(handle.is_null());
+    assert_eq!(last_error(ptr::null()).as_deref(), Some("path is null"));
+    fs::remove_file(&file).unwrap();
+
+    // A successful open clears it
+    let handle = open(&dir);
+    assert_eq!(last_error(ptr::null()), None);
+    assert_eq!(last_error(handle), None);
+
+    // Null handles and arguments are refused rather than followed
+    assert_eq!(set(ptr::null(), "a", "1"), WALDB_ERROR);
+    assert_eq!(last_error(ptr::null()).as_deref(), Some("handle is null"));
+    let code = unsafe { waldb_set(handle, ptr::null(), 3, b"1".as_ptr() as *const c_char, 1, ptr::null_mut()) };
+    assert_eq!(code, WALDB_ERROR);
+    assert_eq!(last_error(handle).as_deref(), Some("key is null"));
+    let code = unsafe { waldb_get(handle, b"a".as_ptr() as *const c_char, 1, ptr::null_mut(), 0, ptr::null_mut()) };
+    assert_eq!(code, WALDB_ERROR);
+    assert_eq!(last_error(handle).as_deref(), Some("value_len is null"));
+    let code = unsafe { waldb_scan_prefix(handle, ptr::null(), 0, usize::MAX, None, ptr::null_mut(), ptr::null_mut()) };
+    assert_eq!(code, WALDB_ERROR);
+    assert_eq!(last_error(handle).as_deref(), Some("callback is null"));
+
+    // Bytes that aren't UTF-8, and store errors, come back as messages
+    let code = unsafe { waldb_set(handle, b"\xff\xfe".as_ptr() as *const c_char, 2, b"1".as_ptr() as *const c_char, 1, ptr::null_mut()) };
+    assert_eq!(code, WALDB_ERROR);
+    assert!(last_error(handle).unwrap().starts_with("key is not UTF-8"));
+    assert_eq!(set(handle, "scalar", "1"), WALDB_OK);
+    assert_eq!(set(handle, "scalar/child", "2"), WALDB_ERROR);
+    let message = last_error(handle).unwrap();
+    assert!(message.starts_with("Set failed") && message.contains("scalar parent"), "{}", message);
+
+    // Errors belong to the thread that made them
+    let shared = handle as usize;
+    thread::spawn(move || assert_eq!(last_error(shared as *const WaldbHandle), None)).join().unwrap();
+    assert!(last_error(handle).is_some());
+
+    // A close drops the handle's message
+    unsafe { waldb_close(handle) };
+    assert_eq!(last_error(handle), None);
+
+    cleanup(&dir);
+    println!("  ✓ Error paths passed");
+}
+
+fn test_concurrent_use() {
+    println!("Testing one handle from two threads...");
+    let dir = test_dir("concurrent");
+    let handle = open(&dir);
+
+    let shared = handle as usize;
+    let writers: Vec<_> = (0..2).map(|t| thread::spawn(move || {
+        let handle = shared as *const WaldbHandle;
+        for i in 0..500 {
+            assert_eq!(set(handle, &format!("t{}/{:03}", t, i), &format!("{}", i)), WALDB_OK);
+            if i % 50 == 0 {
+                assert_eq!(scan(handle, &format!("t{}/", t), usize::MAX).len(), i + 1);
+            }
+        }
+        assert_eq!(set(handle, &format!("t{}/000/child", t), "x"), WALDB_ERROR);
+        assert!(last_error(handle).unwrap().contains("scalar parent"));
+    })).collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    assert_eq!(scan(handle, "t0/", usize::MAX).len(), 500);
+    assert_eq!(scan(handle, "t1/", usize::MAX).len(), 500);
+    assert_eq!(get(handle, "t1/499").as_deref(), Some("499"));
+    assert_eq!(last_error(handle), None);
+    unsafe { waldb_close(handle) };
+
+    cleanup(&dir);
+    println!("  ✓ Concurrent use passed");
+}
+
+fn main() {
+    println!("Running WalDB C API Tests");
+    println!("=========================\n");
+
+    test_round_trip();
+    test_error_paths();
+    test_concurrent_use();
+
+    println!("\n=========================");
+    println!("All C API tests passed! ✅");
+}
@@ -0,0 +1,437 @@
+// Integration tests for the waldb-cli one-shot and piped modes
+
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, UNIX_EPOCH};
+
+use waldb::{Store, StoreOptions};
+
+fn test_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("waldb_cli_test_{}_{}", name, std::process::id())).to_string_lossy().into_owned();
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn cleanup(dir: &str) {
+    let _ = fs::remove_dir_all(dir);
+}
+
+fn cli(dir: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_waldb-cli"))
+        .arg(dir)
+        .args(args)
+        .output()
+        .expect("Failed to run waldb-cli")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn test_get_set_exit_codes() {
+    println!("Testing get/set exit codes...");
+    let dir = test_dir("get_set");
+    
+    let out = cli(&dir, &["set", "users/alice", "hello", "world", "--quiet"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "");
+    
+    let out = cli(&dir, &["get", "users/alice"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "hello world\n");
+    
+    let out = cli(&dir, &["get", "users/nobody"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(stdout(&out), "");
+    
+    let out = cli(&dir, &["frobnicate"]);
+    assert_eq!(out.status.code(), Some(2));
+    
+    let out = cli(&dir, &["scan", "users/", "--limit", "lots"]);
+    assert_eq!(out.status.code(), Some(2));
+    
+    // Writing under a scalar is a store error
+    let out = cli(&dir, &["set", "users/alice/name", "x"]);
+    assert_eq!(out.status.code(), Some(2));
+    
+    let out = cli(&dir, &["incr", "hits"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "1\n");
+    let out = cli(&dir, &["incr", "hits", "-11"]);
+    assert_eq!(stdout(&out), "-10\n");
+    assert_eq!(cli(&dir, &["incr", "hits", "ten"]).status.code(), Some(2));
+    assert_eq!(cli(&dir, &["incr", "users/alice"]).status.code(), Some(2));
+    
+    let out = cli(&dir, &["mv", "users/alice", "people/alice", "--quiet"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "");
+    assert_eq!(stdout(&cli(&dir, &["get", "people/alice"])), "hello world\n");
+    assert_eq!(cli(&dir, &["get", "users/alice"]).status.code(), Some(1));
+    assert_eq!(cli(&dir, &["mv", "people", "people/alice/x"]).status.code(), Some(2));
+    
+    cleanup(&dir);
+    println!("✓ Get/set exit codes test passed");
+}
+
+fn test_json_output() {
+    println!("Testing JSON output...");
+    let dir = test_dir("json");
+    
+    for (key, value) in [("items/a", "one"), ("items/b", "say \"hi\""), ("items/c", "three"), ("other/x", "x")] {
+        assert_eq!(cli(&dir, &["set", key, value, "--quiet"]).status.code(), Some(0));
+    }
+    
+    let out = cli(&dir, &["scan", "items/", "--json", "--limit", "2"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), 
+        "{\"key\":\"items/a\",\"value\":\"one\"}\n{\"key\":\"items/b\",\"value\":\"say \\\"hi\\\"\"}\n");
+    
+    let out = cli(&dir, &["pattern", "*/x", "--json"]);
+    assert_eq!(stdout(&out), "{\"key\":\"other/x\",\"value\":\"x\"}\n");
+    
+    let out = cli(&dir, &["range", "items/b", "items/z"]);
+    assert_eq!(stdout(&out), "items/b = say \"hi\"\nitems/c = three\n");
+    
+    let out = cli(&dir, &["export"]);
+    assert_eq!(stdout(&out).lines().count(), 4);
+    
+    // A trailing slash prints the subtree as one JSON document
+    let out = cli(&dir, &["get", "items/", "--json"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "{\"a\":\"one\",\"b\":\"say \\\"hi\\\"\",\"c\":\"three\"}\n");
+    assert_eq!(cli(&dir, &["get", "nothing/"]).status.code(), Some(1));
+    
+    let out = cli(&dir, &["stats", "--json"]);
+    assert_eq!(out.status.code(), Some(0));
+    let stats = stdout(&out);
+    assert!(stats.starts_with("{\"l0\":") && stats.contains("\"l1\":") && stats.trim_end().ends_with('}'));
+    assert!(stats.contains("\"l0_pressure\":\"normal\""), "{}", stats);
+    // The id is the one the IDENTITY file holds
+    let identity = fs::read_to_string(std::path::Path::new(&dir).join("IDENTITY")).unwrap();
+    let id = identity.lines().find_map(|line| line.strip_prefix("id ")).unwrap();
+    assert!(stats.contains(&format!("\"store_id\":\"{}\"", id)), "{}", stats);
+    // Opened for the one command, so everything read back from disk is durable
+    let number = |field: &str| stats.split(&format!("\"{}\":", field)).nth(1).and_then(|rest| rest.split([',', '}']).next()).unwrap().parse::<u64>().unwrap();
+    assert!(number("seq") > 0);
+    assert_eq!(number("durable_seq"), number("seq"));
+    assert!(number("flushed_seq") <= number("seq"));
+    
+    let out = cli(&dir, &["health", "--json"]);
+    assert_eq!(out.status.code(), Some(0), "{}", stdout(&out));
+    let health = stdout(&out);
+    assert!(health.starts_with("{\"healthy\":true,\"checks\":[{\"name\":\"memtable_read\",\"status\":\"passed\""), "{}", health);
+    // The probe write is deleted again and never shows in user reads
+    assert_eq!(stdout(&cli(&dir, &["export"])).lines().count(), 4);
+    
+    cleanup(&dir);
+    println!("✓ JSON output test passed");
+}
+
+fn test_piped_stdin() {
+    println!("Testing piped stdin...");
+    let dir = test_dir("piped");
+    
+    let mut child = Command::new(env!("CARGO_BIN_EXE_waldb-cli"))
+        .arg(&dir)
+        .arg("--quiet")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run waldb-cli");
+    
+    child.stdin.take().unwrap().write_all(b"set a b\nget a\n").unwrap();
+    
+    // Must exit at end of input instead of spinning on EOF
+    let out = child.wait_with_output().unwrap();
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "✓ Set 'a' = 'b'\nb\n");
+    
+    let out = cli(&dir, &["get", "a"]);
+    assert_eq!(stdout(&out), "b\n");
+    
+    cleanup(&dir);
+    println!("✓ Piped stdin test passed");
+}
+
+fn test_dump_segment() {
+    println!("Testing dump-seg...");
+    let dir = test_dir("dump_seg");
+    
+    assert_eq!(cli(&dir, &["set", "a", "b", "--quiet"]).status.code(), Some(0));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_waldb-cli"))
+        .args([&dir, "--quiet"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Failed to run waldb-cli");
+    child.stdin.take().unwrap().write_all(b"flush\n").unwrap();
+    assert!(child.wait().unwrap().success());
+    let seg = fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension() == Some("seg".as_ref()))
+        .unwrap();
+    
+    // The store directory is never opened, so a missing one isn't created
+    let elsewhere = test_dir("dump_seg_unopened");
+    let out = cli(&elsewhere, &["dump-seg", seg.to_str().unwrap(), "--values"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert!(stdout(&out).contains("SET shared=0 key=\"a\" value_len=1 value=\"b\""));
+    assert!(!std::path::Path::new(&elsewhere).exists());
+    
+    let out = cli(&dir, &["dump-seg", seg.file_name().unwrap().to_str().unwrap(), "--hex"]);
+    assert!(stdout(&out).contains("key=61 value_len=1\n"));
+    assert_eq!(cli(&dir, &["dump-seg", "missing.seg"]).status.code(), Some(2));
+    
+    cleanup(&dir);
+    println!("✓ Dump-seg test passed");
+}
+
+fn test_as_of_get() {
+    println!("Testing asof get...");
+    let dir = test_dir("as_of");
+    
+    assert_eq!(cli(&dir, &["set", "k", "one", "--quiet"]).status.code(), Some(0));
+    assert_eq!(cli(&dir, &["set", "k", "two", "--quiet"]).status.code(), Some(0));
+    
+    let out = cli(&dir, &["asof", "1", "get", "k"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "one\n");
+    assert_eq!(stdout(&cli(&dir, &["asof", "2", "get", "k"])), "two\n");
+    assert_eq!(cli(&dir, &["asof", "0", "get", "k"]).status.code(), Some(1));
+    assert_eq!(cli(&dir, &["asof", "99", "get", "k"]).status.code(), Some(2));
+    assert_eq!(cli(&dir, &["asof", "soon", "get", "k"]).status.code(), Some(2));
+    
+    cleanup(&dir);
+    println!("✓ Asof get test passed");
+}
+
+fn test_copy() {
+    println!("Testing cp...");
+    let dir = test_dir("copy");
+    
+    assert_eq!(cli(&dir, &["set", "templates/default/theme", "dark", "--quiet"]).status.code(), Some(0));
+    assert_eq!(cli(&dir, &["set", "templates/default/plan", "free", "--quiet"]).status.code(), Some(0));
+    assert_eq!(cli(&dir, &["set", "tenants/acme/theme", "light", "--quiet"]).status.code(), Some(0));
+    
+    // Fail is the default; skip and replace are the optional last argument
+    assert_eq!(cli(&dir, &["cp", "templates/default", "tenants/acme", "--quiet"]).status.code(), Some(2));
+    assert_eq!(cli(&dir, &["get", "tenants/acme/plan"]).status.code(), Some(1));
+    let out = cli(&dir, &["cp", "templates/default", "tenants/acme", "skip"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert!(stdout(&out).contains("Copied 1 keys from 'templates/default' to 'tenants/acme'"));
+    assert_eq!(stdout(&cli(&dir, &["get", "tenants/acme/theme"])), "light\n");
+    assert_eq!(cli(&dir, &["cp", "templates/default", "tenants/acme", "replace", "--quiet"]).status.code(), Some(0));
+    assert_eq!(stdout(&cli(&dir, &["get", "tenants/acme/theme"])), "dark\n");
+    assert_eq!(cli(&dir, &["cp", "templates/default", "tenants/acme", "merge"]).status.code(), Some(2));
+    assert_eq!(cli(&dir, &["cp", "templates", "templates/default/copy"]).status.code(), Some(2));
+    
+    cleanup(&dir);
+    println!("✓ Cp test passed");
+}
+
+fn test_clear_all() {
+    println!("Testing clear-all...");
+    let dir = test_dir("clear_all");
+    
+    assert_eq!(cli(&dir, &["set", "users/alice", "a", "--quiet"]).status.code(), Some(0));
+    assert_eq!(cli(&dir, &["set", "orders/1", "o", "--quiet"]).status.code(), Some(0));
+    
+    // Without --yes nothing is deleted
+    let out = cli(&dir, &["clear-all"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--yes"));
+    assert_eq!(stdout(&cli(&dir, &["get", "users/alice"])), "a\n");
+    
+    let out = cli(&dir, &["clear-all", "--yes"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "✓ Cleared the store\n");
+    assert_eq!(cli(&dir, &["get", "users/alice"]).status.code(), Some(1));
+    assert_eq!(stdout(&cli(&dir, &["scan", ""])), "");
+    
+    // The store takes writes again straight away
+    assert_eq!(cli(&dir, &["set", "users/bob", "b", "--quiet"]).status.code(), Some(0));
+    assert_eq!(stdout(&cli(&dir, &["scan", ""])), "users/bob = b\n");
+    
+    cleanup(&dir);
+    println!("✓ Clear-all test passed");
+}
+
+fn test_bench_cleans_up() {
+    println!("Testing bench...");
+    let dir = test_dir("bench");
+    
+    for user in ["alice", "bob", "carol"] {
+        assert_eq!(cli(&dir, &["set", &format!("users/{}", user), user, "--quiet"]).status.code(), Some(0));
+    }
+    let before = stdout(&cli(&dir, &["export"]));
+    
+    let out = cli(&dir, &["bench", "writes", "mixed", "--ops", "200", "--threads", "2", "--prefix", "users/", "--json"]);
+    assert_eq!(out.status.code(), Some(0), "{}", String::from_utf8_lossy(&out.stderr));
+    let lines: Vec<String> = stdout(&out).lines().map(String::from).collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("{\"mode\":\"writes\",\"ops\":200,\"threads\":2,"), "{}", lines[0]);
+    assert!(lines[1].starts_with("{\"mode\":\"mixed\",\"ops\":200,"), "{}", lines[1]);
+    assert!(lines.iter().all(|line| line.contains("\"p99\":") && line.contains("\"hit_rate\":")));
+    
+    // Reads and scans of keys the run wrote, then of the store's own keys
+    let out = cli(&dir, &["bench", "reads", "scan", "--ops", "100", "--keyspace", "synthetic"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert!(stdout(&out).contains("reads: 100 ops on 1 thread in "));
+    assert!(stdout(&out).contains("block cache: "));
+    assert_eq!(cli(&dir, &["bench", "reads", "--ops", "100", "--prefix", "users/"]).status.code(), Some(0));
+    
+    // Nothing the runs wrote is left behind, and what was there is untouched
+    assert_eq!(stdout(&cli(&dir, &["export"])), before);
+    
+    assert_eq!(cli(&dir, &["bench", "reads", "--prefix", "nobody/"]).status.code(), Some(1));
+    assert_eq!(cli(&dir, &["bench", "sideways"]).status.code(), Some(2));
+    assert_eq!(cli(&dir, &["bench", "--ops", "lots"]).status.code(), Some(2));
+    
+    cleanup(&dir);
+    println!("✓ Bench test passed");
+}
+
+fn test_warmup() {
+    println!("Testing warmup...");
+    let dir = test_dir("warmup");
+    
+    assert_eq!(cli(&dir, &["set", "users/alice", "admin", "--quiet"]).status.code(), Some(0));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_waldb-cli"))
+        .args([&dir, "--quiet"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Failed to run waldb-cli");
+    child.stdin.take().unwrap().write_all(b"flush\n").unwrap();
+    assert!(child.wait().unwrap().success());
+    
+    let out = cli(&dir, &["warmup", "users/", "--json"]);
+    assert_eq!(out.status.code(), Some(0), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(stdout(&out).starts_with("{\"bytes_loaded\":"), "{}", stdout(&out));
+    assert!(stdout(&out).contains("\"complete\":true"));
+    assert_eq!(cli(&dir, &["warmup", "--level", "2", "--quiet"]).status.code(), Some(0));
+    assert_eq!(cli(&dir, &["warmup", "--level", "9"]).status.code(), Some(2));
+    assert_eq!(cli(&dir, &["warmup", "--max-bytes", "lots"]).status.code(), Some(2));
+    
+    cleanup(&dir);
+    println!("✓ Warmup test passed");
+}
+
+fn test_reads_report() {
+    println!("Testing reads-report...");
+    let dir = test_dir("reads_report");
+    {
+        let store = Store::open(std::path::Path::new(&dir)).unwrap();
+        store.set("users/alice", "admin", false).unwrap();
+        store.set("users/bob", "viewer", false).unwrap();
+        store.flush().unwrap();
+    }
+    
+    // Run alone, it reads the sample itself, and every key comes from the one L0 segment
+    let out = cli(&dir, &["reads-report", "users/", "--json"]);
+    assert_eq!(out.status.code(), Some(0), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(stdout(&out).trim(), "{\"levels\":[{\"level\":\"L0\",\"gets\":2,\"segments_probed\":{\"mean\":1.000,\"p99\":1},\"bloom_negatives\":{\"mean\":0.000,\"p99\":0},\"blocks_read\":{\"mean\":1.000,\"p99\":1}}]}");
+    let out = cli(&dir, &["reads-report", "nobody/"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out).trim(), "No gets since open");
+    
+    cleanup(&dir);
+    println!("✓ Reads report test passed");
+}
+
+fn test_stats_history() {
+    println!("Testing stats --history...");
+    let dir = test_dir("stats_history");
+    
+    let out = cli(&dir, &["stats", "--history", "1h"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert!(stdout(&out).starts_with("No samples"), "{}", stdout(&out));
+    
+    let options = StoreOptions { stats_interval: Some(Duration::from_millis(10)), ..Default::default() };
+    let (store, _) = Store::open_with_options(std::path::Path::new(&dir), options).unwrap();
+    store.set("users/alice", "admin", false).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    drop(store);
+    let samples = Store::read_stats_history(std::path::Path::new(&dir), UNIX_EPOCH).unwrap();
+    assert!(!samples.is_empty());
+    
+    // A header, then a row per sample, the first with nothing to compare against
+    let out = cli(&dir, &["stats", "--history", "1h"]);
+    assert_eq!(out.status.code(), Some(0), "{}", String::from_utf8_lossy(&out.stderr));
+    let text = stdout(&out);
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines[0].starts_with("UTC"), "{}", text);
+    assert_eq!(lines.len(), samples.len() + 1);
+    assert!(lines[1].split_whitespace().nth(2) == Some("-"), "{}", lines[1]);
+    
+    let out = cli(&dir, &["stats", "--history", "30s", "--json"]);
+    assert_eq!(stdout(&out).lines().count(), samples.len());
+    assert!(stdout(&out).lines().all(|line| line.starts_with("{\"at_ms\":") && line.contains("\"wal_syncs\":")));
+    assert_eq!(cli(&dir, &["stats", "--history", "soon"]).status.code(), Some(2));
+    assert_eq!(cli(&dir, &["stats", "--history", "5y"]).status.code(), Some(2));
+    
+    cleanup(&dir);
+    println!("✓ Stats history test passed");
+}
+
+fn test_snapshot_round_trip() {
+    println!("Testing snapshot, verify-snapshot and import...");
+    let dir = test_dir("snapshot");
+    let restored = test_dir("snapshot_restored");
+    
+    for (key, value) in [("items/a", "one"), ("items/b", "two"), ("other/x", "x")] {
+        assert_eq!(cli(&dir, &["set", key, value, "--quiet"]).status.code(), Some(0));
+    }
+    let out = cli(&dir, &["snapshot", "items/"]);
+    assert_eq!(out.status.code(), Some(0));
+    fs::create_dir_all(std::env::temp_dir()).unwrap();
+    let file = format!("{}.snapshot", dir);
+    fs::write(&file, &out.stdout).unwrap();
+    
+    // Checked without opening the store, so the directory needn't exist
+    let out = cli(&restored, &["verify-snapshot", &file, "--json"]);
+    assert_eq!(out.status.code(), Some(0), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(stdout(&out).contains("\"prefix\":\"items/\",") && stdout(&out).contains("\"chunks\":1,\"entries\":2,"), "{}", stdout(&out));
+    assert!(!std::path::Path::new(&restored).exists());
+    
+    let out = cli(&restored, &["import", &file]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "✓ Imported 2 entries in 1 of 1 chunks\n");
+    assert_eq!(stdout(&cli(&restored, &["scan", ""])), "items/a = one\nitems/b = two\n");
+    assert_eq!(cli(&restored, &["import", &file, "1", "--quiet"]).status.code(), Some(0));
+    assert_eq!(cli(&restored, &["import", &file, "first"]).status.code(), Some(2));
+    
+    // A damaged entry fails both, naming its chunk
+    fs::write(&file, String::from_utf8(fs::read(&file).unwrap()).unwrap().replace("\"two\"", "\"too\"")).unwrap();
+    let out = cli(&restored, &["verify-snapshot", &file]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Snapshot chunk 0 is damaged"));
+    assert_eq!(cli(&restored, &["import", &file]).status.code(), Some(2));
+    
+    let _ = fs::remove_file(&file);
+    cleanup(&dir);
+    cleanup(&restored);
+    println!("✓ Snapshot test passed");
+}
+
+fn main() {
+    println!("Running WalDB CLI Tests");
+    println!("==============================\n");
+    
+    test_get_set_exit_codes();
+    test_json_output();
+    test_piped_stdin();
+    test_dump_segment();
+    test_as_of_get();
+    test_copy();
+    test_clear_all();
+    test_bench_cleans_up();
+    test_warmup();
+    test_reads_report();
+    test_stats_history();
+    test_snapshot_round_trip();
+    
+    println!("\n==============================");
+    println!("All CLI tests passed! ✅");
+}
@@ -1,10 +1,15 @@
 // Thorough compaction tests for WalDB
 
-// Include the main waldb module (it has all the imports we need)
-include!("waldb.rs");
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use waldb::{CompactionOptions, Keyspace, RawState, RebuildMode, RebuildOptions, SegmentPlacement, Store, StoreEvent, StoreHandle, StoreOptions, WalDbError};
 
 fn test_dir(name: &str) -> String {
-    let dir = format!("/tmp/waldb_compaction_test_{}", name);
+    let dir = std::env::temp_dir().join(format!("waldb_compaction_test_{}", name)).to_string_lossy().into_owned();
     let _ = fs::remove_dir_all(&dir);
     dir
 }
@@ -28,9 +33,9 @@ fn test_basic_compaction() {
         store.flush().unwrap();
     }
     
-    // Check initial segment counts
+    // Check initial segment counts; the fourth flush wakes compaction, which may have merged already
     let (l0_before, l1_before, _l2_before) = store.segment_counts();
-    assert!(l0_before >= 5, "Should have at least 5 L0 segments");
+    assert!(l0_before >= 5 || l1_before > 0, "Should have 5 L0 segments or merged them");
     
     // Wait for compaction (compaction runs every second)
     thread::sleep(Duration::from_secs(3));
@@ -164,6 +169,267 @@ fn test_l1_to_l2_compaction() {
     println!("✓ L1 to L2 compaction test passed");
 }
 
+fn test_l2_compaction_drops_raw_tombstones() {
+    println!("Testing raw range after L2 compaction...");
+    let dir = test_dir("l2_raw_tombstones");
+    let store = Store::open(Path::new(&dir)).unwrap();
+    
+    store.set("doomed", "value", false).unwrap();
+    store.delete("doomed").unwrap();
+    
+    // Enough flushes to fill L1 and push everything into a single L2 merge
+    for batch in 0..40 {
+        store.set(&format!("filler/{:02}", batch), "x", false).unwrap();
+        store.flush().unwrap();
+    }
+    
+    let raw = store.get_range_raw("doomed", "doomed~").unwrap();
+    assert_eq!(raw.len(), 1);
+    assert_eq!(raw[0].state, RawState::PointDeleted);
+    
+    store.compact().unwrap();
+    let (_, _, l2) = store.segment_counts();
+    assert!(l2 > 0, "Expected an L2 segment");
+    
+    // The tombstone has done its job and is gone from the raw view too
+    assert!(store.get_range_raw("doomed", "doomed~").unwrap().is_empty());
+    assert_eq!(store.get("doomed").unwrap(), None);
+    
+    cleanup(&dir);
+    println!("✓ Raw range L2 compaction test passed");
+}
+
+fn test_l2_merge_keeps_deletes() {
+    println!("Testing deletes stay deleted across L1 to L2 merges...");
+    let dir = test_dir("l2_merge_deletes");
+    let store = Store::open(Path::new(&dir)).unwrap();
+    
+    let fill = |store: &Store, round: usize| {
+        for batch in 0..40 {
+            store.set(&format!("filler/{}/{:02}", round, batch), "x", false).unwrap();
+            store.flush().unwrap();
+        }
+        store.compact().unwrap();
+    };
+    store.set("doomed", "value", false).unwrap();
+    fill(&store, 0);
+    assert!(store.segment_counts().2 > 0, "Expected doomed in L2");
+    
+    // The second merge drops the tombstone, so it has to take the L2 segment holding the value
+    store.delete("doomed").unwrap();
+    fill(&store, 1);
+    assert_eq!(store.get("doomed").unwrap(), None);
+    assert!(store.get_range_raw("doomed", "doomed~").unwrap().is_empty());
+    assert_eq!(store.scan_prefix("filler/", usize::MAX).unwrap().len(), 80);
+    
+    drop(store);
+    let store = Store::open(Path::new(&dir)).unwrap();
+    assert_eq!(store.get("doomed").unwrap(), None);
+    
+    drop(store);
+    cleanup(&dir);
+    println!("✓ L2 merge delete test passed");
+}
+
+fn segment_files(dir: &str) -> Vec<std::path::PathBuf> {
+    fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension() == Some("seg".as_ref()))
+        .collect()
+}
+
+// Segment files besides the merge's inputs, finished or not
+fn merge_outputs(dir: &str, inputs: &[std::path::PathBuf]) -> Vec<String> {
+    fs::read_dir(dir).unwrap()
+        .filter(|e| !inputs.contains(&e.as_ref().unwrap().path()))
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.contains(".seg"))
+        .collect()
+}
+
+fn test_compacted_files_removed() {
+    println!("Testing compacted-away segment files are removed...");
+    let dir = test_dir("compacted_files_removed");
+    
+    let stale = {
+        let store = Store::open(Path::new(&dir)).unwrap();
+        store.set("doomed", "value", false).unwrap();
+        store.flush().unwrap();
+        let first = segment_files(&dir).pop().unwrap();
+        let stale = (first.clone(), fs::read(&first).unwrap());
+        
+        store.delete("doomed").unwrap();
+        for batch in 0..40 {
+            store.set(&format!("filler/{:02}", batch), "x", false).unwrap();
+            store.flush().unwrap();
+        }
+        store.compact().unwrap();
+        
+        // Nothing reads the merged inputs any more, so none of their files are left
+        let (l0, l1, l2) = store.segment_counts();
+        assert_eq!(segment_files(&dir).len(), l0 + l1 + l2);
+        stale
+    };
+    
+    // A file whose deletion was deferred past shutdown must not come back as live data
+    fs::write(&stale.0, &stale.1).unwrap();
+    {
+        let store = Store::open(Path::new(&dir)).unwrap();
+        assert!(!stale.0.exists(), "Superseded segment file should be removed at open");
+        assert_eq!(store.get("doomed").unwrap(), None);
+        assert_eq!(store.get("filler/39").unwrap(), Some("x".to_string()));
+    }
+    
+    cleanup(&dir);
+    println!("✓ Compacted files removal test passed");
+}
+
+fn test_contains_after_l2_compaction() {
+    println!("Testing contains() on keys only in L2...");
+    let dir = test_dir("contains_l2");
+    let store = Store::open(Path::new(&dir)).unwrap();
+    
+    store.set("kept", "value", false).unwrap();
+    store.set("doomed", "value", false).unwrap();
+    store.delete("doomed").unwrap();
+    for batch in 0..40 {
+        store.set(&format!("filler/{:02}", batch), "x", false).unwrap();
+        store.flush().unwrap();
+    }
+    store.compact().unwrap();
+    
+    let (l0, l1, l2) = store.segment_counts();
+    assert!(l2 > 0, "Expected an L2 segment");
+    assert_eq!(store.memtable_len(), 0);
+    assert!(store.contains("kept").unwrap());
+    assert!(store.contains("filler/00").unwrap());
+    assert!(!store.contains("doomed").unwrap());
+    assert_eq!((l0, l1, l2), store.segment_counts());
+    
+    cleanup(&dir);
+    println!("✓ Contains L2 compaction test passed");
+}
+
+fn test_set_many_duplicate_keys() {
+    println!("Testing duplicate keys in one set_many across compaction...");
+    let dir = test_dir("set_many_duplicates");
+    
+    {
+        let store = Store::open(Path::new(&dir)).unwrap();
+        store.set_many(vec![
+            ("dup".to_string(), "first".to_string()),
+            ("other".to_string(), "x".to_string()),
+            ("dup".to_string(), "second".to_string()),
+        ], None).unwrap();
+        
+        // Later entries take later seqs, so the last occurrence wins everywhere
+        let raw = store.get_range_raw("dup", "dup~").unwrap();
+        assert_eq!(raw[0].state, RawState::Live("second".to_string()));
+        assert!(raw[0].seq > store.get_range_raw("other", "other~").unwrap()[0].seq);
+        assert_eq!(store.get("dup").unwrap(), Some("second".to_string()));
+        
+        store.flush().unwrap();
+        assert_eq!(store.get("dup").unwrap(), Some("second".to_string()));
+    }
+    
+    // Replay the WAL into a second segment holding the same writes
+    let store = Store::open(Path::new(&dir)).unwrap();
+    assert_eq!(store.get("dup").unwrap(), Some("second".to_string()));
+    store.flush().unwrap();
+    
+    // Enough flushes to carry both copies through L1 into L2
+    for batch in 0..40 {
+        store.set(&format!("filler/{:02}", batch), "x", false).unwrap();
+        store.flush().unwrap();
+    }
+    store.compact().unwrap();
+    let (_, _, l2) = store.segment_counts();
+    assert!(l2 > 0, "Expected an L2 segment");
+    assert_eq!(store.get("dup").unwrap(), Some("second".to_string()));
+    
+    drop(store);
+    let store = Store::open(Path::new(&dir)).unwrap();
+    assert_eq!(store.get("dup").unwrap(), Some("second".to_string()));
+    
+    cleanup(&dir);
+    println!("✓ Set many duplicates compaction test passed");
+}
+
+fn throttled_store(dir: &str, bytes_per_sec: u64) -> Store {
+    let options = StoreOptions {
+        compaction: CompactionOptions { max_bytes_per_sec: Some(bytes_per_sec), ..Default::default() },
+        ..Default::default()
+    };
+    Store::open_with_options(Path::new(dir), options).unwrap().0
+}
+
+// Four L0 segments of about 64KB each, enough for one L0 compaction
+fn write_l0_segments(store: &Store) {
+    let value = "v".repeat(1000);
+    for batch in 0..4 {
+        for i in 0..64 {
+            store.set(&format!("throttle/{}/{:02}", batch, i), &value, false).unwrap();
+        }
+        store.flush().unwrap();
+    }
+}
+
+fn test_throttled_compaction() {
+    println!("Testing compaction stays under its IO budget...");
+    let dir = test_dir("throttled");
+    let store = throttled_store(&dir, 256 * 1024);
+    write_l0_segments(&store);
+    let input_bytes: u64 = segment_files(&dir).iter().map(|p| fs::metadata(p).unwrap().len()).sum();
+    
+    // The merge both reads and writes every block, so about 2 * input bytes go through the budget
+    let start = Instant::now();
+    store.compact().unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+    let budget_secs = input_bytes as f64 * 2.0 / (256.0 * 1024.0);
+    println!("  Compacted {} bytes in {:.2}s (budget allows {:.2}s)", input_bytes, elapsed, budget_secs);
+    assert!(elapsed >= budget_secs * 0.8, "Compaction ran faster than its budget allows");
+    
+    assert_eq!(store.segment_counts(), (0, 1, 0));
+    for batch in 0..4 {
+        assert!(store.contains(&format!("throttle/{}/63", batch)).unwrap());
+    }
+    
+    cleanup(&dir);
+    println!("✓ Throttled compaction test passed");
+}
+
+fn test_shutdown_interrupts_compaction() {
+    println!("Testing shutdown stops a throttled background compaction...");
+    let dir = test_dir("throttled_shutdown");
+    let inputs;
+    
+    {
+        // Slow enough that the merge would take minutes
+        let store = throttled_store(&dir, 1024);
+        write_l0_segments(&store);
+        inputs = segment_files(&dir);
+        
+        // Let the background thread start merging; readers still see the inputs meanwhile.
+        // The output is written under a temporary name until it is finished.
+        thread::sleep(Duration::from_secs(6));
+        assert!(merge_outputs(&dir, &inputs).iter().any(|name| name.ends_with(".seg.tmp")),
+                "Background compaction should be under way");
+        assert_eq!(store.segment_counts(), (4, 0, 0));
+        assert!(store.contains("throttle/3/63").unwrap());
+    }
+    
+    // The merge notices within its current pause and removes its partial output
+    thread::sleep(Duration::from_millis(500));
+    assert!(merge_outputs(&dir, &inputs).is_empty(), "Interrupted merge should leave no output behind");
+    
+    let store = Store::open(Path::new(&dir)).unwrap();
+    assert_eq!(store.segment_counts(), (4, 0, 0));
+    assert!(store.contains("throttle/0/00").unwrap());
+    
+    cleanup(&dir);
+    println!("✓ Shutdown interrupts compaction test passed");
+}
+
 fn test_compaction_with_subtrees() {
     println!("Testing compaction with subtree operations...");
     let dir = test_dir("subtrees");
@@ -276,11 +542,11 @@ fn test_compaction_under_load() {
         }
     });
     
-    // Let it run for a while
-    thread::sleep(Duration::from_secs(3));
+    // Let it run past the first compaction check (every 5s)
+    thread::sleep(Duration::from_secs(6));
     
     // Check that compaction is happening
-    let (l0, l1, _) = store.segment_counts();
+    let (_, l1, _) = store.segment_counts();
     assert!(l1 > 0, "Should have some L1 segments from compaction");
     
     writer.join().unwrap();
@@ -296,6 +562,784 @@ fn test_compaction_under_load() {
     println!("✓ Load compaction test passed");
 }
 
+fn test_sealed_prefix_archive() {
+    println!("Testing sealed prefixes move to the archive and out of compaction...");
+    let dir = test_dir("sealed_prefix");
+    let written: Arc<Mutex<Vec<u64>>> = Arc::new(Mutex::new(Vec::new()));
+    let sink = written.clone();
+    let options = StoreOptions {
+        on_event: Some(Arc::new(move |event: StoreEvent| {
+            if let StoreEvent::CompactionFinished { bytes_written, .. } = event {
+                sink.lock().unwrap().push(bytes_written);
+            }
+        })),
+        ..Default::default()
+    };
+    let (store, _) = Store::open_with_options(Path::new(&dir), options.clone()).unwrap();
+    let value = |i: usize| format!("{}-{}", i, "x".repeat(100));
+    let fill = |store: &Store, round: usize| {
+        for batch in 0..40 {
+            store.set(&format!("filler/{}/{:02}", round, batch), "x", false).unwrap();
+            store.flush().unwrap();
+        }
+        store.compact().unwrap();
+    };
+    
+    for i in 0..2000 {
+        store.set(&format!("events/2024-01/{:04}", i), &value(i), false).unwrap();
+    }
+    store.flush().unwrap();
+    store.delete("events/2024-01/0001").unwrap();
+    store.set("events/2024-05/live", "hot", false).unwrap();
+    
+    let report = store.seal_prefix("events/2024-01").unwrap();
+    assert_eq!(report.prefix, "events/2024-01/");
+    assert_eq!(report.keys, 1999);
+    assert!(!report.segments.is_empty() && report.bytes > 200_000, "{:?}", report);
+    assert_eq!(store.sealed_prefixes(), vec!["events/2024-01/"]);
+    
+    // Reads find the archived data through every lookup
+    assert_eq!(store.get("events/2024-01/0042").unwrap(), Some(value(42)));
+    assert_eq!(store.get("events/2024-01/0001").unwrap(), None);
+    assert_eq!(store.scan_prefix("events/2024-01/", usize::MAX).unwrap().len(), 1999);
+    assert_eq!(store.get_range("events/2024-01/0010", "events/2024-01/0013").unwrap().len(), 3);
+    assert_eq!(store.get_pattern("events/2024-01/000*").unwrap().len(), 9);
+    
+    // Writes under the prefix, or covering it, are refused; the rest of the tree is not
+    for err in [
+        store.set("events/2024-01/new", "x", false).unwrap_err(),
+        store.delete("events/2024-01/0042").unwrap_err(),
+        store.delete_subtree("events").unwrap_err(),
+        store.set_many(vec![("events/2024-01/a".to_string(), "x".to_string())], None).unwrap_err(),
+    ] {
+        assert!(matches!(&err, WalDbError::Sealed { prefix, .. } if prefix == "events/2024-01/"), "{}", err);
+    }
+    store.set("events/2024-05/more", "hot", false).unwrap();
+    
+    // Compaction drops the copies the archive replaced and never rewrites the archive
+    let archive_files: Vec<(String, Vec<u8>)> = report.segments.iter()
+        .map(|name| (name.clone(), fs::read(Path::new(&dir).join(name)).unwrap()))
+        .collect();
+    fill(&store, 0);
+    let (_, _, l2) = store.segment_counts();
+    assert!(l2 > 0, "Expected an L2 segment");
+    {
+        let written = written.lock().unwrap();
+        assert!(!written.is_empty());
+        assert!(written.iter().all(|&bytes| bytes < report.bytes / 4), "{:?} vs archive of {}", written, report.bytes);
+    }
+    for (name, bytes) in &archive_files {
+        assert_eq!(&fs::read(Path::new(&dir).join(name)).unwrap(), bytes, "{} was rewritten", name);
+    }
+    assert_eq!(store.get("events/2024-01/1999").unwrap(), Some(value(1999)));
+    assert_eq!(store.scan_prefix("events/2024-01/", usize::MAX).unwrap().len(), 1999);
+    drop(store);
+    
+    // The seal and the archive survive a reopen
+    let (store, _) = Store::open_with_options(Path::new(&dir), options.clone()).unwrap();
+    assert_eq!(store.sealed_prefixes(), vec!["events/2024-01/"]);
+    assert_eq!(store.get("events/2024-01/0042").unwrap(), Some(value(42)));
+    assert!(store.set("events/2024-01/new", "x", false).is_err());
+    
+    // Unsealed, the prefix takes writes again, and a delete keeps shadowing the archive
+    // even once its tombstone reaches the bottom level
+    assert!(store.unseal_prefix("events/2024-01").unwrap());
+    assert!(!store.unseal_prefix("events/2024-01").unwrap());
+    store.set("events/2024-01/new", "x", false).unwrap();
+    store.delete("events/2024-01/0042").unwrap();
+    fill(&store, 1);
+    assert_eq!(store.get("events/2024-01/0042").unwrap(), None);
+    assert_eq!(store.get("events/2024-01/new").unwrap(), Some("x".to_string()));
+    
+    // Sealing again folds the old archive into the new one
+    let resealed = store.seal_prefix("events/2024-01/").unwrap();
+    assert_eq!(resealed.keys, 1999);
+    for name in &report.segments {
+        assert!(!Path::new(&dir).join(name).exists(), "{} outlived the reseal", name);
+    }
+    drop(store);
+    
+    let store = Store::open(Path::new(&dir)).unwrap();
+    assert_eq!(store.get("events/2024-01/0042").unwrap(), None);
+    assert_eq!(store.get("events/2024-01/new").unwrap(), Some("x".to_string()));
+    assert_eq!(store.get("events/2024-01/0043").unwrap(), Some(value(43)));
+    assert_eq!(store.get("events/2024-05/more").unwrap(), Some("hot".to_string()));
+    
+    cleanup(&dir);
+    println!("✓ Sealed prefix archive test passed");
+}
+
+fn test_value_dedup_reclaim() {
+    println!("Testing deduplicated values share storage and reclaim once unreferenced...");
+    let dir = test_dir("value_dedup");
+    let options = StoreOptions { dedup_values_over: Some(1024), ..Default::default() };
+    let (store, _) = Store::open_with_options(Path::new(&dir), options.clone()).unwrap();
+    let value = |v: usize| format!("{}:{}", v, "x".repeat(4094));
+    let on_disk = |dir: &str| -> (u64, u64) {
+        let mut segments = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "seg") {
+                segments += fs::metadata(&path).unwrap().len();
+            }
+        }
+        (segments, fs::metadata(Path::new(dir).join("values.log")).unwrap().len())
+    };
+    
+    // 100k keys over 10 distinct 4KB values: 400MB as written, which the WAL keeps whole
+    for chunk in 0..100 {
+        let entries = (0..1000)
+            .map(|i| chunk * 1000 + i)
+            .map(|n| (format!("docs/{}/{:06}", n % 10, n), value(n % 10)))
+            .collect();
+        store.set_many(entries, None).unwrap();
+    }
+    store.flush().unwrap();
+    store.compact().unwrap();
+    let (segments, values) = on_disk(&dir);
+    assert!(values < 64 * 1024, "values.log holds {} bytes for 10 distinct values", values);
+    assert!(segments < 20 * 1024 * 1024, "segments hold {} bytes", segments);
+    assert_eq!(store.get("docs/3/012343").unwrap(), Some(value(3)));
+    assert_eq!(store.scan_prefix("docs/7/", usize::MAX).unwrap().len(), 10_000);
+    
+    // Nothing is unreferenced yet
+    assert_eq!(store.reclaim_values().unwrap(), 0);
+    
+    // Delete the keys holding 8 of the values; their references are dead once covered
+    for v in 0..8 {
+        store.delete_subtree(&format!("docs/{}", v)).unwrap();
+    }
+    let freed = store.reclaim_values().unwrap();
+    let (_, shrunk) = on_disk(&dir);
+    assert!(freed > 0 && shrunk == values - freed, "freed {} of {}, left {}", freed, values, shrunk);
+    assert!(shrunk < 10 * 1024, "values.log still holds {} bytes", shrunk);
+    assert_eq!(store.get("docs/8/000008").unwrap(), Some(value(8)));
+    assert_eq!(store.get("docs/9/099999").unwrap(), Some(value(9)));
+    assert_eq!(store.get("docs/3/012343").unwrap(), None);
+    let survivors = store.scan_prefix("docs/", usize::MAX).unwrap();
+    assert_eq!(survivors.len(), 20_000);
+    assert!(survivors.iter().all(|(key, v)| *v == value(key[5..6].parse().unwrap())));
+    
+    // A reclaimed value is stored afresh when written again
+    store.set("docs/0/again", &value(0), false).unwrap();
+    store.flush().unwrap();
+    assert_eq!(store.get("docs/0/again").unwrap(), Some(value(0)));
+    drop(store);
+    
+    // References resolve after a reopen, even with deduplication turned off
+    let store = Store::open(Path::new(&dir)).unwrap();
+    assert_eq!(store.get("docs/0/again").unwrap(), Some(value(0)));
+    assert_eq!(store.get("docs/9/000019").unwrap(), Some(value(9)));
+    assert_eq!(store.get_pattern("docs/8/00000*").unwrap(), vec![("docs/8/000008".to_string(), value(8))]);
+    drop(store);
+    
+    // A segment of references without the log it refers to is corruption, not missing data
+    fs::remove_file(Path::new(&dir).join("values.log")).unwrap();
+    assert!(matches!(Store::open(Path::new(&dir)), Err(WalDbError::Corruption { .. })));
+    
+    cleanup(&dir);
+    println!("✓ Value dedup reclaim test passed");
+}
+
+fn test_multi_dir_placement() {
+    println!("Testing segments spread across data directories...");
+    let dir = test_dir("multi_dir_fast");
+    let big = test_dir("multi_dir_big");
+    let spare = test_dir("multi_dir_spare");
+    let names = |dir: &str| -> Vec<String> {
+        let mut names: Vec<String> = segment_files(dir).iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+    
+    // WAL and L0 on the fast disk, compacted levels on the big one
+    let tiered = StoreOptions {
+        data_dirs: vec![big.clone().into()],
+        placement: SegmentPlacement::Tiered { l0: None, cold: Some(0) },
+        ..Default::default()
+    };
+    let archives = {
+        let (store, _) = Store::open_with_options(Path::new(&dir), tiered.clone()).unwrap();
+        for batch in 0..45 {
+            let entries = (0..50).map(|i| (format!("k/{:02}/{:03}", batch, i), format!("v{}", i))).collect();
+            store.set_many(entries, None).unwrap();
+            store.flush().unwrap();
+        }
+        store.compact().unwrap();
+        let (l0, l1, l2) = store.segment_counts();
+        assert!(l1 > 0 && l2 > 0, "Expected L1 and L2 segments, got {:?}", (l0, l1, l2));
+        assert_eq!(names(&dir).len(), l0, "{:?}", names(&dir));
+        assert_eq!(names(&big).len(), l1 + l2, "{:?}", names(&big));
+        assert!(Path::new(&dir).join("wal.log").exists() && !Path::new(&big).join("wal.log").exists());
+        
+        // Archives are cold too
+        let archives: Vec<String> = store.seal_prefix("k/00").unwrap().segments.iter()
+            .map(|name| name.rsplit('/').next().unwrap().to_string())
+            .collect();
+        assert!(!archives.is_empty() && archives.iter().all(|name| names(&big).contains(name)), "{:?}", archives);
+        archives
+    };
+    
+    let check = |store: &Store| {
+        assert_eq!(store.scan_prefix("k/", usize::MAX).unwrap().len(), 45 * 50);
+        assert_eq!(store.get("k/00/007").unwrap(), Some("v7".to_string()));
+        assert_eq!(store.get("k/44/049").unwrap(), Some("v49".to_string()));
+    };
+    {
+        let (store, _) = Store::open_with_options(Path::new(&dir), tiered.clone()).unwrap();
+        check(&store);
+    }
+    
+    // Dirs listed differently from when the files were written, and one file moved by
+    // hand: each is found wherever it is now
+    let moved = names(&big).into_iter().find(|name| !archives.contains(name)).unwrap();
+    fs::rename(Path::new(&big).join(&moved), Path::new(&dir).join(&moved)).unwrap();
+    let shuffled = StoreOptions { data_dirs: vec![spare.clone().into(), big.clone().into()], ..Default::default() };
+    {
+        let (store, report) = Store::open_with_options(Path::new(&dir), shuffled).unwrap();
+        assert!(report.ignored_files.is_empty(), "{:?}", report.ignored_files);
+        check(&store);
+        let mut dump = Vec::new();
+        store.dump_segment(&moved, &mut dump, Default::default()).unwrap();
+        
+        // New files follow the new placement, and compaction still finds and deletes its inputs
+        for batch in 0..45 {
+            store.set(&format!("more/{:02}", batch), "x", false).unwrap();
+            store.flush().unwrap();
+        }
+        store.compact().unwrap();
+        let (l0, l1, l2) = store.segment_counts();
+        assert_eq!(names(&dir).len() + names(&big).len() + names(&spare).len(), l0 + l1 + l2 + archives.len());
+        assert!(names(&spare).is_empty());
+        check(&store);
+    }
+    {
+        let (store, _) = Store::open_with_options(Path::new(&dir), tiered.clone()).unwrap();
+        check(&store);
+        assert_eq!(store.get("more/44").unwrap(), Some("x".to_string()));
+    }
+    cleanup(&dir);
+    cleanup(&big);
+    
+    // Balanced spreads new segments over every directory
+    let balanced = StoreOptions {
+        data_dirs: vec![big.clone().into(), spare.clone().into()],
+        placement: SegmentPlacement::Balanced,
+        ..Default::default()
+    };
+    {
+        let (store, _) = Store::open_with_options(Path::new(&dir), balanced.clone()).unwrap();
+        for batch in 0..9 {
+            store.set(&format!("b/{}", batch), &"x".repeat(1000), false).unwrap();
+            store.flush().unwrap();
+        }
+        for d in [&dir, &big, &spare] {
+            assert!(!names(d).is_empty(), "No segments in {}", d);
+        }
+    }
+    let (store, _) = Store::open_with_options(Path::new(&dir), balanced).unwrap();
+    assert_eq!(store.get("b/8").unwrap(), Some("x".repeat(1000)));
+    drop(store);
+    
+    // A tier naming a directory that wasn't given is refused up front
+    let bad = StoreOptions { placement: SegmentPlacement::Tiered { l0: None, cold: Some(1) }, data_dirs: vec![big.clone().into()], ..Default::default() };
+    assert!(matches!(Store::open_with_options(Path::new(&dir), bad), Err(WalDbError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput));
+    
+    cleanup(&dir);
+    cleanup(&big);
+    cleanup(&spare);
+    println!("✓ Multi-directory placement test passed");
+}
+
+fn test_rebuild_into() {
+    println!("Testing rebuild into a fresh directory and swap...");
+    let dir = test_dir("rebuild_src");
+    let dest = test_dir("rebuild_dest");
+    let everything = |store: &dyn Keyspace| store.scan_prefix("", usize::MAX).unwrap();
+    
+    // Fragmented: overwrites and deletes spread over every level, plus a blob and a seal
+    let store = Store::open(Path::new(&dir)).unwrap();
+    for round in 0..30 {
+        for i in 0..50 {
+            store.set(&format!("items/{:02}/{:03}", round % 7, i), &format!("v{}", round), false).unwrap();
+        }
+        store.delete(&format!("items/{:02}/{:03}", round % 7, round)).unwrap();
+        store.flush().unwrap();
+    }
+    store.compact().unwrap();
+    store.delete_subtree("items/03").unwrap();
+    store.set("archive/2024/a", "old", false).unwrap();
+    store.seal_prefix("archive/2024").unwrap();
+    store.set_file("files/logo", b"\x89PNG not really").unwrap();
+    store.set("hot/unflushed", "memtable", false).unwrap();
+    let (l0, l1, l2) = store.segment_counts();
+    assert!(l0 + l1 + l2 > 1);
+    let last_seq = store.set("hot/last", "x", false).unwrap();
+    let before = everything(&store);
+    
+    // Reject mode: the old store refuses writes from the start and stays that way
+    let handle = StoreHandle::new(store);
+    let old = handle.rebuild(Path::new(&dest), RebuildOptions::default()).unwrap();
+    assert!(old.is_read_only());
+    assert!(matches!(old.set("hot/late", "x", false), Err(WalDbError::ReadOnly)));
+    assert_eq!(handle.current().segment_counts(), (0, 0, 1));
+    assert_eq!(everything(&handle), before);
+    assert_eq!(handle.get_file("files/logo").unwrap(), b"\x89PNG not really");
+    assert_eq!(handle.current().sealed_prefixes(), vec!["archive/2024/".to_string()]);
+    assert!(handle.set("archive/2024/b", "x", false).is_err());
+    assert_eq!(handle.set("hot/next", "y", false).unwrap(), last_seq + 1);
+    
+    // Nothing refers to the old directory any more
+    drop(old);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(handle.get("items/00/049").unwrap(), Some("v28".to_string()));
+    drop(handle);
+    let reopened = Store::open(Path::new(&dest)).unwrap();
+    assert_eq!(reopened.get("hot/next").unwrap(), Some("y".to_string()));
+    assert_eq!(reopened.get_file("files/logo").unwrap(), b"\x89PNG not really");
+    drop(reopened);
+    
+    // Catch-up mode: writes carry on during the copy and after it, and none are lost
+    let source = test_dir("rebuild_catch_up");
+    let store = Store::open(Path::new(&source)).unwrap();
+    for i in 0..2000 {
+        store.set(&format!("base/{:02}/{:02}", i / 100, i % 100), "x", false).unwrap();
+    }
+    store.flush().unwrap();
+    let copy = store.rebuild_into(Path::new(&dir), RebuildOptions { mode: RebuildMode::CatchUp, ..Default::default() }).unwrap();
+    assert!(!store.is_read_only());
+    store.set("base/00/00", "changed", false).unwrap();
+    store.delete("base/00/01").unwrap();
+    store.delete_subtree("base/19").unwrap();
+    store.flush().unwrap();
+    store.set("after/flush", "x", false).unwrap();
+    assert_eq!(copy.catch_up_from(&store).unwrap(), 4);
+    assert_eq!(everything(&copy), everything(&store));
+    assert_eq!(copy.catch_up_from(&store).unwrap(), 0);
+    drop(copy);
+    cleanup(&dir);
+    
+    // Through a handle, writers keep going while it rebuilds and swaps
+    let handle = StoreHandle::new(store);
+    let writer = {
+        let handle = handle.clone();
+        thread::spawn(move || {
+            let mut acked = Vec::new();
+            for i in 0..3000 {
+                let key = format!("live/{:04}", i);
+                if handle.set(&key, "x", false).is_ok() {
+                    acked.push(key);
+                }
+            }
+            acked
+        })
+    };
+    let old = handle.rebuild(Path::new(&dir), RebuildOptions { mode: RebuildMode::CatchUp, ..Default::default() }).unwrap();
+    let acked = writer.join().unwrap();
+    assert_eq!(acked.len(), 3000);
+    assert!(old.is_read_only());
+    for key in &acked {
+        assert_eq!(handle.get(key).unwrap(), Some("x".to_string()), "{} lost in the swap", key);
+    }
+    assert_eq!(handle.get("base/00/00").unwrap(), Some("changed".to_string()));
+    assert!(handle.get("base/00/01").unwrap().is_none() && handle.get("base/19/99").unwrap().is_none());
+    
+    // Not into a directory that already holds something
+    assert!(matches!(handle.current().rebuild_into(Path::new(&dir), RebuildOptions::default()),
+                     Err(WalDbError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists));
+    assert!(!handle.current().is_read_only());
+    
+    drop(old);
+    drop(handle);
+    cleanup(&source);
+    cleanup(&dir);
+    cleanup(&dest);
+    println!("✓ Rebuild and swap test passed");
+}
+
+fn test_as_of_history_floor() {
+    println!("Testing as-of reads across compaction...");
+    let dir = test_dir("as_of_floor");
+    let store = Store::open(Path::new(&dir)).unwrap();
+    
+    let seq = store.set("incident/flag", "on", false).unwrap();
+    store.flush().unwrap();
+    store.set("incident/flag", "off", false).unwrap();
+    store.flush().unwrap();
+    store.delete("incident/flag").unwrap();
+    store.flush().unwrap();
+    assert_eq!(store.as_of(seq).unwrap().get("incident/flag").unwrap(), Some("on".to_string()));
+    
+    // Enough flushes to merge every version, and the tombstone, into L2
+    for batch in 0..40 {
+        store.set(&format!("filler/{:02}", batch), "x", false).unwrap();
+        store.flush().unwrap();
+    }
+    store.compact().unwrap();
+    let (_, _, l2) = store.segment_counts();
+    assert!(l2 > 0, "Expected an L2 segment");
+    assert!(store.history_floor() > seq);
+    
+    let error = store.as_of(seq).unwrap_err();
+    assert!(matches!(error, WalDbError::HistoryUnavailable { seq: asked, .. } if asked == seq));
+    assert!(error.to_string().contains("history floor"), "{}", error);
+    
+    // The floor is in the manifest, and anything at or past it still reads
+    let floor = store.history_floor();
+    drop(store);
+    let store = Store::open(Path::new(&dir)).unwrap();
+    assert_eq!(store.history_floor(), floor);
+    assert!(store.as_of(seq).is_err());
+    assert_eq!(store.as_of(floor).unwrap().get("incident/flag").unwrap(), None);
+    
+    drop(store);
+    cleanup(&dir);
+    println!("✓ As-of history floor test passed");
+}
+
+fn test_unique_segment_names() {
+    println!("Testing every segment gets a file of its own...");
+    let dir = test_dir("unique_names");
+    let dir_path = Path::new(&dir);
+    let store = Store::open(dir_path).unwrap();
+    
+    // Flushes with nothing new in between, and compactions back to back within a second
+    let mut expected = Vec::new();
+    for round in 0..3 {
+        for batch in 0..4 {
+            let key = format!("round{}/batch{}", round, batch);
+            store.set(&key, "v", false).unwrap();
+            expected.push(key);
+            store.flush().unwrap();
+            store.flush().unwrap();
+        }
+        store.compact().unwrap();
+    }
+    let (l0, l1, l2) = store.segment_counts();
+    assert_eq!((l0, l1), (0, 3));
+    assert_eq!(segment_files(&dir).len(), l0 + l1 + l2);
+    drop(store);
+    
+    let store = Store::open(dir_path).unwrap();
+    for key in &expected {
+        assert_eq!(store.get(key).unwrap(), Some("v".to_string()), "{} lost", key);
+    }
+    drop(store);
+    
+    // Names from before the counter still open, and new files number past them
+    let mut names: Vec<String> = segment_files(&dir).iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect();
+    names.sort();
+    let renamed = names.pop().unwrap();
+    fs::rename(dir_path.join(&renamed), dir_path.join("l1_0000000042.seg")).unwrap();
+    let manifest = fs::read_to_string(dir_path.join("manifest.log")).unwrap();
+    fs::write(dir_path.join("manifest.log"), manifest.replace(&renamed, "l1_0000000042.seg")).unwrap();
+    let store = Store::open(dir_path).unwrap();
+    store.set("after", "v", false).unwrap();
+    store.flush().unwrap();
+    let added: Vec<String> = segment_files(&dir).iter()
+        .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+        .filter(|name| !names.contains(name) && name != "l1_0000000042.seg")
+        .collect();
+    assert!(added.len() == 1 && added[0] > renamed, "{:?} after {}", added, renamed);
+    for key in &expected {
+        assert_eq!(store.get(key).unwrap(), Some("v".to_string()), "{} lost", key);
+    }
+    drop(store);
+    
+    // A live file listed twice means one segment was written over another, and open says so
+    let manifest = fs::read_to_string(dir_path.join("manifest.log")).unwrap();
+    let listed = manifest.lines().find(|line| line.ends_with("|1|l1_0000000042.seg")).unwrap().to_string();
+    fs::write(dir_path.join("manifest.log"), format!("{}{}\n", manifest, listed)).unwrap();
+    let error = Store::open(dir_path).unwrap_err();
+    assert!(error.to_string().contains("lists segment l1_0000000042.seg twice"), "{}", error);
+    
+    cleanup(&dir);
+    println!("✓ Unique segment names test passed");
+}
+
+fn range_store(dir: &str) -> Store {
+    let options = StoreOptions {
+        compaction: CompactionOptions { target_segment_bytes: Some(32 * 1024), ..Default::default() },
+        ..Default::default()
+    };
+    Store::open_with_options(Path::new(dir), options).unwrap().0
+}
+
+fn check_range_reads(store: &Store) {
+    let value = "c".repeat(200);
+    for batch in 0..40 {
+        for i in 0..100 {
+            assert_eq!(store.get(&format!("cold/{:02}/{:03}", batch, i)).unwrap(), Some(value.clone()));
+        }
+    }
+    for i in 0..50 {
+        assert_eq!(store.get(&format!("queues/{:02}", i)).unwrap(), Some("round-7".to_string()));
+    }
+    
+    // Ranges spanning many L2 segments come back whole and in order
+    let all = store.get_range("cold/", "cold0").unwrap();
+    assert_eq!(all.len(), 4000);
+    assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(store.get_range("cold/05/050", "cold/25/050").unwrap().len(), 2000);
+    assert_eq!(store.range_iter("cold/05/050", "cold/25/050").unwrap().count(), 2000);
+    assert_eq!(store.get_range("cold/39/099", "queues/01").unwrap().len(), 2);
+}
+
+fn test_compact_range() {
+    println!("Testing compact_range on a hot prefix...");
+    let dir = test_dir("compact_range");
+    let store = range_store(&dir);
+    
+    // Cold data merged down into L2, cut into segments of about 32KB
+    let value = "c".repeat(200);
+    for batch in 0..40 {
+        for i in 0..100 {
+            store.set(&format!("cold/{:02}/{:03}", batch, i), &value, false).unwrap();
+        }
+        store.flush().unwrap();
+    }
+    store.compact().unwrap();
+    let (_, _, cold_segments) = store.segment_counts();
+    assert!(cold_segments > 10, "Expected the L2 merge cut into many segments, got {}", cold_segments);
+    
+    // The hot prefix rewritten over and over, ending up in L1
+    for round in 0..8 {
+        for i in 0..50 {
+            store.set(&format!("queues/{:02}", i), &format!("round-{}", round), false).unwrap();
+        }
+        store.flush().unwrap();
+    }
+    store.compact().unwrap();
+    let (l0, l1, _) = store.segment_counts();
+    assert_eq!((l0, l1), (0, 2));
+    
+    // Only the hot segments are merged; the cold ones stay as they are
+    let files = segment_files(&dir);
+    let total: u64 = files.iter().map(|p| fs::metadata(p).unwrap().len()).sum();
+    let before = store.compaction_stats();
+    assert_eq!(store.compact_range("queues/", "queues0").unwrap(), 2);
+    let after = store.compaction_stats();
+    let (written, read) = (after.bytes_written - before.bytes_written, after.bytes_read - before.bytes_read);
+    assert!(written > 0 && written * 20 < total, "Rewrote {} of {} bytes", written, total);
+    assert!(read * 20 < total, "Read {} of {} bytes", read, total);
+    assert_eq!(files.iter().filter(|p| p.exists()).count(), cold_segments);
+    assert_eq!(store.segment_counts(), (0, 0, cold_segments + 1));
+    
+    // Nothing overlaps an empty stretch of keys, or a backwards range
+    assert_eq!(store.compact_range("m", "p").unwrap(), 0);
+    assert_eq!(store.compact_range("queues0", "queues/").unwrap(), 0);
+    check_range_reads(&store);
+    drop(store);
+    
+    // The bounds come back from the manifest: the hot segment is still found on its own
+    let store = range_store(&dir);
+    check_range_reads(&store);
+    assert_eq!(store.compact_range("queues/05", "queues/06").unwrap(), 1);
+    check_range_reads(&store);
+    
+    cleanup(&dir);
+    println!("✓ compact_range test passed");
+}
+
+fn test_segment_bounds_skip_reads() {
+    println!("Testing reads skip segments outside their key bounds...");
+    let dir = test_dir("segment_bounds");
+    
+    // Time-ordered keys merged into L2, so each segment holds a month or two
+    let value = "e".repeat(200);
+    let segments = {
+        let store = range_store(&dir);
+        for (year, month) in (2020..2024).flat_map(|year| (1..=10).map(move |month| (year, month))) {
+            for i in 0..100 {
+                store.set(&format!("events/{}-{:02}/{:03}", year, month, i), &value, false).unwrap();
+            }
+            store.flush().unwrap();
+        }
+        store.compact().unwrap();
+        store.segment_counts().2
+    };
+    assert!(segments >= 8, "Expected the L2 merge cut into many segments, got {}", segments);
+    
+    // Without the manifest's keys lines the bounds come from the segment footers alone
+    let manifest = Path::new(&dir).join("manifest.log");
+    let text = fs::read_to_string(&manifest).unwrap();
+    let kept: String = text.lines().filter(|line| !line.starts_with("keys|")).map(|line| format!("{}\n", line)).collect();
+    assert!(kept.len() < text.len());
+    fs::write(&manifest, kept).unwrap();
+    
+    let store = range_store(&dir);
+    let blocks = || {
+        let stats = store.cache_stats();
+        stats.hits + stats.misses
+    };
+    let read = |f: &dyn Fn()| {
+        let before = blocks();
+        f();
+        blocks() - before
+    };
+    
+    // Reads past every segment's keys touch no block
+    assert_eq!(read(&|| assert!(store.scan_prefix("events/2024-06/", usize::MAX).unwrap().is_empty())), 0);
+    assert_eq!(read(&|| assert!(store.get_range("events/2024", "events/2025").unwrap().is_empty())), 0);
+    assert_eq!(read(&|| assert!(store.get_pattern("events/2024-*/00?").unwrap().is_empty())), 0);
+    assert_eq!(read(&|| assert!(store.range_iter("archive/", "archive0").unwrap().next().is_none())), 0);
+    assert_eq!(read(&|| assert_eq!(store.get("events/2024-06/001").unwrap(), None)), 0);
+    assert_eq!(store.lookup_stats().blocks_read, 0);
+    
+    // A narrow window reads a fraction of what the whole range does
+    let all = read(&|| assert_eq!(store.scan_prefix("events/", usize::MAX).unwrap().len(), 4000));
+    let month = read(&|| assert_eq!(store.scan_prefix("events/2022-03/", usize::MAX).unwrap().len(), 100));
+    assert!(month * 10 < all, "A month read {} of {} blocks", month, all);
+    let pattern = read(&|| assert_eq!(store.get_pattern("events/2022-03/*").unwrap().len(), 100));
+    assert!(pattern * 10 < all, "A month's pattern read {} of {} blocks", pattern, all);
+    assert_eq!(store.get_pattern("events/*/099").unwrap().len(), 40);
+    assert_eq!(store.get("events/2022-03/042").unwrap(), Some(value.clone()));
+    drop(store);
+    
+    cleanup(&dir);
+    println!("✓ Segment bounds test passed");
+}
+
+fn test_max_segment_size() {
+    println!("Testing files stay under max_segment_size...");
+    let dir = test_dir("max_segment_size");
+    let copy = test_dir("max_segment_size_copy");
+    // The shape of gigabytes under a 64MB cap, at a size the suite can afford
+    let cap = 256 * 1024;
+    let options = || StoreOptions { max_segment_size: Some(cap), ..Default::default() };
+    let store = Store::open_with_options(Path::new(&dir), options()).unwrap().0;
+    
+    // Batches of about 1MB each, so every flush is cut several times, then merged down
+    let value = "m".repeat(500);
+    let batch = |b: usize| (0..2000).map(|i| (format!("big/{:02}/{:04}", b, i), value.clone())).collect::<Vec<_>>();
+    store.set_many(batch(0), None).unwrap();
+    store.flush().unwrap();
+    assert!(segment_files(&dir).len() >= 4, "A 1MB flush should be cut into several files");
+    for b in 1..12 {
+        store.set_many(batch(b), None).unwrap();
+    }
+    store.flush().unwrap();
+    store.compact().unwrap();
+    
+    // Every file, and so every index open parses, is bounded by the cap plus the block,
+    // index and bloom written after the cut
+    let check_files = |dir: &str| {
+        let sizes: Vec<u64> = segment_files(dir).iter().map(|p| fs::metadata(p).unwrap().len()).collect();
+        let total: u64 = sizes.iter().sum();
+        assert!(total > 12 * 1024 * 1024, "Only {} bytes of segments", total);
+        assert!(sizes.iter().all(|&size| size <= cap + 64 * 1024), "A file is past the cap: {:?}", sizes);
+        assert!(sizes.len() as u64 >= total / (cap + 64 * 1024));
+    };
+    check_files(&dir);
+    let (_, _, l2) = store.segment_counts();
+    assert!(l2 > 10, "Expected the L2 merge cut into many files, got {}", l2);
+    
+    let check_reads = |store: &Store| {
+        for b in 0..12 {
+            for i in (0..2000).step_by(37) {
+                assert_eq!(store.get(&format!("big/{:02}/{:04}", b, i)).unwrap().as_deref(), Some(value.as_str()));
+            }
+        }
+        assert_eq!(store.range_iter("big/", "big0").unwrap().count(), 24000);
+        assert_eq!(store.get_range("big/03/1990", "big/04/0010").unwrap().len(), 20);
+    };
+    check_reads(&store);
+    
+    // Reopened, and copied by rebuild_into, which cuts its files the same way
+    drop(store);
+    let store = Store::open_with_options(Path::new(&dir), options()).unwrap().0;
+    check_reads(&store);
+    let rebuilt = store.rebuild_into(Path::new(&copy), RebuildOptions { store: options(), ..Default::default() }).unwrap();
+    check_files(&copy);
+    check_reads(&rebuilt);
+    
+    drop(rebuilt);
+    drop(store);
+    cleanup(&dir);
+    cleanup(&copy);
+    println!("✓ Max segment size test passed");
+}
+
+fn test_flush_compact_backup_stress() {
+    println!("Testing flush, compaction and backup racing heavy writes...");
+    let dir = test_dir("admin_stress");
+    let store = Arc::new(Store::open(Path::new(&dir)).unwrap());
+    let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let running = |store: &Arc<Store>, job: fn(&Store, usize)| {
+        let (store, stop) = (store.clone(), stop.clone());
+        thread::spawn(move || {
+            let mut round = 0;
+            while !stop.load(std::sync::atomic::Ordering::SeqCst) {
+                job(&store, round);
+                round += 1;
+            }
+            round
+        })
+    };
+    
+    // Each writer keeps overwriting its own keys, so the last value of each is known
+    let writers: Vec<_> = (0..4).map(|w| {
+        let (store, stop) = (store.clone(), stop.clone());
+        thread::spawn(move || {
+            let mut i = 0;
+            while !stop.load(std::sync::atomic::Ordering::SeqCst) {
+                for _ in 0..20 {
+                    store.set(&format!("w{}/{:04}", w, i % 2000), &format!("{:0>100}", i), false).unwrap();
+                    i += 1;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            i
+        })
+    }).collect();
+    let others = vec![
+        running(&store, |store, _| {
+            store.flush().unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }),
+        running(&store, |store, _| {
+            store.compact().unwrap();
+            thread::sleep(Duration::from_millis(100));
+        }),
+        running(&store, |store, round| {
+            let dest = test_dir(&format!("admin_stress_backup_{}", round));
+            let options = RebuildOptions { mode: RebuildMode::CatchUp, ..Default::default() };
+            drop(store.rebuild_into(Path::new(&dest), options).unwrap());
+            cleanup(&dest);
+            thread::sleep(Duration::from_millis(500));
+        }),
+    ];
+    
+    thread::sleep(Duration::from_secs(30));
+    stop.store(true, std::sync::atomic::Ordering::SeqCst);
+    
+    // A deadlock shows up as threads that never finish
+    let (done, finished) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let written: Vec<usize> = writers.into_iter().map(|writer| writer.join().unwrap()).collect();
+        let rounds: Vec<usize> = others.into_iter().map(|other| other.join().unwrap()).collect();
+        done.send((written, rounds)).unwrap();
+    });
+    let (written, rounds) = finished.recv_timeout(Duration::from_secs(60)).expect("Threads still running 60s after stopping: deadlock");
+    assert!(rounds.iter().all(|&round| round > 0), "{:?}", rounds);
+    
+    let check = |store: &Store| {
+        for (w, &count) in written.iter().enumerate() {
+            for i in count.saturating_sub(2000)..count {
+                assert_eq!(store.get(&format!("w{}/{:04}", w, i % 2000)).unwrap(), Some(format!("{:0>100}", i)));
+            }
+        }
+    };
+    check(&store);
+    drop(Arc::try_unwrap(store).unwrap());
+    check(&Store::open(Path::new(&dir)).unwrap());
+    
+    cleanup(&dir);
+    println!("✓ Flush, compaction and backup stress test passed");
+}
+
 fn main() {
     println!("Running WalDB Compaction Tests");
     println!("==============================\n");
@@ -304,9 +1348,26 @@ fn main() {
     test_compaction_with_overwrites();
     test_compaction_with_deletes();
     test_l1_to_l2_compaction();
+    test_l2_compaction_drops_raw_tombstones();
+    test_l2_merge_keeps_deletes();
+    test_compacted_files_removed();
+    test_contains_after_l2_compaction();
+    test_set_many_duplicate_keys();
+    test_throttled_compaction();
+    test_shutdown_interrupts_compaction();
     test_compaction_with_subtrees();
     test_compaction_persistence();
     test_compaction_under_load();
+    test_sealed_prefix_archive();
+    test_value_dedup_reclaim();
+    test_multi_dir_placement();
+    test_rebuild_into();
+    test_as_of_history_floor();
+    test_unique_segment_names();
+    test_compact_range();
+    test_segment_bounds_skip_reads();
+    test_max_segment_size();
+    test_flush_compact_backup_stress();
     
     println!("\n==============================");
     println!("All compaction tests passed! ✅");
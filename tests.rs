@@ -1,18 +1,45 @@
 // WalDB Test Suite
 // Comprehensive tests that also serve as usage examples
 
-mod waldb_store {
-    include!("waldb.rs");
-}
-
-use waldb_store::*;
+use waldb::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
 use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Counts heap allocations per thread, so a test can see what one call costs without the
+// store's background threads in the count
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+    
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// Allocations this thread makes while f runs
+fn allocations_in<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+}
 
 // Test helper to create isolated test directories
 fn test_dir(name: &str) -> String {
-    let dir = format!("/tmp/waldb_test_{}_{}", name, std::process::id());
+    let dir = std::env::temp_dir().join(format!("waldb_test_{}_{}", name, std::process::id())).to_string_lossy().into_owned();
     let _ = std::fs::remove_dir_all(&dir);
     dir
 }
@@ -22,11 +49,43 @@ fn cleanup(dir: &str) {
     let _ = std::fs::remove_dir_all(dir);
 }
 
+// Whether the suite runs against Store::open_in_memory (WALDB_TEST_BACKEND=memory) rather
+// than store directories. Tests that need files or a real reopen are skipped there (FILE_ONLY);
+// the others call reopen, which hands back the same store in memory.
+fn in_memory() -> bool {
+    static IN_MEMORY: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *IN_MEMORY.get_or_init(|| std::env::var("WALDB_TEST_BACKEND").is_ok_and(|backend| backend == "memory"))
+}
+
+// Store::open on the backend under test; in memory, dir is ignored
+fn open_store(dir: &std::path::Path) -> waldb::Result<Store> {
+    open_store_with(dir, StoreOptions::default()).map(|(store, _)| store)
+}
+
+// With the background threads a store on disk has, so tests waiting on the flusher or
+// compaction see the same thing in memory
+fn open_store_with(dir: &std::path::Path, options: StoreOptions) -> waldb::Result<(Store, OpenReport)> {
+    if !in_memory() {
+        return Store::open_with_options(dir, options);
+    }
+    Store::open_in_memory_with_options(StoreOptions { background_threads: true, ..options }).map(|store| (store, OpenReport::default()))
+}
+
+// The store in dir closed and opened again with options, so nothing is cached. In memory a
+// reopen would lose everything, so store comes back as it is; open it with the same options.
+fn reopen(store: Store, dir: &std::path::Path, options: StoreOptions) -> Store {
+    if in_memory() {
+        return store;
+    }
+    drop(store);
+    open_store_with(dir, options).unwrap().0
+}
+
 // ==================== BASIC OPERATIONS ====================
 
 fn test_simple_set_and_get() {
     let dir = test_dir("simple");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     store.set("name", "Alice", false).unwrap();
     assert_eq!(store.get("name").unwrap(), Some("Alice".to_string()));
@@ -36,7 +95,7 @@ fn test_simple_set_and_get() {
 
 fn test_update_value() {
     let dir = test_dir("update");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     store.set("counter", "1", false).unwrap();
     store.set("counter", "2", false).unwrap();
@@ -47,7 +106,7 @@ fn test_update_value() {
 
 fn test_delete_key() {
     let dir = test_dir("delete");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     store.set("temp", "data", false).unwrap();
     store.delete("temp").unwrap();
@@ -58,7 +117,7 @@ fn test_delete_key() {
 
 fn test_get_nonexistent() {
     let dir = test_dir("nonexistent");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     assert_eq!(store.get("missing").unwrap(), None);
     
@@ -69,7 +128,7 @@ fn test_get_nonexistent() {
 
 fn test_nested_paths() {
     let dir = test_dir("nested");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     store.set("users/alice/name", "Alice", false).unwrap();
     store.set("users/alice/age", "30", false).unwrap();
@@ -84,7 +143,7 @@ fn test_nested_paths() {
 
 fn test_deep_nesting() {
     let dir = test_dir("deep");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     let deep_path = "level1/level2/level3/level4/level5/level6/level7/data";
     store.set(deep_path, "deep_value", false).unwrap();
@@ -97,23 +156,105 @@ fn test_deep_nesting() {
 
 fn test_parent_scalar_violation() {
     let dir = test_dir("scalar_violation");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     store.set("config", "scalar_value", false).unwrap();
     let result = store.set("config/child", "value", false);
     
     assert!(result.is_err());
-    assert_eq!(
-        result.unwrap_err().to_string(),
-        "Cannot write under scalar parent"
-    );
+    let err = result.unwrap_err();
+    assert_eq!(err.to_string(), "Cannot write under scalar parent");
+    assert!(matches!(err, WalDbError::TreeSemantics { ref path, ancestor: None } if path == "config/child"));
+    
+    cleanup(&dir);
+}
+
+fn test_strict_reads() {
+    let dir = test_dir("strict_reads");
+    let lenient_dir = test_dir("strict_reads_off");
+    let options = StoreOptions { strict_reads: true, ..Default::default() };
+    let (store, _) = open_store_with(std::path::Path::new(&dir), options).unwrap();
+    let lenient = open_store(std::path::Path::new(&lenient_dir)).unwrap();
+    for s in [&store, &lenient] {
+        s.set("config", "scalar", false).unwrap();
+        s.set("a/b", "scalar", false).unwrap();
+        s.set("users/alice/name", "Alice", false).unwrap();
+    }
+    
+    let ancestor = |result: Result<Option<String>>| match result {
+        Err(WalDbError::TreeSemantics { ancestor, .. }) => ancestor,
+        other => panic!("Expected TreeSemantics, got {:?}", other),
+    };
+    let check = |store: &Store| {
+        // Scalars one, two and three levels up, named in the error
+        assert_eq!(ancestor(store.get("config/child")).as_deref(), Some("config"));
+        assert_eq!(ancestor(store.get("a/b/c/d")).as_deref(), Some("a/b"));
+        assert_eq!(ancestor(store.get("a/b/c/d/e")).as_deref(), Some("a/b"));
+        let err = store.get_ref("config/child").unwrap_err();
+        assert_eq!(err.to_string(), "Cannot read config/child: config holds a scalar value, so nothing can be under it");
+        assert!(matches!(store.contains("a/b/c"), Err(WalDbError::TreeSemantics { .. })));
+        
+        // Prefix reads, whether the prefix ends at the scalar or inside its subtree
+        for prefix in ["config/", "config/ch", "a/b/c/"] {
+            assert!(matches!(store.scan_prefix(prefix, 10), Err(WalDbError::TreeSemantics { .. })), "{}", prefix);
+            assert!(store.scan_prefix_iter(prefix).is_err(), "{}", prefix);
+        }
+        
+        // Misses elsewhere and hits are unaffected
+        assert_eq!(store.get("config").unwrap(), Some("scalar".to_string()));
+        assert_eq!(store.get("users/alice/email").unwrap(), None);
+        assert_eq!(store.get("users/bob/name").unwrap(), None);
+        assert_eq!(store.scan_prefix("users/bob/", 10).unwrap(), vec![]);
+        assert_eq!(store.scan_prefix("confi", 10).unwrap().len(), 1);
+        assert_eq!(store.scan_prefix_iter("users/").unwrap().count(), 1);
+    };
+    check(&store);
+    store.flush().unwrap();
+    check(&store);
+    
+    // The default store just misses
+    assert_eq!(lenient.get("config/child").unwrap(), None);
+    assert_eq!(lenient.scan_prefix("config/", 10).unwrap(), vec![]);
+    assert!(!lenient.contains("a/b/c").unwrap());
+    
+    // Namespaced reads name the ancestor inside the namespace
+    store.namespace("acme").set("plan", "free", false).unwrap();
+    match store.namespace("acme").get("plan/tier") {
+        Err(WalDbError::TreeSemantics { path, ancestor }) => {
+            assert_eq!(path, "plan/tier");
+            assert_eq!(ancestor.as_deref(), Some("plan"));
+        }
+        other => panic!("Expected TreeSemantics, got {:?}", other),
+    }
+    
+    // A deleted ancestor, in the memtable or a segment, no longer holds anything up
+    store.delete("config").unwrap();
+    assert_eq!(store.get("config/child").unwrap(), None);
+    store.flush().unwrap();
+    assert_eq!(store.get("config/child").unwrap(), None);
+    store.delete_subtree("a/").unwrap();
+    store.delete("a").unwrap();
+    assert_eq!(store.get("a/b/c").unwrap(), None);
+    assert_eq!(store.scan_prefix("a/b/", 10).unwrap(), vec![]);
+    
+    // Hits never check their ancestors, so they read no more blocks than with the option off
+    for s in [&store, &lenient] {
+        s.flush().unwrap();
+    }
+    let before = (store.lookup_stats().blocks_read, lenient.lookup_stats().blocks_read);
+    for _ in 0..100 {
+        store.get("users/alice/name").unwrap().unwrap();
+        lenient.get("users/alice/name").unwrap().unwrap();
+    }
+    assert_eq!(store.lookup_stats().blocks_read - before.0, lenient.lookup_stats().blocks_read - before.1);
     
     cleanup(&dir);
+    cleanup(&lenient_dir);
 }
 
 fn test_scalar_to_tree_conversion() {
     let dir = test_dir("scalar_to_tree");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     // First set a scalar
     store.set("node", "scalar", false).unwrap();
@@ -139,11 +280,65 @@ fn test_scalar_to_tree_conversion() {
     cleanup(&dir);
 }
 
+fn test_set_with_options() {
+    let dir = test_dir("set_with_options");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    let replace = SetOptions { replace_subtree: true, ..Default::default() };
+    let convert = SetOptions { convert_scalar_parent: true, ..Default::default() };
+    let both = SetOptions { replace_subtree: true, convert_scalar_parent: true };
+    
+    // Neither flag: a plain set, still refused under a scalar
+    store.set("a", "1", false).unwrap();
+    let err = store.set_with_options("a/b", "x", SetOptions::default()).unwrap_err();
+    assert!(matches!(err, WalDbError::TreeSemantics { ref path, ancestor: None } if path == "a/b"));
+    
+    // replace_subtree alone clears children but doesn't lift the parent check
+    store.set("r/old", "1", false).unwrap();
+    store.set_with_options("r", "v", replace).unwrap();
+    assert_eq!(store.get("r/old").unwrap(), None);
+    assert!(store.set_with_options("r/new", "x", replace).is_err());
+    
+    // convert_scalar_parent drops the parent's value and keeps its siblings' data
+    store.set("p", "scalar", false).unwrap();
+    store.set_with_options("p/c", "x", convert).unwrap();
+    assert_eq!(store.get("p").unwrap(), None);
+    assert_eq!(store.get("p/c").unwrap(), Some("x".to_string()));
+    
+    // Both: converts the parent and clears the path's own children
+    store.set("q/c/deep", "1", false).unwrap();
+    store.set("q", "scalar", true).unwrap();
+    store.set_with_options("q/c", "x", both).unwrap();
+    assert_eq!(store.get("q").unwrap(), None);
+    assert_eq!(store.get("q/c").unwrap(), Some("x".to_string()));
+    assert_eq!(store.get("q/c/deep").unwrap(), None);
+    
+    // A deleted parent no longer blocks, even when its value was flushed first
+    store.set("f", "1", false).unwrap();
+    store.flush().unwrap();
+    store.delete("f").unwrap();
+    store.set("f/g", "x", false).unwrap();
+    
+    // Conversion of a flushed scalar survives another flush and a reopen
+    store.set("s", "old", false).unwrap();
+    store.flush().unwrap();
+    store.set_with_options("s/t", "x", convert).unwrap();
+    store.flush().unwrap();
+    
+    let store = reopen(store, std::path::Path::new(&dir), StoreOptions::default());
+    assert_eq!(store.get("s").unwrap(), None);
+    assert_eq!(store.get("s/t").unwrap(), Some("x".to_string()));
+    assert_eq!(store.get("p").unwrap(), None);
+    assert_eq!(store.get("p/c").unwrap(), Some("x".to_string()));
+    store.set("s/u", "y", false).unwrap();
+    
+    cleanup(&dir);
+}
+
 // ==================== SUBTREE OPERATIONS ====================
 
 fn test_get_subtree_as_json() {
     let dir = test_dir("subtree_json");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     store.set("app/users/1/name", "Alice", false).unwrap();
     store.set("app/users/1/email", "alice@example.com", false).unwrap();
@@ -163,7 +358,7 @@ fn test_get_subtree_as_json() {
 
 fn test_delete_subtree() {
     let dir = test_dir("delete_subtree");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     store.set("temp/a", "1", false).unwrap();
     store.set("temp/b", "2", false).unwrap();
@@ -180,7 +375,7 @@ fn test_delete_subtree() {
 
 fn test_replace_subtree() {
     let dir = test_dir("replace_subtree");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     store.set("config/old/a", "1", false).unwrap();
     store.set("config/old/b", "2", false).unwrap();
@@ -194,13 +389,283 @@ fn test_replace_subtree() {
     cleanup(&dir);
 }
 
+// Strict JSON parser for checking get_subtree_json output: no raw control characters,
+// no leading zeros, escapes must be valid and surrogate escapes must pair up
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    Str(String),
+    Object(Vec<(String, Json)>),
+}
+
+fn parse_json(text: &str) -> Result<Json, String> {
+    let mut parser = JsonParser { chars: text.chars().collect(), pos: 0 };
+    let value = parser.value()?;
+    if parser.pos != parser.chars.len() {
+        return Err(format!("Trailing input at {}", parser.pos));
+    }
+    Ok(value)
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl JsonParser {
+    fn next(&mut self) -> Result<char, String> {
+        let c = *self.chars.get(self.pos).ok_or("Unexpected end of input")?;
+        self.pos += 1;
+        Ok(c)
+    }
+    
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        for expected in word.chars() {
+            if self.next()? != expected {
+                return Err(format!("Expected {} at {}", word, self.pos));
+            }
+        }
+        Ok(())
+    }
+    
+    fn value(&mut self) -> Result<Json, String> {
+        match self.chars.get(self.pos) {
+            Some('{') => self.object(),
+            Some('"') => self.string().map(Json::Str),
+            Some('t') => self.expect("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some('n') => self.expect("null").map(|_| Json::Null),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            other => Err(format!("Unexpected {:?} at {}", other, self.pos)),
+        }
+    }
+    
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect("{")?;
+        let mut members = Vec::new();
+        if self.chars.get(self.pos) == Some(&'}') {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(":")?;
+            members.push((key, self.value()?));
+            match self.next()? {
+                ',' => continue,
+                '}' => return Ok(Json::Object(members)),
+                c => return Err(format!("Unexpected {:?} in object at {}", c, self.pos)),
+            }
+        }
+    }
+    
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            code = code * 16 + self.next()?.to_digit(16).ok_or("Bad \\u escape")?;
+        }
+        Ok(code)
+    }
+    
+    fn string(&mut self) -> Result<String, String> {
+        self.expect("\"")?;
+        let mut out = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(out),
+                '\\' => match self.next()? {
+                    '"' => out.push('"'),
+                    '\\' => out.push('\\'),
+                    '/' => out.push('/'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'u' => {
+                        let unit = self.hex4()?;
+                        let code = match unit {
+                            0xD800..=0xDBFF => {
+                                self.expect("\\u")?;
+                                let low = self.hex4()?;
+                                if !(0xDC00..=0xDFFF).contains(&low) {
+                                    return Err("Unpaired high surrogate".to_string());
+                                }
+                                0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                            }
+                            0xDC00..=0xDFFF => return Err("Unpaired low surrogate".to_string()),
+                            unit => unit,
+                        };
+                        out.push(char::from_u32(code).ok_or("Bad code point")?);
+                    }
+                    c => return Err(format!("Bad escape \\{}", c)),
+                },
+                c if (c as u32) < 0x20 => return Err(format!("Raw control character {:#x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+    }
+    
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        if self.chars[self.pos] == '-' {
+            self.pos += 1;
+        }
+        let digits = |p: &mut Self| {
+            let from = p.pos;
+            while p.chars.get(p.pos).is_some_and(|c| c.is_ascii_digit()) {
+                p.pos += 1;
+            }
+            p.pos - from
+        };
+        let int_start = self.pos;
+        let int_len = digits(self);
+        if int_len == 0 || (int_len > 1 && self.chars[int_start] == '0') {
+            return Err(format!("Bad integer part at {}", start));
+        }
+        if self.chars.get(self.pos) == Some(&'.') {
+            self.pos += 1;
+            if digits(self) == 0 {
+                return Err(format!("Bad fraction at {}", start));
+            }
+        }
+        if matches!(self.chars.get(self.pos), Some('e') | Some('E')) {
+            self.pos += 1;
+            if matches!(self.chars.get(self.pos), Some('+') | Some('-')) {
+                self.pos += 1;
+            }
+            if digits(self) == 0 {
+                return Err(format!("Bad exponent at {}", start));
+            }
+        }
+        Ok(Json::Number(self.chars[start..self.pos].iter().collect()))
+    }
+}
+
+// Parsed subtree back to "relative/path" -> leaf, for comparing against what was written
+fn flatten_json(json: &Json, path: &str, out: &mut std::collections::BTreeMap<String, String>) {
+    match json {
+        Json::Object(members) => {
+            for (key, child) in members {
+                let child_path = if path.is_empty() { key.clone() } else { format!("{}/{}", path, key) };
+                flatten_json(child, &child_path, out);
+            }
+        }
+        Json::Str(s) => {
+            out.insert(path.to_string(), format!("s:{}", s));
+        }
+        leaf => {
+            out.insert(path.to_string(), format!("{:?}", leaf));
+        }
+    }
+}
+
+fn test_subtree_json_round_trip() {
+    let dir = test_dir("subtree_json_round_trip");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    let entries = [
+        ("quote\"key", "say \"hi\""),
+        ("back\\slash", "C:\\path\\"),
+        ("ctl\u{1}\u{1f}", "bell\u{7}nul\u{0}esc\u{1b}"),
+        ("lines", "a\nb\r\nc\td\u{8}\u{c}"),
+        ("emoji😀", "🎉 and 中文"),
+        ("separators", "\u{2028}\u{2029}\u{7f}"),
+        ("nested/deep/x", "leaf"),
+        ("nested/deep/y", ""),
+        ("", "empty key component"),
+        ("numbers/int", "42"),
+        ("numbers/padded", "007"),
+        ("numbers/float", "1.5"),
+        ("numbers/trailing_zero", "1.50"),
+        ("numbers/negative_zero", "-0"),
+        ("numbers/exp", "1e5"),
+        ("numbers/huge", "123456789012345678901234567890"),
+        ("literals/true", "true"),
+        ("literals/null", "null"),
+        ("literals/capital", "True"),
+    ];
+    for (key, value) in entries {
+        store.set(&format!("adv/{}", key), value, false).unwrap();
+    }
+    store.set("adv/deleted", "x", false).unwrap();
+    store.delete("adv/deleted").unwrap();
+    store.set("adv/gone/a", "x", false).unwrap();
+    store.delete_subtree("adv/gone").unwrap();
+    store.set("adv!sibling", "not under adv/", false).unwrap();
+    
+    // Default: every value is the stored string, whatever it looks like
+    let json = store.get_subtree_json("adv/").unwrap().unwrap();
+    assert!(json.chars().all(|c| (c as u32) <= 0xFFFF), "Non-BMP characters should be escaped");
+    let mut flat = std::collections::BTreeMap::new();
+    flatten_json(&parse_json(&json).unwrap_or_else(|e| panic!("{} in {}", e, json)), "", &mut flat);
+    let expected: std::collections::BTreeMap<String, String> = entries.iter()
+        .map(|(k, v)| (k.to_string(), format!("s:{}", v)))
+        .collect();
+    assert_eq!(flat, expected);
+    
+    // Same document after a flush, and with or without the trailing slash
+    store.flush().unwrap();
+    assert_eq!(store.get_subtree_json("adv").unwrap(), Some(json));
+    
+    // Typed: only canonical literals lose their quotes
+    let typed = store.get_subtree_json_with_options("adv/numbers", JsonOptions { detect_types: true }).unwrap().unwrap();
+    let mut flat = std::collections::BTreeMap::new();
+    flatten_json(&parse_json(&typed.json).unwrap(), "", &mut flat);
+    assert_eq!(flat["int"], "Number(\"42\")");
+    assert_eq!(flat["float"], "Number(\"1.5\")");
+    for key in ["padded", "trailing_zero", "negative_zero", "exp", "huge"] {
+        assert!(flat[key].starts_with("s:"), "{} should stay a string: {}", key, flat[key]);
+    }
+    let typed = store.get_subtree_json_with_options("adv/literals", JsonOptions { detect_types: true }).unwrap().unwrap();
+    assert_eq!(parse_json(&typed.json).unwrap(), Json::Object(vec![
+        ("capital".to_string(), Json::Str("True".to_string())),
+        ("null".to_string(), Json::Null),
+        ("true".to_string(), Json::Bool(true)),
+    ]));
+    
+    assert_eq!(store.get_subtree_json("missing").unwrap(), None);
+    assert_eq!(store.get_subtree_json("adv/gone").unwrap(), None);
+    
+    cleanup(&dir);
+}
+
+fn test_subtree_json_root_scalar_and_conflicts() {
+    let dir = test_dir("subtree_json_conflicts");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // A scalar at the root is the whole document
+    store.set("solo", "x\u{0}", false).unwrap();
+    assert_eq!(store.get_subtree_json("solo").unwrap(), Some("\"x\\u0000\"".to_string()));
+    
+    // Scalars that also have children: the children win and the paths are reported
+    store.set("tree/a/b", "1", false).unwrap();
+    store.set("tree/a", "shadowed", false).unwrap();
+    store.set("tree/c", "2", false).unwrap();
+    store.set("tree/c/d", "3", false).unwrap_err();  // Tree semantics still apply to writes
+    store.set("tree", "root scalar", false).unwrap();
+    
+    let tree = store.get_subtree_json_with_options("tree", JsonOptions::default()).unwrap().unwrap();
+    assert_eq!(tree.json, "{\"a\":{\"b\":\"1\"},\"c\":\"2\"}");
+    assert_eq!(tree.conflicts, vec!["tree".to_string(), "tree/a".to_string()]);
+    
+    // The whole store when the prefix is empty
+    let all = store.get_subtree_json_with_options("", JsonOptions::default()).unwrap().unwrap();
+    assert_eq!(all.json, "{\"solo\":\"x\\u0000\",\"tree\":{\"a\":{\"b\":\"1\"},\"c\":\"2\"}}");
+    assert_eq!(all.conflicts, vec!["tree".to_string(), "tree/a".to_string()]);
+    
+    cleanup(&dir);
+}
+
 // ==================== WILDCARDS (FUTURE FEATURE) ====================
 // These tests are placeholders for wildcard pattern matching functionality
 // which is planned but not yet implemented in Antler.
 
 fn test_wildcard_star_match() {
     let dir = test_dir("wildcard_star");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     store.set("users/alice/profile", "data1", false).unwrap();
     store.set("users/bob/profile", "data2", false).unwrap();
@@ -219,7 +684,7 @@ fn test_wildcard_star_match() {
 
 fn test_wildcard_question_match() {
     let dir = test_dir("wildcard_question");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     store.set("log1", "data1", false).unwrap();
     store.set("log2", "data2", false).unwrap();
@@ -243,7 +708,7 @@ fn test_wildcard_question_match() {
 
 fn test_wildcard_delete() {
     let dir = test_dir("wildcard_delete");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     // Setup test data
     store.set("temp/file1.txt", "data1", false).unwrap();
@@ -266,19 +731,255 @@ fn test_wildcard_delete() {
     cleanup(&dir);
 }
 
+fn test_streamed_pattern_delete() {
+    let dir = test_dir("streamed_delete");
+    let dir_path = std::path::Path::new(&dir);
+    let store = open_store(dir_path).unwrap();
+    for batch in 0..10 {
+        let entries = (0..100_000).map(|i| (format!("sess/{:07}", batch * 100_000 + i), "x".to_string())).collect();
+        store.set_many(entries, None).unwrap();
+    }
+    store.set("sessions", "keep", false).unwrap();
+    store.flush().unwrap();
+    
+    // Not the path/* shape, so every match is a point delete, 10,000 per chunk
+    let mut counts = Vec::new();
+    let deleted = store.delete_pattern_cancellable("sess/?*", &CancellationToken::new(), |count| counts.push(count)).unwrap();
+    assert_eq!(deleted, 1_000_000);
+    assert_eq!(counts, (1..=100).map(|chunk| chunk * 10_000).collect::<Vec<_>>());
+    assert!(store.scan_prefix("sess/", 1).unwrap().is_empty());
+    
+    let store = reopen(store, dir_path, StoreOptions::default());
+    assert!(store.scan_prefix("sess/", 1).unwrap().is_empty());
+    assert_eq!(store.get("sess/0999999").unwrap(), None);
+    assert_eq!(store.get("sessions").unwrap(), Some("keep".to_string()));
+    
+    // Cancelling keeps the chunks already written
+    let entries = (0..25_000).map(|i| (format!("jobs/{:05}", i), "x".to_string())).collect();
+    store.set_many(entries, None).unwrap();
+    let token = CancellationToken::new();
+    let result = store.delete_pattern_cancellable("jobs/*?", &token, |_| token.cancel());
+    assert!(matches!(result, Err(WalDbError::Cancelled)));
+    assert_eq!(store.scan_prefix("jobs/", usize::MAX).unwrap().len(), 15_000);
+    assert_eq!(store.delete_prefix("jobs/1").unwrap(), 10_000);
+    assert_eq!(store.scan_prefix("jobs/", usize::MAX).unwrap().len(), 5_000);
+    
+    // A path followed by /* or /** is one subtree tombstone, counted first
+    for i in 0..50 {
+        store.set(&format!("tree/a/{:02}", i), "x", false).unwrap();
+    }
+    let before = store.set("tree/b", "keep", false).unwrap();
+    assert_eq!(store.delete_pattern("tree/a/**").unwrap(), 50);
+    assert_eq!(store.set("tree/c", "x", false).unwrap(), before + 2);
+    let raw = store.get_range_raw("tree/", "tree0").unwrap();
+    assert_eq!(raw.iter().filter(|entry| matches!(entry.state, RawState::SubtreeDeleted(_))).count(), 1);
+    assert!(!raw.iter().any(|entry| entry.state == RawState::PointDeleted));
+    assert_eq!(store.delete_pattern("tree/a/*").unwrap(), 0);
+    assert_eq!(store.delete_prefix("jobs/").unwrap(), 5_000);
+    store.flush().unwrap();
+    
+    let store = reopen(store, dir_path, StoreOptions::default());
+    assert_eq!(store.get("tree/a/07").unwrap(), None);
+    assert_eq!(store.get("tree/b").unwrap(), Some("keep".to_string()));
+    assert!(store.scan_prefix("jobs/", 1).unwrap().is_empty());
+    drop(store);
+    
+    cleanup(&dir);
+}
+
+fn test_cancel_pattern_scan() {
+    let dir = test_dir("cancel_scan");
+    let store = Arc::new(open_store(std::path::Path::new(&dir)).unwrap());
+    for batch in 0..20 {
+        let entries = (0..5000).map(|i| (format!("users/{:02}/{:05}/name", batch, i), "x".to_string())).collect();
+        store.set_many(entries, None).unwrap();
+        store.flush().unwrap();
+    }
+    
+    // A leading * can't use the index, so this reads every block in the store
+    let token = CancellationToken::new();
+    let scan = {
+        let store = Arc::clone(&store);
+        let token = token.clone();
+        thread::spawn(move || store.get_pattern_cancellable("*sessions*", &token))
+    };
+    thread::sleep(Duration::from_millis(10));
+    let cancelled_at = Instant::now();
+    token.cancel();
+    let result = scan.join().unwrap();
+    assert!(matches!(result, Err(WalDbError::Cancelled)), "Expected Cancelled, got {:?}", result.map(|r| r.len()));
+    assert!(cancelled_at.elapsed() < Duration::from_millis(500), "Scan took {:?} to stop", cancelled_at.elapsed());
+    
+    // A timeout trips the same way, and a fresh token leaves the scan alone
+    let start = Instant::now();
+    let timed = CancellationToken::new().with_timeout(Duration::from_millis(10));
+    assert!(matches!(store.scan_prefix_cancellable("users/", usize::MAX, &timed), Err(WalDbError::Cancelled)));
+    assert!(start.elapsed() < Duration::from_millis(500));
+    let matches = store.get_pattern_cancellable("users/03/0000?/name", &CancellationToken::new()).unwrap();
+    assert_eq!(matches.len(), 10);
+    
+    // The store is untouched by the abandoned scans
+    assert_eq!(store.get("users/19/04999/name").unwrap(), Some("x".to_string()));
+    store.set("users/new", "y", false).unwrap();
+    assert_eq!(store.get("users/new").unwrap(), Some("y".to_string()));
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_pattern_grouped() {
+    let dir = test_dir("pattern_grouped");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    store.set("users/1/name", "Alice", false).unwrap();
+    store.set("users/1/address/city", "Paris/Lyon", false).unwrap();
+    store.set("users/10/name", "Bob", false).unwrap();
+    store.set("users/2/name", "Carol", false).unwrap();
+    store.set("users/2/tags/0", "a/b", false).unwrap();
+    store.set("users/3", "scalar", false).unwrap();
+    store.set("users-archive/1/name", "Old", false).unwrap();
+    let owned = |fields: &[(&str, &str)]| fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>();
+    
+    // Fields are relative to the group, whatever the values hold; users/3 has no field below
+    // its group and is left out
+    let expected = vec![
+        ("users/1".to_string(), owned(&[("address/city", "Paris/Lyon"), ("name", "Alice")])),
+        ("users/10".to_string(), owned(&[("name", "Bob")])),
+        ("users/2".to_string(), owned(&[("name", "Carol"), ("tags/0", "a/b")])),
+    ];
+    assert_eq!(store.get_pattern_grouped("users/*", 2, usize::MAX).unwrap(), expected);
+    assert_eq!(store.get_pattern_grouped("users/*/name", 2, usize::MAX).unwrap().len(), 3);
+    assert_eq!(store.get_pattern_grouped("users/*", 3, usize::MAX).unwrap()[0],
+               ("users/1/address".to_string(), owned(&[("city", "Paris/Lyon")])));
+    assert!(store.get_pattern_grouped("users/*", 4, usize::MAX).unwrap().is_empty());
+    assert!(store.get_pattern_grouped("users/*", 0, usize::MAX).is_err());
+    
+    // The limit counts groups, not fields
+    assert_eq!(store.get_pattern_grouped("users/*", 2, 1).unwrap(), expected[..1].to_vec());
+    assert_eq!(store.get_pattern_grouped("users/*", 2, 2).unwrap(), expected[..2].to_vec());
+    assert!(store.get_pattern_grouped("users/*", 2, 0).unwrap().is_empty());
+    
+    // The same groups in the same order from segments, with newer writes merged in
+    store.flush().unwrap();
+    assert_eq!(store.get_pattern_grouped("users/*", 2, usize::MAX).unwrap(), expected);
+    store.set("users/2/name", "Caroline", false).unwrap();
+    store.delete("users/10/name").unwrap();
+    store.flush().unwrap();
+    store.set("users/0/name", "Zed", false).unwrap();
+    let expected = vec![
+        ("users/0".to_string(), owned(&[("name", "Zed")])),
+        expected[0].clone(),
+        ("users/2".to_string(), owned(&[("name", "Caroline"), ("tags/0", "a/b")])),
+    ];
+    assert_eq!(store.get_pattern_grouped("users/*", 2, usize::MAX).unwrap(), expected);
+    for _ in 0..4 {
+        store.set("filler", "x", false).unwrap();
+        store.flush().unwrap();
+    }
+    store.compact().unwrap();
+    assert!(store.segment_counts().1 > 0);
+    assert_eq!(store.get_pattern_grouped("users/*", 2, usize::MAX).unwrap(), expected);
+    
+    // A namespace groups its own keys
+    let tenant = store.namespace("tenant");
+    tenant.set("users/1/name", "Inner", false).unwrap();
+    assert_eq!(tenant.get_pattern_grouped("users/*", 2, usize::MAX).unwrap(),
+               vec![("users/1".to_string(), owned(&[("name", "Inner")]))]);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_subtree_version() {
+    let dir = test_dir("subtree_version");
+    let store = Arc::new(open_store(std::path::Path::new(&dir)).unwrap());
+    assert_eq!(store.subtree_version("users/1").unwrap(), 0);
+    store.set("users/1/name", "Alice", false).unwrap();
+    let seq = store.set("users/1/address/city", "Paris", false).unwrap();
+    store.set("users/10/name", "Bob", false).unwrap();
+    store.set("users/2/name", "Carol", false).unwrap();
+    
+    // Only writes at or under the prefix count, so siblings like users/10 don't
+    assert_eq!(store.subtree_version("users/1").unwrap(), seq);
+    assert_eq!(store.subtree_version("users/1/").unwrap(), seq);
+    assert!(store.subtree_version("").unwrap() > seq);
+    
+    // Two clients race a read-modify-write of users/1; exactly one wins each round
+    for round in 0..20 {
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let clients: Vec<_> = (0..2).map(|client| {
+            let (store, barrier) = (Arc::clone(&store), Arc::clone(&barrier));
+            thread::spawn(move || {
+                let version = store.subtree_version("users/1").unwrap();
+                barrier.wait();
+                let entries = vec![
+                    ("users/1/name".to_string(), format!("client{}", client)),
+                    ("users/1/round".to_string(), round.to_string()),
+                ];
+                store.set_many_if_version(entries, Some("users/1"), version).unwrap()
+            })
+        }).collect();
+        let wins: Vec<bool> = clients.into_iter().map(|client| client.join().unwrap()).collect();
+        assert_eq!(wins.iter().filter(|won| **won).count(), 1, "round {}", round);
+        let winner = wins.iter().position(|won| *won).unwrap();
+        assert_eq!(store.get("users/1/name").unwrap(), Some(format!("client{}", winner)));
+        assert_eq!(store.get("users/1/round").unwrap(), Some(round.to_string()));
+        assert_eq!(store.get("users/1/address/city").unwrap(), None);
+        if round % 5 == 4 {
+            store.flush().unwrap();
+        }
+    }
+    
+    // A version read before a flush still matches after it, and a change made on either
+    // side of the flush is caught
+    let version = store.subtree_version("users/1").unwrap();
+    store.flush().unwrap();
+    assert_eq!(store.subtree_version("users/1").unwrap(), version);
+    store.set("users/1/email", "a@example.com", false).unwrap();
+    store.flush().unwrap();
+    let entries = vec![("users/1/name".to_string(), "Stale".to_string())];
+    assert!(!store.set_many_if_version(entries.clone(), Some("users/1"), version).unwrap());
+    assert_ne!(store.get("users/1/name").unwrap().as_deref(), Some("Stale"));
+    
+    // Deletes move it too, whether of one field or of the object
+    let version = store.subtree_version("users/1").unwrap();
+    store.delete("users/1/email").unwrap();
+    assert!(store.subtree_version("users/1").unwrap() > version);
+    store.flush().unwrap();
+    let version = store.subtree_version("users/1").unwrap();
+    store.delete_subtree("users/1").unwrap();
+    assert!(store.subtree_version("users/1").unwrap() > version);
+    let version = store.subtree_version("users/1").unwrap();
+    store.set("users/10/name", "Robert", false).unwrap();
+    assert!(store.set_many_if_version(entries, Some("users/1"), version).unwrap());
+    assert_eq!(store.get("users/1/name").unwrap().as_deref(), Some("Stale"));
+    
+    // In a namespace it counts local keys, and without a replace the whole namespace
+    let tenant = store.namespace("tenant");
+    let version = tenant.subtree_version("").unwrap();
+    assert_eq!(version, 0);
+    tenant.set("a", "1", false).unwrap();
+    assert!(!tenant.set_many_if_version(vec![("b".to_string(), "2".to_string())], None, version).unwrap());
+    let version = tenant.subtree_version("").unwrap();
+    assert!(tenant.set_many_if_version(vec![("b".to_string(), "2".to_string())], None, version).unwrap());
+    assert_eq!(tenant.get("b").unwrap().as_deref(), Some("2"));
+    
+    drop(store);
+    cleanup(&dir);
+}
+
 // ==================== PERSISTENCE & RECOVERY ====================
 
 fn test_persistence_across_restarts() {
     let dir = test_dir("persistence");
     
     {
-        let store = Store::open(std::path::Path::new(&dir)).unwrap();
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
         store.set("persistent", "value", false).unwrap();
         store.flush().unwrap();
     } // Store dropped
     
     {
-        let store = Store::open(std::path::Path::new(&dir)).unwrap();
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
         assert_eq!(store.get("persistent").unwrap(), Some("value".to_string()));
     }
     
@@ -289,14 +990,14 @@ fn test_wal_recovery() {
     let dir = test_dir("wal_recovery");
     
     {
-        let store = Store::open(std::path::Path::new(&dir)).unwrap();
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
         store.set("data1", "value1", false).unwrap();
         store.set("data2", "value2", false).unwrap();
         // No flush - simulate crash
     }
     
     {
-        let store = Store::open(std::path::Path::new(&dir)).unwrap();
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
         // WAL should replay automatically
         assert_eq!(store.get("data1").unwrap(), Some("value1".to_string()));
         assert_eq!(store.get("data2").unwrap(), Some("value2".to_string()));
@@ -305,113 +1006,1153 @@ fn test_wal_recovery() {
     cleanup(&dir);
 }
 
-fn test_flush_to_disk() {
-    let dir = test_dir("flush");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+// Snapshot the directory as a crash would leave it: only what already reached the files
+fn crash_copy(dir: &str, into: &str) {
+    let _ = std::fs::remove_dir_all(into);
+    std::fs::create_dir_all(into).unwrap();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_file() {
+            std::fs::copy(&path, std::path::Path::new(into).join(path.file_name().unwrap())).unwrap();
+        }
+    }
+}
+
+fn test_wait_durable_survives_crash() {
+    let dir = test_dir("wait_durable");
+    let copy = test_dir("wait_durable_copy");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
-    for i in 0..100 {
-        store.set(&format!("key{}", i), &format!("value{}", i), false).unwrap();
+    let mut lost_without_wait = 0;
+    for i in 0..20 {
+        // Durable write: must be in the copy no matter when the crash lands
+        let seq = store.set(&format!("durable/{}", i), "v", false).unwrap();
+        store.wait_durable(seq).unwrap();
+        crash_copy(&dir, &copy);
+        {
+            let recovered = open_store(std::path::Path::new(&copy)).unwrap();
+            assert_eq!(recovered.get(&format!("durable/{}", i)).unwrap(), Some("v".to_string()),
+                "acknowledged durable write {} was lost", i);
+        }
+        
+        // Plain write: allowed to vanish if the crash beats the group commit
+        store.set(&format!("plain/{}", i), "v", false).unwrap();
+        crash_copy(&dir, &copy);
+        {
+            let recovered = open_store(std::path::Path::new(&copy)).unwrap();
+            if recovered.get(&format!("plain/{}", i)).unwrap().is_none() {
+                lost_without_wait += 1;
+            }
+        }
     }
+    println!("{} of 20 writes without wait_durable missing after crash", lost_without_wait);
     
-    store.flush().unwrap();
+    // Seqs past the last assigned one would never become durable
+    assert!(store.wait_durable(u64::MAX).is_err());
     
-    // Verify segment file created
-    let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
-    let seg_files = entries.iter()
-        .filter(|e| e.as_ref().unwrap().path().extension() == Some("seg".as_ref()))
-        .count();
-    assert!(seg_files > 0);
+    // Every write kind hands back a seq that can be waited on
+    let mut batch = WriteBatch::new();
+    batch.put("batched", "v");
+    store.wait_durable(store.write(batch).unwrap()).unwrap();
+    store.wait_durable(store.set_many(vec![("many".to_string(), "v".to_string())], None).unwrap()).unwrap();
+    store.wait_durable(store.delete("many").unwrap()).unwrap();
+    store.wait_durable(store.delete_subtree("plain").unwrap()).unwrap();
     
     cleanup(&dir);
+    cleanup(&copy);
 }
 
-// ==================== BATCH OPERATIONS ====================
-
-fn test_bulk_insert() {
-    let dir = test_dir("bulk");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
-    
-    for i in 0..1000 {
-        store.set(&format!("key{:04}", i), &format!("value{}", i), false).unwrap();
-    }
+fn test_durable_and_flushed_seq() {
+    let dir = test_dir("seq_info");
+    let copy = test_dir("seq_info_copy");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    assert_eq!((store.durable_seq(), store.flushed_seq()), (0, 0));
+    
+    // Buffered writes have seqs, but aren't durable until the group commit syncs them
+    store.pause_wal_flusher(true);
+    store.set("a", "1", false).unwrap();
+    let last = store.set_many(vec![("b".to_string(), "2".to_string()), ("c".to_string(), "3".to_string())], None).unwrap();
+    assert_eq!(last, store.seq().unwrap());
+    assert!(store.durable_seq() <= store.seq().unwrap());
+    store.pause_wal_flusher(false);
+    store.wait_durable(last).unwrap();
+    assert!(store.durable_seq() >= last);
+    assert_eq!(store.flushed_seq(), 0);
+    
+    // A flush puts everything in a segment and syncs the WAL past it
     store.flush().unwrap();
-    
-    assert_eq!(store.get("key0500").unwrap(), Some("value500".to_string()));
-    assert_eq!(store.get("key0999").unwrap(), Some("value999".to_string()));
+    assert_eq!(store.flushed_seq(), last);
+    assert!(store.flushed_seq() <= store.durable_seq());
+    
+    // A crash keeps everything up to durable_seq
+    let deleted = store.delete("a").unwrap();
+    store.wait_durable(deleted).unwrap();
+    let durable = store.durable_seq();
+    assert!(durable >= deleted && durable <= store.seq().unwrap());
+    if !in_memory() {
+        crash_copy(&dir, &copy);
+        {
+            let recovered = open_store(std::path::Path::new(&copy)).unwrap();
+            assert_eq!(recovered.get("a").unwrap(), None);
+            assert!(recovered.seq().unwrap() >= durable);
+        }
+        
+        // Reopened, everything read back is durable, and the segments still end at the flush
+        drop(store);
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        assert_eq!(store.seq().unwrap(), deleted);
+        assert_eq!(store.durable_seq(), deleted);
+        assert_eq!(store.flushed_seq(), last);
+        store.flush().unwrap();
+        assert_eq!(store.flushed_seq(), deleted);
+    }
     
     cleanup(&dir);
+    cleanup(&copy);
 }
 
-fn test_prefix_operations() {
-    let dir = test_dir("prefix");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
-    
-    store.set("logs/2024/01/01", "log1", false).unwrap();
-    store.set("logs/2024/01/02", "log2", false).unwrap();
-    store.set("logs/2024/02/01", "log3", false).unwrap();
-    
-    // Use get_pattern for prefix queries - returns Vec<(String, String)>
-    let january_logs = store.get_pattern("logs/2024/01/*").unwrap();
-    assert_eq!(january_logs.len(), 2);
+fn test_missing_wal_seq() {
+    let dir = test_dir("missing_wal_seq");
+    let dir_path = std::path::Path::new(&dir);
+    let put = |store: &Store, round: usize| {
+        let entries = (0..50).map(|i| (format!("items/{:02}", i), format!("round {}", round))).collect();
+        store.set_many(entries, None).unwrap()
+    };
+    
+    // Two flushed rounds, then one the WAL alone holds when it goes
+    let lost = {
+        let store = open_store(dir_path).unwrap();
+        put(&store, 1);
+        store.flush().unwrap();
+        put(&store, 2);
+        store.flush().unwrap();
+        let lost = put(&store, 3);
+        store.wait_durable(lost).unwrap();
+        lost
+    };
+    std::fs::remove_file(dir_path.join("wal.log")).unwrap();
+    
+    // Open says so, and skips well past the seqs the lost writes took
+    let (options, events) = recording_options();
+    let (store, report) = open_store_with(dir_path, options).unwrap();
+    assert!(report.missing_wal);
+    assert!(events.lock().unwrap().iter().any(|event| event.starts_with("WalMissing")));
+    assert!(store.seq().unwrap() > lost);
+    assert_eq!(store.get("items/00").unwrap().as_deref(), Some("round 2"));
+    
+    // New writes win over the flushed ones in gets and scans, before and after compaction
+    assert!(put(&store, 4) > lost);
+    store.set("items/00", "latest", false).unwrap();
+    let check = |store: &Store| {
+        assert_eq!(store.get("items/00").unwrap().as_deref(), Some("latest"));
+        assert_eq!(store.get("items/49").unwrap().as_deref(), Some("round 4"));
+        let range = store.get_range("items/", "items/~").unwrap();
+        assert_eq!(range.len(), 50);
+        assert!(range[1..].iter().all(|(_, value)| value == "round 4"));
+        assert_eq!(store.scan_prefix("items/", usize::MAX).unwrap(), range);
+    };
+    check(&store);
+    store.flush().unwrap();
+    store.compact().unwrap();
+    check(&store);
     
-    // Convert to HashMap for easier assertions
-    let log_map: std::collections::HashMap<_, _> = january_logs.into_iter().collect();
-    assert_eq!(log_map.get("logs/2024/01/01"), Some(&"log1".to_string()));
-    assert_eq!(log_map.get("logs/2024/01/02"), Some(&"log2".to_string()));
-    assert!(!log_map.contains_key("logs/2024/02/01"));
+    // With the WAL back in place, a reopen carries on as usual
+    let seq = store.seq().unwrap();
+    drop(store);
+    let (store, report) = open_store_with(dir_path, StoreOptions::default()).unwrap();
+    assert!(!report.missing_wal);
+    assert_eq!(store.seq().unwrap(), seq);
+    check(&store);
     
     cleanup(&dir);
 }
 
-// ==================== SPECIAL CHARACTERS ====================
+// A WAL frame as versions before group commits wrote one per entry: length, record, CRC
+fn old_wal_frame(out: &mut Vec<u8>, seq: u64, kind: u8, key: &str, value: Option<&str>) {
+    let mut record = seq.to_le_bytes().to_vec();
+    record.push(kind);
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(key.as_bytes());
+    if let Some(value) = value {
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(value.as_bytes());
+    }
+    // Bitwise, so it checks the store's table-driven CRC rather than sharing it
+    let mut crc = 0xffffffffu32;
+    for &byte in &record {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    out.extend_from_slice(&(record.len() as u32).to_le_bytes());
+    out.extend_from_slice(&record);
+    out.extend_from_slice(&(crc ^ 0xffffffff).to_le_bytes());
+}
 
-fn test_unicode_support() {
-    let dir = test_dir("unicode");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
-    
-    store.set("users/李明/name", "李明", false).unwrap();
-    store.set("emoji/🎉", "party", false).unwrap();
-    store.set("mixed/αβγ/数字", "value", false).unwrap();
+fn test_wal_mixed_frame_formats() {
+    let dir = test_dir("wal_mixed_frames");
+    let copy = test_dir("wal_mixed_frames_copy");
+    let wal = std::path::Path::new(&dir).join("wal.log");
+    
+    // A log an older version left behind, one frame per entry
+    let mut old = b"WAL2".to_vec();
+    old_wal_frame(&mut old, 1, 1, "old/a", Some("1"));
+    old_wal_frame(&mut old, 2, 1, "old/b", Some("2"));
+    old_wal_frame(&mut old, 3, 2, "old/a", None);
+    old_wal_frame(&mut old, 4, 1, "gone/x", Some("x"));
+    old_wal_frame(&mut old, 5, 3, "gone/", None);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(&wal, &old).unwrap();
+    
+    // It replays, and takes the new magic before anything is appended to it
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    assert_eq!(store.seq().unwrap(), 5);
+    assert_eq!(store.get("old/a").unwrap(), None);
+    assert_eq!(store.get("old/b").unwrap(), Some("2".to_string()));
+    assert_eq!(store.get("gone/x").unwrap(), None);
+    let upgraded = std::fs::read(&wal).unwrap();
+    assert_eq!(&upgraded[..4], b"WAL3");
+    assert_eq!(&upgraded[4..], &old[4..]);
+    
+    // Writes buffered together go out as one group frame with a single CRC
+    store.pause_wal_flusher(true);
+    for i in 0..10 {
+        store.set(&format!("new/{}", i), "v", false).unwrap();
+    }
+    let last = store.delete("old/b").unwrap();
+    store.pause_wal_flusher(false);
+    store.wait_durable(last).unwrap();
+    let data = std::fs::read(&wal).unwrap();
+    let frame = &data[old.len()..];
+    let frame_len = frame.len();
+    let entries = (0..10).map(|_| 4 + 13 + "new/0".len() + 4 + 1).sum::<usize>() + 4 + 13 + "old/b".len();
+    assert_eq!(frame.len(), 4 + 17 + entries + 4);
+    assert_eq!(frame[12], 6, "expected an RT_GROUP frame");
+    assert_eq!(u32::from_le_bytes(frame[17..21].try_into().unwrap()), 11);
+    
+    // An old-style frame behind the group frames still replays in order
+    let mut data = data;
+    old_wal_frame(&mut data, last + 1, 1, "new/0", Some("after"));
+    drop(store);
+    std::fs::write(&wal, &data).unwrap();
+    {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        assert_eq!(store.seq().unwrap(), last + 1);
+        assert_eq!(store.get("new/0").unwrap(), Some("after".to_string()));
+        assert_eq!(store.get("new/9").unwrap(), Some("v".to_string()));
+        assert_eq!(store.get("old/b").unwrap(), None);
+        assert_eq!(store.get("gone/x").unwrap(), None);
+    }
     
-    assert_eq!(store.get("users/李明/name").unwrap(), Some("李明".to_string()));
-    assert_eq!(store.get("emoji/🎉").unwrap(), Some("party".to_string()));
-    assert_eq!(store.get("mixed/αβγ/数字").unwrap(), Some("value".to_string()));
+    // A torn group frame loses the whole group, and only it
+    data.truncate(old.len() + frame_len - 3);
+    crash_copy(&dir, &copy);
+    std::fs::write(std::path::Path::new(&copy).join("wal.log"), &data).unwrap();
+    {
+        let store = open_store(std::path::Path::new(&copy)).unwrap();
+        assert_eq!(store.seq().unwrap(), 5);
+        assert_eq!(store.get("old/b").unwrap(), Some("2".to_string()));
+        assert!(store.scan_prefix("new/", usize::MAX).unwrap().is_empty());
+    }
     
     cleanup(&dir);
+    cleanup(&copy);
 }
 
-fn test_empty_values() {
-    let dir = test_dir("empty");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+fn test_reopen_skips_flushed_wal_records() {
+    let dir = test_dir("reopen_skips_flushed");
     
-    store.set("empty", "", false).unwrap();
-    assert_eq!(store.get("empty").unwrap(), Some("".to_string()));
+    {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        for group in 0..100 {
+            let entries = (0..500).map(|i| (format!("bulk/{:02}/{:03}", group, i), "value".to_string())).collect();
+            store.set_many(entries, None).unwrap();
+        }
+        // Newer than the subtomb, so the subtomb's seq is below every segment's
+        store.delete_subtree("bulk/07").unwrap();
+        store.set("after", "x", false).unwrap();
+        store.flush().unwrap();
+    }
+    
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    assert_eq!(store.memtable_len(), 0, "Flushed records were replayed into the memtable");
+    assert_eq!(store.get("bulk/07/001").unwrap(), None, "Subtomb lost on replay");
+    assert_eq!(store.get("bulk/08/001").unwrap(), Some("value".to_string()));
+    
+    // The first write lands in the memtable instead of re-flushing history
+    let segments = store.segment_counts();
+    store.set("first", "write", false).unwrap();
+    assert_eq!(store.segment_counts(), segments);
+    assert_eq!(store.memtable_len(), 1);
     
     cleanup(&dir);
 }
 
-fn test_special_paths() {
-    let dir = test_dir("special_paths");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+fn test_tombstone_durability() {
+    let dir = test_dir("tombstone_durability");
+    let dir_path = std::path::Path::new(&dir);
+    let wal_path = dir_path.join("wal.log");
+    
+    let store = open_store(dir_path).unwrap();
+    for i in 0..3 {
+        store.set(&format!("points/{}", i), "value", false).unwrap();
+        store.set(&format!("tree/{}", i), "value", false).unwrap();
+        store.set(&format!("later/{}", i), "value", false).unwrap();
+    }
+    store.flush().unwrap();
+    assert_eq!(store.pending_tombstones(), 0);
     
-    store.set("with spaces/in path", "works", false).unwrap();
-    store.set("with-dashes", "also-works", false).unwrap();
-    store.set("under_scores", "work_too", false).unwrap();
+    // Deletes of keys only segments hold, then a write under a deleted subtree
+    store.delete("points/1").unwrap();
+    store.delete_subtree("tree").unwrap();
+    store.set("tree/new", "after", false).unwrap();
+    assert_eq!(store.pending_tombstones(), 2);
+    store.flush().unwrap();
+    assert_eq!(store.pending_tombstones(), 0);
     
-    assert_eq!(store.get("with spaces/in path").unwrap(), Some("works".to_string()));
-    assert_eq!(store.get("with-dashes").unwrap(), Some("also-works".to_string()));
-    assert_eq!(store.get("under_scores").unwrap(), Some("work_too".to_string()));
+    // A subtree delete alone leaves the memtable empty, and the flush still persists it
+    store.delete_subtree("later").unwrap();
+    assert_eq!((store.memtable_len(), store.pending_tombstones()), (0, 1));
+    store.flush().unwrap();
+    assert_eq!(store.pending_tombstones(), 0);
+    drop(store);
+    
+    if !in_memory() {
+        // With the WAL gone, every delete holds and new writes still take later seqs
+        std::fs::OpenOptions::new().write(true).open(&wal_path).unwrap().set_len(0).unwrap();
+        let store = open_store(dir_path).unwrap();
+        assert_eq!(store.get("points/1").unwrap(), None);
+        assert_eq!(store.get("points/2").unwrap(), Some("value".to_string()));
+        assert_eq!(store.scan_prefix("tree/", usize::MAX).unwrap(), vec![("tree/new".to_string(), "after".to_string())]);
+        assert!(store.scan_prefix("later/", usize::MAX).unwrap().is_empty());
+        assert_eq!(store.pending_tombstones(), 0);
+        store.set("later/0", "again", false).unwrap();
+        assert_eq!(store.get("later/0").unwrap(), Some("again".to_string()));
+        drop(store);
+        
+        let store = open_store(dir_path).unwrap();
+        assert_eq!(store.get("later/0").unwrap(), Some("again".to_string()));
+        assert_eq!(store.get("later/1").unwrap(), None);
+        
+        drop(store);
+    }
     
     cleanup(&dir);
 }
 
-// ==================== PERFORMANCE TESTS ====================
-
-fn test_write_performance() {
-    let dir = test_dir("write_perf");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+fn test_replay_flush_threshold() {
+    let dir = test_dir("replay_flush_threshold");
+    let dir_path = std::path::Path::new(&dir);
+    let threshold = 64 * 1024;
+    let options = StoreOptions { replay_flush_threshold: Some(threshold), ..Default::default() };
+    
+    // Short-lived processes that never fill a memtable, so only open ever flushes
+    let mut slowest_open = Duration::ZERO;
+    let mut largest_wal = 0;
+    for round in 0..500 {
+        let started = Instant::now();
+        let (store, _) = open_store_with(dir_path, options.clone()).unwrap();
+        if round >= 400 {
+            slowest_open = slowest_open.max(started.elapsed());
+        }
+        let stats = store.wal_stats().unwrap();
+        assert_eq!(stats.checkpointed, stats.replayed_bytes > threshold);
+        largest_wal = largest_wal.max(stats.bytes);
+        
+        for i in 0..100 {
+            store.set(&format!("jobs/{}/{:03}", round, i), "done", false).unwrap();
+        }
+        if round > 0 {
+            store.delete_subtree(&format!("jobs/{}", round - 1)).unwrap();
+        }
+    }
     
-    let start = Instant::now();
+    // A checkpoint leaves the live subtree deletes, so the log starts each round near empty
+    // and stays under the threshold plus one round's writes
+    assert!(largest_wal < threshold + 16 * 1024, "WAL grew to {} bytes", largest_wal);
+    assert!(slowest_open < Duration::from_secs(1), "Open took {:?}", slowest_open);
+    
+    let (store, _) = open_store_with(dir_path, options.clone()).unwrap();
+    assert_eq!(store.scan_prefix("jobs/", usize::MAX).unwrap().len(), 100);
+    assert_eq!(store.get("jobs/499/042").unwrap(), Some("done".to_string()));
+    assert_eq!(store.get("jobs/498/042").unwrap(), None);
+    drop(store);
+    
+    // Without the option the WAL keeps every round
+    let store = open_store(dir_path).unwrap();
+    store.set("jobs/extra", "x", false).unwrap();
+    drop(store);
+    let store = open_store(dir_path).unwrap();
+    let stats = store.wal_stats().unwrap();
+    assert!(!stats.checkpointed);
+    assert!(stats.replayed_records > 0 && stats.bytes == stats.replayed_bytes);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_memtable_max_age() {
+    let dir = test_dir("memtable_max_age");
+    let dir_path = std::path::Path::new(&dir);
+    let options = StoreOptions { memtable_max_age: Some(Duration::from_millis(100)), ..Default::default() };
+    let (store, _) = open_store_with(dir_path, options.clone()).unwrap();
+    
+    // An empty memtable has no age, and nothing is flushed for it
+    assert_eq!(store.memtable_stats().unwrap(), MemtableStats::default());
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.segment_counts().0, 0);
+    
+    let mut seq = 0;
+    for i in 0..10 {
+        seq = store.set(&format!("quiet/{}", i), "value", false).unwrap();
+    }
+    let stats = store.memtable_stats().unwrap();
+    assert_eq!(stats.entries, 10);
+    assert!(stats.bytes > 0 && stats.bytes < 256 * 1024);
+    assert!(stats.age.is_some());
+    
+    // Far short of a full memtable, the writes still reach a segment without a flush() call
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.memtable_len() > 0 {
+        assert!(Instant::now() < deadline, "Memtable not flushed: {:?}", store.memtable_stats().unwrap());
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(store.segment_counts().0, 1);
+    assert_eq!(store.flushed_seq(), seq);
+    assert_eq!(store.memtable_stats().unwrap().age, None);
+    drop(store);
+    
+    if !in_memory() {
+        // So reopen has nothing of them to put back in the memtable
+        let store = open_store(dir_path).unwrap();
+        assert_eq!(store.memtable_len(), 0);
+        assert_eq!(store.get("quiet/9").unwrap(), Some("value".to_string()));
+        
+        // The default age is minutes, so a fresh write stays put
+        store.set("quiet/10", "value", false).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(store.memtable_len(), 1);
+        
+        drop(store);
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_stats_history() {
+    let dir = test_dir("stats_history");
+    let dir_path = std::path::Path::new(&dir);
+    let ring = dir_path.join("stats.ring");
+    
+    // Without stats_interval no ring is written, though metrics still count
+    let store = open_store(dir_path).unwrap();
+    store.set("a/b", "1", false).unwrap();
+    store.flush().unwrap();
+    let metrics = store.metrics().unwrap();
+    assert_eq!(metrics.flushes, 1);
+    assert!(metrics.wal_syncs >= 1);
+    assert_eq!(metrics.flushed_seq, metrics.seq);
+    thread::sleep(Duration::from_millis(100));
+    assert!(!ring.exists());
+    assert!(store.stats_history(UNIX_EPOCH).unwrap().is_empty());
+    drop(store);
+    
+    // Eight slots, so a few hundred milliseconds of samples wrap around
+    let options = StoreOptions {
+        stats_interval: Some(Duration::from_millis(10)),
+        stats_ring_bytes: Some(16 + 8 * 128),
+        ..Default::default()
+    };
+    let opened = SystemTime::now();
+    let (store, _) = open_store_with(dir_path, options.clone()).unwrap();
+    for i in 0..100 {
+        store.set(&format!("k/{}", i), "v", false).unwrap();
+    }
+    store.flush().unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(std::fs::metadata(&ring).unwrap().len(), 16 + 8 * 128);
+    let history = store.stats_history(UNIX_EPOCH).unwrap();
+    assert_eq!(history.len(), 8);
+    assert!(history.windows(2).all(|pair| pair[0].at < pair[1].at));
+    assert!(history[0].at > opened + Duration::from_millis(50), "oldest samples weren't overwritten");
+    assert_eq!(history[7].metrics.flushes, 1);
+    assert_eq!(history[7].metrics.seq, store.metrics().unwrap().seq);
+    assert_eq!(store.stats_history(history[4].at).unwrap(), history[4..].to_vec());
+    drop(store);
+    thread::sleep(Duration::from_millis(50));
+    
+    // A sample caught halfway through overwriting another fails its CRC and is skipped;
+    // the rest still read, straight from the file
+    let mut data = std::fs::read(&ring).unwrap();
+    let history = Store::read_stats_history(dir_path, UNIX_EPOCH).unwrap();
+    let (torn, other) = (16, 16 + 128);
+    let half = data[other..other + 64].to_vec();
+    data[torn..torn + 64].copy_from_slice(&half);
+    std::fs::write(&ring, &data).unwrap();
+    let after = Store::read_stats_history(dir_path, UNIX_EPOCH).unwrap();
+    assert_eq!(after.len(), 7);
+    assert!(after.iter().all(|sample| history.contains(sample)));
+    
+    // Reopened, the ring carries on after its newest sample and counters start again
+    let (store, _) = open_store_with(dir_path, options).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let reopened = store.stats_history(UNIX_EPOCH).unwrap();
+    assert_eq!(reopened.len(), 8);
+    assert!(reopened.last().unwrap().at > history.last().unwrap().at);
+    assert_eq!(reopened.last().unwrap().metrics.flushes, 0);
+    drop(store);
+    
+    // A ring of another size is started afresh
+    let options = StoreOptions { stats_interval: Some(Duration::from_millis(10)), stats_ring_bytes: Some(16 + 4 * 128), ..Default::default() };
+    let (store, _) = open_store_with(dir_path, options).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(std::fs::metadata(&ring).unwrap().len(), 16 + 4 * 128);
+    assert!(store.stats_history(UNIX_EPOCH).unwrap().iter().all(|sample| sample.at > reopened.last().unwrap().at));
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_l0_admission() {
+    let dir = test_dir("l0_admission");
+    let dir_path = std::path::Path::new(&dir);
+    
+    // A stop below where compaction starts could never be lifted
+    let options = StoreOptions { l0_stop_threshold: Some(3), ..Default::default() };
+    assert!(matches!(open_store_with(dir_path, options), Err(WalDbError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput));
+    
+    // Past the slowdown threshold each flush waits 10ms more per segment
+    let (mut options, events) = recording_options();
+    options.l0_slowdown_threshold = Some(1);
+    let (store, _) = open_store_with(dir_path, options).unwrap();
+    events.lock().unwrap().clear();
+    for i in 0..3 {
+        store.set(&format!("slow/{}", i), "x", false).unwrap();
+        store.flush().unwrap();
+    }
+    let slowdowns: Vec<String> = events.lock().unwrap().iter().filter(|e| e.starts_with("L0")).cloned().collect();
+    assert_eq!(slowdowns, vec!["L0Slowdown { segments: 1, delay: 10ms }", "L0Slowdown { segments: 2, delay: 20ms }"]);
+    let stats = store.l0_stats().unwrap();
+    assert_eq!((stats.segments, stats.pressure, stats.slowdowns, stats.stalls), (3, L0Pressure::Slowdown, 2, 0));
+    drop(store);
+    cleanup(&dir);
+    
+    // At the stop threshold a flush waits for compaction, which a full L0 wakes at once. The
+    // I/O budget keeps the merge going long enough to catch the stall.
+    let (mut options, events) = recording_options();
+    options.l0_stop_threshold = Some(4);
+    options.compaction.max_bytes_per_sec = Some(8 * 1024);
+    let (store, _) = open_store_with(dir_path, options).unwrap();
+    let value = "v".repeat(1000);
+    for i in 0..4 {
+        store.set(&format!("stop/{}", i), &value, false).unwrap();
+        store.flush().unwrap();
+    }
+    assert_eq!(store.l0_stats().unwrap().pressure, L0Pressure::Stopped);
+    store.set("stop/4", &value, false).unwrap();
+    let started = Instant::now();
+    store.flush().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(3), "Compaction waited for its interval: {:?}", started.elapsed());
+    assert_eq!(store.segment_counts(), (1, 1, 0));
+    let stats = store.l0_stats().unwrap();
+    assert_eq!((stats.segments, stats.pressure, stats.stalls), (1, L0Pressure::Normal, 1));
+    assert!(stats.stall_time > Duration::ZERO);
+    let events = events.lock().unwrap().clone();
+    let stalled = events.iter().position(|e| e == "L0Stalled { segments: 4 }").expect("No L0Stalled event");
+    assert!(events[stalled..].iter().any(|e| e == "CompactionFinished L1"), "{:?}", events);
+    assert_eq!(store.get("stop/4").unwrap(), Some(value));
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_wal_backpressure() {
+    let dir = test_dir("wal_backpressure");
+    let dir_path = std::path::Path::new(&dir);
+    
+    // A slow disk, noting which thread each WAL sync ran on
+    let syncs = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let hook_syncs = syncs.clone();
+    let options = StoreOptions {
+        wal_flush_bytes: Some(1024),
+        max_unflushed_bytes: Some(8192),
+        io_hook: Some(Arc::new(move |site: WriteSite, _: &std::path::Path| {
+            if site == WriteSite::Wal {
+                hook_syncs.lock().unwrap().push(thread::current().id());
+                thread::sleep(Duration::from_millis(5));
+            }
+            Ok(())
+        })),
+        ..Default::default()
+    };
+    let (store, _) = open_store_with(dir_path, options).unwrap();
+    syncs.lock().unwrap().clear();
+    
+    // Bursts far past the bound wait for the flusher instead of syncing themselves. The same
+    // keys each round keep the memtable from flushing.
+    let burst = |round: usize| (0..500).map(|i| (format!("burst/{:03}", i), format!("{}-{}", round, "v".repeat(40)))).collect();
+    for round in 0..10 {
+        store.set_many(burst(round), None).unwrap();
+        assert!(store.wal_stats().unwrap().buffered_bytes < 8192 + 128);
+    }
+    let stats = store.wal_stats().unwrap();
+    assert!(stats.throttled_writes > 0 && stats.throttle_wait > Duration::ZERO, "{:?}", stats);
+    assert!(!syncs.lock().unwrap().is_empty());
+    assert!(!syncs.lock().unwrap().contains(&thread::current().id()), "A writer synced the WAL itself");
+    
+    // With the flusher paused, the write that finds the buffer full syncs it
+    store.pause_wal_flusher(true);
+    store.set_many(burst(10), None).unwrap();
+    assert!(store.wal_stats().unwrap().buffered_bytes < 8192 + 128);
+    assert!(syncs.lock().unwrap().contains(&thread::current().id()));
+    store.pause_wal_flusher(false);
+    drop(store);
+    
+    if !in_memory() {
+        let store = open_store(dir_path).unwrap();
+        assert_eq!(store.get("burst/042").unwrap(), Some(format!("10-{}", "v".repeat(40))));
+        
+        drop(store);
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_deferred_manifest_syncs() {
+    let dir = test_dir("deferred_manifest");
+    let copy = test_dir("deferred_manifest_copy");
+    let options = StoreOptions { defer_manifest_syncs: true, ..Default::default() };
+    let (store, _) = open_store_with(std::path::Path::new(&dir), options).unwrap();
+    
+    // Past the memtable threshold, so the last set flushes and leaves its entry to the flusher
+    let value = "x".repeat(1000);
+    let mut i = 0;
+    while store.segment_counts().0 == 0 {
+        let seq = store.set(&format!("bulk/{:05}", i), &value, false).unwrap();
+        store.wait_durable(seq).unwrap();
+        i += 1;
+    }
+    // Segment entries, leaving out the lines that advance the file name counter, give key
+    // bounds or record the seq or the time
+    let manifest = std::path::Path::new(&dir).join("manifest.log");
+    let entry_lines = || std::fs::read_to_string(&manifest).unwrap().lines()
+        .filter(|line| !["file|", "keys|", "seq|", "clock|"].iter().any(|tag| line.starts_with(tag)))
+        .count();
+    assert_eq!(entry_lines(), 1);
+    
+    // A crash that loses the unsynced entry only means replaying those writes from the WAL
+    crash_copy(&dir, &copy);
+    let copied_manifest = std::path::Path::new(&copy).join("manifest.log");
+    std::fs::write(&copied_manifest, "").unwrap();
+    let recovered = open_store(std::path::Path::new(&copy)).unwrap();
+    for n in 0..i {
+        assert_eq!(recovered.get(&format!("bulk/{:05}", n)).unwrap().as_deref(), Some(value.as_str()), "bulk/{:05}", n);
+    }
+    drop(recovered);
+    
+    // Entries appended through the kept-open handle all land, and reopening finds the segments
+    store.set("tail", "t", false).unwrap();
+    store.flush().unwrap();
+    let segments = store.segment_counts();
+    drop(store);
+    assert_eq!(entry_lines(), segments.0 + segments.1 + segments.2);
+    
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    assert_eq!(store.segment_counts(), segments);
+    assert_eq!(store.memtable_len(), 0);
+    assert_eq!(store.get("bulk/00000").unwrap().as_deref(), Some(value.as_str()));
+    assert_eq!(store.get("tail").unwrap().as_deref(), Some("t"));
+    
+    drop(store);
+    cleanup(&dir);
+    cleanup(&copy);
+}
+
+fn test_flush_to_disk() {
+    let dir = test_dir("flush");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    for i in 0..100 {
+        store.set(&format!("key{}", i), &format!("value{}", i), false).unwrap();
+    }
+    
+    store.flush().unwrap();
+    
+    // Verify segment file created
+    let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    let seg_files = entries.iter()
+        .filter(|e| e.as_ref().unwrap().path().extension() == Some("seg".as_ref()))
+        .count();
+    assert!(seg_files > 0);
+    
+    cleanup(&dir);
+}
+
+// ==================== BATCH OPERATIONS ====================
+
+fn test_bulk_insert() {
+    let dir = test_dir("bulk");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    for i in 0..1000 {
+        store.set(&format!("key{:04}", i), &format!("value{}", i), false).unwrap();
+    }
+    store.flush().unwrap();
+    
+    assert_eq!(store.get("key0500").unwrap(), Some("value500".to_string()));
+    assert_eq!(store.get("key0999").unwrap(), Some("value999".to_string()));
+    
+    cleanup(&dir);
+}
+
+fn test_prefix_operations() {
+    let dir = test_dir("prefix");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("logs/2024/01/01", "log1", false).unwrap();
+    store.set("logs/2024/01/02", "log2", false).unwrap();
+    store.set("logs/2024/02/01", "log3", false).unwrap();
+    
+    // Use get_pattern for prefix queries - returns Vec<(String, String)>
+    let january_logs = store.get_pattern("logs/2024/01/*").unwrap();
+    assert_eq!(january_logs.len(), 2);
+    
+    // Convert to HashMap for easier assertions
+    let log_map: std::collections::HashMap<_, _> = january_logs.into_iter().collect();
+    assert_eq!(log_map.get("logs/2024/01/01"), Some(&"log1".to_string()));
+    assert_eq!(log_map.get("logs/2024/01/02"), Some(&"log2".to_string()));
+    assert!(!log_map.contains_key("logs/2024/02/01"));
+    
+    cleanup(&dir);
+}
+
+fn test_scan_prefix_merged() {
+    let dir = test_dir("scan_prefix_merged");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    let scan = |prefix: &str| -> Vec<String> {
+        store.scan_prefix(prefix, usize::MAX).unwrap().into_iter().map(|(k, v)| format!("{}={}", k, v)).collect()
+    };
+    
+    // Nothing stored yet is an empty scan, not an error
+    assert!(scan("docs/").is_empty());
+    
+    // Keys continuing past '~' used to fall outside the scanned range
+    for key in ["docs/a", "docs/m", "docs/~tilde", "docs/\u{7f}del", "docs/é", "docs/🎉", "docsx/outside"] {
+        store.set(key, "1", false).unwrap();
+    }
+    let before_flush = scan("docs/");
+    assert_eq!(before_flush, vec!["docs/a=1", "docs/m=1", "docs/~tilde=1", "docs/\u{7f}del=1", "docs/é=1", "docs/🎉=1"]);
+    
+    store.flush().unwrap();
+    assert_eq!(scan("docs/"), before_flush);
+    
+    // Memtable updates and deletes shadow the flushed records
+    store.set("docs/m", "2", false).unwrap();
+    store.delete("docs/é").unwrap();
+    store.set("docs/b", "1", false).unwrap();
+    assert_eq!(scan("docs/"), vec!["docs/a=1", "docs/b=1", "docs/m=2", "docs/~tilde=1", "docs/\u{7f}del=1", "docs/🎉=1"]);
+    
+    // And once flushed on top of the older segment, the newest record still wins
+    store.flush().unwrap();
+    store.delete("docs/a").unwrap();
+    store.set("docs/é", "3", false).unwrap();
+    store.delete_subtree("docs/🎉").unwrap();
+    store.set("docs/🎉", "scalar", false).unwrap();
+    store.flush().unwrap();
+    assert_eq!(scan("docs/"), vec!["docs/b=1", "docs/m=2", "docs/~tilde=1", "docs/\u{7f}del=1", "docs/é=3", "docs/🎉=scalar"]);
+    assert_eq!(store.scan_prefix("docs/", 2).unwrap().len(), 2);
+    assert_eq!(scan("docsx"), vec!["docsx/outside=1"]);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_scan_allocations() {
+    let dir = test_dir("scan_allocations");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Three L0 segments, each holding every key, so a scan reads three records per result
+    let keys = 20_000;
+    for round in 0..3 {
+        for i in 0..keys {
+            store.set(&format!("scan/{:05}", i), &format!("value {} of key {}", round, i), false).unwrap();
+        }
+        store.flush().unwrap();
+    }
+    
+    // Older copies of a key cost nothing, so allocations follow the results
+    store.scan_prefix("scan/", usize::MAX).unwrap();
+    let (results, allocations) = allocations_in(|| store.scan_prefix("scan/", usize::MAX).unwrap());
+    assert_eq!(results.len(), keys);
+    assert_eq!(results[7].1, "value 2 of key 7");
+    assert!(allocations < keys * 3, "{} allocations for {} results", allocations, keys);
+    
+    let (results, allocations) = allocations_in(|| store.get_range("scan/01000", "scan/01100").unwrap());
+    assert_eq!(results.len(), 100);
+    assert!(allocations < 1000, "{} allocations for {} results", allocations, results.len());
+    
+    // A pattern reads every record, but only allocates for the ones that match
+    let (results, allocations) = allocations_in(|| store.get_pattern("scan/0000?").unwrap());
+    assert_eq!(results.len(), 10);
+    assert_eq!(results[3], ("scan/00003".to_string(), "value 2 of key 3".to_string()));
+    assert!(allocations < keys / 4, "{} allocations reading {} records", allocations, keys * 3);
+    
+    cleanup(&dir);
+}
+
+fn test_range_iter_snapshot() {
+    let dir = test_dir("range_iter_snapshot");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Spread the range over two segments and the memtable, with overwrites and deletes
+    for i in 0..300 {
+        store.set(&format!("items/{:03}", i), &format!("v{}", i), false).unwrap();
+    }
+    store.set("items/sub/a", "1", false).unwrap();
+    store.flush().unwrap();
+    for i in (0..300).step_by(3) {
+        store.set(&format!("items/{:03}", i), &format!("w{}", i), false).unwrap();
+    }
+    store.delete("items/007").unwrap();
+    store.delete_subtree("items/sub").unwrap();
+    store.flush().unwrap();
+    store.set("items/150", "memtable", false).unwrap();
+    store.set("other", "x", false).unwrap();
+    
+    let expected = store.scan_prefix("items/", usize::MAX).unwrap();
+    assert_eq!(expected.len(), 299);
+    let mut iter = store.scan_prefix_iter("items/").unwrap();
+    let mut seen: Vec<(String, String)> = iter.by_ref().take(100).collect::<Result<_>>().unwrap();
+    
+    // Writes made after the iterator was opened don't show, even once flushed
+    store.set("items/000", "changed", false).unwrap();
+    store.delete("items/250").unwrap();
+    store.set("items/299a", "new", false).unwrap();
+    store.delete_subtree("items").unwrap();
+    store.flush().unwrap();
+    seen.extend(iter.by_ref().map(|entry| entry.unwrap()));
+    assert_eq!(seen, expected);
+    assert!(iter.next().is_none());
+    
+    // A new iterator sees them, and stops at the end of its range
+    assert_eq!(store.scan_prefix_iter("items/").unwrap().count(), 0);
+    store.set("items/a", "1", false).unwrap();
+    store.set("items/b", "2", false).unwrap();
+    store.set("items/c", "3", false).unwrap();
+    let range: Vec<(String, String)> = store.range_iter("items/b", "items/c").unwrap().collect::<Result<_>>().unwrap();
+    assert_eq!(range, vec![("items/b".to_string(), "2".to_string())]);
+    assert_eq!(store.range_iter("items/c", "items/b").unwrap().count(), 0);
+    
+    // Through a namespace, keys come back relative to it
+    let ns = store.namespace("tenant");
+    ns.set("docs/1", "a", false).unwrap();
+    let local: Vec<(String, String)> = ns.scan_prefix_iter("docs/").unwrap().collect::<Result<_>>().unwrap();
+    assert_eq!(local, vec![("docs/1".to_string(), "a".to_string())]);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_range_iter_pins_segments() {
+    let dir = test_dir("range_iter_pins");
+    let dir_path = std::path::Path::new(&dir);
+    let store = open_store(dir_path).unwrap();
+    let segment_files = || -> Vec<std::path::PathBuf> {
+        std::fs::read_dir(dir_path).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "seg"))
+            .collect()
+    };
+    
+    // Below the L0 threshold, so nothing merges these before the iterators open
+    for i in 0..3 {
+        for j in 0..100 {
+            store.set(&format!("data/{}/{:03}", i, j), "x", false).unwrap();
+        }
+        store.flush().unwrap();
+    }
+    let pinned = segment_files();
+    assert_eq!(pinned.len(), 3);
+    
+    let mut iter = store.range_iter("data/", "data0").unwrap();
+    let mut abandoned = store.range_iter("data/", "data0").unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0, "data/0/000");
+    assert_eq!(abandoned.next().unwrap().unwrap().0, "data/0/000");
+    
+    // Compacting them away leaves the files for the iterators still reading them
+    store.set("data/3/000", "x", false).unwrap();
+    store.flush().unwrap();
+    store.compact().unwrap();
+    assert_eq!(store.segment_counts().0, 0);
+    assert!(pinned.iter().all(|path| path.exists()));
+    assert_eq!(iter.by_ref().count(), 299);
+    
+    // Dropped mid-iteration or finished, neither keeps them once the next pass runs
+    drop(abandoned);
+    drop(iter);
+    store.compact().unwrap();
+    assert!(pinned.iter().all(|path| !path.exists()));
+    assert_eq!(store.scan_prefix("data/", usize::MAX).unwrap().len(), 301);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_segment_registry() {
+    let dir = test_dir("segment_registry");
+    let copy = test_dir("segment_registry_copy");
+    let dir_path = std::path::Path::new(&dir);
+    let store = open_store(dir_path).unwrap();
+    let segment_files = |dir: &std::path::Path| -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".seg"))
+            .collect();
+        names.sort();
+        names
+    };
+    
+    for i in 0..3 {
+        for j in 0..50 {
+            store.set(&format!("data/{}/{:02}", i, j), &format!("v{}", i), false).unwrap();
+        }
+        store.flush().unwrap();
+    }
+    let pinned = segment_files(dir_path);
+    assert_eq!(pinned.len(), 3);
+    
+    // A snapshot and a cursor both keep the compacted-away files, and the last to go deletes them
+    let snapshot = store.as_of(store.seq().unwrap()).unwrap();
+    let mut iter = store.range_iter("data/", "data0").unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0, "data/0/00");
+    store.set("data/0/00", "changed", false).unwrap();
+    store.flush().unwrap();
+    store.compact().unwrap();
+    assert_eq!(store.segment_counts(), (0, 1, 0));
+    assert!(pinned.iter().all(|name| dir_path.join(name).exists()));
+    assert_eq!(snapshot.get("data/0/00").unwrap().as_deref(), Some("v0"));
+    assert_eq!(snapshot.get("data/2/49").unwrap().as_deref(), Some("v2"));
+    drop(snapshot);
+    assert!(pinned.iter().all(|name| dir_path.join(name).exists()));
+    assert_eq!(iter.by_ref().count(), 149);
+    drop(iter);
+    assert!(pinned.iter().all(|name| !dir_path.join(name).exists()));
+    
+    // A dropped segment a reader holds at a crash goes at the next open
+    let l1 = segment_files(dir_path).remove(0);
+    store.set("data/3/00", "v3", false).unwrap();
+    store.flush().unwrap();
+    let extra = segment_files(dir_path).into_iter().find(|name| *name != l1).unwrap();
+    let iter = store.range_iter("data/3/", "data/30").unwrap();
+    assert!(store.drop_segment(&extra).unwrap());
+    assert!(dir_path.join(&extra).exists());
+    crash_copy(&dir, &copy);
+    drop(iter);
+    assert!(!dir_path.join(&extra).exists());
+    {
+        let (reopened, report) = open_store_with(std::path::Path::new(&copy), StoreOptions::default()).unwrap();
+        assert_eq!(report.dropped_segments, vec![extra.clone()]);
+        assert!(report.ignored_files.is_empty());
+        assert_eq!(segment_files(std::path::Path::new(&copy)), vec![l1.clone()]);
+        assert_eq!(reopened.get("data/0/00").unwrap().as_deref(), Some("changed"));
+    }
+    let (_, report) = open_store_with(std::path::Path::new(&copy), StoreOptions::default()).unwrap();
+    assert!(report.dropped_segments.is_empty());
+    
+    drop(store);
+    cleanup(&dir);
+    cleanup(&copy);
+}
+
+fn test_memtable_key_order() {
+    // Segments that are prefixes of each other, bytes on either side of '/', unicode, empty
+    // segments and depths from 1 to 22
+    let deep: String = (0..20).map(|i| format!("/s{}", i)).collect();
+    let mut keys: Vec<String> = [
+        "k/a!", "k/a/b", "k/a-1/c", "k/a.b", "k/a0", "k/a/c!/d", "k/a/c/d/e", "k/ab", "k/a b/c",
+        "k/é/1", "k/e\u{301}/1", "k/日本/語", "k/日/x", "k/\u{1F600}", "k//z", "k/n/9", "k/n/10", "k/n/010", "k/n/1a",
+    ].iter().map(|k| k.to_string()).collect();
+    keys.push(format!("k/deep{}", deep));
+    keys.push(format!("k/deep{}/t", &deep[..deep.len() - 4]));
+    keys.push(format!("k/deep{}x", deep));
+    
+    for collation in [Collation::Binary, Collation::NumericAware] {
+        let dir = test_dir(&format!("memtable_key_order_{:?}", collation));
+        let options = StoreOptions { collation, ..Default::default() };
+        let (store, _) = open_store_with(std::path::Path::new(&dir), options).unwrap();
+        for key in keys.iter().rev() {
+            store.set(key, key, false).unwrap();
+        }
+        let mut expected = keys.clone();
+        expected.sort_by(|a, b| collation.compare(a.as_bytes(), b.as_bytes()));
+        let scanned = |store: &Store| -> (Vec<String>, Vec<String>) {
+            let range = store.get_range("k/", "k0").unwrap().into_iter().map(|(k, _)| k).collect();
+            let streamed = store.range_iter("k/", "k0").unwrap().map(|entry| entry.unwrap().0).collect();
+            (range, streamed)
+        };
+        
+        // The memtable orders them as a segment does
+        assert_eq!(scanned(&store), (expected.clone(), expected.clone()), "{:?}", collation);
+        assert_eq!(store.get_range("k/a", "k/a0").unwrap().len(), expected.iter().filter(|k| collation.compare(k.as_bytes(), b"k/a").is_ge() && collation.compare(k.as_bytes(), b"k/a0").is_lt()).count());
+        for key in &keys {
+            assert_eq!(store.get(key).unwrap().as_ref(), Some(key));
+        }
+        // Known segments in an unknown combination, and an unknown segment
+        assert_eq!(store.get("k/n/a!").unwrap(), None);
+        assert_eq!(store.get("k/nope").unwrap(), None);
+        assert_eq!(store.scan_prefix("k/a", usize::MAX).unwrap().len(), keys.iter().filter(|k| k.starts_with("k/a")).count());
+        
+        store.flush().unwrap();
+        assert_eq!(scanned(&store), (expected.clone(), expected.clone()), "{:?}", collation);
+        store.set("k/a/b", "again", false).unwrap();
+        assert_eq!(scanned(&store), (expected.clone(), expected), "{:?}", collation);
+        assert_eq!(store.get("k/a/b").unwrap(), Some("again".to_string()));
+    }
+}
+
+fn test_numeric_collation() {
+    let dir = test_dir("numeric_collation");
+    let dir_path = std::path::Path::new(&dir);
+    let options = StoreOptions { collation: Collation::NumericAware, ..Default::default() };
+    let (store, _) = open_store_with(dir_path, options.clone()).unwrap();
+    let keys = |entries: Vec<(String, String)>| -> Vec<String> { entries.into_iter().map(|(k, _)| k).collect() };
+    
+    for id in ["1", "2", "10", "9"] {
+        store.set(&format!("users/{}/name", id), id, false).unwrap();
+    }
+    store.set("users/1a", "x", false).unwrap();
+    let natural = vec!["users/1/name", "users/1a", "users/2/name", "users/9/name", "users/10/name"];
+    assert_eq!(keys(store.scan_prefix("users/", usize::MAX).unwrap()), natural);
+    
+    // The same order out of a segment, and merged with memtable records on top
+    store.flush().unwrap();
+    assert_eq!(keys(store.scan_prefix("users/", usize::MAX).unwrap()), natural);
+    store.set("users/3/name", "3", false).unwrap();
+    assert_eq!(keys(store.get_range("users/2", "users/10").unwrap()), vec!["users/2/name", "users/3/name", "users/9/name"]);
+    assert!(store.get_range("users/10", "users/9").unwrap().is_empty());
+    
+    // A prefix ending mid-segment still finds users/10, which sorts far from users/1
+    assert_eq!(keys(store.scan_prefix("users/1", usize::MAX).unwrap()), vec!["users/1/name", "users/1a", "users/10/name"]);
+    assert_eq!(store.get("users/10/name").unwrap(), Some("10".to_string()));
+    store.compact().unwrap();
+    assert_eq!(keys(store.get_range("users/2", "users/10").unwrap()), vec!["users/2/name", "users/3/name", "users/9/name"]);
+    drop(store);
+    
+    // The segments record their order, so opening with the default collation is refused
+    if !in_memory() {
+        let err = open_store(dir_path).unwrap_err();
+        assert!(matches!(err, WalDbError::CollationMismatch { found: Collation::NumericAware, requested: Collation::Binary, .. }), "{}", err);
+        
+        let (store, _) = open_store_with(dir_path, options).unwrap();
+        assert_eq!(store.get("users/9/name").unwrap(), Some("9".to_string()));
+        assert_eq!(keys(store.get_range("users/2", "users/10").unwrap()), vec!["users/2/name", "users/3/name", "users/9/name"]);
+        drop(store);
+    }
+    
+    cleanup(&dir);
+    
+    // Binary stays the default and keeps byte order
+    let store = open_store(dir_path).unwrap();
+    for id in ["1", "2", "10", "9"] {
+        store.set(&format!("users/{}", id), id, false).unwrap();
+    }
+    store.flush().unwrap();
+    assert_eq!(keys(store.scan_prefix("users/", usize::MAX).unwrap()), vec!["users/1", "users/10", "users/2", "users/9"]);
+    drop(store);
+    cleanup(&dir);
+}
+
+// ==================== SPECIAL CHARACTERS ====================
+
+fn test_unicode_support() {
+    let dir = test_dir("unicode");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("users/李明/name", "李明", false).unwrap();
+    store.set("emoji/🎉", "party", false).unwrap();
+    store.set("mixed/αβγ/数字", "value", false).unwrap();
+    
+    assert_eq!(store.get("users/李明/name").unwrap(), Some("李明".to_string()));
+    assert_eq!(store.get("emoji/🎉").unwrap(), Some("party".to_string()));
+    assert_eq!(store.get("mixed/αβγ/数字").unwrap(), Some("value".to_string()));
+    
+    cleanup(&dir);
+}
+
+fn test_path_segments() {
+    let dir = test_dir("path_segments");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Ids that would add levels, walk the tree or collide when joined by hand
+    let ids = ["a/b", "a", "b", ".", "..", "", "/", "李/明", "%2F", "100%", "\0x", "\u{1}sys"];
+    for (i, id) in ids.iter().enumerate() {
+        store.set_path(&["ids", id, "name"], &i.to_string(), SetOptions::default()).unwrap();
+    }
+    for (i, id) in ids.iter().enumerate() {
+        assert_eq!(store.get_path(&["ids", id, "name"]).unwrap(), Some(i.to_string()), "{:?}", id);
+    }
+    assert_eq!(path_key(&["ids", "a/b", "name"]).unwrap(), "ids/a%2Fb/name");
+    assert_eq!(store.get("ids/a/b/name").unwrap(), None);
+    
+    // Scans give every id back exactly, one level each
+    let scanned = store.scan_path_prefix_segments(&["ids"], usize::MAX).unwrap();
+    assert_eq!(scanned.len(), ids.len());
+    for (segments, value) in &scanned {
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[1], ids[value.parse::<usize>().unwrap()]);
+    }
+    assert_eq!(store.scan_path_prefix(&["ids", "a"], usize::MAX).unwrap(), vec![("ids/a/name".to_string(), "1".to_string())]);
+    
+    // Reserved leading characters are just characters in a segment
+    store.set_path(&["\u{1}sys", "x"], "mine", SetOptions::default()).unwrap();
+    assert_eq!(store.get_path(&["\u{1}sys", "x"]).unwrap().as_deref(), Some("mine"));
+    assert_eq!(path_segments(&path_key(&["\0", "%", "%25"]).unwrap()), vec!["\0", "%", "%25"]);
+    assert_eq!(path_segments("plain/50%/x"), vec!["plain", "50%", "x"]);
+    
+    store.delete_path(&["ids", "a/b", "name"]).unwrap();
+    assert_eq!(store.get_path(&["ids", "a/b", "name"]).unwrap(), None);
+    assert_eq!(store.get_path(&["ids", "a", "name"]).unwrap().as_deref(), Some("1"));
+    assert_eq!(store.get_path(&["ids", "b", "name"]).unwrap().as_deref(), Some("2"));
+    
+    assert!(matches!(store.set_path(&[], "x", SetOptions::default()), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.get_path(&[""]), Err(WalDbError::InvalidKey { .. })));
+    
+    cleanup(&dir);
+}
+
+fn test_empty_values() {
+    let dir = test_dir("empty");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("empty", "", false).unwrap();
+    assert_eq!(store.get("empty").unwrap(), Some("".to_string()));
+    
+    cleanup(&dir);
+}
+
+fn test_special_paths() {
+    let dir = test_dir("special_paths");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("with spaces/in path", "works", false).unwrap();
+    store.set("with-dashes", "also-works", false).unwrap();
+    store.set("under_scores", "work_too", false).unwrap();
+    
+    assert_eq!(store.get("with spaces/in path").unwrap(), Some("works".to_string()));
+    assert_eq!(store.get("with-dashes").unwrap(), Some("also-works".to_string()));
+    assert_eq!(store.get("under_scores").unwrap(), Some("work_too".to_string()));
+    
+    cleanup(&dir);
+}
+
+// ==================== PERFORMANCE TESTS ====================
+
+fn test_write_performance() {
+    let dir = test_dir("write_perf");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    let start = Instant::now();
     for i in 0..1000 {
         store.set(&format!("perf/{}", i), "data", false).unwrap();
     }
@@ -429,7 +2170,7 @@ fn test_write_performance() {
 
 fn test_read_performance() {
     let dir = test_dir("read_perf");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     // Setup: insert keys
     for i in 0..1000 {
@@ -455,17 +2196,19 @@ fn test_read_performance() {
 fn test_cache_effectiveness() {
     let dir = test_dir("cache");
     
-    // Create many keys to ensure cache misses on first access
+    // Create many keys to ensure cache misses on first access. Values of about a quarter
+    // block put each key read below in its own block, so the cold pass really reads the file.
     {
-        let store = Store::open(std::path::Path::new(&dir)).unwrap();
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        let value = "v".repeat(1000);
         for i in 0..1000 {
-            store.set(&format!("key{}", i), "value", false).unwrap();
+            store.set(&format!("key{}", i), &value, false).unwrap();
         }
         store.flush().unwrap();
     }
     
     // Reopen store to start with empty cache
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
     // First reads - cache misses (cold)
     let start1 = Instant::now();
@@ -496,11 +2239,81 @@ fn test_cache_effectiveness() {
     cleanup(&dir);
 }
 
+fn test_warmup() {
+    let dir = test_dir("warmup");
+    let dir_path = std::path::Path::new(&dir);
+    let store = open_store(dir_path).unwrap();
+    let value = "v".repeat(200);
+    let entries = (0..4000).flat_map(|i| [(format!("hot/{:05}", i), value.clone()), (format!("cold/{:05}", i), value.clone())]).collect();
+    store.set_many(entries, None).unwrap();
+    store.flush().unwrap();
+    
+    // A cold cache, then only the blocks under hot/ read in
+    let store = reopen(store, dir_path, StoreOptions::default());
+    assert_eq!(store.cache_stats().resident_bytes, 0);
+    let report = store.warmup(WarmupOptions { prefixes: vec!["hot/".to_string()], max_bytes: u64::MAX, ..Default::default() }).unwrap();
+    assert!(report.complete);
+    assert!(report.bytes_loaded > 4000 * 200 && report.bytes_loaded < 2 * 4000 * 200, "{:?}", report);
+    assert_eq!(report.bytes_pinned, 0);
+    let stats = store.cache_stats();
+    assert_eq!((stats.resident_bytes, stats.misses), (report.bytes_loaded, 0));
+    for i in (0..4000).step_by(7) {
+        assert!(store.get(&format!("hot/{:05}", i)).unwrap().is_some());
+    }
+    assert_eq!(store.cache_stats().misses, 0);
+    store.get("cold/02000").unwrap();
+    assert_eq!(store.cache_stats().misses, 1);
+    
+    // Cached blocks aren't read again, and a small budget stops partway
+    let hot = WarmupOptions { prefixes: vec!["hot/".to_string()], max_bytes: u64::MAX, ..Default::default() };
+    assert_eq!(store.warmup(hot).unwrap().bytes_loaded, 0);
+    let resident = store.cache_stats().resident_bytes;
+    let report = store.warmup(WarmupOptions { levels: vec![0], prefixes: Vec::new(), max_bytes: 100_000 }).unwrap();
+    assert!(!report.complete);
+    assert!(report.bytes_loaded <= 100_000 && report.bytes_loaded > 90_000, "{:?}", report);
+    assert_eq!(store.cache_stats().resident_bytes, resident + report.bytes_loaded);
+    assert_eq!(store.warmup(WarmupOptions { levels: vec![2], max_bytes: u64::MAX, ..Default::default() }).unwrap(),
+        WarmupReport { complete: true, ..Default::default() });
+    drop(store);
+    cleanup(&dir);
+    
+    // With pin_indexes, an L2 segment that fits the pin budget is pinned whole
+    let dir = test_dir("warmup_pin");
+    let dir_path = std::path::Path::new(&dir);
+    let pinning = |pin_budget: Option<usize>| StoreOptions { pin_indexes: true, pin_budget, ..Default::default() };
+    let (store, _) = open_store_with(dir_path, pinning(None)).unwrap();
+    for i in 0..40 {
+        store.set(&format!("small/{:02}", i), "v", false).unwrap();
+        store.flush().unwrap();
+        store.compact().unwrap();
+    }
+    assert_eq!(store.segment_counts().2, 1);
+    let store = reopen(store, dir_path, pinning(None));
+    let report = store.warmup(WarmupOptions { max_bytes: u64::MAX, ..Default::default() }).unwrap();
+    assert!(report.bytes_pinned > 0 && report.bytes_pinned == report.bytes_loaded, "{:?}", report);
+    assert_eq!(store.cache_stats().pinned_bytes, report.bytes_pinned);
+    assert_eq!(store.get("small/17").unwrap().as_deref(), Some("v"));
+    assert_eq!(store.cache_stats().misses, 0);
+    drop(store);
+    
+    if !in_memory() {
+        // Too big for the budget, it is cached like any other
+        let (store, _) = open_store_with(dir_path, pinning(Some(1))).unwrap();
+        let report = store.warmup(WarmupOptions { max_bytes: u64::MAX, ..Default::default() }).unwrap();
+        assert!(report.bytes_loaded > 0 && report.bytes_pinned == 0, "{:?}", report);
+        assert_eq!(store.cache_stats().pinned_bytes, 0);
+        
+        drop(store);
+    }
+    
+    cleanup(&dir);
+}
+
 // ==================== CONCURRENT ACCESS ====================
 
 fn test_concurrent_reads() {
     let dir = test_dir("concurrent_reads");
-    let store = Arc::new(Store::open(std::path::Path::new(&dir)).unwrap());
+    let store = Arc::new(open_store(std::path::Path::new(&dir)).unwrap());
     
     // Setup data
     for i in 0..100 {
@@ -528,15 +2341,404 @@ fn test_concurrent_reads() {
     cleanup(&dir);
 }
 
-fn test_concurrent_read_write() {
-    let dir = test_dir("concurrent_rw");
-    let store = Arc::new(Store::open(std::path::Path::new(&dir)).unwrap());
+fn test_concurrent_cold_segment_reads() {
+    let dir = test_dir("concurrent_cold_reads");
+    let dir_path = std::path::Path::new(&dir);
+    let value = |i: usize| format!("value {} {}", i, "x".repeat(i % 50));
+    let opened = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = opened.clone();
+    let options = StoreOptions {
+        on_event: Some(Arc::new(move |event: StoreEvent| {
+            if let StoreEvent::SegmentReaderOpened { path } = event {
+                sink.lock().unwrap().push(path);
+            }
+        })),
+        ..Default::default()
+    };
+    let (store, _) = open_store_with(dir_path, options.clone()).unwrap();
+    let entries = (0..20_000).map(|i| (format!("cold/{:06}", i), value(i))).collect();
+    store.set_many(entries, None).unwrap();
+    store.flush().unwrap();
     
-    let mut handles = vec![];
+    let store = Arc::new(reopen(store, dir_path, options));
+    opened.lock().unwrap().clear();
+    let (l0, l1, l2) = store.segment_counts();
+    assert!(l0 + l1 + l2 > 0);
+    
+    // Every thread walks the same cold blocks, each from a different starting point
+    let handles: Vec<_> = (0..16).map(|t| {
+        let store = store.clone();
+        thread::spawn(move || {
+            for n in 0..20_000 {
+                let i = (n + t * 1250) % 20_000;
+                assert_eq!(store.get(&format!("cold/{:06}", i)).unwrap(), Some(value(i)));
+            }
+            assert_eq!(store.scan_prefix("cold/", usize::MAX).unwrap().len(), 20_000);
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
     
-    // Writer thread
-    let store_write = store.clone();
-    let write_handle = thread::spawn(move || {
+    let mut opened = opened.lock().unwrap().clone();
+    let opens = opened.len();
+    opened.sort();
+    opened.dedup();
+    assert!(opens <= l0 + l1 + l2, "{} opens for {} segments", opens, l0 + l1 + l2);
+    assert_eq!(opened.len(), opens);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_coalesced_point_gets() {
+    let dir = test_dir("coalesced_gets");
+    let dir_path = std::path::Path::new(&dir);
+    let loads = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = loads.clone();
+    let options = StoreOptions {
+        coalesce_point_gets: true,
+        on_event: Some(Arc::new(move |event: StoreEvent| {
+            if let StoreEvent::BlockLoaded { offset, .. } = event {
+                sink.lock().unwrap().push(offset);
+            }
+        })),
+        ..Default::default()
+    };
+    let (store, _) = open_store_with(dir_path, options.clone()).unwrap();
+    let entries = (0..5_000).map(|i| (format!("cfg/{:05}", i), format!("v{}", i))).collect();
+    store.set_many(entries, None).unwrap();
+    store.flush().unwrap();
+    
+    // A burst of gets for one cold key reads its block once
+    let store = Arc::new(reopen(store, dir_path, options));
+    loads.lock().unwrap().clear();
+    let barrier = Arc::new(std::sync::Barrier::new(64));
+    let handles: Vec<_> = (0..64).map(|_| {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            barrier.wait();
+            assert_eq!(store.get("cfg/02500").unwrap(), Some("v2500".to_string()));
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(loads.lock().unwrap().len(), 1);
+    
+    // Shared answers never go back in time: a reader only ever sees the counter climb
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let readers: Vec<_> = (0..8).map(|_| {
+        let store = store.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut last = 0u64;
+            while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                if let Some(value) = store.get("counter").unwrap() {
+                    let seen: u64 = value.parse().unwrap();
+                    assert!(seen >= last, "saw {} after {}", seen, last);
+                    last = seen;
+                }
+            }
+        })
+    }).collect();
+    for n in 1..=200u64 {
+        store.set("counter", &n.to_string(), false).unwrap();
+        // Flushed, so the readers go past the memtable and coalesce
+        store.flush().unwrap();
+        let seen: u64 = store.get("counter").unwrap().unwrap().parse().unwrap();
+        assert_eq!(seen, n);
+    }
+    done.store(true, std::sync::atomic::Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_deleted_key_lookups() {
+    let dir = test_dir("deleted_lookups");
+    let dir_path = std::path::Path::new(&dir);
+    let keys: Vec<String> = (0..1000).map(|i| format!("gone/{:04}", i)).collect();
+    {
+        let store = open_store(dir_path).unwrap();
+        store.set_many(keys.iter().map(|key| (key.clone(), "v".to_string())).collect(), None).unwrap();
+        store.flush().unwrap();
+        for key in &keys {
+            store.delete(key).unwrap();
+        }
+        store.flush().unwrap();
+    }
+    
+    // The tombstone in the newer segment settles each lookup; the older one is never read
+    let store = open_store(dir_path).unwrap();
+    assert_eq!(store.segment_counts(), (2, 0, 0));
+    for key in &keys {
+        assert_eq!(store.get(key).unwrap(), None);
+        assert!(!store.contains(key).unwrap());
+    }
+    assert_eq!(store.lookup_stats(), LookupStats { blocks_read: 2000, negative_cache_hits: 0 });
+    drop(store);
+    
+    // With the negative cache only the first lookup of each key reads anything
+    let options = StoreOptions { negative_lookup_cache: Some(2000), ..Default::default() };
+    let (store, _) = open_store_with(dir_path, options).unwrap();
+    for _ in 0..3 {
+        for key in &keys {
+            assert_eq!(store.get(key).unwrap(), None);
+            assert!(!store.contains(key).unwrap());
+        }
+    }
+    assert_eq!(store.lookup_stats(), LookupStats { blocks_read: 1000, negative_cache_hits: 5000 });
+    
+    // Writes forget their keys, flushed or not
+    store.set("gone/0001", "back", false).unwrap();
+    assert_eq!(store.get("gone/0001").unwrap(), Some("back".to_string()));
+    store.flush().unwrap();
+    assert_eq!(store.get("gone/0001").unwrap(), Some("back".to_string()));
+    let mut batch = WriteBatch::new();
+    batch.put("gone/0002", "batched");
+    store.write(batch).unwrap();
+    store.flush().unwrap();
+    assert!(store.contains("gone/0002").unwrap());
+    
+    // As do subtree deletes over them, and a value under a deleted subtree reads as absent
+    store.delete_subtree("gone").unwrap();
+    assert_eq!(store.get("gone/0001").unwrap(), None);
+    store.set("gone/0003", "again", false).unwrap();
+    assert_eq!(store.get("gone/0003").unwrap(), Some("again".to_string()));
+    
+    // Least recently used keys go first once the cache is full
+    drop(store);
+    let options = StoreOptions { negative_lookup_cache: Some(10), ..Default::default() };
+    let (store, _) = open_store_with(dir_path, options).unwrap();
+    for key in &keys[..20] {
+        store.get(key).unwrap();
+    }
+    let before = store.lookup_stats();
+    for key in &keys[10..20] {
+        store.get(key).unwrap();
+    }
+    store.get(&keys[0]).unwrap();
+    let after = store.lookup_stats();
+    assert_eq!(after.negative_cache_hits - before.negative_cache_hits, 10);
+    assert!(after.blocks_read > before.blocks_read);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_may_contain() {
+    let dir = test_dir("may_contain");
+    let blocks_loaded = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = blocks_loaded.clone();
+    let options = StoreOptions {
+        on_event: Some(Arc::new(move |event: StoreEvent| {
+            if let StoreEvent::BlockLoaded { .. } = event {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        })),
+        ..Default::default()
+    };
+    let (store, _) = open_store_with(std::path::Path::new(&dir), options).unwrap();
+    
+    // Live keys must always be maybes, and no answer may read a block, cached or not
+    let mut live: std::collections::HashMap<String, bool> = std::collections::HashMap::new();
+    let check = |store: &Store, live: &std::collections::HashMap<String, bool>, stage: &str| {
+        let reads = || (store.cache_stats().hits + store.cache_stats().misses, store.lookup_stats().blocks_read,
+                        blocks_loaded.load(std::sync::atomic::Ordering::SeqCst));
+        let before = reads();
+        let mut false_positives = 0;
+        for (key, &is_live) in live {
+            let maybe = store.may_contain(key);
+            assert!(maybe || !is_live, "{}: live key {} ruled out", stage, key);
+            false_positives += (maybe && !is_live) as usize;
+        }
+        let never_written = (0..1000).filter(|i| store.may_contain(&format!("absent/{}", i))).count();
+        assert_eq!(reads(), before, "{}: may_contain read a block", stage);
+        (false_positives, never_written)
+    };
+    
+    let mut state = 0x2545f4914f6cdd1du64;
+    let mut next = move |bound: u64| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % bound
+    };
+    for round in 0..6 {
+        for _ in 0..2000 {
+            let key = format!("k/{}/{}", next(20), next(200));
+            match next(10) {
+                0..=6 => {
+                    store.set(&key, "v", false).unwrap();
+                    live.insert(key, true);
+                }
+                7 | 8 => {
+                    store.delete(&key).unwrap();
+                    live.insert(key, false);
+                }
+                _ => {
+                    let group = format!("k/{}", next(20));
+                    store.delete_subtree(&group).unwrap();
+                    for (key, is_live) in live.iter_mut() {
+                        if key.starts_with(&format!("{}/", group)) {
+                            *is_live = false;
+                        }
+                    }
+                }
+            }
+        }
+        check(&store, &live, &format!("round {} memtable", round));
+        store.flush().unwrap();
+        check(&store, &live, &format!("round {} flushed", round));
+    }
+    store.compact().unwrap();
+    assert_eq!(store.segment_counts().0, 2);
+    let (_, never_written) = check(&store, &live, "compacted");
+    
+    // Bounds and blooms rule out nearly every key no segment holds
+    assert!(never_written < 50, "{} of 1000 absent keys were maybes", never_written);
+    assert!(!store.may_contain(""));
+    
+    // The same through a namespace
+    let ns = store.namespace("shard");
+    ns.set("a", "1", false).unwrap();
+    assert!(ns.may_contain("a"));
+    assert!(!store.may_contain("a"));
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_read_amplification_report() {
+    let dir = test_dir("read_amplification");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Keys interleaved across L2, L1, L0 and the memtable, so every segment's range takes in
+    // every key and only blooms tell them apart
+    let key = |i: usize| format!("k/{:04}", i);
+    let write = |level: usize, flushes: usize| {
+        for part in 0..flushes {
+            let keys = (0..400).filter(|i| i % 4 == level && i / 4 % flushes == part);
+            store.set_many(keys.map(|i| (key(i), "v".repeat(20))).collect(), None).unwrap();
+            store.flush().unwrap();
+        }
+    };
+    write(0, 4);
+    store.compact().unwrap();
+    store.compact_range("k/", "k0").unwrap();
+    write(1, 4);
+    store.compact().unwrap();
+    write(2, 1);
+    for i in (3..400).step_by(4) {
+        store.set(&key(i), "v", false).unwrap();
+    }
+    assert_eq!(store.segment_counts(), (1, 1, 1));
+    assert!(store.read_amplification_report().levels.is_empty());
+    
+    // Past the first and last keys, each segment's bounds would rule the gets out on their own
+    for i in 4..396 {
+        assert!(store.get(&key(i)).unwrap().is_some());
+        assert_eq!(store.get(&format!("{}x", key(i))).unwrap(), None);
+    }
+    assert!(store.contains(&key(0)).unwrap());
+    
+    let report = store.read_amplification_report();
+    let levels: Vec<(ReadLevel, u64)> = report.levels.iter().map(|level| (level.level, level.gets)).collect();
+    assert_eq!(levels, vec![(ReadLevel::Memtable, 98), (ReadLevel::L0, 98), (ReadLevel::L1, 98), (ReadLevel::L2, 98), (ReadLevel::NotFound, 392)]);
+    let row = |level: ReadLevel| *report.levels.iter().find(|row| row.level == level).unwrap();
+    
+    // Each get probes the segments down to the one answering it; those past it hold older seqs
+    let memtable = row(ReadLevel::Memtable);
+    assert_eq!((memtable.segments_probed, memtable.blocks_read), (ReadCost::default(), ReadCost::default()));
+    for (level, probed) in [(ReadLevel::L0, 1), (ReadLevel::L1, 2), (ReadLevel::L2, 3), (ReadLevel::NotFound, 3)] {
+        let row = row(level);
+        assert_eq!((row.segments_probed.mean, row.segments_probed.p99), (probed as f64, probed), "{}", level);
+        
+        // Blooms rule out the segments above the answer, allowing for a rare false positive
+        let answered = (level != ReadLevel::NotFound) as u64;
+        assert!(row.bloom_negatives.mean >= (probed - answered) as f64 - 0.05, "{}: {:?}", level, row);
+        assert!(row.blocks_read.mean >= answered as f64 && row.blocks_read.mean <= answered as f64 + 0.05, "{}: {:?}", level, row);
+        assert!((row.bloom_negatives.mean + row.blocks_read.mean - probed as f64).abs() < 1e-9, "{}: {:?}", level, row);
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_read_budget() {
+    let dir = test_dir("read_budget");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Every segment's bounds take in every k/ key, so a key only the oldest holds is probed
+    // for in all six: three L1 segments of four flushes each, then three L0 ones
+    for i in 0..15 {
+        store.set_many(vec![
+            ("k/a".to_string(), i.to_string()),
+            (format!("k/m{:02}", i), "v".repeat(20)),
+            ("k/z".to_string(), i.to_string()),
+        ], None).unwrap();
+        store.flush().unwrap();
+        if i % 4 == 3 {
+            store.compact().unwrap();
+        }
+    }
+    assert_eq!(store.segment_counts(), (3, 3, 0));
+    
+    let segments = |max| ReadBudget { max_segments: Some(max), ..Default::default() };
+    let blocks = |max| ReadBudget { max_block_reads: Some(max), ..Default::default() };
+    let exceeded = |key: &str, budget| match store.get_with_budget(key, budget) {
+        Err(WalDbError::BudgetExceeded { path, segments_probed, block_reads, candidate }) => {
+            assert_eq!(path, key);
+            (segments_probed, block_reads, candidate)
+        }
+        other => panic!("expected BudgetExceeded, got {:?}", other),
+    };
+    
+    // The same layout stops in the same place every time
+    for _ in 0..3 {
+        assert_eq!(exceeded("k/m00", segments(5)), (5, 0, None));
+        assert_eq!(exceeded("k/m00", segments(0)), (0, 0, None));
+    }
+    assert_eq!(exceeded("k/m00", blocks(0)), (6, 0, None));
+    assert_eq!(store.get_with_budget("k/m00", segments(2)).unwrap_err().to_string(),
+        "Read of k/m00 ran out of budget: 2 segments probed, 0 blocks read from disk");
+    assert!(matches!(store.get_with_budget("k/m00", ReadBudget { max_segments: Some(6), max_block_reads: Some(0) }), Err(WalDbError::BudgetExceeded { .. })));
+    
+    // A generous budget gets what get does, and a block the cache now holds costs nothing
+    assert_eq!(store.get_with_budget("k/m00", segments(6)).unwrap(), store.get("k/m00").unwrap());
+    assert_eq!(store.get_with_budget("k/m00", blocks(0)).unwrap().as_deref(), Some("v".repeat(20).as_str()));
+    for key in ["k/a", "k/m07", "k/m14", "k/q", "j"] {
+        assert_eq!(store.get_with_budget(key, blocks(1)).unwrap(), store.get(key).unwrap(), "{}", key);
+        assert_eq!(store.get_with_budget(key, ReadBudget::default()).unwrap(), store.get(key).unwrap(), "{}", key);
+    }
+    // Blooms rule a missing key out of every segment without reading a block
+    assert_eq!(store.get_with_budget("k/q", blocks(0)).unwrap(), None);
+    // And the memtable answers without probing any
+    store.set("k/m00", "new", false).unwrap();
+    assert_eq!(store.get_with_budget("k/m00", segments(0)).unwrap().as_deref(), Some("new"));
+    
+    // Namespaces report their own paths
+    let ns = store.namespace("tenant");
+    ns.set("x", "1", false).unwrap();
+    store.flush().unwrap();
+    assert!(matches!(ns.get_with_budget("x", blocks(0)), Err(WalDbError::BudgetExceeded { path, .. }) if path == "x"));
+    
+    cleanup(&dir);
+}
+
+fn test_concurrent_read_write() {
+    let dir = test_dir("concurrent_rw");
+    let store = Arc::new(open_store(std::path::Path::new(&dir)).unwrap());
+    
+    let mut handles = vec![];
+    
+    // Writer thread
+    let store_write = store.clone();
+    let write_handle = thread::spawn(move || {
         for i in 0..100 {
             store_write.set(&format!("concurrent/{}", i), &format!("val{}", i), false).unwrap();
             thread::sleep(Duration::from_micros(100));
@@ -563,266 +2765,3491 @@ fn test_concurrent_read_write() {
     cleanup(&dir);
 }
 
+fn test_concurrent_increments() {
+    let dir = test_dir("concurrent_increments");
+    
+    {
+        let store = Arc::new(open_store(std::path::Path::new(&dir)).unwrap());
+        let handles: Vec<_> = (0..16).map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..10_000 {
+                    store.increment("stats/page_views/home", 1).unwrap();
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        
+        assert_eq!(store.get("stats/page_views/home").unwrap(), Some("160000".to_string()));
+        store.flush().unwrap();
+    }
+    
+    if !in_memory() {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        assert_eq!(store.get("stats/page_views/home").unwrap(), Some("160000".to_string()));
+        assert_eq!(store.increment("stats/page_views/home", -160_000).unwrap(), 0);
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_sharded_memtable() {
+    let dir = test_dir("sharded_memtable");
+    let path = std::path::Path::new(&dir);
+    let sharded = |shards| StoreOptions { memtable_shards: Some(shards), ..Default::default() };
+    
+    match open_store_with(path, sharded(3)) {
+        Err(WalDbError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+        other => panic!("expected InvalidInput, got {:?}", other.map(|_| ())),
+    }
+    
+    {
+        let (store, _) = open_store_with(path, sharded(8)).unwrap();
+        let store = Arc::new(store);
+        let handles: Vec<_> = (0..8).map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let mut last = 0;
+                for i in 0..2000 {
+                    last = store.set(&format!("t{}/item/{:04}", t, i), &i.to_string(), false).unwrap();
+                    store.increment("counters/total", 1).unwrap();
+                }
+                store.delete(&format!("t{}/item/0000", t)).unwrap();
+                last
+            })
+        }).collect();
+        for handle in handles {
+            let seq = handle.join().unwrap();
+            store.wait_durable(seq).unwrap();
+        }
+        
+        // Tree checks still see the parent, which shares its child's shard
+        assert!(matches!(store.set("t3/item/0001/x", "v", false), Err(WalDbError::TreeSemantics { .. })));
+        
+        // A scan merges every shard back into key order
+        let all = store.get_range("", "~").unwrap();
+        assert_eq!(all.len(), 8 * 1999 + 1);
+        assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(all[0], ("counters/total".to_string(), "16000".to_string()));
+        assert_eq!(store.scan_prefix("t5/", usize::MAX).unwrap().len(), 1999);
+    }
+    
+    if !in_memory() {
+        // The WAL replays the same whether or not the store reopens sharded
+        {
+            let store = open_store(path).unwrap();
+            assert_eq!(store.get("counters/total").unwrap(), Some("16000".to_string()));
+            assert_eq!(store.get("t7/item/1999").unwrap(), Some("1999".to_string()));
+            assert_eq!(store.get("t2/item/0000").unwrap(), None);
+        }
+        let (store, _) = open_store_with(path, sharded(4)).unwrap();
+        store.flush().unwrap();
+        assert_eq!(store.memtable_len(), 0);
+        assert_eq!(store.get("t0/item/0042").unwrap(), Some("42".to_string()));
+        assert_eq!(store.get_range("", "~").unwrap().len(), 8 * 1999 + 1);
+        
+        drop(store);
+    }
+    
+    cleanup(&dir);
+}
+
 // ==================== ERROR HANDLING ====================
 
-fn test_invalid_operations() {
-    let dir = test_dir("invalid");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+fn test_bad_segment_magic() {
+    let dir = test_dir("bad_magic");
+    {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        store.set("key", "value", false).unwrap();
+        store.flush().unwrap();
+    }
+    
+    // Clobber the header of the flushed segment
+    let seg_path = std::fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension() == Some("seg".as_ref()))
+        .unwrap();
+    let mut data = std::fs::read(&seg_path).unwrap();
+    data[..7].copy_from_slice(b"NOTWALD");
+    std::fs::write(&seg_path, data).unwrap();
+    
+    let result = open_store(std::path::Path::new(&dir));
+    match result {
+        Err(WalDbError::Corruption { file, offset, .. }) => {
+            assert_eq!(file, seg_path);
+            assert_eq!(offset, 0);
+        }
+        other => panic!("Expected corruption error, got {:?}", other.map(|_| ())),
+    }
+    
+    cleanup(&dir);
+}
+
+// Flush one segment and return its path
+fn flushed_segment(dir: &str) -> std::path::PathBuf {
+    {
+        let store = open_store(std::path::Path::new(dir)).unwrap();
+        store.set("key", "value", false).unwrap();
+        store.flush().unwrap();
+    }
+    std::fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension() == Some("seg".as_ref()))
+        .unwrap()
+}
+
+fn test_open_ignores_unknown_files() {
+    let dir = test_dir("unknown_files");
+    flushed_segment(&dir);
+    
+    let dir_path = std::path::Path::new(&dir);
+    std::fs::write(dir_path.join(".nfs0000000012345"), b"stale").unwrap();
+    std::fs::write(dir_path.join("manifest.log~"), b"editor backup").unwrap();
+    std::fs::write(dir_path.join("l0_9999999999.seg"), b"orphan, not in manifest").unwrap();
+    
+    let logged = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = logged.clone();
+    let options = StoreOptions {
+        debug_log: Some(std::sync::Arc::new(move |msg: &str| sink.lock().unwrap().push(msg.to_string()))),
+        ..Default::default()
+    };
+    
+    let (store, report) = open_store_with(dir_path, options).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("value".to_string()));
+    assert_eq!(report.ignored_files, vec![
+        dir_path.join(".nfs0000000012345"),
+        dir_path.join("l0_9999999999.seg"),
+        dir_path.join("manifest.log~"),
+    ]);
+    assert!(report.skipped_segments.is_empty());
+    assert_eq!(logged.lock().unwrap().len(), 3);
+    
+    cleanup(&dir);
+}
+
+fn test_open_unreadable_segment() {
+    let dir = test_dir("unreadable_segment");
+    let seg_path = flushed_segment(&dir);
+    std::fs::write(&seg_path, b"WAL").unwrap();
+    
+    match open_store(std::path::Path::new(&dir)) {
+        Err(WalDbError::Corruption { file, detail, .. }) => {
+            assert_eq!(file, seg_path);
+            assert!(detail.contains(seg_path.file_name().unwrap().to_str().unwrap()), "{}", detail);
+        }
+        other => panic!("Expected corruption error, got {:?}", other.map(|_| ())),
+    }
+    
+    // A missing segment that no compaction replaced is just as fatal
+    std::fs::remove_file(&seg_path).unwrap();
+    assert!(matches!(open_store(std::path::Path::new(&dir)), Err(WalDbError::Corruption { .. })));
+    
+    let options = StoreOptions { skip_unreadable_segments: true, ..Default::default() };
+    let (store, report) = open_store_with(std::path::Path::new(&dir), options).unwrap();
+    assert_eq!(report.skipped_segments.len(), 1);
+    assert_eq!(report.skipped_segments[0].0, seg_path);
+    // The WAL still has the write
+    assert_eq!(store.get("key").unwrap(), Some("value".to_string()));
+    
+    cleanup(&dir);
+}
+
+// Event names in arrival order, collected from StoreOptions::on_event
+fn recording_options() -> (StoreOptions, Arc<std::sync::Mutex<Vec<String>>>) {
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = events.clone();
+    let options = StoreOptions {
+        on_event: Some(Arc::new(move |event: StoreEvent| {
+            let name = match event {
+                StoreEvent::CompactionStarted { level, .. } => format!("CompactionStarted L{}", level),
+                StoreEvent::CompactionFinished { level, .. } => format!("CompactionFinished L{}", level),
+                StoreEvent::MemtableFlushed { .. } => "MemtableFlushed".to_string(),
+                StoreEvent::WalSyncFailed { retry_in, .. } => format!("WalSyncFailed retry={}", retry_in.is_some()),
+                StoreEvent::SegmentOpenFailed { path, .. } => format!("SegmentOpenFailed {}", path.display()),
+                StoreEvent::StorageDegraded { .. } => "StorageDegraded".to_string(),
+                StoreEvent::Opened { .. } => "Opened".to_string(),
+                other => format!("{:?}", other),
+            };
+            sink.lock().unwrap().push(name);
+        })),
+        ..Default::default()
+    };
+    (options, events)
+}
+
+fn test_sync_diff() {
+    let dir_a = test_dir("sync_diff_a");
+    let dir_b = test_dir("sync_diff_b");
+    let a = open_store(std::path::Path::new(&dir_a)).unwrap();
+    let b = open_store(std::path::Path::new(&dir_b)).unwrap();
+    for store in [&a, &b] {
+        for i in 0..20 {
+            store.set(&format!("users/{}/name", i), &format!("user {}", i), false).unwrap();
+            store.set(&format!("users/{}/age", i), &format!("{}", 20 + i), false).unwrap();
+        }
+        store.set("other/key", "x", false).unwrap();
+    }
+    // Seqs and layout differ between the two, the digests still agree
+    a.flush().unwrap();
+    assert_eq!(a.sync_digest("users/", SyncGranularity::Children).unwrap(), b.sync_digest("users/", SyncGranularity::Children).unwrap());
+    
+    a.set("users/3/name", "renamed \"3\" \u{1F600}\n", false).unwrap();
+    a.delete("users/7/age").unwrap();
+    a.set("users/25/name", "new", false).unwrap();
+    a.delete_subtree("users/9").unwrap();
+    a.set("other/key", "y", false).unwrap();
+    
+    let remote = b.sync_digest("users/", SyncGranularity::Children).unwrap();
+    assert_eq!(remote.len(), 20);
+    let plan = a.diff_against("users/", SyncGranularity::Children, &remote).unwrap();
+    assert_eq!(plan.changed, vec!["users/3", "users/7"]);
+    assert_eq!(plan.local_only, vec!["users/25"]);
+    assert_eq!(plan.remote_only, vec!["users/9"]);
+    
+    let mut payload = Vec::new();
+    assert_eq!(a.export_diff(&plan, &mut payload).unwrap(), 4);
+    assert_eq!(b.apply_diff(&payload[..]).unwrap(), 4);
+    assert_eq!(b.get_range("users/", "users0").unwrap(), a.get_range("users/", "users0").unwrap());
+    assert_eq!(b.sync_digest("users/", SyncGranularity::Children).unwrap(), a.sync_digest("users/", SyncGranularity::Children).unwrap());
+    assert!(a.diff_against("users/", SyncGranularity::Children, &b.sync_digest("users/", SyncGranularity::Children).unwrap()).unwrap().is_empty());
+    // Outside the prefix nothing moved
+    assert_eq!(b.get("other/key").unwrap(), Some("x".to_string()));
+    
+    // Fixed-size key ranges cover the same ground
+    a.set("users/12/name", "changed", false).unwrap();
+    let plan = a.diff_against("users/", SyncGranularity::KeyChars(1), &b.sync_digest("users/", SyncGranularity::KeyChars(1)).unwrap()).unwrap();
+    assert_eq!(plan.changed, vec!["users/1"]);
+    let mut payload = Vec::new();
+    a.export_diff(&plan, &mut payload).unwrap();
+    assert_eq!(b.apply_diff(&payload[..]).unwrap(), 1);
+    assert_eq!(b.get_range("users/", "users0").unwrap(), a.get_range("users/", "users0").unwrap());
+    
+    // A payload that isn't one is refused before anything is written
+    assert!(b.apply_diff(&b"{\"key\":\"users/1/name\",\"value\":\"x\"}\n"[..]).is_err());
+    assert!(b.apply_diff(&b"{\"sync\":\"users/\",\"granularity\":\"children\"}\n{\"key\":\"users/1/name\",\"value\":\"x\"}\n"[..]).is_err());
+    assert_eq!(b.get("users/1/name").unwrap(), Some("user 1".to_string()));
+}
+
+fn test_snapshot_import() {
+    let dir = test_dir("snapshot_source");
+    let source = open_store(std::path::Path::new(&dir)).unwrap();
+    for i in 0..25 {
+        source.set(&format!("items/{:02}", i), &format!("v{:02}", i), false).unwrap();
+    }
+    source.set("items/05", "quoted \"5\" \u{1F600}\n", false).unwrap();
+    source.set("other/key", "x", false).unwrap();
+    source.flush().unwrap();
+    
+    // Ten entries a chunk, so 25 make two full chunks and a short one
+    let options = SnapshotOptions { prefix: "items/".to_string(), chunk_entries: 10 };
+    let mut file = Vec::new();
+    let info = source.export_snapshot(&mut file, &options).unwrap();
+    assert_eq!((info.chunks, info.entries), (3, 25));
+    assert_eq!(info.store_id, source.store_id());
+    assert_eq!(info.seq_ceiling, source.seq().unwrap());
+    assert_eq!(info.seq_floor, source.history_floor());
+    let mut again = Vec::new();
+    source.export_snapshot(&mut again, &options).unwrap();
+    assert_eq!(again, file);
+    
+    // Into the test directory, which a store in memory never creates
+    let path = std::path::Path::new(&dir).join("items.snapshot");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(&path, &file).unwrap();
+    assert_eq!(Store::verify_export_file(&path).unwrap(), info);
+    
+    // A damaged middle chunk stops the import there, with the chunk before it applied
+    let text = String::from_utf8(file.clone()).unwrap();
+    assert!(text.contains("{\"key\":\"items/14\",\"value\":\"v14\"}\n"));
+    let damaged = text.replace("\"v14\"", "\"v41\"");
+    std::fs::write(&path, &damaged).unwrap();
+    let chunk_of = |result: Result<SnapshotInfo>| match result {
+        Err(WalDbError::SnapshotCorrupt { chunk, .. }) => chunk,
+        other => panic!("expected SnapshotCorrupt, got {:?}", other),
+    };
+    assert_eq!(chunk_of(Store::verify_export_file(&path)), Some(1));
+    
+    let target_dir = test_dir("snapshot_target");
+    let target = open_store(std::path::Path::new(&target_dir)).unwrap();
+    let error = target.import(damaged.as_bytes(), ImportOptions::default()).unwrap_err();
+    assert!(matches!(error, WalDbError::SnapshotCorrupt { chunk: Some(1), .. }), "{:?}", error);
+    assert!(error.to_string().starts_with("Snapshot chunk 1 is damaged"), "{}", error);
+    assert_eq!(target.get("items/09").unwrap(), Some("v09".to_string()));
+    assert_eq!(target.get("items/10").unwrap(), None);
+    
+    // Repaired, the import picks up at the chunk that failed
+    let report = target.import(&file[..], ImportOptions { resume_from_chunk: 1 }).unwrap();
+    assert_eq!((report.chunks_applied, report.entries_applied), (2, 15));
+    assert_eq!(report.snapshot, info);
+    assert_eq!(target.scan_prefix("items/", usize::MAX).unwrap(), source.scan_prefix("items/", usize::MAX).unwrap());
+    assert_eq!(target.get("other/key").unwrap(), None);
+    
+    // Applying chunks again converges on the same data
+    let report = target.import(&file[..], ImportOptions::default()).unwrap();
+    assert_eq!(report.chunks_applied, 3);
+    assert_eq!(target.scan_prefix("items/", usize::MAX).unwrap(), source.scan_prefix("items/", usize::MAX).unwrap());
+    
+    // Cut off partway through a chunk, that chunk is named; a changed trailer or header is not a chunk's
+    let cut = &text[..text.find("items/22").unwrap()];
+    assert_eq!(chunk_of(target.import(cut.as_bytes(), ImportOptions::default()).map(|report| report.snapshot)), Some(2));
+    let bad_digest = text.replace(&format!("{:016x}", info.digest), &format!("{:016x}", info.digest ^ 1));
+    assert_eq!(chunk_of(target.import(bad_digest.as_bytes(), ImportOptions::default()).map(|report| report.snapshot)), None);
+    let bad_header = text.replacen(&info.seq_ceiling.to_string(), &(info.seq_ceiling + 1).to_string(), 1);
+    assert_eq!(chunk_of(target.import(bad_header.as_bytes(), ImportOptions { resume_from_chunk: 3 }).map(|report| report.snapshot)), None);
+    assert_eq!(chunk_of(target.import(&b""[..], ImportOptions::default()).map(|report| report.snapshot)), None);
+    
+    // Nothing exported is an empty snapshot that still checks out
+    let mut empty = Vec::new();
+    let info = source.export_snapshot(&mut empty, &SnapshotOptions { prefix: "nothing/".to_string(), ..Default::default() }).unwrap();
+    assert_eq!((info.chunks, info.entries), (0, 0));
+    assert_eq!(target.import(&empty[..], ImportOptions::default()).unwrap().chunks_applied, 0);
+    
+    drop(source);
+    drop(target);
+    cleanup(&dir);
+    cleanup(&target_dir);
+}
+
+fn test_submission_order() {
+    let dir = test_dir("submission_order");
+    let store = Arc::new(open_store(std::path::Path::new(&dir)).unwrap());
+    let order = SubmissionOrder::new();
+    
+    // As the Node binding does it: tickets taken in call order on one thread, each operation on
+    // a thread of its own. Spawning the get first gives it every chance to win the lock.
+    let mut handles = Vec::new();
+    for i in 0..10_000 {
+        let key = format!("ryw/{}", i % 16);
+        let write = order.submit(Access::Write);
+        let read = order.submit(Access::Read);
+        let (reader, writer) = (Arc::clone(&store), Arc::clone(&store));
+        let (read_key, expected) = (key.clone(), i.to_string());
+        handles.push(thread::spawn(move || {
+            read.wait();
+            assert_eq!(reader.get(&read_key).unwrap(), Some(expected));
+        }));
+        handles.push(thread::spawn(move || {
+            write.wait();
+            writer.set(&key, &i.to_string(), false).unwrap();
+        }));
+        if handles.len() >= 64 {
+            for handle in handles.drain(..) {
+                handle.join().unwrap();
+            }
+        }
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    
+    // Reads with no write between them don't wait on each other
+    let first = order.submit(Access::Read);
+    let second = order.submit(Access::Read);
+    second.wait();
+    first.wait();
+    // A write waits for the reads before it, a later read for that write
+    let write = order.submit(Access::Write);
+    let after = order.submit(Access::Read);
+    let started = Instant::now();
+    let waiter = thread::spawn(move || {
+        after.wait();
+        started.elapsed()
+    });
+    thread::sleep(Duration::from_millis(50));
+    drop(first);
+    drop(second);
+    write.wait();
+    drop(write);
+    assert!(waiter.join().unwrap() >= Duration::from_millis(50));
+}
+
+fn test_health_check() {
+    let dir = test_dir("health_check");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    for i in 0..50 {
+        store.set(&format!("users/{}", i), "x", false).unwrap();
+    }
+    store.flush().unwrap();
+    
+    let options = HealthCheckOptions { write_probe: true, max_duration: Duration::from_secs(5) };
+    let statuses = |report: &HealthReport| -> Vec<(&'static str, HealthStatus)> {
+        report.checks.iter().map(|check| (check.name, check.status.clone())).collect()
+    };
+    // A loaded machine can wake the flusher late, so give it a few tries
+    let healthy = |options: &HealthCheckOptions| {
+        (0..20).any(|_| {
+            let report = store.health_check(options.clone()).unwrap();
+            thread::sleep(Duration::from_millis(5));
+            report.healthy()
+        })
+    };
+    assert!(healthy(&options));
+    let report = store.health_check(options.clone()).unwrap();
+    let names: Vec<&str> = report.checks.iter().map(|check| check.name).collect();
+    assert_eq!(names, vec!["memtable_read", "write_probe", "wal_buffer", "wal_flusher", "block_cache"]);
+    
+    // The probe leaves nothing a user read sees
+    assert_eq!(store.scan_prefix("", usize::MAX).unwrap().len(), 50);
+    assert!(store.list_namespaces().unwrap().is_empty());
+    
+    // A stalled flusher fails its check and only that one
+    store.pause_wal_flusher(true);
+    thread::sleep(Duration::from_millis(100));
+    let report = store.health_check(options.clone()).unwrap();
+    assert!(!report.healthy());
+    for (name, status) in statuses(&report) {
+        match name {
+            "wal_flusher" => assert!(matches!(status, HealthStatus::Failed(_)), "{:?}", status),
+            _ => assert_eq!(status, HealthStatus::Passed, "{}", name),
+        }
+    }
+    store.pause_wal_flusher(false);
+    assert!(healthy(&options));
+    
+    // Out of time, the remaining checks are skipped and the report isn't healthy
+    let report = store.health_check(HealthCheckOptions { write_probe: false, max_duration: Duration::ZERO }).unwrap();
+    assert_eq!(report.checks.len(), 4);
+    assert!(report.checks.iter().all(|check| check.status == HealthStatus::Skipped));
+    assert!(!report.healthy());
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_store_events() {
+    let dir = test_dir("store_events");
+    let (mut options, events) = recording_options();
+    options.skip_unreadable_segments = true;
+    
+    // A segment that fails to open is reported before the store is; in memory there is none
+    let mut opening = vec!["Opened".to_string()];
+    if !in_memory() {
+        let seg_path = flushed_segment(&dir);
+        std::fs::write(&seg_path, b"WAL").unwrap();
+        opening.insert(0, format!("SegmentOpenFailed {}", seg_path.display()));
+    }
+    let (store, _) = open_store_with(std::path::Path::new(&dir), options).unwrap();
+    assert_eq!(*events.lock().unwrap(), opening);
+    events.lock().unwrap().clear();
+    
+    for batch in 0..4 {
+        store.set(&format!("batch/{}", batch), "x", false).unwrap();
+        store.flush().unwrap();
+    }
+    store.compact().unwrap();
+    assert_eq!(*events.lock().unwrap(), vec![
+        "MemtableFlushed", "MemtableFlushed", "MemtableFlushed", "MemtableFlushed",
+        "CompactionStarted L1", "CompactionFinished L1",
+    ]);
+    
+    cleanup(&dir);
+}
+
+// The system clock, its wall time set back by `behind` seconds and its monotonic time moved
+// on by `ahead`
+struct SteppedClock {
+    behind: std::sync::atomic::AtomicU64,
+    ahead: std::sync::atomic::AtomicU64,
+}
+
+impl Clock for SteppedClock {
+    fn wall(&self) -> SystemTime {
+        SystemTime::now() - Duration::from_secs(self.behind.load(std::sync::atomic::Ordering::SeqCst))
+    }
+    
+    fn monotonic(&self) -> Instant {
+        Instant::now() + Duration::from_secs(self.ahead.load(std::sync::atomic::Ordering::SeqCst))
+    }
+}
+
+fn stepped_clock() -> Arc<SteppedClock> {
+    Arc::new(SteppedClock { behind: std::sync::atomic::AtomicU64::new(0), ahead: std::sync::atomic::AtomicU64::new(0) })
+}
+
+fn test_clock_steps_back() {
+    let dir = test_dir("clock_steps_back");
+    let path = std::path::Path::new(&dir);
+    let clock = stepped_clock();
+    let step = |secs| clock.behind.store(secs, std::sync::atomic::Ordering::SeqCst);
+    let (mut options, events) = recording_options();
+    options.clock = Some(clock.clone());
+    let went_back = || events.lock().unwrap().iter().filter(|event| event.starts_with("ClockWentBackwards")).count();
+    
+    let before = {
+        let (store, _) = open_store_with(path, options.clone()).unwrap();
+        store.set("a", "1", false).unwrap();
+        store.flush().unwrap();
+        let before = store.now();
+        if !in_memory() {
+            assert!(std::fs::read_to_string(path.join("manifest.log")).unwrap().contains("clock|"));
+        }
+        
+        // An hour back, time holds where it was, and the event comes once
+        step(3600);
+        assert!(store.now() >= before);
+        assert!(store.now() >= before);
+        assert_eq!(went_back(), 1);
+        
+        // Flushes keep naming files from the counter, whatever the clock says
+        // (three segments stay under the L0 threshold, so no merge runs meanwhile)
+        for i in 0..2 {
+            store.set(&format!("b/{}", i), "x", false).unwrap();
+            store.flush().unwrap();
+        }
+        if !in_memory() {
+            let files = std::fs::read_dir(path).unwrap()
+                .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".seg"))
+                .count();
+            assert_eq!(files, 3);
+        }
+        assert_eq!(store.segment_counts().0, 3);
+        
+        // Once the clock catches up it is read as it is; a small step back says nothing
+        step(0);
+        assert!(store.now() >= before);
+        step(0);
+        let caught_up = store.now();
+        assert!(caught_up >= before);
+        assert_eq!(went_back(), 1);
+        before
+    };
+    
+    if !in_memory() {
+        // Reopened with the clock still behind, open compares it with the manifest's clock line
+        events.lock().unwrap().clear();
+        step(3600);
+        let (store, _) = open_store_with(path, options).unwrap();
+        assert_eq!(went_back(), 1);
+        assert_eq!(events.lock().unwrap().last().map(String::as_str), Some("Opened"));
+        assert!(store.now() >= before - Duration::from_secs(1));
+        assert_eq!(store.get("b/1").unwrap(), Some("x".to_string()));
+        
+        drop(store);
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_clock_drives_health_check() {
+    let dir = test_dir("clock_health_check");
+    let clock = stepped_clock();
+    let options = StoreOptions { clock: Some(clock.clone()), ..Default::default() };
+    let (store, _) = open_store_with(std::path::Path::new(&dir), options).unwrap();
+    let flusher = || {
+        let report = store.health_check(HealthCheckOptions { write_probe: false, max_duration: Duration::from_secs(5) }).unwrap();
+        report.checks.into_iter().find(|check| check.name == "wal_flusher").unwrap().status
+    };
+    assert_eq!(flusher(), HealthStatus::Passed);
+    
+    // With the flusher held, a minute on the store's clock is a stall, however little real time passed
+    store.pause_wal_flusher(true);
+    thread::sleep(Duration::from_millis(50));
+    clock.ahead.store(60, std::sync::atomic::Ordering::SeqCst);
+    match flusher() {
+        HealthStatus::Failed(detail) => assert!(detail.starts_with("Last ran"), "{}", detail),
+        other => panic!("Expected the flusher check to fail, got {:?}", other),
+    }
+    
+    // Its next pass is stamped by the same clock
+    store.pause_wal_flusher(false);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while flusher() != HealthStatus::Passed {
+        assert!(Instant::now() < deadline, "Flusher never ran again");
+        thread::sleep(Duration::from_millis(5));
+    }
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_wal_sync_failure_retains_entries() {
+    let dir = test_dir("wal_sync_failure");
+    let dir_path = std::path::Path::new(&dir);
+    let wal_path = dir_path.join("wal.log");
+    let saved_path = dir_path.join("wal.log.saved");
+    
+    {
+        let (options, events) = recording_options();
+        let (store, _) = open_store_with(dir_path, options).unwrap();
+        store.set("before", "1", false).unwrap();
+        store.flush().unwrap();
+        
+        // A directory where the WAL should be fails every open, even for root, unlike permissions
+        std::fs::rename(&wal_path, &saved_path).unwrap();
+        std::fs::create_dir(&wal_path).unwrap();
+        
+        store.set("during/a", "x", false).unwrap();
+        let seq = store.set("during/b", "y", false).unwrap();
+        
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !events.lock().unwrap().iter().any(|e| e == "WalSyncFailed retry=true") {
+            assert!(Instant::now() < deadline, "Expected a WalSyncFailed event, got {:?}", events.lock().unwrap());
+            thread::sleep(Duration::from_millis(10));
+        }
+        
+        // Once the disk is back the flusher's retry writes the entries it held on to
+        std::fs::remove_dir(&wal_path).unwrap();
+        std::fs::rename(&saved_path, &wal_path).unwrap();
+        store.wait_durable(seq).unwrap();
+    }
+    
+    let store = open_store(dir_path).unwrap();
+    assert_eq!(store.get("before").unwrap(), Some("1".to_string()));
+    assert_eq!(store.get("during/a").unwrap(), Some("x".to_string()));
+    assert_eq!(store.get("during/b").unwrap(), Some("y".to_string()));
+    
+    cleanup(&dir);
+}
+
+fn test_storage_full_degraded_mode() {
+    let dir = test_dir("storage_full");
+    let dir_path = std::path::Path::new(&dir);
+    
+    // A disk that refuses writes at the sites listed, segment ones only after two blocks
+    let full: Arc<std::sync::Mutex<Vec<WriteSite>>> = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let segment_writes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let (mut options, events) = recording_options();
+    let (failing, writes) = (full.clone(), segment_writes.clone());
+    options.io_hook = Some(Arc::new(move |site: WriteSite, _: &std::path::Path| {
+        let refused = failing.lock().unwrap().contains(&site)
+            && (site != WriteSite::Segment || writes.fetch_add(1, std::sync::atomic::Ordering::SeqCst) >= 2);
+        match refused {
+            true => Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "No space left on device")),
+            false => Ok(()),
+        }
+    }));
+    let wait_for = |done: &dyn Fn() -> bool, what: &str| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(5));
+        }
+    };
+    
+    let (store, _) = open_store_with(dir_path, options).unwrap();
+    let mut acked = Vec::new();
+    for i in 0..1000 {
+        store.set(&format!("before/{:04}", i), "v", false).unwrap();
+        acked.push(format!("before/{:04}", i));
+    }
+    store.flush().unwrap();
+    
+    // The WAL fills up: writes are taken until the flusher's sync fails, then refused
+    full.lock().unwrap().push(WriteSite::Wal);
+    let mut refused = None;
+    let mut last_seq = 0;
+    for i in 0.. {
+        let key = format!("during/{:06}", i);
+        match store.set(&key, "v", false) {
+            Ok(seq) => {
+                last_seq = seq;
+                acked.push(key);
+            }
+            Err(WalDbError::StorageFull) => {
+                refused = Some(key);
+                break;
+            }
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(store.is_degraded());
+    assert!(events.lock().unwrap().iter().any(|e| e == "StorageDegraded"));
+    assert!(matches!(store.delete("before/0000"), Err(WalDbError::StorageFull)));
+    assert!(matches!(store.set_many(vec![("x".to_string(), "y".to_string())], None), Err(WalDbError::StorageFull)));
+    assert!(matches!(store.flush(), Err(WalDbError::StorageFull)));
+    let mut batch = WriteBatch::new();
+    batch.put("batched", "v");
+    assert!(matches!(store.write(batch), Err(WalDbError::StorageFull)));
+    assert_eq!(store.get("before/0000").unwrap(), Some("v".to_string()));
+    
+    // Given room, the flusher's retry writes what it held on to and writes resume
+    full.lock().unwrap().clear();
+    wait_for(&|| !store.is_degraded(), "the WAL retry");
+    store.wait_durable(last_seq).unwrap();
+    assert!(events.lock().unwrap().iter().any(|e| e == "StorageRecovered"));
+    store.set("after/wal", "v", false).unwrap();
+    acked.push("after/wal".to_string());
+    
+    // A flush that fails mid-segment leaves neither file nor manifest entry behind
+    for i in 0..1000 {
+        store.set(&format!("unflushed/{:04}", i), "v", false).unwrap();
+        acked.push(format!("unflushed/{:04}", i));
+    }
+    let segment_files = || {
+        let mut files: Vec<_> = std::fs::read_dir(dir_path).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension() == Some("seg".as_ref()))
+            .collect();
+        files.sort();
+        files
+    };
+    let files = segment_files();
+    // The file number the flush took is spent either way
+    let manifest_entries = || std::fs::read_to_string(dir_path.join("manifest.log")).unwrap()
+        .lines().filter(|line| !line.starts_with("file|")).map(str::to_string).collect::<Vec<_>>();
+    let manifest = manifest_entries();
+    events.lock().unwrap().clear();
+    segment_writes.store(0, std::sync::atomic::Ordering::SeqCst);
+    full.lock().unwrap().push(WriteSite::Segment);
+    match store.flush() {
+        Err(WalDbError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::StorageFull),
+        other => panic!("Expected the flush to fail, got {:?}", other),
+    }
+    assert!(segment_writes.load(std::sync::atomic::Ordering::SeqCst) > 2);
+    assert_eq!(segment_files(), files);
+    assert_eq!(manifest_entries(), manifest);
+    assert!(events.lock().unwrap().iter().any(|e| e == "StorageDegraded"));
+    
+    // The WAL still takes syncs, so the store comes back by itself and the flush can go again
+    wait_for(&|| !store.is_degraded(), "the WAL probe");
+    full.lock().unwrap().clear();
+    store.flush().unwrap();
+    assert_eq!(segment_files().len(), files.len() + 1);
+    drop(store);
+    
+    // Every acknowledged write survives; the refused one never happened
+    let store = open_store(dir_path).unwrap();
+    for key in &acked {
+        assert_eq!(store.get(key).unwrap(), Some("v".to_string()), "{} was lost", key);
+    }
+    assert_eq!(store.get(&refused.unwrap()).unwrap(), None);
+    assert_eq!(store.get("batched").unwrap(), None);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_atomic_file_creation() {
+    let dir = test_dir("atomic_files");
+    let dir_path = std::path::Path::new(&dir);
+    let names = |suffix: &str| -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir_path).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(suffix))
+            .collect();
+        names.sort();
+        names
+    };
+    
+    // A flush that fails partway leaves neither the segment nor its temporary file
+    let options = StoreOptions {
+        io_hook: Some(Arc::new(|site: WriteSite, _: &std::path::Path| match site {
+            WriteSite::Segment => Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "No space left on device")),
+            _ => Ok(()),
+        })),
+        ..Default::default()
+    };
+    let (store, _) = open_store_with(dir_path, options).unwrap();
+    for i in 0..500 {
+        store.set(&format!("items/{:04}", i), "some value to fill a few blocks", false).unwrap();
+    }
+    assert!(store.flush().is_err());
+    assert!(names(".seg").is_empty() && names(".tmp").is_empty(), "{:?}", names(""));
+    drop(store);
+    
+    // Every file the store wrote came into being under its own name, with nothing left over
+    let store = open_store(dir_path).unwrap();
+    store.flush().unwrap();
+    assert_eq!(names(".seg").len(), 1);
+    assert!(names(".tmp").is_empty(), "{:?}", names(""));
+    assert!(std::fs::read(dir_path.join("wal.log")).unwrap().starts_with(b"WAL3"));
+    drop(store);
+    
+    // Temporary files a crash left behind are removed by the next open; a stranger's stay
+    for name in ["l0_0000000777.seg.tmp", "l0_0000000778.seg.tmp.tmp", "wal.log.tmp", "manifest.log.tmp", "notes.tmp"] {
+        std::fs::write(dir_path.join(name), b"torn").unwrap();
+    }
+    let (store, report) = open_store_with(dir_path, StoreOptions::default()).unwrap();
+    assert_eq!(names(".tmp"), vec!["notes.tmp"]);
+    assert_eq!(report.ignored_files, vec![dir_path.join("notes.tmp")]);
+    assert_eq!(store.get("items/0499").unwrap(), Some("some value to fill a few blocks".to_string()));
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_open_newer_format_segment() {
+    let dir = test_dir("newer_format");
+    let seg_path = flushed_segment(&dir);
+    
+    let mut data = std::fs::read(&seg_path).unwrap();
+    data[..7].copy_from_slice(b"WALDB08");
+    std::fs::write(&seg_path, data).unwrap();
+    
+    // Not skippable: silently dropping a newer segment would look like data loss
+    let options = StoreOptions { skip_unreadable_segments: true, ..Default::default() };
+    match open_store_with(std::path::Path::new(&dir), options) {
+        Err(err @ WalDbError::NewerFormat { .. }) => {
+            assert!(matches!(&err, WalDbError::NewerFormat { file, version: 8 } if *file == seg_path));
+            assert!(err.to_string().contains("created by a newer version"));
+        }
+        other => panic!("Expected newer format error, got {:?}", other.map(|_| ())),
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_store_identity() {
+    let dir = test_dir("store_identity");
+    let dir_path = std::path::Path::new(&dir);
+    let identity_path = dir_path.join("IDENTITY");
+    
+    // Written on creation, and the Opened event carries it
+    let (mut options, events) = recording_options();
+    let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let (sink, record) = (ids.clone(), options.on_event.take().unwrap());
+    options.on_event = Some(Arc::new(move |event: StoreEvent| {
+        if let StoreEvent::Opened { identity } = &event {
+            sink.lock().unwrap().push(identity.id);
+        }
+        record(event);
+    }));
+    let (store, report) = open_store_with(dir_path, options.clone()).unwrap();
+    assert!(report.created_identity);
+    let identity = store.identity();
+    assert_eq!(store.store_id(), identity.id);
+    assert!(store.format_version() >= 6);
+    assert!(identity.created.elapsed().unwrap() < Duration::from_secs(60));
+    assert_eq!(*ids.lock().unwrap(), vec![identity.id]);
+    assert_eq!(*events.lock().unwrap(), vec!["Opened"]);
+    let text = identity.id.to_string();
+    assert_eq!(text.len(), 36);
+    assert_eq!(&text[14..15], "4", "{} should read as a v4 UUID", text);
+    store.set("users/alice", "1", false).unwrap();
+    store.flush().unwrap();
+    drop(store);
+    
+    // Kept across reopens, and a copy of the directory shares it
+    let (store, report) = open_store_with(dir_path, options).unwrap();
+    assert!(!report.created_identity && report.ignored_files.is_empty());
+    assert_eq!(store.identity(), identity);
+    assert_eq!(ids.lock().unwrap().len(), 2);
+    drop(store);
+    
+    // A store from before identity files gets a new one and keeps its data
+    std::fs::remove_file(&identity_path).unwrap();
+    let (store, report) = open_store_with(dir_path, StoreOptions::default()).unwrap();
+    assert!(report.created_identity);
+    assert_ne!(store.store_id(), identity.id);
+    assert_eq!(store.get("users/alice").unwrap(), Some("1".to_string()));
+    let adopted = store.store_id();
+    drop(store);
+    assert_eq!(open_store(dir_path).unwrap().store_id(), adopted);
+    
+    // A newer format is refused before anything else is read, naming the file and version
+    let text = std::fs::read_to_string(&identity_path).unwrap();
+    let newer: String = text.lines().map(|line| match line.starts_with("format ") {
+        true => "format 99\nfuture_key something\n".to_string(),
+        false => format!("{}\n", line),
+    }).collect();
+    std::fs::write(&identity_path, newer).unwrap();
+    match open_store(dir_path) {
+        Err(err @ WalDbError::NewerFormat { .. }) => {
+            assert!(matches!(&err, WalDbError::NewerFormat { file, version: 99 } if *file == identity_path));
+            assert!(err.to_string().contains("format 99"), "{}", err);
+        }
+        other => panic!("Expected newer format error, got {:?}", other.map(|_| ())),
+    }
+    
+    // Unknown keys alone don't stop an open, but a damaged file does
+    std::fs::write(&identity_path, text.clone() + "future_key something\n").unwrap();
+    assert_eq!(open_store(dir_path).unwrap().store_id(), adopted);
+    std::fs::write(&identity_path, text.replace("id ", "id x")).unwrap();
+    assert!(matches!(open_store(dir_path), Err(WalDbError::Corruption { .. })));
+    
+    cleanup(&dir);
+}
+
+fn test_salvage_truncated_segment() {
+    let dir = test_dir("salvage_truncated");
+    let dir_path = std::path::Path::new(&dir);
+    let entries: Vec<(String, String)> = (0..2000)
+        .map(|i| (format!("items/{:05}/name", i), format!("item {}", i)))
+        .collect();
+    
+    {
+        let store = open_store(dir_path).unwrap();
+        store.set_many(entries.clone(), None).unwrap();
+        store.flush().unwrap();
+    }
+    
+    // Only the segment may serve reads, as after a checkpoint dropped the WAL
+    std::fs::remove_file(dir_path.join("wal.log")).unwrap();
+    let seg_path = std::fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension() == Some("seg".as_ref()))
+        .unwrap();
+    let original = std::fs::read(&seg_path).unwrap();
+    
+    // Where each record ends: seq, type, shared, suffix length, value length, suffix, value
+    let mut record_ends = Vec::new();
+    let mut pos = 7;
+    while record_ends.len() < entries.len() {
+        let suffix_len = u32::from_le_bytes(original[pos + 13..pos + 17].try_into().unwrap()) as usize;
+        let value_len = u32::from_le_bytes(original[pos + 17..pos + 21].try_into().unwrap()) as usize;
+        pos += 21 + suffix_len + value_len;
+        record_ends.push(pos);
+    }
+    let data_end = pos;
+    
+    // Cut through data blocks, the index, the bloom filter, and the footer
+    for cut in [data_end / 3, data_end / 2 + 5, data_end - 1, data_end, data_end + 40, original.len() - 33, original.len() - 1] {
+        std::fs::write(&seg_path, &original[..cut]).unwrap();
+        let (store, report) = open_store_with(dir_path, StoreOptions::default()).unwrap();
+        assert_eq!(report.salvaged_segments, vec![seg_path.clone()], "cut at {}", cut);
+        
+        let complete = record_ends.iter().filter(|&&end| end <= cut).count();
+        assert!(complete > 0);
+        assert_eq!(store.scan_prefix("items/", usize::MAX).unwrap(), entries[..complete], "cut at {}", cut);
+        for (key, value) in entries[..complete].iter().step_by(37) {
+            assert_eq!(store.get(key).unwrap().as_ref(), Some(value), "cut at {}", cut);
+        }
+        if let Some((key, _)) = entries.get(complete) {
+            assert_eq!(store.get(key).unwrap(), None);
+        }
+    }
+    
+    // Compaction rewrites the salvaged file, so the next open finds a clean footer
+    let cut = data_end / 2;
+    std::fs::write(&seg_path, &original[..cut]).unwrap();
+    let complete = record_ends.iter().filter(|&&end| end <= cut).count();
+    {
+        let store = open_store(dir_path).unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.scan_prefix("items/", usize::MAX).unwrap(), entries[..complete]);
+    }
+    let (store, report) = open_store_with(dir_path, StoreOptions::default()).unwrap();
+    assert!(report.salvaged_segments.is_empty());
+    assert!(report.ignored_files.is_empty());
+    assert_eq!(store.scan_prefix("items/", usize::MAX).unwrap(), entries[..complete]);
+    
+    cleanup(&dir);
+}
+
+fn test_manifest_overlapping_segments() {
+    let dir = test_dir("manifest_overlap");
+    let saved = test_dir("manifest_overlap_saved");
+    let dir_path = std::path::Path::new(&dir);
+    let segment_files = |path: &std::path::Path| -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(path).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".seg"))
+            .collect();
+        names.sort();
+        names
+    };
+    let check_reads = |store: &Store| {
+        assert_eq!(store.get("k/a").unwrap(), Some("a4".to_string()));
+        assert_eq!(store.get("k/b").unwrap(), None);
+        assert_eq!(store.scan_prefix("k/", 10).unwrap(), vec![
+            ("k/a".to_string(), "a4".to_string()),
+            ("k/c".to_string(), "c".to_string()),
+        ]);
+    };
+    
+    // Four flushes, keeping copies of the L0 files the compaction merges and then deletes
+    std::fs::create_dir_all(&saved).unwrap();
+    let l1 = {
+        let store = open_store(dir_path).unwrap();
+        for i in 1..=4 {
+            store.set("k/a", &format!("a{}", i), false).unwrap();
+            match i {
+                1 => { store.set("k/b", "b", false).unwrap(); }
+                3 => { store.delete("k/b").unwrap(); }
+                _ => { store.set("k/c", "c", false).unwrap(); }
+            }
+            store.flush().unwrap();
+        }
+        for name in segment_files(dir_path) {
+            std::fs::copy(dir_path.join(&name), std::path::Path::new(&saved).join(&name)).unwrap();
+        }
+        store.compact().unwrap();
+        assert_eq!(store.segment_counts(), (0, 1, 0));
+        segment_files(dir_path).remove(0)
+    };
+    let l0s = segment_files(std::path::Path::new(&saved));
+    assert_eq!(l0s.len(), 4);
+    
+    // As after a crash before the inputs were deleted: the compaction's entry names them
+    for name in &l0s {
+        std::fs::copy(std::path::Path::new(&saved).join(name), dir_path.join(name)).unwrap();
+    }
+    {
+        let (store, report) = open_store_with(dir_path, StoreOptions::default()).unwrap();
+        assert_eq!(report.dropped_segments, l0s);
+        assert!(report.overlapping_segments.is_empty());
+        assert_eq!(segment_files(dir_path), vec![l1.clone()]);
+        assert_eq!(store.segment_counts(), (0, 1, 0));
+        check_reads(&store);
+    }
+    
+    // An old segment put back by hand isn't one the compaction lists, so open only reports it
+    let first = &l0s[0];
+    std::fs::copy(std::path::Path::new(&saved).join(first), dir_path.join(first)).unwrap();
+    let manifest = std::fs::read_to_string(dir_path.join("manifest.log")).unwrap();
+    let seq_high = manifest.lines().find_map(|line| line.strip_suffix(&format!("|0|{}", first))).unwrap();
+    std::fs::write(dir_path.join("manifest.log"), format!("{}{}|0|{}\n", manifest, seq_high, first)).unwrap();
+    {
+        let (store, report) = open_store_with(dir_path, StoreOptions::default()).unwrap();
+        assert!(report.dropped_segments.is_empty());
+        assert_eq!(report.overlapping_segments, vec![(first.clone(), l1.clone())]);
+        assert_eq!(store.segment_counts(), (1, 1, 0));
+        check_reads(&store);
+        
+        assert!(store.drop_segment(first).unwrap());
+        assert!(!store.drop_segment(first).unwrap());
+        assert!(!store.drop_segment("999999.seg").unwrap());
+        assert_eq!(store.segment_counts(), (0, 1, 0));
+        assert!(!dir_path.join(first).exists());
+        check_reads(&store);
+    }
+    let (store, report) = open_store_with(dir_path, StoreOptions::default()).unwrap();
+    assert!(report.dropped_segments.is_empty() && report.overlapping_segments.is_empty());
+    assert_eq!(store.segment_counts(), (0, 1, 0));
+    check_reads(&store);
+    drop(store);
+    
+    cleanup(&dir);
+    cleanup(&saved);
+}
+
+fn test_dump_segment() {
+    let dir = test_dir("dump_segment");
+    let bad_dir = test_dir("dump_segment_bad");
+    let options = DumpOptions { values: true, hex: false };
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    let entries: Vec<(String, String)> = (0..300).map(|i| (format!("items/{:03}", i), "x".repeat(30))).collect();
+    store.set_many(entries, None).unwrap();
+    store.flush().unwrap();
+    
+    let name = std::fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .find(|name| name.ends_with(".seg"))
+        .unwrap();
+    let dump = |path: &std::path::Path| {
+        let mut out = Vec::new();
+        let anomalies = Store::dump_segment_file(path, &mut out, options).unwrap();
+        (anomalies, String::from_utf8(out).unwrap())
+    };
+    
+    let mut out = Vec::new();
+    assert_eq!(store.dump_segment(&name, &mut out, options).unwrap(), 0);
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.contains("footer: seq_low=1 seq_high=300 key_count=300"), "{}", text);
+    assert!(text.contains(" max_key=\"items/299\"\n"), "{}", text);
+    assert!(text.contains(&format!("  @7 seq=1 SET shared=0 key=\"items/000\" value_len=30 value=\"{}\"\n", "x".repeat(30))));
+    assert!(text.contains("seq=2 SET shared=8 key=\"items/001\""));
+    assert!(text.contains("\nblock 3 @"));
+    assert!(text.ends_with("summary: 300 records, 4 blocks, 0 anomalies\n"), "{}", text);
+    assert!(!text.contains("!!"));
+    
+    // Nothing in the output varies between runs
+    let seg_path = std::path::Path::new(&dir).join(&name);
+    assert_eq!(dump(&seg_path), (0, text));
+    
+    // Bump the first key past its neighbours and nudge the second index entry off its record
+    let mut bytes = std::fs::read(&seg_path).unwrap();
+    let footer = bytes.len() - 32;
+    let index_bytes = u32::from_le_bytes(bytes[footer + 20..footer + 24].try_into().unwrap()) as usize;
+    let bloom_bytes = u32::from_le_bytes(bytes[footer + 24..footer + 28].try_into().unwrap()) as usize;
+    let max_key_len = u32::from_le_bytes(bytes[footer - 12..footer - 8].try_into().unwrap()) as usize;
+    let index_start = footer - 12 - max_key_len - bloom_bytes - index_bytes;  // 12 for the max key length, flags word and collation code
+    let second = index_start + 12 + "items/000".len();
+    let block_off = u64::from_le_bytes(bytes[second + 4..second + 12].try_into().unwrap());
+    bytes[second + 4..second + 12].copy_from_slice(&(block_off + 1).to_le_bytes());
+    bytes[7 + 21 + 8] = b'z';
+    std::fs::create_dir_all(&bad_dir).unwrap();
+    let bad_path = std::path::Path::new(&bad_dir).join(&name);
+    std::fs::write(&bad_path, &bytes).unwrap();
+    
+    let (anomalies, text) = dump(&bad_path);
+    assert!(anomalies >= 4, "{}", text);
+    assert!(text.contains("  @7 seq=1 SET shared=0 key=\"items/00z\""));
+    assert!(text.contains("!! key out of order: \"items/001\" after \"items/00z\""), "{}", text);
+    assert!(text.contains(&format!("bytes past the block boundary at @{}", block_off + 1)), "{}", text);
+    assert!(text.contains(&format!("!! index entry 1 points at @{}, which doesn't start a record", block_off + 1)), "{}", text);
+    assert!(text.contains("!! index entry 0 key doesn't match the record's \"items/00z\""), "{}", text);
+    
+    drop(store);
+    cleanup(&dir);
+    cleanup(&bad_dir);
+}
+
+fn test_increment_errors() {
+    let dir = test_dir("increment_errors");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Missing counts from zero, and values already in segments are picked up
+    assert_eq!(store.increment("counters/a", 5).unwrap(), 5);
+    store.flush().unwrap();
+    assert_eq!(store.increment("counters/a", -7).unwrap(), -2);
+    
+    for bad in ["abc", " 5", "1.5", ""] {
+        store.set("counters/bad", bad, false).unwrap();
+        match store.increment("counters/bad", 1) {
+            Err(WalDbError::NotAnInteger { path, value }) => {
+                assert_eq!(path, "counters/bad");
+                assert_eq!(value, bad);
+            }
+            other => panic!("Expected NotAnInteger for {:?}, got {:?}", bad, other),
+        }
+        assert_eq!(store.get("counters/bad").unwrap(), Some(bad.to_string()));
+    }
+    
+    store.set("counters/max", &i64::MAX.to_string(), false).unwrap();
+    assert!(matches!(store.increment("counters/max", 1), Err(WalDbError::NotAnInteger { .. })));
+    assert_eq!(store.increment("counters/max", i64::MIN).unwrap(), -1);
+    
+    assert!(matches!(store.increment("counters/a/child", 1), Err(WalDbError::TreeSemantics { .. })));
+    
+    store.delete_subtree("counters").unwrap();
+    assert_eq!(store.increment("counters/a", 1).unwrap(), 1);
+    
+    cleanup(&dir);
+}
+
+fn test_queue() {
+    let dir = test_dir("queue");
+    let path = std::path::Path::new(&dir);
+    
+    let last = {
+        let store = Arc::new(open_store(path).unwrap());
+        
+        // Keys are the prefix plus a zero-padded number, increasing with each push
+        let a = store.push("jobs", "a").unwrap();
+        let b = store.push("jobs/", "b").unwrap();
+        assert!(a.starts_with("jobs/") && a.len() == "jobs/".len() + 20, "{}", a);
+        assert!(a[5..].bytes().all(|c| c.is_ascii_digit()));
+        assert!(a < b);
+        store.push("jobs", "c").unwrap();
+        
+        assert_eq!(store.peek_min("jobs").unwrap(), Some((a.clone(), "a".to_string())));
+        assert_eq!(store.queue_len("jobs", 10).unwrap(), 3);
+        assert_eq!(store.queue_len("jobs", 2).unwrap(), 2);
+        assert_eq!(store.pop_min("jobs").unwrap(), Some((a.clone(), "a".to_string())));
+        assert_eq!(store.get(&a).unwrap(), None);
+        assert_eq!(store.queue_len("jobs", 10).unwrap(), 2);
+        
+        // A key set below what has been popped is still found
+        store.set("jobs/0", "early", false).unwrap();
+        assert_eq!(store.pop_min("jobs").unwrap(), Some(("jobs/0".to_string(), "early".to_string())));
+        assert_eq!(store.pop_min("jobs").unwrap(), Some((b, "b".to_string())));
+        store.flush().unwrap();
+        assert_eq!(store.pop_min("jobs").unwrap().map(|(_, value)| value), Some("c".to_string()));
+        assert_eq!(store.pop_min("jobs").unwrap(), None);
+        assert_eq!(store.peek_min("jobs").unwrap(), None);
+        
+        for bad in ["", "/"] {
+            assert!(matches!(store.push(bad, "x"), Err(WalDbError::InvalidKey { .. })));
+            assert!(matches!(store.pop_min(bad), Err(WalDbError::InvalidKey { .. })));
+        }
+        assert!(matches!(store.push("\u{1}jobs", "x"), Err(WalDbError::InvalidKey { .. })));
+        store.set("scalar", "1", false).unwrap();
+        assert!(matches!(store.push("scalar", "x"), Err(WalDbError::TreeSemantics { .. })));
+        
+        // Poppers racing each other and a flush each get a different entry
+        for i in 0..10_000 {
+            store.push("work", &i.to_string()).unwrap();
+        }
+        let popped = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let flusher = {
+            let store = store.clone();
+            let popped = popped.clone();
+            thread::spawn(move || {
+                while popped.load(std::sync::atomic::Ordering::SeqCst) < 5_000 {
+                    store.flush().unwrap();
+                    thread::sleep(Duration::from_millis(5));
+                }
+            })
+        };
+        let handles: Vec<_> = (0..8).map(|_| {
+            let store = store.clone();
+            let popped = popped.clone();
+            thread::spawn(move || {
+                let mut values = Vec::new();
+                while popped.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 5_000 {
+                    values.push(store.pop_min("work").unwrap().unwrap().1);
+                }
+                values
+            })
+        }).collect();
+        let mut seen: Vec<usize> = handles.into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .map(|value| value.parse().unwrap())
+            .collect();
+        flusher.join().unwrap();
+        seen.sort_unstable();
+        assert_eq!(seen, (0..5_000).collect::<Vec<_>>());
+        
+        store.push("work", "10000").unwrap()
+    };
+    
+    if !in_memory() {
+        // The rest drains after a reopen, and new keys still sort after the old ones
+        let store = Arc::new(open_store(path).unwrap());
+        assert_eq!(store.queue_len("work", usize::MAX).unwrap(), 5_001);
+        let handles: Vec<_> = (0..8).map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let mut values = Vec::new();
+                while let Some((_, value)) = store.pop_min("work").unwrap() {
+                    values.push(value);
+                }
+                values
+            })
+        }).collect();
+        let mut seen: Vec<usize> = handles.into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .map(|value| value.parse().unwrap())
+            .collect();
+        seen.sort_unstable();
+        assert_eq!(seen, (5_000..10_001).collect::<Vec<_>>());
+        assert!(store.push("work", "next").unwrap() > last);
+        
+        drop(store);
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_invalid_operations() {
+    let dir = test_dir("invalid");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("parent", "value", false).unwrap();
+    let result = store.set("parent/child", "fails", false);
+    
+    assert!(result.is_err());
+    
+    cleanup(&dir);
+}
+
+// ==================== ADVANCED FEATURES ====================
+
+fn test_compaction() {
+    let dir = test_dir("compaction");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Create multiple L0 segments, short of the count that wakes compaction
+    let flush_batch = |batch: usize| {
+        for i in 0..50 {
+            store.set(&format!("batch{}/key{:03}", batch, i), "value", false).unwrap();
+        }
+        store.flush().unwrap();
+    };
+    for batch in 0..3 {
+        flush_batch(batch);
+    }
+    
+    // Check initial state
+    let (l0_before, l1_before, _) = store.segment_counts();
+    assert_eq!(l0_before, 3, "Should have 3 L0 segments");
+    assert_eq!(l1_before, 0, "Should have 0 L1 segments");
+    for batch in 3..5 {
+        flush_batch(batch);
+    }
+    
+    // Wait for compaction
+    thread::sleep(Duration::from_secs(6));
+    
+    // Check after compaction
+    let (l0_after, l1_after, _) = store.segment_counts();
+    assert!(l0_after < l0_before, "L0 segments should decrease");
+    assert!(l1_after > l1_before, "L1 segments should increase");
+    
+    // Verify data integrity
+    assert_eq!(store.get("batch0/key000").unwrap(), Some("value".to_string()));
+    assert_eq!(store.get("batch4/key049").unwrap(), Some("value".to_string()));
+    
+    // Test tombstone handling
+    store.set("test_key", "value1", false).unwrap();
+    store.flush().unwrap();
+    store.delete("test_key").unwrap();
+    store.flush().unwrap();
+    
+    // Wait for potential compaction
+    thread::sleep(Duration::from_secs(6));
+    
+    // Tombstone should still be effective
+    assert_eq!(store.get("test_key").unwrap(), None);
+    
+    cleanup(&dir);
+}
+
+fn test_group_commit_behavior() {
+    let dir = test_dir("group_commit");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Rapid writes should be batched
+    let start = Instant::now();
+    for i in 0..100 {
+        store.set(&format!("batch/{}", i), "value", false).unwrap();
+    }
+    let elapsed = start.elapsed();
+    
+    // Should complete quickly due to batching
+    assert!(elapsed.as_millis() < 200, "Group commit not working");
+    
+    cleanup(&dir);
+}
+
+fn test_range_queries() {
+    let dir = test_dir("range_queries");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Insert test data
+    for i in 0..20 {
+        store.set(&format!("key{:02}", i), &format!("value{}", i), false).unwrap();
+    }
+    store.flush().unwrap();
+    
+    // Test basic range
+    let results = store.get_range("key05", "key15").unwrap();
+    assert_eq!(results.len(), 10);
+    assert_eq!(results[0].0, "key05");
+    assert_eq!(results[9].0, "key14");
+    
+    // Test range with limit
+    let limited = store.get_range_limit("key00", "key20", 5).unwrap();
+    assert_eq!(limited.len(), 5);
+    
+    // Test prefix scan
+    store.set("users/alice/age", "30", false).unwrap();
+    store.set("users/alice/email", "alice@example.com", false).unwrap();
+    store.set("users/bob/age", "25", false).unwrap();
+    let alice_data = store.scan_prefix("users/alice/", 10).unwrap();
+    assert_eq!(alice_data.len(), 2);
+    
+    // Test with deletes
+    store.delete("key10").unwrap();
+    let range_with_delete = store.get_range("key09", "key12").unwrap();
+    assert!(!range_with_delete.iter().any(|(k, _)| k == "key10"));
+    
+    cleanup(&dir);
+}
+
+fn test_tombstone_behavior() {
+    let dir = test_dir("tombstone");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Set initial value
+    store.set("tomb/key", "value", false).unwrap();
+    assert_eq!(store.get("tomb/key").unwrap(), Some("value".to_string()));
+    
+    // Delete it
+    store.delete("tomb/key").unwrap();
+    assert_eq!(store.get("tomb/key").unwrap(), None);
+    
+    // Flush to disk
+    store.flush().unwrap();
+    
+    // Should still be deleted after flush
+    assert_eq!(store.get("tomb/key").unwrap(), None);
+    
+    // Set new value (this should override the tombstone)
+    store.set("tomb/key", "new_value", false).unwrap();
+    
+    // Should immediately see the new value
+    let result = store.get("tomb/key").unwrap();
+    assert_eq!(result, Some("new_value".to_string()), "Failed to set new value after tombstone");
+    
+    cleanup(&dir);
+}
+
+fn test_contains() {
+    let dir = test_dir("contains");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("c/live", "value", false).unwrap();
+    store.set("c/deleted", "value", false).unwrap();
+    store.set("c/sub/a", "value", false).unwrap();
+    store.set("c/empty", "", false).unwrap();
+    store.delete("c/deleted").unwrap();
+    store.delete_subtree("c/sub").unwrap();
+    
+    // Memtable answers, including a tombstone and a subtomb-covered scalar
+    let check = |store: &Store| {
+        assert!(store.contains("c/live").unwrap());
+        assert!(store.contains("c/empty").unwrap());
+        assert!(!store.contains("c/deleted").unwrap());
+        assert!(!store.contains("c/sub/a").unwrap());
+        assert!(!store.contains("c/missing").unwrap());
+        assert!(!store.contains("c").unwrap(), "An object is not a scalar");
+    };
+    check(&store);
+    
+    // Same answers once everything lives in segments
+    store.flush().unwrap();
+    check(&store);
+    
+    // A newer segment tombstone hides an older segment value, and a rewrite revives it
+    store.set("c/live2", "value", false).unwrap();
+    store.flush().unwrap();
+    store.delete("c/live2").unwrap();
+    store.flush().unwrap();
+    assert!(!store.contains("c/live2").unwrap());
+    store.set("c/sub/a", "again", false).unwrap();
+    assert!(store.contains("c/sub/a").unwrap());
+    
+    for key in ["c/live", "c/deleted", "c/sub/a", "c/missing", "c/live2"] {
+        assert_eq!(store.contains(key).unwrap(), store.get(key).unwrap().is_some(), "contains and get disagree on {}", key);
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_delete_heavy_workload_flushes() {
+    let dir = test_dir("delete_heavy");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Point tombstones must count towards the memtable threshold
+    for i in 0..1_000_000 {
+        store.delete(&format!("gone/{:07}", i)).unwrap();
+    }
+    
+    let (l0, l1, l2) = store.segment_counts();
+    assert!(l0 + l1 + l2 > 0, "Deletes should flush the memtable into segments");
+    
+    assert_eq!(store.get("gone/0000000").unwrap(), None);
+    assert_eq!(store.get("gone/0999999").unwrap(), None);
+    
+    cleanup(&dir);
+}
+
+// ==================== BATCH OPERATIONS ====================
+
+fn test_set_many_basic() {
+    let dir = test_dir("set_many_basic");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Set multiple key-value pairs atomically
+    let entries = vec![
+        ("users/alice/name".to_string(), "Alice".to_string()),
+        ("users/alice/age".to_string(), "30".to_string()),
+        ("users/bob/name".to_string(), "Bob".to_string()),
+        ("users/bob/age".to_string(), "25".to_string()),
+    ];
+    
+    store.set_many(entries, None).unwrap();
+    
+    // Verify all entries were stored
+    assert_eq!(store.get("users/alice/name").unwrap(), Some("Alice".to_string()));
+    assert_eq!(store.get("users/alice/age").unwrap(), Some("30".to_string()));
+    assert_eq!(store.get("users/bob/name").unwrap(), Some("Bob".to_string()));
+    assert_eq!(store.get("users/bob/age").unwrap(), Some("25".to_string()));
+    
+    cleanup(&dir);
+}
+
+fn test_set_many_with_subtree_replacement() {
+    let dir = test_dir("set_many_replace");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Set initial data
+    store.set("users/alice/name", "Alice", false).unwrap();
+    store.set("users/alice/age", "30", false).unwrap();
+    store.set("users/alice/job", "Engineer", false).unwrap();
+    
+    // Replace entire alice subtree with new data
+    let new_alice = vec![
+        ("users/alice/name".to_string(), "Alice Smith".to_string()),
+        ("users/alice/email".to_string(), "alice@example.com".to_string()),
+    ];
+    
+    store.set_many(new_alice, Some("users/alice")).unwrap();
+    
+    // Verify replacement happened
+    assert_eq!(store.get("users/alice/name").unwrap(), Some("Alice Smith".to_string()));
+    assert_eq!(store.get("users/alice/email").unwrap(), Some("alice@example.com".to_string()));
+    assert_eq!(store.get("users/alice/age").unwrap(), None); // Should be gone
+    assert_eq!(store.get("users/alice/job").unwrap(), None); // Should be gone
+    
+    cleanup(&dir);
+}
+
+fn test_set_many_empty() {
+    let dir = test_dir("set_many_empty");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Empty batch should succeed and do nothing
+    store.set_many(vec![], None).unwrap();
+    store.set_many(vec![], Some("some/path")).unwrap();
+    
+    cleanup(&dir);
+}
+
+fn test_set_many_parent_scalar_violation() {
+    let dir = test_dir("set_many_violation");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Set a scalar value
+    store.set("config", "scalar_value", false).unwrap();
+    
+    // Try to set children under the scalar - should fail
+    let entries = vec![
+        ("config/child1".to_string(), "value1".to_string()),
+        ("config/child2".to_string(), "value2".to_string()),
+    ];
+    
+    let result = store.set_many(entries, None);
+    assert!(result.is_err());
+    let err = result.unwrap_err();
+    assert_eq!(err.to_string(), "Cannot write under scalar parent");
+    assert!(matches!(err, WalDbError::TreeSemantics { .. }));
+    
+    // The whole batch is refused, with the scalar flushed or not, before anything is written
+    for flushed in [false, true] {
+        if flushed {
+            store.flush().unwrap();
+        }
+        let entries = vec![
+            ("other".to_string(), "written first".to_string()),
+            ("config/child".to_string(), "value".to_string()),
+        ];
+        assert!(matches!(store.set_many(entries, None), Err(WalDbError::TreeSemantics { .. })));
+        assert_eq!(store.get("other").unwrap(), None);
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_set_many_replace_spares_siblings() {
+    let dir = test_dir("set_many_replace_siblings");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("users/1/name", "One", false).unwrap();
+    store.set("users/10/name", "Ten", false).unwrap();
+    store.set("users/1b", "sibling", false).unwrap();
+    store.flush().unwrap();
+    
+    // Replacing users/1 clears it and what is under it, not keys that merely start with it
+    store.set_many(vec![("users/1/email".to_string(), "one@example.com".to_string())], Some("users/1")).unwrap();
+    assert_eq!(store.get("users/1/name").unwrap(), None);
+    assert_eq!(store.get("users/1/email").unwrap().as_deref(), Some("one@example.com"));
+    assert_eq!(store.get("users/10/name").unwrap().as_deref(), Some("Ten"));
+    assert_eq!(store.get("users/1b").unwrap().as_deref(), Some("sibling"));
+    
+    // A scalar at the replaced path goes too, and the batch may write under it
+    store.set("flag", "on", false).unwrap();
+    store.flush().unwrap();
+    store.set_many(vec![("flag/state".to_string(), "on".to_string())], Some("flag")).unwrap();
+    assert_eq!(store.get("flag").unwrap(), None);
+    assert_eq!(store.get("flag/state").unwrap().as_deref(), Some("on"));
+    
+    cleanup(&dir);
+}
+
+fn test_batch_parent_probes() {
+    let dir = test_dir("batch_parent_probes");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Deleted parents leave point tombstones in a segment, so each parent check reads a block
+    for i in 0..20 {
+        store.set(&format!("users/{}", i), "old", false).unwrap();
+        store.delete(&format!("users/{}", i)).unwrap();
+    }
+    store.set("locked", "scalar", false).unwrap();
+    store.flush().unwrap();
+    let block_reads = |store: &Store| {
+        let stats = store.cache_stats();
+        stats.hits + stats.misses
+    };
+    
+    // 1000 entries under 10 parents probe each parent once
+    let entries: Vec<(String, String)> = (0..1000).map(|i| (format!("users/{}/field{}", i % 10, i), "v".to_string())).collect();
+    let before = block_reads(&store);
+    store.set_many(entries, None).unwrap();
+    assert_eq!(block_reads(&store) - before, 10);
+    
+    // The same for a WriteBatch, whose own sets stand in for the store where they come first
+    let mut batch = WriteBatch::new();
+    for i in 0..1000 {
+        batch.put(&format!("users/{}/field{}", 10 + i % 10, i), "v");
+    }
+    batch.put("fresh", "scalar");
+    let before = block_reads(&store);
+    store.write(batch).unwrap();
+    assert_eq!(block_reads(&store) - before, 10);
+    
+    // A parent found to be a scalar still refuses every batch, whichever entry probes it first
+    let mut batch = WriteBatch::new();
+    batch.put("users/0/field0", "w").put("locked/a", "x").put("locked/b", "x");
+    assert!(matches!(store.write(batch), Err(WalDbError::TreeSemantics { .. })));
+    let entries = vec![("users/1/other".to_string(), "w".to_string()), ("fresh/child".to_string(), "x".to_string())];
+    assert!(matches!(store.set_many(entries, None), Err(WalDbError::TreeSemantics { .. })));
+    // Nor do siblings skip the check once an entry between them set their parent
+    let entries = vec![("users/2/a".to_string(), "w".to_string()), ("users/2".to_string(), "x".to_string()), ("users/2/b".to_string(), "w".to_string())];
+    assert!(matches!(store.set_many(entries, None), Err(WalDbError::TreeSemantics { .. })));
+    assert_eq!(store.get("users/0/field0").unwrap().as_deref(), Some("v"));
+    assert_eq!(store.get("users/1/other").unwrap(), None);
+    
+    cleanup(&dir);
+}
+
+fn test_has_live_children() {
+    let dir = test_dir("has_live_children");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    let subtree_tombs = |store: &Store| store.get_range_raw("", "\u{10ffff}").unwrap().into_iter()
+        .filter(|entry| matches!(entry.state, RawState::SubtreeDeleted(_))).count();
+    
+    store.set("users/a/name", "A", false).unwrap();
+    store.set("users/a/tags/x", "1", false).unwrap();
+    store.set("users/ab", "sibling", false).unwrap();
+    assert!(store.has_live_children("users/a").unwrap());
+    assert!(store.has_live_children("users").unwrap());
+    assert!(!store.has_live_children("users/a/name").unwrap());
+    store.flush().unwrap();
+    
+    // A deleted subtree's keys are still in the segment index, but none of them is live
+    store.delete_subtree("users/a").unwrap();
+    assert!(!store.has_live_children("users/a").unwrap());
+    store.flush().unwrap();
+    assert!(!store.has_live_children("users/a").unwrap());
+    assert!(!store.has_live_children("users/a/tags").unwrap());
+    assert!(store.has_live_children("users").unwrap());
+    
+    // One surviving grandchild is enough, and point deletes of the rest don't count
+    store.set("users/b/profile/bio", "hi", false).unwrap();
+    store.set("users/b/email", "b@example.com", false).unwrap();
+    store.flush().unwrap();
+    store.delete("users/b/email").unwrap();
+    assert!(store.has_live_children("users/b").unwrap());
+    store.flush().unwrap();
+    assert!(store.has_live_children("users/b").unwrap());
+    store.delete("users/b/profile/bio").unwrap();
+    assert!(!store.has_live_children("users/b").unwrap());
+    
+    // A set after the subtree delete is live again, and it all survives a reopen
+    store.set("users/a/name", "A2", false).unwrap();
+    let store = reopen(store, std::path::Path::new(&dir), StoreOptions::default());
+    assert!(store.has_live_children("users/a").unwrap());
+    assert!(!store.has_live_children("users/b").unwrap());
+    assert!(!store.has_live_children("users/ab").unwrap());
+    
+    // Namespaces ask about their own keys
+    let ns = store.namespace("tenant");
+    assert!(!ns.has_live_children("users").unwrap());
+    ns.set("users/a/name", "N", false).unwrap();
+    assert!(ns.has_live_children("users").unwrap());
+    assert!(ns.has_live_children("").unwrap());
+    
+    // set_many only writes a subtree tombstone when there is something under the base to hide
+    let before = subtree_tombs(&store);
+    store.set_many(vec![("users/c/name".to_string(), "C".to_string())], Some("users/c")).unwrap();
+    assert_eq!(subtree_tombs(&store), before);
+    store.set_many(vec![("users/c/email".to_string(), "c@example.com".to_string())], Some("users/c")).unwrap();
+    assert_eq!(subtree_tombs(&store), before + 1);
+    assert_eq!(store.get("users/c/name").unwrap(), None);
+    
+    cleanup(&dir);
+}
+
+fn test_clear_all() {
+    let dir = test_dir("clear_all");
+    let path = std::path::Path::new(&dir);
+    let invalid = |result: Result<()>| match result {
+        Err(WalDbError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+        other => panic!("expected InvalidInput, got {:?}", other),
+    };
+    
+    let store = open_store(path).unwrap();
+    for i in 0..100 {
+        store.set(&format!("users/{}/name", i), "old", false).unwrap();
+    }
+    store.flush().unwrap();
+    store.set("orders/1", "old", false).unwrap();
+    store.namespace("tenant").set("config", "old", false).unwrap();
+    store.set_vector("docs/1/embedding", vec![1.0, 0.0], false).unwrap();
+    
+    // An empty prefix or a bare * would take everything, so they are refused
+    invalid(store.delete_subtree("").map(|_| ()));
+    invalid(store.delete_subtree("/").map(|_| ()));
+    invalid(store.delete_pattern("*").map(|_| ()));
+    invalid(store.delete_pattern("**").map(|_| ()));
+    invalid(store.delete_pattern_cancellable("/*", &CancellationToken::new(), |_| {}).map(|_| ()));
+    invalid(store.delete_prefix("").map(|_| ()));
+    assert_eq!(store.get("users/7/name").unwrap(), Some("old".to_string()));
+    assert_eq!(store.delete_pattern("*/1/name").unwrap(), 1);
+    
+    // One subtree tombstone and nothing per key, the memtable's keys included
+    let wal_bytes = store.wal_stats().unwrap().bytes;
+    let tomb_seq = store.clear_all().unwrap();
+    store.wait_durable(tomb_seq).unwrap();
+    assert!(store.wal_stats().unwrap().bytes - wal_bytes < 64);
+    let raw = store.get_range_raw("", "\u{10ffff}").unwrap();
+    assert_eq!(raw.len(), 1, "{:?}", raw);
+    assert!(matches!(&raw[0].state, RawState::SubtreeDeleted(prefix) if prefix.is_empty()));
+    store.flush().unwrap();
+    assert_eq!(store.segment_counts(), (1, 0, 0));
+    
+    assert_eq!(store.get("users/7/name").unwrap(), None);
+    assert_eq!(store.get("orders/1").unwrap(), None);
+    assert_eq!(store.namespace("tenant").get("config").unwrap(), None);
+    assert_eq!(store.get_vector("docs/1/embedding").unwrap(), None);
+    assert!(store.scan_prefix("", usize::MAX).unwrap().is_empty());
+    
+    // Writes after it have newer seqs, so the tombstone leaves them be
+    store.set("users/7/name", "new", false).unwrap();
+    store.namespace("tenant").set("config", "new", false).unwrap();
+    assert_eq!(store.get("users/7/name").unwrap(), Some("new".to_string()));
+    
+    // And after a reopen, which replays the tombstone ahead of the later writes
+    let store = reopen(store, path, StoreOptions::default());
+    assert_eq!(store.get("users/7/name").unwrap(), Some("new".to_string()));
+    assert_eq!(store.get("users/8/name").unwrap(), None);
+    assert_eq!(store.namespace("tenant").get("config").unwrap(), Some("new".to_string()));
+    
+    // Compaction drops the covered segment whole, and with it the history before the clear
+    store.set("after", "flushed", false).unwrap();
+    store.flush().unwrap();
+    assert_eq!(store.segment_counts(), (2, 0, 0));
+    store.compact().unwrap();
+    assert_eq!(store.segment_counts(), (1, 0, 0));
+    assert!(store.history_floor() >= tomb_seq);
+    assert!(matches!(store.as_of(tomb_seq - 1), Err(WalDbError::HistoryUnavailable { .. })));
+    assert_eq!(store.scan_prefix("", usize::MAX).unwrap(), vec![
+        ("\0tenant/config".to_string(), "new".to_string()),
+        ("after".to_string(), "flushed".to_string()),
+        ("users/7/name".to_string(), "new".to_string()),
+    ]);
+    let store = reopen(store, path, StoreOptions::default());
+    assert_eq!(store.segment_counts(), (1, 0, 0));
+    assert_eq!(store.get("users/8/name").unwrap(), None);
+    assert_eq!(store.get("after").unwrap(), Some("flushed".to_string()));
+    
+    // A sealed prefix would lose its archive, so it has to be unsealed first
+    store.seal_prefix("users/").unwrap();
+    assert!(matches!(store.clear_all(), Err(WalDbError::Sealed { .. })));
+    assert_eq!(store.get("users/7/name").unwrap(), Some("new".to_string()));
+    store.unseal_prefix("users/").unwrap();
+    store.clear_all().unwrap();
+    assert!(store.scan_prefix("", usize::MAX).unwrap().is_empty());
+    
+    cleanup(&dir);
+}
+
+fn test_strict_utf8() {
+    let dir = test_dir("strict_utf8");
+    let path = std::path::Path::new(&dir);
+    let unicode = "日本語 ✓ Zürich 🎉";
+    {
+        let store = open_store(path).unwrap();
+        store.set("badkey/qqq", "x", false).unwrap();
+        store.set("badval/a", "AAAA", false).unwrap();
+        store.set("good/ünïcode", unicode, false).unwrap();
+        store.flush().unwrap();
+    }
+    
+    // Multi-byte text round-trips as it was written
+    {
+        let store = open_store(path).unwrap();
+        assert_eq!(store.get("good/ünïcode").unwrap().as_deref(), Some(unicode));
+        assert_eq!(store.scan_prefix("good/", 10).unwrap(), vec![("good/ünïcode".to_string(), unicode.to_string())]);
+    }
+    
+    // Put a byte no UTF-8 text holds into a key and into a value
+    let seg_path = std::fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "seg"))
+        .unwrap();
+    let mut bytes = std::fs::read(&seg_path).unwrap();
+    let find = |bytes: &[u8], needle: &[u8]| bytes.windows(needle.len()).position(|w| w == needle).unwrap();
+    let key_at = find(&bytes, b"qqq");
+    bytes[key_at] = 0x80;
+    let value_at = find(&bytes, b"AAAA");
+    bytes[value_at] = 0xff;
+    std::fs::write(&seg_path, &bytes).unwrap();
+    
+    {
+        let store = open_store(path).unwrap();
+        match store.get("badval/a") {
+            Err(WalDbError::InvalidUtf8 { path, file, offset }) => {
+                assert_eq!((path.as_str(), file.as_path()), ("badval/a", seg_path.as_path()));
+                assert!(offset > 0 && (offset as usize) < value_at);
+            }
+            other => panic!("expected InvalidUtf8, got {:?}", other),
+        }
+        assert!(matches!(store.scan_prefix("badval/", 10), Err(WalDbError::InvalidUtf8 { .. })));
+        match store.scan_prefix("badkey/", 10) {
+            Err(WalDbError::Corruption { file, offset, detail }) => {
+                assert_eq!(file, seg_path);
+                assert!(offset > 0 && (offset as usize) < key_at);
+                assert!(detail.contains("not valid UTF-8"), "{}", detail);
+            }
+            other => panic!("expected Corruption, got {:?}", other),
+        }
+        assert!(matches!(store.range_iter("badkey/", "badkey0").unwrap().next(), Some(Err(WalDbError::Corruption { .. }))));
+        
+        // Records beside the bad ones still read
+        assert_eq!(store.get("good/ünïcode").unwrap().as_deref(), Some(unicode));
+        assert_eq!(store.get("badkey/qqq").unwrap(), None);
+    }
+    
+    // lossy_reads gets the data out, bad bytes replaced
+    let options = StoreOptions { lossy_reads: true, ..Default::default() };
+    let (store, _) = open_store_with(path, options).unwrap();
+    assert_eq!(store.get("badval/a").unwrap().as_deref(), Some("\u{fffd}AAA"));
+    assert_eq!(store.scan_prefix("badkey/", 10).unwrap(), vec![("badkey/\u{fffd}qq".to_string(), "x".to_string())]);
+    assert_eq!(store.get("good/ünïcode").unwrap().as_deref(), Some(unicode));
+    drop(store);
+    
+    cleanup(&dir);
+}
+
+fn test_write_batch_ops_in_order() {
+    let dir = test_dir("write_batch_order");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("users/alice/name", "Alice", false).unwrap();
+    store.set("users/bob/name", "Bob", false).unwrap();
+    
+    let mut batch = WriteBatch::new();
+    batch.put("users/alice/age", "30")
+        .delete_subtree("users/alice")
+        .put("users/alice/name", "Alice Smith")
+        .delete("users/bob/name")
+        .put("users/carol/embedding", &encode_vector(&[1.0, 0.0], false))
+        .put("users/carol/embedding", &encode_vector(&[0.0, 1.0], false));
+    assert_eq!(batch.len(), 6);
+    store.write(batch).unwrap();
+    
+    let check = |store: &Store| {
+        // The subtree delete shadows only the ops queued before it
+        assert_eq!(store.get("users/alice/age").unwrap(), None);
+        assert_eq!(store.get("users/alice/name").unwrap(), Some("Alice Smith".to_string()));
+        assert_eq!(store.get("users/bob/name").unwrap(), None);
+        assert_eq!(store.get_vector("users/carol/embedding").unwrap(), Some(vec![0.0, 1.0]));
+    };
+    check(&store);
+    
+    let store = reopen(store, std::path::Path::new(&dir), StoreOptions::default());
+    check(&store);
+    
+    store.write(WriteBatch::new()).unwrap();
+    
+    cleanup(&dir);
+}
+
+fn test_write_batch_spans_flushes() {
+    let dir = test_dir("write_batch_flushes");
+    let value = "x".repeat(100);
+    
+    {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        let mut batch = WriteBatch::new();
+        for i in 0..20_000 {
+            batch.put(&format!("import/{:05}", i), &value);
+        }
+        store.write(batch).unwrap();
+        
+        let (l0, l1, l2) = store.segment_counts();
+        assert!(l0 + l1 + l2 > 1, "Expected the batch to flush more than once");
+        assert_eq!(store.scan_prefix("import/", usize::MAX).unwrap().len(), 20_000);
+    }
+    
+    if !in_memory() {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        let entries = store.scan_prefix("import/", usize::MAX).unwrap();
+        assert_eq!(entries.len(), 20_000);
+        assert!(entries.iter().all(|(_, v)| *v == value));
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_write_batch_survives_sidecar_failure() {
+    let dir = test_dir("write_batch_sidecar");
+    let path = std::path::Path::new(&dir);
+    let sidecar = path.join("vectors.dat");
+    
+    {
+        let (options, events) = recording_options();
+        let (store, _) = open_store_with(path, options).unwrap();
+        // The first vector rewrites vectors.dat through a temp file that can't be created
+        std::fs::create_dir_all(path.join("vectors.dat.tmp/blocked")).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("items/a/name", "a")
+            .put("items/a/embedding", "v:1,0")
+            .put("items/b/embedding", "v:0,2");
+        store.write(batch).unwrap();
+        
+        assert!(events.lock().unwrap().iter().any(|event| event.starts_with("VectorSidecarFailed")));
+        assert_eq!(store.get("items/a/name").unwrap(), Some("a".to_string()));
+        assert!(!sidecar.exists());
+        assert_ranked(&vector_search(&store, vec![0.0, 1.0], Metric::Dot, None), &[("items/b", 2.0), ("items/a", 0.0)]);
+    }
+    
+    // The next open finds no sidecar and rebuilds it from the records
+    std::fs::remove_dir_all(path.join("vectors.dat.tmp")).unwrap();
+    let store = open_store(path).unwrap();
+    assert!(std::fs::read(&sidecar).unwrap().starts_with(b"WALVEC1"));
+    assert_ranked(&vector_search(&store, vec![0.0, 1.0], Metric::Dot, None), &[("items/b", 2.0), ("items/a", 0.0)]);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_write_batch_is_atomic() {
+    let dir = test_dir("write_batch_atomic");
+    
+    {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        store.set("config", "scalar_value", false).unwrap();
+        
+        // A tree violation anywhere rejects the whole batch, including ops queued in it earlier
+        let mut batch = WriteBatch::new();
+        batch.put("settings/theme", "dark")
+            .put("settings", "flat")
+            .put("settings/theme", "light");
+        assert!(matches!(store.write(batch), Err(WalDbError::TreeSemantics { .. })));
+        
+        let mut batch = WriteBatch::new();
+        batch.put("other", "1").put("config/child", "2");
+        assert!(matches!(store.write(batch), Err(WalDbError::TreeSemantics { .. })));
+        assert_eq!(store.get("settings/theme").unwrap(), None);
+        assert_eq!(store.get("other").unwrap(), None);
+        
+        // Deleting the scalar earlier in the batch clears the way
+        let mut batch = WriteBatch::new();
+        batch.delete("config").put("config/child", "2");
+        store.write(batch).unwrap();
+        assert_eq!(store.get("config/child").unwrap(), Some("2".to_string()));
+        
+        store.set("before", "kept", false).unwrap();
+        store.flush().unwrap();
+        
+        let mut batch = WriteBatch::new();
+        for i in 0..100 {
+            batch.put(&format!("torn/{:03}", i), "value");
+        }
+        store.write(batch).unwrap();
+    }
+    
+    if !in_memory() {
+        // Tear the tail of the batch frame as a crash mid-write would
+        let wal = std::path::Path::new(&dir).join("wal.log");
+        let len = std::fs::metadata(&wal).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&wal).unwrap().set_len(len - 10).unwrap();
+        
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        assert_eq!(store.get("before").unwrap(), Some("kept".to_string()));
+        assert!(store.scan_prefix("torn/", usize::MAX).unwrap().is_empty());
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_object_flattening_simulation() {
+    let dir = test_dir("object_flatten");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Simulate what Node.js wrapper would do: flatten a JS object
+    // Original object: { name: "Alice", age: 30, settings: { theme: "dark", notifications: true } }
+    let flattened = vec![
+        ("users/123/name".to_string(), "Alice".to_string()),
+        ("users/123/age".to_string(), "30".to_string()),
+        ("users/123/settings/theme".to_string(), "dark".to_string()),
+        ("users/123/settings/notifications".to_string(), "true".to_string()),
+    ];
+    
+    store.set_many(flattened, Some("users/123")).unwrap();
+    
+    // Should be able to access individual properties (like Firebase)
+    assert_eq!(store.get("users/123/name").unwrap(), Some("Alice".to_string()));
+    assert_eq!(store.get("users/123/age").unwrap(), Some("30".to_string()));
+    assert_eq!(store.get("users/123/settings/theme").unwrap(), Some("dark".to_string()));
+    assert_eq!(store.get("users/123/settings/notifications").unwrap(), Some("true".to_string()));
+    
+    // With new API, getting a parent path returns None (no JSON reconstruction)
+    let subtree_result = store.get("users/123/").unwrap();
+    assert_eq!(subtree_result, None);
+    
+    // Same for path without trailing slash - no JSON reconstruction
+    let object_result = store.get("users/123").unwrap();
+    assert_eq!(object_result, None);
+    
+    // And nested objects also return None
+    let settings_result = store.get("users/123/settings").unwrap();
+    assert_eq!(settings_result, None);
+    
+    cleanup(&dir);
+}
+
+fn test_skip_identical_sets() {
+    let dir = test_dir("skip_identical");
+    let dir_path = std::path::Path::new(&dir);
+    let options = StoreOptions { skip_identical_sets: true, ..Default::default() };
+    let (store, _) = open_store_with(dir_path, options).unwrap();
+    
+    let first = store.set("sync/doc/title", "Hello", false).unwrap();
+    store.set("sync/doc/body", "text", false).unwrap();
+    store.flush().unwrap();
+    let wal_len = || store.wal_stats().map(|stats| stats.bytes + stats.buffered_bytes as u64).unwrap();
+    let (wal_before, segments_before) = (wal_len(), store.segment_counts());
+    
+    // The flushed value is found in its segment, so nothing is written or assigned a seq
+    let latest = first + 1;
+    for _ in 0..100_000 {
+        assert_eq!(store.set("sync/doc/title", "Hello", false).unwrap(), latest);
+    }
+    assert_eq!(store.memtable_len(), 0);
+    assert_eq!(store.segment_counts(), segments_before);
+    assert_eq!(wal_len(), wal_before);
+    
+    // A real change still lands and takes the next seq
+    assert_eq!(store.set("sync/doc/title", "Hello!", false).unwrap(), latest + 1);
+    assert_eq!(store.get("sync/doc/title").unwrap(), Some("Hello!".to_string()));
+    
+    // A deleted value is not a match, and a replace always writes
+    store.delete("sync/doc/body").unwrap();
+    assert_eq!(store.set("sync/doc/body", "text", false).unwrap(), latest + 3);
+    assert_eq!(store.set("sync/doc/body", "text", true).unwrap(), latest + 4);
+    
+    // set_many writes only the changed entries, judging repeats against earlier entries
+    let entries = vec![
+        ("sync/doc/title".to_string(), "Hello!".to_string()),
+        ("sync/doc/tags".to_string(), "a".to_string()),
+        ("sync/doc/body".to_string(), "text".to_string()),
+        ("sync/doc/tags".to_string(), "a".to_string()),
+        ("sync/doc/title".to_string(), "Bye".to_string()),
+        ("sync/doc/title".to_string(), "Hello!".to_string()),
+    ];
+    assert_eq!(store.set_many(entries, None).unwrap(), latest + 7);
+    assert_eq!(store.get("sync/doc/title").unwrap(), Some("Hello!".to_string()));
+    assert_eq!(store.get("sync/doc/tags").unwrap(), Some("a".to_string()));
+    drop(store);
+    
+    if !in_memory() {
+        // set_if_changed works without the option
+        let store = open_store(dir_path).unwrap();
+        assert!(!store.set_if_changed("sync/doc/tags", "a").unwrap());
+        assert!(store.set_if_changed("sync/doc/tags", "b").unwrap());
+        assert!(store.set_if_changed("sync/doc/new", "x").unwrap());
+        assert_eq!(store.set("sync/doc/new", "x", false).unwrap(), latest + 10);
+        assert_eq!(store.get("sync/doc/tags").unwrap(), Some("b".to_string()));
+        
+        drop(store);
+    }
+    
+    cleanup(&dir);
+}
+
+fn test_move_subtree() {
+    let dir = test_dir("move_subtree");
+    let copy = test_dir("move_subtree_copy");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("users/alice/name", "Alice", false).unwrap();
+    store.set("users/alice/profile/city", "Oslo", false).unwrap();
+    store.set("users/alice!", "sibling", false).unwrap();
+    store.flush().unwrap();
+    store.set("users/alice/email", "a@x", false).unwrap();
+    store.set("archive/alice/stale", "old", false).unwrap();
+    
+    // Flushed and unflushed keys move together; the sibling stays and the target is replaced
+    assert_eq!(store.move_subtree("users/alice", "archive/alice").unwrap(), 3);
+    assert_eq!(store.scan_prefix("users/alice/", usize::MAX).unwrap(), vec![]);
+    assert_eq!(store.get("users/alice!").unwrap(), Some("sibling".to_string()));
+    assert_eq!(store.get("archive/alice/profile/city").unwrap(), Some("Oslo".to_string()));
+    assert_eq!(store.get("archive/alice/email").unwrap(), Some("a@x".to_string()));
+    assert_eq!(store.get("archive/alice/stale").unwrap(), None);
+    
+    // A scalar moves as itself; a missing path moves nothing
+    assert_eq!(store.move_subtree("users/alice!", "users/bob").unwrap(), 1);
+    assert_eq!(store.get("users/bob").unwrap(), Some("sibling".to_string()));
+    assert_eq!(store.move_subtree("users/nobody", "users/carol").unwrap(), 0);
+    
+    assert!(matches!(store.move_subtree("archive", "archive/alice/x"), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.move_subtree("archive/alice", "archive"), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.move_subtree("", "x"), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.move_subtree("archive/alice", "users/bob/alice"), Err(WalDbError::TreeSemantics { .. })));
+    
+    // The move is the last WAL frame; cut it short and recovery drops it whole
+    store.set("docs/a/1", "one", false).unwrap();
+    store.set("docs/a/2", "two", false).unwrap();
+    store.flush().unwrap();
+    store.move_subtree("docs/a", "docs/b").unwrap();
+    if !in_memory() {
+        drop(store);
+        crash_copy(&dir, &copy);
+        let wal = std::path::Path::new(&copy).join("wal.log");
+        let len = std::fs::metadata(&wal).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&wal).unwrap().set_len(len - 3).unwrap();
+        {
+            let recovered = open_store(std::path::Path::new(&copy)).unwrap();
+            assert_eq!(recovered.get("docs/a/1").unwrap(), Some("one".to_string()));
+            assert_eq!(recovered.get("docs/a/2").unwrap(), Some("two".to_string()));
+            assert_eq!(recovered.scan_prefix("docs/b/", usize::MAX).unwrap(), vec![]);
+        }
+        
+        // The whole frame replays as the completed move
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        assert_eq!(store.get("docs/a/1").unwrap(), None);
+        assert_eq!(store.get("docs/b/2").unwrap(), Some("two".to_string()));
+        
+        drop(store);
+    }
+    
+    cleanup(&dir);
+    cleanup(&copy);
+}
+
+fn test_copy_subtree() {
+    let dir = test_dir("copy_subtree");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    let get = |store: &Store, key: &str| store.get(key).unwrap();
+    
+    store.set("templates/default/theme", "dark", false).unwrap();
+    store.set("templates/default/limits/users", "5", false).unwrap();
+    store.set("templates/default!", "sibling", false).unwrap();
+    store.flush().unwrap();
+    store.set("templates/default/limits/disk", "10GB", false).unwrap();
+    
+    // Flushed and unflushed keys copy together, the sibling doesn't, and the source stays
+    assert_eq!(store.copy_subtree("templates/default", "tenants/acme", CopyMode::Fail).unwrap(), 3);
+    assert_eq!(get(&store, "tenants/acme/limits/disk"), Some("10GB".to_string()));
+    assert_eq!(get(&store, "tenants/acme!"), None);
+    assert_eq!(store.scan_prefix("templates/default/", usize::MAX).unwrap().len(), 3);
+    
+    // Fail writes nothing if any destination key is taken
+    store.set("tenants/globex/theme", "light", false).unwrap();
+    store.set("tenants/globex/extra", "kept", false).unwrap();
+    assert!(matches!(store.copy_subtree("templates/default", "tenants/globex", CopyMode::Fail),
+        Err(WalDbError::AlreadyExists { path }) if path == "tenants/globex/theme"));
+    assert_eq!(get(&store, "tenants/globex/limits/users"), None);
+    
+    // Skip keeps the taken keys; Replace clears the destination first
+    assert_eq!(store.copy_subtree("templates/default", "tenants/globex", CopyMode::Skip).unwrap(), 2);
+    assert_eq!(get(&store, "tenants/globex/theme"), Some("light".to_string()));
+    assert_eq!(get(&store, "tenants/globex/limits/users"), Some("5".to_string()));
+    assert_eq!(store.copy_subtree("templates/default", "tenants/globex", CopyMode::Replace).unwrap(), 3);
+    assert_eq!(get(&store, "tenants/globex/theme"), Some("dark".to_string()));
+    assert_eq!(get(&store, "tenants/globex/extra"), None);
+    
+    // A scalar copies as itself, and a missing source copies nothing, even with Replace
+    assert_eq!(store.copy_subtree("templates/default!", "tenants/initech", CopyMode::Fail).unwrap(), 1);
+    assert_eq!(get(&store, "tenants/initech"), Some("sibling".to_string()));
+    assert_eq!(store.copy_subtree("templates/missing", "tenants/acme", CopyMode::Replace).unwrap(), 0);
+    assert_eq!(get(&store, "tenants/acme/theme"), Some("dark".to_string()));
+    
+    // Into or over the source, under a scalar, or children onto a scalar kept in place
+    assert!(matches!(store.copy_subtree("templates", "templates/default/copy", CopyMode::Replace), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.copy_subtree("templates/default", "templates", CopyMode::Replace), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.copy_subtree("templates/default", "templates/default/", CopyMode::Skip), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.copy_subtree("", "x", CopyMode::Fail), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.copy_subtree("templates/default", "tenants/initech/copy", CopyMode::Fail), Err(WalDbError::TreeSemantics { .. })));
+    assert!(matches!(store.copy_subtree("templates/default", "tenants/initech", CopyMode::Skip), Err(WalDbError::TreeSemantics { .. })));
+    assert_eq!(store.copy_subtree("templates/default", "tenants/initech", CopyMode::Replace).unwrap(), 3);
+    
+    // More than one chunk, read back after a flush and a reopen
+    let entries = (0..25_000).map(|i| (format!("big/src/{:05}", i), format!("v{}", i))).collect();
+    store.set_many(entries, None).unwrap();
+    assert_eq!(store.copy_subtree("big/src", "big/dst", CopyMode::Fail).unwrap(), 25_000);
+    store.flush().unwrap();
+    let store = reopen(store, std::path::Path::new(&dir), StoreOptions::default());
+    assert_eq!(store.scan_prefix("big/dst/", usize::MAX).unwrap().len(), 25_000);
+    assert_eq!(get(&store, "big/dst/24999"), Some("v24999".to_string()));
+    assert_eq!(get(&store, "tenants/globex/limits/disk"), Some("10GB".to_string()));
+    
+    // Namespaces copy within themselves and report local paths
+    let ns = store.namespace("tenant");
+    ns.set("docs/1", "x", false).unwrap();
+    assert_eq!(ns.copy_subtree("docs", "copies", CopyMode::Fail).unwrap(), 1);
+    assert_eq!(ns.get("copies/1").unwrap(), Some("x".to_string()));
+    assert!(matches!(ns.copy_subtree("docs", "copies", CopyMode::Fail), Err(WalDbError::AlreadyExists { path }) if path == "copies/1"));
+    assert_eq!(get(&store, "copies/1"), None);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_move_subtree_concurrent_readers() {
+    let dir = test_dir("move_subtree_readers");
+    let store = std::sync::Arc::new(open_store(std::path::Path::new(&dir)).unwrap());
+    for i in 0..200 {
+        store.set(&format!("team/alice/{:03}", i), "v", false).unwrap();
+    }
+    
+    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let readers: Vec<_> = (0..4).map(|_| {
+        let (store, done) = (store.clone(), done.clone());
+        std::thread::spawn(move || {
+            let mut reads = 0;
+            while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                // Every read sees the subtree wholly under one name
+                assert_eq!(store.scan_prefix("team/", usize::MAX).unwrap().len(), 200);
+                reads += 1;
+            }
+            reads
+        })
+    }).collect();
+    
+    for round in 0..50 {
+        let (from, to) = if round % 2 == 0 { ("team/alice", "team/bob") } else { ("team/bob", "team/alice") };
+        assert_eq!(store.move_subtree(from, to).unwrap(), 200);
+        if round % 10 == 0 {
+            store.flush().unwrap();
+        }
+    }
+    done.store(true, std::sync::atomic::Ordering::Relaxed);
+    let reads: usize = readers.into_iter().map(|r| r.join().unwrap()).sum();
+    assert!(reads > 0);
+    assert_eq!(store.scan_prefix("team/alice/", usize::MAX).unwrap().len(), 200);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_subscribe_coalesced() {
+    let dir = test_dir("subscribe_coalesced");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    let window = Duration::from_millis(1000);
+    let hot = store.subscribe_coalesced("hot/", window).unwrap();
+    let quiet = store.subscribe_coalesced("quiet/", window).unwrap();
+    
+    // 10k changes to 100 keys land inside one window
+    for round in 0..100 {
+        let entries = (0..100).map(|k| (format!("hot/{:03}", k), format!("{}", round * 100 + k))).collect();
+        store.set_many(entries, None).unwrap();
+    }
+    store.set("other/key", "x", false).unwrap();
+    let batch = hot.recv_timeout(window * 5).unwrap();
+    assert_eq!(batch.changes.len(), 100);
+    assert!(batch.deleted_subtrees.is_empty());
+    assert_eq!(batch.changes["hot/000"], Some("9900".to_string()));
+    assert_eq!(batch.changes["hot/099"], Some("9999".to_string()));
+    assert!(hot.recv_timeout(window + window / 2).is_err());
+    assert!(quiet.try_recv().is_err());
+    
+    // A delete after a set leaves None; a subtree delete swallows the writes before it only
+    store.set("hot/a", "1", false).unwrap();
+    store.delete("hot/a").unwrap();
+    store.set("hot/sub/x", "1", false).unwrap();
+    store.delete_subtree("hot/sub").unwrap();
+    store.set("hot/sub/y", "2", false).unwrap();
+    let batch = hot.recv_timeout(window * 5).unwrap();
+    assert_eq!(batch.changes.get("hot/a"), Some(&None));
+    assert_eq!(batch.deleted_subtrees, vec!["hot/sub/".to_string()]);
+    assert_eq!(batch.changes.get("hot/sub/x"), None);
+    assert_eq!(batch.changes.get("hot/sub/y"), Some(&Some("2".to_string())));
+    
+    // Namespaced keys never reach a subscription on the store itself
+    let all = store.subscribe_coalesced("", Duration::from_millis(50)).unwrap();
+    store.namespace("tenant").set("hot/x", "1", false).unwrap();
+    store.set("plain", "1", false).unwrap();
+    let batch = all.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(batch.changes.keys().collect::<Vec<_>>(), vec!["plain"]);
+    assert!(store.subscribe_coalesced("\0tenant/", window).is_err());
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+// ==================== NAMESPACES ====================
+
+// Written against the trait, so the same code fills a Store or a Namespace
+fn fill_round(keys: &dyn Keyspace, round: usize, tag: &str) {
+    let entries = (0..20).map(|i| (format!("user/{:02}/r{:02}", i, round), tag.to_string())).collect();
+    keys.set_many(entries, None).unwrap();
+    keys.flush().unwrap();
+}
+
+fn test_namespace_isolation() {
+    let dir = test_dir("namespace_isolation");
+    
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    let sessions = store.namespace("sessions");
+    let docs = store.namespace("docs/v2");
+    
+    // 40 flushes are enough for compact() to push data through L1 into L2
+    for round in 0..40 {
+        fill_round(&sessions, round, "s");
+        fill_round(&docs, round, "d");
+        fill_round(&store, round, "plain");
+    }
+    store.compact().unwrap();
+    assert!(store.segment_counts().2 > 0, "Compaction should reach L2");
+    
+    assert_eq!(sessions.get("user/07/r39").unwrap(), Some("s".to_string()));
+    assert_eq!(docs.get("user/07/r39").unwrap(), Some("d".to_string()));
+    assert_eq!(store.get("user/07/r39").unwrap(), Some("plain".to_string()));
+    
+    // Scans and patterns return unprefixed keys and only their own
+    let scanned = sessions.scan_prefix("user/", usize::MAX).unwrap();
+    assert_eq!(scanned.len(), 800);
+    assert!(scanned.iter().all(|(key, value)| key.starts_with("user/") && value == "s"));
+    assert_eq!(docs.get_pattern("user/*/r00").unwrap().len(), 20);
+    assert_eq!(docs.get_range("user/00/", "user/01/").unwrap().len(), 40);
+    assert_eq!(store.scan_prefix("user/", usize::MAX).unwrap().len(), 800);
+    
+    // Deletes stay inside the namespace they were made through
+    sessions.delete_subtree("user/00").unwrap();
+    assert_eq!(docs.delete_pattern("user/01/*").unwrap(), 40);
+    assert_eq!(sessions.get("user/00/r00").unwrap(), None);
+    assert_eq!(docs.get("user/00/r00").unwrap(), Some("d".to_string()));
+    assert_eq!(store.get("user/01/r00").unwrap(), Some("plain".to_string()));
+    assert_eq!(store.delete_pattern("*r39").unwrap(), 20);
+    assert_eq!(sessions.get("user/05/r39").unwrap(), Some("s".to_string()));
+    
+    sessions.set("user/05", "scalar", false).unwrap();
+    assert!(matches!(sessions.set("user/05/x", "y", false), Err(WalDbError::TreeSemantics { path, .. }) if path == "user/05/x"));
+    assert_eq!(sessions.increment("visits", 3).unwrap(), 3);
+    assert_eq!(docs.increment("visits", 1).unwrap(), 1);
+    let json = docs.get_subtree_json("user/02").unwrap().unwrap();
+    assert!(json.starts_with("{\"r00\":\"d\""), "{}", json);
+    
+    let store = reopen(store, std::path::Path::new(&dir), StoreOptions::default());
+    assert_eq!(store.list_namespaces().unwrap(), vec!["docs/v2".to_string(), "sessions".to_string()]);
+    let sessions = store.namespace("sessions");
+    let docs = store.namespace("docs/v2");
+    assert_eq!(sessions.get("user/05").unwrap(), Some("scalar".to_string()));
+    assert_eq!(docs.get("user/05/r10").unwrap(), Some("d".to_string()));
+    assert_eq!(sessions.get("visits").unwrap(), Some("3".to_string()));
+    assert_eq!(docs.get("visits").unwrap(), Some("1".to_string()));
+    assert_eq!(sessions.get("user/00/r05").unwrap(), None);
+    assert_eq!(store.get("user/00/r05").unwrap(), Some("plain".to_string()));
+    
+    // The prefix is out of reach of Store's own writes
+    let reserved = "\u{0}sessions/user/05";
+    assert!(matches!(store.set(reserved, "x", false), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.delete(reserved), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.delete_subtree("\u{0}sessions"), Err(WalDbError::InvalidKey { .. })));
+    let mut batch = WriteBatch::new();
+    batch.put("ok", "1").delete(reserved);
+    assert!(matches!(store.write(batch), Err(WalDbError::InvalidKey { .. })));
+    assert!(matches!(store.set_many(vec![(reserved.to_string(), "x".to_string())], None), Err(WalDbError::InvalidKey { .. })));
+    assert_eq!(sessions.get("user/05").unwrap(), Some("scalar".to_string()));
+    assert_eq!(store.get("ok").unwrap(), None);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_drop_namespace() {
+    let dir = test_dir("drop_namespace");
+    
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    // "a" is a string prefix of "ab"; their stored prefixes must not nest
+    for name in ["a", "ab", "50% off"] {
+        let ns = store.namespace(name);
+        ns.set("item", name, false).unwrap();
+        ns.set_vector("embedding", vec![1.0, 0.0], false).unwrap();
+        ns.flush().unwrap();
+        ns.set("late", name, false).unwrap();
+    }
+    store.set("item", "plain", false).unwrap();
+    assert_eq!(store.list_namespaces().unwrap(), vec!["50% off", "a", "ab"]);
+    
+    store.drop_namespace("a").unwrap();
+    assert_eq!(store.list_namespaces().unwrap(), vec!["50% off", "ab"]);
+    let a = store.namespace("a");
+    assert_eq!(a.get("item").unwrap(), None);
+    assert_eq!(a.get_vector("embedding").unwrap(), None);
+    assert_eq!(store.namespace("ab").get("item").unwrap(), Some("ab".to_string()));
+    assert_eq!(store.namespace("ab").get_vector("embedding").unwrap(), Some(vec![1.0, 0.0]));
+    assert_eq!(store.namespace("50% off").get("late").unwrap(), Some("50% off".to_string()));
+    assert_eq!(store.get("item").unwrap(), Some("plain".to_string()));
+    
+    // A dropped namespace can be written to again
+    a.set("item", "again", false).unwrap();
+    assert_eq!(a.name(), "a");
+    
+    let store = reopen(store, std::path::Path::new(&dir), StoreOptions::default());
+    assert_eq!(store.namespace("a").get("item").unwrap(), Some("again".to_string()));
+    assert_eq!(store.namespace("a").get("late").unwrap(), None);
+    assert_eq!(store.namespace("ab").get("late").unwrap(), Some("ab".to_string()));
+    assert_eq!(store.list_namespaces().unwrap(), vec!["50% off", "a", "ab"]);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+// ==================== TEXT SEARCH ====================
+
+fn text_search(store: &Store, query: &str, fields: &[&str], anchor: Option<TextAnchor>,
+               field_weights: Option<std::collections::HashMap<String, f32>>) -> Vec<SearchGroup> {
+    store.search(SearchOptions {
+        pattern: "items/*".to_string(),
+        filters: None,
+        vector: None,
+        text: Some(TextSearchOptions {
+            query: query.to_string(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            case_sensitive: None,
+            anchor,
+            field_weights,
+        }),
+        scoring: None,
+        limit: None,
+        order_by: None,
+    }).unwrap()
+}
+
+fn test_text_search_unicode_case() {
+    let dir = test_dir("text_unicode");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("items/a/title", "Café Crème", false).unwrap();
+    store.set("items/b/title", "STRASSE", false).unwrap();
+    store.set("items/c/title", "Tea House", false).unwrap();
+    
+    let results = text_search(&store, "CAFÉ", &["title"], None, None);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, "items/a");
+    
+    let results = text_search(&store, "crÈme", &["title"], Some(TextAnchor::Exact), None);
+    assert_eq!(results.len(), 1);
+    
+    // Full case folding: ß matches SS
+    let results = text_search(&store, "straße", &["title"], None, None);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, "items/b");
+    
+    cleanup(&dir);
+}
+
+fn test_text_search_anchors() {
+    let dir = test_dir("text_anchor");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("items/a/title", "category theory", false).unwrap();
+    store.set("items/b/title", "concatenate strings", false).unwrap();
+    store.set("items/c/title", "cat", false).unwrap();
+    
+    let keys = |results: Vec<SearchGroup>| results.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    
+    assert_eq!(keys(text_search(&store, "cat", &["title"], Some(TextAnchor::Contains), None)),
+               vec!["items/a", "items/b", "items/c"]);
+    assert_eq!(keys(text_search(&store, "cat", &["title"], Some(TextAnchor::Prefix), None)),
+               vec!["items/a", "items/c"]);
+    assert_eq!(keys(text_search(&store, "cat", &["title"], Some(TextAnchor::Exact), None)),
+               vec!["items/c"]);
+    
+    cleanup(&dir);
+}
+
+fn test_text_search_field_weights_and_ties() {
+    let dir = test_dir("text_weights");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("items/d/title", "rust", false).unwrap();
+    store.set("items/b/body", "rust", false).unwrap();
+    store.set("items/c/title", "rust", false).unwrap();
+    store.set("items/a/body", "rust", false).unwrap();
+    store.set("items/e/title", "rust", false).unwrap();
+    store.set("items/e/body", "rust", false).unwrap();
+    
+    let mut weights = std::collections::HashMap::new();
+    weights.insert("title".to_string(), 3.0);
+    
+    let results = text_search(&store, "rust", &["title", "body"], None, Some(weights));
+    let ranked: Vec<(String, String)> = results.into_iter()
+        .map(|(k, fields)| (k, fields["_text_score"].clone()))
+        .collect();
+    
+    // Both fields matched scores 1.0; title-only 0.75; body-only 0.25; ties ordered by key
+    assert_eq!(ranked, vec![
+        ("items/e".to_string(), "1".to_string()),
+        ("items/c".to_string(), "0.75".to_string()),
+        ("items/d".to_string(), "0.75".to_string()),
+        ("items/a".to_string(), "0.25".to_string()),
+        ("items/b".to_string(), "0.25".to_string()),
+    ]);
+    
+    cleanup(&dir);
+}
+
+fn test_search_group_order() {
+    let dir = test_dir("search_group_order");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    let search = |pattern: &str, text: Option<TextSearchOptions>, scoring: Option<ScoringWeights>, limit: Option<usize>| {
+        store.search(SearchOptions { pattern: pattern.to_string(), filters: None, vector: None, text, scoring, limit, order_by: None }).unwrap()
+    };
+    
+    for id in ["k", "c", "x", "a", "m", "b"] {
+        store.set(&format!("docs/{}/title", id), "rust tips", false).unwrap();
+        store.set(&format!("docs/{}/body", id), "text", false).unwrap();
+    }
+    store.flush().unwrap();
+    // Newer copies of flushed keys, now in both the memtable and a segment
+    store.set("docs/m/title", "rust tips and tricks", false).unwrap();
+    store.set("docs/b/title", "go tips", false).unwrap();
+    
+    // Unscored results come in group key order, so a limit always keeps the same ones
+    let keys = |groups: &[SearchGroup]| groups.iter().map(|g| g.0.clone()).collect::<Vec<_>>();
+    let all = search("docs/*", None, None, None);
+    assert_eq!(keys(&all), vec!["docs/a", "docs/b", "docs/c", "docs/k", "docs/m", "docs/x"]);
+    assert_eq!(keys(&search("docs/*", None, None, Some(2))), vec!["docs/a", "docs/b"]);
+    
+    // Each field appears once with its newest value, and entries carry full keys in key order
+    let m = &all[4];
+    assert_eq!(search_group_entries(m), vec![
+        ("docs/m/body".to_string(), "text".to_string()),
+        ("docs/m/title".to_string(), "rust tips and tricks".to_string()),
+    ]);
+    
+    // Scored results: best first, equal scores in group key order
+    let text = TextSearchOptions {
+        query: "rust".to_string(),
+        fields: vec!["title".to_string()],
+        case_sensitive: None,
+        anchor: None,
+        field_weights: None,
+    };
+    assert_eq!(keys(&search("docs/*", Some(text.clone()), None, None)), vec!["docs/a", "docs/c", "docs/k", "docs/m", "docs/x"]);
+    let text = TextSearchOptions { query: "rust tricks".to_string(), ..text };
+    let weights = ScoringWeights { vector: 1.0, text: 1.0, filter: 1.0 };
+    assert_eq!(keys(&search("docs/*", Some(text), Some(weights), None)), vec!["docs/m", "docs/a", "docs/c", "docs/k", "docs/x"]);
+    
+    // A key starting with '/' groups under "" and keeps its leading '/' in the entries
+    store.set("/lead/name", "x", false).unwrap();
+    let root = search("*", None, None, None);
+    let lead = root.iter().find(|g| g.0.is_empty()).expect("Group with an empty key");
+    assert_eq!(search_group_entries(lead), vec![("/lead/name".to_string(), "x".to_string())]);
+    
+    cleanup(&dir);
+}
+
+fn test_search_limit_pushdown() {
+    let dir = test_dir("search_limit_pushdown");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    let search = |filters: Option<Vec<SearchFilter>>, limit: Option<usize>, order_by: Option<SearchOrder>| {
+        store.search(SearchOptions { pattern: "users/*".to_string(), filters, vector: None, text: None, scoring: None, limit, order_by }).unwrap()
+    };
+    let pro = || Some(vec![SearchFilter { field: "plan".to_string(), op: FilterOp::Eq, value: "pro".to_string(), value_type: ValueType::String }]);
+    
+    // Every tenth user upgraded in a later segment, and a few changes left in the memtable
+    let user = |i: usize, plan: &str| vec![(format!("users/{:05}/name", i), format!("user {}", i)), (format!("users/{:05}/plan", i), plan.to_string())];
+    store.set_many((0..20_000).flat_map(|i| user(i, "free")).collect(), None).unwrap();
+    store.flush().unwrap();
+    store.set_many((0..20_000).step_by(10).flat_map(|i| user(i, "pro")).collect(), None).unwrap();
+    store.flush().unwrap();
+    store.delete_subtree("users/00010/").unwrap();
+    store.set("users/19990/plan", "free", false).unwrap();
+    store.delete("users/19999/name").unwrap();
+    
+    let all = search(None, None, None);
+    assert_eq!(all.len(), 19_999);
+    let pros = search(pro(), None, None);
+    assert_eq!(pros.len(), 1998);
+    let mut all_desc = search(None, None, Some(SearchOrder::KeyDesc));
+    all_desc.reverse();
+    assert_eq!(all_desc, all);
+    
+    // Unscored with a limit, the scan stops at the limit-th match
+    let visits = |search: &dyn Fn() -> Vec<SearchGroup>| {
+        let before = store.search_stats().groups_visited;
+        let groups = search();
+        (groups, store.search_stats().groups_visited - before)
+    };
+    let (first, visited) = visits(&|| search(None, Some(20), None));
+    assert_eq!((first.as_slice(), visited), (&all[..20], 20));
+    let (first_pros, visited) = visits(&|| search(pro(), Some(20), None));
+    assert_eq!(first_pros, pros[..20]);
+    assert_eq!(first_pros.last().unwrap().0, "users/00200");
+    assert_eq!(visited, 200);
+    
+    // Descending reads from the other end
+    let (latest, visited) = visits(&|| search(None, Some(20), Some(SearchOrder::KeyDesc)));
+    assert_eq!(latest.iter().rev().collect::<Vec<_>>(), all[all.len() - 20..].iter().collect::<Vec<_>>());
+    assert_eq!(latest[0].1.len(), 1);
+    assert_eq!(visited, 20);
+    let (latest_pros, visited) = visits(&|| search(pro(), Some(3), Some(SearchOrder::KeyDesc)));
+    assert_eq!(latest_pros.iter().map(|g| g.0.as_str()).collect::<Vec<_>>(), vec!["users/19980", "users/19970", "users/19960"]);
+    assert!(visited <= 40, "visited {}", visited);
+    
+    // Scoring needs every candidate, so a scored search still reads them all
+    let text = TextSearchOptions { query: "user".to_string(), fields: vec!["name".to_string()], case_sensitive: None, anchor: None, field_weights: None };
+    let (scored, visited) = visits(&|| store.search(SearchOptions {
+        pattern: "users/*".to_string(), filters: None, vector: None, text: Some(text.clone()), scoring: None, limit: Some(20), order_by: None,
+    }).unwrap());
+    assert_eq!((scored.len(), visited), (20, 19_999));
+    
+    // The reverse iterator merges the memtable and segments as the forward one does
+    let mut forward: Vec<(String, String)> = store.range_iter("users/00005", "users/00100").unwrap().collect::<Result<_>>().unwrap();
+    forward.reverse();
+    let reverse: Vec<(String, String)> = store.range_iter_rev("users/00005", "users/00100").unwrap().collect::<Result<_>>().unwrap();
+    assert_eq!(reverse, forward);
+    assert_eq!(reverse.len(), 2 * 94);
+    let tail: Vec<(String, String)> = store.scan_prefix_iter_rev("users/1999").unwrap().take(2).collect::<Result<_>>().unwrap();
+    assert_eq!(tail, vec![
+        ("users/19999/plan".to_string(), "free".to_string()),
+        ("users/19998/plan".to_string(), "free".to_string()),
+    ]);
+    
+    drop(store);
+    cleanup(&dir);
+}
+
+fn test_search_filter_value_types() {
+    let dir = test_dir("search_filter_value_types");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    for (id, v) in [("a", "9"), ("b", "10"), ("c", "9.5"), ("d", "abc")] {
+        store.set(&format!("items/{}/v", id), v, false).unwrap();
+    }
+    store.set("items/e/other", "9", false).unwrap();
+    
+    let search = |op: FilterOp, value: &str, value_type: ValueType| {
+        let filter = SearchFilter { field: "v".to_string(), op, value: value.to_string(), value_type };
+        store.search(SearchOptions {
+            pattern: "items/*".to_string(), filters: Some(vec![filter]),
+            vector: None, text: None, scoring: None, limit: None, order_by: None,
+        })
+    };
+    let matching = |op: FilterOp, value: &str, value_type: ValueType| -> String {
+        search(op, value, value_type).unwrap().iter().map(|g| &g.0["items/".len()..]).collect()
+    };
+    
+    use ValueType::{Auto, Number};
+    let string = ValueType::String;
+    // (op, value, Auto, String, Number); a=9 b=10 c=9.5 d=abc, e has no field and never matches
+    let cases = [
+        (FilterOp::Eq, "9", "a", "a", "a"),
+        (FilterOp::Ne, "9", "bcd", "bcd", "bc"),
+        (FilterOp::Gt, "9", "bcd", "cd", "bc"),
+        (FilterOp::Gte, "9", "abcd", "acd", "abc"),
+        (FilterOp::Lt, "9", "", "b", ""),
+        (FilterOp::Lte, "9", "a", "ab", "a"),
+        (FilterOp::Gt, "9.5", "bd", "d", "b"),
+        (FilterOp::Lt, "9.5", "a", "ab", "a"),
+        (FilterOp::Eq, "10.0", "b", "", "b"),
+        (FilterOp::Lt, "10", "ac", "", "ac"),
+        (FilterOp::In(vec!["9".to_string(), "10.0".to_string()]), "", "ab", "a", "ab"),
+        (FilterOp::Contains, "9", "ac", "ac", "ac"),
+        (FilterOp::Contains, "b", "d", "d", ""),
+        (FilterOp::Contains, ".", "c", "c", "c"),
+    ];
+    for (op, value, auto, as_string, as_number) in cases {
+        assert_eq!(matching(op.clone(), value, Auto), auto, "Auto {:?} {}", op, value);
+        assert_eq!(matching(op.clone(), value, string), as_string, "String {:?} {}", op, value);
+        assert_eq!(matching(op.clone(), value, Number), as_number, "Number {:?} {}", op, value);
+    }
+    
+    // Non-numeric text compares as a string in Auto and String
+    assert_eq!(matching(FilterOp::Lt, "abd", Auto), "abcd");
+    assert_eq!(matching(FilterOp::In(vec!["abc".to_string(), "10".to_string()]), "", Auto), "bd");
+    
+    // A Number filter with a non-numeric value is rejected rather than matching nothing
+    for op in [FilterOp::Gt, FilterOp::In(vec!["9".to_string(), "abc".to_string()])] {
+        match search(op, "abc", Number) {
+            Err(WalDbError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+            other => panic!("expected InvalidInput, got {:?}", other.map(|g| g.len())),
+        }
+    }
+    
+    cleanup(&dir);
+}
+
+// ==================== VECTOR SEARCH ====================
+
+fn vector_search(store: &Store, query: Vec<f32>, metric: Metric, threshold: Option<f32>) -> Vec<(String, f32)> {
+    let results = store.search(SearchOptions {
+        pattern: "items/*".to_string(),
+        filters: None,
+        vector: Some(VectorSearchOptions {
+            query,
+            field: "embedding".to_string(),
+            threshold,
+            metric,
+        }),
+        text: None,
+        scoring: None,
+        limit: None,
+        order_by: None,
+    }).unwrap();
+    
+    let value_field = if metric == Metric::Euclidean { "_vector_distance" } else { "_vector_score" };
+    results.into_iter()
+        .map(|(k, fields)| {
+            let value = fields[value_field].parse::<f32>().unwrap();
+            (k, value)
+        })
+        .collect()
+}
+
+fn assert_ranked(actual: &[(String, f32)], expected: &[(&str, f32)]) {
+    assert_eq!(actual.len(), expected.len(), "got {:?}", actual);
+    for ((key, value), (exp_key, exp_value)) in actual.iter().zip(expected) {
+        assert_eq!(key, exp_key, "got {:?}", actual);
+        assert!((value - exp_value).abs() < 1e-4, "{}: {} != {}", key, value, exp_value);
+    }
+}
+
+fn test_vector_metrics() {
+    let dir = test_dir("vector_metrics");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set_vector("items/a/embedding", vec![1.0, 0.0], false).unwrap();
+    store.set_vector("items/b/embedding", vec![0.0, 2.0], false).unwrap();
+    store.set_vector("items/c/embedding", vec![3.0, 4.0], false).unwrap();
+    
+    let query = vec![1.0, 1.0];
+    let sqrt2 = 2f32.sqrt();
+    
+    // Cosine: a and b tie at 1/√2 and are ordered by key
+    assert_ranked(&vector_search(&store, query.clone(), Metric::Cosine, None),
+                  &[("items/c", 7.0 / (5.0 * sqrt2)), ("items/a", 1.0 / sqrt2), ("items/b", 1.0 / sqrt2)]);
+    
+    assert_ranked(&vector_search(&store, query.clone(), Metric::Dot, None),
+                  &[("items/c", 7.0), ("items/b", 2.0), ("items/a", 1.0)]);
+    
+    // Euclidean is lower-is-better, for both ordering and threshold
+    assert_ranked(&vector_search(&store, query.clone(), Metric::Euclidean, None),
+                  &[("items/a", 1.0), ("items/b", sqrt2), ("items/c", 13f32.sqrt())]);
+    assert_ranked(&vector_search(&store, query.clone(), Metric::Euclidean, Some(1.5)),
+                  &[("items/a", 1.0), ("items/b", sqrt2)]);
+    
+    assert_ranked(&vector_search(&store, query, Metric::Dot, Some(2.0)),
+                  &[("items/c", 7.0), ("items/b", 2.0)]);
+    
+    cleanup(&dir);
+}
+
+fn test_vector_normalize_on_write() {
+    let dir = test_dir("vector_normalize");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set_vector("items/a/embedding", vec![3.0, 4.0], true).unwrap();
+    store.set_vector("items/b/embedding", vec![0.0, 0.0], true).unwrap();
+    
+    assert_eq!(store.get_vector("items/a/embedding").unwrap(), Some(vec![0.6, 0.8]));
+    assert_eq!(store.get_vector("items/b/embedding").unwrap(), Some(vec![0.0, 0.0]));
+    
+    // Cosine is unchanged by normalization, dot now sees the unit vector
+    assert_ranked(&vector_search(&store, vec![1.0, 1.0], Metric::Cosine, None),
+                  &[("items/a", 7.0 / (5.0 * 2f32.sqrt())), ("items/b", 0.0)]);
+    assert_ranked(&vector_search(&store, vec![1.0, 1.0], Metric::Dot, None),
+                  &[("items/a", 1.4), ("items/b", 0.0)]);
+    
+    cleanup(&dir);
+}
+
+fn test_vector_metrics_long_vectors() {
+    let dir = test_dir("vector_long");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
-    store.set("parent", "value", false).unwrap();
-    let result = store.set("parent/child", "fails", false);
+    // 11 dims exercises both the full lanes and the remainder
+    store.set_vector("items/a/embedding", vec![1.0; 11], false).unwrap();
+    let query: Vec<f32> = (1..=11).map(|i| i as f32).collect();
     
-    assert!(result.is_err());
+    assert_ranked(&vector_search(&store, query.clone(), Metric::Dot, None), &[("items/a", 66.0)]);
+    // Σ(i-1)² for i in 1..=11 = 385
+    assert_ranked(&vector_search(&store, query.clone(), Metric::Euclidean, None), &[("items/a", 385f32.sqrt())]);
+    // |query|² = 506
+    assert_ranked(&vector_search(&store, query, Metric::Cosine, None),
+                  &[("items/a", 66.0 / (11f32.sqrt() * 506f32.sqrt()))]);
     
     cleanup(&dir);
 }
 
-// ==================== ADVANCED FEATURES ====================
-
-fn test_compaction() {
-    let dir = test_dir("compaction");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+fn test_vector_sidecar_rebuild_on_corruption() {
+    let dir = test_dir("vector_sidecar_corrupt");
+    let sidecar = std::path::Path::new(&dir).join("vectors.dat");
     
-    // Create multiple L0 segments
-    for batch in 0..5 {
-        for i in 0..50 {
-            store.set(&format!("batch{}/key{:03}", batch, i), "value", false).unwrap();
-        }
+    {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        store.set_vector("items/a/embedding", vec![1.0, 0.0], false).unwrap();
+        store.set_vector("items/b/embedding", vec![0.0, 2.0], false).unwrap();
         store.flush().unwrap();
     }
     
-    // Check initial state
-    let (l0_before, l1_before, _) = store.segment_counts();
-    assert_eq!(l0_before, 5, "Should have 5 L0 segments");
-    assert_eq!(l1_before, 0, "Should have 0 L1 segments");
-    
-    // Wait for compaction
-    thread::sleep(Duration::from_secs(6));
-    
-    // Check after compaction
-    let (l0_after, l1_after, _) = store.segment_counts();
-    assert!(l0_after < l0_before, "L0 segments should decrease");
-    assert!(l1_after > l1_before, "L1 segments should increase");
-    
-    // Verify data integrity
-    assert_eq!(store.get("batch0/key000").unwrap(), Some("value".to_string()));
-    assert_eq!(store.get("batch4/key049").unwrap(), Some("value".to_string()));
+    // Flip bytes inside the last row so its checksum no longer matches
+    let mut bytes = std::fs::read(&sidecar).unwrap();
+    let len = bytes.len();
+    bytes[len - 6] ^= 0xff;
+    std::fs::write(&sidecar, &bytes).unwrap();
     
-    // Test tombstone handling
-    store.set("test_key", "value1", false).unwrap();
-    store.flush().unwrap();
-    store.delete("test_key").unwrap();
-    store.flush().unwrap();
+    {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        assert_eq!(store.get_vector("items/b/embedding").unwrap(), Some(vec![0.0, 2.0]));
+        assert_ranked(&vector_search(&store, vec![0.0, 1.0], Metric::Dot, None),
+                      &[("items/b", 2.0), ("items/a", 0.0)]);
+    }
     
-    // Wait for potential compaction
-    thread::sleep(Duration::from_secs(6));
+    // Garbage header and a missing file are rebuilt the same way
+    std::fs::write(&sidecar, b"garbage").unwrap();
+    {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        assert_eq!(store.get_vector("items/a/embedding").unwrap(), Some(vec![1.0, 0.0]));
+    }
+    assert!(std::fs::read(&sidecar).unwrap().starts_with(b"WALVEC1"));
     
-    // Tombstone should still be effective
-    assert_eq!(store.get("test_key").unwrap(), None);
+    std::fs::remove_file(&sidecar).unwrap();
+    {
+        let store = open_store(std::path::Path::new(&dir)).unwrap();
+        assert_eq!(vector_search(&store, vec![1.0, 0.0], Metric::Dot, None).len(), 2);
+    }
+    assert!(sidecar.exists());
     
     cleanup(&dir);
 }
 
-fn test_group_commit_behavior() {
-    let dir = test_dir("group_commit");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+fn test_vector_sidecar_consistency_after_deletes() {
+    let dir = test_dir("vector_sidecar_deletes");
+    
+    let check = |store: &Store| {
+        assert_eq!(store.get_vector("items/a/embedding").unwrap(), None);
+        assert_eq!(store.get_vector("items/b/embedding").unwrap(), None);
+        assert_eq!(store.get_vector("items/c/embedding").unwrap(), None);
+        assert_eq!(store.get_vector("items/e/embedding").unwrap(), Some(vec![1.0, 1.0, 1.0]));
+        
+        let keys: Vec<String> = vector_search(store, vec![1.0, 1.0], Metric::Dot, None)
+            .into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["items/d".to_string()]);
+        
+        // Vectors wider than the sidecar's rows are still searchable from the primary records
+        assert_ranked(&vector_search(store, vec![1.0, 0.0, 0.0], Metric::Dot, None), &[("items/e", 1.0)]);
+    };
     
-    // Rapid writes should be batched
-    let start = Instant::now();
-    for i in 0..100 {
-        store.set(&format!("batch/{}", i), "value", false).unwrap();
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    for key in ["a", "b", "c", "d"] {
+        store.set_vector(&format!("items/{}/embedding", key), vec![1.0, 2.0], false).unwrap();
     }
-    let elapsed = start.elapsed();
+    store.set_vector("items/e/embedding", vec![1.0, 1.0, 1.0], false).unwrap();
     
-    // Should complete quickly due to batching
-    assert!(elapsed.as_millis() < 200, "Group commit not working");
+    store.delete("items/a/embedding").unwrap();
+    store.delete_subtree("items/b").unwrap();
+    store.set("items/c/embedding", "s:not a vector", false).unwrap();
+    
+    check(&store);
+    
+    // Replay and the persisted sidecar agree after reopening
+    let store = reopen(store, std::path::Path::new(&dir), StoreOptions::default());
+    check(&store);
     
     cleanup(&dir);
 }
 
-fn test_range_queries() {
-    let dir = test_dir("range_queries");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+fn test_vector_sidecar_compacts_dead_rows() {
+    let dir = test_dir("vector_sidecar_compact");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
     
-    // Insert test data
-    for i in 0..20 {
-        store.set(&format!("key{:02}", i), &format!("value{}", i), false).unwrap();
+    for i in 0..5000 {
+        store.set_vector("items/a/embedding", vec![i as f32, 1.0], false).unwrap();
     }
-    store.flush().unwrap();
-    
-    // Test basic range
-    let results = store.get_range("key05", "key15").unwrap();
-    assert_eq!(results.len(), 10);
-    assert_eq!(results[0].0, "key05");
-    assert_eq!(results[9].0, "key14");
-    
-    // Test range with limit
-    let limited = store.get_range_limit("key00", "key20", 5).unwrap();
-    assert_eq!(limited.len(), 5);
     
-    // Test prefix scan
-    store.set("users/alice/age", "30", false).unwrap();
-    store.set("users/alice/email", "alice@example.com", false).unwrap();
-    store.set("users/bob/age", "25", false).unwrap();
-    let alice_data = store.scan_prefix("users/alice/", 10).unwrap();
-    assert_eq!(alice_data.len(), 2);
+    // One live row plus at most the tolerated dead rows, not 5000 appended rows
+    let size = std::fs::metadata(std::path::Path::new(&dir).join("vectors.dat")).unwrap().len();
+    assert!(size < 2100 * 50, "sidecar not compacted: {} bytes", size);
+    assert_eq!(store.get_vector("items/a/embedding").unwrap(), Some(vec![4999.0, 1.0]));
     
-    // Test with deletes
-    store.delete("key10").unwrap();
-    let range_with_delete = store.get_range("key09", "key12").unwrap();
-    assert!(!range_with_delete.iter().any(|(k, _)| k == "key10"));
+    drop(store);
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    assert_eq!(store.get_vector("items/a/embedding").unwrap(), Some(vec![4999.0, 1.0]));
     
     cleanup(&dir);
 }
 
-fn test_tombstone_behavior() {
-    let dir = test_dir("tombstone");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+// ==================== RAW RANGE ====================
+
+fn test_get_range_raw_reports_tombstones() {
+    let dir = test_dir("range_raw");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    store.set("users/alice", "a", false).unwrap();
+    store.set("users/bob", "b", false).unwrap();
+    store.set("users/carol/name", "c", false).unwrap();
+    store.set("users/carol/age", "n:30", false).unwrap();
+    store.flush().unwrap();
     
-    // Set initial value
-    store.set("tomb/key", "value", false).unwrap();
-    assert_eq!(store.get("tomb/key").unwrap(), Some("value".to_string()));
+    store.delete("users/bob").unwrap();
+    store.delete_subtree("users/carol").unwrap();
+    store.flush().unwrap();
     
-    // Delete it
-    store.delete("tomb/key").unwrap();
-    assert_eq!(store.get("tomb/key").unwrap(), None);
+    let live = store.get_range("users/", "users/~").unwrap();
+    assert_eq!(live, vec![("users/alice".to_string(), "a".to_string())]);
     
-    // Flush to disk
-    store.flush().unwrap();
+    let raw = store.get_range_raw("users/", "users/~").unwrap();
+    let states: Vec<(&str, &RawState)> = raw.iter().map(|e| (e.key.as_str(), &e.state)).collect();
+    assert_eq!(states, vec![
+        ("users/alice", &RawState::Live("a".to_string())),
+        ("users/bob", &RawState::PointDeleted),
+        ("users/carol/", &RawState::SubtreeDeleted("users/carol/".to_string())),
+    ]);
     
-    // Should still be deleted after flush
-    assert_eq!(store.get("tomb/key").unwrap(), None);
+    // Tombstones are newer than the values they shadow
+    assert!(raw[1].seq > raw[0].seq);
     
-    // Set new value (this should override the tombstone)
-    store.set("tomb/key", "new_value", false).unwrap();
+    // A subtree tombstone is reported for any overlapping range, even one inside it
+    let inside = store.get_range_raw("users/carol/b", "users/carol/z").unwrap();
+    assert_eq!(inside.len(), 1);
+    assert_eq!(inside[0].state, RawState::SubtreeDeleted("users/carol/".to_string()));
     
-    // Should immediately see the new value
-    let result = store.get("tomb/key").unwrap();
-    assert_eq!(result, Some("new_value".to_string()), "Failed to set new value after tombstone");
+    // A later write under the subtree wins over the tombstone
+    store.set("users/carol/name", "c2", false).unwrap();
+    let raw = store.get_range_raw("users/carol/", "users/carol/~").unwrap();
+    assert_eq!(raw.len(), 2);
+    assert!(raw.iter().any(|e| e.state == RawState::Live("c2".to_string())));
     
     cleanup(&dir);
 }
 
-// ==================== BATCH OPERATIONS ====================
+fn test_apply_replicated() {
+    let primary_dir = test_dir("replicated_primary");
+    let replica_dir = test_dir("replicated_replica");
+    let replica_options = StoreOptions { replica: true, ..Default::default() };
+    let primary = open_store(std::path::Path::new(&primary_dir)).unwrap();
+    let (replica, _) = open_store_with(std::path::Path::new(&replica_dir), replica_options.clone()).unwrap();
+    
+    // Some of it flushed, so changes come from segments as well as the memtable
+    let users = (0..50).map(|i| (format!("users/{:02}/name", i), format!("user{}", i))).collect();
+    primary.set_many(users, None).unwrap();
+    primary.set("config/mode", "fast", false).unwrap();
+    primary.flush().unwrap();
+    primary.delete("users/03/name").unwrap();
+    primary.delete_subtree("users/10").unwrap();
+    primary.delete_subtree("users/12").unwrap();
+    primary.set("users/12/name", "back", false).unwrap();
+    primary.set("config/mode", "safe", false).unwrap();
+    
+    let same = |primary: &Store, replica: &Store| {
+        assert_eq!(replica.get_range_raw("", "\u{10FFFF}").unwrap(), primary.get_range_raw("", "\u{10FFFF}").unwrap());
+        assert_eq!(replica.scan_prefix("", usize::MAX).unwrap(), primary.scan_prefix("", usize::MAX).unwrap());
+        assert_eq!(replica.get("users/12/name").unwrap(), primary.get("users/12/name").unwrap());
+        assert_eq!(replica.seq().unwrap(), primary.seq().unwrap());
+    };
+    
+    let (_, changes) = primary.changes_since(0).unwrap();
+    assert_eq!(replica.apply_replicated(changes.clone()).unwrap(), changes.len());
+    same(&primary, &replica);
+    assert_eq!(replica.get("users/03/name").unwrap(), None);
+    assert_eq!(replica.get("users/10/name").unwrap(), None);
+    
+    // Local writes would take the primary's seqs, so only the primary's changes go in
+    assert!(matches!(replica.set("local", "x", false), Err(WalDbError::Replica)));
+    assert!(matches!(replica.delete_subtree("users"), Err(WalDbError::Replica)));
+    
+    // A reconnect that resends what already landed applies only what is new
+    let cursor = replica.seq().unwrap();
+    primary.set("users/40/name", "renamed", false).unwrap();
+    primary.delete_subtree("config").unwrap();
+    assert_eq!(replica.apply_replicated(changes.clone()).unwrap(), 0);
+    let (_, resent) = primary.changes_since(cursor - 5).unwrap();
+    let (_, fresh) = primary.changes_since(cursor).unwrap();
+    assert_eq!(fresh.len(), 2);
+    assert_eq!(replica.apply_replicated(resent).unwrap(), 2);
+    same(&primary, &replica);
+    
+    // Out of order is refused before anything is applied
+    primary.set("late/a", "1", false).unwrap();
+    primary.set("late/b", "2", false).unwrap();
+    let (_, mut late) = primary.changes_since(replica.seq().unwrap()).unwrap();
+    late.reverse();
+    assert!(replica.apply_replicated(late).is_err());
+    assert_eq!(replica.get("late/a").unwrap(), None);
+    
+    // What the replica applied survives a flush and a reopen under the same seqs
+    let (_, late) = primary.changes_since(replica.seq().unwrap()).unwrap();
+    replica.apply_replicated(late).unwrap();
+    replica.flush().unwrap();
+    let replica = reopen(replica, std::path::Path::new(&replica_dir), replica_options);
+    same(&primary, &replica);
+    
+    drop(replica);
+    drop(primary);
+    cleanup(&primary_dir);
+    cleanup(&replica_dir);
+}
 
-fn test_set_many_basic() {
-    let dir = test_dir("set_many_basic");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
-    
-    // Set multiple key-value pairs atomically
-    let entries = vec![
-        ("users/alice/name".to_string(), "Alice".to_string()),
-        ("users/alice/age".to_string(), "30".to_string()),
-        ("users/bob/name".to_string(), "Bob".to_string()),
-        ("users/bob/age".to_string(), "25".to_string()),
-    ];
-    
-    store.set_many(entries, None).unwrap();
+fn test_as_of() {
+    let dir = test_dir("as_of");
+    let path = std::path::Path::new(&dir);
     
-    // Verify all entries were stored
-    assert_eq!(store.get("users/alice/name").unwrap(), Some("Alice".to_string()));
-    assert_eq!(store.get("users/alice/age").unwrap(), Some("30".to_string()));
-    assert_eq!(store.get("users/bob/name").unwrap(), Some("Bob".to_string()));
-    assert_eq!(store.get("users/bob/age").unwrap(), Some("25".to_string()));
+    {
+        let store = open_store(path).unwrap();
+        let good = store.set("config/mode", "good", false).unwrap();
+        let bad = store.set("config/mode", "bad", false).unwrap();
+        store.set("config/extra", "later", false).unwrap();
+        let deleted = store.delete("config/mode").unwrap();
+        store.delete_subtree("config").unwrap();
+        
+        // The memtable has only the newest records, so the older ones come from the WAL
+        let snapshot = store.as_of(good).unwrap();
+        assert_eq!(snapshot.seq(), good);
+        assert_eq!(snapshot.get("config/mode").unwrap(), Some("good".to_string()));
+        assert_eq!(snapshot.get("config/extra").unwrap(), None);
+        assert_eq!(snapshot.get_range("config/", "config0").unwrap(), vec![("config/mode".to_string(), "good".to_string())]);
+        assert_eq!(store.as_of(bad).unwrap().get("config/mode").unwrap(), Some("bad".to_string()));
+        assert_eq!(store.as_of(deleted - 1).unwrap().get("config/extra").unwrap(), Some("later".to_string()));
+        assert_eq!(store.as_of(deleted).unwrap().get("config/mode").unwrap(), None);
+        assert_eq!(store.as_of(deleted + 1).unwrap().get("config/extra").unwrap(), None);
+        assert_eq!(store.get("config/mode").unwrap(), None);
+        
+        // Later writes and flushes leave an open snapshot as it was
+        store.set("config/mode", "restored", false).unwrap();
+        store.flush().unwrap();
+        assert_eq!(snapshot.get("config/mode").unwrap(), Some("good".to_string()));
+        
+        match store.as_of(deleted + 100) {
+            Err(WalDbError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+            other => panic!("expected InvalidInput, got {:?}", other.map(|_| ())),
+        }
+        
+        // That flush left out the overwritten versions, so those seqs are gone
+        assert!(store.history_floor() >= deleted);
+        assert!(matches!(store.as_of(good), Err(WalDbError::HistoryUnavailable { .. })));
+    }
+    cleanup(&dir);
     
+    // Versions in separate segments stay readable, after a reopen too
+    let dir = test_dir("as_of_segments");
+    let path = std::path::Path::new(&dir);
+    let store = open_store(path).unwrap();
+    let first = store.set("doc", "v1", false).unwrap();
+    store.flush().unwrap();
+    let second = store.set("doc", "v2", false).unwrap();
+    store.flush().unwrap();
+    store.delete("doc").unwrap();
+    let store = reopen(store, path, StoreOptions::default());
+    assert_eq!(store.history_floor(), 0);
+    assert_eq!(store.as_of(first).unwrap().get("doc").unwrap(), Some("v1".to_string()));
+    assert_eq!(store.as_of(second).unwrap().get("doc").unwrap(), Some("v2".to_string()));
+    assert_eq!(store.get("doc").unwrap(), None);
+    
+    drop(store);
     cleanup(&dir);
 }
 
-fn test_set_many_with_subtree_replacement() {
-    let dir = test_dir("set_many_replace");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
-    
-    // Set initial data
-    store.set("users/alice/name", "Alice", false).unwrap();
-    store.set("users/alice/age", "30", false).unwrap();
-    store.set("users/alice/job", "Engineer", false).unwrap();
-    
-    // Replace entire alice subtree with new data
-    let new_alice = vec![
-        ("users/alice/name".to_string(), "Alice Smith".to_string()),
-        ("users/alice/email".to_string(), "alice@example.com".to_string()),
-    ];
-    
-    store.set_many(new_alice, Some("users/alice")).unwrap();
-    
-    // Verify replacement happened
-    assert_eq!(store.get("users/alice/name").unwrap(), Some("Alice Smith".to_string()));
-    assert_eq!(store.get("users/alice/email").unwrap(), Some("alice@example.com".to_string()));
-    assert_eq!(store.get("users/alice/age").unwrap(), None); // Should be gone
-    assert_eq!(store.get("users/alice/job").unwrap(), None); // Should be gone
+fn test_prefix_write_stats() {
+    let dir = test_dir("prefix_write_stats");
+    let options = StoreOptions {
+        accounting_roots: vec!["tenants/*".to_string(), "orgs/*/projects/*".to_string(), "logs".to_string()],
+        ..Default::default()
+    };
+    let store = open_store_with(std::path::Path::new(&dir), options).unwrap().0;
+    
+    // Nested keys count toward the bucket their root names, whatever their depth
+    store.set("tenants/acme/users/alice/name", "Alice", false).unwrap();
+    store.set("tenants/acme/users/bob", "Bob", false).unwrap();
+    store.set("tenants/acme", "x", true).unwrap();
+    store.increment("tenants/globex/hits", 5).unwrap();
+    store.delete("tenants/globex/hits").unwrap();
+    store.set("orgs/o1/projects/p1/tasks/1", "t", false).unwrap();
+    store.set("orgs/o1/settings", "s", false).unwrap();  // Too shallow for its root
+    store.set("logs/2024/01", "l", false).unwrap();
+    store.set("tenantsx/acme", "no", false).unwrap();
+    store.set("elsewhere", "no", false).unwrap();
+    store.set_many(vec![
+        ("tenants/initech/a".to_string(), "1".to_string()),
+        ("tenants/initech/b".to_string(), "2".to_string()),
+    ], None).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put("tenants/initech/c", "3").delete("logs/2024/01");
+    store.write(batch).unwrap();
+    
+    let stats = store.prefix_write_stats();
+    assert_eq!(stats.keys().collect::<Vec<_>>(), vec!["logs", "orgs/o1/projects/p1", "tenants/acme", "tenants/globex", "tenants/initech"]);
+    assert_eq!(stats["tenants/acme"].ops, 3);
+    assert_eq!(stats["tenants/acme"].bytes, ("tenants/acme/users/alice/name".len() + 5 + "tenants/acme/users/bob".len() + 3 + "tenants/acme".len() + 1) as u64);
+    assert_eq!(stats["tenants/acme"].window_ops, 0);
+    assert_eq!(stats["tenants/globex"], PrefixWriteStats { ops: 2, bytes: ("tenants/globex/hits".len() * 2 + 1) as u64, window_ops: 0 });
+    assert_eq!(stats["tenants/initech"].ops, 3);
+    assert_eq!(stats["orgs/o1/projects/p1"].ops, 1);
+    assert_eq!(stats["logs"].ops, 2);
+    
+    store.reset_prefix_write_stats();
+    assert!(store.prefix_write_stats().is_empty());
+    store.set("tenants/acme/users/carol", "Carol", false).unwrap();
+    assert_eq!(store.prefix_write_stats().into_iter().map(|(bucket, stats)| (bucket, stats.ops)).collect::<Vec<_>>(),
+        vec![("tenants/acme".to_string(), 1)]);
+    drop(store);
+    cleanup(&dir);
     
+    // Bad roots, and a limit with nothing to count in, are refused at open
+    for (roots, limited) in [(vec!["tenants//x"], false), (vec![""], false), (vec![], true)] {
+        let options = StoreOptions {
+            accounting_roots: roots.into_iter().map(String::from).collect(),
+            write_rate_limit: limited.then(|| WriteRateLimit { ops_per_sec: 1, window: Duration::from_secs(1), on_limit_exceeded: Arc::new(|_, _| {}) }),
+            ..Default::default()
+        };
+        match open_store_with(std::path::Path::new(&dir), options) {
+            Err(WalDbError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+            other => panic!("expected InvalidInput, got {:?}", other.map(|_| ())),
+        }
+    }
     cleanup(&dir);
 }
 
-fn test_set_many_empty() {
-    let dir = test_dir("set_many_empty");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+fn test_write_rate_limit_window() {
+    let dir = test_dir("write_rate_limit");
+    let alerts = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = alerts.clone();
+    let options = StoreOptions {
+        accounting_roots: vec!["tenants/*".to_string()],
+        // At most 10 writes per 400ms window
+        write_rate_limit: Some(WriteRateLimit {
+            ops_per_sec: 25,
+            window: Duration::from_millis(400),
+            on_limit_exceeded: Arc::new(move |bucket, stats| seen.lock().unwrap().push((bucket.to_string(), stats))),
+        }),
+        ..Default::default()
+    };
+    let store = open_store_with(std::path::Path::new(&dir), options).unwrap().0;
+    
+    for i in 0..30 {
+        store.set(&format!("tenants/noisy/{}", i), "v", false).unwrap();
+    }
+    for i in 0..5 {
+        store.set(&format!("tenants/quiet/{}", i), "v", false).unwrap();
+    }
     
-    // Empty batch should succeed and do nothing
-    store.set_many(vec![], None).unwrap();
-    store.set_many(vec![], Some("some/path")).unwrap();
+    // Only the bucket over the limit is reported, and only once per tenth of the window
+    {
+        let alerts = alerts.lock().unwrap();
+        assert!(!alerts.is_empty() && alerts.len() <= 3, "{:?}", alerts);
+        assert!(alerts.iter().all(|(bucket, stats)| bucket == "tenants/noisy" && stats.window_ops > 10));
+    }
+    let stats = store.prefix_write_stats();
+    assert_eq!(stats["tenants/noisy"].window_ops, 30);
+    assert_eq!(stats["tenants/quiet"].window_ops, 5);
+    
+    // A reset leaves the window alone; once the window slides past the burst it is forgotten
+    store.reset_prefix_write_stats();
+    assert_eq!(store.prefix_write_stats()["tenants/noisy"], PrefixWriteStats { ops: 0, bytes: 0, window_ops: 30 });
+    thread::sleep(Duration::from_millis(500));
+    assert!(store.prefix_write_stats().is_empty());
+    let reported = alerts.lock().unwrap().len();
+    for i in 0..5 {
+        store.set(&format!("tenants/noisy/{}", i), "w", false).unwrap();
+    }
+    assert_eq!(alerts.lock().unwrap().len(), reported);
+    assert_eq!(store.prefix_write_stats()["tenants/noisy"].window_ops, 5);
     
+    drop(store);
     cleanup(&dir);
 }
 
-fn test_set_many_parent_scalar_violation() {
-    let dir = test_dir("set_many_violation");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+fn test_shared_value_reads() {
+    let dir = test_dir("shared_value_reads");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    // Either side of the inline length, and a multibyte value ending on it
+    let long = "x".repeat(64);
+    let values = [("a/short", "8 bytes!"), ("a/edge", "twenty-two bytes long!"), ("a/over", "twenty-three bytes long"), ("a/long", long.as_str()), ("a/utf8", "ééééééééééé"), ("a/empty", "")];
+    for (key, value) in values {
+        store.set(key, value, false).unwrap();
+    }
+    store.set("b/gone", "x", false).unwrap();
+    store.delete("b/gone").unwrap();
     
-    // Set a scalar value
-    store.set("config", "scalar_value", false).unwrap();
+    let check = |store: &Store| {
+        for (key, value) in values {
+            assert_eq!(store.get_ref(key).unwrap().as_deref(), Some(value), "{}", key);
+            assert_eq!(store.get(key).unwrap().as_deref(), Some(value), "{}", key);
+        }
+        assert_eq!(store.get_ref("b/gone").unwrap(), None);
+        assert_eq!(store.get_ref("a/missing").unwrap(), None);
+        
+        let owned = store.scan_prefix("a/", usize::MAX).unwrap();
+        let shared = store.scan_prefix_ref("a/", usize::MAX).unwrap();
+        assert_eq!(owned.len(), values.len());
+        assert!(owned.iter().zip(&shared).all(|((k1, v1), (k2, v2))| k1 == k2 && v1.as_str() == &**v2));
+        assert_eq!(store.get_range_ref("a/", "b/~", 2).unwrap(), vec![
+            ("a/edge".to_string(), Arc::from("twenty-two bytes long!")),
+            ("a/empty".to_string(), Arc::from("")),
+        ]);
+    };
+    
+    // A long memtable value is handed out shared rather than copied
+    check(&store);
+    let (first, second) = (store.get_ref("a/long").unwrap().unwrap(), store.get_ref("a/long").unwrap().unwrap());
+    assert!(Arc::ptr_eq(&first, &second));
     
-    // Try to set children under the scalar - should fail
-    let entries = vec![
-        ("config/child1".to_string(), "value1".to_string()),
-        ("config/child2".to_string(), "value2".to_string()),
-    ];
+    store.flush().unwrap();
+    check(&store);
     
-    let result = store.set_many(entries, None);
-    assert!(result.is_err());
-    assert_eq!(result.unwrap_err().to_string(), "Cannot write under scalar parent");
+    // The WAL replays what it was given from the borrowed entries
+    store.set("a/long", "after the flush and long enough to share", false).unwrap();
+    let store = reopen(store, std::path::Path::new(&dir), StoreOptions::default());
+    assert_eq!(store.get_ref("a/long").unwrap().as_deref(), Some("after the flush and long enough to share"));
+    assert_eq!(store.get("a/short").unwrap().as_deref(), Some("8 bytes!"));
     
+    drop(store);
     cleanup(&dir);
 }
 
-fn test_object_flattening_simulation() {
-    let dir = test_dir("object_flatten");
-    let store = Store::open(std::path::Path::new(&dir)).unwrap();
+// ==================== SEGMENT FORMAT ====================
+
+fn segment_bytes(dir: &str) -> u64 {
+    std::fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension() == Some("seg".as_ref()))
+        .map(|p| std::fs::metadata(p).unwrap().len())
+        .sum()
+}
+
+fn test_front_coded_segment_size() {
+    let dir = test_dir("front_coding");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    
+    let fields = ["title", "status", "assignee", "priority", "due", "estimate", "created", "updated"];
+    let mut entries = Vec::new();
+    for task in 0..1000 {
+        let uuid = format!("{:08x}-4f2a-4c1e-9b7d-{:012x}", task * 2654435761u64 % 0xffffffff, task);
+        for field in fields {
+            let key = format!("tenants/acme-corporation/projects/website-redesign/tasks/{}/fields/{}", uuid, field);
+            entries.push((key, format!("v{}", task)));
+        }
+    }
     
-    // Simulate what Node.js wrapper would do: flatten a JS object
-    // Original object: { name: "Alice", age: 30, settings: { theme: "dark", notifications: true } }
-    let flattened = vec![
-        ("users/123/name".to_string(), "Alice".to_string()),
-        ("users/123/age".to_string(), "30".to_string()),
-        ("users/123/settings/theme".to_string(), "dark".to_string()),
-        ("users/123/settings/notifications".to_string(), "true".to_string()),
-    ];
+    // What the same records cost with a full key in each: 17 header bytes plus key and value
+    let full_key_bytes: usize = entries.iter().map(|(k, v)| 17 + k.len() + v.len()).sum();
     
-    store.set_many(flattened, Some("users/123")).unwrap();
+    store.set_many(entries.clone(), None).unwrap();
+    store.flush().unwrap();
     
-    // Should be able to access individual properties (like Firebase)
-    assert_eq!(store.get("users/123/name").unwrap(), Some("Alice".to_string()));
-    assert_eq!(store.get("users/123/age").unwrap(), Some("30".to_string()));
-    assert_eq!(store.get("users/123/settings/theme").unwrap(), Some("dark".to_string()));
-    assert_eq!(store.get("users/123/settings/notifications").unwrap(), Some("true".to_string()));
+    if !in_memory() {
+        let on_disk = segment_bytes(&dir) as usize;
+        assert!(on_disk * 2 <= full_key_bytes, "Segment is {} bytes, full keys would be {}", on_disk, full_key_bytes);
+    }
     
-    // With new API, getting a parent path returns None (no JSON reconstruction)
-    let subtree_result = store.get("users/123/").unwrap();
-    assert_eq!(subtree_result, None);
+    // Keys come back whole through get, scan, and pattern reads
+    for (key, value) in entries.iter().step_by(97) {
+        assert_eq!(store.get(key).unwrap().as_ref(), Some(value));
+    }
+    let scanned = store.scan_prefix("tenants/acme-corporation/", usize::MAX).unwrap();
+    let mut expected = entries.clone();
+    expected.sort();
+    assert_eq!(scanned, expected);
+    assert_eq!(store.get_pattern("tenants/*/fields/title").unwrap().len(), 1000);
     
-    // Same for path without trailing slash - no JSON reconstruction
-    let object_result = store.get("users/123").unwrap();
-    assert_eq!(object_result, None);
+    cleanup(&dir);
+}
+
+fn test_reads_pre_front_coding_segment() {
+    let dir = test_dir("legacy_segment");
+    let seg_path = flushed_segment(&dir);
+    std::fs::remove_file(std::path::Path::new(&dir).join("wal.log")).unwrap();
+    
+    // Rewrite the segment in the version 3 layout: one block of full-key records, no bloom
+    let records: [(u64, u8, &str, &str); 3] = [
+        (1, 1, "legacy/a", "one"),
+        (2, 1, "legacy/b", "two"),
+        (3, 2, "legacy/c", ""),
+    ];
+    let mut data = b"WALDB03".to_vec();
+    for (seq, kind, key, value) in records {
+        data.extend_from_slice(&seq.to_le_bytes());
+        data.push(kind);
+        data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        data.extend_from_slice(key.as_bytes());
+        data.extend_from_slice(value.as_bytes());
+    }
+    let mut index = Vec::new();
+    index.extend_from_slice(&8u32.to_le_bytes());
+    index.extend_from_slice(&7u64.to_le_bytes());
+    index.extend_from_slice(b"legacy/a");
+    data.extend_from_slice(&index);
+    for field in [1u64, 3] {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    for field in [3u32, index.len() as u32, 0, 0] {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    std::fs::write(&seg_path, data).unwrap();
+    
+    let check = |store: &Store| {
+        assert_eq!(store.get("legacy/a").unwrap(), Some("one".to_string()));
+        assert_eq!(store.get("legacy/b").unwrap(), Some("two".to_string()));
+        assert_eq!(store.get("legacy/c").unwrap(), None);
+        assert_eq!(store.scan_prefix("legacy/", usize::MAX).unwrap().len(), 2);
+        assert_eq!(store.get_pattern("legacy/?").unwrap().len(), 2);
+    };
+    
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    check(&store);
+    
+    // Merging the old segment with front-coded ones keeps every key intact
+    for i in 0..4 {
+        store.set(&format!("new/{}", i), "x", false).unwrap();
+        store.flush().unwrap();
+    }
+    store.compact().unwrap();
+    assert_eq!(store.segment_counts().0, 1);
+    check(&store);
+    assert_eq!(store.scan_prefix("new/", usize::MAX).unwrap().len(), 4);
     
-    // And nested objects also return None
-    let settings_result = store.get("users/123/settings").unwrap();
-    assert_eq!(settings_result, None);
+    cleanup(&dir);
+}
+
+// ==================== QUOTAS ====================
+
+fn test_quotas() {
+    let dir = test_dir("quotas");
+    let path = std::path::Path::new(&dir);
+    let value = |len: usize| "v".repeat(len);
+    {
+        let store = open_store(path).unwrap();
+        let usage = |prefix: &str| {
+            let usage = store.quota_usage(prefix).unwrap().unwrap();
+            assert_eq!(usage.used_bytes, store.prefix_size_exact(&format!("{}/", prefix)).unwrap(), "{}", prefix);
+            usage.used_bytes
+        };
+        assert!(matches!(store.set_quota("", 10), Err(WalDbError::InvalidKey { .. })));
+        store.set("t/x/before", &value(10), false).unwrap();
+        store.set_quota("t/x", 100).unwrap();
+        assert_eq!(usage("t/x"), 20);
+        
+        // Exactly to the cap, each key counted as its path plus its value
+        store.set("t/x/a", &value(35), false).unwrap();
+        store.set("t/x/b", &value(35), false).unwrap();
+        assert_eq!(usage("t/x"), 100);
+        match store.set("t/x/c", "", false) {
+            Err(WalDbError::QuotaExceeded { path, prefix, used, max_bytes }) => {
+                assert_eq!((path.as_str(), prefix.as_str(), used, max_bytes), ("t/x/c", "t/x/", 100, 100));
+            }
+            other => panic!("Expected QuotaExceeded, got {:?}", other),
+        }
+        assert!(matches!(store.increment("t/x/n", 1), Err(WalDbError::QuotaExceeded { .. })));
+        assert_eq!(store.get("t/x/c").unwrap(), None);
+        assert_eq!(usage("t/x"), 100);
+        
+        // Siblings sharing the name aren't under it, and rewrites that don't grow still go through
+        store.set("t/xy", &value(500), false).unwrap();
+        store.set("t/x/a", &value(35), false).unwrap();
+        store.set("t/x/a", &value(5), false).unwrap();
+        assert_eq!(usage("t/x"), 70);
+        store.delete("t/x/b").unwrap();
+        assert_eq!(usage("t/x"), 30);
+        store.set("t/x/c", &value(40), false).unwrap();
+        assert_eq!(usage("t/x"), 75);
+        
+        // A batch that doesn't fit writes nothing, and one that frees room first does
+        let mut batch = WriteBatch::new();
+        batch.put("t/x/d", &value(10)).put("t/x/e", &value(10));
+        assert!(matches!(store.write(batch), Err(WalDbError::QuotaExceeded { .. })));
+        assert_eq!(store.get("t/x/d").unwrap(), None);
+        let mut batch = WriteBatch::new();
+        batch.delete("t/x/c").put("t/x/d", &value(10)).put("t/x/e", &value(10));
+        store.write(batch).unwrap();
+        assert_eq!(usage("t/x"), 60);
+        assert!(matches!(store.set_many(vec![("t/x/f".to_string(), value(36))], None), Err(WalDbError::QuotaExceeded { .. })));
+        store.set_many(vec![("t/x/f".to_string(), value(35))], Some("t/x/d")).unwrap();
+        assert_eq!(usage("t/x"), 85);
+        
+        // Nested quotas: a write has to fit under each
+        store.set_quota("t", 694).unwrap();
+        assert_eq!(usage("t"), 589);
+        store.set("t/y/a", &value(100), false).unwrap();
+        assert_eq!(usage("t"), 694);
+        assert!(matches!(store.set("t/y/b", "", false), Err(WalDbError::QuotaExceeded { prefix, .. }) if prefix == "t/"));
+        assert!(matches!(store.set("t/x/g", "", false), Err(WalDbError::QuotaExceeded { prefix, .. }) if prefix == "t/"));
+        store.set_quota("t", 1000).unwrap();
+        
+        // A subtree deleted inside a quota takes what it held off it, one over the quota clears it
+        store.set_many(vec![("t/x/sub/1".to_string(), "z".to_string())], Some("t/x/f")).unwrap();
+        assert_eq!((usage("t/x"), usage("t")), (55, 664));
+        store.delete_subtree("t/x/sub").unwrap();
+        assert_eq!((usage("t/x"), usage("t")), (45, 654));
+        store.delete_subtree("t/x").unwrap();
+        assert_eq!((usage("t/x"), usage("t")), (0, 609));
+        store.set("t/x/a", &value(45), false).unwrap();
+        assert_eq!((usage("t/x"), usage("t")), (50, 659));
+    }
+    
+    // The caps are kept, and what's under them counted afresh
+    if !in_memory() {
+        let store = open_store(path).unwrap();
+        let usage = store.quota_usage("t/x/").unwrap().unwrap();
+        assert_eq!((usage.used_bytes, usage.max_bytes), (50, 100));
+        assert_eq!(store.quota_usage("t").unwrap().unwrap().used_bytes, 659);
+        store.set("t/x/b", &value(45), false).unwrap();
+        assert!(matches!(store.set("t/x/c", "", false), Err(WalDbError::QuotaExceeded { .. })));
+        
+        assert!(store.remove_quota("t/x").unwrap());
+        assert!(!store.remove_quota("t/x").unwrap());
+        assert!(store.quota_usage("t/x").unwrap().is_none());
+        store.set("t/x/c", "", false).unwrap();
+        assert_eq!(store.quota_usage("t").unwrap().unwrap().used_bytes, 714);
+    }
+    
+    cleanup(&dir);
+}
+
+// ==================== VALIDATORS ====================
+
+fn test_validators() {
+    let dir = test_dir("validators");
+    let store = open_store(std::path::Path::new(&dir)).unwrap();
+    let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recording = |name: &'static str, max_len: usize| -> ValueValidator {
+        let calls = calls.clone();
+        Box::new(move |key, value| {
+            calls.lock().unwrap().push((name, key.to_string()));
+            if value.len() > max_len { Err(format!("{} allows {} bytes", name, max_len)) } else { Ok(()) }
+        })
+    };
+    assert!(matches!(store.register_validator("/", recording("root", 0)), Err(WalDbError::InvalidKey { .. })));
+    store.set("users/1/name", "a long name", false).unwrap();
+    store.register_validator("users", recording("users", 10)).unwrap();
+    store.register_validator("users/1/", recording("user 1", 5)).unwrap();
+    assert_eq!(store.validated_prefixes(), vec!["users/", "users/1/"]);
+    
+    // Most specific first, and all have to pass
+    store.set("users/1/name", "abc", false).unwrap();
+    assert_eq!(*calls.lock().unwrap(), vec![("user 1", "users/1/name".to_string()), ("users", "users/1/name".to_string())]);
+    match store.set("users/1/name", "abcdefgh", false) {
+        Err(WalDbError::ValidationFailed { key, reason }) => {
+            assert_eq!((key.as_str(), reason.as_str()), ("users/1/name", "user 1 allows 5 bytes"));
+        }
+        other => panic!("Expected ValidationFailed, got {:?}", other),
+    }
+    assert!(matches!(store.set("users/2/name", "abcdefghijk", false), Err(WalDbError::ValidationFailed { reason, .. }) if reason == "users allows 10 bytes"));
+    store.set("users/2/name", "abcdefgh", false).unwrap();
+    store.set("usersx/1", "abcdefghijk", false).unwrap();
+    assert_eq!(store.get("users/1/name").unwrap().as_deref(), Some("abc"));
+    
+    // One refused value fails the whole set_many or batch
+    let entries = vec![("users/3/a".to_string(), "ok".to_string()), ("users/3/b".to_string(), "far too long".to_string())];
+    assert!(matches!(store.set_many(entries, None), Err(WalDbError::ValidationFailed { key, .. }) if key == "users/3/b"));
+    assert!(matches!(store.set_many_if_version(vec![("users/3/b".to_string(), "far too long".to_string())], None, 0),
+                     Err(WalDbError::ValidationFailed { .. })));
+    let mut batch = WriteBatch::new();
+    batch.put("users/3/a", "ok").delete("users/2/name").put("users/1/x", "too long");
+    assert!(matches!(store.write(batch), Err(WalDbError::ValidationFailed { key, .. }) if key == "users/1/x"));
+    assert_eq!(store.get("users/3/a").unwrap(), None);
+    assert_eq!(store.get("users/2/name").unwrap().as_deref(), Some("abcdefgh"));
+    
+    // Reads and deletes don't run them
+    calls.lock().unwrap().clear();
+    assert!(store.get("users/2/name").unwrap().is_some());
+    store.delete("users/2/name").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.delete("users/1/name").delete_subtree("users/2");
+    store.write(batch).unwrap();
+    store.delete_subtree("users").unwrap();
+    assert!(calls.lock().unwrap().is_empty());
+    
+    // Without them, anything goes
+    assert!(store.remove_validator("users/1").unwrap());
+    assert!(!store.remove_validator("users/1").unwrap());
+    store.set("users/1/name", "abcdefgh", false).unwrap();
+    assert!(store.remove_validator("users").unwrap());
+    store.set("users/1/name", "a very long name indeed", false).unwrap();
+    assert!(store.validated_prefixes().is_empty());
     
     cleanup(&dir);
 }
 
 // ==================== TEST RUNNER ====================
 
+// Tests that reopen the store or reach into its files, so they have nothing to check in memory
+const FILE_ONLY: &[&str] = &[
+    "Persistence", "WAL Recovery", "Wait Durable Survives Crash", "Missing WAL Seq",
+    "WAL Mixed Frame Formats", "Reopen Skips Flushed WAL", "Replay Flush Threshold",
+    "Stats History", "Deferred Manifest Syncs", "Flush to Disk", "Range Iter Pins Segments",
+    "Segment Registry", "Deleted Key Lookups", "WAL Sync Failure Retains Entries",
+    "Storage Full Degraded Mode", "Atomic File Creation", "Bad Segment Magic",
+    "Open Ignores Unknown Files", "Open Unreadable Segment", "Open Newer Format Segment",
+    "Store Identity", "Salvage Truncated Segment", "Manifest Overlapping Segments", "Dump Segment",
+    "Strict UTF-8", "Write Batch Survives Sidecar Failure", "Vector Sidecar Rebuild",
+    "Vector Sidecar Compaction", "Reads Pre-Front-Coding Segment"
+];
+
 fn main() {
     println!("Running WalDB Test Suite");
     println!("========================");
@@ -835,43 +6262,146 @@ fn main() {
         ("Nested Paths", test_nested_paths as fn()),
         ("Deep Nesting", test_deep_nesting as fn()),
         ("Parent Scalar Violation", test_parent_scalar_violation as fn()),
+        ("Strict Reads", test_strict_reads as fn()),
         ("Scalar to Tree", test_scalar_to_tree_conversion as fn()),
+        ("Set With Options", test_set_with_options as fn()),
         ("Subtree JSON", test_get_subtree_as_json as fn()),
         ("Delete Subtree", test_delete_subtree as fn()),
         ("Replace Subtree", test_replace_subtree as fn()),
+        ("Subtree JSON Round Trip", test_subtree_json_round_trip as fn()),
+        ("Subtree JSON Conflicts", test_subtree_json_root_scalar_and_conflicts as fn()),
         ("Persistence", test_persistence_across_restarts as fn()),
         ("WAL Recovery", test_wal_recovery as fn()),
+        ("Wait Durable Survives Crash", test_wait_durable_survives_crash as fn()),
+        ("Durable And Flushed Seq", test_durable_and_flushed_seq as fn()),
+        ("Missing WAL Seq", test_missing_wal_seq as fn()),
+        ("WAL Mixed Frame Formats", test_wal_mixed_frame_formats as fn()),
+        ("Reopen Skips Flushed WAL", test_reopen_skips_flushed_wal_records as fn()),
+        ("Tombstone Durability", test_tombstone_durability as fn()),
+        ("Replay Flush Threshold", test_replay_flush_threshold as fn()),
+        ("Memtable Max Age", test_memtable_max_age as fn()),
+        ("Stats History", test_stats_history as fn()),
+        ("L0 Admission", test_l0_admission as fn()),
+        ("WAL Backpressure", test_wal_backpressure as fn()),
+        ("Deferred Manifest Syncs", test_deferred_manifest_syncs as fn()),
         ("Flush to Disk", test_flush_to_disk as fn()),
         ("Bulk Insert", test_bulk_insert as fn()),
         ("Prefix Operations", test_prefix_operations as fn()),
+        ("Scan Prefix Merged", test_scan_prefix_merged as fn()),
+        ("Scan Allocations", test_scan_allocations as fn()),
+        ("Range Iter Snapshot", test_range_iter_snapshot as fn()),
+        ("Range Iter Pins Segments", test_range_iter_pins_segments as fn()),
+        ("Segment Registry", test_segment_registry as fn()),
+        ("Memtable Key Order", test_memtable_key_order as fn()),
+        ("Numeric Collation", test_numeric_collation as fn()),
         ("Unicode Support", test_unicode_support as fn()),
+        ("Path Segments", test_path_segments as fn()),
         ("Empty Values", test_empty_values as fn()),
         ("Special Paths", test_special_paths as fn()),
         ("Write Performance", test_write_performance as fn()),
         ("Read Performance", test_read_performance as fn()),
         ("Cache Effectiveness", test_cache_effectiveness as fn()),
+        ("Warmup", test_warmup as fn()),
         ("Concurrent Reads", test_concurrent_reads as fn()),
+        ("Concurrent Cold Segment Reads", test_concurrent_cold_segment_reads as fn()),
+        ("Coalesced Point Gets", test_coalesced_point_gets as fn()),
+        ("Deleted Key Lookups", test_deleted_key_lookups as fn()),
+        ("May Contain", test_may_contain as fn()),
+        ("Read Amplification Report", test_read_amplification_report as fn()),
+        ("Read Budget", test_read_budget as fn()),
         ("Concurrent Read/Write", test_concurrent_read_write as fn()),
+        ("Concurrent Increments", test_concurrent_increments as fn()),
+        ("Sharded Memtable", test_sharded_memtable as fn()),
+        ("Increment Errors", test_increment_errors as fn()),
+        ("Queue", test_queue as fn()),
         ("Invalid Operations", test_invalid_operations as fn()),
+        ("Sync Diff", test_sync_diff as fn()),
+        ("Snapshot Import", test_snapshot_import as fn()),
+        ("Submission Order", test_submission_order as fn()),
+        ("Health Check", test_health_check as fn()),
+        ("Store Events", test_store_events as fn()),
+        ("Clock Steps Back", test_clock_steps_back as fn()),
+        ("Clock Drives Health Check", test_clock_drives_health_check as fn()),
+        ("WAL Sync Failure Retains Entries", test_wal_sync_failure_retains_entries as fn()),
+        ("Storage Full Degraded Mode", test_storage_full_degraded_mode as fn()),
+        ("Atomic File Creation", test_atomic_file_creation as fn()),
+        ("Bad Segment Magic", test_bad_segment_magic as fn()),
+        ("Open Ignores Unknown Files", test_open_ignores_unknown_files as fn()),
+        ("Open Unreadable Segment", test_open_unreadable_segment as fn()),
+        ("Open Newer Format Segment", test_open_newer_format_segment as fn()),
+        ("Store Identity", test_store_identity as fn()),
+        ("Salvage Truncated Segment", test_salvage_truncated_segment as fn()),
+        ("Manifest Overlapping Segments", test_manifest_overlapping_segments as fn()),
+        ("Dump Segment", test_dump_segment as fn()),
         ("Compaction", test_compaction as fn()),
         ("Group Commit", test_group_commit_behavior as fn()),
         ("Range Queries", test_range_queries as fn()),
         ("Tombstones", test_tombstone_behavior as fn()),
+        ("Contains", test_contains as fn()),
+        ("Delete-Heavy Flush", test_delete_heavy_workload_flushes as fn()),
         ("Wildcard Star Match", test_wildcard_star_match as fn()),
         ("Wildcard Question Match", test_wildcard_question_match as fn()),
         ("Wildcard Delete", test_wildcard_delete as fn()),
+        ("Streamed Pattern Delete", test_streamed_pattern_delete as fn()),
+        ("Cancel Pattern Scan", test_cancel_pattern_scan as fn()),
+        ("Pattern Grouped", test_pattern_grouped as fn()),
+        ("Subtree Version", test_subtree_version as fn()),
         ("Set Many Basic", test_set_many_basic as fn()),
         ("Set Many Subtree Replace", test_set_many_with_subtree_replacement as fn()),
         ("Set Many Empty", test_set_many_empty as fn()),
         ("Set Many Parent Violation", test_set_many_parent_scalar_violation as fn()),
+        ("Set Many Replace Spares Siblings", test_set_many_replace_spares_siblings as fn()),
+        ("Batch Parent Probes", test_batch_parent_probes as fn()),
+        ("Has Live Children", test_has_live_children as fn()),
+        ("Clear All", test_clear_all as fn()),
+        ("Strict UTF-8", test_strict_utf8 as fn()),
+        ("Write Batch Ops In Order", test_write_batch_ops_in_order as fn()),
+        ("Write Batch Spans Flushes", test_write_batch_spans_flushes as fn()),
+        ("Write Batch Is Atomic", test_write_batch_is_atomic as fn()),
+        ("Write Batch Survives Sidecar Failure", test_write_batch_survives_sidecar_failure as fn()),
+        ("Skip Identical Sets", test_skip_identical_sets as fn()),
+        ("Move Subtree", test_move_subtree as fn()),
+        ("Move Subtree Concurrent Readers", test_move_subtree_concurrent_readers as fn()),
+        ("Copy Subtree", test_copy_subtree as fn()),
+        ("Subscribe Coalesced", test_subscribe_coalesced as fn()),
+        ("Namespace Isolation", test_namespace_isolation as fn()),
+        ("Drop Namespace", test_drop_namespace as fn()),
         ("Object Flattening", test_object_flattening_simulation as fn()),
+        ("Text Search Unicode Case", test_text_search_unicode_case as fn()),
+        ("Text Search Anchors", test_text_search_anchors as fn()),
+        ("Text Search Weights/Ties", test_text_search_field_weights_and_ties as fn()),
+        ("Search Group Order", test_search_group_order as fn()),
+        ("Search Limit Pushdown", test_search_limit_pushdown as fn()),
+        ("Search Filter Value Types", test_search_filter_value_types as fn()),
+        ("Vector Metrics", test_vector_metrics as fn()),
+        ("Vector Normalize On Write", test_vector_normalize_on_write as fn()),
+        ("Vector Metrics Long Vectors", test_vector_metrics_long_vectors as fn()),
+        ("Vector Sidecar Rebuild", test_vector_sidecar_rebuild_on_corruption as fn()),
+        ("Vector Sidecar Deletes", test_vector_sidecar_consistency_after_deletes as fn()),
+        ("Vector Sidecar Compaction", test_vector_sidecar_compacts_dead_rows as fn()),
+        ("Raw Range Tombstones", test_get_range_raw_reports_tombstones as fn()),
+        ("Apply Replicated", test_apply_replicated as fn()),
+        ("As Of", test_as_of as fn()),
+        ("Prefix Write Stats", test_prefix_write_stats as fn()),
+        ("Write Rate Limit Window", test_write_rate_limit_window as fn()),
+        ("Shared Value Reads", test_shared_value_reads as fn()),
+        ("Front Coded Segment Size", test_front_coded_segment_size as fn()),
+        ("Reads Pre-Front-Coding Segment", test_reads_pre_front_coding_segment as fn()),
+        ("Quotas", test_quotas as fn()),
+        ("Validators", test_validators as fn()),
     ];
     
     let mut passed = 0;
     let mut failed = 0;
+    let mut skipped = 0;
     
     for (name, test) in tests {
         print!("Testing {}: ", name);
+        if in_memory() && FILE_ONLY.contains(&name) {
+            println!("⏭️  SKIPPED");
+            skipped += 1;
+            continue;
+        }
         std::panic::catch_unwind(test).map_or_else(
             |_| {
                 println!("❌ FAILED");
@@ -885,10 +6415,10 @@ fn main() {
     }
     
     println!("\n========================");
-    println!("Results: {} passed, {} failed", passed, failed);
+    println!("Results: {} passed, {} failed, {} skipped", passed, failed, skipped);
     
     // Clean up any remaining test directories
-    let _ = std::fs::read_dir("/tmp")
+    let _ = std::fs::read_dir(std::env::temp_dir())
         .map(|entries| {
             for entry in entries.flatten() {
                 if let Some(name) = entry.file_name().to_str() {
@@ -903,39 +6433,3 @@ fn main() {
         std::process::exit(1);
     }
 }
-
-#[cfg(test)]
-mod reconstruction_tests {
-    use super::waldb_store::*;
-    use tempfile::tempdir;
-    
-    #[test]
-    fn test_object_reconstruction_after_flush() {
-        let dir = tempdir().unwrap();
-        let store = Store::open(dir.path()).unwrap();
-        
-        // Set individual properties (simulating flattened object)
-        store.set("obj/a", "n:1", false).unwrap();
-        store.set("obj/b", "n:2", false).unwrap();
-        store.set("obj/c/d", "n:3", false).unwrap();
-        
-        // Before flush - should reconstruct
-        let result = store.get("obj").unwrap();
-        println!("Before flush: {:?}", result);
-        assert!(result.is_some(), "Should reconstruct object before flush");
-        
-        // Flush to segments
-        store.flush().unwrap();
-        
-        // After flush - should still reconstruct
-        let result2 = store.get("obj").unwrap();
-        println!("After flush: {:?}", result2);
-        assert!(result2.is_some(), "Should reconstruct object after flush");
-        
-        // Reopen store
-        let store2 = Store::open(dir.path()).unwrap();
-        let result3 = store2.get("obj").unwrap();
-        println!("After reopen: {:?}", result3);
-        assert!(result3.is_some(), "Should reconstruct object after reopen");
-    }
-}
\ No newline at end of file
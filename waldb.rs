@@ -7,25 +7,251 @@
 // - Thread-safe concurrent access
 // - Vector and text search capabilities
 
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::{Borrow, Cow};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const MAGIC: &[u8] = b"WALDB03";
-const WAL_MAGIC: &[u8] = b"WAL2";
+// extern "C" functions for embedders outside Rust
+#[cfg(feature = "capi")]
+#[path = "waldb_ffi.rs"]
+pub mod ffi;
+
+const MAGIC: &[u8] = b"WALDB07";
+const MAGIC_PREFIX: &[u8] = b"WALDB";  // Followed by a two-digit format version
+const FORMAT_VERSION: u32 = 7;
+const IDENTITY_FILE: &str = "IDENTITY";
+const IDENTITY_HEADER: &str = "waldb-identity";
+const TMP_SUFFIX: &str = ".tmp";  // Files written under this name, then renamed into place
+const FRONT_CODED_VERSION: u32 = 4;  // Block keys are stored relative to the previous key from here on
+const COLLATION_VERSION: u32 = 5;  // The footer records the key collation from here on
+const FLAGS_VERSION: u32 = 6;  // The footer starts with a flags word from here on
+const BOUNDS_VERSION: u32 = 7;  // The segment's largest key sits before the footer, its length first in it, from here on
+const FOOTER_VALUE_REFS: u32 = 1;  // Footer flag: some records are RT_SET_REF
+const WAL_MAGIC: &[u8] = b"WAL3";
+const WAL_MAGIC_V2: &[u8] = b"WAL2";  // A frame and CRC per entry rather than per group commit
 const RT_SET: u8 = 1;
 const RT_DEL_POINT: u8 = 2;
 const RT_DEL_SUB: u8 = 3;
+const RT_BATCH: u8 = 4;  // WAL only: a whole WriteBatch under one CRC
+const RT_SET_REF: u8 = 5;  // Segments only: the value is the hex content hash of a value log entry
+const RT_GROUP: u8 = 6;  // WAL only: the entries of one group commit under one CRC
+const VALUE_LOG_MAGIC: &[u8] = b"WALVAL1";
+const VALUE_ENTRY_HEADER: u64 = 12;  // Content hash, value length
+const VALUE_CACHE_SIZE: usize = 16 * 1024 * 1024;
+const BATCH_HEADER: usize = 17;  // Frame length, seq, kind, empty key length
+const GROUP_HEADER: usize = 17;  // Seq, kind, empty key length, entry count
 const BLOCK_SIZE: usize = 4096;
 const MEMTABLE_THRESHOLD: usize = 256 * 1024;
+const MEMTABLE_MAX_AGE: Duration = Duration::from_secs(5 * 60);  // Default StoreOptions::memtable_max_age
+const CLOCK_STEP_TOLERANCE: Duration = Duration::from_secs(1);  // A wall clock further behind the latest time seen has stepped back
+const COMPACTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const SUBTOMB_THRESHOLD: usize = 64 * 1024;
+const MEMTABLE_ENTRY_OVERHEAD: usize = 32;  // Per-entry bookkeeping estimate (seq, tag, map node)
+const COMPACT_INLINE: usize = 22;  // Longest memtable value kept in the entry, which stays a String's size
 const L0_COMPACTION_THRESHOLD: usize = 4;
 const L1_COMPACTION_THRESHOLD: usize = 10;
+const L0_SLOWDOWN_STEP: Duration = Duration::from_millis(10);  // Added to a flush per L0 segment from l0_slowdown_threshold on
+const L0_SLOWDOWN_MAX: Duration = Duration::from_millis(100);
+const L0_STALL_CHECK: Duration = Duration::from_millis(100);  // A held-back flush looks for a close or a degraded store this often
+const DELETE_CHUNK: usize = 10_000;  // Point deletes per batch when delete_pattern streams its matches
+const COPY_CHUNK: usize = 10_000;  // Keys per batch when copy_subtree streams the source
+const SNAPSHOT_CHUNK: usize = 10_000;  // Default SnapshotOptions::chunk_entries
+const SNAPSHOT_VERSION: &str = "1";  // export_snapshot's header names its format
+const ARCHIVE_LEVEL: usize = 3;  // Sealed prefixes' segments, below L2 and outside compaction
+const ARCHIVE_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;  // A seal starts another archive file past this
+const L2_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;  // Default CompactionOptions::target_segment_bytes
+const MAX_SEGMENT_BYTES: u64 = 256 * 1024 * 1024;  // Default StoreOptions::max_segment_size
 const CACHE_SIZE: usize = 32 * 1024 * 1024;
+const WARMUP_READ_BYTES: usize = 1024 * 1024;  // Most adjacent blocks warmup reads at once
+const ACCOUNTING_BUCKETS: usize = 4096;  // Slots in the accounting table, a power of two
+const ACCOUNTING_PROBES: usize = 64;  // Slots a bucket may look through before it overflows
+const WINDOW_SLICES: usize = 10;  // A write rate window slides this many steps at a time
+const READ_LEVELS: usize = 6;  // ReadLevel's variants, each a row of read_amplification_report
+const READ_COST_BUCKETS: usize = 32;  // A get cost histogram's: exact up to 15, then one per power of two
+const SLICE_COUNT_BITS: u32 = 24;  // A window slice's writes, below the tick it began at
+const CACHE_PROBATION_PERCENT: usize = 10;  // Of CACHE_SIZE, for blocks scans read
 const GROUP_COMMIT_MS: u64 = 10;
+const WAL_RETRY_MIN: Duration = Duration::from_millis(50);  // First retry after a failed group commit
+const WAL_RETRY_MAX: Duration = Duration::from_secs(5);     // Backoff doubles up to here
+const WAL_FLUSH_BYTES: usize = 64 * 1024;  // Buffered WAL bytes that wake the flusher early
+const WAL_MAX_UNFLUSHED: usize = 16 * 1024 * 1024;  // Buffered WAL bytes writers wait below
+const HIGH_WATER_SYNCS: u64 = 4096;  // WAL syncs between the flusher's seq lines in the manifest
+const SEQ_SAFETY_GAP: u64 = 1000;  // Seqs skipped on open when the WAL holding the newest ones is gone
+const VECTOR_MAGIC: &[u8] = b"WALVEC1";
+const STATS_RING_FILE: &str = "stats.ring";
+const STATS_RING_MAGIC: &[u8] = b"WALSTAT1";
+const STATS_RING_HEADER: usize = 16;  // Magic, record size, unused
+const STATS_RING_BYTES: u64 = 4 * 1024 * 1024;  // Default StoreOptions::stats_ring_bytes
+const STATS_RECORD: usize = 128;  // Sample number, time, 13 counters, CRC, padding
+const VR_PUT: u8 = 1;
+const VR_DEL: u8 = 2;
+const VECTOR_COMPACT_MIN: usize = 1024;  // Dead rows tolerated before fragmentation forces a rewrite
+const COALESCE_MAX_ENTRIES: usize = 10_000;  // A subscription batch this big goes out before its window closes
+const NAMESPACE_MARKER: char = '\0';  // Leads every namespaced key; Store's own writes refuse it
+const SYSTEM_MARKER: char = '\u{1}';  // Leads keys the store keeps for itself: user writes refuse them, user reads skip them
+const SYSTEM_PREFIX: &str = "\u{1}sys/";  // Where the system_* methods keep their keys
+const HEALTH_PROBE_KEY: &str = "health/probe";  // Under SYSTEM_PREFIX, health_check's write probe
+const QUOTA_KEYS: &str = "quota/";  // Under SYSTEM_PREFIX, set_quota's caps by escaped prefix
+
+pub type Result<T, E = WalDbError> = std::result::Result<T, E>;
+
+/// Errors returned by the public `Store` API
+#[derive(Debug)]
+pub enum WalDbError {
+    /// Underlying filesystem failure
+    Io(io::Error),
+    /// Write would place a child under an existing scalar value, or, under
+    /// StoreOptions::strict_reads, a read asked for one. A read names the scalar ancestor.
+    TreeSemantics { path: String, ancestor: Option<String> },
+    /// On-disk data failed validation
+    Corruption { file: PathBuf, offset: u64, detail: String },
+    /// An internal lock was poisoned by a panicking thread
+    Locked(String),
+    /// Key cannot be stored as given
+    InvalidKey { reason: String },
+    /// File was written by a newer WalDB with a format this build cannot read
+    NewerFormat { file: PathBuf, version: u32 },
+    /// increment() found a value that isn't an i64, or the sum would overflow one
+    NotAnInteger { path: String, value: String },
+    /// A scan's CancellationToken was cancelled or ran past its timeout
+    Cancelled,
+    /// The store's segments are ordered by a different collation than StoreOptions asked for
+    CollationMismatch { file: PathBuf, found: Collation, requested: Collation },
+    /// A write would change data under a prefix sealed by seal_prefix
+    Sealed { path: String, prefix: String },
+    /// The store is read-only after a WAL or flush write failed, until a WAL sync succeeds
+    StorageFull,
+    /// The store refuses writes because rebuild_into is copying it, or has copied it, into
+    /// another directory
+    ReadOnly,
+    /// Store::as_of was asked for a seq older than the history floor: merges have since
+    /// dropped versions a read that old would need
+    HistoryUnavailable { seq: u64, floor: u64 },
+    /// copy_subtree with CopyMode::Fail found a value where it would write one
+    AlreadyExists { path: String },
+    /// The store was opened with StoreOptions::replica and takes writes only through
+    /// apply_replicated
+    Replica,
+    /// The value of path, in the segment or WAL record at offset of file, isn't UTF-8.
+    /// StoreOptions::lossy_reads reads it with replacement characters instead.
+    InvalidUtf8 { path: String, file: PathBuf, offset: u64 },
+    /// A snapshot from export_snapshot failed its checks: chunk names the damaged chunk,
+    /// None for the header or trailer. Chunks before it were applied by import.
+    SnapshotCorrupt { chunk: Option<usize>, detail: String },
+    /// A write would take the bytes under prefix past the max_bytes set_quota gave it. used
+    /// is what the prefix held before the write, none of which was applied.
+    QuotaExceeded { path: String, prefix: String, used: u64, max_bytes: u64 },
+    /// A validator register_validator gave a prefix over key refused its value. Nothing in
+    /// the write was applied.
+    ValidationFailed { key: String, reason: String },
+    /// get_with_budget would have probed more segments or read more blocks from disk than its
+    /// ReadBudget allows. candidate is the newest value found before it stopped, None if none
+    /// was or that record is a delete; a segment not yet probed may hold a newer one.
+    BudgetExceeded { path: String, segments_probed: u64, block_reads: u64, candidate: Option<String> },
+}
+
+impl fmt::Display for WalDbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalDbError::Io(e) => write!(f, "{}", e),
+            WalDbError::TreeSemantics { path, ancestor: Some(ancestor) } => write!(
+                f, "Cannot read {}: {} holds a scalar value, so nothing can be under it", path, ancestor
+            ),
+            WalDbError::TreeSemantics { .. } => write!(f, "Cannot write under scalar parent"),
+            WalDbError::Corruption { detail, .. } => write!(f, "{}", detail),
+            WalDbError::Locked(msg) => write!(f, "{}", msg),
+            WalDbError::InvalidKey { reason } => write!(f, "{}", reason),
+            WalDbError::NewerFormat { file, version } => write!(
+                f, "{} was created by a newer version of WalDB (format {}, supported {})",
+                file.display(), version, FORMAT_VERSION
+            ),
+            WalDbError::NotAnInteger { path, value } => write!(f, "Cannot increment {}: {:?} is not a 64-bit integer", path, value),
+            WalDbError::Cancelled => write!(f, "Operation cancelled"),
+            WalDbError::CollationMismatch { file, found, requested } => write!(
+                f, "{} is ordered by {:?} collation but the store was opened with {:?}; open it with StoreOptions::collation = Collation::{:?}",
+                file.display(), found, requested, found
+            ),
+            WalDbError::Sealed { path, prefix } => write!(f, "Cannot write {}: {} is sealed, unseal_prefix it first", path, prefix),
+            WalDbError::StorageFull => write!(f, "Store is read-only: its disk refused a write, and writes resume once a WAL sync succeeds"),
+            WalDbError::ReadOnly => write!(f, "Store is read-only: it is being or has been rebuilt into another directory, which takes the writes"),
+            WalDbError::HistoryUnavailable { seq, floor } => write!(
+                f, "Cannot read as of seq {}: the store's history floor is {}, and versions older than that may have been compacted away",
+                seq, floor
+            ),
+            WalDbError::AlreadyExists { path } => write!(f, "Cannot copy to {}: it already holds a value", path),
+            WalDbError::Replica => write!(f, "Store is a replica: it takes writes only from its primary, through apply_replicated"),
+            WalDbError::InvalidUtf8 { path, file, offset } => write!(
+                f, "Value of {} in {} at offset {} is not valid UTF-8; open the store with StoreOptions::lossy_reads to read it with replacement characters",
+                path, file.display(), offset
+            ),
+            WalDbError::SnapshotCorrupt { chunk: Some(chunk), detail } => write!(f, "Snapshot chunk {} is damaged: {}", chunk, detail),
+            WalDbError::SnapshotCorrupt { chunk: None, detail } => write!(f, "Bad snapshot: {}", detail),
+            WalDbError::QuotaExceeded { path, prefix, used, max_bytes } => write!(
+                f, "Cannot write {}: it would take {} past its quota of {} bytes, {} of which are in use",
+                path, prefix, max_bytes, used
+            ),
+            WalDbError::ValidationFailed { key, reason } => write!(f, "Cannot write {}: {}", key, reason),
+            WalDbError::BudgetExceeded { path, segments_probed, block_reads, .. } => write!(
+                f, "Read of {} ran out of budget: {} segments probed, {} blocks read from disk",
+                path, segments_probed, block_reads
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WalDbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WalDbError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WalDbError {
+    fn from(e: io::Error) -> Self {
+        WalDbError::Io(e)
+    }
+}
+
+impl From<WalDbError> for io::Error {
+    fn from(e: WalDbError) -> Self {
+        match e {
+            WalDbError::Io(e) => e,
+            WalDbError::TreeSemantics { .. } | WalDbError::InvalidKey { .. } => {
+                io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+            }
+            WalDbError::Corruption { .. } | WalDbError::NewerFormat { .. } => {
+                io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+            }
+            WalDbError::Locked(_) => io::Error::other(e.to_string()),
+            WalDbError::NotAnInteger { .. } => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+            WalDbError::Cancelled => io::Error::new(io::ErrorKind::Interrupted, e.to_string()),
+            WalDbError::CollationMismatch { .. } => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+            WalDbError::Sealed { .. } => io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()),
+            WalDbError::StorageFull => io::Error::new(io::ErrorKind::StorageFull, e.to_string()),
+            WalDbError::ReadOnly => io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()),
+            WalDbError::HistoryUnavailable { .. } => io::Error::new(io::ErrorKind::NotFound, e.to_string()),
+            WalDbError::AlreadyExists { .. } => io::Error::new(io::ErrorKind::AlreadyExists, e.to_string()),
+            WalDbError::Replica => io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()),
+            WalDbError::InvalidUtf8 { .. } => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+            WalDbError::SnapshotCorrupt { .. } => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+            WalDbError::QuotaExceeded { .. } => io::Error::new(io::ErrorKind::QuotaExceeded, e.to_string()),
+            WalDbError::ValidationFailed { .. } => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+            WalDbError::BudgetExceeded { .. } => io::Error::new(io::ErrorKind::TimedOut, e.to_string()),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Store {
@@ -34,57 +260,570 @@ pub struct Store {
     wal: Arc<GroupCommitWAL>,
     cache: Arc<BlockCache>,
     manifest: Arc<Mutex<Manifest>>,
+    vectors: Arc<Mutex<VectorSidecar>>,
+    next_segment_id: Arc<AtomicU64>,  // Ids key the block cache, never reused within a process
+    segment_registry: Arc<SegmentRegistry>,  // Segments out of the store whose files wait for their last reader
+    compaction: CompactionOptions,
+    max_segment_size: u64,
+    compacting: Arc<Mutex<()>>,  // One merge at a time, so the background thread and compact() never pick the same inputs
+    admin: Arc<Mutex<()>>,  // Serializes flushes, compaction installs, drop_segment, seal_prefix and close; see LockRank
+    unlisted: Arc<Mutex<Vec<ManifestEntry>>>,  // Flushed segments in use but not yet in the manifest, oldest first
     compaction_shutdown: Arc<(Mutex<bool>, Condvar)>,
+    events: EventSink,
+    skip_identical_sets: bool,
+    point_gets: Option<Arc<Mutex<HashMap<String, GetSlot>>>>,  // In-flight segment lookups, with coalesce_point_gets
+    manifest_unsynced: Arc<AtomicBool>,  // A flush's manifest entry is written but not yet fsynced
+    defer_manifest_syncs: bool,
+    collation: Collation,
+    values: Arc<Mutex<ValueLog>>,
+    value_cache: Arc<ValueCache>,
+    dedup_values_over: Option<usize>,
+    open_snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,  // Seq each live RangeIter reads as of, with a count
+    replayed: WalStats,  // What open read from the WAL, for wal_stats
+    segment_dirs: Arc<SegmentDirs>,
+    blocks_read: Arc<AtomicU64>,  // Blocks point lookups have searched, for lookup_stats
+    negative_hits: Arc<AtomicU64>,  // Gets and contains answered by the negative lookup cache
+    read_amp: Arc<ReadAmplification>,  // What each get cost, by the level that answered it
+    groups_visited: Arc<AtomicU64>,  // Groups searches have read, for search_stats
+    compaction_io: Arc<(AtomicU64, AtomicU64)>,  // Bytes merges have read and written, for compaction_stats
+    compaction_timing: Arc<(AtomicU64, AtomicU64)>,  // Merges installed and the microseconds they took, for metrics
+    flush_timing: Arc<(AtomicU64, AtomicU64)>,  // Memtable flushes and the microseconds they took
+    flushed_seq: Arc<AtomicU64>,  // Every write up to here is in a segment; the WAL holds the ones after. Set under the inner write lock.
+    identity: StoreIdentity,
+    read_only: Arc<AtomicBool>,  // Set by rebuild_into: writes fail with WalDbError::ReadOnly
+    replica: bool,  // StoreOptions::replica: writes fail with WalDbError::Replica
+    pin_budget: Option<usize>,  // Bytes warmup may pin, with StoreOptions::pin_indexes
+    strict_reads: bool,  // StoreOptions::strict_reads: misses under a scalar fail with TreeSemantics
+    lossy_reads: bool,  // StoreOptions::lossy_reads: text that isn't UTF-8 reads with replacement characters
+    memtable_max_age: Duration,  // StoreOptions::memtable_max_age: the compaction thread flushes an older memtable
+    shard_writers: Option<Arc<Vec<Mutex<()>>>>,  // With memtable_shards: one point write per shard at a time
+    accounting: Option<Arc<WriteAccounting>>,  // With StoreOptions::accounting_roots
+    l0_slowdown: Option<usize>,  // StoreOptions::l0_slowdown_threshold
+    l0_stop: Option<usize>,  // StoreOptions::l0_stop_threshold
+    l0_changed: Arc<(Mutex<u64>, Condvar)>,  // Bumped as segments leave their level, for flushes l0_stop holds back
+    l0_throttling: Arc<(AtomicU64, AtomicU64, AtomicU64)>,  // Flushes slowed, flushes stalled and the microseconds they stalled
+    compaction_wanted: Arc<AtomicBool>,  // Wakes the compaction thread before its next check
+    in_memory: bool,  // Store::open_in_memory: no file is read or written, and dir names nothing
+    blobs: Option<BlobMap>,  // In memory, where set_file puts blobs
+    quotas: Arc<Quotas>,
+    validators: Arc<Validators>,
+    clock: Arc<StoreClock>,  // StoreOptions::clock, never read back past a time it already gave
 }
 
 #[derive(Debug)]
 struct StoreInner {
-    seq: u64,
-    memtable: BTreeMap<String, MemValue>,
-    memtable_size: usize,
+    seq: AtomicU64,  // Sharded writes take it under the read lock, so it is atomic
+    collation: Collation,  // Orders the memtable, so it lives here too
+    memtable: Vec<RwLock<MemShard>>,  // One shard, or StoreOptions::memtable_shards of them
+    memtable_size: AtomicUsize,  // Across all shards
+    memtable_born: AtomicU64,  // Nanoseconds after epoch, plus one, of the first record since the memtable was last emptied; 0 while empty
+    epoch: Instant,  // The clock's monotonic time at open, which memtable ages count from
+    clock: Arc<StoreClock>,
     segments_l0: Vec<Arc<Segment>>,
     segments_l1: Vec<Arc<Segment>>,
     segments_l2: Vec<Arc<Segment>>,
+    segments_archive: Vec<Arc<Segment>>,  // Level 3: sealed prefixes' data, read last and never compacted
+    sealed: Vec<String>,  // Prefixes refusing writes, each ending in '/'
     subtombs: HashMap<String, u64>,
+    subtomb_size: usize,
+    subtomb_gc_at: usize,  // Next subtomb_size that triggers a GC pass
+    subscribers: Vec<Subscriber>,  // Told of every memtable change, in seq order, under the write lock
+    negative: Option<Mutex<NegativeCache>>,  // With StoreOptions::negative_lookup_cache
+    queue_heads: QueueHeads,
+}
+
+// The store's locks, in the only order a thread may take them: holding one, it may go on to
+// take those after it but never one before it or a second of the same rank. Everything else
+// (memtable shards and shard writers, the block and value caches, the value log, the vector
+// sidecar, the segment registry) is a leaf taken after these, with nothing ranked taken under it.
+//
+//   Compacting  one merge at a time, held through the merge's reads and writes
+//   Admin       flushes, compaction installs, segment drops, seals and close, one at a time
+//   Inner       the store state, read or write
+//   Unlisted    flushed segments waiting for their manifest entries
+//   Manifest
+//   WalBuffer   records waiting for the flusher
+//
+// A try_lock can't deadlock, so try_admin takes Admin under Inner. Debug builds check the
+// order on every blocking acquisition and panic on a violation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LockRank {
+    Compacting,
+    Admin,
+    Inner,
+    Unlisted,
+    Manifest,
+    WalBuffer,
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    // The ranked locks this thread holds, in the order it took them
+    static HELD_LOCKS: std::cell::RefCell<Vec<LockRank>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+// Panic if this thread holds a lock that must not be held while taking rank. The WAL buffer is
+// only checked, not recorded: nothing ranked is taken under it, and its guard goes through
+// Condvar waits that need it unwrapped.
+fn check_lock_order(rank: LockRank) {
+    #[cfg(debug_assertions)]
+    HELD_LOCKS.with(|held| {
+        if let Some(conflict) = held.borrow().iter().find(|&&other| other >= rank) {
+            panic!("Lock order violation: taking {:?} while holding {:?}", rank, conflict);
+        }
+    });
+    #[cfg(not(debug_assertions))]
+    let _ = rank;
+}
+
+// A guard for a ranked lock, which this thread holds until it drops
+struct Ranked<G> {
+    guard: G,
+    #[cfg_attr(not(debug_assertions), allow(dead_code))]
+    rank: LockRank,
+}
+
+impl<G> Ranked<G> {
+    // A lock already taken, by try_lock or after check_lock_order
+    fn held(rank: LockRank, guard: G) -> Self {
+        #[cfg(debug_assertions)]
+        HELD_LOCKS.with(|held| held.borrow_mut().push(rank));
+        Ranked { guard, rank }
+    }
+}
+
+// Check the order, then take the lock with take
+fn lock_ranked<G, E>(rank: LockRank, take: impl FnOnce() -> std::result::Result<G, E>) -> std::result::Result<Ranked<G>, E> {
+    check_lock_order(rank);
+    Ok(Ranked::held(rank, take()?))
+}
+
+impl<G> Drop for Ranked<G> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        HELD_LOCKS.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(i) = held.iter().rposition(|&rank| rank == self.rank) {
+                held.remove(i);
+            }
+        });
+    }
+}
+
+impl<G: std::ops::Deref> std::ops::Deref for Ranked<G> {
+    type Target = G::Target;
+    
+    fn deref(&self) -> &G::Target {
+        &self.guard
+    }
+}
+
+impl<G: std::ops::DerefMut> std::ops::DerefMut for Ranked<G> {
+    fn deref_mut(&mut self) -> &mut G::Target {
+        &mut self.guard
+    }
+}
+
+type InnerRead<'a> = Ranked<RwLockReadGuard<'a, StoreInner>>;
+type InnerWrite<'a> = Ranked<RwLockWriteGuard<'a, StoreInner>>;
+
+// An in-memory store's set_file blobs, compressed and keyed by hash as under blobs/
+type BlobMap = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+// What a point write holds: see Store::point_lock
+enum PointLock<'a> {
+    Store(InnerWrite<'a>),
+    Shard(InnerRead<'a>, MutexGuard<'a, ()>),
+}
+
+impl std::ops::Deref for PointLock<'_> {
+    type Target = StoreInner;
+    
+    fn deref(&self) -> &StoreInner {
+        match self {
+            PointLock::Store(inner) => inner,
+            PointLock::Shard(inner, _) => inner,
+        }
+    }
+}
+
+// The memtable keys whose first path segment hashes to one shard, so a key, its parent and
+// its children always share one
+#[derive(Debug, Default)]
+struct MemShard {
+    entries: BTreeMap<CollatedKey, MemValue>,
+    size: usize,
+    overwritten: u64,  // Newest seq of a record that replaced or was replaced by another of its key
+}
+
+impl MemShard {
+    fn get(&self, key: &str, collation: Collation) -> Option<&MemValue> {
+        self.entries.get(&(key, collation) as &dyn KeyView)
+    }
+}
+
+// The records of every shard between low and high, in key order
+fn mem_range<'a>(shards: &'a [RwLockReadGuard<'_, MemShard>], low: Bound<&dyn KeyView>, high: Bound<&dyn KeyView>) -> Vec<(&'a CollatedKey, &'a MemValue)> {
+    let mut records: Vec<_> = shards.iter().flat_map(|shard| shard.entries.range::<dyn KeyView, _>((low, high))).collect();
+    if shards.len() > 1 {
+        records.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    }
+    records
+}
+
+// A subscription's feed into its aggregator thread
+#[derive(Debug)]
+struct Subscriber {
+    prefix: String,
+    sender: mpsc::Sender<Change>,
+}
+
+// One memtable change as a subscriber sees it
+#[derive(Debug, Clone)]
+enum Change {
+    Key(String, Option<String>),
+    Subtree(String),
+}
+
+// A memtable key, ordered by the store's collation. Lookups borrow it as a KeyView so they
+// don't have to allocate one. Kept flat rather than as interned path segments: with keys
+// sharing 20 leading segments, interned ones made inserts about six times slower
+// (bench_deep_key_writes), as a memcmp over the shared part beats a pointer per segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CollatedKey {
+    key: String,
+    collation: Collation,
+}
+
+trait KeyView {
+    fn key(&self) -> &str;
+    fn collation(&self) -> Collation;
+}
+
+impl KeyView for CollatedKey {
+    fn key(&self) -> &str {
+        &self.key
+    }
+    
+    fn collation(&self) -> Collation {
+        self.collation
+    }
+}
+
+impl KeyView for (&str, Collation) {
+    fn key(&self) -> &str {
+        self.0
+    }
+    
+    fn collation(&self) -> Collation {
+        self.1
+    }
+}
+
+impl<'a> Borrow<dyn KeyView + 'a> for CollatedKey {
+    fn borrow(&self) -> &(dyn KeyView + 'a) {
+        self
+    }
+}
+
+impl PartialEq for dyn KeyView + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for dyn KeyView + '_ {}
+
+impl PartialOrd for dyn KeyView + '_ {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for dyn KeyView + '_ {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.collation().compare(self.key().as_bytes(), other.key().as_bytes())
+    }
+}
+
+impl PartialOrd for CollatedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CollatedKey {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (self as &dyn KeyView).cmp(other as &dyn KeyView)
+    }
+}
+
+// A memtable value. Short ones live in the entry itself, so holding them allocates nothing;
+// longer ones are an Arc<str> that get_ref and the *_ref scans hand out without copying.
+#[derive(Clone)]
+enum CompactStr {
+    Inline(u8, [u8; COMPACT_INLINE]),
+    Shared(Arc<str>),
+}
+
+impl CompactStr {
+    fn as_str(&self) -> &str {
+        match self {
+            // Only ever filled from a whole &str
+            CompactStr::Inline(len, bytes) => std::str::from_utf8(&bytes[..*len as usize]).unwrap_or_default(),
+            CompactStr::Shared(value) => value,
+        }
+    }
+    
+    // Free for a shared value; an inline one is copied out
+    fn to_arc(&self) -> Arc<str> {
+        match self {
+            CompactStr::Inline(..) => Arc::from(self.as_str()),
+            CompactStr::Shared(value) => value.clone(),
+        }
+    }
+}
+
+impl From<&str> for CompactStr {
+    fn from(value: &str) -> Self {
+        if value.len() > COMPACT_INLINE {
+            return CompactStr::Shared(Arc::from(value));
+        }
+        let mut bytes = [0; COMPACT_INLINE];
+        bytes[..value.len()].copy_from_slice(value.as_bytes());
+        CompactStr::Inline(value.len() as u8, bytes)
+    }
+}
+
+impl std::ops::Deref for CompactStr {
+    type Target = str;
+    
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Debug for CompactStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+// What a ranged read collects per key: a String, or for the *_ref reads an Arc<str> that
+// shares a memtable value instead of copying it
+trait RangeValue: Sized {
+    fn from_memtable(value: &CompactStr) -> Self;
+    fn from_segment(value: &str) -> Self;
+    fn from_string(value: String) -> Self;
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl RangeValue for String {
+    fn from_memtable(value: &CompactStr) -> Self {
+        value.to_string()
+    }
+    
+    fn from_segment(value: &str) -> Self {
+        value.to_string()
+    }
+    
+    fn from_string(value: String) -> Self {
+        value
+    }
+    
+    fn as_bytes(&self) -> &[u8] {
+        str::as_bytes(self)
+    }
+}
+
+impl RangeValue for Arc<str> {
+    fn from_memtable(value: &CompactStr) -> Self {
+        value.to_arc()
+    }
+    
+    fn from_segment(value: &str) -> Self {
+        Arc::from(value)
+    }
+    
+    fn from_string(value: String) -> Self {
+        Arc::from(value)
+    }
+    
+    fn as_bytes(&self) -> &[u8] {
+        str::as_bytes(self)
+    }
 }
 
 #[derive(Debug, Clone)]
 enum MemValue {
-    Scalar(String, u64),
+    Scalar(CompactStr, u64),
     PointTomb(u64),
 }
 
+impl MemValue {
+    // Estimated RAM held by a memtable entry; tombstones carry no value
+    fn footprint(&self, key_len: usize) -> usize {
+        match self {
+            MemValue::Scalar(v, _) => key_len + v.len() + MEMTABLE_ENTRY_OVERHEAD,
+            MemValue::PointTomb(_) => key_len + MEMTABLE_ENTRY_OVERHEAD,
+        }
+    }
+    
+    fn seq(&self) -> u64 {
+        match self {
+            MemValue::Scalar(_, seq) | MemValue::PointTomb(seq) => *seq,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct GroupCommitWAL {
     path: PathBuf,
-    buffer: Mutex<Vec<WALEntry>>,
+    memory: Option<Mutex<Vec<u8>>>,  // Store::open_in_memory: the log itself, in place of the file at path
+    flusher: bool,  // A background flusher syncs the buffer; without one every append syncs itself
+    buffer: Mutex<WalBuffer>,
     // sync_interval: Duration, // Currently using const GROUP_COMMIT_MS
     shutdown: Arc<(Mutex<bool>, Condvar)>,
+    durable_seq: AtomicU64,  // Highest seq fsynced to the WAL
+    durable_signal: (Mutex<()>, Condvar),
+    last_pass: Mutex<Instant>,  // When the background flusher last woke to sync, by clock, for health_check
+    clock: Arc<StoreClock>,
+    paused: AtomicBool,  // Store::pause_wal_flusher: the flusher wakes but skips its passes
+    degraded: AtomicBool,  // A WAL or flush write failed; writes are refused until a sync succeeds
+    flush_bytes: usize,  // Buffered bytes past which an append wakes the flusher
+    max_unflushed: usize,  // Buffered bytes past which an append waits for the flusher
+    flush_wanted: AtomicBool,  // An append asked for a pass before the flusher's interval is up
+    drained: Condvar,  // With buffer: notified whenever a sync empties it
+    throttled: AtomicU64,  // Appends that waited on max_unflushed
+    throttle_wait_us: AtomicU64,  // And how long they waited in all
+    syncs: AtomicU64,  // Group commits written and fsynced
+    sync_time_us: AtomicU64,  // And how long that took in all
+    io_hook: HookSlot,
+    events: EventSink,
 }
 
-#[derive(Debug)]
-struct WALEntry {
+// A record to log, borrowed from the write that makes it: append encodes it straight into
+// the buffer
+#[derive(Debug, Clone, Copy)]
+struct WALEntry<'a> {
     seq: u64,
     kind: u8,
-    key: String,
-    value: Option<String>,
+    key: &'a str,
+    value: Option<&'a str>,
+}
+
+// Records appended since the last sync, already encoded but not yet framed
+#[derive(Debug, Default)]
+struct WalBuffer {
+    data: Vec<u8>,
+    records: usize,
+    last_seq: Option<u64>,
+}
+
+impl WalBuffer {
+    fn push(&mut self, entry: &WALEntry) {
+        entry.encode(&mut self.data);
+        self.records += 1;
+        self.last_seq = Some(entry.seq);
+    }
+    
+    // The buffered entries as one RT_GROUP frame: length, then the newest seq, the kind, an
+    // empty key and the entry count ahead of the entries, then a CRC over all of that
+    fn encode_group(&self, out: &mut Vec<u8>) {
+        let Some(last_seq) = self.last_seq else {
+            return;
+        };
+        out.extend_from_slice(&((GROUP_HEADER + self.data.len()) as u32).to_le_bytes());
+        let start = out.len();
+        out.extend_from_slice(&last_seq.to_le_bytes());
+        out.push(RT_GROUP);
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&(self.records as u32).to_le_bytes());
+        out.extend_from_slice(&self.data);
+        let crc = crc32(&out[start..]);
+        out.extend_from_slice(&crc.to_le_bytes());
+    }
+    
+    fn clear(&mut self) {
+        self.data.clear();
+        self.records = 0;
+        self.last_seq = None;
+    }
 }
 
 #[derive(Debug)]
 struct Segment {
+    id: u64,
     path: PathBuf,
     // seq_low: u64,     // Not currently used but may be useful for compaction
     seq_high: u64,
     // key_count: usize, // Not currently used but may be useful for stats
     bloom: Option<BloomFilter>,
-    index: Vec<(String, u64)>,
+    index: SegmentIndex,
     index_start: u64,  // Offset where the index begins
+    front_coded: bool,
+    collation: Collation,  // Order of the keys in the blocks and index
+    archive_of: Option<String>,  // The sealed prefix an archive segment holds
+    value_refs: bool,  // Some records are RT_SET_REF, resolved through the value log
+    salvaged: bool,  // Index and bloom rebuilt from the records; compaction rewrites the file
+    bounds: Option<(String, String)>,  // Smallest and largest key, None if the manifest doesn't say
+    backing: SegmentBacking,
+}
+
+// Where a segment's bytes are read from
+#[derive(Debug)]
+enum SegmentBacking {
+    File(Mutex<Option<Arc<File>>>),  // Shared by every block read, opened on the first miss
+    InMemory(Arc<Vec<u8>>),  // Store::open_in_memory: the whole file, never written out
+}
+
+// Sparse block index kept as the bytes read from the file, with a table of where each
+// entry sits in them, so opening a segment costs two allocations however many blocks it has
+#[derive(Debug, Default)]
+struct SegmentIndex {
+    data: Vec<u8>,  // On-disk layout per entry: key length u32, block offset u64, key
+    entries: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    key_off: u32,
+    key_len: u32,  // A u16 would pad out to the same size and can't hold every valid key
+    block_off: u64,
 }
 
 #[derive(Debug)]
 struct Manifest {
-    path: PathBuf,
+    path: Option<PathBuf>,  // None for Store::open_in_memory, whose lines go nowhere
     entries: Vec<ManifestEntry>,
+    sealed: Vec<String>,  // As of the last seal or unseal line
+    history_floor: u64,  // The highest floor line: Store::as_of refuses seqs older than this
+    next_file: u64,  // Number for the next segment file, past every one named or handed out
+    subtombs: HashMap<String, u64>,  // Subtree deletes flushes have listed, newest seq per prefix
+    high_water: u64,  // The highest seq line: seqs up to it were handed out, whatever the WAL still holds
+    clock: u64,  // The highest clock line: the store had read the wall clock this many seconds after UNIX_EPOCH
+    dropped: Vec<String>,  // Files drop lines took out, as entries name them: open deletes any still there
+    file: Option<File>,  // Append handle, opened by the first entry added
+}
+
+// Where segment files are deleted. A segment taken out of the store (compacted away, sealed
+// over or dropped) waits in pending until the registry holds its last reference: a RangeIter
+// or Snapshot reading it holds another, and Windows refuses to delete a file still open. The
+// manifest already says each pending segment is gone, so open deletes any a crash leaves.
+#[derive(Debug, Default)]
+struct SegmentRegistry {
+    pending: Mutex<Vec<Arc<Segment>>>,
+    unlink_failed: Mutex<Vec<PathBuf>>,  // Closed, but deleting them failed; the next sweep tries again
+    in_memory: bool,  // Store::open_in_memory: there are no files to delete
+}
+
+// The store directory and StoreOptions::data_dirs. Manifest entries name a segment in the
+// store directory by its file name and one in data_dirs[i] as "d{i}/" and its file name.
+#[derive(Debug)]
+struct SegmentDirs {
+    store: PathBuf,
+    data: Vec<PathBuf>,
+    placement: SegmentPlacement,
 }
 
 #[derive(Debug, Clone)]
@@ -92,6 +831,30 @@ struct ManifestEntry {
     seq_high: u64,
     level: usize,
     filename: String,
+    archive_of: Option<String>,  // Level 3 entries: the prefix whose seal wrote the file
+    inputs: Vec<String>,  // Compaction outputs: the file names of the segments merged into it
+    bounds: Option<(String, String)>,  // Smallest and largest key, from its keys line
+}
+
+// Values deduplicated by StoreOptions::dedup_values_over, each stored once in values.log.
+// Segments name them by content hash in RT_SET_REF records; the WAL always holds them whole.
+#[derive(Debug)]
+struct ValueLog {
+    path: PathBuf,
+    file: Option<Arc<File>>,  // Read and append handle, created by the first value stored
+    index: HashMap<u64, (u64, u32)>,  // Content hash -> offset and length of the value bytes
+    len: u64,  // End of the last whole entry, where the next one goes
+    unsynced: bool,
+    marking: Option<HashSet<u64>>,  // While reclaim_values marks: every hash stored since it began
+}
+
+// Values read from the value log, by content hash. Nothing goes stale: a hash names the same
+// bytes for as long as any record refers to it.
+#[derive(Debug)]
+struct ValueCache {
+    values: RwLock<(HashMap<u64, Arc<str>>, usize)>,  // Values and their total size
+    max_size: usize,
+    lossy: bool,  // StoreOptions::lossy_reads
 }
 
 #[derive(Debug)]
@@ -101,2108 +864,11839 @@ struct BloomFilter {
     hash_count: usize,  // Number of entries in the hash index
 }
 
+// Cache key: segment id plus block offset
+type BlockKey = (u64, u64);
+
+// A block some thread is reading from disk; the others missing on it wait on the mutex
+type LoadSlot = Arc<Mutex<Option<Arc<Vec<u8>>>>>;
+
+// A get some thread is looking up in the segments; the others asking for that path wait on
+// the mutex. The outer Option is None until the lookup succeeds, then holds its answer and
+// the level that gave it.
+type GetSlot = Arc<Mutex<Option<(Option<String>, ReadLevel)>>>;
+
+// Keys the segments were recently found not to hold, least recently used evicted first. Gets
+// fill it under the read lock, hence its own mutex; writes clear their keys under the write
+// lock or their shard's, so an entry is never older than the last write that could have made
+// its key present.
+// StoreOptions::accounting_roots. Buckets claim a slot of a fixed table on their first write
+// and are counted in it with relaxed atomics, so the write path takes no lock.
+struct WriteAccounting {
+    roots: Vec<Vec<String>>,  // Each root's path segments, "*" matching any one
+    slots: Box<[BucketCounters]>,
+    overflow: Vec<BucketCounters>,  // One per root, named by it, for buckets the table has no room for
+    limit: Option<WriteRateLimit>,
+    limit_ops: u64,  // Writes over the whole window that exceed the limit
+    slice_nanos: u64,  // A tenth of the window
+    started: Instant,
+}
+
+#[derive(Default)]
+struct BucketCounters {
+    name: OnceLock<Box<str>>,
+    ops: AtomicU64,
+    bytes: AtomicU64,
+    slices: [AtomicU64; WINDOW_SLICES],  // Per tenth of the window: the tick it began at and its writes
+    alerted: AtomicU64,  // Tick of the last on_limit_exceeded call
+}
+
+// What gets cost, by the level that answered them. Every counter is a relaxed atomic, so a
+// get records itself with a handful of adds and no lock.
+#[derive(Debug, Default)]
+struct ReadAmplification {
+    levels: [LevelCounters; READ_LEVELS],  // By ReadLevel
+}
+
+#[derive(Debug, Default)]
+struct LevelCounters {
+    gets: AtomicU64,
+    costs: [CostCounters; 3],  // Segments probed, bloom negatives, blocks read
+}
+
+// Gets that cost nothing are left out of buckets: they are gets less the rest
+#[derive(Debug, Default)]
+struct CostCounters {
+    sum: AtomicU64,
+    buckets: [AtomicU64; READ_COST_BUCKETS],
+}
+
+// What one get cost, gathered as it looks through the segments
+#[derive(Debug, Clone, Copy)]
+struct ReadTrace {
+    level: ReadLevel,
+    probed: u64,
+    bloom_negatives: u64,
+    blocks: u64,
+}
+
+// The byte caps set_quota gave prefixes, each with the key and value bytes live under it
+#[derive(Debug, Default)]
+struct Quotas {
+    any: AtomicBool,  // Whether table has any, so writes skip its lock while none are set
+    table: Mutex<BTreeMap<String, Quota>>,  // By prefix, each with its trailing '/'
+}
+
+#[derive(Debug)]
+struct Quota {
+    max_bytes: u64,
+    used: Option<u64>,  // None until a write under the prefix counts it, after open or replicated changes
+}
+
+// What a write does to the bytes under quota: a key set to a value this long, a key deleted, or
+// every key under a prefix deleted
+enum QuotaOp<'a> {
+    Put(&'a str, usize),
+    Delete(&'a str),
+    Clear(&'a str),
+}
+
+// Bytes a write took from quotas before reaching the WAL, handed back on drop unless the write
+// commits
+struct QuotaCharge<'a> {
+    quotas: &'a Quotas,
+    charged: Vec<(String, Option<i64>)>,  // None where the quota hadn't been counted, so is again
+}
+
+// The checks register_validator gave prefixes. Process-local: nothing here is persisted.
+#[derive(Default)]
+struct Validators {
+    any: AtomicBool,  // Whether table has any, so writes skip its lock while none are set
+    table: Mutex<BTreeMap<String, Arc<ValidatorFn>>>,  // By prefix, each with its trailing '/'
+}
+
+type ValidatorFn = dyn Fn(&str, &str) -> std::result::Result<(), String> + Send + Sync;
+
+// Where pop_min starts looking under each prefix it has popped from: no key under the prefix
+// that sorts before its head is live. A set of such a key lowers the head to it.
+#[derive(Debug, Default)]
+struct QueueHeads {
+    any: AtomicBool,  // Whether table has any, so writes skip its lock while none are kept
+    table: Mutex<HashMap<String, String>>,  // By prefix, each with its trailing '/'
+}
+
+// StoreOptions::clock, and the latest wall time read from it. Everything the store timestamps
+// takes the later of the two, so a clock set back can't date anything before what came earlier.
+struct StoreClock {
+    source: Arc<dyn Clock>,
+    seen: AtomicU64,  // Microseconds after UNIX_EPOCH: the latest wall time read, or the manifest's clock line
+    behind: AtomicBool,  // ClockWentBackwards was sent and the clock hasn't caught up since
+    events: EventSink,
+}
+
+#[derive(Debug)]
+struct NegativeCache {
+    capacity: usize,
+    keys: HashMap<String, u64>,  // Key to the tick it was last used at
+    order: BTreeMap<u64, String>,  // The same, oldest first
+    tick: u64,
+}
+
+// Blocks point reads use go straight into the main region. Blocks scans read go into a small
+// probationary one, oldest out first, and only move to the main region if read again while
+// there, so one pass over the whole store can't push out the blocks point reads keep using.
 #[derive(Debug)]
 struct BlockCache {
-    cache: RwLock<HashMap<(PathBuf, u64), Arc<Vec<u8>>>>,
+    cache: RwLock<HashMap<BlockKey, Arc<Vec<u8>>>>,  // Main region
     size: RwLock<usize>,
     max_size: usize,
+    probation: Mutex<Probation>,
+    pinned: RwLock<HashMap<BlockKey, Arc<Vec<u8>>>>,  // Blocks warmup pinned, outside max_size and never evicted
+    pinned_size: AtomicUsize,
+    loading: Mutex<HashMap<BlockKey, LoadSlot>>,
+    events: EventSink,
+    hits: AtomicU64,  // Reads served without going to disk, for cache_stats
+    misses: AtomicU64,
 }
 
-impl Drop for Store {
-    fn drop(&mut self) {
-        // Signal shutdown to background threads
-        let (lock, cvar) = &*self.wal.shutdown;
-        let mut shutdown = lock.lock().expect("WAL shutdown lock should not be poisoned");
-        *shutdown = true;
-        cvar.notify_all();
-        
-        // Signal compaction thread shutdown
-        let (comp_lock, comp_cvar) = &*self.compaction_shutdown;
-        let mut comp_shutdown = comp_lock.lock().expect("Compaction shutdown lock should not be poisoned");
-        *comp_shutdown = true;
-        comp_cvar.notify_all();
-        
-        // Sync any remaining WAL entries
-        let _ = self.wal.sync_now();
-    }
+#[derive(Debug, Default)]
+struct Probation {
+    blocks: HashMap<BlockKey, Arc<Vec<u8>>>,
+    order: VecDeque<BlockKey>,  // Oldest first; may name blocks already promoted out
+    size: usize,
+    max_size: usize,
 }
 
-impl Store {
-    pub fn open(dir: &Path) -> io::Result<Self> {
-        fs::create_dir_all(dir)?;
-        
-        let wal_path = dir.join("wal.log");
-        let manifest_path = dir.join("manifest.log");
-        
-        // Load manifest
-        let manifest = Arc::new(Mutex::new(Manifest::load(&manifest_path)?));
-        
-        // Create WAL with background flusher
-        let wal = Arc::new(GroupCommitWAL::new(&wal_path)?);
-        
-        // Start background WAL flusher thread
-        let wal_clone = wal.clone();
-        thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::from_millis(GROUP_COMMIT_MS));
-                // Silently ignore sync errors - WAL will retry on next interval
-                let _ = wal_clone.sync_now();
-                
-                let shutdown = wal_clone.shutdown.0.lock().expect("WAL shutdown lock should not be poisoned");
-                if *shutdown {
-                    break;
-                }
-            }
-        });
+// Who a block read is for, which decides where BlockCache admits the block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockAccess {
+    Point,
+    Scan,
+}
+
+// Paces one merge's reads and writes to CompactionOptions::max_bytes_per_sec. With a shutdown
+// signal the pauses wake on it and the merge gives up, so dropping the store doesn't wait out
+// the budget.
+struct IoThrottle<'a> {
+    bytes_per_sec: Option<u64>,
+    shutdown: Option<&'a (Mutex<bool>, Condvar)>,
+    started: Instant,
+    bytes: u64,
+}
+
+// Columnar copy of every vector value in the store: fixed-width f32 rows in vectors.dat.
+// Derived from the primary records, so it is rebuilt whenever it is missing or invalid.
+#[derive(Debug)]
+struct VectorSidecar {
+    path: Option<PathBuf>,  // None for Store::open_in_memory, which keeps only the rows
+    file: Option<File>,  // Append handle, created on the first rewrite
+    dims: usize,         // Row width, fixed by the first vector stored (0 while empty)
+    data: Vec<f32>,      // Row-major, one row per put record in file order
+    rows: Vec<VectorRow>,
+    index: BTreeMap<String, usize>,  // Live path -> row
+    max_seq: u64,
+}
+
+#[derive(Debug)]
+struct VectorRow {
+    seq: u64,
+    unit: bool,
+}
+
+/// Options for `Store::open_with_options`
+#[derive(Clone, Default)]
+pub struct StoreOptions {
+    /// Open anyway when a manifest-listed segment can't be read, recording it in the report
+    pub skip_unreadable_segments: bool,
+    /// Receives debug messages, such as files in the directory that aren't part of the store
+    pub debug_log: Option<DebugLog>,
+    /// Limits on how hard compaction may drive the disk
+    pub compaction: CompactionOptions,
+    /// Size past which flushes, merges and rebuild_into start another segment file, None for
+    /// 256MB. Keeps each file's index, which open reads whole, and each bloom filter small.
+    pub max_segment_size: Option<u64>,
+    /// Receives background activity and failures that have no caller to return an error to.
+    /// Runs on whichever thread hit the event, sometimes with store locks held, so it must not
+    /// call back into the store.
+    pub on_event: Option<EventHandler>,
+    /// Make set and set_many drop writes whose value is byte-identical to the live one,
+    /// so rewriting unchanged data costs a lookup instead of a WAL record and a seq
+    pub skip_identical_sets: bool,
+    /// Let concurrent gets of one path that miss the memtable share a single segment lookup.
+    /// Saves bloom probes and block parsing on hot keys, but a get may wait on another's, and
+    /// where a lookup is one cached block the bookkeeping costs more than it saves.
+    pub coalesce_point_gets: bool,
+    /// Leave the fsync of a memtable flush's manifest entry to the WAL flusher's next pass
+    /// instead of making the write that triggered the flush wait for it. An entry lost to a
+    /// crash only costs a longer WAL replay on reopen. Compaction entries are always synced
+    /// before their inputs are deleted.
+    pub defer_manifest_syncs: bool,
+    /// How keys sort in scans and on disk. Segments record it, so a store that has flushed
+    /// must be reopened with the collation it was written with.
+    pub collation: Collation,
+    /// Store values longer than this many bytes once, in values.log, and have segments refer
+    /// to them by content hash, so keys sharing a large value share its bytes. The WAL still
+    /// logs every value whole. reclaim_values drops the ones nothing refers to any more.
+    /// Stores that have deduplicated read their references whatever this says.
+    pub dedup_values_over: Option<usize>,
+    /// Checkpoint the WAL at open once replaying it read more than this many bytes: the
+    /// replayed memtable is flushed to a segment and the log rewritten down to the live
+    /// subtree deletes. Without it the WAL only grows, and a process that never writes a
+    /// memtable's worth before exiting replays its whole history on every open.
+    pub replay_flush_threshold: Option<u64>,
+    /// Remember up to this many keys that gets and contains found in no segment, and answer
+    /// them again without probing blooms or reading blocks. Worth it where the same missing or
+    /// deleted keys are asked for over and over; any write to a key forgets it.
+    pub negative_lookup_cache: Option<usize>,
+    /// Split the memtable into this many shards, a power of two, by a hash of each key's
+    /// first path segment. Sets, deletes and increments then take the store lock shared and
+    /// only their shard's exclusively, so writers to different shards run side by side.
+    /// Batches, subtree deletes, moves and every write while a subscription is open still
+    /// take the whole store.
+    pub memtable_shards: Option<usize>,
+    /// Asked before each WAL sync and each block a memtable flush writes, which then fails
+    /// with whatever error it returns. For rehearsing a full or failing disk in tests.
+    pub io_hook: Option<IoHook>,
+    /// More directories segment files may go in, on other disks say, as placement decides.
+    /// The WAL, manifest and value log stay in the store directory. Open looks for each
+    /// segment where the manifest recorded it and then in every directory, so files moved
+    /// between them, or a list given in another order, still open.
+    pub data_dirs: Vec<PathBuf>,
+    /// Which directory each new segment file goes in
+    pub placement: SegmentPlacement,
+    /// Count writes per bucket for Store::prefix_write_stats. Each root is a path in which
+    /// `*` stands for any one segment, and a key under it counts toward the bucket named by
+    /// its first that many segments: with `tenants/*`, `tenants/acme/users/1` counts toward
+    /// `tenants/acme`. A key counts in the first root it matches, or nowhere. Past 4096
+    /// buckets, new ones count toward the root itself.
+    pub accounting_roots: Vec<String>,
+    /// Report accounting buckets writing faster than this
+    pub write_rate_limit: Option<WriteRateLimit>,
+    /// Wake the WAL flusher for a group commit once this many bytes of records are buffered,
+    /// rather than leaving them for its next pass. None for 64 KiB.
+    pub wal_flush_bytes: Option<usize>,
+    /// Make writes wait for the flusher while this many bytes of records are buffered, which
+    /// bounds the buffer's memory through a burst without any writer syncing the WAL itself.
+    /// The waits are counted in Store::wal_stats. None for 16 MiB; never less than
+    /// wal_flush_bytes.
+    pub max_unflushed_bytes: Option<usize>,
+    /// Refuse every write with WalDbError::Replica except apply_replicated's, which logs its
+    /// primary's changes under the primary's seqs. A local write would take seqs the primary
+    /// hands out too.
+    pub replica: bool,
+    /// Have Store::warmup pin the blocks it loads of any L2 segment small enough to fit whole
+    /// in what is left of pin_budget, so later reads can't evict them. Indexes and blooms are
+    /// always in memory; this keeps the data of the small, hot segments beside them. Pinned
+    /// blocks go when compaction retires their segment.
+    pub pin_indexes: bool,
+    /// Bytes of blocks pin_indexes may pin, on top of the block cache. None for a quarter of
+    /// the cache's size.
+    pub pin_budget: Option<usize>,
+    /// Have get, get_ref, contains and the prefix scans fail with WalDbError::TreeSemantics,
+    /// naming the ancestor, when they find nothing because an ancestor of the path holds a
+    /// scalar. Tree semantics keep anything from being stored there, so such a read is almost
+    /// always a wrongly built path. Only misses pay for the check, a contains per ancestor.
+    pub strict_reads: bool,
+    /// Read keys and values that aren't UTF-8 with replacement characters, as WalDB once did,
+    /// instead of failing with WalDbError::Corruption for a key or WalDbError::InvalidUtf8 for
+    /// a value. For getting data out of a damaged store: two keys that differ only in their
+    /// bad bytes read as one.
+    pub lossy_reads: bool,
+    /// Flush the memtable to an L0 segment once its first record is this old, however small
+    /// it is, so a store whose writes stop short of a full memtable doesn't keep them only in
+    /// the WAL, to be replayed on every open. The compaction thread checks every 5 seconds, or
+    /// every max_age if that is shorter, so a memtable may reach up to twice the age. None for
+    /// 5 minutes.
+    pub memtable_max_age: Option<Duration>,
+    /// Have a background thread append a MetricsSnapshot to stats.ring in the store
+    /// directory this often, for Store::stats_history to read back after an incident. It
+    /// reads only counters, never waits on a write, and skips a sample it fails to write.
+    /// The ring is history, not data: rebuild_into doesn't copy it, and open starts a
+    /// damaged one afresh. None, the default, writes nothing.
+    pub stats_interval: Option<Duration>,
+    /// Size of stats.ring, whose oldest samples are overwritten once it is full. A ring of
+    /// another size is started afresh. None for 4 MiB, 32768 samples: nearly four days at
+    /// one every 10 seconds.
+    pub stats_ring_bytes: Option<u64>,
+    /// Delay each memtable flush while L0 holds at least this many segments, by 10ms for
+    /// each one from here on, up to 100ms, sending StoreEvent::L0Slowdown. Every read probes
+    /// every L0 segment, so this slows the writer filling them before reads slow down. The
+    /// delay is taken outside the store lock. None, the default, never delays.
+    pub l0_slowdown_threshold: Option<usize>,
+    /// Hold a memtable flush, and with it whichever writers find the memtable full, while L0
+    /// holds at least this many segments, sending StoreEvent::L0Stalled, until compaction
+    /// brings it below. A flush that finds the store closing or refusing writes stops waiting.
+    /// At least 4, where compaction starts; None, the default, never holds one.
+    pub l0_stop_threshold: Option<usize>,
+    /// Run the WAL flusher and compaction threads in a store from Store::open_in_memory, as
+    /// a store in a directory always does. Without them each write is logged as it is made,
+    /// and segments are only compacted by Store::compact.
+    pub background_threads: bool,
+    /// Where the store reads the time, None for SystemClock. For tests that step the clock.
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
+/// Where new segment files go, for StoreOptions::placement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SegmentPlacement {
+    /// All of them in the store directory, beside the WAL
+    #[default]
+    StoreDir,
+    /// Flushed L0 segments in `l0` and compaction output and archives, the large and rarely
+    /// rewritten files, in `cold`. Each is an index into data_dirs, or None for the store
+    /// directory.
+    Tiered { l0: Option<usize>, cold: Option<usize> },
+    /// Each in whichever of the store directory and data_dirs holds the fewest bytes of
+    /// segments. The standard library can't ask a filesystem for its free space, so this
+    /// evens out what the store has written rather than what is left.
+    Balanced,
+}
+
+/// Key order for StoreOptions::collation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Collation {
+    /// Byte order, which is str order: users/10 sorts before users/9
+    #[default]
+    Binary,
+    /// Paths compared one segment at a time, with runs of digits compared by value, so
+    /// users/9 sorts before users/10. Segments equal but for leading zeros fall back to
+    /// byte order. Everything under a path still sorts together.
+    NumericAware,
+}
+
+pub type EventHandler = Arc<dyn Fn(StoreEvent) + Send + Sync>;
+
+/// What `StoreOptions::on_event` is told about
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StoreEvent {
+    /// A merge into `level` began with this many input segments
+    CompactionStarted { level: usize, inputs: usize },
+    /// The merge into `level` was installed, having written a file of `bytes_written`
+    CompactionFinished { level: usize, duration: Duration, bytes_written: u64 },
+    /// A background compaction or salvaged-segment rewrite failed; the inputs stay in use
+    CompactionFailed { error: WalDbError },
+    /// Compacted-away segment files couldn't be deleted; the next pass tries again
+    SegmentRemoveFailed { error: WalDbError },
+    /// A group commit couldn't write or fsync the WAL. The entries stay buffered and the
+    /// flusher tries again after `retry_in`, or never if the store is closing.
+    WalSyncFailed { error: io::Error, retry_in: Option<Duration> },
+    /// Store::open skipped a segment it couldn't read (skip_unreadable_segments)
+    SegmentOpenFailed { path: PathBuf, error: WalDbError },
+    /// The memtable, `bytes` of it, was written out as an L0 segment
+    MemtableFlushed { bytes: usize, duration: Duration },
+    /// The compaction thread's flush of a memtable past StoreOptions::memtable_max_age failed.
+    /// The records stay in the memtable and the next check tries again.
+    MemtableFlushFailed { error: WalDbError },
+    /// A segment's shared read handle was opened, on the first cache miss in it. Block reads
+    /// reuse the handle for as long as the segment is in use, so this comes once per file.
+    SegmentReaderOpened { path: PathBuf },
+    /// The WAL flusher couldn't fsync deferred manifest entries (defer_manifest_syncs). It
+    /// tries again on its next pass.
+    ManifestSyncFailed { error: WalDbError },
+    /// A block missed the cache and was read from the segment at `path`
+    BlockLoaded { path: PathBuf, offset: u64, bytes: usize },
+    /// A WAL sync or memtable flush failed to write, so the store refuses writes with
+    /// WalDbError::StorageFull. The flusher keeps retrying the WAL in the background.
+    StorageDegraded { error: io::Error },
+    /// A WAL sync succeeded after StorageDegraded, and writes are accepted again
+    StorageRecovered,
+    /// Store::open finished. The last event open sends, so a handler shared by several
+    /// stores can tell them apart.
+    Opened { identity: StoreIdentity },
+    /// The stats sampler (StoreOptions::stats_interval) couldn't open stats.ring, and stopped,
+    /// or couldn't write a sample to it, which it skipped
+    StatsWriteFailed { error: io::Error },
+    /// A memtable flush was delayed by `delay`, as L0 held `segments` segments, at least
+    /// StoreOptions::l0_slowdown_threshold
+    L0Slowdown { segments: usize, delay: Duration },
+    /// A memtable flush found `segments` L0 segments, at least
+    /// StoreOptions::l0_stop_threshold, and waits for compaction to bring L0 below it
+    L0Stalled { segments: usize },
+    /// Store::open found wal.log missing, though the manifest shows the store wrote to one
+    /// (OpenReport::missing_wal). Writes take seqs after `resumed_at`, past any it held.
+    WalMissing { resumed_at: u64 },
+    /// The wall clock read `wall`, more than a second before `last_seen`, the latest time the
+    /// store had read from it in this process or recorded in the manifest. Until it catches
+    /// up, Store::now and everything the store timestamps keep to last_seen. Sent once each
+    /// time the clock falls behind.
+    ClockWentBackwards { wall: SystemTime, last_seen: SystemTime },
+    /// A write to vectors.dat failed after the batch it mirrors was logged. The batch applied
+    /// anyway; the sidecar carries on in memory only, and the next open rebuilds the file.
+    VectorSidecarFailed { error: io::Error },
+}
+
+// StoreOptions::on_event, if any
+#[derive(Clone, Default)]
+struct EventSink(Option<EventHandler>);
+
+// StoreOptions::io_hook, if any
+#[derive(Clone, Default)]
+struct HookSlot(Option<IoHook>);
+
+impl fmt::Debug for HookSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HookSlot({})", if self.0.is_some() { "set" } else { "none" })
+    }
+}
+
+impl fmt::Debug for EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventSink({})", if self.0.is_some() { "set" } else { "none" })
+    }
+}
+
+/// Compaction settings for `StoreOptions`
+#[derive(Debug, Clone, Default)]
+pub struct CompactionOptions {
+    /// Budget for the bytes compaction reads and writes per second, None or 0 for no limit.
+    /// Compaction pauses between blocks to stay under it, leaving the disk to foreground reads.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Size past which a merge into L2 starts another segment, None for 64MB. The output is
+    /// cut between keys, so each L2 file holds one key range that compact_range can rewrite
+    /// without the rest.
+    pub target_segment_bytes: Option<u64>,
+}
+
+pub type DebugLog = Arc<dyn Fn(&str) + Send + Sync>;
+
+pub type IoHook = Arc<dyn Fn(WriteSite, &Path) -> io::Result<()> + Send + Sync>;
+
+/// The file write StoreOptions::io_hook is asked about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WriteSite {
+    /// Writing and fsyncing buffered entries to wal.log
+    Wal,
+    /// Writing a block, or the index and footer, of a memtable flush's segment
+    Segment,
+}
+
+/// Where a store reads the time, for StoreOptions::clock
+pub trait Clock: Send + Sync {
+    /// The wall-clock time, which steps backwards when the system clock is set back
+    fn wall(&self) -> SystemTime;
+    /// A time that never goes backwards, for measuring how long something took
+    fn monotonic(&self) -> Instant;
+}
+
+/// The operating system's clocks: SystemTime::now and Instant::now
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+    
+    fn monotonic(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl fmt::Debug for StoreOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StoreOptions")
+            .field("skip_unreadable_segments", &self.skip_unreadable_segments)
+            .field("debug_log", &self.debug_log.is_some())
+            .field("compaction", &self.compaction)
+            .field("on_event", &self.on_event.is_some())
+            .field("skip_identical_sets", &self.skip_identical_sets)
+            .finish()
+    }
+}
+
+/// What `Store::open_with_options` found in the directory besides the store itself
+#[derive(Debug, Clone, Default)]
+pub struct OpenReport {
+    /// Manifest-listed segments that couldn't be read, with the reason (skip_unreadable_segments only)
+    pub skipped_segments: Vec<(PathBuf, String)>,
+    /// Segments whose footer was damaged, served from the records that parsed until compaction rewrites them
+    pub salvaged_segments: Vec<PathBuf>,
+    /// Files not referenced by the store, left untouched
+    pub ignored_files: Vec<PathBuf>,
+    /// This open wrote the IDENTITY file: the store is new, or older than identity files
+    pub created_identity: bool,
+    /// Segments a later compaction replaced or drop_segment took out whose files were still
+    /// there, now removed
+    pub dropped_segments: Vec<String>,
+    /// Segments no newer than one on a higher level that doesn't list them as merged into it,
+    /// each with that segment. Reads are still right, but compaction may merge their records
+    /// twice; `Store::drop_segment` removes one known to be redundant. Named as the manifest
+    /// records them.
+    pub overlapping_segments: Vec<(String, String)>,
+    /// wal.log was gone though the manifest shows the store wrote to one. Writes only it held
+    /// are lost, and seqs carry on SEQ_SAFETY_GAP (1000) past the newest the store knows of.
+    pub missing_wal: bool,
+}
+
+/// Who a store is, from its IDENTITY file. See `Store::identity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreIdentity {
+    /// Random when the file is written, so two directories sharing one are copies
+    pub id: StoreId,
+    /// When the file was written: at creation, or on the first open of an older store
+    pub created: SystemTime,
+    /// On-disk format version. Open refuses a store whose version is newer than this build's.
+    pub format_version: u32,
+}
+
+/// A store's random 128-bit id, displayed as a version 4 UUID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StoreId(pub u128);
+
+impl fmt::Display for StoreId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = format!("{:032x}", self.0);
+        write!(f, "{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+    }
+}
+
+/// From `Store::wal_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalStats {
+    /// Size of wal.log, not counting the records waiting for the next group commit
+    pub bytes: u64,
+    /// Records waiting for the next group commit
+    pub buffered: usize,
+    /// Bytes those records take up
+    pub buffered_bytes: usize,
+    /// Writes that waited for the flusher because StoreOptions::max_unflushed_bytes were
+    /// buffered, counted since open
+    pub throttled_writes: u64,
+    /// How long they waited in all
+    pub throttle_wait: Duration,
+    /// Records open read from the WAL, including those it skipped as already flushed
+    pub replayed_records: u64,
+    /// Bytes of WAL open read
+    pub replayed_bytes: u64,
+    /// Whether open checkpointed the WAL, per StoreOptions::replay_flush_threshold
+    pub checkpointed: bool,
+}
+
+/// From `Store::memtable_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemtableStats {
+    /// Records the memtable holds
+    pub entries: usize,
+    /// Their accounted size, which flushes the memtable once it passes 256 KiB
+    pub bytes: usize,
+    /// How long since its first record, None while it is empty. Past
+    /// StoreOptions::memtable_max_age the compaction thread flushes it.
+    pub age: Option<Duration>,
+}
+
+/// From `Store::lookup_stats`, counted since open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LookupStats {
+    /// Segment blocks gets and contains searched, whether from the block cache or disk
+    pub blocks_read: u64,
+    /// Gets and contains answered by StoreOptions::negative_lookup_cache
+    pub negative_cache_hits: u64,
+}
+
+/// The level whose record answered a get, in `Store::read_amplification_report`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReadLevel {
+    Memtable,
+    L0,
+    L1,
+    L2,
+    /// A sealed prefix's archive segments
+    Archive,
+    /// No record of the key anywhere, the negative lookup cache's answers included
+    NotFound,
+}
+
+impl ReadLevel {
+    const ALL: [ReadLevel; READ_LEVELS] = [ReadLevel::Memtable, ReadLevel::L0, ReadLevel::L1, ReadLevel::L2, ReadLevel::Archive, ReadLevel::NotFound];
+}
+
+impl fmt::Display for ReadLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReadLevel::Memtable => "memtable",
+            ReadLevel::L0 => "L0",
+            ReadLevel::L1 => "L1",
+            ReadLevel::L2 => "L2",
+            ReadLevel::Archive => "archive",
+            ReadLevel::NotFound => "not found",
+        })
+    }
+}
+
+/// One cost of a get, over the gets a LevelReads counts
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReadCost {
+    pub mean: f64,
+    /// Exact up to 15; past that, the power of two below the 99th percentile
+    pub p99: u64,
+}
+
+/// What the gets answered at one level cost
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LevelReads {
+    pub level: ReadLevel,
+    pub gets: u64,
+    /// Segments whose key range and seqs left them to be asked, whether or not their bloom
+    /// filter then ruled the key out
+    pub segments_probed: ReadCost,
+    /// Probed segments their bloom filter ruled out. Close to segments_probed less
+    /// blocks_read, or blooms are letting too much through.
+    pub bloom_negatives: ReadCost,
+    /// Segment blocks searched, whether from the block cache or disk; cache_stats tells which
+    pub blocks_read: ReadCost,
+}
+
+/// From `Store::read_amplification_report`, counted since open
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadAmplificationReport {
+    /// A row for each level that answered a get, memtable first and NotFound last
+    pub levels: Vec<LevelReads>,
+}
+
+/// Limits on what one `Store::get_with_budget` may cost. None leaves that cost unbounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadBudget {
+    /// Segment blocks the get may read from disk; blocks already in the block cache are free
+    pub max_block_reads: Option<u64>,
+    /// Segments the get may probe, counted as LevelReads::segments_probed counts them
+    pub max_segments: Option<u64>,
+}
+
+/// From `Store::search_stats`, counted since open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SearchStats {
+    /// Groups searches have read the fields of, matched or not. An unscored search with a
+    /// limit stops once it has enough matches; any other reads every group its pattern matches.
+    pub groups_visited: u64,
+}
+
+/// From `Store::compaction_stats`, counted since open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Bytes of segment blocks merges have read from their inputs
+    pub bytes_read: u64,
+    /// Bytes of segment files merges have written
+    pub bytes_written: u64,
+}
+
+/// From `Store::cache_stats`, counted since open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Segment block reads the block cache answered, including ones that waited on another
+    /// read's load of the same block
+    pub hits: u64,
+    /// Segment block reads that went to disk. Compaction and warmup reads bypass the count.
+    pub misses: u64,
+    /// Bytes of blocks the cache holds now, pinned ones included
+    pub resident_bytes: u64,
+    /// Bytes of blocks Store::warmup pinned under StoreOptions::pin_indexes
+    pub pinned_bytes: u64,
+}
+
+impl CacheStats {
+    /// Hits as a share of all block reads, 0 before any
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+/// The store's counters at one moment, from `Store::metrics` or, as
+/// StoreOptions::stats_interval recorded them, `Store::stats_history`. Counts and times run
+/// from open, so they start again from 0 after a reopen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub seq: u64,
+    pub durable_seq: u64,
+    pub flushed_seq: u64,
+    /// Accounted size of the memtable, as in MemtableStats
+    pub memtable_bytes: u64,
+    /// Memtables written out as L0 segments, and how long that took in all
+    pub flushes: u64,
+    pub flush_time: Duration,
+    /// Merges compaction installed, how long they took in all and what they wrote
+    pub compactions: u64,
+    pub compaction_time: Duration,
+    pub compaction_bytes_written: u64,
+    /// Group commits written and fsynced to the WAL, and how long that took in all
+    pub wal_syncs: u64,
+    pub wal_sync_time: Duration,
+    /// As in CacheStats
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+/// A sample from `Store::stats_history`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampedSnapshot {
+    pub at: SystemTime,
+    pub metrics: MetricsSnapshot,
+}
+
+/// How close L0 is to StoreOptions::l0_slowdown_threshold and l0_stop_threshold
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum L0Pressure {
+    /// Below both, or neither is set
+    #[default]
+    Normal,
+    /// At or past l0_slowdown_threshold: flushes are delayed
+    Slowdown,
+    /// At or past l0_stop_threshold: flushes wait for compaction
+    Stopped,
+}
+
+impl fmt::Display for L0Pressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            L0Pressure::Normal => "normal",
+            L0Pressure::Slowdown => "slowdown",
+            L0Pressure::Stopped => "stopped",
+        })
+    }
+}
+
+/// From `Store::l0_stats`. The counts and time run from open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct L0Stats {
+    /// L0 segments now
+    pub segments: usize,
+    pub pressure: L0Pressure,
+    /// Flushes delayed by l0_slowdown_threshold
+    pub slowdowns: u64,
+    /// Flushes held by l0_stop_threshold, and how long they waited in all
+    pub stalls: u64,
+    pub stall_time: Duration,
+}
+
+/// What `Store::warmup` reads into the block cache
+#[derive(Debug, Clone, Default)]
+pub struct WarmupOptions {
+    /// Levels whose segments to read, 0 to 2 and 3 for sealed prefixes' archives. Every level
+    /// when empty.
+    pub levels: Vec<usize>,
+    /// Read only the blocks that may hold keys under these. Whole segments when empty.
+    pub prefixes: Vec<String>,
+    /// Stop before loading more than this many bytes. The block cache's size caps it too, as
+    /// past that the later blocks would evict the earlier ones.
+    pub max_bytes: u64,
+}
+
+/// From `Store::warmup`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WarmupReport {
+    /// Bytes of blocks read into the cache, not counting ones it held already
+    pub bytes_loaded: u64,
+    pub blocks_loaded: u64,
+    /// Bytes of those pinned under StoreOptions::pin_indexes
+    pub bytes_pinned: u64,
+    /// False if the budget ran out before every block asked for was loaded
+    pub complete: bool,
+}
+
+/// One bucket's writes, from `Store::prefix_write_stats` or `WriteRateLimit::on_limit_exceeded`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefixWriteStats {
+    /// Sets, increments and deletes, and set_many entries and batch ops, since open or the
+    /// last reset_prefix_write_stats
+    pub ops: u64,
+    /// Key and value bytes those writes carried
+    pub bytes: u64,
+    /// Writes in the last WriteRateLimit::window, or 0 without a write_rate_limit
+    pub window_ops: u64,
+}
+
+pub type LimitHandler = Arc<dyn Fn(&str, PrefixWriteStats) + Send + Sync>;
+
+/// A soft write rate for `StoreOptions::write_rate_limit`. Nothing is refused; throttling a
+/// bucket that goes over is up to the handler.
+#[derive(Clone)]
+pub struct WriteRateLimit {
+    /// Writes per second a bucket may average over the window
+    pub ops_per_sec: u64,
+    /// How far back the rate looks. It slides a tenth of this at a time.
+    pub window: Duration,
+    /// Told the bucket and its stats while it is over the limit, at most once per tenth of
+    /// the window. Runs on the writing thread with store locks held, so it must not call back
+    /// into the store.
+    pub on_limit_exceeded: LimitHandler,
+}
+
+impl fmt::Debug for WriteRateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteRateLimit")
+            .field("ops_per_sec", &self.ops_per_sec)
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+/// What `Store::seal_prefix` copied into the archive
+#[derive(Debug, Clone, Default)]
+pub struct SealReport {
+    /// The sealed prefix, with the trailing '/' it is matched by
+    pub prefix: String,
+    /// Live keys copied
+    pub keys: usize,
+    /// Size of the archive files written
+    pub bytes: u64,
+    /// The archive files, named as the manifest records them. None if nothing was live.
+    pub segments: Vec<String>,
+}
+
+/// A quota set by `Store::set_quota`, as `Store::quota_usage` reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaUsage {
+    /// Key and value bytes live under the prefix, as prefix_size_exact counts them
+    pub used_bytes: u64,
+    pub max_bytes: u64,
+}
+
+/// A check for `Store::register_validator`, called with the key and value being set. An Err
+/// refuses the value, and its text becomes the reason in WalDbError::ValidationFailed.
+pub type ValueValidator = Box<ValidatorFn>;
+
+/// Options for `Store::rebuild_into`
+#[derive(Debug, Clone, Default)]
+pub struct RebuildOptions {
+    pub mode: RebuildMode,
+    /// How the copy is opened. Its collation is always the source's.
+    pub store: StoreOptions,
+}
+
+/// What happens to writes while `Store::rebuild_into` copies a store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RebuildMode {
+    /// The source refuses writes with WalDbError::ReadOnly from the start
+    #[default]
+    RejectWrites,
+    /// The source takes writes throughout, and the copy is caught up with them at the end.
+    /// Writes that land after that need `Store::catch_up_from` before switching over.
+    CatchUp,
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        // Waits out a flush or compaction install under way
+        let _admin = self.admin_lock();
+        
+        // Signal shutdown to background threads
+        let (lock, cvar) = &*self.wal.shutdown;
+        let mut shutdown = lock.lock().expect("WAL shutdown lock should not be poisoned");
+        *shutdown = true;
+        cvar.notify_all();
+        
+        // Signal compaction thread shutdown
+        let (comp_lock, comp_cvar) = &*self.compaction_shutdown;
+        let mut comp_shutdown = comp_lock.lock().expect("Compaction shutdown lock should not be poisoned");
+        *comp_shutdown = true;
+        comp_cvar.notify_all();
+        
+        // Sync any remaining WAL entries
+        if let Err(error) = self.wal.sync_now() {
+            self.events.emit(StoreEvent::WalSyncFailed { error, retry_in: None });
+        }
+        if let Err(error) = self.list_flushed().and_then(|()| sync_manifest(&self.manifest, &self.manifest_unsynced)) {
+            self.events.emit(StoreEvent::ManifestSyncFailed { error });
+        }
+        
+        if let Err(error) = self.segment_registry.sweep() {
+            self.events.emit(StoreEvent::SegmentRemoveFailed { error: error.into() });
+        }
+    }
+}
+
+impl Store {
+    pub fn open(dir: &Path) -> Result<Self> {
+        Self::open_with_options(dir, StoreOptions::default()).map(|(store, _)| store)
+    }
+    
+    pub fn open_with_options(dir: &Path, options: StoreOptions) -> Result<(Self, OpenReport)> {
+        Self::open_at(Some(dir), options)
+    }
+    
+    /// A new, empty store that keeps everything in memory: its WAL, manifest and segments
+    /// are never written anywhere, and it is gone once the last handle drops. Reads, writes,
+    /// flushes and compaction take the same paths as in a store on disk, so it suits tests of
+    /// code built on WalDB. No threads run unless StoreOptions::background_threads asks.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::open_in_memory_with_options(StoreOptions::default())
+    }
+    
+    /// open_in_memory with options. data_dirs and placement have no directories to put
+    /// anything in, and l0_stop_threshold would wait for good on compaction without
+    /// background_threads, so those are refused. dedup_values_over and stats_interval,
+    /// which need a file of their own, are ignored.
+    pub fn open_in_memory_with_options(options: StoreOptions) -> Result<Self> {
+        let unsupported = if !options.data_dirs.is_empty() || options.placement != SegmentPlacement::StoreDir {
+            Some("data_dirs and placement")
+        } else if options.l0_stop_threshold.is_some() && !options.background_threads {
+            Some("l0_stop_threshold without background_threads")
+        } else {
+            None
+        };
+        if let Some(unsupported) = unsupported {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("An in-memory store can't take {}", unsupported)).into());
+        }
+        Self::open_at(None, options).map(|(store, _)| store)
+    }
+    
+    // A store in dir, or with None in memory. Paths in memory are relative to nothing and
+    // only name things, so no file is ever opened by one.
+    fn open_at(dir: Option<&Path>, options: StoreOptions) -> Result<(Self, OpenReport)> {
+        let in_memory = dir.is_none();
+        let dir = dir.unwrap_or(Path::new(""));
+        let shard_count = match options.memtable_shards {
+            Some(shards) if !shards.is_power_of_two() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("memtable_shards must be a power of two, not {}", shards)).into());
+            }
+            shards => shards.unwrap_or(1),
+        };
+        // Below where compaction starts, a held flush would wait for good
+        if let Some(stop) = options.l0_stop_threshold.filter(|&stop| stop < L0_COMPACTION_THRESHOLD) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("l0_stop_threshold must be at least {}, not {}", L0_COMPACTION_THRESHOLD, stop)).into());
+        }
+        let accounting = WriteAccounting::new(&options.accounting_roots, options.write_rate_limit.clone())?;
+        let events = EventSink(options.on_event.clone());
+        let clock = Arc::new(StoreClock::new(options.clock.clone(), events.clone()));
+        let (identity, created_identity) = match in_memory {
+            true => (StoreIdentity::generate(clock.now()), true),
+            false => {
+                fs::create_dir_all(dir)?;
+                // First, so a store from a newer version is refused before anything in it is touched
+                StoreIdentity::load_or_create(dir, clock.now())?
+            }
+        };
+        for data_dir in &options.data_dirs {
+            fs::create_dir_all(data_dir)?;
+        }
+        let segment_dirs = Arc::new(SegmentDirs::new(dir, &options.data_dirs, options.placement)?);
+        let mut report = OpenReport { created_identity, ..Default::default() };
+        let wal_path = dir.join("wal.log");
+        let manifest_path = dir.join("manifest.log");
+        
+        // Load manifest
+        let (manifest, values) = match in_memory {
+            true => (Manifest::in_memory(), ValueLog::new(&dir.join("values.log"))),
+            false => (Manifest::load(&manifest_path)?, ValueLog::load(&dir.join("values.log"))?),
+        };
+        clock.resume(manifest.clock);
+        let manifest = Arc::new(Mutex::new(manifest));
         
         let mut inner = StoreInner {
-            seq: 0,
-            memtable: BTreeMap::new(),
-            memtable_size: 0,
+            seq: AtomicU64::new(0),
+            collation: options.collation,
+            memtable: (0..shard_count).map(|_| RwLock::default()).collect(),
+            memtable_size: AtomicUsize::new(0),
+            memtable_born: AtomicU64::new(0),
+            epoch: clock.monotonic(),
+            clock: clock.clone(),
             segments_l0: Vec::new(),
             segments_l1: Vec::new(),
             segments_l2: Vec::new(),
+            segments_archive: Vec::new(),
+            sealed: manifest.lock().expect("Manifest lock should not be poisoned during initialization").sealed.clone(),
             subtombs: HashMap::new(),
+            subtomb_size: 0,
+            subtomb_gc_at: SUBTOMB_THRESHOLD,
+            subscribers: Vec::new(),
+            negative: options.negative_lookup_cache.map(|capacity| Mutex::new(NegativeCache::new(capacity))),
+            queue_heads: QueueHeads::default(),
         };
         
         // Load segments from manifest
+        let next_segment_id = Arc::new(AtomicU64::new(0));
+        let segment_registry = Arc::new(SegmentRegistry::new(in_memory));
         let manifest_lock = manifest.lock().expect("Manifest lock should not be poisoned during initialization");
-        for entry in &manifest_lock.entries {
-            let seg_path = dir.join(&entry.filename);
-            if let Ok(seg) = Segment::open(&seg_path) {
-                let seq_high = seg.seq_high;
-                match entry.level {
-                    0 => inner.segments_l0.push(Arc::new(seg)),
-                    1 => inner.segments_l1.push(Arc::new(seg)),
-                    2 => inner.segments_l2.push(Arc::new(seg)),
-                    _ => {}
+        let mut loaded = Vec::new();
+        for (i, entry) in manifest_lock.entries.iter().enumerate() {
+            let seg_path = segment_dirs.resolve(&entry.filename);
+            
+            // Compacted-away files stay listed in the manifest behind the entry that replaced
+            // them, and may outlive the compaction if a reader still held them or it crashed
+            if manifest_lock.superseded(i) {
+                match segment_registry.unlink(&seg_path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    Err(_) => continue,
+                    Ok(()) => {
+                        report.dropped_segments.push(entry.filename.clone());
+                        continue;
+                    }
+                }
+            }
+            
+            let seg = match Segment::open(&seg_path, next_segment_id.fetch_add(1, Ordering::Relaxed), options.collation) {
+                Ok(seg) if seg.collation != options.collation => {
+                    return Err(WalDbError::CollationMismatch { file: seg_path, found: seg.collation, requested: options.collation });
+                }
+                Ok(seg) => seg,
+                Err(e @ WalDbError::NewerFormat { .. }) => return Err(e),
+                Err(e) if options.skip_unreadable_segments => {
+                    report.skipped_segments.push((seg_path.clone(), e.to_string()));
+                    events.emit(StoreEvent::SegmentOpenFailed { path: seg_path, error: e });
+                    continue;
+                }
+                Err(WalDbError::Io(e)) => {
+                    return Err(WalDbError::Corruption {
+                        file: seg_path,
+                        offset: 0,
+                        detail: format!("Cannot open segment {}: {}", entry.filename, e),
+                    });
+                }
+                Err(e) => return Err(e),
+            };
+            if seg.value_refs && values.file.is_none() {
+                return Err(WalDbError::Corruption {
+                    file: seg_path,
+                    offset: 0,
+                    detail: format!("Segment {} refers to deduplicated values but values.log is missing", entry.filename),
+                });
+            }
+            if seg.salvaged {
+                report.salvaged_segments.push(seg_path);
+            }
+            loaded.push(entry);
+            // The footer's bounds, or for older segments the manifest's. Manifest bounds that
+            // don't fit the index belong to another file put under this name.
+            let fits = |(min, max): &(String, String)| !seg.index.is_empty()
+                && seg.index.key(0) == min.as_bytes()
+                && seg.collation.compare(seg.index.key(seg.index.len() - 1), max.as_bytes()).is_le();
+            let bounds = seg.bounds.clone().or_else(|| entry.bounds.clone().filter(fits));
+            let seg = Segment { bounds, ..seg };
+            let seq_high = seg.seq_high;
+            match entry.level {
+                0 => inner.segments_l0.push(Arc::new(seg)),
+                1 => inner.segments_l1.push(Arc::new(seg)),
+                2 => inner.segments_l2.push(Arc::new(seg)),
+                ARCHIVE_LEVEL => inner.segments_archive.push(Arc::new(Segment { archive_of: entry.archive_of.clone(), ..seg })),
+                _ => {}
+            }
+            inner.seq.fetch_max(seq_high, Ordering::SeqCst);
+        }
+        
+        // Files drop_segment took out stay behind if a reader still held them at a crash
+        for filename in &manifest_lock.dropped {
+            match segment_registry.unlink(&segment_dirs.resolve(filename)) {
+                Ok(()) => report.dropped_segments.push(filename.clone()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        
+        // Each level only ever holds data newer than every level above it, so a segment no newer
+        // than one further down is a copy of records that were merged, or one put back by hand.
+        // compact_range leaves older segments above its outputs, but never ones they overlap.
+        for entry in loaded.iter().filter(|entry| entry.archive_of.is_none()) {
+            let covering = loaded.iter().find(|other| {
+                other.archive_of.is_none() && other.level > entry.level && other.seq_high >= entry.seq_high
+                    && bounds_overlap(&entry.bounds, &other.bounds, options.collation)
+            });
+            if let Some(covering) = covering {
+                report.overlapping_segments.push((entry.filename.clone(), covering.filename.clone()));
+            }
+        }
+        
+        if !in_memory {
+            // A crash can leave the temporary file of an atomic write behind (AtomicFile): remove
+            // those. Anything else in the directory isn't ours: leave it alone but say so.
+            let known = ["wal.log", "manifest.log", "vectors.dat", "values.log", "blobs", IDENTITY_FILE, STATS_RING_FILE];
+            for data_dir in &options.data_dirs {
+                for dir_entry in fs::read_dir(data_dir)? {
+                    let path = dir_entry?.path();
+                    if path.file_name().and_then(|name| name.to_str()).is_some_and(|name| is_leftover_tmp(name, &[])) {
+                        fs::remove_file(&path)?;
+                    }
+                }
+            }
+            for dir_entry in fs::read_dir(dir)? {
+                let path = dir_entry?.path();
+                if path.file_name().and_then(|name| name.to_str()).is_some_and(|name| is_leftover_tmp(name, &known)) {
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+                // Names that aren't UTF-8 can't be ours either
+                let ours = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+                    known.contains(&name) || manifest_lock.entries.iter().any(|e| e.filename.rsplit('/').next() == Some(name))
+                });
+                if ours {
+                    continue;
                 }
-                if seq_high > inner.seq {
-                    inner.seq = seq_high;
+                if let Some(log) = &options.debug_log {
+                    log(&format!("Ignoring unknown file in store directory: {}", path.file_name().unwrap_or_default().to_string_lossy()));
                 }
+                report.ignored_files.push(path);
             }
+            report.ignored_files.sort();
         }
+        
+        // Subtree deletes a flush persisted, which the WAL may no longer hold. Writes after
+        // open must still get seqs past them.
+        for (prefix, &seq) in &manifest_lock.subtombs {
+            inner.seq.fetch_max(seq, Ordering::SeqCst);
+            inner.subtomb_add(prefix.clone(), seq);
+        }
+        let high_water = manifest_lock.high_water;
         drop(manifest_lock);
+        let wal_existed = !in_memory && wal_path.exists();
+        
+        // Replay WAL. Sets and point deletes up to the newest segment are already in one, unless
+        // a segment was skipped or salvaged and the WAL is the only complete copy left.
+        let replay_after = if report.skipped_segments.is_empty() && report.salvaged_segments.is_empty() {
+            inner.seq()
+        } else {
+            0
+        };
+        let (replayed_records, replayed_bytes) = match in_memory {
+            true => (0, 0),
+            false => inner.replay_wal(&wal_path, replay_after, options.lossy_reads)?,
+        };
+        
+        // Seqs handed out since the last seq line went with a missing WAL, and a replica or a
+        // changes_since cursor may have seen them, so carry on well past the newest known.
+        // Only a store that wrote a seq line had a WAL to lose: rebuild_into's copies don't.
+        inner.seq.fetch_max(high_water, Ordering::SeqCst);
+        if high_water > 0 && !wal_existed {
+            let resumed_at = inner.seq.fetch_add(SEQ_SAFETY_GAP, Ordering::SeqCst) + SEQ_SAFETY_GAP;
+            report.missing_wal = true;
+            events.emit(StoreEvent::WalMissing { resumed_at });
+        }
+        
+        // Reuse the vector sidecar unless it is invalid or holds writes the primary lost
+        let vectors_path = (!in_memory).then(|| dir.join("vectors.dat"));
+        let vectors = match vectors_path.as_deref().map(VectorSidecar::load).transpose()? {
+            None => Some(VectorSidecar::new(None)),
+            Some(Some(mut vectors)) if vectors.max_seq <= inner.seq() => {
+                vectors.catch_up(&inner)?;
+                Some(vectors)
+            }
+            Some(_) => None,
+        };
+        let rebuild_vectors = vectors.is_none();
+        
+        // Create WAL with background flusher
+        let wal = Arc::new(match in_memory {
+            true => GroupCommitWAL::in_memory(&wal_path, options.background_threads, &options, events.clone(), clock.clone()),
+            false => GroupCommitWAL::new(&wal_path, inner.seq(), &options, events.clone(), clock.clone())?,
+        });
         
-        // Replay WAL
-        inner.replay_wal(&wal_path)?;
+        // Start background WAL flusher thread
+        let wal_clone = wal.clone();
+        let flusher_events = events.clone();
+        let manifest_unsynced = Arc::new(AtomicBool::new(false));
+        let deferred_manifest = options.defer_manifest_syncs.then(|| (manifest.clone(), manifest_unsynced.clone()));
+        let flusher_manifest = (manifest.clone(), manifest_unsynced.clone());
+        if wal.flusher {
+            thread::spawn(move || {
+                let mut retry_in = None;
+                let mut marked_at = 0;
+                loop {
+                    let (lock, cvar) = &*wal_clone.shutdown;
+                    let shutdown = lock.lock().expect("WAL shutdown lock should not be poisoned");
+                    let wait = retry_in.unwrap_or(Duration::from_millis(GROUP_COMMIT_MS));
+                    // An append past wal_flush_bytes cuts the wait short, unless a failing disk is
+                    // being left alone
+                    let (shutdown, _) = cvar.wait_timeout_while(shutdown, wait, |shutdown| {
+                        !*shutdown && (retry_in.is_some() || !wal_clone.flush_wanted.load(Ordering::SeqCst))
+                    }).expect("WAL shutdown lock should not be poisoned");
+                    if *shutdown {
+                        break;
+                    }
+                    drop(shutdown);
+                    wal_clone.flush_wanted.store(false, Ordering::SeqCst);
+                    if wal_clone.paused.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    *wal_clone.last_pass.lock().expect("WAL pass lock should not be poisoned") = wal_clone.clock.monotonic();
+                
+                    // A failed sync keeps its entries, so backing off loses nothing and leaves a full
+                    // or failing disk alone for a while
+                    match wal_clone.sync_now() {
+                        Ok(()) => retry_in = None,
+                        Err(error) => {
+                            let next = retry_in.map_or(WAL_RETRY_MIN, |d: Duration| (d * 2).min(WAL_RETRY_MAX));
+                            flusher_events.emit(StoreEvent::WalSyncFailed { error, retry_in: Some(next) });
+                            retry_in = Some(next);
+                        }
+                    }
+                    // A store that rarely flushes still records its seq now and then, for an open
+                    // that finds the WAL gone
+                    let syncs = wal_clone.syncs.load(Ordering::Relaxed);
+                    if syncs >= marked_at + HIGH_WATER_SYNCS {
+                        marked_at = syncs;
+                        let (manifest, unsynced) = &flusher_manifest;
+                        if let Err(error) = mark_high_water(manifest, unsynced, wal_clone.durable_seq.load(Ordering::SeqCst)) {
+                            flusher_events.emit(StoreEvent::ManifestSyncFailed { error });
+                        }
+                    }
+                    if let Some((manifest, unsynced)) = &deferred_manifest {
+                        if let Err(error) = sync_manifest(manifest, unsynced) {
+                            flusher_events.emit(StoreEvent::ManifestSyncFailed { error });
+                        }
+                    }
+                }
+            });
+        }
         
         let compaction_shutdown = Arc::new((Mutex::new(false), Condvar::new()));
         
-        let store = Store {
+        let mut store = Store {
             dir: dir.to_path_buf(),
             inner: Arc::new(RwLock::new(inner)),
             wal,
-            cache: Arc::new(BlockCache::new(CACHE_SIZE)),
+            cache: Arc::new(BlockCache::new(CACHE_SIZE, events.clone())),
             manifest,
+            vectors: Arc::new(Mutex::new(vectors.unwrap_or_else(|| VectorSidecar::new(vectors_path)))),
+            next_segment_id,
+            segment_registry,
+            compaction: options.compaction.clone(),
+            max_segment_size: options.max_segment_size.unwrap_or(MAX_SEGMENT_BYTES),
+            compacting: Arc::new(Mutex::new(())),
+            admin: Arc::new(Mutex::new(())),
+            unlisted: Arc::new(Mutex::new(Vec::new())),
             compaction_shutdown: compaction_shutdown.clone(),
+            events,
+            skip_identical_sets: options.skip_identical_sets,
+            point_gets: options.coalesce_point_gets.then(Default::default),
+            manifest_unsynced,
+            defer_manifest_syncs: options.defer_manifest_syncs,
+            collation: options.collation,
+            values: Arc::new(Mutex::new(values)),
+            value_cache: Arc::new(ValueCache::new(VALUE_CACHE_SIZE, options.lossy_reads)),
+            dedup_values_over: options.dedup_values_over.filter(|_| !in_memory),
+            open_snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            replayed: WalStats { replayed_records, replayed_bytes, ..Default::default() },
+            segment_dirs,
+            blocks_read: Arc::new(AtomicU64::new(0)),
+            negative_hits: Arc::new(AtomicU64::new(0)),
+            read_amp: Arc::new(ReadAmplification::default()),
+            groups_visited: Arc::new(AtomicU64::new(0)),
+            compaction_io: Arc::new((AtomicU64::new(0), AtomicU64::new(0))),
+            compaction_timing: Arc::new((AtomicU64::new(0), AtomicU64::new(0))),
+            flush_timing: Arc::new((AtomicU64::new(0), AtomicU64::new(0))),
+            flushed_seq: Arc::new(AtomicU64::new(replay_after)),
+            identity,
+            read_only: Arc::new(AtomicBool::new(false)),
+            replica: options.replica,
+            pin_budget: options.pin_indexes.then(|| options.pin_budget.unwrap_or(CACHE_SIZE / 4)),
+            strict_reads: options.strict_reads,
+            lossy_reads: options.lossy_reads,
+            memtable_max_age: options.memtable_max_age.unwrap_or(MEMTABLE_MAX_AGE),
+            shard_writers: options.memtable_shards.map(|_| Arc::new((0..shard_count).map(|_| Mutex::new(())).collect())),
+            accounting: accounting.map(Arc::new),
+            l0_slowdown: options.l0_slowdown_threshold,
+            l0_stop: options.l0_stop_threshold,
+            l0_changed: Arc::new((Mutex::new(0), Condvar::new())),
+            l0_throttling: Arc::new((AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0))),
+            compaction_wanted: Arc::new(AtomicBool::new(false)),
+            in_memory,
+            blobs: in_memory.then(Default::default),
+            quotas: Arc::new(Quotas::default()),
+            validators: Arc::new(Validators::default()),
+            clock,
         };
         
-        // Start compaction thread
-        let store_clone = store.clone();
-        thread::spawn(move || {
-            store_clone.compaction_thread();
-        });
+        if rebuild_vectors {
+            store.rebuild_vectors()?;
+        }
         
-        Ok(store)
-    }
-    
-    pub fn set(&self, path: &str, value: &str, replace_subtree: bool) -> io::Result<()> {
-        // Check parent isn't a scalar (tree semantics)
-        if let Some(parent) = parent_path(path) {
-            // Check if parent exists as an actual scalar value (not reconstructed object)
-            if self.has_scalar_value(&parent)? {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Cannot write under scalar parent"
-                ));
-            }
+        // Everything else the log holds is in segments once the replay is flushed. Nothing
+        // has been written yet, so there is nothing buffered for the rewrite to lose. Subtree
+        // deletes replaced or collected since are, though, so history stops here.
+        if options.replay_flush_threshold.is_some_and(|threshold| replayed_bytes > threshold) {
+            store.flush_now()?;
+            let inner = store.read_inner()?;
+            store.raise_history_floor(inner.seq())?;
+            store.wal.checkpoint(&inner.subtombs)?;
+            drop(inner);
+            store.replayed.checkpointed = true;
         }
         
-        let mut inner = self.inner.write().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Lock poisoned: {}", e)))?;
-        inner.seq += 1;
-        let seq = inner.seq;
+        // Flush an oversized replay now rather than on the first write. Compaction isn't
+        // running yet to make room in L0, so neither of these waits for it.
+        store.maybe_flush_now(store.write_inner()?)?;
+        store.load_quotas()?;
         
-        if replace_subtree {
-            // Emit subtomb for prefix
-            let prefix = format!("{}/", path);
-            self.wal.append(&WALEntry {
-                seq,
-                kind: RT_DEL_SUB,
-                key: prefix.clone(),
-                value: None,
-            })?;
-            inner.subtombs.insert(prefix, seq);
-            
-            // Emit point tombstone for the node itself (if it was scalar)
-            self.wal.append(&WALEntry {
-                seq,
-                kind: RT_DEL_POINT,
-                key: path.to_string(),
-                value: None,
-            })?;
-            inner.memtable.insert(path.to_string(), MemValue::PointTomb(seq));
+        if let Some(log) = &options.debug_log {
+            for (path, reason) in &report.skipped_segments {
+                log(&format!("Skipped unreadable segment {}: {}", path.display(), reason));
+            }
+            for path in &report.salvaged_segments {
+                log(&format!("Salvaged segment with a damaged footer: {}", path.display()));
+            }
         }
         
-        // Set the scalar value
-        self.wal.append(&WALEntry {
-            seq,
-            kind: RT_SET,
-            key: path.to_string(),
-            value: Some(value.to_string()),
-        })?;
-        
-        inner.memtable.insert(path.to_string(), MemValue::Scalar(value.to_string(), seq));
-        inner.memtable_size += path.len() + value.len() + 16;
+        store.events.emit(StoreEvent::Opened { identity });
         
-        if inner.memtable_size >= MEMTABLE_THRESHOLD {
-            self.flush_memtable_locked(&mut inner)?;
+        if let Some(interval) = options.stats_interval.filter(|_| !in_memory) {
+            store.start_stats_sampler(interval, options.stats_ring_bytes.unwrap_or(STATS_RING_BYTES));
         }
         
-        Ok(())
+        // Start compaction thread
+        if !in_memory || options.background_threads {
+            let store_clone = store.clone();
+            thread::spawn(move || {
+                store_clone.compaction_thread();
+            });
+        }
+        
+        Ok((store, report))
     }
     
-    pub fn get(&self, path: &str) -> io::Result<Option<String>> {
-        let inner = self.inner.read().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Lock poisoned: {}", e)))?;
-        
-        // Just get the exact key value, no reconstruction
-        if let Some(mv) = inner.memtable.get(path) {
-            match mv {
-                MemValue::Scalar(v, seq) if !self.covered_by_subtomb(&inner, path, *seq) => {
-                    return Ok(Some(v.clone()));
-                }
-                MemValue::PointTomb(_) => return Ok(None),
-                _ => {}
-            }
+    /// Returns the seq assigned to the write, for wait_durable. A write dropped by
+    /// skip_identical_sets returns the latest seq instead, which covers the earlier write.
+    pub fn set(&self, path: &str, value: &str, replace_subtree: bool) -> Result<u64> {
+        reject_reserved(path)?;
+        self.set_unchecked(path, value, replace_subtree)
+    }
+    
+    /// Set path unless it already holds exactly this value, whatever skip_identical_sets says.
+    /// Returns whether anything was written.
+    pub fn set_if_changed(&self, path: &str, value: &str) -> Result<bool> {
+        reject_reserved(path)?;
+        Ok(self.set_with(path, value, SetOptions::default(), true)?.1)
+    }
+    
+    /// set, with what happens to the path's children and a scalar parent spelled out. Plain
+    /// set(.., true) is replace_subtree alone.
+    pub fn set_with_options(&self, path: &str, value: &str, options: SetOptions) -> Result<u64> {
+        reject_reserved(path)?;
+        Ok(self.set_with(path, value, options, self.skip_identical_sets)?.0)
+    }
+    
+    // The write methods without the reserved-prefix check, for Namespace and for helpers
+    // whose path was already checked
+    fn set_unchecked(&self, path: &str, value: &str, replace_subtree: bool) -> Result<u64> {
+        let options = SetOptions { replace_subtree, ..Default::default() };
+        Ok(self.set_with(path, value, options, self.skip_identical_sets)?.0)
+    }
+    
+    // The write lock, for a write that may go ahead: none may while the store is degraded
+    fn write_lock(&self) -> Result<InnerWrite<'_>> {
+        let inner = self.write_inner()?;
+        self.check_writable()?;
+        Ok(inner)
+    }
+    
+    fn read_inner(&self) -> Result<InnerRead<'_>> {
+        lock_ranked(LockRank::Inner, || self.inner.read().map_err(|e| WalDbError::Locked(format!("Lock poisoned: {}", e))))
+    }
+    
+    fn write_inner(&self) -> Result<InnerWrite<'_>> {
+        lock_ranked(LockRank::Inner, || self.inner.write().map_err(|e| WalDbError::Locked(format!("Lock poisoned: {}", e))))
+    }
+    
+    fn manifest_locked(&self) -> Result<Ranked<MutexGuard<'_, Manifest>>> {
+        lock_ranked(LockRank::Manifest, || self.manifest.lock().map_err(|e| WalDbError::Locked(format!("Manifest lock poisoned: {}", e))))
+    }
+    
+    fn compacting_lock(&self) -> Result<Ranked<MutexGuard<'_, ()>>> {
+        lock_ranked(LockRank::Compacting, || self.compacting.lock().map_err(|e| WalDbError::Locked(format!("Compaction lock poisoned: {}", e))))
+    }
+    
+    fn admin_lock(&self) -> Result<Ranked<MutexGuard<'_, ()>>> {
+        lock_ranked(LockRank::Admin, || self.admin.lock().map_err(|e| WalDbError::Locked(format!("Admin lock poisoned: {}", e))))
+    }
+    
+    // The admin lock if nothing holds it. Unlike admin_lock this may be called with the inner
+    // lock held, as a write that fills the memtable does.
+    fn try_admin(&self) -> Result<Option<Ranked<MutexGuard<'_, ()>>>> {
+        match self.admin.try_lock() {
+            Ok(guard) => Ok(Some(Ranked::held(LockRank::Admin, guard))),
+            Err(std::sync::TryLockError::WouldBlock) => Ok(None),
+            Err(std::sync::TryLockError::Poisoned(e)) => Err(WalDbError::Locked(format!("Admin lock poisoned: {}", e))),
         }
-        
-        // Check segments
-        let mut best: Option<(Option<String>, u64)> = None;
-        for seg in inner.segments_l0.iter()
-            .chain(inner.segments_l1.iter())
-            .chain(inner.segments_l2.iter())
-        {
-            if let Some(bloom) = &seg.bloom {
-                if !bloom.might_contain(path) {
-                    continue;
-                }
-            }
-            
-            if let Some((val, seq)) = self.get_from_segment(seg, path)? {
-                if !self.covered_by_subtomb(&inner, path, seq) {
-                    if best.is_none() || best.as_ref().unwrap().1 < seq {
-                        best = Some((val, seq));
-                    }
-                }
-            }
+    }
+    
+    fn check_writable(&self) -> Result<()> {
+        self.check_unfrozen()?;
+        if self.replica {
+            return Err(WalDbError::Replica);
         }
-        
-        Ok(best.and_then(|(v, _)| v))
+        Ok(())
     }
     
-    fn has_scalar_value(&self, path: &str) -> io::Result<bool> {
-        let inner = self.inner.read().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Lock poisoned: {}", e)))?;
-        
-        // Check memtable for exact scalar value
-        if let Some(mv) = inner.memtable.get(path) {
-            if let MemValue::Scalar(_, seq) = mv {
-                if !self.covered_by_subtomb(&inner, path, *seq) {
-                    return Ok(true);
-                }
-            }
+    // What stops a replica's applies and flushes too: a degraded disk or a rebuild
+    fn check_unfrozen(&self) -> Result<()> {
+        if self.wal.degraded.load(Ordering::SeqCst) {
+            return Err(WalDbError::StorageFull);
         }
-        
-        // Check segments for exact scalar value
-        for seg in inner.segments_l0.iter()
-            .chain(inner.segments_l1.iter())
-            .chain(inner.segments_l2.iter())
-        {
-            if let Some(bloom) = &seg.bloom {
-                if !bloom.might_contain(path) {
-                    continue;
-                }
-            }
-            
-            if let Some((Some(_), seq)) = self.get_from_segment(seg, path)? {
-                if !self.covered_by_subtomb(&inner, path, seq) {
-                    return Ok(true);
-                }
-            }
+        if self.read_only.load(Ordering::SeqCst) {
+            return Err(WalDbError::ReadOnly);
         }
-        
-        Ok(false)
+        Ok(())
     }
     
-    // Removed get_internal - no more JSON reconstruction
-        
-    
-    // Removed unused methods: has_children_in_segments, has_newer_children_in_segments, get_prefix_internal
-    // These were for JSON reconstruction which now happens in the Node.js layer
-    
-    fn covered_by_subtomb(&self, inner: &std::sync::RwLockReadGuard<StoreInner>, key: &str, seq: u64) -> bool {
-        for (prefix, tomb_seq) in &inner.subtombs {
-            if key.starts_with(prefix) && *tomb_seq >= seq {
-                return true;
+    // What a set, delete or increment of path holds while it checks and writes. With
+    // memtable_shards that is the read lock and path's shard writer, unless a subscriber has
+    // to be told of changes in seq order, which takes the write lock.
+    fn point_lock(&self, path: &str) -> Result<PointLock<'_>> {
+        if let Some(writers) = &self.shard_writers {
+            let inner = self.read_inner()?;
+            if inner.subscribers.is_empty() {
+                self.check_writable()?;
+                let writer = writers[inner.shard_of(path)].lock().map_err(|e| WalDbError::Locked(format!("Shard lock poisoned: {}", e)))?;
+                return Ok(PointLock::Shard(inner, writer));
             }
         }
-        false
+        Ok(PointLock::Store(self.write_lock()?))
     }
     
-    fn get_from_segment(&self, seg: &Arc<Segment>, key: &str) -> io::Result<Option<(Option<String>, u64)>> {
-        // Returns Some((Some(value), seq)) for RT_SET
-        // Returns Some((None, seq)) for RT_DEL_POINT
-        // Returns None for not found
-        // Binary search index
-        let idx = match seg.index.binary_search_by_key(&key.to_string(), |(k, _)| k.clone()) {
-            Ok(i) => i,
-            Err(i) if i > 0 => i - 1,
-            _ => return Ok(None),
-        };
-        
-        let (_, offset) = &seg.index[idx];
-        let next_offset = if idx + 1 < seg.index.len() {
-            seg.index[idx + 1].1
-        } else {
-            seg.index_start
+    // A sharded point write's records, all at one new seq and all in one shard. The seq is
+    // taken and the records applied under the shard's lock, so a reader holding every shard
+    // sees all of the write or none of it, and nothing newer than the seq it reads.
+    // Each record is a key and its new value, or None to delete it.
+    fn shard_commit(&self, inner: InnerRead<'_>, writer: MutexGuard<'_, ()>, records: Vec<(String, Option<CompactStr>)>,
+                    charge: QuotaCharge<'_>) -> Result<u64> {
+        let seq = {
+            let mut shard = inner.memtable[inner.shard_of(&records[0].0)].write().unwrap_or_else(|e| e.into_inner());
+            let entries: Vec<WALEntry> = records.iter().map(|(key, value)| WALEntry {
+                seq: 0,
+                kind: if value.is_some() { RT_SET } else { RT_DEL_POINT },
+                key,
+                value: value.as_deref(),
+            }).collect();
+            let seq = self.wal.append_next(&inner.seq, &entries)?;
+            let mut vectors = self.vectors_locked()?;
+            for (key, value) in records {
+                vectors.apply(&key, value.as_deref(), seq)?;
+                let value = match value {
+                    Some(value) => MemValue::Scalar(value, seq),
+                    None => MemValue::PointTomb(seq),
+                };
+                inner.shard_insert(&mut shard, key, value);
+            }
+            charge.commit();
+            seq
         };
         
-        let block_data = self.cache.get_or_load(&seg.path, *offset, (next_offset - offset) as usize)?;
-        
-        // Parse block
-        let mut pos = 0;
-        while pos + 8 < block_data.len() {
-            let mut seq_bytes = [0u8; 8];
-            seq_bytes.copy_from_slice(&block_data[pos..pos + 8]);
-            let seq = u64::from_le_bytes(seq_bytes);
-            pos += 8;
-            
-            if pos >= block_data.len() {
-                break;
-            }
-            
-            let rec_type = block_data[pos];
-            pos += 1;
-            
-            if pos + 4 > block_data.len() {
-                break;
-            }
-            
-            let mut klen_bytes = [0u8; 4];
-            klen_bytes.copy_from_slice(&block_data[pos..pos + 4]);
-            let klen = u32::from_le_bytes(klen_bytes) as usize;
-            pos += 4;
-            
-            // Block format: klen, vlen, key, value
-            if pos + 4 > block_data.len() {
-                break;
-            }
-            
-            let mut vlen_bytes = [0u8; 4];
-            vlen_bytes.copy_from_slice(&block_data[pos..pos + 4]);
-            let vlen = u32::from_le_bytes(vlen_bytes) as usize;
-            pos += 4;
-            
-            if pos + klen > block_data.len() {
-                break;
-            }
-            
-            let k = String::from_utf8_lossy(&block_data[pos..pos + klen]);
-            pos += klen;
-            
-            if k == key {
-                if rec_type == RT_SET {
-                    if pos + vlen > block_data.len() {
-                        break;
-                    }
-                    let v = String::from_utf8_lossy(&block_data[pos..pos + vlen]);
-                    return Ok(Some((Some(v.to_string()), seq)));
-                } else if rec_type == RT_DEL_POINT {
-                    // Return tombstone marker
-                    return Ok(Some((None, seq)));
-                }
-            }
-            
-            pos += vlen;
+        let full = inner.memtable_size() >= MEMTABLE_THRESHOLD;
+        drop(writer);
+        drop(inner);
+        if full {
+            let inner = self.write_inner()?;
+            self.maybe_flush(inner)?;
         }
-        
-        Ok(None)
+        Ok(seq)
     }
     
-    fn flush_memtable_locked(&self, inner: &mut StoreInner) -> io::Result<()> {
-        if inner.memtable.is_empty() {
-            return Ok(());
+    // The seq to wait on, and whether the write happened or matched the live value
+    fn set_with(&self, path: &str, value: &str, options: SetOptions, skip_identical: bool) -> Result<(u64, bool)> {
+        validate_key(path)?;
+        self.run_validators([(path, value)])?;
+        
+        // A replace writes a subtomb, which takes the whole store
+        let lock = if options.replace_subtree { PointLock::Store(self.write_lock()?) } else { self.point_lock(path)? };
+        lock.check_unsealed(path, false)?;
+        if options.replace_subtree {
+            lock.check_unsealed(&format!("{}/", path), true)?;
         }
         
-        let filename = format!("l0_{:010}.seg", inner.seq);
-        let path = self.dir.join(&filename);
+        // Check parent isn't a scalar (tree semantics), unless the caller asked to clear it
+        let scalar_parent = match parent_path(path) {
+            Some(parent) if self.contains_locked(&lock, &lock.shard(&parent), &parent)? => Some(parent),
+            _ => None,
+        };
+        if scalar_parent.is_some() && !options.convert_scalar_parent {
+            return Err(WalDbError::TreeSemantics { path: path.to_string(), ancestor: None });
+        }
         
-        let mut writer = SegmentWriter::new(&path)?;
+        // A replace also clears children, so it is never a no-op
+        if skip_identical && !options.replace_subtree && self.get_locked(&lock, path)?.as_deref() == Some(value) {
+            return Ok((lock.seq(), false));
+        }
         
-        for (k, v) in &inner.memtable {
-            match v {
-                MemValue::Scalar(val, seq) => {
-                    writer.add(RT_SET, k, Some(val), *seq)?;
-                }
-                MemValue::PointTomb(seq) => {
-                    writer.add(RT_DEL_POINT, k, None, *seq)?;
+        let children = options.replace_subtree.then(|| format!("{}/", path));
+        let ops = [scalar_parent.as_deref().map(QuotaOp::Delete), children.as_deref().map(QuotaOp::Clear), Some(QuotaOp::Put(path, value.len()))];
+        let charge = self.charge_quotas(&lock, ops.into_iter().flatten())?;
+        
+        let mut inner = match lock {
+            PointLock::Store(inner) => inner,
+            PointLock::Shard(inner, writer) => {
+                let mut records = Vec::new();
+                if let Some(parent) = scalar_parent {
+                    records.push((parent, None));
                 }
+                records.push((path.to_string(), Some(CompactStr::from(value))));
+                let seq = self.shard_commit(inner, writer, records, charge)?;
+                self.account(path, path.len() + value.len());
+                return Ok((seq, true));
             }
-        }
+        };
+        let seq = inner.next_seq();
         
-        let seg = writer.finish()?;
+        if let Some(parent) = scalar_parent {
+            self.wal.append(&WALEntry {
+                seq,
+                kind: RT_DEL_POINT,
+                key: &parent,
+                value: None,
+            })?;
+            self.vectors_locked()?.apply(&parent, None, seq)?;
+            inner.memtable_insert(parent, MemValue::PointTomb(seq));
+        }
         
-        // Update manifest
-        {
-            let mut manifest = self.manifest.lock().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Manifest lock poisoned: {}", e)))?;
-            manifest.add_entry(ManifestEntry {
-                seq_high: seg.seq_high,
-                level: 0,
-                filename,
+        if let Some(prefix) = children {
+            // Emit subtomb for prefix
+            self.wal.append(&WALEntry {
+                seq,
+                kind: RT_DEL_SUB,
+                key: &prefix,
+                value: None,
+            })?;
+            self.vectors_locked()?.remove_prefix(&prefix, seq)?;
+            inner.subtomb_insert(prefix, seq);
+            
+            // Emit point tombstone for the node itself (if it was scalar)
+            self.wal.append(&WALEntry {
+                seq,
+                kind: RT_DEL_POINT,
+                key: path,
+                value: None,
             })?;
+            inner.memtable_insert(path.to_string(), MemValue::PointTomb(seq));
         }
         
-        inner.segments_l0.push(Arc::new(seg));
-        inner.memtable.clear();
-        inner.memtable_size = 0;
+        // Set the scalar value
+        self.wal.append(&WALEntry {
+            seq,
+            kind: RT_SET,
+            key: path,
+            value: Some(value),
+        })?;
         
-        self.wal.sync_now()?;
+        inner.memtable_insert(path.to_string(), MemValue::Scalar(CompactStr::from(value), seq));
+        self.vectors_locked()?.apply(path, Some(value), seq)?;
+        charge.commit();
         
-        Ok(())
+        self.maybe_flush(inner)?;
+        self.account(path, path.len() + value.len());
+        Ok((seq, true))
     }
     
-    pub fn flush(&self) -> io::Result<()> {
-        let mut inner = self.inner.write().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Lock poisoned: {}", e)))?;
-        self.flush_memtable_locked(&mut inner)?;
-        self.wal.sync_now()?;
-        Ok(())
+    /// Add delta to the integer at path and return the new value. A missing path counts as 0.
+    /// The read and the write happen under one lock, so concurrent increments are never lost;
+    /// the result is logged as an ordinary set of its decimal string.
+    pub fn increment(&self, path: &str, delta: i64) -> Result<i64> {
+        reject_reserved(path)?;
+        self.increment_unchecked(path, delta)
     }
     
-    pub fn delete(&self, path: &str) -> io::Result<()> {
-        let mut inner = self.inner.write().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Lock poisoned: {}", e)))?;
-        inner.seq += 1;
-        let seq = inner.seq;
+    fn increment_unchecked(&self, path: &str, delta: i64) -> Result<i64> {
+        validate_key(path)?;
+        
+        let lock = self.point_lock(path)?;
+        lock.check_unsealed(path, false)?;
+        if let Some(parent) = parent_path(path) {
+            if self.contains_locked(&lock, &lock.shard(&parent), &parent)? {
+                return Err(WalDbError::TreeSemantics { path: path.to_string(), ancestor: None });
+            }
+        }
+        let current = match self.get_locked(&lock, path)? {
+            Some(value) => value.parse::<i64>().map_err(|_| WalDbError::NotAnInteger {
+                path: path.to_string(),
+                value,
+            })?,
+            None => 0,
+        };
+        let next = current.checked_add(delta).ok_or_else(|| WalDbError::NotAnInteger {
+            path: path.to_string(),
+            value: format!("{} + {}", current, delta),
+        })?;
         
+        let value = next.to_string();
+        let bytes = path.len() + value.len();
+        let charge = self.charge_quotas(&lock, [QuotaOp::Put(path, value.len())])?;
+        let mut inner = match lock {
+            PointLock::Store(inner) => inner,
+            PointLock::Shard(inner, writer) => {
+                self.shard_commit(inner, writer, vec![(path.to_string(), Some(CompactStr::from(value.as_str())))], charge)?;
+                self.account(path, bytes);
+                return Ok(next);
+            }
+        };
+        let seq = inner.next_seq();
         self.wal.append(&WALEntry {
             seq,
-            kind: RT_DEL_POINT,
-            key: path.to_string(),
-            value: None,
+            kind: RT_SET,
+            key: path,
+            value: Some(&value),
         })?;
         
-        inner.memtable.insert(path.to_string(), MemValue::PointTomb(seq));
-        Ok(())
+        self.vectors_locked()?.apply(path, Some(&value), seq)?;
+        inner.memtable_insert(path.to_string(), MemValue::Scalar(CompactStr::from(value.as_str()), seq));
+        charge.commit();
+        
+        self.maybe_flush(inner)?;
+        self.account(path, bytes);
+        Ok(next)
     }
     
-    /// Set multiple key-value pairs atomically, optionally replacing a subtree first
-    pub fn set_many(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>) -> io::Result<()> {
-        
-        if entries.is_empty() {
-            return Ok(());
-        }
-        
-        let mut inner = self.inner.write().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Lock poisoned: {}", e)))?;
-        
-        // Replace subtree if specified
-        if let Some(base_path) = replace_subtree_at {
-            // Check if we need a point tombstone (only if base path has a value or children)
-            let needs_point_tomb = {
-                // Check for direct value
-                let has_memtable_value = if let Some(mv) = inner.memtable.get(base_path) {
-                    matches!(mv, MemValue::Scalar(_, _))
-                } else {
-                    false
-                };
-                
-                if has_memtable_value {
-                    true
-                } else {
-                    // Check in segments
-                    let has_value_in_segments = inner.segments_l0.iter()
-                        .chain(inner.segments_l1.iter())
-                        .chain(inner.segments_l2.iter())
-                        .any(|seg| {
-                            seg.index.iter().any(|(k, _)| k == base_path)
-                        });
-                    
-                    if has_value_in_segments {
-                        true
-                    } else {
-                        // Check for children
-                        let prefix = format!("{}/", base_path);
-                        let has_children_mem = inner.memtable.keys().any(|k| k.starts_with(&prefix));
-                        let has_children_seg = inner.segments_l0.iter()
-                            .chain(inner.segments_l1.iter())
-                            .chain(inner.segments_l2.iter())
-                            .any(|seg| {
-                                seg.index.iter().any(|(k, _)| k.starts_with(&prefix))
-                            });
-                        has_children_mem || has_children_seg
-                    }
-                }
-            };
-            
-            // Only add point tombstone if there's something to delete
-            if needs_point_tomb {
-                inner.seq += 1;
-                let point_tomb_seq = inner.seq;
-                
-                self.wal.append(&WALEntry {
-                    seq: point_tomb_seq,
-                    kind: RT_DEL_POINT,
-                    key: base_path.to_string(),
-                    value: None,
-                })?;
-                
-                inner.memtable.insert(base_path.to_string(), MemValue::PointTomb(point_tomb_seq));
+    pub fn get(&self, path: &str) -> Result<Option<String>> {
+        self.get_as(path, &ReadBudget::default(), |value| value.to_string(), |value| value)
+    }
+    
+    /// get that shares the value instead of copying it. A memtable value longer than 22
+    /// bytes comes back without allocating; shorter ones and segment reads are copied once.
+    pub fn get_ref(&self, path: &str) -> Result<Option<Arc<str>>> {
+        self.get_as(path, &ReadBudget::default(), CompactStr::to_arc, Arc::<str>::from)
+    }
+    
+    /// get that gives up with WalDbError::BudgetExceeded rather than probe more segments or
+    /// read more blocks from disk than budget allows, for a caller with a fallback that would
+    /// rather fail fast than wait on a store with many overlapping segments. Each check is
+    /// made before the probe or read it would pay for, so a store that doesn't change gives
+    /// the same outcome every time. Concurrent gets of the same key aren't coalesced with it.
+    pub fn get_with_budget(&self, path: &str, budget: ReadBudget) -> Result<Option<String>> {
+        self.get_as(path, &budget, |value| value.to_string(), |value| value)
+    }
+    
+    // A get answered from the memtable through from_memtable, from the segments through
+    // from_segments, within budget
+    fn get_as<T>(&self, path: &str, budget: &ReadBudget, from_memtable: impl FnOnce(&CompactStr) -> T, from_segments: impl FnOnce(String) -> T) -> Result<Option<T>> {
+        let inner = self.read_inner()?;
+        // Held throughout, so no sharded write to path can land between the memtable miss
+        // and the segment lookup
+        let shard = inner.shard(path);
+        if let Some(value) = self.memtable_get(&inner, &shard, path) {
+            if value.is_none() {
+                self.check_no_scalar_ancestor(&inner, &shard, path, parent_path(path))?;
             }
-            
-            // Always add subtree deletion to subtombs (for any children)
-            inner.seq += 1;
-            let subtomb_seq = inner.seq;
-            
-            self.wal.append(&WALEntry {
-                seq: subtomb_seq,
-                kind: RT_DEL_SUB,
-                key: base_path.to_string(),
-                value: None,
-            })?;
-            
-            inner.subtombs.insert(base_path.to_string(), subtomb_seq);
+            self.read_amp.record(&ReadTrace { level: ReadLevel::Memtable, ..Default::default() });
+            return Ok(value.map(from_memtable));
         }
-        
-        // Store all entries with same sequence number for atomicity
-        inner.seq += 1;
-        let batch_seq = inner.seq;
-        
-        for (key, value) in &entries {
-            // Check parent isn't a scalar (tree semantics)
-            if let Some(parent) = parent_path(key) {
-                // Check if parent exists as a scalar value
-                if let Some(mv) = inner.memtable.get(&parent) {
-                    if matches!(mv, MemValue::Scalar(_, _)) {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            "Cannot write under scalar parent"
-                        ));
-                    }
-                }
-            }
-            
-            self.wal.append(&WALEntry {
-                seq: batch_seq,
-                kind: RT_SET,
-                key: key.to_string(),
-                value: Some(value.to_string()),
-            })?;
-            
-            inner.memtable.insert(key.to_string(), MemValue::Scalar(value.to_string(), batch_seq));
-            inner.memtable_size += key.len() + value.len() + 32; // Estimate
+        if self.known_absent(&inner, path)? {
+            self.read_amp.record(&ReadTrace::default());
+            self.check_no_scalar_ancestor(&inner, &shard, path, parent_path(path))?;
+            return Ok(None);
         }
-        
-        // Flush memtable if it gets too large
-        if inner.memtable_size > MEMTABLE_THRESHOLD {
-            self.flush_memtable_locked(&mut inner)?;
+        let mut trace = ReadTrace::default();
+        let value = match &self.point_gets {
+            Some(in_flight) if *budget == ReadBudget::default() => self.coalesced_get(&inner, in_flight, path, &mut trace)?,
+            _ => self.segments_get(&inner, path, budget, &mut trace)?,
+        };
+        self.read_amp.record(&trace);
+        if value.is_none() {
+            self.note_absent(&inner, path)?;
+            self.check_no_scalar_ancestor(&inner, &shard, path, parent_path(path))?;
         }
-        
-        Ok(())
+        Ok(value.map(from_segments))
     }
     
-    pub fn segment_counts(&self) -> (usize, usize, usize) {
-        let inner = self.inner.read().expect("Lock should not be poisoned in segment_counts");
-        (inner.segments_l0.len(), inner.segments_l1.len(), inner.segments_l2.len())
+    fn get_locked(&self, inner: &StoreInner, path: &str) -> Result<Option<String>> {
+        let shard = inner.shard(path);
+        match self.memtable_get(inner, &shard, path) {
+            Some(value) => Ok(value.map(|value| value.to_string())),
+            None => self.segments_get(inner, path, &ReadBudget::default(), &mut ReadTrace::default()),
+        }
     }
     
-    pub fn get_range(&self, start: &str, end: &str) -> io::Result<Vec<(String, String)>> {
-        self.get_range_limit(start, end, usize::MAX)
+    // Some(answer) if the memtable settles path, None if the segments have to be asked
+    fn memtable_get<'a>(&self, inner: &StoreInner, shard: &'a MemShard, path: &str) -> Option<Option<&'a CompactStr>> {
+        match shard.get(path, inner.collation) {
+            Some(MemValue::Scalar(v, seq)) if !self.covered_by_subtomb(inner, path, *seq) => Some(Some(v)),
+            Some(MemValue::PointTomb(_)) => Some(None),
+            _ => None,
+        }
     }
     
-    pub fn get_range_limit(&self, start: &str, end: &str, limit: usize) -> io::Result<Vec<(String, String)>> {
-        let inner = self.inner.read().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Lock poisoned: {}", e)))?;
-        let mut results = BTreeMap::new();
-        
-        // Collect from memtable
-        for (k, v) in &inner.memtable {
-            if k.as_str() >= start && k.as_str() < end {
-                match v {
-                    MemValue::Scalar(val, seq) => {
-                        if !self.covered_by_subtomb(&inner, k, *seq) {
-                            results.insert(k.clone(), (val.clone(), *seq));
-                        }
-                    }
-                    MemValue::PointTomb(seq) => {
-                        // Mark as tombstone
-                        results.insert(k.clone(), (String::new(), *seq | (1u64 << 63)));
-                    }
-                }
-            }
+    // The first get of path takes its slot and does the lookup; gets arriving meanwhile wait on
+    // the slot and take that answer. Every one of them holds the read lock and path's shard
+    // throughout, so no write can land between the lookup and a waiter's get and the answer is
+    // current for all.
+    // A failed lookup leaves the slot empty, and the next waiter looks up for itself. A
+    // waiter's trace has the level of the answer it took, and costs nothing.
+    fn coalesced_get(&self, inner: &StoreInner, in_flight: &Mutex<HashMap<String, GetSlot>>, path: &str, trace: &mut ReadTrace) -> Result<Option<String>> {
+        let slot = in_flight.lock().map_err(|e| WalDbError::Locked(format!("Lock poisoned: {}", e)))?
+            .entry(path.to_string()).or_default().clone();
+        let mut answer = slot.lock().map_err(|e| WalDbError::Locked(format!("Lock poisoned: {}", e)))?;
+        if let Some((value, level)) = &*answer {
+            trace.level = *level;
+            return Ok(value.clone());
         }
         
-        // Collect from all segments
-        for segment in inner.segments_l0.iter()
-            .chain(inner.segments_l1.iter())
-            .chain(inner.segments_l2.iter())
-        {
-            self.collect_range_from_segment(segment, start, end, &mut results)?;
+        let result = self.segments_get(inner, path, &ReadBudget::default(), trace);
+        if let Ok(value) = &result {
+            *answer = Some((value.clone(), trace.level));
         }
         
-        // Filter out tombstones and apply limit
-        let mut final_results = Vec::new();
-        for (key, (value, seq)) in results {
-            if seq & (1u64 << 63) == 0 {  // Not a tombstone marker
-                final_results.push((key, value));
-                if final_results.len() >= limit {
-                    break;
-                }
-            }
+        // Gone before our read lock is, so a get after the next write can't pick the answer up
+        let mut in_flight = in_flight.lock().map_err(|e| WalDbError::Locked(format!("Lock poisoned: {}", e)))?;
+        if in_flight.get(path).is_some_and(|current| Arc::ptr_eq(current, &slot)) {
+            in_flight.remove(path);
         }
-        
-        Ok(final_results)
+        result
     }
     
-    pub fn scan_prefix(&self, prefix: &str, limit: usize) -> io::Result<Vec<(String, String)>> {
-        // Use max char as end bound for prefix scan
-        let end = format!("{}~", prefix);  // ~ comes after most chars
-        self.get_range_limit(prefix, &end, limit)
+    // Whether the negative lookup cache knows the segments don't hold path
+    fn known_absent(&self, inner: &StoreInner, path: &str) -> Result<bool> {
+        let Some(negative) = &inner.negative else { return Ok(false) };
+        let hit = negative.lock().map_err(|e| WalDbError::Locked(format!("Negative cache lock poisoned: {}", e)))?.touch(path);
+        if hit {
+            self.negative_hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(hit)
     }
     
-    fn collect_range_from_segment(&self, seg: &Arc<Segment>, start: &str, end: &str, 
-                                   results: &mut BTreeMap<String, (String, u64)>) -> io::Result<()> {
-        // Find starting position in index
-        let start_idx = match seg.index.binary_search_by_key(&start.to_string(), |(k, _)| k.clone()) {
-            Ok(i) => i,
-            Err(i) => i.saturating_sub(1),  // Include the block before start
-        };
-        
-        // Iterate through relevant index entries
-        for idx in start_idx..seg.index.len() {
-            let (block_key, offset) = &seg.index[idx];
-            
-            // Skip if we're past the end
-            if block_key.as_str() >= end {
-                break;
-            }
-            
-            // Read the block
-            let next_offset = if idx + 1 < seg.index.len() {
-                seg.index[idx + 1].1
-            } else {
-                seg.index_start
-            };
-            
-            let block_data = self.cache.get_or_load(&seg.path, *offset, (next_offset - offset) as usize)?;
-            
-            // Parse all records in block
-            let mut pos = 0;
-            while pos + 8 < block_data.len() {
-                let mut seq_bytes = [0u8; 8];
-                seq_bytes.copy_from_slice(&block_data[pos..pos + 8]);
-                let seq = u64::from_le_bytes(seq_bytes);
-                pos += 8;
-                
-                if pos >= block_data.len() {
-                    break;
-                }
-                
-                let rec_type = block_data[pos];
-                pos += 1;
-                
-                if pos + 8 > block_data.len() {
-                    break;
-                }
-                
-                let mut klen_bytes = [0u8; 4];
-                klen_bytes.copy_from_slice(&block_data[pos..pos + 4]);
-                let klen = u32::from_le_bytes(klen_bytes) as usize;
-                pos += 4;
-                
-                let mut vlen_bytes = [0u8; 4];
-                vlen_bytes.copy_from_slice(&block_data[pos..pos + 4]);
-                let vlen = u32::from_le_bytes(vlen_bytes) as usize;
-                pos += 4;
-                
-                if pos + klen + vlen > block_data.len() {
-                    break;
+    // Called with the read lock and path's shard still held from the lookup, so no write has
+    // slipped in since
+    fn note_absent(&self, inner: &StoreInner, path: &str) -> Result<()> {
+        if let Some(negative) = &inner.negative {
+            negative.lock().map_err(|e| WalDbError::Locked(format!("Negative cache lock poisoned: {}", e)))?.insert(path);
+        }
+        Ok(())
+    }
+    
+    // Segments newest first: within a level the later ones were written later, and each level
+    // only ever receives what the one above it held. Archives copy live values at a new seq,
+    // so they come last and are only skipped by the seq test.
+    fn segments_newest_first(inner: &StoreInner) -> impl Iterator<Item = &Arc<Segment>> {
+        Self::segments_newest_first_by_level(inner).map(|(_, seg)| seg)
+    }
+    
+    // segments_newest_first with the level of each
+    fn segments_newest_first_by_level(inner: &StoreInner) -> impl Iterator<Item = (ReadLevel, &Arc<Segment>)> {
+        inner.segments_l0.iter().rev().map(|seg| (ReadLevel::L0, seg))
+            .chain(inner.segments_l1.iter().rev().map(|seg| (ReadLevel::L1, seg)))
+            .chain(inner.segments_l2.iter().rev().map(|seg| (ReadLevel::L2, seg)))
+            .chain(inner.segments_archive.iter().map(|seg| (ReadLevel::Archive, seg)))
+    }
+    
+    // Counting what the lookup costs into trace, and the level of the record that answers it.
+    // Fails before a probe or disk read past budget; a block's cache miss is judged just before
+    // reading it.
+    fn segments_get(&self, inner: &StoreInner, path: &str, budget: &ReadBudget, trace: &mut ReadTrace) -> Result<Option<String>> {
+        let mut best: Option<(u8, String, u64)> = None;
+        let mut disk_reads = 0;
+        // Seq of the newest record found, hidden by a subtomb or not. A segment with nothing
+        // newer can't change the answer: its record is older, and a subtomb covering the
+        // newer one covers it too. So a delete in a new segment stops the search there.
+        let mut newest = None;
+        for (level, seg) in Self::segments_newest_first_by_level(inner) {
+            if newest.is_some_and(|newest| seg.seq_high <= newest) {
+                continue;
+            }
+            if !seg.may_hold(path) {
+                continue;
+            }
+            if budget.max_segments.is_some_and(|max| trace.probed >= max) {
+                return Err(self.budget_exceeded(path, trace, disk_reads, best));
+            }
+            trace.probed += 1;
+            if let Some(bloom) = &seg.bloom {
+                if !bloom.might_contain(path) {
+                    trace.bloom_negatives += 1;
+                    continue;
                 }
-                
-                let k = String::from_utf8_lossy(&block_data[pos..pos + klen]).to_string();
-                pos += klen;
-                
-                // Check if key is in range
-                if k.as_str() >= start && k.as_str() < end {
-                    // Don't check subtombs here - will be checked at higher level
-                    {
-                        match rec_type {
-                            RT_SET => {
-                                let v = String::from_utf8_lossy(&block_data[pos..pos + vlen]).to_string();
-                                // Only update if newer
-                                if let Some((_, existing_seq)) = results.get(&k) {
-                                    if seq > (*existing_seq & !(1u64 << 63)) {
-                                        results.insert(k, (v, seq));
-                                    }
-                                } else {
-                                    results.insert(k, (v, seq));
-                                }
-                            }
-                            RT_DEL_POINT => {
-                                // Mark as tombstone with high bit set
-                                if let Some((_, existing_seq)) = results.get(&k) {
-                                    if seq > (*existing_seq & !(1u64 << 63)) {
-                                        results.insert(k, (String::new(), seq | (1u64 << 63)));
-                                    }
-                                } else {
-                                    results.insert(k, (String::new(), seq | (1u64 << 63)));
-                                }
-                            }
-                            _ => {}
-                        }
+            }
+            
+            let Some(block) = seg.block_for(path) else { continue };
+            if let Some(max) = budget.max_block_reads {
+                if !self.cache.contains(&(seg.id, seg.block_span(block).0))? {
+                    if disk_reads >= max {
+                        return Err(self.budget_exceeded(path, trace, disk_reads, best));
                     }
+                    disk_reads += 1;
+                }
+            }
+            trace.blocks += 1;
+            if let Some((rec_type, val, seq)) = self.get_from_block(seg, block, path)? {
+                newest = newest.max(Some(seq));
+                if !self.covered_by_subtomb(inner, path, seq)
+                    && best.as_ref().is_none_or(|(_, _, best_seq)| *best_seq < seq) {
+                    best = Some((rec_type, val, seq));
+                    trace.level = level;
                 }
-                
-                pos += vlen;
             }
         }
         
-        Ok(())
+        // Only the newest record's reference is followed: older ones may name values
+        // reclaim_values has dropped
+        self.record_value(best)
     }
     
-    // Wildcard pattern matching - supports * (zero or more chars) and ? (single char)
-    pub fn get_pattern(&self, pattern: &str) -> io::Result<Vec<(String, String)>> {
-        let inner = self.inner.read().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Lock poisoned: {}", e)))?;
-        let mut results = BTreeMap::new();
-        
-        // Check memtable
-        for (key, value) in &inner.memtable {
-            if Self::matches_pattern(key, pattern) {
-                match value {
-                    MemValue::Scalar(v, seq) => {
-                        if !self.covered_by_subtomb(&inner, key, *seq) {
-                            results.insert(key.clone(), Some(v.clone()));
-                        } else {
-                            results.insert(key.clone(), None);
-                        }
-                    }
-                    MemValue::PointTomb(_) => {
-                        results.insert(key.clone(), None);
-                    }
-                }
+    // The value a record get_from_block found holds, None for a delete
+    fn record_value(&self, record: Option<(u8, String, u64)>) -> Result<Option<String>> {
+        match record {
+            Some((RT_SET_REF, hex, _)) => self.resolve_value_ref(hex.as_bytes()).map(Some),
+            Some((RT_SET, value, _)) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+    
+    // What segments_get fails with when budget stops it, best being the newest record so far
+    fn budget_exceeded(&self, path: &str, trace: &ReadTrace, disk_reads: u64, best: Option<(u8, String, u64)>) -> WalDbError {
+        match self.record_value(best) {
+            Ok(candidate) => WalDbError::BudgetExceeded {
+                path: path.to_string(),
+                segments_probed: trace.probed,
+                block_reads: disk_reads,
+                candidate,
+            },
+            Err(e) => e,
+        }
+    }
+    
+    /// Whether get(path) would return a value, without building or copying that value
+    pub fn contains(&self, path: &str) -> Result<bool> {
+        let inner = self.read_inner()?;
+        let shard = inner.shard(path);
+        let found = self.contains_locked(&inner, &shard, path)?;
+        if !found {
+            self.check_no_scalar_ancestor(&inner, &shard, path, parent_path(path))?;
+        }
+        Ok(found)
+    }
+    
+    /// Whether path may hold a value, judged from memory alone: the memtable, subtree deletes,
+    /// and each segment's key bounds and bloom filter. Never reads a block, from disk or the
+    /// block cache, so a router can ask every shard before sending a get to one. False means
+    /// get would find nothing; true may be wrong, for a key a bloom filter passes or whose
+    /// delete sits in a segment.
+    pub fn may_contain(&self, path: &str) -> bool {
+        if validate_key(path).is_err() {
+            return false;
+        }
+        let Ok(inner) = self.read_inner() else {
+            return true;
+        };
+        if let Some(mv) = inner.shard(path).get(path, inner.collation) {
+            match mv {
+                MemValue::Scalar(_, seq) if !self.covered_by_subtomb(&inner, path, *seq) => return true,
+                MemValue::PointTomb(_) => return false,
+                _ => {}
             }
         }
+        // A segment a subtree delete covers for path, all of it older, has nothing live there
+        let found = Self::segments_newest_first(&inner).any(|seg| {
+            seg.may_hold(path)
+                && seg.bloom.as_ref().is_none_or(|bloom| bloom.might_contain(path))
+                && !self.covered_by_subtomb(&inner, path, seg.seq_high)
+        });
+        found
+    }
+    
+    /// Whether anything live is stored under path/: a set no newer point delete or subtree
+    /// delete hides. Stops at the first one found. Segments a subtree delete covers whole
+    /// are passed over, and a key still in a segment index is only counted once its newest
+    /// record is checked.
+    pub fn has_live_children(&self, path: &str) -> Result<bool> {
+        let inner = self.read_inner()?;
+        self.has_live_children_locked(&inner, path)
+    }
+    
+    fn has_live_children_locked(&self, inner: &StoreInner, path: &str) -> Result<bool> {
+        let prefix = if path.is_empty() || path.ends_with('/') { path.to_string() } else { format!("{}/", path) };
+        let end = prefix_end(&prefix);
         
-        // Check all segments
-        for segment in inner.segments_l0.iter()
-            .chain(inner.segments_l1.iter())
-            .chain(inner.segments_l2.iter())
+        // A memtable set is its key's newest record, so only a subtree delete can hide it
         {
-            self.collect_pattern_from_segment(segment, pattern, &mut results)?;
+            let shards = inner.shards();
+            let (low, high) = ((prefix.as_str(), inner.collation), (end.as_str(), inner.collation));
+            for (key, value) in mem_range(&shards, Bound::Included(&low), Bound::Excluded(&high)) {
+                if let MemValue::Scalar(_, seq) = value {
+                    if !is_system_key(&key.key) && !self.covered_by_subtomb(inner, &key.key, *seq) {
+                        return Ok(true);
+                    }
+                }
+            }
         }
         
-        // Filter out tombstones
-        Ok(results.into_iter()
-            .filter_map(|(k, v)| v.map(|val| (k, val)))
-            .collect())
-    }
-    
-    fn collect_pattern_from_segment(&self, seg: &Arc<Segment>, pattern: &str, 
-                                     results: &mut BTreeMap<String, Option<String>>) -> io::Result<()> {
-        // Read through entire segment looking for pattern matches
-        for idx in 0..seg.index.len() {
-            let (_block_key, offset) = &seg.index[idx];
-            
-            // Read the block
-            let next_offset = if idx + 1 < seg.index.len() {
-                seg.index[idx + 1].1
-            } else {
-                seg.index_start
+        // A live key has an uncovered set somewhere; contains_locked says if it is the newest
+        let mut checked = HashSet::new();
+        for seg in Self::segments_newest_first(inner) {
+            if !seg.overlaps(&prefix, &end) || self.covered_by_subtomb(inner, &prefix, seg.seq_high) {
+                continue;
+            }
+            let collation = seg.collation;
+            let start_idx = match seg.index.search(prefix.as_bytes(), collation) {
+                Ok(i) => i,
+                Err(i) => i.saturating_sub(1),
             };
-            
-            let block_data = self.cache.get_or_load(&seg.path, *offset, (next_offset - offset) as usize)?;
-            
-            // Parse all records in block
-            let mut pos = 0;
-            while pos + 8 < block_data.len() {
-                let mut seq_bytes = [0u8; 8];
-                seq_bytes.copy_from_slice(&block_data[pos..pos + 8]);
-                let seq = u64::from_le_bytes(seq_bytes);
-                pos += 8;
-                
-                if pos >= block_data.len() {
-                    break;
-                }
-                
-                let rec_type = block_data[pos];
-                pos += 1;
-                
-                if pos + 8 > block_data.len() {
+            for idx in start_idx..seg.index.len() {
+                if collation.compare(seg.index.key(idx), end.as_bytes()).is_ge() {
                     break;
                 }
-                
-                let mut klen_bytes = [0u8; 4];
-                klen_bytes.copy_from_slice(&block_data[pos..pos + 4]);
-                let klen = u32::from_le_bytes(klen_bytes) as usize;
-                pos += 4;
-                
-                let mut vlen_bytes = [0u8; 4];
-                vlen_bytes.copy_from_slice(&block_data[pos..pos + 4]);
-                let vlen = u32::from_le_bytes(vlen_bytes) as usize;
-                pos += 4;
-                
-                if pos + klen + vlen > block_data.len() {
-                    break;
-                }
-                
-                let k = String::from_utf8_lossy(&block_data[pos..pos + klen]).to_string();
-                pos += klen;
-                
-                // Check if key matches pattern
-                if Self::matches_pattern(&k, pattern) && !results.contains_key(&k) {
-                    let inner = self.inner.read().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Lock poisoned: {}", e)))?;
-                    match rec_type {
-                        RT_SET => {
-                            let v = String::from_utf8_lossy(&block_data[pos..pos + vlen]).to_string();
-                            if !self.covered_by_subtomb(&inner, &k, seq) {
-                                results.insert(k, Some(v));
-                            } else {
-                                results.insert(k, None);
-                            }
-                        }
-                        RT_DEL_POINT => {
-                            results.insert(k, None);
-                        }
-                        _ => {}
+                let (offset, len) = seg.block_span(idx);
+                let block_data = self.cache.get_or_load(seg, offset, len, BlockAccess::Scan)?;
+                let mut reader = BlockReader::new(seg, &block_data, offset);
+                while let Some(record) = reader.next_record() {
+                    if !record.key.starts_with(prefix.as_bytes()) || !matches!(record.rec_type, RT_SET | RT_SET_REF) {
+                        continue;
+                    }
+                    let key = record.key_text(seg, self.lossy_reads)?;
+                    if is_system_key(&key) || self.covered_by_subtomb(inner, &key, record.seq) || !checked.insert(key.to_string()) {
+                        continue;
+                    }
+                    if self.contains_locked(inner, &inner.shard(&key), &key)? {
+                        return Ok(true);
                     }
                 }
-                
-                pos += vlen;
             }
         }
-        
+        Ok(false)
+    }
+    
+    // StoreOptions::strict_reads: fail a read of path that found nothing if from or one of its
+    // ancestors holds a scalar. They all share path's first segment, so shard is theirs too.
+    fn check_no_scalar_ancestor(&self, inner: &StoreInner, shard: &MemShard, path: &str, from: Option<String>) -> Result<()> {
+        if !self.strict_reads {
+            return Ok(());
+        }
+        let mut ancestor = from;
+        while let Some(candidate) = ancestor {
+            if self.contains_locked(inner, shard, &candidate)? {
+                return Err(WalDbError::TreeSemantics { path: path.to_string(), ancestor: Some(candidate) });
+            }
+            ancestor = parent_path(&candidate);
+        }
         Ok(())
     }
     
-    // Delete all keys matching a wildcard pattern
-    pub fn delete_pattern(&self, pattern: &str) -> io::Result<usize> {
-        let matches = self.get_pattern(pattern)?;
-        let count = matches.len();
-        
-        for (key, _) in matches {
-            self.delete(&key)?;
+    // The deepest path a prefix read is under: the prefix itself without its '/', or the parent
+    // of one ending mid-segment
+    fn prefix_owner(prefix: &str) -> Option<String> {
+        match prefix.strip_suffix('/') {
+            Some(owner) if !owner.is_empty() => Some(owner.to_string()),
+            Some(_) => None,
+            None => parent_path(prefix),
         }
-        
-        Ok(count)
     }
     
-    // Helper: Check if a key matches a wildcard pattern
-    fn matches_pattern(key: &str, pattern: &str) -> bool {
-        Self::matches_pattern_recursive(key.chars().collect::<Vec<_>>().as_slice(), 
-                                         pattern.chars().collect::<Vec<_>>().as_slice())
+    /// The size and age of the memtable, the writes not yet flushed to a segment
+    pub fn memtable_stats(&self) -> Result<MemtableStats> {
+        let inner = self.read_inner()?;
+        Ok(MemtableStats { entries: inner.memtable_len(), bytes: inner.memtable_size(), age: inner.memtable_age() })
     }
     
-    fn matches_pattern_recursive(key: &[char], pattern: &[char]) -> bool {
-        match (pattern.first(), key.first()) {
-            (None, None) => true,  // Both exhausted
-            (None, Some(_)) => false,  // Pattern exhausted but key has more
-            (Some('*'), _) => {
-                // * matches zero or more characters
-                // Try matching with 0 chars (skip *)
-                if Self::matches_pattern_recursive(key, &pattern[1..]) {
-                    return true;
-                }
-                // Try matching with 1+ chars (consume one char and keep *)
-                if !key.is_empty() && Self::matches_pattern_recursive(&key[1..], pattern) {
-                    return true;
-                }
-                false
-            }
-            (Some('?'), None) => false,  // ? needs exactly one char but key is empty
-            (Some('?'), Some(_)) => {
-                // ? matches exactly one character
-                Self::matches_pattern_recursive(&key[1..], &pattern[1..])
-            }
-            (Some(&_p), None) => false,  // Pattern has more but key is exhausted
-            (Some(&p), Some(&k)) => {
-                // Regular character must match exactly
-                p == k && Self::matches_pattern_recursive(&key[1..], &pattern[1..])
-            }
+    /// Block reads and negative cache hits by point lookups since open
+    pub fn lookup_stats(&self) -> LookupStats {
+        LookupStats {
+            blocks_read: self.blocks_read.load(Ordering::Relaxed),
+            negative_cache_hits: self.negative_hits.load(Ordering::Relaxed),
         }
     }
     
-    pub fn delete_subtree(&self, prefix: &str) -> io::Result<()> {
-        let mut inner = self.inner.write().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Lock poisoned: {}", e)))?;
-        inner.seq += 1;
-        let seq = inner.seq;
-        
-        let prefix = if prefix.ends_with('/') {
-            prefix.to_string()
-        } else {
-            format!("{}/", prefix)
-        };
-        
-        self.wal.append(&WALEntry {
-            seq,
-            kind: RT_DEL_SUB,
-            key: prefix.clone(),
-            value: None,
-        })?;
-        
-        inner.subtombs.insert(prefix, seq);
-        Ok(())
+    /// What gets and get_refs have cost since open, by the level whose record answered
+    /// them: the segments each probed, those its bloom filter ruled out and the blocks it
+    /// searched. Probes piling up on L0 gets call for compacting sooner, and probes blooms
+    /// don't rule out on not found gets for bigger filters. Reads writes make, as_of and
+    /// contains aren't counted.
+    pub fn read_amplification_report(&self) -> ReadAmplificationReport {
+        self.read_amp.report()
     }
     
-    fn compaction_thread(&self) {
-        loop {
-            // Sleep for a bit between compaction checks
-            thread::sleep(Duration::from_secs(5));
-            
-            // Check for shutdown
-            let (lock, _cvar) = &*self.compaction_shutdown;
-            let shutdown = lock.lock().expect("Compaction shutdown lock should not be poisoned");
-            if *shutdown {
-                break;
-            }
-            drop(shutdown);
-            
-            // Check if L0 compaction is needed
-            let needs_l0_compaction = {
-                let inner = self.inner.read().expect("Inner lock should not be poisoned in compaction thread");
-                inner.segments_l0.len() >= L0_COMPACTION_THRESHOLD
-            };
-            
-            if needs_l0_compaction {
-                if let Err(e) = self.compact_l0_to_l1() {
-                    // Log error but continue
-                    // eprintln!("L0 compaction error: {}", e);
-                    let _ = e; // Suppress warning
-                }
-            }
-            
-            // Check if L1 compaction is needed
-            let needs_l1_compaction = {
-                let inner = self.inner.read().expect("Inner lock should not be poisoned in compaction thread");
-                inner.segments_l1.len() >= L1_COMPACTION_THRESHOLD
-            };
-            
-            if needs_l1_compaction {
-                if let Err(e) = self.compact_l1_to_l2() {
-                    // Log error but continue
-                    let _ = e; // Suppress warning
-                }
-            }
+    /// Groups read by searches since open
+    pub fn search_stats(&self) -> SearchStats {
+        SearchStats { groups_visited: self.groups_visited.load(Ordering::Relaxed) }
+    }
+    
+    /// Bytes compaction has read and written since open
+    pub fn compaction_stats(&self) -> CompactionStats {
+        CompactionStats {
+            bytes_read: self.compaction_io.0.load(Ordering::Relaxed),
+            bytes_written: self.compaction_io.1.load(Ordering::Relaxed),
         }
     }
     
-    fn compact_l0_to_l1(&self) -> io::Result<()> {
-        // Take segments to compact
-        let segments_to_compact = {
-            let mut inner = self.inner.write().expect("Inner write lock should not be poisoned in L0 compaction");
-            if inner.segments_l0.len() < L0_COMPACTION_THRESHOLD {
-                return Ok(());
-            }
-            
-            // Take the oldest L0 segments
-            let mut to_compact = Vec::new();
-            for _ in 0..L0_COMPACTION_THRESHOLD {
-                if let Some(seg) = inner.segments_l0.first() {
-                    to_compact.push(seg.clone());
-                    inner.segments_l0.remove(0);
-                }
-            }
-            to_compact
+    /// L0's size now against StoreOptions::l0_slowdown_threshold and l0_stop_threshold, and
+    /// the flushes they have held up since open
+    pub fn l0_stats(&self) -> Result<L0Stats> {
+        let segments = self.read_inner()?.segments_l0.len();
+        let pressure = if self.l0_stop.is_some_and(|stop| segments >= stop) {
+            L0Pressure::Stopped
+        } else if self.l0_slowdown.is_some_and(|slowdown| segments >= slowdown) {
+            L0Pressure::Slowdown
+        } else {
+            L0Pressure::Normal
         };
-        
-        if segments_to_compact.is_empty() {
-            return Ok(());
-        }
-        
-        // Create new L1 segment
-        let filename = format!("l1_{:010}.seg", SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("System time should be valid")
-            .as_secs());
-        let new_path = self.dir.join(&filename);
-        
-        // Merge segments
-        let merged_segment = self.merge_segments(&segments_to_compact, &new_path, 1)?;
-        
-        // Update state
-        {
-            let mut inner = self.inner.write().expect("Inner write lock should not be poisoned when updating L1 segments");
-            inner.segments_l1.push(Arc::new(merged_segment));
+        Ok(L0Stats {
+            segments,
+            pressure,
+            slowdowns: self.l0_throttling.0.load(Ordering::Relaxed),
+            stalls: self.l0_throttling.1.load(Ordering::Relaxed),
+            stall_time: Duration::from_micros(self.l0_throttling.2.load(Ordering::Relaxed)),
+        })
+    }
+    
+    /// Block cache hits and misses by every read since open, and what it holds now
+    pub fn cache_stats(&self) -> CacheStats {
+        let (resident_bytes, pinned_bytes) = self.cache.resident_bytes();
+        CacheStats {
+            hits: self.cache.hits.load(Ordering::Relaxed),
+            misses: self.cache.misses.load(Ordering::Relaxed),
+            resident_bytes: resident_bytes as u64,
+            pinned_bytes: pinned_bytes as u64,
         }
-        
-        // Update manifest
-        {
-            let mut manifest = self.manifest.lock().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Manifest lock poisoned: {}", e)))?;
-            manifest.add_entry(ManifestEntry {
-                seq_high: segments_to_compact.iter()
-                    .map(|s| s.seq_high)
-                    .max()
-                    .unwrap_or(0),
-                level: 1,
-                filename,
-            })?;
+    }
+    
+    /// Every counter StoreOptions::stats_interval samples, as of now
+    pub fn metrics(&self) -> Result<MetricsSnapshot> {
+        let inner = self.read_inner()?;
+        Ok(self.metrics_sources().sample(inner.memtable_size() as u64, inner.seq()))
+    }
+    
+    /// The samples stats.ring holds from since on, oldest first, including ones written
+    /// before the last reopen. Empty if StoreOptions::stats_interval was never set, or in memory.
+    pub fn stats_history(&self, since: SystemTime) -> Result<Vec<TimestampedSnapshot>> {
+        if self.in_memory {
+            return Ok(Vec::new());
         }
-        
-        // Delete old segment files
-        for seg in segments_to_compact {
-            let _ = fs::remove_file(&seg.path);
+        Self::read_stats_history(&self.dir, since)
+    }
+    
+    /// stats_history for the store in dir, read straight from its stats.ring, so it works
+    /// on a store another process has open
+    pub fn read_stats_history(dir: &Path, since: SystemTime) -> Result<Vec<TimestampedSnapshot>> {
+        let mut samples = match StatsRing::read(&dir.join(STATS_RING_FILE)) {
+            Ok(samples) => samples,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        samples.retain(|(_, sample)| sample.at >= since);
+        samples.sort_by_key(|(number, _)| *number);
+        Ok(samples.into_iter().map(|(_, sample)| sample).collect())
+    }
+    
+    fn metrics_sources(&self) -> MetricsSources {
+        MetricsSources {
+            wal: self.wal.clone(),
+            cache: self.cache.clone(),
+            flushed_seq: self.flushed_seq.clone(),
+            compaction_io: self.compaction_io.clone(),
+            compaction_timing: self.compaction_timing.clone(),
+            flush_timing: self.flush_timing.clone(),
         }
-        
-        Ok(())
     }
     
-    fn compact_l1_to_l2(&self) -> io::Result<()> {
-        // Similar to L0->L1 but for L1->L2
-        let segments_to_compact = {
-            let mut inner = self.inner.write().expect("Inner write lock should not be poisoned in L0 compaction");
-            if inner.segments_l1.len() < L1_COMPACTION_THRESHOLD {
-                return Ok(());
+    /// Read segment blocks into the block cache before the reads that would miss on them, as
+    /// after a restart. Takes the segments on options.levels and, with options.prefixes, only
+    /// their blocks that may hold keys under one. Blocks already cached are skipped and runs
+    /// of adjacent ones are read together, a segment at a time in level order, until the
+    /// byte budget would be passed.
+    pub fn warmup(&self, options: WarmupOptions) -> Result<WarmupReport> {
+        let (segments, live) = {
+            let inner = self.read_inner()?;
+            let levels = [&inner.segments_l0, &inner.segments_l1, &inner.segments_l2, &inner.segments_archive];
+            let live: HashSet<u64> = levels.iter().flat_map(|segments| segments.iter().map(|seg| seg.id)).collect();
+            let segments: Vec<(usize, Arc<Segment>)> = levels.into_iter()
+                .enumerate()
+                .filter(|(level, _)| options.levels.is_empty() || options.levels.contains(level))
+                .flat_map(|(level, segments)| segments.iter().map(move |seg| (level, seg.clone())))
+                .collect();
+            (segments, live)
+        };
+        // Pins of segments retired some way other than retire_segments
+        self.cache.unpin(|id| !live.contains(&id))?;
+        
+        let budget = options.max_bytes.min(self.cache.max_size as u64);
+        let mut pin_room = self.pin_budget.map_or(0, |budget| budget.saturating_sub(self.cache.resident_bytes().1));
+        let mut report = WarmupReport { complete: true, ..Default::default() };
+        for (level, seg) in segments {
+            let mut blocks = Vec::new();
+            for idx in Self::warmup_blocks(&seg, &options.prefixes) {
+                if !self.cache.contains(&(seg.id, seg.block_span(idx).0))? {
+                    blocks.push(idx);
+                }
             }
+            // A small L2 segment is pinned whole or not at all
+            let bytes: usize = blocks.iter().map(|&idx| seg.block_span(idx).1).sum();
+            let pin = level == 2 && self.pin_budget.is_some() && bytes <= pin_room;
             
-            // Take the oldest L1 segments
-            let mut to_compact = Vec::new();
-            for _ in 0..L1_COMPACTION_THRESHOLD {
-                if let Some(seg) = inner.segments_l1.first() {
-                    to_compact.push(seg.clone());
-                    inner.segments_l1.remove(0);
+            let mut run: Vec<usize> = Vec::new();
+            let mut run_bytes = 0;
+            for idx in blocks {
+                let len = seg.block_span(idx).1;
+                if report.bytes_loaded + (run_bytes + len) as u64 > budget {
+                    report.complete = false;
+                    break;
+                }
+                if run.last().is_some_and(|&last| last + 1 != idx) || run_bytes + len > WARMUP_READ_BYTES {
+                    self.warmup_run(&seg, &run, pin, &mut report)?;
+                    run.clear();
+                    run_bytes = 0;
                 }
+                run.push(idx);
+                run_bytes += len;
+            }
+            self.warmup_run(&seg, &run, pin, &mut report)?;
+            if pin {
+                pin_room -= bytes.min(pin_room);
+            }
+            if !report.complete {
+                break;
             }
-            to_compact
-        };
-        
-        if segments_to_compact.is_empty() {
-            return Ok(());
         }
-        
-        // Create new L2 segment
-        let filename = format!("l2_{:010}.seg", SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("System time should be valid")
-            .as_secs());
-        let new_path = self.dir.join(&filename);
-        
-        // Merge segments with more aggressive tombstone removal
-        let merged_segment = self.merge_segments(&segments_to_compact, &new_path, 2)?;
-        
-        // Update state
-        {
-            let mut inner = self.inner.write().expect("Inner write lock should not be poisoned when updating L2 segments");
-            inner.segments_l2.push(Arc::new(merged_segment));
+        Ok(report)
+    }
+    
+    // Indexes of seg's blocks that may hold keys under any of prefixes, in order
+    fn warmup_blocks(seg: &Segment, prefixes: &[String]) -> Vec<usize> {
+        if prefixes.is_empty() {
+            return (0..seg.index.len()).collect();
         }
-        
-        // Update manifest
-        {
-            let mut manifest = self.manifest.lock().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Manifest lock poisoned: {}", e)))?;
-            manifest.add_entry(ManifestEntry {
-                seq_high: segments_to_compact.iter()
-                    .map(|s| s.seq_high)
-                    .max()
-                    .unwrap_or(0),
-                level: 2,
-                filename,
-            })?;
+        let mut blocks = Vec::new();
+        for prefix in prefixes {
+            // The same parent scan collect_prefix_locked does under numeric-aware order
+            let from = if seg.collation == Collation::Binary || prefix.is_empty() || prefix.ends_with('/') {
+                prefix.as_str()
+            } else {
+                prefix.rfind('/').map_or("", |slash| &prefix[..=slash])
+            };
+            let first = match seg.index.search(from.as_bytes(), seg.collation) {
+                Ok(i) => i,
+                Err(i) => i.saturating_sub(1),  // The block before from may hold it
+            };
+            let end = match seg.index.search(prefix_end(from).as_bytes(), seg.collation) {
+                Ok(i) | Err(i) => i,
+            };
+            blocks.extend(first..end);
         }
-        
-        // Delete old segment files
-        for seg in segments_to_compact {
-            let _ = fs::remove_file(&seg.path);
+        blocks.sort_unstable();
+        blocks.dedup();
+        blocks
+    }
+    
+    // Read adjacent blocks of seg with one read and put each in the cache
+    fn warmup_run(&self, seg: &Segment, run: &[usize], pin: bool, report: &mut WarmupReport) -> Result<()> {
+        let (Some(&first), Some(&last)) = (run.first(), run.last()) else { return Ok(()) };
+        let start = seg.block_span(first).0;
+        let (last_offset, last_len) = seg.block_span(last);
+        let data = seg.read_at(start, (last_offset - start) as usize + last_len, &self.events)?;
+        for &idx in run {
+            let (offset, len) = seg.block_span(idx);
+            let at = (offset - start) as usize;
+            self.cache.preload((seg.id, offset), Arc::new(data[at..at + len].to_vec()), pin)?;
+            self.events.emit_with(|| StoreEvent::BlockLoaded { path: seg.path.clone(), offset, bytes: len });
+            report.bytes_loaded += len as u64;
+            report.blocks_loaded += 1;
+            if pin {
+                report.bytes_pinned += len as u64;
+            }
         }
-        
         Ok(())
     }
     
-    fn merge_segments(&self, segments: &[Arc<Segment>], output_path: &Path, level: usize) -> io::Result<Segment> {
-        let mut writer = SegmentWriter::new(output_path)?;
-        
-        // Collect all records from segments
-        let mut all_records: BTreeMap<String, (u8, Option<String>, u64)> = BTreeMap::new();
-        
-        for segment in segments {
-            // Read all records from segment
-            for (key, offset) in &segment.index {
-                // Read block containing this key
-                let next_offset = segment.index.iter()
-                    .find(|(k, _)| k > key)
-                    .map(|(_, o)| *o)
-                    .unwrap_or(segment.index_start);
-                
-                let block_size = (next_offset - offset) as usize;
-                let mut file = File::open(&segment.path)?;
-                file.seek(SeekFrom::Start(*offset))?;
-                
-                let mut block_data = vec![0u8; block_size];
-                file.read_exact(&mut block_data)?;
-                
-                // Parse records from block
-                let mut pos = 0;
-                while pos + 8 < block_data.len() {
-                    let mut seq_bytes = [0u8; 8];
-                    seq_bytes.copy_from_slice(&block_data[pos..pos + 8]);
-                    let seq = u64::from_le_bytes(seq_bytes);
-                    pos += 8;
-                    
-                    if pos >= block_data.len() {
-                        break;
-                    }
-                    
-                    let rec_type = block_data[pos];
-                    pos += 1;
-                    
-                    if pos + 8 > block_data.len() {
-                        break;
-                    }
-                    
-                    let mut klen_bytes = [0u8; 4];
-                    klen_bytes.copy_from_slice(&block_data[pos..pos + 4]);
-                    let klen = u32::from_le_bytes(klen_bytes) as usize;
-                    pos += 4;
-                    
-                    let mut vlen_bytes = [0u8; 4];
-                    vlen_bytes.copy_from_slice(&block_data[pos..pos + 4]);
-                    let vlen = u32::from_le_bytes(vlen_bytes) as usize;
-                    pos += 4;
-                    
-                    if pos + klen + vlen > block_data.len() {
-                        break;
-                    }
-                    
-                    let k = String::from_utf8_lossy(&block_data[pos..pos + klen]).to_string();
-                    pos += klen;
-                    
-                    let value = if rec_type == RT_SET && vlen > 0 {
-                        Some(String::from_utf8_lossy(&block_data[pos..pos + vlen]).to_string())
-                    } else {
-                        None
-                    };
-                    pos += vlen;
-                    
-                    // Keep only the newest version of each key
-                    if let Some(existing) = all_records.get(&k) {
-                        if seq > existing.2 {
-                            all_records.insert(k, (rec_type, value, seq));
-                        }
-                    } else {
-                        all_records.insert(k, (rec_type, value, seq));
-                    }
-                }
+    /// Writes counted per StoreOptions::accounting_roots bucket since open or the last reset,
+    /// for each bucket written since or still inside its rate window. Empty without roots.
+    pub fn prefix_write_stats(&self) -> BTreeMap<String, PrefixWriteStats> {
+        self.accounting.as_ref().map(|accounting| accounting.stats()).unwrap_or_default()
+    }
+    
+    /// Zero every bucket's ops and bytes. Rate windows carry on, so a reset doesn't stop a
+    /// bucket already over write_rate_limit from being reported.
+    pub fn reset_prefix_write_stats(&self) {
+        if let Some(accounting) = &self.accounting {
+            accounting.reset();
+        }
+    }
+    
+    // Count a write of key toward its accounting bucket, if it has one
+    fn account(&self, key: &str, bytes: usize) {
+        if let Some(accounting) = &self.accounting {
+            accounting.record(key, bytes as u64);
+        }
+    }
+    
+    // With path's shard held, as get holds it
+    fn contains_locked(&self, inner: &StoreInner, shard: &MemShard, path: &str) -> Result<bool> {
+        if let Some(mv) = shard.get(path, inner.collation) {
+            match mv {
+                MemValue::Scalar(_, seq) if !self.covered_by_subtomb(inner, path, *seq) => return Ok(true),
+                MemValue::PointTomb(_) => return Ok(false),
+                _ => {}
             }
         }
+        if self.known_absent(inner, path)? {
+            return Ok(false);
+        }
         
-        // Write merged records to new segment
-        for (key, (rec_type, value, seq)) in all_records {
-            // In L2, skip tombstones entirely (they've done their job)
-            if level >= 2 && rec_type != RT_SET {
+        // Newest version across segments decides, as in get
+        let mut best: Option<(bool, u64)> = None;
+        let mut newest = None;
+        for seg in Self::segments_newest_first(inner) {
+            if newest.is_some_and(|newest| seg.seq_high <= newest) {
+                continue;
+            }
+            if !seg.may_hold(path) {
                 continue;
             }
+            if let Some(bloom) = &seg.bloom {
+                if !bloom.might_contain(path) {
+                    continue;
+                }
+            }
             
-            // In L0/L1, preserve tombstones to shadow older data
-            writer.add(rec_type, &key, value.as_deref(), seq)?;
+            if let Some((live, seq)) = self.find_in_segment(seg, path, |record| (record.rec_type != RT_DEL_POINT, record.seq))? {
+                newest = newest.max(Some(seq));
+                if !self.covered_by_subtomb(inner, path, seq)
+                    && best.is_none_or(|(_, best_seq)| best_seq < seq) {
+                    best = Some((live, seq));
+                }
+            }
         }
         
-        writer.finish()
+        let live = best.is_some_and(|(live, _)| live);
+        if !live {
+            self.note_absent(inner, path)?;
+        }
+        Ok(live)
     }
     
-    // ==================== FILE/BLOB SUPPORT ====================
+    fn covered_by_subtomb(&self, inner: &StoreInner, key: &str, seq: u64) -> bool {
+        for (prefix, tomb_seq) in &inner.subtombs {
+            if key.starts_with(prefix) && *tomb_seq >= seq {
+                return true;
+            }
+        }
+        false
+    }
     
-    /// Store a file as a blob with automatic compression and deduplication
-    pub fn set_file(&self, path: &str, data: &[u8]) -> io::Result<()> {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        
-        // Calculate hash for deduplication
-        let mut hasher = DefaultHasher::new();
-        data.hash(&mut hasher);
-        let hash = format!("{:016x}", hasher.finish());
-        
-        // Create blob directory structure
-        let blob_dir = self.dir.join("blobs").join(&hash[0..2]);
-        fs::create_dir_all(&blob_dir)?;
-        let blob_path = blob_dir.join(&hash);
-        
-        // Only write if blob doesn't exist (deduplication)
-        if !blob_path.exists() {
-            // Simple compression using zlib-style DEFLATE
-            // For production, would use zstd or similar
-            let compressed = Self::compress_data(data)?;
-            fs::write(&blob_path, compressed)?;
+    fn get_from_segment(&self, seg: &Arc<Segment>, key: &str) -> Result<Option<(u8, String, u64)>> {
+        match seg.block_for(key) {
+            Some(block) => self.get_from_block(seg, block, key),
+            None => Ok(None),
         }
+    }
+    
+    fn get_from_block(&self, seg: &Arc<Segment>, block: usize, key: &str) -> Result<Option<(u8, String, u64)>> {
+        // Returns Some((RT_SET, value, seq)) for RT_SET
+        // Returns Some((RT_SET_REF, hash, seq)) for RT_SET_REF, for the caller to resolve
+        // Returns Some((RT_DEL_POINT, "", seq)) for RT_DEL_POINT
+        // Returns None for not found
+        self.find_in_block(seg, block, key, |record| {
+            Ok((record.rec_type, record.value_text(seg, self.lossy_reads)?.into_owned(), record.seq))
+        })?.transpose()
+    }
+    
+    // The value an RT_SET_REF record names by its hex content hash
+    fn resolve_value_ref(&self, hex: &[u8]) -> Result<String> {
+        self.value_cache.resolve(&self.values, hex)
+    }
+    
+    // Locate key's set or point delete in a segment and hand the record to f, which runs
+    // while the block is borrowed so callers only copy out what they need
+    fn find_in_segment<T>(&self, seg: &Arc<Segment>, key: &str, f: impl FnOnce(&BlockRecord) -> T) -> Result<Option<T>> {
+        match seg.block_for(key) {
+            Some(block) => self.find_in_block(seg, block, key, f),
+            None => Ok(None),
+        }
+    }
+    
+    // find_in_segment once block_for has found the block
+    fn find_in_block<T>(&self, seg: &Arc<Segment>, block: usize, key: &str, f: impl FnOnce(&BlockRecord) -> T) -> Result<Option<T>> {
+        let (offset, len) = seg.block_span(block);
+        let block_data = self.cache.get_or_load(seg, offset, len, BlockAccess::Point)?;
+        self.blocks_read.fetch_add(1, Ordering::Relaxed);
         
-        // Store metadata in tree
-        let blob_ref = format!("blob:{}", hash);
-        self.set(path, &blob_ref, false)?;
-        self.set(&format!("{}:size", path), &data.len().to_string(), false)?;
-        self.set(&format!("{}:hash", path), &hash, false)?;
-        
-        // Detect MIME type (simplified)
-        let mime_type = Self::detect_mime_type(data);
-        self.set(&format!("{}:type", path), mime_type, false)?;
+        // Parse block
+        let mut reader = BlockReader::new(seg, &block_data, offset);
+        while let Some(record) = reader.next_record() {
+            // Records are sorted, so once past the key it isn't in this block
+            if seg.collation.compare(record.key, key.as_bytes()).is_gt() {
+                break;
+            }
+            
+            if record.key == key.as_bytes() && matches!(record.rec_type, RT_SET | RT_SET_REF | RT_DEL_POINT) {
+                return Ok(Some(f(&record)));
+            }
+        }
         
-        Ok(())
+        Ok(None)
     }
     
-    /// Retrieve a file from blob storage
-    pub fn get_file(&self, path: &str) -> io::Result<Vec<u8>> {
-        // Get blob reference
-        let blob_ref = self.get(path)?
-            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
+    // Write the memtable out as an L0 segment and put it in use. Its manifest entry waits in
+    // unlisted for list_flushed, once the inner lock is released: until then the WAL still
+    // holds every write in the segment, and a compaction lists waiting entries before its own.
+    // Subtree deletes, which no segment holds, go in the manifest first, memtable or none, so
+    // a flush persists every delete as well as every write. Once the segments are written the
+    // seq follows them, for open to carry on past even with the WAL gone.
+    fn flush_memtable_locked(&self, inner: &mut StoreInner) -> Result<()> {
+        if self.manifest_locked()?.add_subtombs(&inner.subtombs)? {
+            self.manifest_unsynced.store(true, Ordering::Release);
+        }
+        if inner.memtable_len() == 0 {
+            return Ok(());
+        }
         
-        let hash = blob_ref.strip_prefix("blob:")
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid blob reference"))?;
+        let started = Instant::now();
+        let bytes = inner.memtable_size();
         
-        // Read compressed blob
-        let blob_path = self.dir.join("blobs").join(&hash[0..2]).join(hash);
-        let compressed = fs::read(blob_path)?;
+        // Sidecar rows become durable no later than the segment holding the same writes
+        self.vectors_locked()?.sync()?;
         
-        // Decompress and return
-        Self::decompress_data(&compressed)
-    }
-    
-    /// Delete a file and its metadata
-    pub fn delete_file(&self, path: &str) -> io::Result<()> {
-        // Delete all metadata
-        self.delete(path)?;
-        self.delete(&format!("{}:size", path))?;
-        self.delete(&format!("{}:type", path))?;
-        self.delete(&format!("{}:hash", path))?;
+        let mut outputs = vec![self.new_segment_file(0)?];
         
-        // Note: Blob itself is not deleted (might be referenced elsewhere)
-        // Could implement reference counting or garbage collection later
+        // The manifest only ever lists a finished file, so a partial one can just go. A disk
+        // that refuses the segment degrades the store, as a failed WAL sync does.
+        let segs = match self.write_memtable_segments(inner, &mut outputs) {
+            Ok(segs) => segs,
+            Err(e) => {
+                for (path, _) in &outputs {
+                    let _ = self.segment_registry.unlink(path);
+                }
+                if let WalDbError::Io(error) = &e {
+                    self.wal.degrade(error);
+                }
+                return Err(e);
+            }
+        };
+        
+        let mut manifest = self.manifest_locked()?;
+        if manifest.raise_high_water(inner.seq())? | manifest.raise_clock(self.clock.now())? {
+            self.manifest_unsynced.store(true, Ordering::Release);
+        }
+        drop(manifest);
+        let mut unlisted = self.unlisted_locked()?;
+        for (seg, (_, filename)) in segs.into_iter().zip(outputs) {
+            unlisted.push(ManifestEntry {
+                seq_high: seg.seq_high,
+                level: 0,
+                filename,
+                archive_of: None,
+                inputs: Vec::new(),
+                bounds: seg.bounds.clone(),
+            });
+            inner.segments_l0.push(Arc::new(seg));
+        }
+        drop(unlisted);
+        self.flushed_seq.store(inner.seq(), Ordering::SeqCst);
+        for shard in &mut inner.memtable {
+            *shard.get_mut().unwrap_or_else(|e| e.into_inner()) = MemShard::default();
+        }
+        *inner.memtable_size.get_mut() = 0;
+        *inner.memtable_born.get_mut() = 0;
+        let duration = started.elapsed();
+        self.flush_timing.0.fetch_add(1, Ordering::Relaxed);
+        self.flush_timing.1.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.events.emit(StoreEvent::MemtableFlushed { bytes, duration });
+        if inner.segments_l0.len() >= L0_COMPACTION_THRESHOLD {
+            self.wake_compaction();
+        }
+        
+        self.wal.sync_now()?;
         
         Ok(())
     }
     
-    // Simple compression helpers (would use proper library in production)
-    fn compress_data(data: &[u8]) -> io::Result<Vec<u8>> {
-        // For now, just store uncompressed with a header
-        // In production, would use zstd::encode_all(data, 3)
-        let mut result = vec![0u8; 4]; // Magic header for "uncompressed"
-        result.extend_from_slice(data);
-        Ok(result)
+    // A writer for a new segment at path, kept in memory by a store opened there
+    fn segment_writer(&self, path: &Path, collation: Collation) -> io::Result<SegmentWriter> {
+        match self.in_memory {
+            true => SegmentWriter::in_memory(path, collation),
+            false => SegmentWriter::new(path, collation),
+        }
     }
     
-    fn decompress_data(data: &[u8]) -> io::Result<Vec<u8>> {
-        // For now, just strip header
-        // In production, would use zstd::decode_all(data)
-        if data.len() < 4 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid compressed data"));
-        }
-        Ok(data[4..].to_vec())
+    // Where a new segment for level goes, under a file name nothing has used
+    fn new_segment_file(&self, level: usize) -> Result<(PathBuf, String)> {
+        let filename = self.manifest_locked()?.new_file_name()?;
+        Ok(self.segment_dirs.place(level, filename)?)
     }
     
-    fn detect_mime_type(data: &[u8]) -> &'static str {
-        // Simple magic byte detection
-        if data.starts_with(b"\x89PNG") { return "image/png"; }
-        if data.starts_with(b"\xFF\xD8\xFF") { return "image/jpeg"; }
-        if data.starts_with(b"GIF8") { return "image/gif"; }
-        if data.starts_with(b"%PDF") { return "application/pdf"; }
-        if data.starts_with(b"PK") { return "application/zip"; }
-        if data.starts_with(b"{") || data.starts_with(b"[") { return "application/json"; }
-        
-        // Try to detect text
-        if data.len() > 0 && data.iter().all(|&b| b < 128 && (b >= 32 || b == 9 || b == 10 || b == 13)) {
-            return "text/plain";
+    // Write the memtable to the file outputs starts with, and to more past max_segment_size,
+    // raising the history floor past the overwritten records the segments leave out
+    fn write_memtable_segments(&self, inner: &StoreInner, outputs: &mut Vec<(PathBuf, String)>) -> Result<Vec<Segment>> {
+        let new_writer = |path: &Path| -> Result<SegmentWriter> {
+            let mut writer = self.segment_writer(path, inner.collation)?;
+            writer.io_hook = self.wal.io_hook.clone();
+            Ok(writer)
+        };
+        let mut writer = new_writer(&outputs[0].0)?;
+        let mut segs = Vec::new();
+        
+        let shards = inner.shards();
+        for (k, v) in mem_range(&shards, Bound::Unbounded, Bound::Unbounded) {
+            if writer.written >= self.segment_cut(0) {
+                let (path, filename) = self.new_segment_file(0)?;
+                let next = new_writer(&path)?;
+                outputs.push((path, filename));
+                self.values_locked()?.sync()?;
+                segs.push(std::mem::replace(&mut writer, next).finish(self.next_segment_id.fetch_add(1, Ordering::Relaxed))?);
+            }
+            match v {
+                MemValue::Scalar(val, seq) => {
+                    self.add_set(&mut writer, &k.key, val, *seq)?;
+                }
+                MemValue::PointTomb(seq) => {
+                    writer.add(RT_DEL_POINT, &k.key, None, *seq)?;
+                }
+            }
         }
         
-        "application/octet-stream"
+        // Values a segment refers to are durable before it is
+        self.values_locked()?.sync()?;
+        segs.push(writer.finish(self.next_segment_id.fetch_add(1, Ordering::Relaxed))?);
+        
+        // Before any reader can see the segments in place of the memtable
+        self.manifest_locked()?.raise_floor(shards.iter().map(|shard| shard.overwritten).max().unwrap_or(0))?;
+        Ok(segs)
     }
     
-    // ==================== VECTOR OPERATIONS ====================
+    // Where a file written into level is cut
+    fn segment_cut(&self, level: usize) -> u64 {
+        segment_cut(level, self.max_segment_size, &self.compaction)
+    }
     
-    /// Store a vector (embedding) 
-    pub fn set_vector(&self, path: &str, vector: Vec<f32>) -> io::Result<()> {
-        // Encode vector as special type
-        let encoded = format!("v:{}", vector.iter()
-            .map(|f| f.to_string())
-            .collect::<Vec<_>>()
-            .join(","));
-        self.set(path, &encoded, false)
+    fn unlisted_locked(&self) -> Result<Ranked<MutexGuard<'_, Vec<ManifestEntry>>>> {
+        lock_ranked(LockRank::Unlisted, || self.unlisted.lock().map_err(|e| WalDbError::Locked(format!("Unlisted segments lock poisoned: {}", e))))
     }
     
-    /// Get a vector
-    pub fn get_vector(&self, path: &str) -> io::Result<Option<Vec<f32>>> {
-        match self.get(path)? {
-            Some(val) if val.starts_with("v:") => {
-                let vec_str = &val[2..];
-                let vector: Result<Vec<f32>, _> = vec_str
-                    .split(',')
-                    .map(|s| s.parse::<f32>())
-                    .collect();
-                Ok(Some(vector.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?))
+    // Append the manifest entries of flushed segments, oldest first. The fsync is left to
+    // sync_manifest. An entry that fails to append stays queued for the next call.
+    fn list_flushed(&self) -> Result<()> {
+        let mut unlisted = self.unlisted_locked()?;
+        if unlisted.is_empty() {
+            return Ok(());
+        }
+        // In one write: open replays the WAL from past the newest listed segment, so one of a
+        // flush's segments listed without the others would lose their records
+        self.manifest_locked()?.append_all(unlisted.clone())?;
+        unlisted.clear();
+        self.manifest_unsynced.store(true, Ordering::Release);
+        Ok(())
+    }
+    
+    // Add a set to a segment being written, as a reference into the value log if the value is
+    // long enough to deduplicate. A hash already naming other bytes keeps the value inline.
+    fn add_set(&self, writer: &mut SegmentWriter, key: &str, value: &str, seq: u64) -> Result<()> {
+        if self.dedup_values_over.is_some_and(|over| value.len() > over) {
+            if let Some(hash) = self.values_locked()?.store(value, &self.value_cache)? {
+                writer.add(RT_SET_REF, key, Some(&format!("{:016x}", hash)), seq)?;
+                return Ok(());
             }
-            _ => Ok(None)
         }
+        writer.add(RT_SET, key, Some(value), seq)?;
+        Ok(())
     }
     
-    // ==================== SEARCH FUNCTIONALITY ====================
+    // maybe_flush_now, ending a write, after L0 admission if the memtable is full: the writer
+    // waits out a slowdown or stall without the lock, then takes it again for the flush
+    fn maybe_flush<'a>(&'a self, mut inner: InnerWrite<'a>) -> Result<()> {
+        if inner.memtable_size() >= MEMTABLE_THRESHOLD && (self.l0_slowdown.is_some() || self.l0_stop.is_some()) {
+            drop(inner);
+            self.admit_l0()?;
+            inner = self.write_inner()?;
+        }
+        self.maybe_flush_now(inner)
+    }
     
-    /// Advanced search with filters, vector similarity, and text search
-    pub fn search(&self, options: SearchOptions) -> io::Result<Vec<(String, HashMap<String, String>)>> {
-        // Get all entries matching pattern
-        let entries = self.get_pattern(&options.pattern)?;
-        
-        // Group by subroot
-        let mut grouped = Self::group_by_subroot(entries, &options.pattern);
-        
-        // Apply filters if provided
-        if let Some(ref filters) = options.filters {
-            if !filters.is_empty() {
-                grouped.retain(|group| Self::matches_filters(group, filters));
+    // maybe_flush_locked: releases the lock, then lists any flushed segment in the manifest and
+    // fsyncs it unless defer_manifest_syncs leaves that to the WAL flusher
+    fn maybe_flush_now(&self, mut inner: InnerWrite<'_>) -> Result<()> {
+        self.maybe_flush_locked(&mut inner)?;
+        drop(inner);
+        self.list_flushed()?;
+        if !self.defer_manifest_syncs && self.manifest_unsynced.load(Ordering::Acquire) {
+            sync_manifest(&self.manifest, &self.manifest_unsynced)?;
+        }
+        Ok(())
+    }
+    
+    // Flush the memtable and/or GC subtombs once their accounted size crosses a threshold.
+    // The write calling this holds the inner lock, so it can only try for the admin lock; while
+    // a flush, compaction install or close holds that, the memtable grows until a later write.
+    // So it does while L0 is at l0_stop_threshold, for a write that came through maybe_flush
+    // to wait on.
+    fn maybe_flush_locked(&self, inner: &mut StoreInner) -> Result<()> {
+        let stopped = self.l0_stop.is_some_and(|stop| inner.segments_l0.len() >= stop);
+        if inner.memtable_size() >= MEMTABLE_THRESHOLD && !stopped {
+            if let Some(_admin) = self.try_admin()? {
+                self.flush_memtable_locked(inner)?;
             }
         }
         
-        // Apply vector search if requested
-        if let Some(ref vector_opts) = options.vector {
-            grouped = self.apply_vector_search(grouped, vector_opts)?;
+        if inner.subtomb_size >= inner.subtomb_gc_at {
+            self.gc_subtombs_locked(inner)?;
         }
         
-        // Apply text search if requested
-        if let Some(ref text_opts) = options.text {
-            grouped = Self::apply_text_search(grouped, text_opts);
-        }
+        Ok(())
+    }
+    
+    // Drop subtombs that no longer shadow anything. A subtomb is still needed only while
+    // some key under its prefix has a newest version that is a value older than the tomb.
+    fn gc_subtombs_locked(&self, inner: &mut StoreInner) -> Result<()> {
+        let tombs: Vec<(String, u64)> = inner.subtombs.iter()
+            .map(|(p, s)| (p.clone(), *s))
+            .collect();
         
-        // Apply hybrid scoring if multiple search types and scoring weights provided
-        if (options.vector.is_some() || options.text.is_some()) && options.scoring.is_some() {
-            grouped = Self::apply_scoring(grouped, options.scoring.as_ref().unwrap());
+        for (prefix, tomb_seq) in tombs {
+            // Redundant if an ancestor subtomb is at least as new
+            let shadowed = inner.subtombs.iter().any(|(other, other_seq)| {
+                other.len() < prefix.len() && prefix.starts_with(other.as_str()) && *other_seq >= tomb_seq
+            });
+            
+            if shadowed || !self.subtomb_needed(inner, &prefix, tomb_seq)? {
+                inner.subtomb_remove(&prefix);
+            }
         }
         
-        // Limit results if specified
-        if let Some(limit) = options.limit {
-            grouped.truncate(limit);
+        // Back off so a store with many live subtombs doesn't rescan on every write
+        inner.subtomb_gc_at = (inner.subtomb_size * 2).max(SUBTOMB_THRESHOLD);
+        Ok(())
+    }
+    
+    fn subtomb_needed(&self, inner: &StoreInner, prefix: &str, tomb_seq: u64) -> Result<bool> {
+        let results = self.collect_prefix_locked::<String>(inner, prefix, None)?;
+        Ok(results.values().any(|(_, seq)| seq & (1u64 << 63) == 0 && *seq <= tomb_seq))
+    }
+    
+    /// Write the memtable out as an L0 segment, after waiting out any L0 slowdown or stall
+    /// (StoreOptions::l0_slowdown_threshold, l0_stop_threshold)
+    pub fn flush(&self) -> Result<()> {
+        if self.read_inner()?.memtable_len() > 0 {
+            self.admit_l0()?;
         }
-        
-        Ok(grouped)
+        self.flush_now()
     }
     
-    fn apply_vector_search(&self, groups: Vec<(String, HashMap<String, String>)>, 
-                           opts: &VectorSearchOptions) -> io::Result<Vec<(String, HashMap<String, String>)>> {
-        // Calculate similarities
-        let mut scored: Vec<(f32, (String, HashMap<String, String>))> = Vec::new();
-        
-        for group in groups {
-            // Find vector field
-            if let Some(vec_str) = group.1.get(&opts.field) {
-                if vec_str.starts_with("v:") {
-                    let vec_data = &vec_str[2..];
-                    let vector: Vec<f32> = vec_data
-                        .split(',')
-                        .filter_map(|s| s.parse::<f32>().ok())
-                        .collect();
-                    
-                    if vector.len() == opts.query.len() {
-                        let similarity = Self::cosine_similarity(&vector, &opts.query);
-                        
-                        // Apply threshold if specified
-                        if opts.threshold.map_or(true, |t| similarity >= t) {
-                            scored.push((similarity, group));
-                        }
-                    }
+    fn flush_now(&self) -> Result<()> {
+        let _admin = self.admin_lock()?;
+        let mut inner = self.write_inner()?;
+        self.check_unfrozen()?;
+        self.flush_memtable_locked(&mut inner)?;
+        self.wal.sync_now()?;
+        drop(inner);
+        self.list_flushed()?;
+        sync_manifest(&self.manifest, &self.manifest_unsynced)
+    }
+    
+    // Before a flush, holding no lock: wait while L0 is at l0_stop_threshold, then sleep if it
+    // is at l0_slowdown_threshold. Waiting stops once the store closes or refuses writes,
+    // leaving the flush to go ahead or fail.
+    fn admit_l0(&self) -> Result<()> {
+        if let Some(stop) = self.l0_stop {
+            let (lock, cvar) = &*self.l0_changed;
+            let mut stalled = None;
+            loop {
+                // The count is read between taking the generation and waiting on it, so a
+                // segment leaving after the read still wakes the wait
+                let seen = *lock.lock().unwrap_or_else(|e| e.into_inner());
+                let segments = self.read_inner()?.segments_l0.len();
+                if segments < stop || self.check_unfrozen().is_err() || self.closing() {
+                    break;
+                }
+                if stalled.is_none() {
+                    stalled = Some(Instant::now());
+                    self.l0_throttling.1.fetch_add(1, Ordering::Relaxed);
+                    self.events.emit(StoreEvent::L0Stalled { segments });
+                    self.wake_compaction();
                 }
+                let generation = lock.lock().unwrap_or_else(|e| e.into_inner());
+                drop(cvar.wait_timeout_while(generation, L0_STALL_CHECK, |generation| *generation == seen).unwrap_or_else(|e| e.into_inner()));
+            }
+            if let Some(started) = stalled {
+                self.l0_throttling.2.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
             }
         }
         
-        // Sort by similarity (highest first)
-        scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
-        
-        // Take top K if specified
-        if let Some(threshold) = opts.threshold {
-            scored.retain(|(score, _)| *score >= threshold);
+        if let Some(slowdown) = self.l0_slowdown {
+            let segments = self.read_inner()?.segments_l0.len();
+            if segments >= slowdown {
+                let delay = (L0_SLOWDOWN_STEP * (segments - slowdown + 1) as u32).min(L0_SLOWDOWN_MAX);
+                self.l0_throttling.0.fetch_add(1, Ordering::Relaxed);
+                self.events.emit(StoreEvent::L0Slowdown { segments, delay });
+                thread::sleep(delay);
+            }
         }
-        
-        // Extract groups and store scores in metadata
-        Ok(scored.into_iter().map(|(score, mut group)| {
-            group.1.insert("_vector_score".to_string(), score.to_string());
-            group
-        }).collect())
+        Ok(())
     }
     
-    fn apply_text_search(groups: Vec<(String, HashMap<String, String>)>, 
-                        opts: &TextSearchOptions) -> Vec<(String, HashMap<String, String>)> {
-        let query_tokens = Self::tokenize(&opts.query.to_lowercase());
-        
-        groups.into_iter().filter_map(|mut group| {
-            let (_, ref fields) = group;
-            
-            // Check specified fields for text matches
-            let mut score = 0.0f32;
-            let mut matches = 0;
-            
-            for field in &opts.fields {
-                if let Some(value) = fields.get(field) {
-                    let value_lower = value.to_lowercase();
-                    let _value_tokens = Self::tokenize(&value_lower);
-                    
-                    // Calculate match score
-                    for query_token in &query_tokens {
-                        if opts.case_sensitive.unwrap_or(false) {
-                            // Case sensitive exact token matching
-                            let case_sensitive_tokens = Self::tokenize(value);
-                            if case_sensitive_tokens.iter().any(|t| t == query_token) {
-                                matches += 1;
-                                score += 1.0;
-                            }
-                        } else {
-                            // Case insensitive fuzzy matching (contains)
-                            if value_lower.contains(query_token) {
-                                matches += 1;
-                                score += 1.0;
-                            }
-                        }
-                    }
-                }
-            }
-            
-            if matches > 0 {
-                let normalized_score = score / query_tokens.len() as f32;
-                group.1.insert("_text_score".to_string(), normalized_score.to_string());
-                Some(group)
-            } else {
-                None
-            }
-        }).collect()
+    /// Block until the write that returned seq has been fsynced to the WAL.
+    /// Rides the next group commit instead of forcing one, so it is much cheaper than flush()
+    pub fn wait_durable(&self, seq: u64) -> Result<()> {
+        let assigned = self.read_inner()?.seq();
+        if seq > assigned {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("seq {} has not been assigned yet", seq)).into());
+        }
+        self.wal.wait_durable(seq)?;
+        Ok(())
     }
     
-    fn apply_scoring(groups: Vec<(String, HashMap<String, String>)>, 
-                    scoring: &ScoringWeights) -> Vec<(String, HashMap<String, String>)> {
-        // Calculate combined scores
-        let mut scored: Vec<(f32, (String, HashMap<String, String>))> = groups
-            .into_iter()
-            .map(|group| {
-                let mut total_score = 0.0;
-                
-                // Vector score
-                if let Some(vec_score) = group.1.get("_vector_score") {
-                    if let Ok(score) = vec_score.parse::<f32>() {
-                        total_score += score * scoring.vector;
-                    }
-                }
-                
-                // Text score  
-                if let Some(text_score) = group.1.get("_text_score") {
-                    if let Ok(score) = text_score.parse::<f32>() {
-                        total_score += score * scoring.text;
-                    }
-                }
-                
-                (total_score, group)
-            })
-            .collect();
-        
-        // Sort by combined score
-        scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
-        
-        scored.into_iter().map(|(score, mut group)| {
-            group.1.insert("_score".to_string(), score.to_string());
-            group
-        }).collect()
+    pub fn delete(&self, path: &str) -> Result<u64> {
+        reject_reserved(path)?;
+        self.delete_unchecked(path)
     }
     
-    fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
-        let mut dot = 0.0;
-        let mut norm_a = 0.0;
-        let mut norm_b = 0.0;
+    fn delete_unchecked(&self, path: &str) -> Result<u64> {
+        validate_key(path)?;
+        
+        let lock = self.point_lock(path)?;
+        lock.check_unsealed(path, false)?;
+        let charge = self.charge_quotas(&lock, [QuotaOp::Delete(path)])?;
+        let mut inner = match lock {
+            PointLock::Store(inner) => inner,
+            PointLock::Shard(inner, writer) => {
+                let seq = self.shard_commit(inner, writer, vec![(path.to_string(), None)], charge)?;
+                self.account(path, path.len());
+                return Ok(seq);
+            }
+        };
+        let seq = inner.next_seq();
         
-        for i in 0..a.len() {
-            dot += a[i] * b[i];
-            norm_a += a[i] * a[i];
-            norm_b += b[i] * b[i];
-        }
+        self.wal.append(&WALEntry {
+            seq,
+            kind: RT_DEL_POINT,
+            key: path,
+            value: None,
+        })?;
         
-        if norm_a == 0.0 || norm_b == 0.0 {
-            return 0.0;
-        }
+        inner.memtable_insert(path.to_string(), MemValue::PointTomb(seq));
+        self.vectors_locked()?.apply(path, None, seq)?;
+        charge.commit();
         
-        dot / (norm_a.sqrt() * norm_b.sqrt())
+        self.maybe_flush(inner)?;
+        self.account(path, path.len());
+        Ok(seq)
     }
     
-    fn tokenize(text: &str) -> Vec<String> {
-        text.split_whitespace()
-            .map(|s| s.trim_matches(|c: char| !c.is_alphanumeric()))
-            .filter(|s| !s.is_empty())
-            .map(|s| s.to_string())
-            .collect()
+    /// Set multiple key-value pairs atomically, optionally replacing a subtree first.
+    /// Returns the seq of the last entry, or 0 if there was nothing to write. Without a
+    /// replace, skip_identical_sets drops entries matching the live value (or an earlier
+    /// entry for the same key) and the rest still apply as one batch.
+    pub fn set_many(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>) -> Result<u64> {
+        for key in entries.iter().map(|(key, _)| key.as_str()).chain(replace_subtree_at) {
+            reject_reserved(key)?;
+        }
+        self.set_many_unchecked(entries, replace_subtree_at)
     }
     
-    fn group_by_subroot(entries: Vec<(String, String)>, pattern: &str) -> Vec<(String, HashMap<String, String>)> {
-        let depth = pattern.matches('/').count() + 1;
-        let mut groups_map: HashMap<String, HashMap<String, String>> = HashMap::new();
-        
-        for (key, value) in entries {
-            let parts: Vec<&str> = key.split('/').collect();
-            if parts.len() < depth {
-                continue;
-            }
-            
-            let group_key = parts[..depth].join("/");
-            let field = if key.len() > group_key.len() + 1 {
-                &key[group_key.len() + 1..]
-            } else {
-                continue; // Skip if no field part
-            };
-            
-            groups_map.entry(group_key.clone())
-                .or_default()
-                .insert(field.to_string(), value);
+    fn set_many_unchecked(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>) -> Result<u64> {
+        if entries.is_empty() {
+            return Ok(0);
         }
+        self.run_validators(entries.iter().map(|(key, value)| (key.as_str(), value.as_str())))?;
         
-        groups_map.into_iter().collect()
+        let inner = self.write_lock()?;
+        self.set_many_locked(inner, entries, replace_subtree_at)
     }
     
-    fn matches_filters(group: &(String, HashMap<String, String>), filters: &[SearchFilter]) -> bool {
-        let (_group_key, fields) = group;
-        
-        for filter in filters {
-            let field_value = match fields.get(&filter.field) {
-                Some(v) => v,
-                None => return false, // Field doesn't exist
-            };
-            
-            // Decode the value to compare properly
-            let decoded = Self::decode_value(field_value);
-            
-            if !Self::compare_values(&decoded, &filter.op, &filter.value) {
-                return false;
-            }
+    /// set_many, but only if nothing at or under the replaced subtree (the whole store
+    /// without one) was written since subtree_version returned expected_version. The check
+    /// and the write share the write lock, so of two callers holding the same version at
+    /// most one gets true; the other writes nothing and gets false.
+    pub fn set_many_if_version(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>,
+                               expected_version: u64) -> Result<bool> {
+        for key in entries.iter().map(|(key, _)| key.as_str()).chain(replace_subtree_at) {
+            reject_reserved(key)?;
         }
-        
-        true
+        self.set_many_if_version_unchecked(entries, replace_subtree_at, replace_subtree_at.unwrap_or(""), expected_version)
     }
     
-    fn decode_value(encoded: &str) -> String {
-        // Handle type prefixes (s:, n:, b:, z:)
-        if encoded.len() > 2 && &encoded[1..2] == ":" {
-            return encoded[2..].to_string();
+    fn set_many_if_version_unchecked(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>,
+                                     versioned: &str, expected_version: u64) -> Result<bool> {
+        self.run_validators(entries.iter().map(|(key, value)| (key.as_str(), value.as_str())))?;
+        let inner = self.write_lock()?;
+        if self.subtree_version_locked(&inner, versioned)? != expected_version {
+            return Ok(false);
         }
-        encoded.to_string()
+        if !entries.is_empty() {
+            self.set_many_locked(inner, entries, replace_subtree_at)?;
+        }
+        Ok(true)
     }
     
-    fn compare_values(field_value: &str, op: &FilterOp, filter_value: &str) -> bool {
-        match op {
-            FilterOp::Eq => field_value == filter_value,
-            FilterOp::Ne => field_value != filter_value,
-            FilterOp::Gt => {
-                // Try numeric comparison first
-                if let (Ok(a), Ok(b)) = (field_value.parse::<f64>(), filter_value.parse::<f64>()) {
-                    a > b
-                } else {
-                    field_value > filter_value
+    /// The newest seq of any write at prefix or under it, deletes included, or 0 if there
+    /// was none. It changes whenever the subtree does, so a read-modify-write of an object
+    /// spanning many keys can pass it to set_many_if_version to detect a lost update.
+    /// Compaction may lower it by dropping old deletes, which only fails that check.
+    pub fn subtree_version(&self, prefix: &str) -> Result<u64> {
+        let inner = self.read_inner()?;
+        self.subtree_version_locked(&inner, prefix)
+    }
+    
+    fn subtree_version_locked(&self, inner: &StoreInner, prefix: &str) -> Result<u64> {
+        let path = prefix.trim_end_matches('/');
+        let children = if path.is_empty() { String::new() } else { format!("{}/", path) };
+        let end = prefix_end(&children);
+        
+        // A subtree delete at or above the prefix rewrote all of it, one below rewrote part
+        let mut version = inner.subtombs.iter()
+            .filter(|(tomb, _)| tomb.starts_with(&children) || children.starts_with(tomb.as_str()))
+            .map(|(_, seq)| *seq)
+            .max()
+            .unwrap_or(0);
+        
+        {
+            let shards = inner.shards();
+            let (low, high) = ((children.as_str(), inner.collation), (end.as_str(), inner.collation));
+            for (key, value) in mem_range(&shards, Bound::Included(&low), Bound::Excluded(&high)) {
+                if !is_system_key(&key.key) {
+                    version = version.max(value.seq());
                 }
             }
-            FilterOp::Gte => {
-                if let (Ok(a), Ok(b)) = (field_value.parse::<f64>(), filter_value.parse::<f64>()) {
-                    a >= b
-                } else {
-                    field_value >= filter_value
-                }
+        }
+        if !path.is_empty() {
+            if let Some(value) = inner.shard(path).get(path, inner.collation) {
+                version = version.max(value.seq());
             }
-            FilterOp::Lt => {
-                if let (Ok(a), Ok(b)) = (field_value.parse::<f64>(), filter_value.parse::<f64>()) {
-                    a < b
-                } else {
-                    field_value < filter_value
+        }
+        
+        // Only segments holding something newer than what was found so far can matter, and
+        // of those only the blocks spanning the prefix are read
+        for seg in Self::segments_newest_first(inner) {
+            if seg.seq_high <= version {
+                continue;
+            }
+            if !path.is_empty() && seg.may_hold(path) && seg.bloom.as_ref().is_none_or(|bloom| bloom.might_contain(path)) {
+                if let Some(seq) = self.find_in_segment(seg, path, |record| record.seq)? {
+                    version = version.max(seq);
                 }
             }
-            FilterOp::Lte => {
-                if let (Ok(a), Ok(b)) = (field_value.parse::<f64>(), filter_value.parse::<f64>()) {
-                    a <= b
-                } else {
-                    field_value <= filter_value
+            if !seg.overlaps(&children, &end) {
+                continue;
+            }
+            let collation = seg.collation;
+            let start_idx = match seg.index.search(children.as_bytes(), collation) {
+                Ok(i) => i,
+                Err(i) => i.saturating_sub(1),
+            };
+            for idx in start_idx..seg.index.len() {
+                if collation.compare(seg.index.key(idx), end.as_bytes()).is_ge() {
+                    break;
+                }
+                let (offset, len) = seg.block_span(idx);
+                let block_data = self.cache.get_or_load(seg, offset, len, BlockAccess::Scan)?;
+                let mut reader = BlockReader::new(seg, &block_data, offset);
+                while let Some(record) = reader.next_record() {
+                    if record.seq <= version || !record.key.starts_with(children.as_bytes())
+                        || !matches!(record.rec_type, RT_SET | RT_SET_REF | RT_DEL_POINT) {
+                        continue;
+                    }
+                    if !is_system_key(&record.key_text(seg, self.lossy_reads)?) {
+                        version = record.seq;
+                    }
                 }
             }
         }
+        Ok(version)
     }
-}
-
-// Search filter types
-#[derive(Debug, Clone)]
-pub struct SearchFilter {
-    pub field: String,
-    pub op: FilterOp,
-    pub value: String,
-}
-
-#[derive(Debug, Clone)]
-pub enum FilterOp {
-    Eq,  // ==
-    Ne,  // !=
-    Gt,  // >
-    Gte, // >=
-    Lt,  // <
-    Lte, // <=
-}
-
-// Advanced search options
-#[derive(Debug, Clone)]
-pub struct SearchOptions {
-    pub pattern: String,
-    pub filters: Option<Vec<SearchFilter>>,
-    pub vector: Option<VectorSearchOptions>,
-    pub text: Option<TextSearchOptions>,
-    pub scoring: Option<ScoringWeights>,
-    pub limit: Option<usize>,
-}
-
-#[derive(Debug, Clone)]
-pub struct VectorSearchOptions {
-    pub query: Vec<f32>,
-    pub field: String,
-    pub threshold: Option<f32>,
-}
-
-#[derive(Debug, Clone)]
-pub struct TextSearchOptions {
-    pub query: String,
-    pub fields: Vec<String>,
-    pub case_sensitive: Option<bool>,
-}
-
-#[derive(Debug, Clone)]
-pub struct ScoringWeights {
-    pub vector: f32,
-    pub text: f32,
-    pub filter: f32,
-}
-
-impl StoreInner {
-    fn replay_wal(&mut self, path: &Path) -> io::Result<()> {
-        if !path.exists() {
-            return Ok(());
+    
+    // Whether the store holds a scalar at path, for a batch's parent checks. Each distinct path
+    // is probed once per batch in probed, so siblings sharing a parent cost one segment probe
+    // between them rather than one each.
+    fn stored_scalar(&self, inner: &StoreInner, probed: &mut HashMap<String, bool>, path: &str) -> Result<bool> {
+        if let Some(&scalar) = probed.get(path) {
+            return Ok(scalar);
         }
-        
-        let file = File::open(path)?;
-        let mut reader = BufReader::new(file);
-        
-        let mut magic_buf = [0u8; 4];
-        if reader.read_exact(&mut magic_buf).is_err() {
-            return Ok(());
+        let scalar = self.contains_locked(inner, &inner.shard(path), path)?;
+        probed.insert(path.to_string(), scalar);
+        Ok(scalar)
+    }
+    
+    fn set_many_locked(&self, mut inner: InnerWrite<'_>, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>) -> Result<u64> {
+        for (key, _) in &entries {
+            inner.check_unsealed(key, false)?;
         }
-        
-        if &magic_buf != WAL_MAGIC {
-            return Ok(());
+        if let Some(base_path) = replace_subtree_at {
+            inner.check_unsealed(base_path, true)?;
         }
         
-        loop {
-            let mut len_buf = [0u8; 4];
-            if reader.read_exact(&mut len_buf).is_err() {
-                break;
-            }
-            
-            let len = u32::from_le_bytes(len_buf) as usize;
-            let mut record = vec![0u8; len];
-            
-            if reader.read_exact(&mut record).is_err() {
-                break;
+        // Validate every entry before the WAL sees any of it. A parent is a scalar if an
+        // earlier entry set it, or if the store holds it and the replace doesn't clear it.
+        // Siblings usually come together, so an entry whose parent the one before it found
+        // clear skips the checks, unless an entry in between set the parent itself.
+        let replaced = |path: &str| replace_subtree_at.is_some_and(|base| path == base || path.strip_prefix(base).is_some_and(|rest| rest.starts_with('/')));
+        let mut batch_keys: HashSet<&str> = HashSet::new();
+        let mut probed = HashMap::new();
+        let mut clear_parent = None;
+        for (key, _) in &entries {
+            validate_key(key)?;
+            if let Some(parent) = parent_of(key).filter(|&parent| clear_parent != Some(parent)) {
+                let parent_is_scalar = batch_keys.contains(parent)
+                    || (!replaced(parent) && self.stored_scalar(&inner, &mut probed, parent)?);
+                if parent_is_scalar {
+                    return Err(WalDbError::TreeSemantics { path: key.to_string(), ancestor: None });
+                }
+                clear_parent = Some(parent);
             }
-            
-            let mut crc_buf = [0u8; 4];
-            if reader.read_exact(&mut crc_buf).is_err() {
-                break;
+            if clear_parent == Some(key.as_str()) {
+                clear_parent = None;
             }
+            batch_keys.insert(key);
+        }
+        
+        // Replace subtree if specified: a point tombstone for the base and a subtree
+        // tombstone for what is under it, which leaves siblings like "base2" alone. With
+        // nothing live under the base there is nothing for a subtree tombstone to hide.
+        let clear_children = match replace_subtree_at {
+            Some(base_path) => self.has_live_children_locked(&inner, base_path)?,
+            None => false,
+        };
+        let children = replace_subtree_at.filter(|_| clear_children)
+            .map(|base_path| if base_path.ends_with('/') { base_path.to_string() } else { format!("{}/", base_path) });
+        let ops = replace_subtree_at.map(QuotaOp::Delete).into_iter()
+            .chain(children.as_deref().map(QuotaOp::Clear))
+            .chain(entries.iter().map(|(key, value)| QuotaOp::Put(key, value.len())));
+        let charge = self.charge_quotas(&inner, ops)?;
+        if let Some(base_path) = replace_subtree_at {
+            let point_tomb_seq = inner.next_seq();
             
-            let expected_crc = u32::from_le_bytes(crc_buf);
-            if crc32(&record) != expected_crc {
-                break;
-            }
+            self.wal.append(&WALEntry {
+                seq: point_tomb_seq,
+                kind: RT_DEL_POINT,
+                key: base_path,
+                value: None,
+            })?;
             
-            // Parse record
-            if record.len() < 13 {
-                continue;
-            }
+            inner.memtable_insert(base_path.to_string(), MemValue::PointTomb(point_tomb_seq));
+            self.vectors_locked()?.apply(base_path, None, point_tomb_seq)?;
+        }
+        if let Some(prefix) = children {
+            let subtomb_seq = inner.next_seq();
             
-            let mut seq_bytes = [0u8; 8];
-            seq_bytes.copy_from_slice(&record[0..8]);
-            let seq = u64::from_le_bytes(seq_bytes);
+            self.wal.append(&WALEntry {
+                seq: subtomb_seq,
+                kind: RT_DEL_SUB,
+                key: &prefix,
+                value: None,
+            })?;
             
-            let kind = record[8];
+            self.vectors_locked()?.remove_prefix(&prefix, subtomb_seq)?;
+            inner.subtomb_insert(prefix, subtomb_seq);
+        }
+        
+        // Each entry takes its own seq, so a key repeated in the batch resolves to its last
+        // occurrence in the memtable, on WAL replay, and in every segment merge alike
+        let skip_identical = self.skip_identical_sets && replace_subtree_at.is_none();
+        let mut written: HashMap<&str, &str> = HashMap::new();
+        for (key, value) in &entries {
+            if skip_identical {
+                let current = match written.get(key.as_str()) {
+                    Some(&pending) => Some(Cow::Borrowed(pending)),
+                    None => self.get_locked(&inner, key)?.map(Cow::Owned),
+                };
+                if current.as_deref() == Some(value.as_str()) {
+                    continue;
+                }
+                written.insert(key, value);
+            }
             
-            let mut klen_bytes = [0u8; 4];
-            klen_bytes.copy_from_slice(&record[9..13]);
-            let klen = u32::from_le_bytes(klen_bytes) as usize;
+            let seq = inner.next_seq();
             
-            if record.len() < 13 + klen {
-                continue;
-            }
+            self.wal.append(&WALEntry {
+                seq,
+                kind: RT_SET,
+                key,
+                value: Some(value),
+            })?;
             
-            let key = String::from_utf8_lossy(&record[13..13 + klen]).to_string();
+            inner.memtable_insert(key.to_string(), MemValue::Scalar(CompactStr::from(value.as_str()), seq));
+            self.vectors_locked()?.apply(key, Some(value), seq)?;
+            self.account(key, key.len() + value.len());
+        }
+        charge.commit();
+        
+        // Flush memtable if it gets too large
+        let seq = inner.seq();
+        self.maybe_flush(inner)?;
+        Ok(seq)
+    }
+    
+    /// Apply a WriteBatch atomically. The batch reaches the WAL as one pre-encoded frame, so
+    /// recovery sees all of it or none of it. Parent checks see the store and earlier ops in
+    /// the batch, like set_many. Large batches flush to several L0 segments as they apply,
+    /// and every op applies once the frame is logged, even if one of those flushes fails.
+    /// Returns the seq of the last op, or 0 for an empty batch.
+    pub fn write(&self, batch: WriteBatch) -> Result<u64> {
+        for (_, _, key, _) in batch_records(&batch.buf[BATCH_HEADER..]) {
+            reject_reserved(&key)?;
+        }
+        self.write_unchecked(batch)
+    }
+    
+    fn write_unchecked(&self, batch: WriteBatch) -> Result<u64> {
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        if self.validators.any.load(Ordering::SeqCst) {
+            let puts: Vec<_> = batch_records(&batch.buf[BATCH_HEADER..])
+                .filter_map(|(kind, _, key, value)| Some((key, value.filter(|_| kind == RT_SET)?)))
+                .collect();
+            self.run_validators(puts.iter().map(|(key, value)| (key.as_ref(), value.as_ref())))?;
+        }
+        
+        let mut inner = self.write_lock()?;
+        let seq = self.write_locked(&mut inner, batch)?;
+        self.maybe_flush(inner)?;
+        Ok(seq)
+    }
+    
+    fn write_locked(&self, inner: &mut StoreInner, batch: WriteBatch) -> Result<u64> {
+        let mut frame = batch.buf;
+        
+        // Validate everything before the WAL sees any of it
+        {
+            let mut written: HashMap<Cow<str>, bool> = HashMap::new();  // Key -> is a scalar after the op
+            let mut cleared: Vec<Cow<str>> = Vec::new();
+            let mut probed = HashMap::new();
             
-            match kind {
-                RT_SET => {
-                    if record.len() >= 17 + klen {
-                        let mut vlen_bytes = [0u8; 4];
-                        vlen_bytes.copy_from_slice(&record[13 + klen..17 + klen]);
-                        let vlen = u32::from_le_bytes(vlen_bytes) as usize;
-                        
-                        if record.len() >= 17 + klen + vlen {
-                            let val = String::from_utf8_lossy(&record[17 + klen..17 + klen + vlen]).to_string();
-                            self.memtable.insert(key.clone(), MemValue::Scalar(val.clone(), seq));
-                            self.memtable_size += key.len() + val.len() + 16;
+            for (kind, _, key, _) in batch_records(&frame[BATCH_HEADER..]) {
+                match kind {
+                    RT_SET => {
+                        validate_key(&key)?;
+                        inner.check_unsealed(&key, false)?;
+                        if let Some(parent) = parent_path(&key) {
+                            let parent_is_scalar = match written.get(parent.as_str()) {
+                                Some(&scalar) => scalar,
+                                None => !cleared.iter().any(|prefix| parent.starts_with(prefix.as_ref()))
+                                    && self.stored_scalar(inner, &mut probed, &parent)?,
+                            };
+                            if parent_is_scalar {
+                                return Err(WalDbError::TreeSemantics { path: key.into_owned(), ancestor: None });
+                            }
                         }
+                        written.insert(key, true);
+                    }
+                    RT_DEL_POINT => {
+                        validate_key(&key)?;
+                        inner.check_unsealed(&key, false)?;
+                        written.insert(key, false);
+                    }
+                    _ => {
+                        inner.check_unsealed(&key, true)?;
+                        written.retain(|k, _| !k.starts_with(key.as_ref()));
+                        cleared.push(key);
                     }
                 }
-                RT_DEL_POINT => {
-                    self.memtable.insert(key, MemValue::PointTomb(seq));
-                }
-                RT_DEL_SUB => {
-                    self.subtombs.insert(key, seq);
-                }
-                _ => {}
             }
+        }
+        
+        // Batch keys went in as str, so they read back as one
+        let ops = batch_ops(&frame[BATCH_HEADER..]).map(|(kind, _, key, value)| {
+            let key = std::str::from_utf8(key).expect("Batch keys are UTF-8");
+            match (kind, value) {
+                (RT_SET, Some(value)) => QuotaOp::Put(key, value.len()),
+                (RT_DEL_POINT, _) => QuotaOp::Delete(key),
+                _ => QuotaOp::Clear(key),
+            }
+        });
+        let charge = self.charge_quotas(inner, ops)?;
+        
+        let base_seq = inner.seq() + 1;
+        let frame_len = (frame.len() - 4) as u32;
+        frame[0..4].copy_from_slice(&frame_len.to_le_bytes());
+        frame[4..12].copy_from_slice(&base_seq.to_le_bytes());
+        let crc = crc32(&frame[4..]);
+        frame.extend_from_slice(&crc.to_le_bytes());
+        self.wal.append_frame(&frame, base_seq + batch.ops as u64 - 1)?;
+        
+        // The whole frame is logged, so from here every op applies. A memtable's worth of ops
+        // goes to a segment before the next one; a flush that fails leaves the memtable as it
+        // was, so the rest still go in and its error is returned once they have.
+        let mut flush_error = None;
+        for (kind, offset, key, value) in batch_records(&frame[BATCH_HEADER..frame.len() - 4]) {
+            if flush_error.is_none() {
+                flush_error = self.maybe_flush_locked(inner).err();
+            }
+            let seq = base_seq + offset;
+            inner.set_seq(seq);
+            self.account(&key, key.len() + value.as_ref().map_or(0, |value| value.len()));
             
-            if seq > self.seq {
-                self.seq = seq;
+            match (kind, value) {
+                (RT_SET, Some(value)) => {
+                    self.mirror_vectors(|vectors| vectors.apply(&key, Some(&value), seq));
+                    inner.memtable_insert(key.into_owned(), MemValue::Scalar(CompactStr::from(value.as_ref()), seq));
+                }
+                (RT_DEL_POINT, _) => {
+                    self.mirror_vectors(|vectors| vectors.apply(&key, None, seq));
+                    inner.memtable_insert(key.into_owned(), MemValue::PointTomb(seq));
+                }
+                _ => {
+                    self.mirror_vectors(|vectors| vectors.remove_prefix(&key, seq));
+                    inner.subtomb_insert(key.into_owned(), seq);
+                }
             }
         }
+        charge.commit();
         
-        Ok(())
-    }
-}
-
-impl GroupCommitWAL {
-    fn new(path: &Path) -> io::Result<Self> {
-        Ok(GroupCommitWAL {
-            path: path.to_path_buf(),
-            buffer: Mutex::new(Vec::new()),
-            // sync_interval: Duration::from_millis(GROUP_COMMIT_MS),
-            shutdown: Arc::new((Mutex::new(false), Condvar::new())),
-        })
+        match flush_error {
+            Some(error) => Err(error),
+            None => Ok(inner.seq()),
+        }
     }
     
-    fn append(&self, entry: &WALEntry) -> io::Result<()> {
-        let mut buffer = self.buffer.lock().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("WAL buffer lock poisoned: {}", e)))?;
-        buffer.push(WALEntry {
-            seq: entry.seq,
-            kind: entry.kind,
-            key: entry.key.clone(),
-            value: entry.value.clone(),
-        });
-        
-        // Optionally sync immediately for critical operations
-        if buffer.len() > 100 {
-            drop(buffer);
-            self.sync_now()?;
+    // Apply a logged write to the vector sidecar, which can't fail the write. A failed sidecar
+    // write sends StoreEvent::VectorSidecarFailed and detaches the sidecar from vectors.dat,
+    // keeping its rows in memory for searches and leaving the next open to rebuild the file.
+    fn mirror_vectors(&self, op: impl Fn(&mut VectorSidecar) -> io::Result<()>) {
+        let mut vectors = self.vectors.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(error) = op(&mut vectors) {
+            vectors.detach();
+            self.events.emit(StoreEvent::VectorSidecarFailed { error });
+            // Detached, it touches no file, so this can't fail
+            let _ = op(&mut vectors);
         }
-        
-        Ok(())
     }
     
-    fn sync_now(&self) -> io::Result<()> {
-        let mut buffer = self.buffer.lock().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("WAL buffer lock poisoned: {}", e)))?;
-        if buffer.is_empty() {
-            return Ok(());
+    /// Move the value at from and everything under it to to, replacing whatever to held,
+    /// and return how many keys moved. The copy and the deletion of from go to the WAL as one
+    /// batch frame applied under one lock, so readers and crash recovery see the subtree in
+    /// one place or the other, never both or neither. The subtree is held in memory while it
+    /// moves, once as records and once in the frame.
+    pub fn move_subtree(&self, from: &str, to: &str) -> Result<usize> {
+        reject_reserved(from)?;
+        reject_reserved(to)?;
+        self.move_subtree_unchecked(from, to)
+    }
+    
+    fn move_subtree_unchecked(&self, from: &str, to: &str) -> Result<usize> {
+        let from = from.strip_suffix('/').unwrap_or(from);
+        let to = to.strip_suffix('/').unwrap_or(to);
+        let nested = |outer: &str, path: &str| path == outer || path.strip_prefix(outer).is_some_and(|rest| rest.starts_with('/'));
+        if from.is_empty() || to.is_empty() {
+            return Err(WalDbError::InvalidKey { reason: "Cannot move to or from the root".to_string() });
         }
+        if nested(from, to) || nested(to, from) {
+            return Err(WalDbError::InvalidKey { reason: format!("Cannot move {} to {}: one contains the other", from, to) });
+        }
+        validate_key(to)?;
         
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.path)?;
-        
-        // Write magic if new file
-        if file.metadata()?.len() == 0 {
-            file.write_all(WAL_MAGIC)?;
+        let mut inner = self.write_lock()?;
+        if let Some(parent) = parent_path(to) {
+            if self.get_locked(&inner, &parent)?.is_some() {
+                return Err(WalDbError::TreeSemantics { path: to.to_string(), ancestor: None });
+            }
         }
         
-        for entry in buffer.drain(..) {
-            let mut record = Vec::new();
-            record.extend_from_slice(&entry.seq.to_le_bytes());
-            record.push(entry.kind);
-            record.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
-            record.extend_from_slice(entry.key.as_bytes());
-            
-            if let Some(val) = &entry.value {
-                record.extend_from_slice(&(val.len() as u32).to_le_bytes());
-                record.extend_from_slice(val.as_bytes());
+        // Holds from and from/..., plus in byte order siblings like "from!"
+        let records = self.collect_range_locked::<String>(&inner, from, &prefix_end(&format!("{}/", from)), None)?;
+        let mut batch = WriteBatch::new();
+        batch.delete_subtree(to).delete(to);
+        let mut moved = 0;
+        for (key, (value, seq)) in records {
+            if seq & (1u64 << 63) != 0 || self.covered_by_subtomb(&inner, &key.key, seq) {
+                continue;
             }
-            
-            file.write_all(&(record.len() as u32).to_le_bytes())?;
-            file.write_all(&record)?;
-            file.write_all(&crc32(&record).to_le_bytes())?;
+            let relative = match key.key.strip_prefix(from) {
+                Some("") => "",
+                Some(rest) if rest.starts_with('/') => rest,
+                _ => continue,
+            };
+            batch.put(&format!("{}{}", to, relative), &value);
+            moved += 1;
+        }
+        if moved == 0 {
+            return Ok(0);
         }
         
-        file.sync_all()?;
-        Ok(())
+        // Copy first, then delete: replayed in order, a later op never undoes an earlier one here
+        batch.delete_subtree(from).delete(from);
+        self.write_locked(&mut inner, batch)?;
+        self.maybe_flush(inner)?;
+        Ok(moved)
     }
-}
-
-impl Segment {
-    fn open(path: &Path) -> io::Result<Self> {
-        let mut file = File::open(path)?;
-        let file_len = file.metadata()?.len();
-        
-        // Read header
-        let mut magic_buf = [0u8; 7];
-        file.read_exact(&mut magic_buf)?;
-        if &magic_buf != MAGIC {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad magic"));
+    
+    /// Copy the value at from and everything under it to to, and return how many keys were
+    /// written. The source is read as of the call and written COPY_CHUNK (10,000) keys at a
+    /// time, each chunk one atomic batch, so memory stays flat however large the subtree;
+    /// chunks written before a failure stay written. mode says what becomes of values already
+    /// at the destination: Fail checks for them before the first chunk, Skip as each key is
+    /// copied, and Replace clears to in the first chunk.
+    pub fn copy_subtree(&self, from: &str, to: &str, mode: CopyMode) -> Result<usize> {
+        reject_reserved(from)?;
+        reject_reserved(to)?;
+        self.copy_subtree_unchecked(from, to, mode)
+    }
+    
+    fn copy_subtree_unchecked(&self, from: &str, to: &str, mode: CopyMode) -> Result<usize> {
+        let from = from.strip_suffix('/').unwrap_or(from);
+        let to = to.strip_suffix('/').unwrap_or(to);
+        let nested = |outer: &str, path: &str| path == outer || path.strip_prefix(outer).is_some_and(|rest| rest.starts_with('/'));
+        if from.is_empty() || to.is_empty() {
+            return Err(WalDbError::InvalidKey { reason: "Cannot copy to or from the root".to_string() });
+        }
+        if nested(from, to) || nested(to, from) {
+            return Err(WalDbError::InvalidKey { reason: format!("Cannot copy {} to {}: one contains the other", from, to) });
+        }
+        validate_key(to)?;
+        if let Some(parent) = parent_path(to) {
+            if self.contains(&parent)? {
+                return Err(WalDbError::TreeSemantics { path: to.to_string(), ancestor: None });
+            }
         }
         
-        // Read footer from end
-        file.seek(SeekFrom::End(-32))?;
-        let mut footer = [0u8; 32];
-        file.read_exact(&mut footer)?;
-        
-        // seq_low stored in footer but not currently used
-        // let mut seq_low_bytes = [0u8; 8];
-        // seq_low_bytes.copy_from_slice(&footer[0..8]);
-        // let seq_low = u64::from_le_bytes(seq_low_bytes);
-        
-        let mut seq_high_bytes = [0u8; 8];
-        seq_high_bytes.copy_from_slice(&footer[8..16]);
-        let seq_high = u64::from_le_bytes(seq_high_bytes);
-        
-        // key_count stored in footer but not currently used
-        // let mut key_count_bytes = [0u8; 4];
-        // key_count_bytes.copy_from_slice(&footer[16..20]);
-        // let key_count = u32::from_le_bytes(key_count_bytes) as usize;
-        
-        let mut index_size_bytes = [0u8; 4];
-        index_size_bytes.copy_from_slice(&footer[20..24]);
-        let index_size = u32::from_le_bytes(index_size_bytes) as usize;
-        
-        let mut bloom_size_bytes = [0u8; 4];
-        bloom_size_bytes.copy_from_slice(&footer[24..28]);
-        let bloom_size = u32::from_le_bytes(bloom_size_bytes) as usize;
-        
-        let mut hash_count_bytes = [0u8; 4];
-        hash_count_bytes.copy_from_slice(&footer[28..32]);
-        let hash_count = u32::from_le_bytes(hash_count_bytes) as usize;
-        
-        // Calculate index start position
-        let index_start = file_len - 32 - index_size as u64 - bloom_size as u64;
-        
-        // Read bloom filter
-        let bloom = if bloom_size > 0 {
-            file.seek(SeekFrom::End(-(32 + bloom_size as i64)))?;
-            let mut bloom_data = vec![0u8; bloom_size];
-            file.read_exact(&mut bloom_data)?;
-            
-            Some(BloomFilter {
-                bits: bloom_data,
-                bit_count: bloom_size * 8,
-                hash_count,
-            })
-        } else {
-            None
+        // Holds from and from/..., plus in byte order siblings like "from!"
+        let end = prefix_end(&format!("{}/", from));
+        let destination = |key: &str| match key.strip_prefix(from) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(format!("{}{}", to, rest)),
+            _ => None,
         };
         
-        // Read index
-        file.seek(SeekFrom::End(-(32 + bloom_size as i64 + index_size as i64)))?;
-        let mut index_data = vec![0u8; index_size];
-        file.read_exact(&mut index_data)?;
-        
-        let mut index = Vec::new();
-        let mut pos = 0;
+        // Only a destination with something in it needs every key probed
+        let to_scalar = self.contains(to)?;
+        if mode == CopyMode::Fail && (to_scalar || self.scan_prefix_iter(&format!("{}/", to))?.next().is_some()) {
+            for entry in self.range_iter(from, &end)? {
+                let (key, _) = entry?;
+                match destination(&key) {
+                    Some(path) if self.contains(&path)? => return Err(WalDbError::AlreadyExists { path }),
+                    _ => {}
+                }
+            }
+        }
         
-        while pos < index_data.len() {
-            if pos + 12 > index_data.len() {
-                break;
+        let mut chunk = WriteBatch::new();
+        if mode == CopyMode::Replace {
+            chunk.delete_subtree(to).delete(to);
+        }
+        let (mut copied, mut pending) = (0, 0);
+        for entry in self.range_iter(from, &end)? {
+            let (key, value) = entry?;
+            let Some(path) = destination(&key) else {
+                continue;
+            };
+            match mode {
+                CopyMode::Replace => {}
+                // Nothing replaces the scalar at to, so nothing can go under it
+                _ if to_scalar && path != to => return Err(WalDbError::TreeSemantics { path, ancestor: None }),
+                CopyMode::Skip if self.contains(&path)? => continue,
+                _ => {}
             }
-            
-            let mut klen_bytes = [0u8; 4];
-            klen_bytes.copy_from_slice(&index_data[pos..pos + 4]);
-            let klen = u32::from_le_bytes(klen_bytes) as usize;
-            pos += 4;
-            
-            let mut offset_bytes = [0u8; 8];
-            offset_bytes.copy_from_slice(&index_data[pos..pos + 8]);
-            let offset = u64::from_le_bytes(offset_bytes);
-            pos += 8;
-            
-            if pos + klen > index_data.len() {
-                break;
+            chunk.put(&path, &value);
+            pending += 1;
+            if pending == COPY_CHUNK {
+                self.write_unchecked(std::mem::take(&mut chunk))?;
+                copied += pending;
+                pending = 0;
             }
-            
-            let key = String::from_utf8_lossy(&index_data[pos..pos + klen]).to_string();
-            pos += klen;
-            
-            index.push((key, offset));
         }
-        
-        Ok(Segment {
-            path: path.to_path_buf(),
-            // seq_low,
-            seq_high,
-            // key_count,
-            bloom,
-            index,
-            index_start,  // Store for block boundary calculation
-        })
+        if pending > 0 {
+            self.write_unchecked(chunk)?;
+            copied += pending;
+        }
+        Ok(copied)
     }
-}
-
-struct SegmentWriter {
-    file: File,
-    path: PathBuf,
-    seq_low: u64,
-    seq_high: u64,
-    key_count: usize,
-    current_block: Vec<u8>,
-    index: Vec<(String, u64)>,
-    bloom: BloomFilter,
-    written: u64,
-}
-
-impl SegmentWriter {
-    fn new(path: &Path) -> io::Result<Self> {
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?;
-        
-        let mut writer = SegmentWriter {
-            file,
-            path: path.to_path_buf(),
-            seq_low: u64::MAX,
-            seq_high: 0,
-            key_count: 0,
-            current_block: Vec::new(),
-            index: Vec::new(),
-            bloom: BloomFilter::new(10000, 7),  // Fixed params for now
-            written: 0,
+    
+    /// Deletes only the WAL holds so far: point tombstones in the memtable, and subtree deletes
+    /// no flush has listed in the manifest. flush() brings it to 0.
+    pub fn pending_tombstones(&self) -> usize {
+        let inner = self.read_inner().expect("Lock should not be poisoned in pending_tombstones");
+        let points = inner.shards().iter()
+            .map(|shard| shard.entries.values().filter(|value| matches!(value, MemValue::PointTomb(_))).count())
+            .sum::<usize>();
+        let manifest = self.manifest_locked().expect("Manifest lock should not be poisoned in pending_tombstones");
+        let subtrees = inner.subtombs.iter()
+            .filter(|(prefix, &seq)| manifest.subtombs.get(prefix.as_str()).is_none_or(|&listed| listed < seq))
+            .count();
+        points + subtrees
+    }
+    
+    pub fn memtable_len(&self) -> usize {
+        let inner = self.read_inner().expect("Lock should not be poisoned in memtable_len");
+        inner.memtable_len()
+    }
+    
+    pub fn segment_counts(&self) -> (usize, usize, usize) {
+        let inner = self.read_inner().expect("Lock should not be poisoned in segment_counts");
+        (inner.segments_l0.len(), inner.segments_l1.len(), inner.segments_l2.len())
+    }
+    
+    /// Exercise the storage path for a supervisor's liveness probe, in this order: a read of
+    /// the probe key, the probe write with write_probe, the WAL buffer's depth, whether the
+    /// background flusher (when there is one) woke within 3 sync intervals, and a read of one
+    /// block picked at random through the block cache. Failures go in the report rather than
+    /// Err. Checks due once max_duration has passed are skipped, and count against healthy().
+    pub fn health_check(&self, options: HealthCheckOptions) -> Result<HealthReport> {
+        let started = Instant::now();
+        let mut report = HealthReport::default();
+        let mut run = |name: &'static str, check: &dyn Fn() -> std::result::Result<(), String>| {
+            if started.elapsed() >= options.max_duration {
+                report.checks.push(HealthCheck { name, status: HealthStatus::Skipped, duration: Duration::ZERO });
+                return;
+            }
+            let check_started = Instant::now();
+            let status = match check() {
+                Ok(()) => HealthStatus::Passed,
+                Err(detail) => HealthStatus::Failed(detail),
+            };
+            report.checks.push(HealthCheck { name, status, duration: check_started.elapsed() });
         };
         
-        writer.file.write_all(MAGIC)?;
-        writer.written = MAGIC.len() as u64;
-        
-        Ok(writer)
+        run("memtable_read", &|| self.system_get(HEALTH_PROBE_KEY).map(|_| ()).map_err(|e| e.to_string()));
+        if options.write_probe {
+            run("write_probe", &|| self.health_write_probe());
+        }
+        run("wal_buffer", &|| {
+            check_lock_order(LockRank::WalBuffer);
+            let depth = self.wal.buffer.lock().map_err(|e| format!("WAL buffer lock poisoned: {}", e))?.data.len();
+            if depth >= self.wal.max_unflushed {
+                return Err(format!("{} bytes waiting to be synced, so writes are waiting on the flusher", depth));
+            }
+            Ok(())
+        });
+        run("wal_flusher", &|| {
+            if !self.wal.flusher {
+                return Ok(());
+            }
+            let last_pass = *self.wal.last_pass.lock().map_err(|e| format!("WAL pass lock poisoned: {}", e))?;
+            let idle = self.clock.monotonic().saturating_duration_since(last_pass);
+            if idle > Duration::from_millis(3 * GROUP_COMMIT_MS) {
+                return Err(format!("Last ran {:?} ago", idle));
+            }
+            Ok(())
+        });
+        run("block_cache", &|| self.health_read_block());
+        Ok(report)
     }
     
-    fn add(&mut self, rec_type: u8, key: &str, value: Option<&str>, seq: u64) -> io::Result<()> {
-        self.bloom.add(key);
-        
-        if seq < self.seq_low {
-            self.seq_low = seq;
+    // Set the probe key, read it back and delete it; as a system key it stays out of user reads
+    fn health_write_probe(&self) -> std::result::Result<(), String> {
+        let value = format!("{:?}", Instant::now());
+        self.system_set(HEALTH_PROBE_KEY, &value).map_err(|e| e.to_string())?;
+        let read = self.system_get(HEALTH_PROBE_KEY).map_err(|e| e.to_string())?;
+        self.system_delete(HEALTH_PROBE_KEY).map_err(|e| e.to_string())?;
+        match read {
+            Some(read) if read == value => Ok(()),
+            read => Err(format!("Read back {:?} after writing {:?}", read, value)),
         }
-        if seq > self.seq_high {
-            self.seq_high = seq;
+    }
+    
+    // Load one block of a random segment through the cache and check it decodes
+    fn health_read_block(&self) -> std::result::Result<(), String> {
+        let segments: Vec<Arc<Segment>> = {
+            let inner = self.read_inner().map_err(|e| e.to_string())?;
+            inner.segments_l0.iter()
+                .chain(inner.segments_l1.iter())
+                .chain(inner.segments_l2.iter())
+                .chain(inner.segments_archive.iter())
+                .filter(|seg| !seg.index.is_empty())
+                .cloned()
+                .collect()
+        };
+        if segments.is_empty() {
+            return Ok(());
         }
         
-        // Build record with FIXED consistent order
-        let mut record = Vec::new();
-        record.extend_from_slice(&seq.to_le_bytes());
-        record.push(rec_type);
-        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
-        record.extend_from_slice(&(value.as_ref().map_or(0, |v| v.len()) as u32).to_le_bytes());
-        record.extend_from_slice(key.as_bytes());
-        if let Some(v) = value {
-            record.extend_from_slice(v.as_bytes());
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let pick = xxhash(&nanos.to_le_bytes(), 0) as usize;
+        let seg = &segments[pick % segments.len()];
+        let idx = (pick / segments.len()) % seg.index.len();
+        let (offset, len) = seg.block_span(idx);
+        let block_data = self.cache.get_or_load(seg, offset, len, BlockAccess::Scan).map_err(|e| format!("{}: {}", seg.path.display(), e))?;
+        match BlockReader::new(seg, &block_data, offset).next_record() {
+            Some(_) => Ok(()),
+            None => Err(format!("{}: block {} at offset {} holds no records", seg.path.display(), idx, offset)),
         }
-        
-        if self.current_block.len() + record.len() > BLOCK_SIZE {
-            self.flush_block()?;
+    }
+    
+    /// Stop or restart the background WAL flusher's passes, to rehearse a stall: while paused,
+    /// writes stay buffered until max_unflushed_bytes pile up, when the write that finds the
+    /// buffer full syncs it, or a durable write syncs them, and health_check fails its
+    /// wal_flusher check.
+    pub fn pause_wal_flusher(&self, paused: bool) {
+        self.wal.paused.store(paused, Ordering::SeqCst);
+    }
+    
+    /// The time by StoreOptions::clock, but never before a time this has already returned or
+    /// the latest the manifest records, so a wall clock set back can't move it backwards.
+    /// What anything comparing against stored timestamps, such as an expiry, should use.
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+    
+    /// The store's IDENTITY: its id, when it was created and its on-disk format version
+    pub fn identity(&self) -> StoreIdentity {
+        self.identity
+    }
+    
+    /// Stays the same for the life of the store, and is shared only with copies of its directory
+    pub fn store_id(&self) -> StoreId {
+        self.identity.id
+    }
+    
+    pub fn format_version(&self) -> u32 {
+        self.identity.format_version
+    }
+    
+    /// Whether writes are being refused with WalDbError::StorageFull after a WAL sync or
+    /// memtable flush failed to write. Reads carry on, and the flusher keeps retrying the
+    /// WAL until a sync succeeds and writes are accepted again.
+    pub fn is_degraded(&self) -> bool {
+        self.wal.degraded.load(Ordering::SeqCst)
+    }
+    
+    /// The newest seq this store has handed out or applied. A replica asks its primary for
+    /// changes_since this after a reconnect.
+    pub fn seq(&self) -> Result<u64> {
+        Ok(self.read_inner()?.seq())
+    }
+    
+    /// The newest seq fsynced to the WAL: a crash keeps every write up to it, and may lose
+    /// the ones after. Never past seq(). A plain atomic read.
+    pub fn durable_seq(&self) -> u64 {
+        self.wal.durable_seq.load(Ordering::SeqCst)
+    }
+    
+    /// The newest seq written out to segments: replay after a crash starts past it. Right
+    /// after flush() it is at most durable_seq, since a flush syncs the WAL too, but a
+    /// background flush may write a segment before the group commit syncs the same writes.
+    /// A plain atomic read.
+    pub fn flushed_seq(&self) -> u64 {
+        self.flushed_seq.load(Ordering::SeqCst)
+    }
+    
+    /// Whether writes are refused with WalDbError::ReadOnly because rebuild_into is copying
+    /// the store or has handed it over to the copy
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+    
+    /// How big the WAL is now and what open read back from it
+    pub fn wal_stats(&self) -> Result<WalStats> {
+        let bytes = self.wal.len()?;
+        let (buffered, buffered_bytes) = {
+            check_lock_order(LockRank::WalBuffer);
+            let buffer = self.wal.buffer.lock().map_err(|e| WalDbError::Locked(format!("WAL buffer lock poisoned: {}", e)))?;
+            (buffer.records, buffer.data.len())
+        };
+        Ok(WalStats {
+            bytes,
+            buffered,
+            buffered_bytes,
+            throttled_writes: self.wal.throttled.load(Ordering::Relaxed),
+            throttle_wait: Duration::from_micros(self.wal.throttle_wait_us.load(Ordering::Relaxed)),
+            ..self.replayed
+        })
+    }
+    
+    /// Write a record-by-record description of one of this store's segment files, named
+    /// as in the directory (000042.seg), whichever of the data_dirs it is in. See
+    /// dump_segment_file.
+    pub fn dump_segment(&self, filename: &str, out: impl Write, options: DumpOptions) -> Result<usize> {
+        if self.in_memory {
+            let inner = self.read_inner()?;
+            let seg = inner.segments_l0.iter()
+                .chain(inner.segments_l1.iter())
+                .chain(inner.segments_l2.iter())
+                .chain(inner.segments_archive.iter())
+                .find(|seg| seg.path.file_name().and_then(|name| name.to_str()) == Some(filename))
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No segment {}", filename)))?;
+            drop(inner);
+            return seg.dump(out, options);
+        }
+        Segment::open(&self.segment_dirs.resolve(filename), 0, self.collation)?.dump(out, options)
+    }
+    
+    /// Write a description of a segment file without opening a store: header, footer fields,
+    /// bloom fill, every record with its offset, seq and type under the block it falls in,
+    /// then the index. Lines starting "!!" flag keys out of order, records running past a
+    /// block boundary and index entries that don't point at a matching record. The output
+    /// holds nothing that changes between runs, so two dumps can be diffed. Returns how many
+    /// anomalies were flagged. A segment whose footer is too damaged to say how its keys are
+    /// ordered is checked against byte order.
+    pub fn dump_segment_file(path: &Path, out: impl Write, options: DumpOptions) -> Result<usize> {
+        Segment::open(path, 0, Collation::Binary)?.dump(out, options)
+    }
+    
+    pub fn get_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        self.get_range_limit(start, end, usize::MAX)
+    }
+    
+    pub fn get_range_limit(&self, start: &str, end: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        self.range_scan(start, end, limit, None)
+    }
+    
+    /// get_range_limit that gives up with WalDbError::Cancelled once cancel trips
+    pub fn get_range_limit_cancellable(&self, start: &str, end: &str, limit: usize, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        self.range_scan(start, end, limit, Some(cancel))
+    }
+    
+    /// get_range_limit with values shared as in get_ref, so memtable values aren't copied
+    pub fn get_range_ref(&self, start: &str, end: &str, limit: usize) -> Result<Vec<(String, Arc<str>)>> {
+        self.range_scan(start, end, limit, None)
+    }
+    
+    fn range_scan<V: RangeValue>(&self, start: &str, end: &str, limit: usize, cancel: Option<&CancellationToken>) -> Result<Vec<(String, V)>> {
+        let inner = self.read_inner()?;
+        let results = self.collect_range_locked(&inner, start, end, cancel)?;
+        Ok(self.live_entries(&inner, results, limit))
+    }
+    
+    fn prefix_scan<V: RangeValue>(&self, prefix: &str, limit: usize, cancel: Option<&CancellationToken>) -> Result<Vec<(String, V)>> {
+        let inner = self.read_inner()?;
+        let results = self.collect_prefix_locked(&inner, prefix, cancel)?;
+        let entries = self.live_entries(&inner, results, limit);
+        if entries.is_empty() && self.strict_reads {
+            self.check_no_scalar_ancestor(&inner, &inner.shard(prefix), prefix, Self::prefix_owner(prefix))?;
+        }
+        Ok(entries)
+    }
+    
+    fn live_entries<V>(&self, inner: &StoreInner, results: BTreeMap<CollatedKey, (V, u64)>, limit: usize) -> Vec<(String, V)> {
+        // Filter out tombstones and apply limit
+        let mut final_results = Vec::new();
+        for (key, (value, seq)) in results {
+            if seq & (1u64 << 63) == 0 && !self.covered_by_subtomb(inner, &key.key, seq) {
+                final_results.push((key.key, value));
+                if final_results.len() >= limit {
+                    break;
+                }
+            }
         }
         
-        if self.current_block.is_empty() {
-            self.index.push((key.to_string(), self.written));
+        final_results
+    }
+    
+    /// Newest record per key in [start, end) including tombstones, plus every subtree
+    /// tombstone overlapping the range, for consumers that replicate deletions
+    pub fn get_range_raw(&self, start: &str, end: &str) -> Result<Vec<RawEntry>> {
+        let inner = self.read_inner()?;
+        let results = self.collect_range_locked(&inner, start, end, None)?;
+        
+        let mut entries = Vec::new();
+        for (key, (value, seq)) in results {
+            let seq_only = seq & !(1u64 << 63);
+            // Keys under a newer subtree tombstone are reported through that tombstone
+            if self.covered_by_subtomb(&inner, &key.key, seq_only) {
+                continue;
+            }
+            let state = if seq & (1u64 << 63) != 0 { RawState::PointDeleted } else { RawState::Live(value) };
+            entries.push(RawEntry { key: key.key, seq: seq_only, state });
         }
         
-        self.current_block.extend_from_slice(&record);
-        self.key_count += 1;
+        let collation = inner.collation;
+        for (prefix, seq) in inner.subtombs.iter().filter(|(prefix, _)| !is_system_key(prefix)) {
+            if collation.compare(prefix.as_bytes(), end.as_bytes()).is_lt() && collation.compare(prefix_end(prefix).as_bytes(), start.as_bytes()).is_gt() {
+                entries.push(RawEntry {
+                    key: prefix.clone(),
+                    seq: *seq,
+                    state: RawState::SubtreeDeleted(prefix.clone()),
+                });
+            }
+        }
         
-        Ok(())
+        entries.sort_by(|a, b| collation.compare(a.key.as_bytes(), b.key.as_bytes()));
+        Ok(entries)
     }
     
-    fn flush_block(&mut self) -> io::Result<()> {
-        if self.current_block.is_empty() {
-            return Ok(());
+    /// Every live entry in key order as export lines, {"key":..,"value":..}, streamed through
+    /// range_iter. Returns how many were written.
+    pub fn export(&self, mut out: impl Write, options: ExportOptions) -> Result<usize> {
+        let mut iter = self.range_iter("", &prefix_end(""))?;
+        iter.hide_system = !options.include_system;
+        let mut line = String::new();
+        let mut written = 0;
+        for entry in iter {
+            let (key, value) = entry?;
+            line.clear();
+            push_export_line(&mut line, &key, &value);
+            out.write_all(line.as_bytes())?;
+            written += 1;
         }
+        out.flush()?;
+        Ok(written)
+    }
+    
+    /// The live entries under options.prefix as a snapshot, a file that checks itself: a
+    /// header naming the store, the prefix and the seqs it spans, then the entries as export
+    /// lines, closed every chunk_entries by a line with the chunk's CRC32 and the running
+    /// entry count, then a trailer with the total and a digest of every line before it. The
+    /// same data always writes the same entry and chunk lines. Returns what the header and
+    /// trailer say.
+    pub fn export_snapshot(&self, mut out: impl Write, options: &SnapshotOptions) -> Result<SnapshotInfo> {
+        let chunk_entries = options.chunk_entries.max(1);
+        // The ceiling is read with the iterator's view, so no write past it is in the file
+        let (iter, seq_ceiling) = {
+            let inner = self.read_inner()?;
+            (self.scan_prefix_iter_locked(&inner, &options.prefix)?, inner.seq())
+        };
+        let mut info = SnapshotInfo {
+            store_id: self.store_id(),
+            prefix: options.prefix.clone(),
+            seq_floor: self.history_floor(),
+            seq_ceiling,
+            chunks: 0,
+            entries: 0,
+            digest: 0,
+        };
         
-        self.file.write_all(&self.current_block)?;
-        self.written += self.current_block.len() as u64;
-        self.current_block.clear();
+        // Numbers go out as strings, so parse_json_fields reads every line back
+        let mut line = String::from("{\"snapshot\":");
+        push_json_string(&mut line, SNAPSHOT_VERSION);
+        for (name, value) in [
+            ("store_id", info.store_id.to_string()),
+            ("prefix", info.prefix.clone()),
+            ("seq_floor", info.seq_floor.to_string()),
+            ("seq_ceiling", info.seq_ceiling.to_string()),
+        ] {
+            line.push_str(&format!(",\"{}\":", name));
+            push_json_string(&mut line, &value);
+        }
+        line.push_str("}\n");
+        info.digest = xxhash(line.as_bytes(), info.digest);
+        out.write_all(line.as_bytes())?;
+        
+        // Each chunk is gathered whole to take its CRC, then closed and written out
+        fn close_chunk(chunk: &mut String, in_chunk: &mut usize, info: &mut SnapshotInfo, out: &mut impl Write) -> Result<()> {
+            info.entries += *in_chunk;
+            chunk.push_str(&format!("{{\"chunk\":\"{}\",\"entries\":\"{}\",\"crc\":\"{:08x}\"}}\n", info.chunks, info.entries, crc32(chunk.as_bytes())));
+            for line in chunk.split_inclusive('\n') {
+                info.digest = xxhash(line.as_bytes(), info.digest);
+            }
+            out.write_all(chunk.as_bytes())?;
+            info.chunks += 1;
+            chunk.clear();
+            *in_chunk = 0;
+            Ok(())
+        }
+        let mut chunk = String::new();
+        let mut in_chunk = 0;
+        for entry in iter {
+            let (key, value) = entry?;
+            push_export_line(&mut chunk, &key, &value);
+            in_chunk += 1;
+            if in_chunk == chunk_entries {
+                close_chunk(&mut chunk, &mut in_chunk, &mut info, &mut out)?;
+            }
+        }
+        if in_chunk > 0 {
+            close_chunk(&mut chunk, &mut in_chunk, &mut info, &mut out)?;
+        }
         
-        Ok(())
+        out.write_all(format!("{{\"end\":\"{}\",\"chunks\":\"{}\",\"digest\":\"{:016x}\"}}\n", info.entries, info.chunks, info.digest).as_bytes())?;
+        out.flush()?;
+        Ok(info)
     }
     
-    fn finish(mut self) -> io::Result<Segment> {
-        self.flush_block()?;
-        
-        let index_start = self.written;
+    /// Apply a snapshot from export_snapshot, each chunk as one WriteBatch once its CRC checks
+    /// out, so a chunk is applied whole or not at all. A damaged chunk fails with
+    /// WalDbError::SnapshotCorrupt naming it, the chunks before it applied. Chunks before
+    /// options.resume_from_chunk are checked but not applied again, for carrying on once the
+    /// file is repaired; applying a chunk twice leaves the same values, so resuming from an
+    /// earlier chunk than needed is harmless. The trailer is checked last, after every chunk
+    /// has been applied.
+    pub fn import(&self, reader: impl BufRead, options: ImportOptions) -> Result<ImportReport> {
+        let (mut chunks_applied, mut entries_applied) = (0, 0);
+        let snapshot = read_snapshot(reader, |chunk, entries| {
+            if chunk < options.resume_from_chunk {
+                return Ok(());
+            }
+            let mut batch = WriteBatch::new();
+            for (key, value) in &entries {
+                batch.put(key, value);
+            }
+            self.write(batch)?;
+            chunks_applied += 1;
+            entries_applied += entries.len();
+            Ok(())
+        })?;
+        Ok(ImportReport { snapshot, chunks_applied, entries_applied })
+    }
+    
+    /// Check a snapshot file from export_snapshot the way import does, without a store:
+    /// every chunk's CRC and count, then the trailer's total and digest
+    pub fn verify_export_file(path: &Path) -> Result<SnapshotInfo> {
+        read_snapshot(BufReader::new(File::open(path)?), |_, _| Ok(()))
+    }
+    
+    /// Hash the live data under prefix per bucket, for diff_against on another store. A
+    /// bucket's hash sums its entries' key and value hashes, so stores holding the same data
+    /// agree however it was written. Seqs are left out: each store assigns its own, and a
+    /// replica that applied a diff has different ones. Streams through scan_prefix_iter,
+    /// keeping one hash per bucket.
+    pub fn sync_digest(&self, prefix: &str, granularity: SyncGranularity) -> Result<Vec<(String, u64)>> {
+        let mut buckets: BTreeMap<String, u64> = BTreeMap::new();
+        for entry in self.scan_prefix_iter(prefix)? {
+            let (key, value) = entry?;
+            let hash = xxhash(value.as_bytes(), xxhash(key.as_bytes(), 0));
+            let bucket = granularity.bucket(prefix, &key);
+            match buckets.get_mut(bucket) {
+                Some(sum) => *sum = sum.wrapping_add(hash),
+                None => {
+                    buckets.insert(bucket.to_string(), hash);
+                }
+            }
+        }
+        Ok(buckets.into_iter().collect())
+    }
+    
+    /// Compare this store's sync_digest of prefix with remote_digest, taken on another store
+    /// with the same granularity, to find the buckets export_diff needs to send
+    pub fn diff_against(&self, prefix: &str, granularity: SyncGranularity, remote_digest: &[(String, u64)]) -> Result<DiffPlan> {
+        let local = self.sync_digest(prefix, granularity)?;
+        let remote: HashMap<&str, u64> = remote_digest.iter().map(|(bucket, hash)| (bucket.as_str(), *hash)).collect();
+        
+        let mut plan = DiffPlan { prefix: prefix.to_string(), granularity, ..Default::default() };
+        for (bucket, hash) in &local {
+            match remote.get(bucket.as_str()) {
+                Some(remote_hash) if remote_hash == hash => {}
+                Some(_) => plan.changed.push(bucket.clone()),
+                None => plan.local_only.push(bucket.clone()),
+            }
+        }
+        let local: HashSet<&str> = local.iter().map(|(bucket, _)| bucket.as_str()).collect();
+        plan.remote_only = remote_digest.iter()
+            .filter(|(bucket, _)| !local.contains(bucket.as_str()))
+            .map(|(bucket, _)| bucket.clone())
+            .collect();
+        Ok(plan)
+    }
+    
+    /// Write the payload apply_diff takes on the remote store: a header line naming the prefix
+    /// and granularity, then for each bucket in plan a {"bucket":..} line and its entries as
+    /// export lines, {"key":..,"value":..}. Buckets only the remote has go out empty, which
+    /// clears them there. Returns how many entries were written.
+    pub fn export_diff(&self, plan: &DiffPlan, mut out: impl Write) -> Result<usize> {
+        let mut line = String::from("{\"sync\":");
+        push_json_string(&mut line, &plan.prefix);
+        line.push_str(",\"granularity\":");
+        push_json_string(&mut line, &plan.granularity.name());
+        line.push_str("}\n");
+        out.write_all(line.as_bytes())?;
+        
+        let mut written = 0;
+        for bucket in plan.changed.iter().chain(&plan.local_only).chain(&plan.remote_only) {
+            line.clear();
+            line.push_str("{\"bucket\":");
+            push_json_string(&mut line, bucket);
+            line.push_str("}\n");
+            out.write_all(line.as_bytes())?;
+            
+            for entry in self.scan_prefix_iter(bucket)? {
+                let (key, value) = entry?;
+                if plan.granularity.bucket(&plan.prefix, &key) != bucket {
+                    continue;
+                }
+                line.clear();
+                push_export_line(&mut line, &key, &value);
+                out.write_all(line.as_bytes())?;
+                written += 1;
+            }
+        }
+        out.flush()?;
+        Ok(written)
+    }
+    
+    /// Apply a payload from export_diff. Each bucket becomes one WriteBatch that puts its
+    /// entries, skipping those already stored, and deletes the bucket's other keys, so a
+    /// reader never sees a bucket half applied. Returns how many buckets were applied. A
+    /// malformed line fails with InvalidData, leaving the buckets before it applied.
+    pub fn apply_diff(&self, reader: impl BufRead) -> Result<usize> {
+        let invalid = |detail: String| -> WalDbError { io::Error::new(io::ErrorKind::InvalidData, format!("Bad diff payload: {}", detail)).into() };
+        let mut lines = reader.lines();
+        let header = lines.next().transpose()?.ok_or_else(|| invalid("it is empty".to_string()))?;
+        let (prefix, granularity) = match parse_json_fields(&header).as_deref() {
+            Some([(sync, prefix), (name, granularity)]) if sync == "sync" && name == "granularity" => {
+                let granularity = SyncGranularity::parse(granularity).ok_or_else(|| invalid(format!("unknown granularity {:?}", granularity)))?;
+                (prefix.clone(), granularity)
+            }
+            _ => return Err(invalid(format!("expected a header line, got {:?}", header))),
+        };
         
-        // Write index
-        let mut index_data = Vec::new();
-        for (k, offset) in &self.index {
-            index_data.extend_from_slice(&(k.len() as u32).to_le_bytes());
-            index_data.extend_from_slice(&offset.to_le_bytes());
-            index_data.extend_from_slice(k.as_bytes());
+        let mut applied = 0;
+        let mut bucket: Option<(String, BTreeMap<String, String>)> = None;
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_json_fields(&line).as_deref() {
+                Some([(field, name)]) if field == "bucket" && name.starts_with(prefix.as_str()) => {
+                    if let Some((name, entries)) = bucket.take() {
+                        self.apply_bucket(&prefix, granularity, &name, entries)?;
+                        applied += 1;
+                    }
+                    bucket = Some((name.clone(), BTreeMap::new()));
+                }
+                Some([(field, key), (other, value)]) if field == "key" && other == "value" => match &mut bucket {
+                    Some((name, entries)) if key.starts_with(prefix.as_str()) && granularity.bucket(&prefix, key) == name => {
+                        entries.insert(key.clone(), value.clone());
+                    }
+                    _ => return Err(invalid(format!("{:?} is outside the bucket before it", key))),
+                },
+                _ => return Err(invalid(format!("unexpected line {:?}", line))),
+            }
+        }
+        if let Some((name, entries)) = bucket {
+            self.apply_bucket(&prefix, granularity, &name, entries)?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+    
+    fn apply_bucket(&self, prefix: &str, granularity: SyncGranularity, bucket: &str, mut entries: BTreeMap<String, String>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for entry in self.scan_prefix_iter(bucket)? {
+            let (key, value) = entry?;
+            if granularity.bucket(prefix, &key) != bucket {
+                continue;
+            }
+            match entries.get(&key) {
+                Some(sent) if *sent == value => {
+                    entries.remove(&key);
+                }
+                Some(_) => {}
+                None => {
+                    batch.delete(&key);
+                }
+            }
+        }
+        for (key, value) in &entries {
+            batch.put(key, value);
+        }
+        self.write(batch)?;
+        Ok(())
+    }
+    
+    /// The live value at prefix and everything under it as one JSON document, None if there is
+    /// neither. Values stay JSON strings exactly as stored.
+    pub fn get_subtree_json(&self, prefix: &str) -> Result<Option<String>> {
+        Ok(self.get_subtree_json_with_options(prefix, JsonOptions::default())?.map(|tree| tree.json))
+    }
+    
+    /// get_subtree_json, also reporting paths that hold a scalar and have children.
+    /// The children win those conflicts and the scalar is left out of the document.
+    pub fn get_subtree_json_with_options(&self, prefix: &str, options: JsonOptions) -> Result<Option<SubtreeJson>> {
+        let root = prefix.strip_suffix('/').unwrap_or(prefix);
+        let (start, end) = if root.is_empty() {
+            (String::new(), prefix_end(""))
+        } else {
+            (root.to_string(), prefix_end(&format!("{}/", root)))
+        };
+        
+        let inner = self.read_inner()?;
+        let results = self.collect_range_locked(&inner, &start, &end, None)?;
+        
+        let mut root_value = None;
+        let mut children: BTreeMap<String, JsonNode> = BTreeMap::new();
+        let mut conflicts = Vec::new();
+        for (key, (value, seq)) in results {
+            if seq & (1u64 << 63) != 0 || self.covered_by_subtomb(&inner, &key.key, seq) {
+                continue;
+            }
+            if key.key == root {
+                root_value = Some(value);
+                continue;
+            }
+            let relative = if root.is_empty() {
+                key.key.as_str()
+            } else {
+                match key.key.strip_prefix(root).and_then(|rest| rest.strip_prefix('/')) {
+                    Some(relative) => relative,
+                    None => continue,  // A sibling such as "root!" that sorts inside the range
+                }
+            };
+            JsonNode::insert(&mut children, root, relative, value, &mut conflicts);
+        }
+        drop(inner);
+        
+        let tree = match root_value {
+            Some(_) if !children.is_empty() => {
+                conflicts.insert(0, root.to_string());
+                JsonNode::Object(children)
+            }
+            Some(value) => JsonNode::Value(value),
+            None if children.is_empty() => return Ok(None),
+            None => JsonNode::Object(children),
+        };
+        
+        let mut json = String::new();
+        tree.write(&mut json, &options);
+        Ok(Some(SubtreeJson { json, conflicts }))
+    }
+    
+    // Newest record per key in [start, end) across memtable and segments. Point tombstones
+    // carry the high seq bit; subtree tombstones are left for the caller to apply.
+    fn collect_range_locked<V: RangeValue>(&self, inner: &StoreInner, start: &str, end: &str,
+                                           cancel: Option<&CancellationToken>) -> Result<BTreeMap<CollatedKey, (V, u64)>> {
+        let mut results = BTreeMap::new();
+        if inner.collation.compare(start.as_bytes(), end.as_bytes()).is_ge() {
+            return Ok(results);
+        }
+        
+        // Collect from memtable
+        let (low, high) = ((start, inner.collation), (end, inner.collation));
+        for (k, v) in mem_range(&inner.shards(), Bound::Included(&low), Bound::Excluded(&high)) {
+            match v {
+                MemValue::Scalar(val, seq) => {
+                    results.insert(k.clone(), (V::from_memtable(val), *seq));
+                }
+                MemValue::PointTomb(seq) => {
+                    // Mark as tombstone
+                    results.insert(k.clone(), (V::from_segment(""), *seq | (1u64 << 63)));
+                }
+            }
+        }
+        
+        // Collect from all segments, newest first so older copies of a key are passed over
+        // rather than copied and replaced
+        for segment in Self::segments_newest_first(inner) {
+            self.collect_range_from_segment(segment, start, end, cancel, &mut results)?;
+        }
+        
+        // Every ranged read comes through here, so this is where system keys stay hidden
+        results.retain(|key, _| !is_system_key(&key.key));
+        
+        // Shadowed references may name values reclaim_values has dropped, so only the newest
+        // record's is followed, and only if no subtree tombstone hides it
+        for (key, (value, seq)) in results.iter_mut() {
+            if *seq & (1u64 << 62) != 0 {
+                *seq &= !(1u64 << 62);
+                if !self.covered_by_subtomb(inner, &key.key, *seq) {
+                    *value = V::from_string(self.resolve_value_ref(value.as_bytes())?);
+                }
+            }
+        }
+        
+        Ok(results)
+    }
+    
+    /// Live entries under prefix in key order, newest record per key across the memtable and
+    /// every level, up to limit
+    pub fn scan_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        self.prefix_scan(prefix, limit, None)
+    }
+    
+    /// scan_prefix that gives up with WalDbError::Cancelled once cancel trips
+    pub fn scan_prefix_cancellable(&self, prefix: &str, limit: usize, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        self.prefix_scan(prefix, limit, Some(cancel))
+    }
+    
+    /// scan_prefix with values shared as in get_ref
+    pub fn scan_prefix_ref(&self, prefix: &str, limit: usize) -> Result<Vec<(String, Arc<str>)>> {
+        self.prefix_scan(prefix, limit, None)
+    }
+    
+    // Records under prefix. Bounded by char::MAX, not '~', so keys continuing with DEL or
+    // non-ASCII are included. Numeric-aware order puts "users/10" after "users/1" + char::MAX,
+    // so there a prefix ending mid-segment scans its parent and keeps the matching keys.
+    fn collect_prefix_locked<V: RangeValue>(&self, inner: &StoreInner, prefix: &str, cancel: Option<&CancellationToken>)
+                                            -> Result<BTreeMap<CollatedKey, (V, u64)>> {
+        if inner.collation == Collation::Binary || prefix.is_empty() || prefix.ends_with('/') {
+            return self.collect_range_locked(inner, prefix, &prefix_end(prefix), cancel);
+        }
+        let parent = prefix.rfind('/').map_or("", |slash| &prefix[..=slash]);
+        let mut results = self.collect_range_locked(inner, parent, &prefix_end(parent), cancel)?;
+        results.retain(|key, _| key.key.starts_with(prefix));
+        Ok(results)
+    }
+    
+    /// Live entries in [start, end) in key order, read as the iterator advances instead of
+    /// gathered up front. It sees the store as of this call whatever is written meanwhile, and
+    /// the segment files it reads stay on disk until it is dropped.
+    pub fn range_iter(&self, start: &str, end: &str) -> Result<RangeIter> {
+        let inner = self.read_inner()?;
+        self.range_iter_locked(&inner, start, end, None)
+    }
+    
+    /// range_iter from the last entry before end back to start
+    pub fn range_iter_rev(&self, start: &str, end: &str) -> Result<RangeIter> {
+        Ok(self.range_iter(start, end)?.reversed())
+    }
+    
+    /// range_iter over the live entries under prefix
+    pub fn scan_prefix_iter(&self, prefix: &str) -> Result<RangeIter> {
+        let inner = self.read_inner()?;
+        self.scan_prefix_iter_locked(&inner, prefix)
+    }
+    
+    fn scan_prefix_iter_locked(&self, inner: &StoreInner, prefix: &str) -> Result<RangeIter> {
+        // An iterator can't know it is empty until read, so strict reads check up front
+        if self.strict_reads {
+            self.check_no_scalar_ancestor(inner, &inner.shard(prefix), prefix, Self::prefix_owner(prefix))?;
+        }
+        // The same parent scan collect_prefix_locked does under numeric-aware order
+        if inner.collation == Collation::Binary || prefix.is_empty() || prefix.ends_with('/') {
+            return self.range_iter_locked(inner, prefix, &prefix_end(prefix), None);
+        }
+        let parent = prefix.rfind('/').map_or("", |slash| &prefix[..=slash]);
+        self.range_iter_locked(inner, parent, &prefix_end(parent), Some(prefix))
+    }
+    
+    /// scan_prefix_iter from the last entry under prefix back to the first
+    pub fn scan_prefix_iter_rev(&self, prefix: &str) -> Result<RangeIter> {
+        Ok(self.scan_prefix_iter(prefix)?.reversed())
+    }
+    
+    /// The store as it stood right after the write that returned seq, for finding out what
+    /// happened to data after the fact: later sets and deletes don't exist in it. Reads the
+    /// WAL back to recover the versions the memtable has overwritten, so it costs a pass over
+    /// the log, and holds the segments it reads until dropped. Fails with
+    /// WalDbError::HistoryUnavailable if seq is older than history_floor.
+    pub fn as_of(&self, seq: u64) -> Result<Snapshot<'_>> {
+        let inner = self.read_inner()?;
+        // Held throughout, so no sharded write lands while the log is read
+        let _shards = inner.shards();
+        if seq > inner.seq() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("seq {} has not been assigned yet", seq)).into());
+        }
+        let floor = self.history_floor();
+        if seq < floor {
+            return Err(WalDbError::HistoryUnavailable { seq, floor });
+        }
+        
+        // Each key's newest record up to seq the segments don't have, and each prefix's newest
+        // subtree delete, which only the WAL keeps. The buffer is copied before the log is
+        // read, so an entry a sync moves meanwhile is seen twice rather than not at all.
+        let (mut memtable, mut subtombs) = (BTreeMap::new(), HashMap::new());
+        let flushed_seq = self.flushed_seq.load(Ordering::SeqCst);
+        let mut visit = |kind: u8, record_seq: u64, key: Cow<str>, value: Option<Cow<str>>| {
+            if record_seq > seq {
+                return;
+            }
+            let record = match (kind, value) {
+                (RT_DEL_SUB, _) => {
+                    subtombs.insert(key.into_owned(), record_seq);
+                    return;
+                }
+                _ if record_seq <= flushed_seq => return,
+                (RT_SET, Some(value)) => MemValue::Scalar(CompactStr::from(value.as_ref()), record_seq),
+                (RT_DEL_POINT, _) => MemValue::PointTomb(record_seq),
+                _ => return,
+            };
+            memtable.insert(CollatedKey { key: key.into_owned(), collation: inner.collation }, record);
+        };
+        check_lock_order(LockRank::WalBuffer);
+        let buffered = self.wal.buffer.lock()
+            .map_err(|e| WalDbError::Locked(format!("WAL buffer lock poisoned: {}", e)))?
+            .data.clone();
+        self.wal.read(self.lossy_reads, &mut visit)?;
+        for record in group_records(&buffered) {
+            // Not on disk yet, so at no offset of the log
+            apply_wal_record(record, self.lossy_reads, &mut visit).map_err(|bad| bad.error(&self.wal.path, 0))?;
+        }
+        
+        let segments = inner.segments_l0.iter()
+            .chain(inner.segments_l1.iter())
+            .chain(inner.segments_l2.iter())
+            .chain(inner.segments_archive.iter())
+            .cloned()
+            .collect();
+        *self.open_snapshots.lock().map_err(|e| WalDbError::Locked(format!("Snapshot list lock poisoned: {}", e)))?
+            .entry(seq).or_default() += 1;
+        Ok(Snapshot { store: self, seq, memtable, subtombs, segments })
+    }
+    
+    /// The oldest seq as_of still answers for. Flushes of overwritten keys, compaction
+    /// merges and drop_segment move it up as they discard versions older reads would need.
+    pub fn history_floor(&self) -> u64 {
+        self.manifest_locked().expect("Manifest lock should not be poisoned in history_floor").history_floor
+    }
+    
+    // Copies the memtable's records in range and pins the segments; the blocks are read later
+    fn range_iter_locked(&self, inner: &StoreInner, start: &str, end: &str, keep_prefix: Option<&str>) -> Result<RangeIter> {
+        // The seq is read with every shard held, so the copy holds everything up to it
+        let shards = inner.shards();
+        let memtable = if inner.collation.compare(start.as_bytes(), end.as_bytes()).is_lt() {
+            let (low, high) = ((start, inner.collation), (end, inner.collation));
+            mem_range(&shards, Bound::Included(&low), Bound::Excluded(&high))
+        } else {
+            Vec::new()
+        };
+        let segments = inner.segments_l0.iter()
+            .chain(inner.segments_l1.iter())
+            .chain(inner.segments_l2.iter())
+            .chain(inner.segments_archive.iter());
+        self.range_iter_over(memtable, segments, inner.subtombs.clone(), inner.seq(), (start, end), keep_prefix)
+    }
+    
+    // A RangeIter over [start, end) of these memtable records (the ones in range) and
+    // segments, reading no record newer than seq
+    fn range_iter_over<'a>(&self, memtable: Vec<(&CollatedKey, &MemValue)>, segments: impl Iterator<Item = &'a Arc<Segment>>,
+                           subtombs: HashMap<String, u64>, seq: u64, (start, end): (&str, &str), keep_prefix: Option<&str>) -> Result<RangeIter> {
+        let mut sources = Vec::new();
+        if self.collation.compare(start.as_bytes(), end.as_bytes()).is_lt() {
+            let memtable = memtable.into_iter()
+                .map(|(k, v)| match v {
+                    MemValue::Scalar(value, seq) => (k.key.clone(), RT_SET, value.to_string(), *seq),
+                    MemValue::PointTomb(seq) => (k.key.clone(), RT_DEL_POINT, String::new(), *seq),
+                })
+                .collect();
+            sources.push(RangeSource { segment: None, next_block: 0, records: memtable });
+            
+            for segment in segments.filter(|segment| segment.overlaps(start, end)) {
+                let next_block = match segment.index.search(start.as_bytes(), segment.collation) {
+                    Ok(i) => i,
+                    Err(i) => i.saturating_sub(1),  // Include the block before start
+                };
+                sources.push(RangeSource { segment: Some(segment.clone()), next_block, records: VecDeque::new() });
+            }
+        }
+        
+        *self.open_snapshots.lock().map_err(|e| WalDbError::Locked(format!("Snapshot list lock poisoned: {}", e)))?
+            .entry(seq).or_default() += 1;
+        Ok(RangeIter {
+            sources,
+            start: start.to_string(),
+            end: end.to_string(),
+            keep_prefix: keep_prefix.map(str::to_string),
+            strip: 0,
+            hide_system: true,
+            collation: self.collation,
+            subtombs,
+            cache: self.cache.clone(),
+            values: self.values.clone(),
+            value_cache: self.value_cache.clone(),
+            snapshots: self.open_snapshots.clone(),
+            segment_registry: self.segment_registry.clone(),
+            seq,
+            reverse: false,
+            lossy: self.lossy_reads,
+            failed: false,
+        })
+    }
+    
+    fn collect_range_from_segment<V: RangeValue>(&self, seg: &Arc<Segment>, start: &str, end: &str, cancel: Option<&CancellationToken>,
+                                                  results: &mut BTreeMap<CollatedKey, (V, u64)>) -> Result<()> {
+        if !seg.overlaps(start, end) {
+            return Ok(());
+        }
+        let collation = seg.collation;
+        let in_range = |key: &[u8]| collation.compare(key, start.as_bytes()).is_ge() && collation.compare(key, end.as_bytes()).is_lt();
+        
+        // Find starting position in index
+        let start_idx = match seg.index.search(start.as_bytes(), collation) {
+            Ok(i) => i,
+            Err(i) => i.saturating_sub(1),  // Include the block before start
+        };
+        
+        // Iterate through relevant index entries
+        for idx in start_idx..seg.index.len() {
+            // Skip if we're past the end
+            if collation.compare(seg.index.key(idx), end.as_bytes()).is_ge() {
+                break;
+            }
+            CancellationToken::check(cancel)?;
+            
+            // Read the block
+            let (offset, len) = seg.block_span(idx);
+            let block_data = self.cache.get_or_load(seg, offset, len, BlockAccess::Scan)?;
+            
+            // Parse all records in block. Keys and values stay slices of the cached block
+            // until a record enters the results; one out of range, or older than the copy of
+            // its key already there, allocates nothing.
+            let mut reader = BlockReader::new(seg, &block_data, offset);
+            while let Some(record) = reader.next_record() {
+                let seq = record.seq;
+                if !in_range(record.key) {
+                    continue;
+                }
+                let tagged = match record.rec_type {
+                    RT_SET => seq,
+                    // Mark as a reference, resolved once the newest record is known
+                    RT_SET_REF => seq | (1u64 << 62),
+                    // Mark as tombstone with high bit set
+                    RT_DEL_POINT => seq | (1u64 << 63),
+                    _ => continue,
+                };
+                let value = || match record.rec_type {
+                    RT_DEL_POINT => Ok(V::from_segment("")),
+                    _ => record.value_text(seg, self.lossy_reads).map(|value| V::from_segment(&value)),
+                };
+                
+                // Only update if newer; subtombs are checked at a higher level
+                let k = record.key_text(seg, self.lossy_reads)?;
+                match results.get_mut(&(k.as_ref(), collation) as &dyn KeyView) {
+                    Some(existing) if seq > (existing.1 & !(1u64 << 63 | 1u64 << 62)) => *existing = (value()?, tagged),
+                    Some(_) => {}
+                    None => {
+                        results.insert(CollatedKey { key: k.into_owned(), collation }, (value()?, tagged));
+                    }
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    // Wildcard pattern matching - supports * (zero or more chars) and ? (single char)
+    pub fn get_pattern(&self, pattern: &str) -> Result<Vec<(String, String)>> {
+        self.pattern_scan(pattern, None)
+    }
+    
+    /// get_pattern that gives up with WalDbError::Cancelled once cancel trips.
+    /// A pattern that doesn't start with a literal prefix reads every block in the store;
+    /// one that does reads only the segments and blocks whose keys may start with it.
+    pub fn get_pattern_cancellable(&self, pattern: &str, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        self.pattern_scan(pattern, Some(cancel))
+    }
+    
+    /// get_pattern's matches grouped by entity: a key's first group_depth segments are its
+    /// group and the rest its field, so a depth of 2 groups users/1/name and users/1/age under
+    /// users/1. Groups come in the order their keys sort in, at most limit of them, each with
+    /// its fields in key order. A match with no segment below its group, at group_depth or
+    /// shallower, is left out, as search leaves it out. Reads what get_pattern reads.
+    pub fn get_pattern_grouped(&self, pattern: &str, group_depth: usize, limit: usize) -> Result<Vec<PatternGroup>> {
+        Self::group_matches(self.pattern_scan(pattern, None)?, group_depth, limit)
+    }
+    
+    fn pattern_scan(&self, pattern: &str, cancel: Option<&CancellationToken>) -> Result<Vec<(String, String)>> {
+        let inner = self.read_inner()?;
+        let mut results = BTreeMap::new();
+        
+        // Check memtable
+        for (key, value) in mem_range(&inner.shards(), Bound::Unbounded, Bound::Unbounded) {
+            let key = &key.key;
+            if Self::matches_pattern(key, pattern) {
+                match value {
+                    MemValue::Scalar(v, seq) => {
+                        if !self.covered_by_subtomb(&inner, key, *seq) {
+                            results.insert(key.clone(), (Some(v.to_string()), *seq));
+                        } else {
+                            results.insert(key.clone(), (None, *seq));
+                        }
+                    }
+                    MemValue::PointTomb(seq) => {
+                        results.insert(key.clone(), (None, *seq));
+                    }
+                }
+            }
+        }
+        
+        // Every match starts with the pattern's literal prefix, so only the segments and blocks
+        // that may hold keys under it are read. Numeric-aware order only keeps the keys under a
+        // whole path together, as collect_prefix_locked finds.
+        let literal = &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())];
+        let prefix = match inner.collation {
+            Collation::Binary => literal,
+            _ => &literal[..literal.rfind('/').map_or(0, |slash| slash + 1)],
+        };
+        let end = prefix_end(prefix);
+        let range = (!prefix.is_empty()).then_some((prefix, end.as_str()));
+        
+        // Check all segments, newest first so older copies of a key are mostly passed over
+        for segment in Self::segments_newest_first(&inner) {
+            self.collect_pattern_from_segment(&inner, segment, pattern, range, cancel, &mut results)?;
+        }
+        
+        // Filter out tombstones, and system keys as collect_range_locked does
+        let mut entries: Vec<(String, String)> = results.into_iter()
+            .filter(|(k, _)| !is_system_key(k))
+            .filter_map(|(k, (v, _))| v.map(|val| (k, val)))
+            .collect();
+        if inner.collation != Collation::Binary {
+            entries.sort_by(|(a, _), (b, _)| inner.collation.compare(a.as_bytes(), b.as_bytes()));
+        }
+        Ok(entries)
+    }
+    
+    // Reads the blocks that may hold keys in range, [start, end), or the whole segment
+    fn collect_pattern_from_segment(&self, inner: &StoreInner, seg: &Arc<Segment>, pattern: &str, range: Option<(&str, &str)>,
+                                     cancel: Option<&CancellationToken>, results: &mut BTreeMap<String, (Option<String>, u64)>) -> Result<()> {
+        let first_block = match range {
+            Some((start, end)) if !seg.overlaps(start, end) => return Ok(()),
+            Some((start, _)) => match seg.index.search(start.as_bytes(), seg.collation) {
+                Ok(i) => i,
+                Err(i) => i.saturating_sub(1),
+            },
+            None => 0,
+        };
+        for idx in first_block..seg.index.len() {
+            if range.is_some_and(|(_, end)| seg.collation.compare(seg.index.key(idx), end.as_bytes()).is_ge()) {
+                break;
+            }
+            CancellationToken::check(cancel)?;
+            
+            // Read the block
+            let (offset, len) = seg.block_span(idx);
+            let block_data = self.cache.get_or_load(seg, offset, len, BlockAccess::Scan)?;
+            
+            // Parse all records in block
+            let mut reader = BlockReader::new(seg, &block_data, offset);
+            while let Some(record) = reader.next_record() {
+                let seq = record.seq;
+                
+                // Borrowed unless lossy_reads repairs it; only owned once it is kept
+                let k = record.key_text(seg, self.lossy_reads)?;
+                
+                // Check if key matches pattern, and that nothing newer for it was found
+                let newer = || results.get(k.as_ref()).is_none_or(|(_, existing_seq)| seq > *existing_seq);
+                if Self::matches_pattern(&k, pattern) && newer() {
+                    match record.rec_type {
+                        // A covered record says nothing about the key: an archive may hold it
+                        // under a newer seq
+                        RT_SET | RT_SET_REF if self.covered_by_subtomb(inner, &k, seq) => {}
+                        RT_SET | RT_SET_REF => {
+                            let v = match record.rec_type {
+                                RT_SET_REF => self.resolve_value_ref(record.value)?,
+                                _ => record.value_text(seg, self.lossy_reads)?.into_owned(),
+                            };
+                            results.insert(k.into_owned(), (Some(v), seq));
+                        }
+                        RT_DEL_POINT => {
+                            results.insert(k.into_owned(), (None, seq));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Delete all keys matching a wildcard pattern and return how many there were. Matches
+    /// are streamed and deleted DELETE_CHUNK (10,000) at a time, each chunk one atomic batch,
+    /// so memory stays flat however many keys match. A path followed by /* or /** is a
+    /// subtree delete instead: one tombstone, which also covers keys written while the ones
+    /// it replaces are counted. Keys inside namespaces are left alone, though get_pattern
+    /// returns them.
+    pub fn delete_pattern(&self, pattern: &str) -> Result<usize> {
+        reject_delete_all(pattern, true)?;
+        self.delete_matching(pattern, true, true, None, &mut |_| {})
+    }
+    
+    /// delete_pattern that stops with WalDbError::Cancelled once cancel trips, calling
+    /// progress with the number deleted so far after each chunk. Chunks written before the
+    /// cancel stay deleted.
+    pub fn delete_pattern_cancellable(&self, pattern: &str, cancel: &CancellationToken, mut progress: impl FnMut(usize)) -> Result<usize> {
+        reject_delete_all(pattern, true)?;
+        self.delete_matching(pattern, true, true, Some(cancel), &mut progress)
+    }
+    
+    /// Delete every key starting with prefix, like delete_pattern with prefix followed by *
+    /// except that * and ? in prefix only match themselves. A prefix ending in '/' is one
+    /// subtree tombstone.
+    pub fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        reject_delete_all(prefix, false)?;
+        self.delete_matching(prefix, false, true, None, &mut |_| {})
+    }
+    
+    // Shared by delete_pattern and delete_prefix, which pass the pattern as a literal prefix.
+    // skip_reserved leaves namespace keys alone and keeps the subtree fast path off reserved
+    // prefixes; Namespace clears it for its own, prefixed, patterns.
+    fn delete_matching(&self, pattern: &str, wildcards: bool, skip_reserved: bool, cancel: Option<&CancellationToken>,
+                       progress: &mut dyn FnMut(usize)) -> Result<usize> {
+        let prefix = match wildcards {
+            true => &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())],
+            false => pattern,
+        };
+        
+        // Everything under a path: count it, then one subtree tombstone
+        let rest = &pattern[prefix.len()..];
+        let subtree = prefix.len() > 1 && prefix.ends_with('/') && (!wildcards || rest == "*" || rest == "**");
+        if subtree && (!skip_reserved || reject_reserved(prefix).is_ok()) {
+            let mut count = 0;
+            for entry in self.scan_prefix_iter(prefix)? {
+                CancellationToken::check(cancel)?;
+                entry?;
+                count += 1;
+            }
+            self.delete_subtree_unchecked(prefix)?;
+            progress(count);
+            return Ok(count);
+        }
+        
+        let mut deleted = 0;
+        let mut chunk = WriteBatch::new();
+        for entry in self.scan_prefix_iter(prefix)? {
+            CancellationToken::check(cancel)?;
+            let (key, _) = entry?;
+            if (skip_reserved && key.starts_with(NAMESPACE_MARKER)) || (wildcards && !Self::matches_pattern(&key, pattern)) {
+                continue;
+            }
+            chunk.delete(&key);
+            if chunk.len() == DELETE_CHUNK {
+                deleted += chunk.len();
+                self.write_unchecked(std::mem::take(&mut chunk))?;
+                progress(deleted);
+            }
+        }
+        if !chunk.is_empty() {
+            deleted += chunk.len();
+            self.write_unchecked(chunk)?;
+            progress(deleted);
+        }
+        Ok(deleted)
+    }
+    
+    // Helper: Check if a key matches a wildcard pattern
+    // Walks both strings char by char in place, so checking a key allocates nothing
+    fn matches_pattern(key: &str, pattern: &str) -> bool {
+        let mut key_chars = key.chars();
+        let mut pattern_chars = pattern.chars();
+        match (pattern_chars.next(), key_chars.next()) {
+            (None, None) => true,  // Both exhausted
+            (None, Some(_)) => false,  // Pattern exhausted but key has more
+            (Some('*'), first) => {
+                // * matches zero or more characters
+                // Try matching with 0 chars (skip *)
+                if Self::matches_pattern(key, pattern_chars.as_str()) {
+                    return true;
+                }
+                // Try matching with 1+ chars (consume one char and keep *)
+                if first.is_some() && Self::matches_pattern(key_chars.as_str(), pattern) {
+                    return true;
+                }
+                false
+            }
+            (Some('?'), None) => false,  // ? needs exactly one char but key is empty
+            (Some('?'), Some(_)) => {
+                // ? matches exactly one character
+                Self::matches_pattern(key_chars.as_str(), pattern_chars.as_str())
+            }
+            (Some(_), None) => false,  // Pattern has more but key is exhausted
+            (Some(p), Some(k)) => {
+                // Regular character must match exactly
+                p == k && Self::matches_pattern(key_chars.as_str(), pattern_chars.as_str())
+            }
+        }
+    }
+    
+    /// Delete everything under prefix with one subtree tombstone. An empty prefix, which
+    /// would take the whole store, fails with InvalidInput: that is clear_all.
+    pub fn delete_subtree(&self, prefix: &str) -> Result<u64> {
+        reject_reserved(prefix)?;
+        reject_delete_all(prefix, false)?;
+        self.delete_subtree_unchecked(prefix)
+    }
+    
+    fn delete_subtree_unchecked(&self, prefix: &str) -> Result<u64> {
+        let mut inner = self.write_lock()?;
+        let prefix = if prefix.ends_with('/') {
+            prefix.to_string()
+        } else {
+            format!("{}/", prefix)
+        };
+        inner.check_unsealed(&prefix, true)?;
+        let charge = self.charge_quotas(&inner, [QuotaOp::Clear(&prefix)])?;
+        let seq = inner.next_seq();
+        
+        self.wal.append(&WALEntry {
+            seq,
+            kind: RT_DEL_SUB,
+            key: &prefix,
+            value: None,
+        })?;
+        
+        self.vectors_locked()?.remove_prefix(&prefix, seq)?;
+        inner.subtomb_insert(prefix, seq);
+        charge.commit();
+        
+        self.maybe_flush(inner)?;
+        Ok(seq)
+    }
+    
+    /// Delete every key in the store, namespaces, vectors and the store's own keys included,
+    /// and return the seq of the one subtree tombstone it takes. The memtable is emptied on
+    /// the spot and the next compaction pass drops each segment the tombstone covers whole,
+    /// without reading it. Writes made afterwards have newer seqs, so the tombstone never
+    /// hides them. Prefix write stats start over, and quotas go with the keys holding them.
+    /// Fails with WalDbError::Sealed while any prefix is sealed.
+    pub fn clear_all(&self) -> Result<u64> {
+        let mut inner = self.write_lock()?;
+        inner.check_unsealed("", true)?;
+        let seq = inner.next_seq();
+        
+        self.wal.append(&WALEntry {
+            seq,
+            kind: RT_DEL_SUB,
+            key: "",
+            value: None,
+        })?;
+        
+        // Every other subtree tombstone is older, so this one stands in for them all
+        self.vectors_locked()?.remove_prefix("", seq)?;
+        inner.memtable_clear();
+        inner.subtombs.clear();
+        inner.subtomb_size = 0;
+        inner.subtomb_insert(String::new(), seq);
+        self.reset_prefix_write_stats();
+        self.quotas.table().clear();
+        self.quotas.any.store(false, Ordering::SeqCst);
+        
+        self.maybe_flush(inner)?;
+        Ok(seq)
+    }
+    
+    /// Copy everything live under prefix into archive segments and make the prefix read-only.
+    /// Archives sit below L2: reads consult them last and compaction never rewrites them. A
+    /// subtree tombstone hides the copies left in L0-L2, so compaction drops those, while
+    /// the archived records take a seq just past it. Until unseal_prefix, writes under the
+    /// prefix and subtree writes covering it fail with WalDbError::Sealed. Holds the write
+    /// lock while it copies, so everything else waits for it.
+    pub fn seal_prefix(&self, prefix: &str) -> Result<SealReport> {
+        reject_reserved(prefix)?;
+        let prefix = sealed_prefix(prefix)?;
+        
+        let _admin = self.admin_lock()?;
+        let mut inner = self.write_lock()?;
+        inner.check_unsealed(&prefix, true)?;
+        
+        // Every earlier write goes to a segment first: WAL replay skips seqs up to the newest
+        // a segment holds, and that will be the archive's. Listed ahead of the archives.
+        self.flush_memtable_locked(&mut inner)?;
+        self.list_flushed()?;
+        let results = self.collect_prefix_locked(&inner, &prefix, None)?;
+        let entries = self.live_entries(&inner, results, usize::MAX);
+        
+        let tomb_seq = inner.seq() + 1;
+        let archive_seq = tomb_seq + 1;
+        let mut report = SealReport { prefix: prefix.clone(), keys: entries.len(), ..Default::default() };
+        let archives = match self.write_archives(&entries, archive_seq, inner.collation, &mut report) {
+            Ok(archives) => archives,
+            Err(e) => {
+                for filename in &report.segments {
+                    let _ = self.segment_registry.unlink(&self.segment_dirs.resolve(filename));
+                }
+                return Err(e);
+            }
+        };
+        
+        // The manifest lists the archive before the WAL holds the tombstone, so a crash in
+        // between leaves duplicates behind rather than losing the data
+        {
+            let mut manifest = self.manifest_locked()?;
+            for (filename, archive) in report.segments.iter().zip(&archives) {
+                manifest.append(ManifestEntry {
+                    seq_high: archive_seq,
+                    level: ARCHIVE_LEVEL,
+                    filename: filename.clone(),
+                    archive_of: Some(prefix.clone()),
+                    inputs: Vec::new(),
+                    bounds: archive.bounds.clone(),
+                })?;
+            }
+            manifest.set_sealed(&prefix, true)?;
+        }
+        
+        self.wal.append(&WALEntry {
+            seq: tomb_seq,
+            kind: RT_DEL_SUB,
+            key: &prefix,
+            value: None,
+        })?;
+        inner.subtomb_add(prefix.clone(), tomb_seq);
+        inner.set_seq(archive_seq);
+        
+        // Archives of this prefix or ones under it hold nothing the new one doesn't
+        let (retired, kept) = std::mem::take(&mut inner.segments_archive).into_iter()
+            .partition(|seg| seg.archive_of.as_ref().is_some_and(|other| other.starts_with(prefix.as_str())));
+        inner.segments_archive = kept;
+        inner.segments_archive.extend(archives.into_iter().map(|seg| Arc::new(Segment { archive_of: Some(prefix.clone()), ..seg })));
+        inner.sealed.push(prefix);
+        drop(inner);
+        
+        self.retire_segments(retired)?;
+        Ok(report)
+    }
+    
+    // Write entries to archive files of about ARCHIVE_SEGMENT_BYTES each, naming each in the
+    // report as soon as it exists so a failure can clean up
+    fn write_archives(&self, entries: &[(String, String)], seq: u64, collation: Collation, report: &mut SealReport) -> Result<Vec<Segment>> {
+        let mut archives = Vec::new();
+        let mut writer: Option<SegmentWriter> = None;
+        for (key, value) in entries {
+            let current = match &mut writer {
+                Some(current) => current,
+                None => {
+                    let (path, filename) = self.new_segment_file(ARCHIVE_LEVEL)?;
+                    let current = self.segment_writer(&path, collation)?;
+                    report.segments.push(filename);
+                    writer.insert(current)
+                }
+            };
+            self.add_set(current, key, value, seq)?;
+            if current.written >= ARCHIVE_SEGMENT_BYTES.min(self.max_segment_size) {
+                archives.push(writer.take().expect("Writer was just used").finish(self.next_segment_id.fetch_add(1, Ordering::Relaxed))?);
+            }
+        }
+        if let Some(current) = writer {
+            archives.push(current.finish(self.next_segment_id.fetch_add(1, Ordering::Relaxed))?);
+        }
+        self.values_locked()?.sync()?;
+        
+        for seg in &archives {
+            report.bytes += seg.file_len()?;
+        }
+        Ok(archives)
+    }
+    
+    /// Let writes under a sealed prefix through again. The archive stays and keeps serving
+    /// reads, with newer writes shadowing it as usual. False if prefix wasn't sealed.
+    pub fn unseal_prefix(&self, prefix: &str) -> Result<bool> {
+        let prefix = sealed_prefix(prefix)?;
+        let mut inner = self.write_lock()?;
+        if !inner.sealed.contains(&prefix) {
+            return Ok(false);
+        }
+        
+        self.manifest_locked()?
+            .set_sealed(&prefix, false)?;
+        inner.sealed.retain(|sealed| *sealed != prefix);
+        Ok(true)
+    }
+    
+    /// Take a segment out of the store, to repair an overlap open reported in
+    /// `OpenReport::overlapping_segments`. Records only it holds are lost. filename is as the
+    /// manifest records it, with or without its data directory. False if no segment has it.
+    pub fn drop_segment(&self, filename: &str) -> Result<bool> {
+        let name = filename.rsplit('/').next().unwrap_or(filename);
+        // Not while a compaction might be merging it
+        let _compacting = self.compacting_lock()?;
+        let _admin = self.admin_lock()?;
+        let mut inner = self.write_lock()?;
+        let inner = &mut *inner;
+        let named = |seg: &Arc<Segment>| seg.path.file_name().and_then(|name| name.to_str()) == Some(name);
+        let found = [&mut inner.segments_l0, &mut inner.segments_l1, &mut inner.segments_l2, &mut inner.segments_archive]
+            .into_iter()
+            .find_map(|level| level.iter().position(named).map(|i| (level, i)));
+        let Some((level, i)) = found else {
+            return Ok(false);
+        };
+        
+        // Whatever the segment held can't be read as of any seq it covers any more. A flush
+        // not yet listed would otherwise be listed after its drop.
+        self.list_flushed()?;
+        let mut manifest = self.manifest_locked()?;
+        manifest.raise_floor(level[i].seq_high)?;
+        manifest.drop_segment(name)?;
+        drop(manifest);
+        let seg = level.remove(i);
+        self.retire_segments(vec![seg])?;
+        Ok(true)
+    }
+    
+    /// Prefixes sealed by seal_prefix, each with its trailing '/'
+    pub fn sealed_prefixes(&self) -> Vec<String> {
+        let inner = self.read_inner().expect("Lock should not be poisoned in sealed_prefixes");
+        inner.sealed.clone()
+    }
+    
+    // ==================== PATH SEGMENTS ====================
+    
+    /// set_with_options at path_key(segments), so each segment is one level whatever it holds
+    pub fn set_path(&self, segments: &[&str], value: &str, options: SetOptions) -> Result<u64> {
+        self.set_with_options(&path_key(segments)?, value, options)
+    }
+    
+    /// get at path_key(segments)
+    pub fn get_path(&self, segments: &[&str]) -> Result<Option<String>> {
+        self.get(&path_key(segments)?)
+    }
+    
+    /// delete at path_key(segments)
+    pub fn delete_path(&self, segments: &[&str]) -> Result<u64> {
+        self.delete(&path_key(segments)?)
+    }
+    
+    /// scan_prefix of the keys under path_key(segments), as stored. No segments scans the
+    /// whole store.
+    pub fn scan_path_prefix(&self, segments: &[&str], limit: usize) -> Result<Vec<(String, String)>> {
+        let prefix = match segments {
+            [] => String::new(),
+            segments => format!("{}/", path_key(segments)?),
+        };
+        self.scan_prefix(&prefix, limit)
+    }
+    
+    /// scan_path_prefix with each key split back into its segments by path_segments
+    pub fn scan_path_prefix_segments(&self, segments: &[&str], limit: usize) -> Result<Vec<(Vec<String>, String)>> {
+        Ok(self.scan_path_prefix(segments, limit)?.into_iter()
+            .map(|(key, value)| (path_segments(&key), value))
+            .collect())
+    }
+    
+    // ==================== QUEUES ====================
+    
+    /// Add value to the queue under prefix, at a new key past every key an earlier push gave
+    /// it, and return that key: prefix, '/', and a seq-derived number zero-padded to 20 digits.
+    /// The number is the store's seq when the push takes its lock, plus one, and seqs never go
+    /// back, reopened or not, so keys keep increasing across restarts. Validators run first,
+    /// outside any lock, so they see the key the push would get if no other write lands first.
+    pub fn push(&self, prefix: &str, value: &str) -> Result<String> {
+        reject_reserved(prefix)?;
+        self.push_unchecked(prefix, value)
+    }
+    
+    fn push_unchecked(&self, prefix: &str, value: &str) -> Result<String> {
+        let prefix = queue_prefix(prefix)?;
+        if self.validators.any.load(Ordering::SeqCst) {
+            self.run_validators([(queue_key(&prefix, self.seq()? + 1).as_str(), value)])?;
+        }
+        
+        // Every key under prefix shares its shard, so pushes to it take turns here. Each
+        // record's seq is past the number its key was given, so the next push's is larger.
+        let lock = self.point_lock(&prefix)?;
+        let key = queue_key(&prefix, lock.seq() + 1);
+        lock.check_unsealed(&key, false)?;
+        let parent = &prefix[..prefix.len() - 1];
+        if self.contains_locked(&lock, &lock.shard(parent), parent)? {
+            return Err(WalDbError::TreeSemantics { path: key, ancestor: None });
+        }
+        
+        let bytes = key.len() + value.len();
+        let charge = self.charge_quotas(&lock, [QuotaOp::Put(&key, value.len())])?;
+        let mut inner = match lock {
+            PointLock::Store(inner) => inner,
+            PointLock::Shard(inner, writer) => {
+                self.shard_commit(inner, writer, vec![(key.clone(), Some(CompactStr::from(value)))], charge)?;
+                self.account(&key, bytes);
+                return Ok(key);
+            }
+        };
+        let seq = inner.next_seq();
+        self.wal.append(&WALEntry {
+            seq,
+            kind: RT_SET,
+            key: &key,
+            value: Some(value),
+        })?;
+        
+        self.vectors_locked()?.apply(&key, Some(value), seq)?;
+        inner.memtable_insert(key.clone(), MemValue::Scalar(CompactStr::from(value), seq));
+        charge.commit();
+        
+        self.maybe_flush(inner)?;
+        self.account(&key, bytes);
+        Ok(key)
+    }
+    
+    /// Remove and return the entry with the smallest live key under prefix, None if there is
+    /// none. Finding it and deleting it happen under one lock, so of any number of callers
+    /// popping at once each gets a different entry. Works on any keys, not only push's.
+    pub fn pop_min(&self, prefix: &str) -> Result<Option<(String, String)>> {
+        reject_reserved(prefix)?;
+        self.pop_min_unchecked(prefix)
+    }
+    
+    fn pop_min_unchecked(&self, prefix: &str) -> Result<Option<(String, String)>> {
+        let prefix = queue_prefix(prefix)?;
+        let lock = self.point_lock(&prefix)?;
+        let Some((key, value)) = self.queue_first(&lock, &prefix)? else { return Ok(None) };
+        lock.check_unsealed(&key, false)?;
+        let charge = self.charge_quotas(&lock, [QuotaOp::Delete(&key)])?;
+        // Nothing before key is live, whether or not the delete goes through
+        lock.queue_heads.advance(&prefix, &key);
+        
+        let mut inner = match lock {
+            PointLock::Store(inner) => inner,
+            PointLock::Shard(inner, writer) => {
+                self.shard_commit(inner, writer, vec![(key.clone(), None)], charge)?;
+                self.account(&key, key.len());
+                return Ok(Some((key, value)));
+            }
+        };
+        let seq = inner.next_seq();
+        self.wal.append(&WALEntry {
+            seq,
+            kind: RT_DEL_POINT,
+            key: &key,
+            value: None,
+        })?;
+        
+        inner.memtable_insert(key.clone(), MemValue::PointTomb(seq));
+        self.vectors_locked()?.apply(&key, None, seq)?;
+        charge.commit();
+        
+        self.maybe_flush(inner)?;
+        self.account(&key, key.len());
+        Ok(Some((key, value)))
+    }
+    
+    /// The entry pop_min would return, left in place
+    pub fn peek_min(&self, prefix: &str) -> Result<Option<(String, String)>> {
+        let prefix = queue_prefix(prefix)?;
+        let inner = self.read_inner()?;
+        self.queue_first(&inner, &prefix)
+    }
+    
+    /// How many live keys are under prefix, counting no further than limit
+    pub fn queue_len(&self, prefix: &str, limit: usize) -> Result<usize> {
+        let prefix = queue_prefix(prefix)?;
+        let inner = self.read_inner()?;
+        let from = inner.queue_heads.head(&prefix).unwrap_or_else(|| prefix.clone());
+        let mut len = 0;
+        for entry in self.range_iter_locked(&inner, &from, &prefix_end(&prefix), None)?.take(limit) {
+            entry?;
+            len += 1;
+        }
+        Ok(len)
+    }
+    
+    // The first live entry under prefix, from its queue head on. The memtable is read only up
+    // to its first live value there, as no key past that can come first; the records before
+    // it are deletes, which may hide segment keys.
+    fn queue_first(&self, inner: &StoreInner, prefix: &str) -> Result<Option<(String, String)>> {
+        let from = inner.queue_heads.head(prefix).unwrap_or_else(|| prefix.to_string());
+        let end = prefix_end(prefix);
+        let shard = inner.shard(prefix);
+        let (low, high) = ((from.as_str(), inner.collation), (end.as_str(), inner.collation));
+        let mut memtable = Vec::new();
+        for (key, value) in shard.entries.range::<dyn KeyView, _>((Bound::Included(&low as &dyn KeyView), Bound::Excluded(&high as &dyn KeyView))) {
+            memtable.push((key, value));
+            if matches!(value, MemValue::Scalar(_, seq) if !self.covered_by_subtomb(inner, &key.key, *seq)) {
+                break;
+            }
+        }
+        
+        let segments = inner.segments_l0.iter()
+            .chain(inner.segments_l1.iter())
+            .chain(inner.segments_l2.iter())
+            .chain(inner.segments_archive.iter());
+        let mut iter = self.range_iter_over(memtable, segments, inner.subtombs.clone(), inner.seq(), (&from, &end), None)?;
+        iter.next().transpose()
+    }
+    
+    // ==================== QUOTAS ====================
+    
+    /// Cap the key and value bytes live under prefix at max_bytes. A set, increment, set_many
+    /// or batch that would take them past it fails with WalDbError::QuotaExceeded before
+    /// anything reaches the WAL; deletes always go through, and a cap below what the prefix
+    /// holds only stops it growing. Quotas nest, and a write has to fit under every one over
+    /// it. The cap is kept in a system key, so it survives a reopen. Usage is counted with
+    /// prefix_size_exact by the first write under the prefix after open, and kept up to date
+    /// by each write from then on; a subtree delete inside the prefix reads what it removes.
+    pub fn set_quota(&self, prefix: &str, max_bytes: u64) -> Result<()> {
+        reject_reserved(prefix)?;
+        let prefix = quota_prefix(prefix)?;
+        self.system_set(&quota_key(&prefix), &max_bytes.to_string())?;
+        
+        let mut table = self.quotas.table();
+        table.entry(prefix)
+            .and_modify(|quota| quota.max_bytes = max_bytes)
+            .or_insert(Quota { max_bytes, used: None });
+        self.quotas.any.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+    
+    /// Take prefix's quota away. False if it had none.
+    pub fn remove_quota(&self, prefix: &str) -> Result<bool> {
+        let prefix = quota_prefix(prefix)?;
+        if !self.quotas.table().contains_key(&prefix) {
+            return Ok(false);
+        }
+        self.system_delete(&quota_key(&prefix))?;
+        
+        let mut table = self.quotas.table();
+        let removed = table.remove(&prefix).is_some();
+        self.quotas.any.store(!table.is_empty(), Ordering::SeqCst);
+        Ok(removed)
+    }
+    
+    /// prefix's quota and what it holds, counted now if no write under it has been since
+    /// open. None if prefix has no quota.
+    pub fn quota_usage(&self, prefix: &str) -> Result<Option<QuotaUsage>> {
+        let prefix = quota_prefix(prefix)?;
+        let inner = self.read_inner()?;
+        let mut table = self.quotas.table();
+        let Some(quota) = table.get_mut(&prefix) else {
+            return Ok(None);
+        };
+        let used_bytes = self.quota_used(&inner, &prefix, quota)?;
+        Ok(Some(QuotaUsage { used_bytes, max_bytes: quota.max_bytes }))
+    }
+    
+    /// The bytes live under prefix: the length of each key, as the full path, plus that of its
+    /// value. What quotas count, so compaction and flushes don't change it. Reads every entry.
+    pub fn prefix_size_exact(&self, prefix: &str) -> Result<u64> {
+        let mut bytes = 0;
+        for entry in self.scan_prefix_iter(prefix)? {
+            let (key, value) = entry?;
+            bytes += (key.len() + value.len()) as u64;
+        }
+        Ok(bytes)
+    }
+    
+    // Read the caps set_quota left in system keys
+    fn load_quotas(&self) -> Result<()> {
+        let start = format!("{}{}", SYSTEM_PREFIX, QUOTA_KEYS);
+        let mut iter = self.range_iter_locked(&*self.read_inner()?, &start, &prefix_end(&start), None)?;
+        iter.hide_system = false;
+        
+        let mut table = self.quotas.table();
+        for entry in iter {
+            let (key, value) = entry?;
+            let Ok(max_bytes) = value.parse() else { continue };
+            table.insert(percent_unescape(&key[start.len()..]), Quota { max_bytes, used: None });
+        }
+        self.quotas.any.store(!table.is_empty(), Ordering::SeqCst);
+        Ok(())
+    }
+    
+    // What quota holds, counted from the store if no write has since open or replicated changes
+    fn quota_used(&self, inner: &StoreInner, prefix: &str, quota: &mut Quota) -> Result<u64> {
+        if let Some(used) = quota.used {
+            return Ok(used);
+        }
+        let used = self.live_bytes(inner, prefix)?;
+        quota.used = Some(used);
+        Ok(used)
+    }
+    
+    // prefix_size_exact under a lock the caller holds
+    fn live_bytes(&self, inner: &StoreInner, prefix: &str) -> Result<u64> {
+        let mut bytes = 0;
+        for entry in self.range_iter_locked(inner, prefix, &prefix_end(prefix), None)? {
+            let (key, value) = entry?;
+            bytes += (key.len() + value.len()) as u64;
+        }
+        Ok(bytes)
+    }
+    
+    // Take what ops add under each quota from it before the write reaches the WAL, or fail with
+    // QuotaExceeded if one hasn't room. Only keys under a quota have their current value read,
+    // and only subtree deletes inside one are read for what they remove, so with no quotas set
+    // this costs one atomic load.
+    fn charge_quotas<'o>(&self, inner: &StoreInner, ops: impl IntoIterator<Item = QuotaOp<'o>>) -> Result<QuotaCharge<'_>> {
+        let mut charge = QuotaCharge { quotas: &self.quotas, charged: Vec::new() };
+        if !self.quotas.any.load(Ordering::SeqCst) {
+            return Ok(charge);
+        }
+        let mut table = self.quotas.table();
+        
+        // Each key under a quota with its bytes once the write applies, in the order first
+        // written, and the prefixes the write clears
+        let mut after: HashMap<&str, u64> = HashMap::new();
+        let mut order: Vec<&str> = Vec::new();
+        let mut cleared: Vec<&str> = Vec::new();
+        let mut affected: BTreeSet<String> = BTreeSet::new();
+        for op in ops {
+            let (key, bytes) = match op {
+                QuotaOp::Clear(prefix) => {
+                    for (_, bytes) in after.iter_mut().filter(|(key, _)| key.starts_with(prefix)) {
+                        *bytes = 0;
+                    }
+                    affected.extend(quotas_over(&table, prefix).map(str::to_string));
+                    affected.extend(table.range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+                        .take_while(|(quota, _)| quota.starts_with(prefix))
+                        .map(|(quota, _)| quota.clone()));
+                    cleared.push(prefix);
+                    continue;
+                }
+                QuotaOp::Put(key, len) => (key, (key.len() + len) as u64),
+                QuotaOp::Delete(key) => (key, 0),
+            };
+            if quotas_over(&table, key).next().is_none() {
+                continue;
+            }
+            affected.extend(quotas_over(&table, key).map(str::to_string));
+            if after.insert(key, bytes).is_none() {
+                order.push(key);
+            }
+        }
+        
+        // Each quota's bytes after the write: from nothing if it was cleared whole, otherwise
+        // from what it held less the subtrees cleared inside it, then each key's change
+        let under_clear = |key: &str| cleared.iter().any(|prefix| key.starts_with(prefix));
+        let mut stored: HashMap<&str, u64> = HashMap::new();
+        let mut totals = Vec::new();
+        for prefix in &affected {
+            let quota = table.get_mut(prefix.as_str()).expect("Affected quotas are in the table");
+            let wiped = under_clear(prefix);
+            let mut total = match wiped {
+                true => 0,
+                false => self.quota_used(inner, prefix, quota)? as i64,
+            };
+            if !wiped {
+                let inside: Vec<&str> = cleared.iter().copied()
+                    .filter(|cleared| cleared.len() > prefix.len() && cleared.starts_with(prefix.as_str()))
+                    .collect();
+                for cleared in inside.iter().filter(|cleared| !inside.iter().any(|outer| outer.len() < cleared.len() && cleared.starts_with(outer))) {
+                    total -= self.live_bytes(inner, cleared)? as i64;
+                }
+            }
+            for &key in order.iter().filter(|key| key.starts_with(prefix.as_str())) {
+                total += after[key] as i64;
+                if !under_clear(key) {
+                    let before = match stored.get(key) {
+                        Some(&before) => before,
+                        None => *stored.entry(key).or_insert(self.get_locked(inner, key)?.map_or(0, |value| (key.len() + value.len()) as u64)),
+                    };
+                    total -= before as i64;
+                }
+            }
+            let total = total.max(0) as u64;
+            if total > quota.max_bytes && quota.used.is_none_or(|used| total > used) {
+                let path = order.iter().find(|key| key.starts_with(prefix.as_str())).copied().unwrap_or(prefix);
+                return Err(WalDbError::QuotaExceeded {
+                    path: path.to_string(),
+                    prefix: prefix.clone(),
+                    used: quota.used.unwrap_or(0),
+                    max_bytes: quota.max_bytes,
+                });
+            }
+            totals.push((prefix, total));
+        }
+        
+        for (prefix, total) in totals {
+            let quota = table.get_mut(prefix.as_str()).expect("Affected quotas are in the table");
+            let change = quota.used.map(|used| total as i64 - used as i64);
+            quota.used = Some(total);
+            charge.charged.push((prefix.clone(), change));
+        }
+        Ok(charge)
+    }
+    
+    // ==================== VALIDATORS ====================
+    
+    /// Check every value set under prefix with validator before the write takes a lock. A
+    /// set, set_many or batch with a key under it whose value the validator refuses fails
+    /// whole with WalDbError::ValidationFailed, and none of it reaches the WAL. Prefixes
+    /// nest: a key is checked by the validator of each prefix over it, most specific first,
+    /// and has to pass all of them. Registering again replaces prefix's validator. Validators
+    /// live only in this process and see only the values written through it: reads,
+    /// deletes, increments and apply_replicated aren't checked, nor are values already held.
+    pub fn register_validator(&self, prefix: &str, validator: ValueValidator) -> Result<()> {
+        reject_reserved(prefix)?;
+        let prefix = validator_prefix(prefix)?;
+        self.validators.table().insert(prefix, Arc::from(validator));
+        self.validators.any.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+    
+    /// Take prefix's validator away. False if it had none.
+    pub fn remove_validator(&self, prefix: &str) -> Result<bool> {
+        let prefix = validator_prefix(prefix)?;
+        let mut table = self.validators.table();
+        let removed = table.remove(&prefix).is_some();
+        self.validators.any.store(!table.is_empty(), Ordering::SeqCst);
+        Ok(removed)
+    }
+    
+    /// Prefixes with a validator, each with its trailing '/'
+    pub fn validated_prefixes(&self) -> Vec<String> {
+        self.validators.table().keys().cloned().collect()
+    }
+    
+    // Run each key's validators over its value, most specific prefix first. They are copied
+    // out of the table before any runs, so a validator may use the store.
+    fn run_validators<'e>(&self, entries: impl IntoIterator<Item = (&'e str, &'e str)>) -> Result<()> {
+        if !self.validators.any.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        for (key, value) in entries {
+            let checks: Vec<_> = {
+                let table = self.validators.table();
+                key.rmatch_indices('/').filter_map(|(i, _)| table.get(&key[..=i]).cloned()).collect()
+            };
+            for check in checks {
+                check(key, value).map_err(|reason| WalDbError::ValidationFailed { key: key.to_string(), reason })?;
+            }
+        }
+        Ok(())
+    }
+    
+    // ==================== REBUILD ====================
+    
+    /// Copy the live data into a new store at dest, compacted into one level-2 segment, and
+    /// open it. Records keep their seqs and the copy carries on from this store's, so seqs
+    /// handed out here mean the same there. Sealed prefixes stay sealed and the blobs the data
+    /// names are copied; dest must be missing or empty. Compaction waits until it returns.
+    /// Once the copy is returned this store refuses writes in RejectWrites mode; in CatchUp
+    /// mode it doesn't until the caller switches over (StoreHandle::rebuild does that).
+    pub fn rebuild_into(&self, dest: &Path, options: RebuildOptions) -> Result<Store> {
+        if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not empty", dest.display())).into());
+        }
+        
+        // Taking the lock lets the writes under way finish first
+        let reject = options.mode == RebuildMode::RejectWrites;
+        if reject {
+            let _inner = self.write_lock()?;
+            self.read_only.store(true, Ordering::SeqCst);
+        }
+        let rebuilt = self.copy_into(dest, options);
+        if reject && rebuilt.is_err() {
+            self.read_only.store(false, Ordering::SeqCst);
+        }
+        rebuilt
+    }
+    
+    fn copy_into(&self, dest: &Path, options: RebuildOptions) -> Result<Store> {
+        // An L2 merge drops point deletes, which the catch-up has to see
+        let _compacting = self.compacting_lock()?;
+        let (mut iter, sealed) = {
+            let inner = self.read_inner()?;
+            let mut iter = self.range_iter_locked(&inner, "", &prefix_end(""), None)?;
+            iter.hide_system = false;
+            (iter, inner.sealed.clone())
+        };
+        
+        let store_options = StoreOptions { collation: self.collation, ..options.store };
+        fs::create_dir_all(dest)?;
+        for data_dir in &store_options.data_dirs {
+            fs::create_dir_all(data_dir)?;
+        }
+        let segment_dirs = SegmentDirs::new(dest, &store_options.data_dirs, store_options.placement)?;
+        let mut manifest = Manifest::load(&dest.join("manifest.log"))?;
+        // Open takes the copy's seq from its segments, so each footer claims the snapshot's
+        let snapshot_seq = iter.seq;
+        let finish = |mut writer: SegmentWriter, filename: String| -> Result<ManifestEntry> {
+            writer.seq_high = snapshot_seq;
+            let seg = writer.finish(0)?;
+            Ok(ManifestEntry { seq_high: snapshot_seq, level: 2, filename, archive_of: None, inputs: Vec::new(), bounds: seg.bounds })
+        };
+        let cut = segment_cut(2, store_options.max_segment_size.unwrap_or(MAX_SEGMENT_BYTES), &store_options.compaction);
+        let mut entries = Vec::new();
+        let (path, mut filename) = segment_dirs.place(2, manifest.new_file_name()?)?;
+        let mut writer = SegmentWriter::new(&path, self.collation)?;
+        while let Some((key, value, seq)) = iter.advance()? {
+            if writer.written >= cut {
+                let (path, next_name) = segment_dirs.place(2, manifest.new_file_name()?)?;
+                let full = std::mem::replace(&mut writer, SegmentWriter::new(&path, self.collation)?);
+                entries.push(finish(full, std::mem::replace(&mut filename, next_name))?);
+            }
+            self.copy_blob(dest, &value)?;
+            writer.add(RT_SET, &key, Some(&value), seq)?;
+        }
+        
+        // With nothing live there is nothing to write, and nothing to keep seqs apart from.
+        // Only the newest versions come along, so the copy has no history before it.
+        manifest.raise_floor(snapshot_seq)?;
+        if writer.key_count > 0 {
+            entries.push(finish(writer, filename)?);
+        }
+        if !entries.is_empty() {
+            manifest.add_entries(entries)?;
+        }
+        for prefix in &sealed {
+            manifest.set_sealed(prefix, true)?;
+        }
+        drop(manifest);
+        drop(iter);
+        
+        let (store, _) = Store::open_with_options(dest, store_options)?;
+        if options.mode == RebuildMode::CatchUp {
+            store.catch_up_from(self)?;
+        }
+        Ok(store)
+    }
+    
+    /// Apply what was written to source after this store's seq, keeping each write's seq:
+    /// how a copy rebuild_into made in CatchUp mode takes the writes made since. Returns how
+    /// many records it applied. Reads every segment of source holding newer records. Only
+    /// each key's newest record comes across, so the history floor moves up to source's seq.
+    pub fn catch_up_from(&self, source: &Store) -> Result<usize> {
+        let since = self.read_inner()?.seq();
+        let (cut, changes) = source.changes_since(since)?;
+        if !changes.is_empty() {
+            self.raise_history_floor(cut)?;
+        }
+        for change in &changes {
+            if let RawState::Live(value) = &change.state {
+                self.take_blob(source, value)?;
+            }
+        }
+        
+        let mut inner = self.write_lock()?;
+        self.apply_changes(&mut inner, &changes)?;
+        inner.seq.fetch_max(cut, Ordering::SeqCst);
+        self.maybe_flush(inner)?;
+        Ok(changes.len())
+    }
+    
+    /// Apply changes from a primary's changes_since, keeping their seqs, so that newest-wins
+    /// and changes_since cursors agree between the two. Changes at or below this store's seq
+    /// were applied before and are skipped, so after a reconnect the replica can ask again
+    /// from its seq, or replay a batch it isn't sure landed. The rest must come in seq
+    /// order. Returns how many it applied. Blobs set_file stored aren't carried: a replica
+    /// reading one needs the primary's blobs directory copied across.
+    pub fn apply_replicated(&self, changes: Vec<RawEntry>) -> Result<usize> {
+        let since = self.seq()?;
+        let changes: Vec<RawEntry> = changes.into_iter().filter(|change| change.seq > since).collect();
+        if let Some(pair) = changes.windows(2).find(|pair| pair[1].seq < pair[0].seq) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Replicated seq {} arrived after {}", pair[1].seq, pair[0].seq)).into());
+        }
+        let Some(last) = changes.last().map(|change| change.seq) else { return Ok(0) };
+        
+        // Only each key's newest record comes across, as with catch_up_from
+        self.raise_history_floor(last)?;
+        let mut inner = self.write_inner()?;
+        self.check_unfrozen()?;
+        // Another apply got in first
+        let changes: Vec<&RawEntry> = changes.iter().filter(|change| change.seq > inner.seq()).collect();
+        self.apply_changes(&mut inner, changes.iter().copied())?;
+        self.maybe_flush(inner)?;
+        Ok(changes.len())
+    }
+    
+    // Log and apply changes under their own seqs, in seq order, moving the store's seq up
+    // to each as it goes
+    fn apply_changes<'a>(&self, inner: &mut InnerWrite<'_>, changes: impl IntoIterator<Item = &'a RawEntry>) -> Result<()> {
+        for change in changes {
+            let (kind, value) = match &change.state {
+                RawState::Live(value) => (RT_SET, Some(value.as_str())),
+                RawState::PointDeleted => (RT_DEL_POINT, None),
+                RawState::SubtreeDeleted(_) => (RT_DEL_SUB, None),
+            };
+            self.wal.append(&WALEntry { seq: change.seq, kind, key: &change.key, value })?;
+            match &change.state {
+                RawState::Live(value) => {
+                    inner.memtable_insert(change.key.clone(), MemValue::Scalar(CompactStr::from(value.as_str()), change.seq));
+                    self.vectors_locked()?.apply(&change.key, Some(value), change.seq)?;
+                }
+                RawState::PointDeleted => {
+                    inner.memtable_insert(change.key.clone(), MemValue::PointTomb(change.seq));
+                    self.vectors_locked()?.apply(&change.key, None, change.seq)?;
+                }
+                RawState::SubtreeDeleted(prefix) => {
+                    self.vectors_locked()?.remove_prefix(prefix, change.seq)?;
+                    inner.subtomb_insert(prefix.clone(), change.seq);
+                }
+            }
+            inner.set_seq(change.seq);
+            self.maybe_flush_locked(inner)?;
+        }
+        // Changes from elsewhere aren't held to quotas, but what they hold is counted again
+        self.quotas.recount_all();
+        Ok(())
+    }
+    
+    /// The newest record of each key written after since, tombstones included, and the
+    /// subtree tombstones since, in seq order, with the seq they are complete up to: what a
+    /// replica passes to apply_replicated. Reads every segment holding newer records.
+    pub fn changes_since(&self, since: u64) -> Result<(u64, Vec<RawEntry>)> {
+        let inner = self.read_inner()?;
+        let shards = inner.shards();
+        let cut = inner.seq();
+        let mut newest: HashMap<String, RawEntry> = HashMap::new();
+        let mut keep = |key: String, seq: u64, state: RawState| {
+            if newest.get(&key).is_none_or(|entry| seq > entry.seq) {
+                newest.insert(key.clone(), RawEntry { key, seq, state });
+            }
+        };
+        
+        for (key, value) in mem_range(&shards, Bound::Unbounded, Bound::Unbounded) {
+            match value {
+                MemValue::Scalar(value, seq) if *seq > since => keep(key.key.clone(), *seq, RawState::Live(value.to_string())),
+                MemValue::PointTomb(seq) if *seq > since => keep(key.key.clone(), *seq, RawState::PointDeleted),
+                _ => {}
+            }
+        }
+        for seg in inner.segments_l0.iter()
+            .chain(inner.segments_l1.iter())
+            .chain(inner.segments_l2.iter())
+            .chain(inner.segments_archive.iter())
+            .filter(|seg| seg.seq_high > since)
+        {
+            for idx in 0..seg.index.len() {
+                let (offset, len) = seg.block_span(idx);
+                let block_data = self.cache.get_or_load(seg, offset, len, BlockAccess::Scan)?;
+                let mut reader = BlockReader::new(seg, &block_data, offset);
+                while let Some(record) = reader.next_record() {
+                    if record.seq <= since {
+                        continue;
+                    }
+                    let key = record.key_text(seg, self.lossy_reads)?.into_owned();
+                    match record.rec_type {
+                        RT_SET => keep(key, record.seq, RawState::Live(record.value_text(seg, self.lossy_reads)?.into_owned())),
+                        RT_SET_REF => keep(key, record.seq, RawState::Live(self.resolve_value_ref(record.value)?)),
+                        RT_DEL_POINT => keep(key, record.seq, RawState::PointDeleted),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        
+        let mut changes: Vec<RawEntry> = newest.into_values().collect();
+        changes.extend(inner.subtombs.iter()
+            .filter(|(_, &seq)| seq > since)
+            .map(|(prefix, &seq)| RawEntry { key: prefix.clone(), seq, state: RawState::SubtreeDeleted(prefix.clone()) }));
+        changes.sort_by_key(|change| change.seq);
+        Ok((cut, changes))
+    }
+    
+    // Copy the blob a set_file value names into dest's blobs, if it is there and dest lacks it
+    fn copy_blob(&self, dest: &Path, value: &str) -> Result<()> {
+        let Some(hash) = value.strip_prefix("blob:") else { return Ok(()) };
+        let Some(shard) = hash.get(..2) else { return Ok(()) };
+        let target = dest.join("blobs").join(shard).join(hash);
+        if target.exists() {
+            return Ok(());
+        }
+        match self.read_blob(hash) {
+            Ok(compressed) => {
+                fs::create_dir_all(dest.join("blobs").join(shard))?;
+                write_atomic(&target, &compressed)?;
+                Ok(())
+            }
+            Err(WalDbError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+    
+    // copy_blob from source into this store, which may keep its blobs in memory
+    fn take_blob(&self, source: &Store, value: &str) -> Result<()> {
+        let Some(blobs) = &self.blobs else {
+            return source.copy_blob(&self.dir, value);
+        };
+        let Some(hash) = value.strip_prefix("blob:") else { return Ok(()) };
+        let held = blobs.lock().map_err(|e| WalDbError::Locked(format!("Blob map lock poisoned: {}", e)))?.contains_key(hash);
+        if held {
+            return Ok(());
+        }
+        match source.read_blob(hash) {
+            Ok(compressed) => {
+                blobs.lock().map_err(|e| WalDbError::Locked(format!("Blob map lock poisoned: {}", e)))?.insert(hash.to_string(), compressed);
+                Ok(())
+            }
+            Err(WalDbError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+    
+    // The compressed bytes of the blob named hash, from blobs/ or an in-memory store's map
+    fn read_blob(&self, hash: &str) -> Result<Vec<u8>> {
+        let missing = || io::Error::new(io::ErrorKind::NotFound, format!("Blob {} not found", hash));
+        match &self.blobs {
+            Some(blobs) => blobs.lock().map_err(|e| WalDbError::Locked(format!("Blob map lock poisoned: {}", e)))?
+                .get(hash).cloned().ok_or_else(|| missing().into()),
+            None => Ok(fs::read(self.dir.join("blobs").join(hash.get(..2).ok_or_else(missing)?).join(hash))?),
+        }
+    }
+    
+    // Sample the counters into stats.ring every interval until the store closes. The sampler
+    // holds the counters rather than the store, and only tries the store lock for the
+    // memtable's size, keeping the last sample's while a writer has it.
+    fn start_stats_sampler(&self, interval: Duration, ring_bytes: u64) {
+        let sources = self.metrics_sources();
+        let inner = self.inner.clone();
+        let shutdown = self.compaction_shutdown.clone();
+        let events = self.events.clone();
+        let clock = self.clock.clone();
+        let path = self.dir.join(STATS_RING_FILE);
+        thread::spawn(move || {
+            let mut ring = match StatsRing::open(&path, ring_bytes) {
+                Ok(ring) => ring,
+                Err(error) => return events.emit(StoreEvent::StatsWriteFailed { error }),
+            };
+            let mut last = MetricsSnapshot::default();
+            loop {
+                let (lock, cvar) = &*shutdown;
+                let closed = lock.lock().unwrap_or_else(|e| e.into_inner());
+                let (closed, _) = cvar.wait_timeout_while(closed, interval, |closed| !*closed).unwrap_or_else(|e| e.into_inner());
+                if *closed {
+                    break;
+                }
+                drop(closed);
+                
+                let (memtable_bytes, seq) = match inner.try_read() {
+                    Ok(inner) => (inner.memtable_size() as u64, inner.seq()),
+                    Err(_) => (last.memtable_bytes, last.seq),
+                };
+                last = sources.sample(memtable_bytes, seq);
+                if let Err(error) = ring.append(clock.now(), &last) {
+                    events.emit(StoreEvent::StatsWriteFailed { error });
+                }
+            }
+        });
+    }
+    
+    // Whether the store has begun to close, which stops background work
+    fn closing(&self) -> bool {
+        *self.compaction_shutdown.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    
+    // Have the compaction thread check now rather than at its next interval
+    fn wake_compaction(&self) {
+        self.compaction_wanted.store(true, Ordering::SeqCst);
+        let (lock, cvar) = &*self.compaction_shutdown;
+        let _shutdown = lock.lock().unwrap_or_else(|e| e.into_inner());
+        cvar.notify_all();
+    }
+    
+    fn compaction_thread(&self) {
+        loop {
+            // Wait a bit between compaction checks, at least 10ms however short max_age is,
+            // unless a flush filled L0 or the store is closing
+            let interval = COMPACTION_CHECK_INTERVAL.min(self.memtable_max_age).max(Duration::from_millis(10));
+            let (lock, cvar) = &*self.compaction_shutdown;
+            let shutdown = lock.lock().expect("Compaction shutdown lock should not be poisoned");
+            let (shutdown, _) = cvar.wait_timeout_while(shutdown, interval, |shutdown| !*shutdown && !self.compaction_wanted.swap(false, Ordering::SeqCst))
+                .expect("Compaction shutdown lock should not be poisoned");
+            if *shutdown {
+                break;
+            }
+            drop(shutdown);
+            
+            if let Err(error) = self.flush_aged_memtable() {
+                self.events.emit(StoreEvent::MemtableFlushFailed { error });
+            }
+            if let Err(error) = self.segment_registry.sweep() {
+                self.events.emit(StoreEvent::SegmentRemoveFailed { error: error.into() });
+            }
+            if let Err(error) = self.drop_covered_segments() {
+                self.report_compaction_error(error);
+            }
+            if let Err(error) = self.rewrite_salvaged(true) {
+                self.report_compaction_error(error);
+            }
+            
+            // Check if L0 compaction is needed
+            let needs_l0_compaction = {
+                let inner = self.read_inner().expect("Inner lock should not be poisoned in compaction thread");
+                inner.segments_l0.len() >= L0_COMPACTION_THRESHOLD
+            };
+            
+            if needs_l0_compaction {
+                match self.compact_l0_to_l1(true) {
+                    // More than one merge's worth goes on at once rather than after the interval
+                    Ok(()) if self.segment_counts().0 >= L0_COMPACTION_THRESHOLD => self.compaction_wanted.store(true, Ordering::SeqCst),
+                    Ok(()) => {}
+                    Err(error) => self.report_compaction_error(error),
+                }
+            }
+            
+            // Check if L1 compaction is needed
+            let needs_l1_compaction = {
+                let inner = self.read_inner().expect("Inner lock should not be poisoned in compaction thread");
+                inner.segments_l1.len() >= L1_COMPACTION_THRESHOLD
+            };
+            
+            if needs_l1_compaction {
+                if let Err(error) = self.compact_l1_to_l2(true) {
+                    self.report_compaction_error(error);
+                }
+            }
+        }
+    }
+    
+    // Flush a memtable older than memtable_max_age, as flush() would, which leaves alone a
+    // store refusing writes or closing
+    fn flush_aged_memtable(&self) -> Result<()> {
+        let due = |inner: &StoreInner| inner.memtable_age().is_some_and(|age| age >= self.memtable_max_age);
+        if !due(&*self.read_inner()?) || self.check_unfrozen().is_err() {
+            return Ok(());
+        }
+        
+        let _admin = self.admin_lock()?;
+        let mut inner = self.write_inner()?;
+        if !due(&inner) || self.check_unfrozen().is_err() || self.closing() {
+            return Ok(());
+        }
+        self.flush_memtable_locked(&mut inner)?;
+        drop(inner);
+        self.list_flushed()?;
+        sync_manifest(&self.manifest, &self.manifest_unsynced)
+    }
+    
+    // A merge stopped by shutdown isn't a failure worth reporting
+    fn report_compaction_error(&self, error: WalDbError) {
+        if !matches!(&error, WalDbError::Io(e) if e.kind() == io::ErrorKind::Interrupted) {
+            self.events.emit(StoreEvent::CompactionFailed { error });
+        }
+    }
+    
+    /// Run any compactions that are due now instead of waiting for the background thread
+    pub fn compact(&self) -> Result<()> {
+        self.segment_registry.sweep()?;
+        self.drop_covered_segments()?;
+        self.rewrite_salvaged(false)?;
+        
+        loop {
+            let (l0, l1, _) = self.segment_counts();
+            if l0 >= L0_COMPACTION_THRESHOLD {
+                self.compact_l0_to_l1(false)?;
+            } else if l1 >= L1_COMPACTION_THRESHOLD {
+                self.compact_l1_to_l2(false)?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+    
+    /// Drop the values no segment refers to any more from the log dedup_values_over writes,
+    /// and return how many bytes it shrank by. A value stays while any record names it,
+    /// shadowed or not, unless a subtree delete covers the record, so compact first to let
+    /// overwrites and point deletes free theirs. Reads every segment with references;
+    /// writes carry on meanwhile, and flushes keep the values they store.
+    pub fn reclaim_values(&self) -> Result<u64> {
+        let _compacting = self.compacting_lock()?;
+        
+        // Flushes from here on pin what they store, so only segments that exist already
+        // need marking
+        self.values_locked()?.marking = Some(HashSet::new());
+        let marked = self.mark_value_refs();
+        let mut values = self.values_locked()?;
+        let pinned = values.marking.take().unwrap_or_default();
+        let marked = marked?;
+        values.retain(|hash| marked.contains(&hash) || pinned.contains(&hash))
+    }
+    
+    // Hashes named by RT_SET_REF records in every segment a read could reach, shadowed or
+    // not, leaving out those under a subtree tombstone every open range_iter sees
+    fn mark_value_refs(&self) -> Result<HashSet<u64>> {
+        let (mut segments, mut subtombs) = {
+            let inner = self.read_inner()?;
+            let segments: Vec<Arc<Segment>> = inner.segments_l0.iter()
+                .chain(inner.segments_l1.iter())
+                .chain(inner.segments_l2.iter())
+                .chain(inner.segments_archive.iter())
+                .filter(|seg| seg.value_refs)
+                .cloned()
+                .collect();
+            (segments, inner.subtombs.clone())
+        };
+        // A range_iter opened before a subtree delete still returns what it covers
+        if let Some(&oldest) = self.open_snapshots.lock().map_err(|e| WalDbError::Locked(format!("Snapshot list lock poisoned: {}", e)))?
+            .keys().next()
+        {
+            subtombs.retain(|_, tomb_seq| *tomb_seq <= oldest);
+        }
+        // A scan that started before a compaction may still be reading its inputs
+        segments.extend(self.segment_registry.waiting().into_iter().filter(|seg| seg.value_refs));
+        
+        let mut marked = HashSet::new();
+        for seg in &segments {
+            let mut file = seg.sequential()?;
+            if !seg.index.is_empty() {
+                file.seek(SeekFrom::Start(seg.block_span(0).0))?;
+            }
+            let mut block_data = Vec::new();
+            for idx in 0..seg.index.len() {
+                let (offset, len) = seg.block_span(idx);
+                block_data.resize(len, 0);
+                file.read_exact(&mut block_data)?;
+                
+                let mut reader = BlockReader::new(seg, &block_data, offset);
+                while let Some(record) = reader.next_record() {
+                    if record.rec_type != RT_SET_REF {
+                        continue;
+                    }
+                    // Dead for good: compaction drops these, and no read ever returns them
+                    let key = record.key_text(seg, self.lossy_reads)?;
+                    if subtombs.iter().any(|(prefix, &tomb_seq)| key.starts_with(prefix.as_str()) && tomb_seq >= record.seq) {
+                        continue;
+                    }
+                    if let Some(hash) = parse_value_ref(record.value) {
+                        marked.insert(hash);
+                    }
+                }
+            }
+        }
+        Ok(marked)
+    }
+    
+    // Rewrite segments salvaged at open into clean files under the same name, so the
+    // manifest entries pointing at them stay valid
+    fn rewrite_salvaged(&self, background: bool) -> Result<()> {
+        let _compacting = self.compacting_lock()?;
+        
+        let salvaged: Vec<(usize, Arc<Segment>)> = {
+            let inner = self.read_inner()?;
+            [&inner.segments_l0, &inner.segments_l1, &inner.segments_l2].into_iter()
+                .enumerate()
+                .flat_map(|(level, segments)| segments.iter()
+                    .filter(|seg| seg.salvaged)
+                    .map(move |seg| (level, seg.clone())))
+                .collect()
+        };
+        
+        for (level, seg) in salvaged {
+            let tmp_path = tmp_path_for(&seg.path);
+            let (mut rewritten, floor) = self.merge_segments(std::slice::from_ref(&seg), &tmp_path, level, background)?;
+            let _admin = self.admin_lock()?;
+            if background && self.closing() {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "Compaction stopped by shutdown").into());
+            }
+            self.raise_history_floor(floor)?;
+            
+            let mut inner = self.write_inner()?;
+            let segments = match level {
+                0 => &mut inner.segments_l0,
+                1 => &mut inner.segments_l1,
+                _ => &mut inner.segments_l2,
+            };
+            match segments.iter().position(|s| Arc::ptr_eq(s, &seg)) {
+                Some(i) => {
+                    fs::rename(&tmp_path, &seg.path)?;
+                    sync_dir(seg.path.parent().unwrap_or(&self.dir))?;
+                    rewritten.path = seg.path.clone();
+                    segments[i] = Arc::new(rewritten);
+                }
+                // A compaction merged it away in the meantime
+                None => {
+                    let _ = fs::remove_file(&tmp_path);
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    // Hand segments taken out of their level to the registry, which deletes their files once
+    // nothing reads them. Every segment leaving its level comes through here, so flushes held
+    // at l0_stop_threshold look at L0 again.
+    fn retire_segments(&self, segments: Vec<Arc<Segment>>) -> Result<()> {
+        let (lock, cvar) = &*self.l0_changed;
+        *lock.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        cvar.notify_all();
+        self.cache.unpin(|id| segments.iter().any(|seg| seg.id == id))?;
+        Ok(self.segment_registry.retire(segments)?)
+    }
+    
+    // Take out the segments a subtree tombstone covers whole, as clear_all leaves them:
+    // nothing in them can be read again, so there is nothing to merge. Reads as of a seq
+    // before the tombstone could have needed them. Returns how many were dropped, none once
+    // the store is closing.
+    fn drop_covered_segments(&self) -> Result<usize> {
+        let _compacting = self.compacting_lock()?;
+        let _admin = self.admin_lock()?;
+        if self.closing() {
+            return Ok(0);
+        }
+        let mut guard = self.write_inner()?;
+        let inner = &mut *guard;
+        
+        // Keys under a path sort together in either collation, so bounds under it put every
+        // key there. A prefix not ending in '/' doesn't have to.
+        let subtombs = &inner.subtombs;
+        let covering = |seg: &Segment| subtombs.iter()
+            .filter(|(prefix, &tomb_seq)| tomb_seq >= seg.seq_high && (prefix.is_empty() || prefix.ends_with('/')
+                && seg.bounds.as_ref().is_some_and(|(min, max)| min.starts_with(prefix.as_str()) && max.starts_with(prefix.as_str()))))
+            .map(|(_, &tomb_seq)| tomb_seq)
+            .max();
+        let mut dropped = Vec::new();
+        for level in [&mut inner.segments_l0, &mut inner.segments_l1, &mut inner.segments_l2, &mut inner.segments_archive] {
+            let mut i = 0;
+            while i < level.len() {
+                match covering(&level[i]) {
+                    Some(tomb_seq) => dropped.push((level.remove(i), tomb_seq)),
+                    None => i += 1,
+                }
+            }
+        }
+        if dropped.is_empty() {
+            return Ok(0);
+        }
+        
+        // A flush not yet listed would otherwise be listed after its drop
+        self.list_flushed()?;
+        let mut manifest = self.manifest_locked()?;
+        for (seg, tomb_seq) in &dropped {
+            manifest.raise_floor(*tomb_seq)?;
+            manifest.drop_segment(seg.path.file_name().and_then(|name| name.to_str()).unwrap_or_default())?;
+        }
+        drop(manifest);
+        
+        // With no segment left, the memtable alone says whether a tombstone still hides anything
+        if [&inner.segments_l0, &inner.segments_l1, &inner.segments_l2, &inner.segments_archive].iter().all(|level| level.is_empty()) {
+            self.gc_subtombs_locked(inner)?;
+        }
+        drop(guard);
+        
+        let count = dropped.len();
+        self.retire_segments(dropped.into_iter().map(|(seg, _)| seg).collect())?;
+        Ok(count)
+    }
+    
+    // Background runs stop early when the store shuts down; compact() runs to completion
+    fn compact_l0_to_l1(&self, background: bool) -> Result<()> {
+        // Take segments to compact
+        let _compacting = self.compacting_lock()?;
+        
+        // The oldest L0 segments, left in place for readers until the merged one replaces them
+        let segments_to_compact = {
+            let inner = self.read_inner().expect("Inner lock should not be poisoned in L0 compaction");
+            if inner.segments_l0.len() < L0_COMPACTION_THRESHOLD {
+                return Ok(());
+            }
+            inner.segments_l0[..L0_COMPACTION_THRESHOLD].to_vec()
+        };
+        
+        self.merge_into(1, segments_to_compact, background)
+    }
+    
+    fn compact_l1_to_l2(&self, background: bool) -> Result<()> {
+        // Similar to L0->L1 but for L1->L2
+        let _compacting = self.compacting_lock()?;
+        
+        // The oldest L1 segments and the L2 segments sharing keys with them, left in place for
+        // readers until the merged ones replace them. The merge drops point tombstones, so
+        // no L2 segment left out may hold an older version of a key it saw.
+        let segments_to_compact = {
+            let inner = self.read_inner().expect("Inner lock should not be poisoned in L1 compaction");
+            if inner.segments_l1.len() < L1_COMPACTION_THRESHOLD {
+                return Ok(());
+            }
+            let candidates: Vec<&Arc<Segment>> = inner.segments_l1.iter().chain(&inner.segments_l2).collect();
+            let taken = (0..candidates.len()).map(|i| i < L1_COMPACTION_THRESHOLD).collect();
+            self.widen_overlapping(candidates, taken)
+        };
+        self.merge_into(2, segments_to_compact, background)
+    }
+    
+    /// Merge the L1 and L2 segments holding keys in [start, end) into new L2 segments, cut
+    /// at CompactionOptions::target_segment_bytes, and leave every other segment alone. A
+    /// segment sharing keys with one merged is merged too, so none left behind overlaps the
+    /// output; segments listed before the manifest recorded key bounds overlap everything.
+    /// Returns how many segments were merged, 0 when none holds a key in the range.
+    pub fn compact_range(&self, start: &str, end: &str) -> Result<usize> {
+        let _compacting = self.compacting_lock()?;
+        if self.collation.compare(start.as_bytes(), end.as_bytes()).is_ge() {
+            return Ok(0);
+        }
+        
+        let segments_to_compact = {
+            let inner = self.read_inner()?;
+            let candidates: Vec<&Arc<Segment>> = inner.segments_l1.iter().chain(&inner.segments_l2).collect();
+            let taken = candidates.iter().map(|seg| seg.overlaps(start, end)).collect();
+            self.widen_overlapping(candidates, taken)
+        };
+        if segments_to_compact.is_empty() {
+            return Ok(0);
+        }
+        
+        let merged = segments_to_compact.len();
+        self.merge_into(2, segments_to_compact, false)?;
+        Ok(merged)
+    }
+    
+    // The candidates taken, widened to whatever overlaps a segment taken until nothing more does
+    fn widen_overlapping(&self, candidates: Vec<&Arc<Segment>>, mut taken: Vec<bool>) -> Vec<Arc<Segment>> {
+        let mut widened = true;
+        while widened {
+            widened = false;
+            for i in 0..candidates.len() {
+                if !taken[i] && (0..candidates.len()).any(|j| taken[j] && bounds_overlap(&candidates[i].bounds, &candidates[j].bounds, self.collation)) {
+                    taken[i] = true;
+                    widened = true;
+                }
+            }
+        }
+        candidates.into_iter().zip(taken).filter(|(_, taken)| *taken).map(|(seg, _)| seg.clone()).collect()
+    }
+    
+    // Replace segments from the levels above with new ones in level, cut at segment_cut
+    fn merge_into(&self, level: usize, segments_to_compact: Vec<Arc<Segment>>, background: bool) -> Result<()> {
+        let mut outputs = vec![self.new_segment_file(level)?];
+        
+        let started = Instant::now();
+        self.events.emit(StoreEvent::CompactionStarted { level, inputs: segments_to_compact.len() });
+        let (merged, floor) = self.merge_segments_split(&segments_to_compact, &mut outputs, level, background, Some(self.segment_cut(level)))?;
+        let mut bytes_written = 0;
+        for seg in &merged {
+            bytes_written += seg.file_len()?;
+        }
+        
+        // Installed as one step against flushes and the like. A close may have come since the
+        // merge began, and a store opened after it reads the inputs.
+        let _admin = self.admin_lock()?;
+        if background && self.closing() {
+            for (path, _) in &outputs {
+                let _ = self.segment_registry.unlink(path);
+            }
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "Compaction stopped by shutdown").into());
+        }
+        
+        // Before any reader can see the merged segments in place of their inputs
+        self.raise_history_floor(floor)?;
+        
+        let seq_high = segments_to_compact.iter()
+            .map(|s| s.seq_high)
+            .max()
+            .unwrap_or(0);
+        let entries: Vec<ManifestEntry> = merged.iter().zip(outputs).map(|(seg, (_, filename))| ManifestEntry {
+            seq_high,
+            level,
+            filename,
+            archive_of: None,
+            inputs: segment_file_names(&segments_to_compact),
+            bounds: seg.bounds.clone(),
+        }).collect();
+        
+        // Update state
+        {
+            let mut guard = self.write_inner().expect("Inner write lock should not be poisoned when installing merged segments");
+            let inner = &mut *guard;
+            for segments in [&mut inner.segments_l0, &mut inner.segments_l1, &mut inner.segments_l2] {
+                segments.retain(|seg| !segments_to_compact.iter().any(|s| Arc::ptr_eq(s, seg)));
+            }
+            let segments = if level == 1 { &mut inner.segments_l1 } else { &mut inner.segments_l2 };
+            segments.extend(merged.into_iter().map(Arc::new));
+        }
+        
+        // Update manifest, after the flushes whose segments may be among the inputs
+        self.list_flushed()?;
+        self.manifest_locked()?.add_entries(entries)?;
+        
+        let duration = started.elapsed();
+        self.compaction_timing.0.fetch_add(1, Ordering::Relaxed);
+        self.compaction_timing.1.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.events.emit(StoreEvent::CompactionFinished { level, duration, bytes_written });
+        self.retire_segments(segments_to_compact)
+    }
+    
+    // The merged segment, and the history floor its dropped records call for: the newest seq
+    // that superseded, covered or was itself a dropped record
+    fn merge_segments(&self, segments: &[Arc<Segment>], output_path: &Path, level: usize, background: bool) -> Result<(Segment, u64)> {
+        let mut outputs = vec![(output_path.to_path_buf(), String::new())];
+        let (mut merged, floor) = self.merge_segments_split(segments, &mut outputs, level, background, None)?;
+        Ok((merged.pop().expect("A merge writes at least one segment"), floor))
+    }
+    
+    // merge_segments into segments of about target bytes each, cut between keys. outputs
+    // starts with the first file's path and manifest name and gains one for each cut.
+    fn merge_segments_split(&self, segments: &[Arc<Segment>], outputs: &mut Vec<(PathBuf, String)>, level: usize,
+                            background: bool, target: Option<u64>) -> Result<(Vec<Segment>, u64)> {
+        let mut throttle = IoThrottle {
+            bytes_per_sec: self.compaction.max_bytes_per_sec.filter(|&rate| rate > 0),
+            shutdown: background.then_some(&*self.compaction_shutdown),
+            started: Instant::now(),
+            bytes: 0,
+        };
+        let merged = self.merge_segments_throttled(segments, outputs, level, &mut throttle, target);
+        if merged.is_err() {
+            for (path, _) in outputs.iter() {
+                let _ = self.segment_registry.unlink(path);
+            }
+        }
+        merged
+    }
+    
+    // Blocks are read straight from the files rather than through the block cache: each is
+    // read once, in order, and caching them would only evict blocks foreground reads want
+    fn merge_segments_throttled(&self, segments: &[Arc<Segment>], outputs: &mut Vec<(PathBuf, String)>, level: usize,
+                                throttle: &mut IoThrottle, target: Option<u64>) -> Result<(Vec<Segment>, u64)> {
+        let mut writer = self.segment_writer(&outputs[0].0, self.collation)?;
+        let mut merged = Vec::new();
+        
+        // Records under a subtree tombstone are dead for good, since every older copy is too.
+        // Point tombstones under an archive keep shadowing it, even from the bottom level.
+        let (subtombs, archived) = {
+            let inner = self.read_inner()?;
+            let archived: Vec<String> = inner.segments_archive.iter().filter_map(|seg| seg.archive_of.clone()).collect();
+            (inner.subtombs.clone(), archived)
+        };
+        
+        // Collect all records from segments
+        let mut all_records: BTreeMap<CollatedKey, (u8, Option<String>, u64)> = BTreeMap::new();
+        let mut floor = 0;
+        
+        for segment in segments {
+            // Between inputs is where a background merge notices shutdown
+            throttle.check_shutdown()?;
+            
+            // Blocks sit back to back, so one handle reads the whole segment front to back
+            let mut file = segment.sequential()?;
+            if !segment.index.is_empty() {
+                file.seek(SeekFrom::Start(segment.block_span(0).0))?;
+            }
+            let mut block_data = Vec::new();
+            
+            for idx in 0..segment.index.len() {
+                let (offset, block_size) = segment.block_span(idx);
+                block_data.resize(block_size, 0);
+                file.read_exact(&mut block_data)?;
+                throttle.consume(block_size as u64)?;
+                self.compaction_io.0.fetch_add(block_size as u64, Ordering::Relaxed);
+                
+                // Parse records from block, copying a key or value out of it only for the
+                // record that is kept
+                let mut reader = BlockReader::new(segment, &block_data, offset);
+                while let Some(record) = reader.next_record() {
+                    let (seq, rec_type) = (record.seq, record.rec_type);
+                    // A reference is copied as is: the value log entry it names stays put
+                    let value = || (matches!(rec_type, RT_SET | RT_SET_REF) && !record.value.is_empty())
+                        .then(|| record.value_text(segment, self.lossy_reads).map(Cow::into_owned))
+                        .transpose();
+                    
+                    // Keep only the newest version of each key. Every write takes a seq of its
+                    // own, so two records with one seq are copies of the same write
+                    let k = record.key_text(segment, self.lossy_reads)?;
+                    match all_records.get_mut(&(k.as_ref(), self.collation) as &dyn KeyView) {
+                        Some(existing) => {
+                            if seq != existing.2 {
+                                floor = floor.max(seq.max(existing.2));
+                            }
+                            if seq > existing.2 {
+                                *existing = (rec_type, value()?, seq);
+                            }
+                        }
+                        None => {
+                            all_records.insert(CollatedKey { key: k.into_owned(), collation: self.collation }, (rec_type, value()?, seq));
+                        }
+                    }
+                }
+            }
+        }
+        
+        // Write merged records to new segment
+        for (key, (rec_type, value, seq)) in all_records {
+            let covering = subtombs.iter()
+                .filter(|(prefix, &tomb_seq)| key.key.starts_with(prefix.as_str()) && tomb_seq >= seq)
+                .map(|(_, &tomb_seq)| tomb_seq)
+                .max();
+            if let Some(tomb_seq) = covering {
+                floor = floor.max(tomb_seq);
+                continue;
+            }
+            
+            // In L2, skip tombstones entirely (they've done their job). Skipped before the
+            // writer sees them, so the merged segment's bloom rules their keys out too.
+            if level >= 2 && rec_type == RT_DEL_POINT && !archived.iter().any(|prefix| key.key.starts_with(prefix.as_str())) {
+                floor = floor.max(seq);
+                continue;
+            }
+            
+            if target.is_some_and(|target| writer.written >= target) {
+                let (path, filename) = self.new_segment_file(level)?;
+                let next = self.segment_writer(&path, self.collation)?;
+                outputs.push((path, filename));
+                merged.push(std::mem::replace(&mut writer, next).finish(self.next_segment_id.fetch_add(1, Ordering::Relaxed))?);
+            }
+            
+            // In L0/L1, preserve tombstones to shadow older data
+            let before = writer.written;
+            writer.add(rec_type, &key.key, value.as_deref(), seq)?;
+            throttle.consume(writer.written - before)?;
+        }
+        
+        merged.push(writer.finish(self.next_segment_id.fetch_add(1, Ordering::Relaxed))?);
+        for seg in &merged {
+            self.compaction_io.1.fetch_add(seg.file_len()?, Ordering::Relaxed);
+        }
+        Ok((merged, floor))
+    }
+    
+    // Move the history floor up to floor and fsync it, before the versions it accounts for
+    // leave the store
+    fn raise_history_floor(&self, floor: u64) -> Result<()> {
+        let mut manifest = self.manifest_locked()?;
+        if floor > manifest.history_floor {
+            manifest.raise_floor(floor)?;
+            manifest.sync()?;
+        }
+        Ok(())
+    }
+    
+    // ==================== NAMESPACES ====================
+    
+    // Keys the store keeps for itself, named relative to SYSTEM_PREFIX. User writes can't
+    // reach them and user scans never return them; only these and get-style reads see them.
+    pub(crate) fn system_get(&self, key: &str) -> Result<Option<String>> {
+        self.get(&format!("{}{}", SYSTEM_PREFIX, key))
+    }
+    
+    pub(crate) fn system_set(&self, key: &str, value: &str) -> Result<u64> {
+        self.set_unchecked(&format!("{}{}", SYSTEM_PREFIX, key), value, false)
+    }
+    
+    pub(crate) fn system_delete(&self, key: &str) -> Result<u64> {
+        self.delete_unchecked(&format!("{}{}", SYSTEM_PREFIX, key))
+    }
+    
+    /// A handle whose keys live under a prefix of their own, sharing this store's WAL,
+    /// memtable, segments and cache. Namespaces exist while they hold data; there is
+    /// nothing to create.
+    pub fn namespace(&self, name: &str) -> Namespace<'_> {
+        Namespace { store: self, prefix: namespace_prefix(name) }
+    }
+    
+    /// Names of the namespaces holding live data, in key order of their prefixes.
+    /// Reads every namespaced record, so it costs as much as a scan of all of them.
+    pub fn list_namespaces(&self) -> Result<Vec<String>> {
+        let start = NAMESPACE_MARKER.to_string();
+        let end = char::from_u32(NAMESPACE_MARKER as u32 + 1).expect("Marker is followed by a char").to_string();
+        
+        let mut names: Vec<String> = Vec::new();
+        let mut last_prefix = "";
+        let entries = self.get_range(&start, &end)?;
+        for (key, _) in &entries {
+            let escaped = key[1..].split('/').next().unwrap_or_default();
+            if escaped != last_prefix {
+                names.push(percent_unescape(escaped));
+                last_prefix = escaped;
+            }
+        }
+        Ok(names)
+    }
+    
+    /// Delete everything in a namespace with one subtree tombstone. Returns its seq.
+    pub fn drop_namespace(&self, name: &str) -> Result<u64> {
+        self.delete_subtree_unchecked(&namespace_prefix(name))
+    }
+    
+    // ==================== FILE/BLOB SUPPORT ====================
+    
+    /// Store a file as a blob with automatic compression and deduplication
+    /// Receive the changes under prefix in batches, at most one per window. The first change
+    /// after a quiet spell opens a window; when it closes, or the batch reaches 10,000 entries,
+    /// the latest state of each key changed in it goes out as one CoalescedBatch. Each
+    /// subscription has an aggregator thread, which exits when the store closes or, at its
+    /// next batch, once the receiver is dropped.
+    pub fn subscribe_coalesced(&self, prefix: &str, window: Duration) -> Result<mpsc::Receiver<CoalescedBatch>> {
+        reject_reserved(prefix)?;
+        let (sender, changes) = mpsc::channel();
+        let (batch_sender, batches) = mpsc::channel();
+        let clock = self.clock.clone();
+        thread::spawn(move || coalesce(changes, batch_sender, window, &clock));
+        
+        let mut inner = self.write_inner()?;
+        inner.subscribers.push(Subscriber { prefix: prefix.to_string(), sender });
+        Ok(batches)
+    }
+    
+    pub fn set_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        reject_reserved(path)?;
+        self.set_file_unchecked(path, data)
+    }
+    
+    fn set_file_unchecked(&self, path: &str, data: &[u8]) -> Result<()> {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        
+        // Calculate hash for deduplication
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let hash = format!("{:016x}", hasher.finish());
+        
+        // An in-memory store keeps its blobs in a map of the same shape
+        if let Some(blobs) = &self.blobs {
+            let mut blobs = blobs.lock().map_err(|e| WalDbError::Locked(format!("Blob map lock poisoned: {}", e)))?;
+            if !blobs.contains_key(&hash) {
+                blobs.insert(hash.clone(), Self::compress_data(data)?);
+            }
+        } else {
+            // Create blob directory structure
+            let blob_dir = self.dir.join("blobs").join(&hash[0..2]);
+            fs::create_dir_all(&blob_dir)?;
+            let blob_path = blob_dir.join(&hash);
+            
+            // Only write if blob doesn't exist (deduplication)
+            if !blob_path.exists() {
+                // Simple compression using zlib-style DEFLATE
+                // For production, would use zstd or similar
+                // Written atomically: a torn blob would pass the exists check and never be rewritten
+                let compressed = Self::compress_data(data)?;
+                write_atomic(&blob_path, &compressed)?;
+            }
+        }
+        
+        // Store metadata in tree
+        let blob_ref = format!("blob:{}", hash);
+        self.set_unchecked(path, &blob_ref, false)?;
+        self.set_unchecked(&format!("{}:size", path), &data.len().to_string(), false)?;
+        self.set_unchecked(&format!("{}:hash", path), &hash, false)?;
+        
+        // Detect MIME type (simplified)
+        let mime_type = Self::detect_mime_type(data);
+        self.set_unchecked(&format!("{}:type", path), mime_type, false)?;
+        
+        Ok(())
+    }
+    
+    /// Retrieve a file from blob storage
+    pub fn get_file(&self, path: &str) -> Result<Vec<u8>> {
+        // Get blob reference
+        let blob_ref = self.get(path)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
+        
+        let hash = blob_ref.strip_prefix("blob:")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid blob reference"))?;
+        
+        // Read compressed blob
+        let compressed = self.read_blob(hash)?;
+        
+        // Decompress and return
+        Self::decompress_data(&compressed)
+    }
+    
+    /// Delete a file and its metadata
+    pub fn delete_file(&self, path: &str) -> Result<()> {
+        reject_reserved(path)?;
+        self.delete_file_unchecked(path)
+    }
+    
+    fn delete_file_unchecked(&self, path: &str) -> Result<()> {
+        // Delete all metadata
+        self.delete_unchecked(path)?;
+        self.delete_unchecked(&format!("{}:size", path))?;
+        self.delete_unchecked(&format!("{}:type", path))?;
+        self.delete_unchecked(&format!("{}:hash", path))?;
+        
+        // Note: Blob itself is not deleted (might be referenced elsewhere)
+        // Could implement reference counting or garbage collection later
+        
+        Ok(())
+    }
+    
+    // Simple compression helpers (would use proper library in production)
+    fn compress_data(data: &[u8]) -> Result<Vec<u8>> {
+        // For now, just store uncompressed with a header
+        // In production, would use zstd::encode_all(data, 3)
+        let mut result = vec![0u8; 4]; // Magic header for "uncompressed"
+        result.extend_from_slice(data);
+        Ok(result)
+    }
+    
+    fn decompress_data(data: &[u8]) -> Result<Vec<u8>> {
+        // For now, just strip header
+        // In production, would use zstd::decode_all(data)
+        if data.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid compressed data").into());
+        }
+        Ok(data[4..].to_vec())
+    }
+    
+    fn detect_mime_type(data: &[u8]) -> &'static str {
+        // Simple magic byte detection
+        if data.starts_with(b"\x89PNG") { return "image/png"; }
+        if data.starts_with(b"\xFF\xD8\xFF") { return "image/jpeg"; }
+        if data.starts_with(b"GIF8") { return "image/gif"; }
+        if data.starts_with(b"%PDF") { return "application/pdf"; }
+        if data.starts_with(b"PK") { return "application/zip"; }
+        if data.starts_with(b"{") || data.starts_with(b"[") { return "application/json"; }
+        
+        // Try to detect text
+        if !data.is_empty() && data.iter().all(|&b| b < 128 && (b >= 32 || b == 9 || b == 10 || b == 13)) {
+            return "text/plain";
+        }
+        
+        "application/octet-stream"
+    }
+    
+    // ==================== VECTOR OPERATIONS ====================
+    
+    /// Store a vector (embedding), optionally scaled to unit length on write
+    pub fn set_vector(&self, path: &str, vector: Vec<f32>, normalize: bool) -> Result<()> {
+        reject_reserved(path)?;
+        self.set_unchecked(path, &encode_vector(&vector, normalize), false)?;
+        Ok(())
+    }
+    
+    /// Get a vector (unit length if it was normalized on write)
+    pub fn get_vector(&self, path: &str) -> Result<Option<Vec<f32>>> {
+        if let Some((vector, _)) = self.vectors_locked()?.get(path) {
+            return Ok(Some(vector.to_vec()));
+        }
+        
+        // Vectors of a different width than the sidecar's live only in the primary records
+        match self.get(path)? {
+            Some(val) => match decode_vector(&val) {
+                Some(vector) => Ok(Some(vector
+                    .map(|v| v.data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)),
+                None => Ok(None),
+            },
+            None => Ok(None)
+        }
+    }
+    
+    fn vectors_locked(&self) -> Result<std::sync::MutexGuard<'_, VectorSidecar>> {
+        self.vectors.lock().map_err(|e| WalDbError::Locked(format!("Vector sidecar lock poisoned: {}", e)))
+    }
+    
+    fn values_locked(&self) -> Result<std::sync::MutexGuard<'_, ValueLog>> {
+        self.values.lock().map_err(|e| WalDbError::Locked(format!("Value log lock poisoned: {}", e)))
+    }
+    
+    // Regenerate vectors.dat from every vector value in the primary records
+    fn rebuild_vectors(&self) -> Result<()> {
+        let entries = self.get_range("", &prefix_end(""))?;
+        let seq = self.read_inner()?.seq();
+        
+        let mut vectors = self.vectors_locked()?;
+        *vectors = VectorSidecar::new(vectors.path.take());
+        for (key, value) in entries {
+            if let Some(Ok(vector)) = decode_vector(&value) {
+                if vectors.dims == 0 {
+                    vectors.dims = vector.data.len();
+                }
+                if !vector.data.is_empty() && vector.data.len() == vectors.dims {
+                    vectors.push_row(key, &vector.data, vector.unit, seq);
+                }
+            }
+        }
+        vectors.rewrite()?;
+        Ok(())
+    }
+    
+    // ==================== SEARCH FUNCTIONALITY ====================
+    
+    /// Advanced search with filters, vector similarity, and text search
+    pub fn search(&self, options: SearchOptions) -> Result<Vec<SearchGroup>> {
+        self.search_with(options, None)
+    }
+    
+    /// search that gives up with WalDbError::Cancelled once cancel trips
+    pub fn search_cancellable(&self, options: SearchOptions, cancel: &CancellationToken) -> Result<Vec<SearchGroup>> {
+        self.search_with(options, Some(cancel))
+    }
+    
+    fn search_with(&self, options: SearchOptions, cancel: Option<&CancellationToken>) -> Result<Vec<SearchGroup>> {
+        if let Some(ref filters) = options.filters {
+            Self::check_filters(filters)?;
+        }
+        
+        // Without scoring, the first groups to pass the filters are the results
+        if options.vector.is_none() && options.text.is_none() {
+            return self.search_in_order(&options, cancel);
+        }
+        
+        // Get all entries matching pattern
+        let entries = self.pattern_scan(&options.pattern, cancel)?;
+        
+        // Group by subroot
+        let mut grouped = Self::group_by_subroot(entries, &options.pattern);
+        self.groups_visited.fetch_add(grouped.len() as u64, Ordering::Relaxed);
+        
+        // Apply filters if provided
+        if let Some(ref filters) = options.filters {
+            if !filters.is_empty() {
+                grouped.retain(|group| Self::matches_filters(group, filters));
+            }
+        }
+        CancellationToken::check(cancel)?;
+        
+        // Apply vector search if requested
+        if let Some(ref vector_opts) = options.vector {
+            grouped = self.apply_vector_search(grouped, vector_opts)?;
+        }
+        
+        // Apply text search if requested
+        if let Some(ref text_opts) = options.text {
+            grouped = Self::apply_text_search(grouped, text_opts);
+            
+            // Text is the only ranking signal unless vector search or hybrid scoring applies
+            if options.vector.is_none() && options.scoring.is_none() {
+                Self::sort_by_score(&mut grouped, "_text_score");
+            }
+        }
+        
+        // Apply hybrid scoring if multiple search types and scoring weights provided
+        if let Some(ref scoring) = options.scoring {
+            if options.vector.is_some() || options.text.is_some() {
+                grouped = Self::apply_scoring(grouped, scoring);
+            }
+        }
+        
+        // Limit results if specified
+        if let Some(limit) = options.limit {
+            grouped.truncate(limit);
+        }
+        
+        Ok(grouped)
+    }
+    
+    // Groups in key order (reverse for KeyDesc), streamed from a scan of the pattern's literal
+    // prefix. Keys under a group are adjacent, so each group is filtered once the scan moves
+    // past it, and the scan stops as soon as limit groups have passed.
+    fn search_in_order(&self, options: &SearchOptions, cancel: Option<&CancellationToken>) -> Result<Vec<SearchGroup>> {
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let mut results = Vec::new();
+        if limit == 0 {
+            return Ok(results);
+        }
+        let literal = options.pattern.find(['*', '?']).map_or(options.pattern.as_str(), |i| &options.pattern[..i]);
+        let entries = match options.order_by.unwrap_or_default() {
+            SearchOrder::KeyAsc => self.scan_prefix_iter(literal)?,
+            SearchOrder::KeyDesc => self.scan_prefix_iter_rev(literal)?,
+        };
+        let depth = options.pattern.matches('/').count() + 1;
+        let filters = options.filters.as_deref().unwrap_or_default();
+        
+        let mut group: Option<SearchGroup> = None;
+        let mut visited = 0;
+        for entry in entries {
+            let (key, value) = entry?;
+            if !Self::matches_pattern(&key, &options.pattern) {
+                continue;
+            }
+            let Some((group_key, field)) = Self::split_group(&key, depth) else { continue };
+            
+            if group.as_ref().is_none_or(|(current, _)| current != group_key) {
+                if let Some(done) = group.take() {
+                    if Self::matches_filters(&done, filters) {
+                        results.push(done);
+                        if results.len() == limit {
+                            break;
+                        }
+                    }
+                }
+                CancellationToken::check(cancel)?;
+                visited += 1;
+                group = Some((group_key.to_string(), BTreeMap::new()));
+            }
+            if let Some((_, fields)) = group.as_mut() {
+                fields.insert(field.to_string(), value);
+            }
+        }
+        if let Some(done) = group.filter(|done| results.len() < limit && Self::matches_filters(done, filters)) {
+            results.push(done);
+        }
+        
+        self.groups_visited.fetch_add(visited, Ordering::Relaxed);
+        Ok(results)
+    }
+    
+    fn apply_vector_search(&self, groups: Vec<SearchGroup>, 
+                           opts: &VectorSearchOptions) -> Result<Vec<SearchGroup>> {
+        let query_norm = lane_sum(&opts.query, &opts.query, |x, y| x * y).sqrt();
+        let score = |vector: &[f32], unit: bool| match opts.metric {
+            Metric::Cosine => {
+                let norm = if unit { 1.0 } else { lane_sum(vector, vector, |x, y| x * y).sqrt() };
+                if norm == 0.0 || query_norm == 0.0 {
+                    0.0
+                } else {
+                    lane_sum(vector, &opts.query, |x, y| x * y) / (norm * query_norm)
+                }
+            }
+            Metric::Dot => lane_sum(vector, &opts.query, |x, y| x * y),
+            Metric::Euclidean => lane_sum(vector, &opts.query, |x, y| (x - y) * (x - y)).sqrt(),
+        };
+        
+        // Calculate metric values; cosine and dot are higher-is-better, euclidean lower-is-better
+        let mut values: Vec<Option<f32>> = vec![None; groups.len()];
+        {
+            let vectors = self.vectors_locked()?;
+            
+            // Resolve each group's vector to a sidecar row, then scan rows in file order
+            let mut rows = Vec::new();
+            for (i, group) in groups.iter().enumerate() {
+                let path = format!("{}/{}", group.0, opts.field);
+                match vectors.index.get(&path) {
+                    Some(&row) => rows.push((row, i)),
+                    None => {
+                        // Not in the sidecar (other width): parse the stored value instead
+                        if let Some(Some(Ok(vector))) = group.1.get(&opts.field).map(|v| decode_vector(v)) {
+                            if vector.data.len() == opts.query.len() {
+                                values[i] = Some(score(&vector.data, vector.unit));
+                            }
+                        }
+                    }
+                }
+            }
+            
+            if vectors.dims == opts.query.len() {
+                rows.sort_unstable();
+                for (row, i) in rows {
+                    values[i] = Some(score(vectors.row(row), vectors.rows[row].unit));
+                }
+            }
+        }
+        
+        let mut scored: Vec<(f32, SearchGroup)> = groups.into_iter()
+            .zip(values)
+            .filter_map(|(group, value)| value.map(|v| (v, group)))
+            .filter(|(value, _)| opts.threshold.is_none_or(|t| opts.metric.within(*value, t)))
+            .collect();
+        
+        // Sort best first, breaking ties by group key so results are stable
+        scored.sort_by(|a, b| {
+            let ord = if opts.metric.higher_is_better() { b.0.partial_cmp(&a.0) } else { a.0.partial_cmp(&b.0) };
+            ord.unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.1.0.cmp(&b.1.0))
+        });
+        
+        // Extract groups and store scores in metadata; _vector_score is always higher-is-better
+        Ok(scored.into_iter().map(|(value, mut group)| {
+            if opts.metric == Metric::Euclidean {
+                group.1.insert("_vector_distance".to_string(), value.to_string());
+                group.1.insert("_vector_score".to_string(), (1.0 / (1.0 + value)).to_string());
+            } else {
+                group.1.insert("_vector_score".to_string(), value.to_string());
+            }
+            group
+        }).collect())
+    }
+    
+    fn apply_text_search(groups: Vec<SearchGroup>, 
+                        opts: &TextSearchOptions) -> Vec<SearchGroup> {
+        let case_sensitive = opts.case_sensitive.unwrap_or(false);
+        // Default keeps the original behavior: substring match ignoring case, whole tokens otherwise
+        let anchor = opts.anchor.unwrap_or(if case_sensitive { TextAnchor::Exact } else { TextAnchor::Contains });
+        let normalize = |text: &str| if case_sensitive { text.to_string() } else { fold_case(text) };
+        
+        let query_tokens = Self::tokenize(&normalize(&opts.query));
+        if query_tokens.is_empty() {
+            return Vec::new();
+        }
+        
+        let field_weight = |field: &str| {
+            opts.field_weights.as_ref()
+                .and_then(|weights| weights.get(field))
+                .map_or(1.0, |w| w.max(0.0))
+        };
+        let total_weight: f32 = opts.fields.iter().map(|f| field_weight(f)).sum();
+        if total_weight <= 0.0 {
+            return Vec::new();
+        }
+        
+        groups.into_iter().filter_map(|mut group| {
+            // Each field contributes its weight scaled by the share of query tokens it matched
+            let mut weighted = 0.0f32;
+            for field in &opts.fields {
+                if let Some(value) = group.1.get(field) {
+                    let value = normalize(value);
+                    let matched = query_tokens.iter()
+                        .filter(|token| Self::text_matches(&value, token, anchor))
+                        .count();
+                    weighted += field_weight(field) * matched as f32 / query_tokens.len() as f32;
+                }
+            }
+            
+            if weighted > 0.0 {
+                let normalized_score = weighted / total_weight;
+                group.1.insert("_text_score".to_string(), normalized_score.to_string());
+                Some(group)
+            } else {
+                None
+            }
+        }).collect()
+    }
+    
+    fn text_matches(value: &str, token: &str, anchor: TextAnchor) -> bool {
+        match anchor {
+            TextAnchor::Contains => value.contains(token),
+            TextAnchor::Prefix => Self::tokenize(value).iter().any(|t| t.starts_with(token)),
+            TextAnchor::Exact => Self::tokenize(value).iter().any(|t| t == token),
+        }
+    }
+    
+    // Order by a score field, best first, breaking ties by group key so results are stable
+    fn sort_by_score(groups: &mut [SearchGroup], score_field: &str) {
+        let score = |group: &SearchGroup| {
+            group.1.get(score_field).and_then(|s| s.parse::<f32>().ok()).unwrap_or(0.0)
+        };
+        groups.sort_by(|a, b| {
+            score(b).partial_cmp(&score(a))
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| a.0.cmp(&b.0))
+        });
+    }
+    
+    fn apply_scoring(groups: Vec<SearchGroup>, 
+                    scoring: &ScoringWeights) -> Vec<SearchGroup> {
+        // Calculate combined scores
+        let mut scored: Vec<SearchGroup> = groups
+            .into_iter()
+            .map(|mut group| {
+                let mut total_score = 0.0;
+                
+                // Vector score
+                if let Some(vec_score) = group.1.get("_vector_score") {
+                    if let Ok(score) = vec_score.parse::<f32>() {
+                        total_score += score * scoring.vector;
+                    }
+                }
+                
+                // Text score (already normalized to 0..1)
+                if let Some(text_score) = group.1.get("_text_score") {
+                    if let Ok(score) = text_score.parse::<f32>() {
+                        total_score += score * scoring.text;
+                    }
+                }
+                
+                group.1.insert("_score".to_string(), total_score.to_string());
+                group
+            })
+            .collect();
+        
+        // Sort by combined score
+        Self::sort_by_score(&mut scored, "_score");
+        scored
+    }
+    
+    fn tokenize(text: &str) -> Vec<String> {
+        text.split_whitespace()
+            .map(|s| s.trim_matches(|c: char| !c.is_alphanumeric()))
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect()
+    }
+    
+    fn group_by_subroot(entries: Vec<(String, String)>, pattern: &str) -> Vec<SearchGroup> {
+        let depth = pattern.matches('/').count() + 1;
+        let mut groups_map: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+        
+        for (key, value) in entries {
+            if let Some((group_key, field)) = Self::split_group(&key, depth) {
+                groups_map.entry(group_key.to_string())
+                    .or_default()
+                    .insert(field.to_string(), value);
+            }
+        }
+        
+        groups_map.into_iter().collect()
+    }
+    
+    // Entries in key order into at most limit groups for get_pattern_grouped. A group's keys
+    // are adjacent in any collation, since they share the group's path.
+    fn group_matches(entries: Vec<(String, String)>, depth: usize, limit: usize) -> Result<Vec<PatternGroup>> {
+        if depth == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "group_depth must be at least 1").into());
+        }
+        let mut groups: Vec<PatternGroup> = Vec::new();
+        for (key, value) in entries {
+            let Some((group_key, field)) = Self::split_group(&key, depth) else { continue };
+            let count = groups.len();
+            match groups.last_mut() {
+                Some((current, fields)) if current == group_key => fields.push((field.to_string(), value)),
+                _ if count == limit => break,
+                _ => groups.push((group_key.to_string(), vec![(field.to_string(), value)])),
+            }
+        }
+        Ok(groups)
+    }
+    
+    // A key's first depth segments, its group, and the rest, its field. None for a key
+    // with no field part.
+    fn split_group(key: &str, depth: usize) -> Option<(&str, &str)> {
+        let (slash, _) = key.match_indices('/').nth(depth - 1)?;
+        let field = &key[slash + 1..];
+        (!field.is_empty()).then(|| (&key[..slash], field))
+    }
+    
+    fn matches_filters(group: &SearchGroup, filters: &[SearchFilter]) -> bool {
+        let (_group_key, fields) = group;
+        
+        for filter in filters {
+            let field_value = match fields.get(&filter.field) {
+                Some(v) => v,
+                None => return false, // Field doesn't exist
+            };
+            
+            // Decode the value to compare properly
+            let decoded = Self::decode_value(field_value);
+            
+            if !Self::compare_values(&decoded, filter) {
+                return false;
+            }
+        }
+        
+        true
+    }
+    
+    /// A Number filter whose own value isn't a number could never match, so that is
+    /// the caller's mistake rather than a miss.
+    fn check_filters(filters: &[SearchFilter]) -> Result<()> {
+        for filter in filters.iter().filter(|f| f.value_type == ValueType::Number) {
+            let values = match &filter.op {
+                FilterOp::In(values) => values.as_slice(),
+                FilterOp::Contains => continue,
+                _ => std::slice::from_ref(&filter.value),
+            };
+            if let Some(bad) = values.iter().find(|v| Self::parse_number(v).is_none()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("Filter on '{}' compares numbers but '{}' is not one", filter.field, bad)).into());
+            }
+        }
+        Ok(())
+    }
+    
+    // NaN and infinities parse as f64 but aren't what anyone means by a number here
+    fn parse_number(s: &str) -> Option<f64> {
+        s.trim().parse::<f64>().ok().filter(|n| n.is_finite())
+    }
+    
+    fn decode_value(encoded: &str) -> String {
+        // Handle type prefixes (s:, n:, b:, z:)
+        if encoded.len() > 2 && &encoded[1..2] == ":" {
+            return encoded[2..].to_string();
+        }
+        encoded.to_string()
+    }
+    
+    fn compare_values(field_value: &str, filter: &SearchFilter) -> bool {
+        use std::cmp::Ordering;
+        
+        // None means the filter fails: a Number field that doesn't parse
+        let ordering = |target: &str| -> Option<Ordering> {
+            match filter.value_type {
+                ValueType::String => Some(field_value.cmp(target)),
+                ValueType::Number => Self::parse_number(field_value)?.partial_cmp(&Self::parse_number(target)?),
+                ValueType::Auto => match (Self::parse_number(field_value), Self::parse_number(target)) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b),
+                    _ => Some(field_value.cmp(target)),
+                },
+            }
+        };
+        
+        match &filter.op {
+            FilterOp::Eq => ordering(&filter.value) == Some(Ordering::Equal),
+            FilterOp::Ne => matches!(ordering(&filter.value), Some(Ordering::Less | Ordering::Greater)),
+            FilterOp::Gt => ordering(&filter.value) == Some(Ordering::Greater),
+            FilterOp::Gte => matches!(ordering(&filter.value), Some(Ordering::Greater | Ordering::Equal)),
+            FilterOp::Lt => ordering(&filter.value) == Some(Ordering::Less),
+            FilterOp::Lte => matches!(ordering(&filter.value), Some(Ordering::Less | Ordering::Equal)),
+            FilterOp::In(values) => values.iter().any(|v| ordering(v) == Some(Ordering::Equal)),
+            FilterOp::Contains => {
+                (filter.value_type != ValueType::Number || Self::parse_number(field_value).is_some())
+                    && field_value.contains(filter.value.as_str())
+            }
+        }
+    }
+}
+
+/// Stops a scan running on another thread: the *_cancellable methods check it between
+/// blocks and return WalDbError::Cancelled once it trips. Clones share one flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+    
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+    
+    /// A token sharing this one's flag that also trips once timeout has passed
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        CancellationToken {
+            cancelled: Arc::clone(&self.cancelled),
+            deadline: Some(Instant::now() + timeout),
+        }
+    }
+    
+    fn check(cancel: Option<&CancellationToken>) -> Result<()> {
+        match cancel {
+            Some(token) if token.is_cancelled() => Err(WalDbError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Runs operations submitted from one thread on others in submission order, for callers
+/// like the Node binding that hand each call to a thread of its own and would otherwise see
+/// those threads race for the store lock. A read's turn comes once every write submitted
+/// before it has finished, a write's once everything submitted before it has; reads between
+/// two writes run concurrently.
+#[derive(Debug, Default)]
+pub struct SubmissionOrder {
+    state: Mutex<SubmissionState>,
+    turn: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct SubmissionState {
+    next: u64,
+    pending: BTreeMap<u64, Access>,  // Tickets not yet dropped
+}
+
+/// What a SubmissionOrder ticket does to the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// A place in a SubmissionOrder, given up when dropped
+#[derive(Debug)]
+pub struct Ticket {
+    order: Arc<SubmissionOrder>,
+    id: u64,
+    access: Access,
+}
+
+impl SubmissionOrder {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+    
+    /// Take the next place in line. Call this on the submitting thread, before handing the
+    /// operation off; submitting is what fixes its order.
+    pub fn submit(self: &Arc<Self>, access: Access) -> Ticket {
+        // Held only for map updates, never over the operation, so a poisoned lock is still sound
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let id = state.next;
+        state.next += 1;
+        state.pending.insert(id, access);
+        Ticket { order: Arc::clone(self), id, access }
+    }
+}
+
+impl Ticket {
+    /// Block until every operation this one has to follow has finished
+    pub fn wait(&self) {
+        let mut state = self.order.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            let mut earlier = state.pending.range(..self.id).map(|(_, access)| *access);
+            let blocked = match self.access {
+                Access::Read => earlier.any(|access| access == Access::Write),
+                Access::Write => earlier.next().is_some(),
+            };
+            if !blocked {
+                return;
+            }
+            state = self.order.turn.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.order.state.lock().unwrap_or_else(|e| e.into_inner()).pending.remove(&self.id);
+        self.order.turn.notify_all();
+    }
+}
+
+/// The key-value surface shared by Store, Namespace and StoreHandle, so code can be written
+/// once for any of them. Store's inherent methods behave identically; see them for details.
+pub trait Keyspace {
+    fn get(&self, path: &str) -> Result<Option<String>>;
+    fn get_with_budget(&self, path: &str, budget: ReadBudget) -> Result<Option<String>>;
+    fn contains(&self, path: &str) -> Result<bool>;
+    fn may_contain(&self, path: &str) -> bool;
+    fn has_live_children(&self, path: &str) -> Result<bool>;
+    fn set(&self, path: &str, value: &str, replace_subtree: bool) -> Result<u64>;
+    fn set_if_changed(&self, path: &str, value: &str) -> Result<bool>;
+    fn set_with_options(&self, path: &str, value: &str, options: SetOptions) -> Result<u64>;
+    fn increment(&self, path: &str, delta: i64) -> Result<i64>;
+    fn delete(&self, path: &str) -> Result<u64>;
+    fn delete_subtree(&self, prefix: &str) -> Result<u64>;
+    fn push(&self, prefix: &str, value: &str) -> Result<String>;
+    fn pop_min(&self, prefix: &str) -> Result<Option<(String, String)>>;
+    fn peek_min(&self, prefix: &str) -> Result<Option<(String, String)>>;
+    fn queue_len(&self, prefix: &str, limit: usize) -> Result<usize>;
+    fn clear_all(&self) -> Result<u64>;
+    fn set_many(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>) -> Result<u64>;
+    fn set_many_if_version(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>, expected_version: u64) -> Result<bool>;
+    fn subtree_version(&self, prefix: &str) -> Result<u64>;
+    fn write(&self, batch: WriteBatch) -> Result<u64>;
+    fn move_subtree(&self, from: &str, to: &str) -> Result<usize>;
+    fn copy_subtree(&self, from: &str, to: &str, mode: CopyMode) -> Result<usize>;
+    fn get_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>>;
+    fn get_range_limit(&self, start: &str, end: &str, limit: usize) -> Result<Vec<(String, String)>>;
+    fn get_range_limit_cancellable(&self, start: &str, end: &str, limit: usize, cancel: &CancellationToken) -> Result<Vec<(String, String)>>;
+    fn scan_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<(String, String)>>;
+    fn scan_prefix_cancellable(&self, prefix: &str, limit: usize, cancel: &CancellationToken) -> Result<Vec<(String, String)>>;
+    fn scan_prefix_iter(&self, prefix: &str) -> Result<RangeIter>;
+    fn get_pattern(&self, pattern: &str) -> Result<Vec<(String, String)>>;
+    fn get_pattern_cancellable(&self, pattern: &str, cancel: &CancellationToken) -> Result<Vec<(String, String)>>;
+    fn get_pattern_grouped(&self, pattern: &str, group_depth: usize, limit: usize) -> Result<Vec<PatternGroup>>;
+    fn delete_pattern(&self, pattern: &str) -> Result<usize>;
+    fn delete_prefix(&self, prefix: &str) -> Result<usize>;
+    fn get_subtree_json(&self, prefix: &str) -> Result<Option<String>>;
+    fn get_subtree_json_with_options(&self, prefix: &str, options: JsonOptions) -> Result<Option<SubtreeJson>>;
+    fn set_file(&self, path: &str, data: &[u8]) -> Result<()>;
+    fn get_file(&self, path: &str) -> Result<Vec<u8>>;
+    fn delete_file(&self, path: &str) -> Result<()>;
+    fn set_vector(&self, path: &str, vector: Vec<f32>, normalize: bool) -> Result<()>;
+    fn get_vector(&self, path: &str) -> Result<Option<Vec<f32>>>;
+    fn search(&self, options: SearchOptions) -> Result<Vec<SearchGroup>>;
+    fn search_cancellable(&self, options: SearchOptions, cancel: &CancellationToken) -> Result<Vec<SearchGroup>>;
+    fn flush(&self) -> Result<()>;
+    fn wait_durable(&self, seq: u64) -> Result<()>;
+}
+
+impl Keyspace for Store {
+    fn get(&self, path: &str) -> Result<Option<String>> { Store::get(self, path) }
+    fn get_with_budget(&self, path: &str, budget: ReadBudget) -> Result<Option<String>> { Store::get_with_budget(self, path, budget) }
+    fn contains(&self, path: &str) -> Result<bool> { Store::contains(self, path) }
+    fn may_contain(&self, path: &str) -> bool { Store::may_contain(self, path) }
+    fn has_live_children(&self, path: &str) -> Result<bool> { Store::has_live_children(self, path) }
+    fn set(&self, path: &str, value: &str, replace_subtree: bool) -> Result<u64> { Store::set(self, path, value, replace_subtree) }
+    fn set_if_changed(&self, path: &str, value: &str) -> Result<bool> { Store::set_if_changed(self, path, value) }
+    fn set_with_options(&self, path: &str, value: &str, options: SetOptions) -> Result<u64> {
+        Store::set_with_options(self, path, value, options)
+    }
+    fn increment(&self, path: &str, delta: i64) -> Result<i64> { Store::increment(self, path, delta) }
+    fn delete(&self, path: &str) -> Result<u64> { Store::delete(self, path) }
+    fn delete_subtree(&self, prefix: &str) -> Result<u64> { Store::delete_subtree(self, prefix) }
+    fn push(&self, prefix: &str, value: &str) -> Result<String> { Store::push(self, prefix, value) }
+    fn pop_min(&self, prefix: &str) -> Result<Option<(String, String)>> { Store::pop_min(self, prefix) }
+    fn peek_min(&self, prefix: &str) -> Result<Option<(String, String)>> { Store::peek_min(self, prefix) }
+    fn queue_len(&self, prefix: &str, limit: usize) -> Result<usize> { Store::queue_len(self, prefix, limit) }
+    fn clear_all(&self) -> Result<u64> { Store::clear_all(self) }
+    fn set_many(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>) -> Result<u64> {
+        Store::set_many(self, entries, replace_subtree_at)
+    }
+    fn set_many_if_version(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>, expected_version: u64) -> Result<bool> {
+        Store::set_many_if_version(self, entries, replace_subtree_at, expected_version)
+    }
+    fn subtree_version(&self, prefix: &str) -> Result<u64> { Store::subtree_version(self, prefix) }
+    fn write(&self, batch: WriteBatch) -> Result<u64> { Store::write(self, batch) }
+    fn move_subtree(&self, from: &str, to: &str) -> Result<usize> { Store::move_subtree(self, from, to) }
+    fn copy_subtree(&self, from: &str, to: &str, mode: CopyMode) -> Result<usize> { Store::copy_subtree(self, from, to, mode) }
+    fn get_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> { Store::get_range(self, start, end) }
+    fn get_range_limit(&self, start: &str, end: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        Store::get_range_limit(self, start, end, limit)
+    }
+    fn get_range_limit_cancellable(&self, start: &str, end: &str, limit: usize, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        Store::get_range_limit_cancellable(self, start, end, limit, cancel)
+    }
+    fn scan_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<(String, String)>> { Store::scan_prefix(self, prefix, limit) }
+    fn scan_prefix_cancellable(&self, prefix: &str, limit: usize, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        Store::scan_prefix_cancellable(self, prefix, limit, cancel)
+    }
+    fn scan_prefix_iter(&self, prefix: &str) -> Result<RangeIter> { Store::scan_prefix_iter(self, prefix) }
+    fn get_pattern(&self, pattern: &str) -> Result<Vec<(String, String)>> { Store::get_pattern(self, pattern) }
+    fn get_pattern_cancellable(&self, pattern: &str, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        Store::get_pattern_cancellable(self, pattern, cancel)
+    }
+    fn get_pattern_grouped(&self, pattern: &str, group_depth: usize, limit: usize) -> Result<Vec<PatternGroup>> {
+        Store::get_pattern_grouped(self, pattern, group_depth, limit)
+    }
+    fn delete_pattern(&self, pattern: &str) -> Result<usize> { Store::delete_pattern(self, pattern) }
+    fn delete_prefix(&self, prefix: &str) -> Result<usize> { Store::delete_prefix(self, prefix) }
+    fn get_subtree_json(&self, prefix: &str) -> Result<Option<String>> { Store::get_subtree_json(self, prefix) }
+    fn get_subtree_json_with_options(&self, prefix: &str, options: JsonOptions) -> Result<Option<SubtreeJson>> {
+        Store::get_subtree_json_with_options(self, prefix, options)
+    }
+    fn set_file(&self, path: &str, data: &[u8]) -> Result<()> { Store::set_file(self, path, data) }
+    fn get_file(&self, path: &str) -> Result<Vec<u8>> { Store::get_file(self, path) }
+    fn delete_file(&self, path: &str) -> Result<()> { Store::delete_file(self, path) }
+    fn set_vector(&self, path: &str, vector: Vec<f32>, normalize: bool) -> Result<()> {
+        Store::set_vector(self, path, vector, normalize)
+    }
+    fn get_vector(&self, path: &str) -> Result<Option<Vec<f32>>> { Store::get_vector(self, path) }
+    fn search(&self, options: SearchOptions) -> Result<Vec<SearchGroup>> { Store::search(self, options) }
+    fn search_cancellable(&self, options: SearchOptions, cancel: &CancellationToken) -> Result<Vec<SearchGroup>> {
+        Store::search_cancellable(self, options, cancel)
+    }
+    fn flush(&self) -> Result<()> { Store::flush(self) }
+    fn wait_durable(&self, seq: u64) -> Result<()> { Store::wait_durable(self, seq) }
+}
+
+/// A keyspace inside a Store, from Store::namespace. Every key is stored under a reserved
+/// prefix that Store's own write methods refuse, so a namespace can't collide with another
+/// or with plain keys, and its delete_subtree("") or clear_all empties it. Store's reads
+/// are not filtered and see namespaced keys with the prefix.
+#[derive(Debug, Clone)]
+pub struct Namespace<'a> {
+    store: &'a Store,
+    prefix: String,  // From namespace_prefix, ending in '/'
+}
+
+impl Namespace<'_> {
+    /// The name this handle was opened with
+    pub fn name(&self) -> String {
+        percent_unescape(&self.prefix[1..self.prefix.len() - 1])
+    }
+    
+    fn key(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path)
+    }
+    
+    fn local(&self, key: String) -> String {
+        match key.strip_prefix(&self.prefix) {
+            Some(local) => local.to_string(),
+            None => key,
+        }
+    }
+    
+    fn local_entries(&self, entries: Vec<(String, String)>) -> Vec<(String, String)> {
+        entries.into_iter().map(|(key, value)| (self.local(key), value)).collect()
+    }
+    
+    // Errors name the path the caller passed, not the stored one
+    fn local_error(&self, error: WalDbError) -> WalDbError {
+        match error {
+            WalDbError::TreeSemantics { path, ancestor } => WalDbError::TreeSemantics {
+                path: self.local(path),
+                ancestor: ancestor.map(|ancestor| self.local(ancestor)),
+            },
+            WalDbError::NotAnInteger { path, value } => WalDbError::NotAnInteger { path: self.local(path), value },
+            WalDbError::AlreadyExists { path } => WalDbError::AlreadyExists { path: self.local(path) },
+            WalDbError::BudgetExceeded { path, segments_probed, block_reads, candidate } => WalDbError::BudgetExceeded {
+                path: self.local(path),
+                segments_probed,
+                block_reads,
+                candidate,
+            },
+            error => error,
+        }
+    }
+}
+
+impl Keyspace for Namespace<'_> {
+    fn get(&self, path: &str) -> Result<Option<String>> {
+        self.store.get(&self.key(path)).map_err(|e| self.local_error(e))
+    }
+    
+    fn get_with_budget(&self, path: &str, budget: ReadBudget) -> Result<Option<String>> {
+        self.store.get_with_budget(&self.key(path), budget).map_err(|e| self.local_error(e))
+    }
+    
+    fn contains(&self, path: &str) -> Result<bool> {
+        self.store.contains(&self.key(path)).map_err(|e| self.local_error(e))
+    }
+    
+    fn may_contain(&self, path: &str) -> bool {
+        self.store.may_contain(&self.key(path))
+    }
+    
+    fn has_live_children(&self, path: &str) -> Result<bool> {
+        self.store.has_live_children(&self.key(path))
+    }
+    
+    fn set(&self, path: &str, value: &str, replace_subtree: bool) -> Result<u64> {
+        self.store.set_unchecked(&self.key(path), value, replace_subtree).map_err(|e| self.local_error(e))
+    }
+    
+    fn set_if_changed(&self, path: &str, value: &str) -> Result<bool> {
+        let (_, written) = self.store.set_with(&self.key(path), value, SetOptions::default(), true).map_err(|e| self.local_error(e))?;
+        Ok(written)
+    }
+    
+    fn set_with_options(&self, path: &str, value: &str, options: SetOptions) -> Result<u64> {
+        let skip_identical = self.store.skip_identical_sets;
+        Ok(self.store.set_with(&self.key(path), value, options, skip_identical).map_err(|e| self.local_error(e))?.0)
+    }
+    
+    fn increment(&self, path: &str, delta: i64) -> Result<i64> {
+        self.store.increment_unchecked(&self.key(path), delta).map_err(|e| self.local_error(e))
+    }
+    
+    fn delete(&self, path: &str) -> Result<u64> {
+        self.store.delete_unchecked(&self.key(path))
+    }
+    
+    fn push(&self, prefix: &str, value: &str) -> Result<String> {
+        self.store.push_unchecked(&self.key(prefix), value).map(|key| self.local(key)).map_err(|e| self.local_error(e))
+    }
+    
+    fn pop_min(&self, prefix: &str) -> Result<Option<(String, String)>> {
+        let entry = self.store.pop_min_unchecked(&self.key(prefix)).map_err(|e| self.local_error(e))?;
+        Ok(entry.map(|(key, value)| (self.local(key), value)))
+    }
+    
+    fn peek_min(&self, prefix: &str) -> Result<Option<(String, String)>> {
+        let entry = self.store.peek_min(&self.key(prefix))?;
+        Ok(entry.map(|(key, value)| (self.local(key), value)))
+    }
+    
+    fn queue_len(&self, prefix: &str, limit: usize) -> Result<usize> {
+        self.store.queue_len(&self.key(prefix), limit)
+    }
+    
+    fn delete_subtree(&self, prefix: &str) -> Result<u64> {
+        self.store.delete_subtree_unchecked(&self.key(prefix))
+    }
+    
+    // Just this namespace, as delete_subtree("") is
+    fn clear_all(&self) -> Result<u64> {
+        self.store.delete_subtree_unchecked(&self.prefix)
+    }
+    
+    fn set_many(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>) -> Result<u64> {
+        let entries = entries.into_iter().map(|(key, value)| (self.key(&key), value)).collect();
+        let replace_subtree_at = replace_subtree_at.map(|path| self.key(path));
+        self.store.set_many_unchecked(entries, replace_subtree_at.as_deref()).map_err(|e| self.local_error(e))
+    }
+    
+    // Without a replace the version checked is the whole namespace's
+    fn set_many_if_version(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>, expected_version: u64) -> Result<bool> {
+        let entries = entries.into_iter().map(|(key, value)| (self.key(&key), value)).collect();
+        let replace_subtree_at = replace_subtree_at.map(|path| self.key(path));
+        let versioned = replace_subtree_at.as_deref().unwrap_or(&self.prefix);
+        self.store.set_many_if_version_unchecked(entries, replace_subtree_at.as_deref(), versioned, expected_version)
+            .map_err(|e| self.local_error(e))
+    }
+    
+    fn subtree_version(&self, prefix: &str) -> Result<u64> {
+        self.store.subtree_version(&self.key(prefix))
+    }
+    
+    fn write(&self, batch: WriteBatch) -> Result<u64> {
+        let mut prefixed = WriteBatch::new();
+        for (kind, _, key, value) in batch_records(&batch.buf[BATCH_HEADER..]) {
+            prefixed.push(kind, &self.key(&key), value.as_deref());
+        }
+        self.store.write_unchecked(prefixed).map_err(|e| self.local_error(e))
+    }
+    
+    fn move_subtree(&self, from: &str, to: &str) -> Result<usize> {
+        if from.trim_end_matches('/').is_empty() || to.trim_end_matches('/').is_empty() {
+            return Err(WalDbError::InvalidKey { reason: "Cannot move to or from the root".to_string() });
+        }
+        self.store.move_subtree_unchecked(&self.key(from), &self.key(to)).map_err(|e| self.local_error(e))
+    }
+    
+    fn copy_subtree(&self, from: &str, to: &str, mode: CopyMode) -> Result<usize> {
+        if from.trim_end_matches('/').is_empty() || to.trim_end_matches('/').is_empty() {
+            return Err(WalDbError::InvalidKey { reason: "Cannot copy to or from the root".to_string() });
+        }
+        self.store.copy_subtree_unchecked(&self.key(from), &self.key(to), mode).map_err(|e| self.local_error(e))
+    }
+    
+    fn get_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        Ok(self.local_entries(self.store.get_range(&self.key(start), &self.key(end))?))
+    }
+    
+    fn get_range_limit(&self, start: &str, end: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        Ok(self.local_entries(self.store.get_range_limit(&self.key(start), &self.key(end), limit)?))
+    }
+    
+    fn get_range_limit_cancellable(&self, start: &str, end: &str, limit: usize, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        Ok(self.local_entries(self.store.get_range_limit_cancellable(&self.key(start), &self.key(end), limit, cancel)?))
+    }
+    
+    fn scan_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        Ok(self.local_entries(self.store.scan_prefix(&self.key(prefix), limit).map_err(|e| self.local_error(e))?))
+    }
+    
+    fn scan_prefix_cancellable(&self, prefix: &str, limit: usize, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        Ok(self.local_entries(self.store.scan_prefix_cancellable(&self.key(prefix), limit, cancel).map_err(|e| self.local_error(e))?))
+    }
+    
+    fn scan_prefix_iter(&self, prefix: &str) -> Result<RangeIter> {
+        let mut iter = self.store.scan_prefix_iter(&self.key(prefix)).map_err(|e| self.local_error(e))?;
+        iter.strip = self.prefix.len();
+        Ok(iter)
+    }
+    
+    fn get_pattern(&self, pattern: &str) -> Result<Vec<(String, String)>> {
+        Ok(self.local_entries(self.store.get_pattern(&self.key(pattern))?))
+    }
+    
+    fn get_pattern_cancellable(&self, pattern: &str, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        Ok(self.local_entries(self.store.get_pattern_cancellable(&self.key(pattern), cancel)?))
+    }
+    
+    // Grouped by local keys, so group_depth doesn't count the namespace's own segments
+    fn get_pattern_grouped(&self, pattern: &str, group_depth: usize, limit: usize) -> Result<Vec<PatternGroup>> {
+        Store::group_matches(self.local_entries(self.store.get_pattern(&self.key(pattern))?), group_depth, limit)
+    }
+    
+    fn delete_pattern(&self, pattern: &str) -> Result<usize> {
+        self.store.delete_matching(&self.key(pattern), true, false, None, &mut |_| {})
+    }
+    
+    fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        self.store.delete_matching(&self.key(prefix), false, false, None, &mut |_| {})
+    }
+    
+    fn get_subtree_json(&self, prefix: &str) -> Result<Option<String>> {
+        self.store.get_subtree_json(&self.key(prefix))
+    }
+    
+    fn get_subtree_json_with_options(&self, prefix: &str, options: JsonOptions) -> Result<Option<SubtreeJson>> {
+        let tree = self.store.get_subtree_json_with_options(&self.key(prefix), options)?;
+        Ok(tree.map(|tree| SubtreeJson {
+            json: tree.json,
+            conflicts: tree.conflicts.into_iter().map(|path| self.local(path)).collect(),
+        }))
+    }
+    
+    fn set_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.store.set_file_unchecked(&self.key(path), data).map_err(|e| self.local_error(e))
+    }
+    
+    fn get_file(&self, path: &str) -> Result<Vec<u8>> {
+        self.store.get_file(&self.key(path))
+    }
+    
+    fn delete_file(&self, path: &str) -> Result<()> {
+        self.store.delete_file_unchecked(&self.key(path))
+    }
+    
+    fn set_vector(&self, path: &str, vector: Vec<f32>, normalize: bool) -> Result<()> {
+        self.store.set_unchecked(&self.key(path), &encode_vector(&vector, normalize), false).map_err(|e| self.local_error(e))?;
+        Ok(())
+    }
+    
+    fn get_vector(&self, path: &str) -> Result<Option<Vec<f32>>> {
+        self.store.get_vector(&self.key(path))
+    }
+    
+    fn search(&self, mut options: SearchOptions) -> Result<Vec<SearchGroup>> {
+        options.pattern = self.key(&options.pattern);
+        let groups = self.store.search(options)?;
+        Ok(groups.into_iter().map(|(key, fields)| (self.local(key), fields)).collect())
+    }
+    
+    fn search_cancellable(&self, mut options: SearchOptions, cancel: &CancellationToken) -> Result<Vec<SearchGroup>> {
+        options.pattern = self.key(&options.pattern);
+        let groups = self.store.search_cancellable(options, cancel)?;
+        Ok(groups.into_iter().map(|(key, fields)| (self.local(key), fields)).collect())
+    }
+    
+    fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+    
+    fn wait_durable(&self, seq: u64) -> Result<()> {
+        self.store.wait_durable(seq)
+    }
+}
+
+/// A Store that can be replaced while in use, to switch to the copy Store::rebuild_into
+/// makes without restarting. Each Keyspace call runs against the store current when it
+/// began: swap waits for the calls running on the old store to finish, and calls after it
+/// go to the new one. Clones share the slot.
+#[derive(Debug, Clone)]
+pub struct StoreHandle {
+    slot: Arc<RwLock<Arc<Store>>>,
+}
+
+impl StoreHandle {
+    pub fn new(store: Store) -> Self {
+        StoreHandle { slot: Arc::new(RwLock::new(Arc::new(store))) }
+    }
+    
+    /// The store calls go to now, for what Keyspace doesn't cover. A swap doesn't wait for
+    /// calls made through it.
+    pub fn current(&self) -> Arc<Store> {
+        // The slot only ever holds a whole Arc, so a poisoned lock still guards a good one
+        self.slot.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+    
+    /// Send calls to store from now on, once the calls under way finish. Returns the old one.
+    pub fn swap(&self, store: Store) -> Arc<Store> {
+        let mut slot = self.slot.write().unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut *slot, Arc::new(store))
+    }
+    
+    /// Rebuild the current store into dest and swap to the copy. In CatchUp mode the final
+    /// catch-up runs once the calls under way have finished, with new ones waiting for the
+    /// swap, and the old store refuses writes from then on. Returns the old store, whose
+    /// directory can be deleted once it is dropped.
+    pub fn rebuild(&self, dest: &Path, options: RebuildOptions) -> Result<Arc<Store>> {
+        let old = self.current();
+        let mode = options.mode;
+        let rebuilt = old.rebuild_into(dest, options)?;
+        
+        let mut slot = self.slot.write().map_err(|e| WalDbError::Locked(format!("Store slot lock poisoned: {}", e)))?;
+        if mode == RebuildMode::CatchUp {
+            // Refused first, so nothing written outside the handle slips past the catch-up
+            {
+                let _inner = old.write_lock()?;
+                old.read_only.store(true, Ordering::SeqCst);
+            }
+            if let Err(e) = rebuilt.catch_up_from(&old) {
+                old.read_only.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+        *slot = Arc::new(rebuilt);
+        Ok(old)
+    }
+    
+    fn with<T>(&self, op: impl FnOnce(&Store) -> Result<T>) -> Result<T> {
+        let store = self.slot.read().map_err(|e| WalDbError::Locked(format!("Store slot lock poisoned: {}", e)))?;
+        op(&store)
+    }
+}
+
+impl Keyspace for StoreHandle {
+    fn get(&self, path: &str) -> Result<Option<String>> { self.with(|store| store.get(path)) }
+    fn get_with_budget(&self, path: &str, budget: ReadBudget) -> Result<Option<String>> {
+        self.with(|store| store.get_with_budget(path, budget))
+    }
+    fn contains(&self, path: &str) -> Result<bool> { self.with(|store| store.contains(path)) }
+    fn may_contain(&self, path: &str) -> bool { self.with(|store| Ok(store.may_contain(path))).unwrap_or(true) }
+    fn has_live_children(&self, path: &str) -> Result<bool> { self.with(|store| store.has_live_children(path)) }
+    fn set(&self, path: &str, value: &str, replace_subtree: bool) -> Result<u64> {
+        self.with(|store| store.set(path, value, replace_subtree))
+    }
+    fn set_if_changed(&self, path: &str, value: &str) -> Result<bool> { self.with(|store| store.set_if_changed(path, value)) }
+    fn set_with_options(&self, path: &str, value: &str, options: SetOptions) -> Result<u64> {
+        self.with(|store| store.set_with_options(path, value, options))
+    }
+    fn increment(&self, path: &str, delta: i64) -> Result<i64> { self.with(|store| store.increment(path, delta)) }
+    fn delete(&self, path: &str) -> Result<u64> { self.with(|store| store.delete(path)) }
+    fn delete_subtree(&self, prefix: &str) -> Result<u64> { self.with(|store| store.delete_subtree(prefix)) }
+    fn push(&self, prefix: &str, value: &str) -> Result<String> { self.with(|store| store.push(prefix, value)) }
+    fn pop_min(&self, prefix: &str) -> Result<Option<(String, String)>> { self.with(|store| store.pop_min(prefix)) }
+    fn peek_min(&self, prefix: &str) -> Result<Option<(String, String)>> { self.with(|store| store.peek_min(prefix)) }
+    fn queue_len(&self, prefix: &str, limit: usize) -> Result<usize> { self.with(|store| store.queue_len(prefix, limit)) }
+    fn clear_all(&self) -> Result<u64> { self.with(|store| store.clear_all()) }
+    fn set_many(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>) -> Result<u64> {
+        self.with(|store| store.set_many(entries, replace_subtree_at))
+    }
+    fn set_many_if_version(&self, entries: Vec<(String, String)>, replace_subtree_at: Option<&str>, expected_version: u64) -> Result<bool> {
+        self.with(|store| store.set_many_if_version(entries, replace_subtree_at, expected_version))
+    }
+    fn subtree_version(&self, prefix: &str) -> Result<u64> { self.with(|store| store.subtree_version(prefix)) }
+    fn write(&self, batch: WriteBatch) -> Result<u64> { self.with(|store| store.write(batch)) }
+    fn move_subtree(&self, from: &str, to: &str) -> Result<usize> { self.with(|store| store.move_subtree(from, to)) }
+    fn copy_subtree(&self, from: &str, to: &str, mode: CopyMode) -> Result<usize> { self.with(|store| store.copy_subtree(from, to, mode)) }
+    fn get_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> { self.with(|store| store.get_range(start, end)) }
+    fn get_range_limit(&self, start: &str, end: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        self.with(|store| store.get_range_limit(start, end, limit))
+    }
+    fn get_range_limit_cancellable(&self, start: &str, end: &str, limit: usize, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        self.with(|store| store.get_range_limit_cancellable(start, end, limit, cancel))
+    }
+    fn scan_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<(String, String)>> { self.with(|store| store.scan_prefix(prefix, limit)) }
+    fn scan_prefix_cancellable(&self, prefix: &str, limit: usize, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        self.with(|store| store.scan_prefix_cancellable(prefix, limit, cancel))
+    }
+    fn scan_prefix_iter(&self, prefix: &str) -> Result<RangeIter> { self.with(|store| store.scan_prefix_iter(prefix)) }
+    fn get_pattern(&self, pattern: &str) -> Result<Vec<(String, String)>> { self.with(|store| store.get_pattern(pattern)) }
+    fn get_pattern_cancellable(&self, pattern: &str, cancel: &CancellationToken) -> Result<Vec<(String, String)>> {
+        self.with(|store| store.get_pattern_cancellable(pattern, cancel))
+    }
+    fn get_pattern_grouped(&self, pattern: &str, group_depth: usize, limit: usize) -> Result<Vec<PatternGroup>> {
+        self.with(|store| store.get_pattern_grouped(pattern, group_depth, limit))
+    }
+    fn delete_pattern(&self, pattern: &str) -> Result<usize> { self.with(|store| store.delete_pattern(pattern)) }
+    fn delete_prefix(&self, prefix: &str) -> Result<usize> { self.with(|store| store.delete_prefix(prefix)) }
+    fn get_subtree_json(&self, prefix: &str) -> Result<Option<String>> { self.with(|store| store.get_subtree_json(prefix)) }
+    fn get_subtree_json_with_options(&self, prefix: &str, options: JsonOptions) -> Result<Option<SubtreeJson>> {
+        self.with(|store| store.get_subtree_json_with_options(prefix, options))
+    }
+    fn set_file(&self, path: &str, data: &[u8]) -> Result<()> { self.with(|store| store.set_file(path, data)) }
+    fn get_file(&self, path: &str) -> Result<Vec<u8>> { self.with(|store| store.get_file(path)) }
+    fn delete_file(&self, path: &str) -> Result<()> { self.with(|store| store.delete_file(path)) }
+    fn set_vector(&self, path: &str, vector: Vec<f32>, normalize: bool) -> Result<()> {
+        self.with(|store| store.set_vector(path, vector, normalize))
+    }
+    fn get_vector(&self, path: &str) -> Result<Option<Vec<f32>>> { self.with(|store| store.get_vector(path)) }
+    fn search(&self, options: SearchOptions) -> Result<Vec<SearchGroup>> { self.with(|store| store.search(options)) }
+    fn search_cancellable(&self, options: SearchOptions, cancel: &CancellationToken) -> Result<Vec<SearchGroup>> {
+        self.with(|store| store.search_cancellable(options, cancel))
+    }
+    fn flush(&self) -> Result<()> { self.with(|store| store.flush()) }
+    fn wait_durable(&self, seq: u64) -> Result<()> { self.with(|store| store.wait_durable(seq)) }
+}
+
+// A record from get_range_raw, tombstones included
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawEntry {
+    pub key: String,
+    pub seq: u64,
+    pub state: RawState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawState {
+    Live(String),
+    PointDeleted,
+    SubtreeDeleted(String),  // Everything under this prefix, reported once per tombstone
+}
+
+/// How Store::sync_digest splits a prefix into buckets. Both stores must use the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncGranularity {
+    /// One bucket per child of the prefix, holding the child and everything under it
+    #[default]
+    Children,
+    /// One bucket per distinct first n chars of the key after the prefix
+    KeyChars(usize),
+}
+
+impl SyncGranularity {
+    // The part of key naming its bucket; key starts with prefix
+    fn bucket<'a>(self, prefix: &str, key: &'a str) -> &'a str {
+        let rest = &key[prefix.len()..];
+        let len = match self {
+            SyncGranularity::Children => {
+                let skip = usize::from(rest.starts_with('/'));
+                rest[skip..].find('/').map_or(rest.len(), |slash| skip + slash)
+            }
+            SyncGranularity::KeyChars(n) => rest.char_indices().nth(n).map_or(rest.len(), |(i, _)| i),
+        };
+        &key[..prefix.len() + len]
+    }
+    
+    // As named in an export_diff header
+    fn name(self) -> String {
+        match self {
+            SyncGranularity::Children => "children".to_string(),
+            SyncGranularity::KeyChars(n) => format!("chars:{}", n),
+        }
+    }
+    
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "children" => Some(SyncGranularity::Children),
+            _ => name.strip_prefix("chars:")?.parse().ok().map(SyncGranularity::KeyChars),
+        }
+    }
+}
+
+/// Buckets whose contents differ between this store and a remote digest, from
+/// Store::diff_against, for export_diff
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffPlan {
+    pub prefix: String,
+    pub granularity: SyncGranularity,
+    /// On both sides with different contents
+    pub changed: Vec<String>,
+    /// Only in this store
+    pub local_only: Vec<String>,
+    /// Only in the remote store; applying the diff empties them
+    pub remote_only: Vec<String>,
+}
+
+impl DiffPlan {
+    /// The stores already agree under the prefix
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.local_only.is_empty() && self.remote_only.is_empty()
+    }
+}
+
+/// Options for Store::get_subtree_json_with_options
+#[derive(Debug, Clone, Default)]
+pub struct JsonOptions {
+    /// Emit values that are canonical JSON integers, floats, booleans or null unquoted.
+    /// Off by default, so every value comes back as the exact string that was stored.
+    pub detect_types: bool,
+}
+
+/// Changes under a prefix gathered over one window, from Store::subscribe_coalesced. Apply
+/// deleted_subtrees first, then changes: a key written after its subtree was deleted shows up
+/// in both, while one written before is folded into the deletion.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoalescedBatch {
+    /// Latest value of each changed key, None if its last change deleted it
+    pub changes: BTreeMap<String, Option<String>>,
+    /// Subtrees deleted in the window, as prefixes ending in '/', oldest first
+    pub deleted_subtrees: Vec<String>,
+}
+
+/// Options for Store::set_with_options
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SetOptions {
+    /// Delete the path's value and all its children before writing, as set(.., true) does.
+    pub replace_subtree: bool,
+    /// If the parent holds a scalar, delete that value so the path can go under it, instead
+    /// of failing with TreeSemantics. The parent's other children are left alone.
+    pub convert_scalar_parent: bool,
+}
+
+/// What Store::copy_subtree does with a key that already holds a value at the destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMode {
+    /// Copy nothing and fail with WalDbError::AlreadyExists
+    Fail,
+    /// Keep the existing value and copy the rest
+    Skip,
+    /// Delete the destination and everything under it first
+    Replace,
+}
+
+/// Options for Store::export
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportOptions {
+    /// Also write the keys the store keeps for itself, which start with \u{1}. For debugging:
+    /// apply_diff and the write methods refuse them, so they can't be imported back.
+    pub include_system: bool,
+}
+
+/// Options for Store::export_snapshot
+#[derive(Debug, Clone)]
+pub struct SnapshotOptions {
+    /// Only keys under this prefix, as scan_prefix_iter reads them. Empty for the whole store.
+    pub prefix: String,
+    /// Entries per chunk, each chunk closed by a line with its CRC32. Default 10,000.
+    pub chunk_entries: usize,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        SnapshotOptions { prefix: String::new(), chunk_entries: SNAPSHOT_CHUNK }
+    }
+}
+
+/// Options for Store::import
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImportOptions {
+    /// Check the chunks before this one without applying them: the chunk a failed import's
+    /// WalDbError::SnapshotCorrupt named, once the file is repaired.
+    pub resume_from_chunk: usize,
+}
+
+/// What a snapshot's header and trailer say, from Store::export_snapshot and
+/// Store::verify_export_file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    /// The store the snapshot was taken from
+    pub store_id: StoreId,
+    pub prefix: String,
+    /// The store's history_floor when the snapshot was taken
+    pub seq_floor: u64,
+    /// The store's seq when the snapshot was taken: no later write is in it
+    pub seq_ceiling: u64,
+    pub chunks: usize,
+    pub entries: usize,
+    /// Chained xxhash of every line before the trailer
+    pub digest: u64,
+}
+
+/// From Store::import
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub snapshot: SnapshotInfo,
+    /// Chunks written this time, leaving out those before resume_from_chunk
+    pub chunks_applied: usize,
+    pub entries_applied: usize,
+}
+
+/// Options for Store::dump_segment
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DumpOptions {
+    /// Print each record's value, not just its length.
+    pub values: bool,
+    /// Print keys and values as hex instead of escaped text.
+    pub hex: bool,
+}
+
+/// Options for Store::health_check
+#[derive(Debug, Clone)]
+pub struct HealthCheckOptions {
+    /// Also set, read back and delete a probe key among the store's system keys, which
+    /// takes a seq and a WAL record like any write.
+    pub write_probe: bool,
+    /// Time budget for the whole call; checks not yet started when it runs out are skipped.
+    pub max_duration: Duration,
+}
+
+impl Default for HealthCheckOptions {
+    fn default() -> Self {
+        HealthCheckOptions { write_probe: false, max_duration: Duration::from_secs(1) }
+    }
+}
+
+/// What Store::health_check found, one entry per check in the order they ran
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HealthReport {
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    /// Every check ran and passed
+    pub fn healthy(&self) -> bool {
+        self.checks.iter().all(|check| check.status == HealthStatus::Passed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub name: &'static str,  // memtable_read, write_probe, wal_buffer, wal_flusher or block_cache
+    pub status: HealthStatus,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthStatus {
+    Passed,
+    Failed(String),  // What went wrong
+    Skipped,  // Out of time
+}
+
+/// A subtree rendered by Store::get_subtree_json_with_options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubtreeJson {
+    pub json: String,
+    /// Paths that hold a scalar and also have children, in key order
+    pub conflicts: Vec<String>,
+}
+
+// Subtree being assembled for get_subtree_json; BTreeMap keeps the output in key order
+enum JsonNode {
+    Value(String),
+    Object(BTreeMap<String, JsonNode>),
+}
+
+impl JsonNode {
+    // Place the value at relative (a /-separated path under root). Keys arrive in order, so
+    // a scalar is always seen before the children that turn it into an object.
+    fn insert(map: &mut BTreeMap<String, JsonNode>, root: &str, relative: &str, value: String, conflicts: &mut Vec<String>) {
+        let mut map = map;
+        let mut parts = relative.split('/').peekable();
+        let mut walked = 0;
+        while let Some(part) = parts.next() {
+            walked += part.len() + 1;
+            if parts.peek().is_none() {
+                if let std::collections::btree_map::Entry::Vacant(slot) = map.entry(part.to_string()) {
+                    slot.insert(JsonNode::Value(value));
+                } else {
+                    conflicts.push(join_path(root, relative));
+                }
+                return;
+            }
+            
+            let child = map.entry(part.to_string()).or_insert_with(|| JsonNode::Object(BTreeMap::new()));
+            if let JsonNode::Value(_) = child {
+                conflicts.push(join_path(root, &relative[..walked - 1]));
+                *child = JsonNode::Object(BTreeMap::new());
+            }
+            map = match child {
+                JsonNode::Object(children) => children,
+                JsonNode::Value(_) => unreachable!("replaced by an object above"),
+            };
+        }
+    }
+    
+    fn write(&self, out: &mut String, options: &JsonOptions) {
+        match self {
+            JsonNode::Value(value) if options.detect_types && is_canonical_json_literal(value) => out.push_str(value),
+            JsonNode::Value(value) => push_json_string(out, value),
+            JsonNode::Object(children) => {
+                out.push('{');
+                for (i, (key, child)) in children.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    push_json_string(out, key);
+                    out.push(':');
+                    child.write(out, options);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// Live entries of a range in key order, from Store::range_iter or scan_prefix_iter, or in
+/// reverse key order from range_iter_rev or scan_prefix_iter_rev. Reads the store as of its
+/// creation: the memtable's share of the range is copied then, and the segments are pinned
+/// and read a block at a time. After an error it yields nothing more.
+#[derive(Debug)]
+pub struct RangeIter {
+    sources: Vec<RangeSource>,  // The memtable copy, then L0 to the archive level
+    start: String,
+    end: String,
+    keep_prefix: Option<String>,  // A numeric-aware prefix scan reads the parent and keeps keys under this
+    strip: usize,  // Leading bytes cut from each key, a namespace's prefix
+    hide_system: bool,  // Skip system keys, unless an export asked for them
+    collation: Collation,
+    subtombs: HashMap<String, u64>,
+    cache: Arc<BlockCache>,
+    values: Arc<Mutex<ValueLog>>,
+    value_cache: Arc<ValueCache>,
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,  // Store::open_snapshots, where seq is counted until drop
+    segment_registry: Arc<SegmentRegistry>,  // Swept on drop, deleting the files only this iterator still held
+    seq: u64,
+    reverse: bool,  // Largest key first, reading each segment's blocks from the end
+    lossy: bool,  // StoreOptions::lossy_reads
+    failed: bool,
+}
+
+// Records in [start, end) from the memtable copy or one segment, decoded a block at a time
+#[derive(Debug)]
+struct RangeSource {
+    segment: Option<Arc<Segment>>,
+    next_block: usize,  // Reading in reverse, one past the next block to decode
+    records: VecDeque<(String, u8, String, u64)>,  // Key, record type, value, seq
+}
+
+impl RangeSource {
+    // Decode blocks until one yields a record in range, and no newer than seq, or the segment
+    // passes end
+    fn fill(&mut self, cache: &BlockCache, start: &str, end: &str, seq: u64, lossy: bool) -> Result<()> {
+        let Some(seg) = &self.segment else { return Ok(()) };
+        let collation = seg.collation;
+        while self.records.is_empty() && self.next_block < seg.index.len() {
+            let idx = self.next_block;
+            self.next_block += 1;
+            if collation.compare(seg.index.key(idx), end.as_bytes()).is_ge() {
+                self.next_block = seg.index.len();
+                break;
+            }
+            
+            let (offset, len) = seg.block_span(idx);
+            let block_data = cache.get_or_load(seg, offset, len, BlockAccess::Scan)?;
+            let mut reader = BlockReader::new(seg, &block_data, offset);
+            while let Some(record) = reader.next_record() {
+                if collation.compare(record.key, end.as_bytes()).is_ge() {
+                    self.next_block = seg.index.len();
+                    break;
+                }
+                if collation.compare(record.key, start.as_bytes()).is_ge()
+                    && record.seq <= seq
+                    && matches!(record.rec_type, RT_SET | RT_SET_REF | RT_DEL_POINT) {
+                    self.records.push_back((
+                        record.key_text(seg, lossy)?.into_owned(),
+                        record.rec_type,
+                        record.value_text(seg, lossy)?.into_owned(),
+                        record.seq,
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    // fill for a reverse scan: blocks from next_block back, each one's records in range
+    // queued largest key first, until one yields a record or a block starts before start
+    fn fill_rev(&mut self, cache: &BlockCache, start: &str, end: &str, seq: u64, lossy: bool) -> Result<()> {
+        let Some(seg) = &self.segment else { return Ok(()) };
+        let collation = seg.collation;
+        while self.records.is_empty() && self.next_block > 0 {
+            self.next_block -= 1;
+            let idx = self.next_block;
+            if collation.compare(seg.index.key(idx), start.as_bytes()).is_lt() {
+                self.next_block = 0;
+            }
+            
+            let (offset, len) = seg.block_span(idx);
+            let block_data = cache.get_or_load(seg, offset, len, BlockAccess::Scan)?;
+            let mut reader = BlockReader::new(seg, &block_data, offset);
+            while let Some(record) = reader.next_record() {
+                if collation.compare(record.key, end.as_bytes()).is_ge() {
+                    break;
+                }
+                if collation.compare(record.key, start.as_bytes()).is_ge()
+                    && record.seq <= seq
+                    && matches!(record.rec_type, RT_SET | RT_SET_REF | RT_DEL_POINT) {
+                    self.records.push_front((
+                        record.key_text(seg, lossy)?.into_owned(),
+                        record.rec_type,
+                        record.value_text(seg, lossy)?.into_owned(),
+                        record.seq,
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RangeIter {
+    // The same range from end back to start. Only for an iterator nothing has been read from.
+    fn reversed(mut self) -> Self {
+        self.reverse = true;
+        for source in &mut self.sources {
+            match &source.segment {
+                None => source.records.make_contiguous().reverse(),
+                // Every block that starts before end
+                Some(segment) => source.next_block = match segment.index.search(self.end.as_bytes(), segment.collation) {
+                    Ok(i) | Err(i) => i,
+                },
+            }
+        }
+        self
+    }
+    
+    // Merge the sources' heads: the smallest key goes next (the largest in reverse), newest
+    // record wins. Comes with the record's seq, which rebuild_into keeps.
+    fn advance(&mut self) -> Result<Option<(String, String, u64)>> {
+        loop {
+            for source in &mut self.sources {
+                if source.records.is_empty() && self.reverse {
+                    source.fill_rev(&self.cache, &self.start, &self.end, self.seq, self.lossy)?;
+                } else if source.records.is_empty() {
+                    source.fill(&self.cache, &self.start, &self.end, self.seq, self.lossy)?;
+                }
+            }
+            
+            let (collation, reverse) = (self.collation, self.reverse);
+            let Some(key) = self.sources.iter()
+                .filter_map(|source| source.records.front().map(|(key, ..)| key))
+                .min_by(|a, b| {
+                    let order = collation.compare(a.as_bytes(), b.as_bytes());
+                    if reverse { order.reverse() } else { order }
+                })
+                .cloned() else {
+                return Ok(None);
+            };
+            
+            // A source holds each key once, as flushes and merges keep only its newest record
+            let mut newest: Option<(u8, String, u64)> = None;
+            for source in &mut self.sources {
+                if source.records.front().is_some_and(|(k, ..)| *k == key) {
+                    let (_, rec_type, value, seq) = source.records.pop_front().expect("Checked non-empty above");
+                    if newest.as_ref().is_none_or(|(_, _, newest_seq)| seq > *newest_seq) {
+                        newest = Some((rec_type, value, seq));
+                    }
+                }
+            }
+            let Some((rec_type, value, seq)) = newest else { continue };
+            
+            let hidden = rec_type == RT_DEL_POINT
+                || (self.hide_system && is_system_key(&key))
+                || self.keep_prefix.as_ref().is_some_and(|prefix| !key.starts_with(prefix.as_str()))
+                || self.subtombs.iter().any(|(prefix, &tomb_seq)| key.starts_with(prefix.as_str()) && tomb_seq >= seq);
+            if hidden {
+                continue;
+            }
+            let value = match rec_type {
+                RT_SET_REF => self.value_cache.resolve(&self.values, value.as_bytes())?,
+                _ => value,
+            };
+            return Ok(Some((key[self.strip..].to_string(), value, seq)));
+        }
+    }
+}
+
+impl Iterator for RangeIter {
+    type Item = Result<(String, String)>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.advance() {
+            Ok(entry) => entry.map(|(key, value, _)| Ok((key, value))),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Drop for RangeIter {
+    fn drop(&mut self) {
+        if let Ok(mut snapshots) = self.snapshots.lock() {
+            if let Some(count) = snapshots.get_mut(&self.seq) {
+                *count -= 1;
+                if *count == 0 {
+                    snapshots.remove(&self.seq);
+                }
+            }
+        }
+        // A file that fails to go now is reported by the compaction thread's next sweep
+        self.sources.clear();
+        let _ = self.segment_registry.sweep();
+    }
+}
+
+/// The store as of a past seq, from Store::as_of. Reads see the writes up to that seq and
+/// none after; it holds the segments and WAL records it reads from, so nothing written or
+/// compacted meanwhile changes what it returns.
+#[derive(Debug)]
+pub struct Snapshot<'a> {
+    store: &'a Store,
+    seq: u64,
+    memtable: BTreeMap<CollatedKey, MemValue>,  // Each key's newest WAL record after the segments' writes
+    subtombs: HashMap<String, u64>,  // Each prefix's newest subtree delete
+    segments: Vec<Arc<Segment>>,  // L0 to the archive level
+}
+
+impl Snapshot<'_> {
+    /// The seq this snapshot reads as of
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+    
+    /// The value path held as of the snapshot's seq
+    pub fn get(&self, path: &str) -> Result<Option<String>> {
+        let mut newest = self.memtable.get(&(path, self.store.collation) as &dyn KeyView).map(|record| match record {
+            MemValue::Scalar(value, seq) => (RT_SET, value.to_string(), *seq),
+            MemValue::PointTomb(seq) => (RT_DEL_POINT, String::new(), *seq),
+        });
+        for seg in &self.segments {
+            if !seg.may_hold(path) || seg.bloom.as_ref().is_some_and(|bloom| !bloom.might_contain(path)) {
+                continue;
+            }
+            if let Some(record) = self.store.get_from_segment(seg, path)? {
+                if record.2 <= self.seq && newest.as_ref().is_none_or(|(_, _, seq)| record.2 > *seq) {
+                    newest = Some(record);
+                }
+            }
+        }
+        
+        match newest {
+            Some((_, _, seq)) if self.subtombs.iter().any(|(prefix, &tomb_seq)| path.starts_with(prefix.as_str()) && tomb_seq >= seq) => Ok(None),
+            Some((RT_SET, value, _)) => Ok(Some(value)),
+            Some((RT_SET_REF, hash, _)) => self.store.resolve_value_ref(hash.as_bytes()).map(Some),
+            _ => Ok(None),
+        }
+    }
+    
+    /// Entries in [start, end) as of the snapshot's seq, as Store::get_range
+    pub fn get_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        self.range_iter(start, end)?.collect()
+    }
+    
+    /// Entries in [start, end) as of the snapshot's seq, read as the iterator advances
+    pub fn range_iter(&self, start: &str, end: &str) -> Result<RangeIter> {
+        let memtable = if self.store.collation.compare(start.as_bytes(), end.as_bytes()).is_lt() {
+            let (low, high) = ((start, self.store.collation), (end, self.store.collation));
+            self.memtable.range::<dyn KeyView, _>((Bound::Included(&low as &dyn KeyView), Bound::Excluded(&high as &dyn KeyView))).collect()
+        } else {
+            Vec::new()
+        };
+        self.store.range_iter_over(memtable, self.segments.iter(), self.subtombs.clone(), self.seq, (start, end), None)
+    }
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut snapshots) = self.store.open_snapshots.lock() {
+            if let Some(count) = snapshots.get_mut(&self.seq) {
+                *count -= 1;
+                if *count == 0 {
+                    snapshots.remove(&self.seq);
+                }
+            }
+        }
+        // As RangeIter does
+        self.segments.clear();
+        let _ = self.store.segment_registry.sweep();
+    }
+}
+
+/// Ops for Store::write, encoded as they are added so a large import holds only its bytes.
+/// Each op takes the next seq after the batch's base seq, so later ops win over earlier ones
+/// on the same key and a delete_subtree shadows only the ops before it.
+#[derive(Debug, Clone)]
+pub struct WriteBatch {
+    buf: Vec<u8>,  // The WAL frame, header filled in by Store::write
+    ops: usize,
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        let mut buf = vec![0u8; BATCH_HEADER];
+        buf[12] = RT_BATCH;
+        WriteBatch { buf, ops: 0 }
+    }
+    
+    pub fn put(&mut self, key: &str, value: &str) -> &mut Self {
+        self.push(RT_SET, key, Some(value));
+        self
+    }
+    
+    pub fn delete(&mut self, key: &str) -> &mut Self {
+        self.push(RT_DEL_POINT, key, None);
+        self
+    }
+    
+    pub fn delete_subtree(&mut self, prefix: &str) -> &mut Self {
+        if prefix.ends_with('/') {
+            self.push(RT_DEL_SUB, prefix, None);
+        } else {
+            self.push(RT_DEL_SUB, &format!("{}/", prefix), None);
+        }
+        self
+    }
+    
+    pub fn len(&self) -> usize {
+        self.ops
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.ops == 0
+    }
+    
+    // Record layout: kind, seq offset u32, key length u32, key, [value length u32, value]
+    fn push(&mut self, kind: u8, key: &str, value: Option<&str>) {
+        self.buf.push(kind);
+        self.buf.extend_from_slice(&(self.ops as u32).to_le_bytes());
+        self.buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(key.as_bytes());
+        if let Some(value) = value {
+            self.buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            self.buf.extend_from_slice(value.as_bytes());
+        }
+        self.ops += 1;
+    }
+}
+
+/// A search result: group key plus its fields, named relative to the group. Results come
+/// best score first, ties and unscored searches in group key order.
+pub type SearchGroup = (String, BTreeMap<String, String>);
+
+/// A get_pattern_grouped result: group key plus its matched fields, named relative to the
+/// group, in key order
+pub type PatternGroup = (String, Vec<(String, String)>);
+
+/// A search group's fields as (full key, value) pairs in key order. The group key is
+/// always followed by '/', so an empty one yields keys that start with '/'. Score fields
+/// such as _text_score come out the same way, under the group key.
+pub fn search_group_entries(group: &SearchGroup) -> Vec<(String, String)> {
+    group.1.iter().map(|(field, value)| (format!("{}/{}", group.0, field), value.clone())).collect()
+}
+
+// Search filter types
+#[derive(Debug, Clone)]
+pub struct SearchFilter {
+    pub field: String,
+    pub op: FilterOp,
+    pub value: String,
+    pub value_type: ValueType,
+}
+
+#[derive(Debug, Clone)]
+pub enum FilterOp {
+    Eq,  // ==
+    Ne,  // !=
+    Gt,  // >
+    Gte, // >=
+    Lt,  // <
+    Lte, // <=
+    In(Vec<String>),  // Equal to any of these; SearchFilter::value is unused
+    Contains,         // Field text contains the value, whatever the value_type
+}
+
+// How a filter compares field and value. A group missing the field never matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueType {
+    #[default]
+    Auto,    // Numbers when both sides parse as one, otherwise strings
+    String,  // Byte order, so "10" < "9"
+    Number,  // Numbers only; a field that doesn't parse fails the filter
+}
+
+// Advanced search options
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub pattern: String,
+    pub filters: Option<Vec<SearchFilter>>,
+    pub vector: Option<VectorSearchOptions>,
+    pub text: Option<TextSearchOptions>,
+    pub scoring: Option<ScoringWeights>,
+    pub limit: Option<usize>,
+    pub order_by: Option<SearchOrder>,  // Without vector or text search; scored results come best first
+}
+
+// Group order of a search without vector or text scoring
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SearchOrder {
+    #[default]
+    KeyAsc,
+    KeyDesc,  // Largest group key first, such as the newest of time-ordered ids
+}
+
+#[derive(Debug, Clone)]
+pub struct VectorSearchOptions {
+    pub query: Vec<f32>,
+    pub field: String,
+    pub threshold: Option<f32>,  // Minimum similarity, or maximum distance for Euclidean
+    pub metric: Metric,
+}
+
+// How stored vectors are compared against the query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Metric {
+    #[default]
+    Cosine,     // Angle only, -1..1
+    Dot,        // Raw inner product, cheapest for pre-normalized embeddings
+    Euclidean,  // L2 distance
+}
+
+impl Metric {
+    fn higher_is_better(self) -> bool {
+        self != Metric::Euclidean
+    }
+    
+    fn within(self, value: f32, threshold: f32) -> bool {
+        if self.higher_is_better() { value >= threshold } else { value <= threshold }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TextSearchOptions {
+    pub query: String,
+    pub fields: Vec<String>,
+    pub case_sensitive: Option<bool>,
+    pub anchor: Option<TextAnchor>,
+    pub field_weights: Option<HashMap<String, f32>>,  // Missing fields weigh 1.0
+}
+
+// How a query token must line up with the field text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextAnchor {
+    Prefix,    // Some word in the field starts with the token
+    Contains,  // Token appears anywhere in the field
+    Exact,     // Some word in the field equals the token
+}
+
+#[derive(Debug, Clone)]
+pub struct ScoringWeights {
+    pub vector: f32,
+    pub text: f32,
+    pub filter: f32,
+}
+
+impl Subscriber {
+    fn wants(&self, change: &Change) -> bool {
+        match change {
+            Change::Key(key, _) => key.starts_with(&self.prefix) && !key.starts_with(NAMESPACE_MARKER) && !is_system_key(key),
+            // A deletion above the prefix clears it as surely as one inside it
+            Change::Subtree(prefix) => !prefix.starts_with(NAMESPACE_MARKER) && !is_system_key(prefix)
+                && (prefix.starts_with(&self.prefix) || self.prefix.starts_with(prefix.as_str())),
+        }
+    }
+}
+
+impl CoalescedBatch {
+    // Fold a change into the batch. A subtree deletion swallows earlier changes under it,
+    // which is why deleted_subtrees apply before changes.
+    fn absorb(&mut self, change: Change) {
+        match change {
+            Change::Key(key, value) => {
+                self.changes.insert(key, value);
+            }
+            Change::Subtree(prefix) => {
+                self.changes.retain(|key, _| !key.starts_with(&prefix));
+                self.deleted_subtrees.retain(|deleted| !deleted.starts_with(&prefix));
+                self.deleted_subtrees.push(prefix);
+            }
+        }
+    }
+    
+    pub fn len(&self) -> usize {
+        self.changes.len() + self.deleted_subtrees.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.deleted_subtrees.is_empty()
+    }
+}
+
+impl StoreInner {
+    fn seq(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst)
+    }
+    
+    // Only under the write lock: sharded writes take theirs through GroupCommitWAL::append_next
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq.get_mut();
+        *seq += 1;
+        *seq
+    }
+    
+    fn set_seq(&mut self, seq: u64) {
+        *self.seq.get_mut() = seq;
+    }
+    
+    fn memtable_size(&self) -> usize {
+        self.memtable_size.load(Ordering::SeqCst)
+    }
+    
+    fn since_epoch(&self) -> Duration {
+        self.clock.monotonic().saturating_duration_since(self.epoch)
+    }
+    
+    // How long since the memtable took its first record, None while it is empty
+    fn memtable_age(&self) -> Option<Duration> {
+        match self.memtable_born.load(Ordering::SeqCst) {
+            0 => None,
+            born => Some(self.since_epoch().saturating_sub(Duration::from_nanos(born - 1))),
+        }
+    }
+    
+    // Which shard key's records go in: a hash of its first path segment, so a key's parent
+    // and children share its shard and a point write's tree checks stay inside it
+    fn shard_of(&self, key: &str) -> usize {
+        if self.memtable.len() == 1 {
+            return 0;
+        }
+        let top = key.split('/').next().unwrap_or(key);
+        xxhash(top.as_bytes(), 0) as usize & (self.memtable.len() - 1)
+    }
+    
+    // Poisoning is ignored: a shard only changes by whole inserts, so it is never left torn
+    fn shard(&self, key: &str) -> RwLockReadGuard<'_, MemShard> {
+        self.memtable[self.shard_of(key)].read().unwrap_or_else(|e| e.into_inner())
+    }
+    
+    // Every shard, locked in order. Held together they show the memtable as of one seq, since
+    // a sharded write takes its seq and inserts under its shard's lock.
+    fn shards(&self) -> Vec<RwLockReadGuard<'_, MemShard>> {
+        self.memtable.iter().map(|shard| shard.read().unwrap_or_else(|e| e.into_inner())).collect()
+    }
+    
+    fn memtable_len(&self) -> usize {
+        self.shards().iter().map(|shard| shard.entries.len()).sum()
+    }
+    
+    // Refuse a write to key, or with subtree one to every key starting with it, that would
+    // change anything under a sealed prefix
+    fn check_unsealed(&self, key: &str, subtree: bool) -> Result<()> {
+        match self.sealed.iter().find(|prefix| key.starts_with(prefix.as_str()) || (subtree && prefix.starts_with(key))) {
+            Some(prefix) => Err(WalDbError::Sealed { path: key.to_string(), prefix: prefix.clone() }),
+            None => Ok(()),
+        }
+    }
+    
+    // Insert into the memtable, charging only the size delta when the key is overwritten
+    fn memtable_insert(&mut self, key: String, value: MemValue) {
+        if !self.subscribers.is_empty() {
+            let current = match &value {
+                MemValue::Scalar(v, _) => Some(v.to_string()),
+                MemValue::PointTomb(_) => None,
+            };
+            self.notify(Change::Key(key.clone(), current));
+        }
+        let index = self.shard_of(&key);
+        let mut shard = self.memtable[index].write().unwrap_or_else(|e| e.into_inner());
+        self.shard_insert(&mut shard, key, value);
+    }
+    
+    // memtable_insert for a sharded write, holding key's shard under the read lock
+    fn shard_insert(&self, shard: &mut MemShard, key: String, value: MemValue) {
+        if let Some(negative) = &self.negative {
+            negative.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+        }
+        if matches!(value, MemValue::Scalar(..)) {
+            self.queue_heads.lower(&key, self.collation);
+        }
+        let key_len = key.len();
+        let added = value.footprint(key_len);
+        let seq = value.seq();
+        let removed = match shard.entries.insert(CollatedKey { key, collation: self.collation }, value) {
+            Some(old) => {
+                // Only the WAL has the older record now, and a flush leaves it behind
+                shard.overwritten = shard.overwritten.max(seq).max(old.seq());
+                old.footprint(key_len)
+            }
+            None => 0,
+        };
+        shard.size = shard.size + added - removed;
+        self.memtable_size.fetch_add(added, Ordering::SeqCst);
+        self.memtable_size.fetch_sub(removed, Ordering::SeqCst);
+        if self.memtable_born.load(Ordering::Relaxed) == 0 {
+            let now = self.since_epoch().as_nanos() as u64 + 1;
+            let _ = self.memtable_born.compare_exchange(0, now, Ordering::SeqCst, Ordering::Relaxed);
+        }
+    }
+    
+    // Empty every shard for clear_all. The WAL keeps the records, for as_of and for replay
+    // under the tombstone that hides them.
+    fn memtable_clear(&mut self) {
+        for shard in &mut self.memtable {
+            *shard.get_mut().unwrap_or_else(|e| e.into_inner()) = MemShard::default();
+        }
+        *self.memtable_size.get_mut() = 0;
+        *self.memtable_born.get_mut() = 0;
+    }
+    
+    fn subtomb_insert(&mut self, prefix: String, seq: u64) {
+        if !self.subscribers.is_empty() {
+            self.notify(Change::Subtree(prefix.clone()));
+        }
+        self.subtomb_add(prefix, seq);
+    }
+    
+    // subtomb_insert without telling subscribers, for a subtomb that hides no live value
+    fn subtomb_add(&mut self, prefix: String, seq: u64) {
+        // A subtree delete can only hide keys, but cached answers under it are dropped anyway
+        // rather than trusted across a change to their prefix
+        if let Some(negative) = &mut self.negative {
+            negative.get_mut().unwrap_or_else(|e| e.into_inner()).remove_prefix(&prefix);
+        }
+        let cost = prefix.len() + MEMTABLE_ENTRY_OVERHEAD;
+        if self.subtombs.insert(prefix, seq).is_none() {
+            self.subtomb_size += cost;
+        }
+    }
+    
+    // A subscriber whose aggregator has exited is dropped the next time a change reaches it
+    fn notify(&mut self, change: Change) {
+        self.subscribers.retain(|subscriber| !subscriber.wants(&change) || subscriber.sender.send(change.clone()).is_ok());
+    }
+    
+    fn subtomb_remove(&mut self, prefix: &str) {
+        if self.subtombs.remove(prefix).is_some() {
+            self.subtomb_size -= prefix.len() + MEMTABLE_ENTRY_OVERHEAD;
+        }
+    }
+    
+    // Subtombs live in the WAL and the manifest, not segments, so they replay whatever their seq
+    // Returns how many records it read, already flushed ones included, and how many bytes
+    fn replay_wal(&mut self, path: &Path, replay_after: u64, lossy: bool) -> Result<(u64, u64)> {
+        let mut records = 0;
+        let bytes = read_wal(path, lossy, |kind, seq, key, value| {
+            records += 1;
+            self.seq.fetch_max(seq, Ordering::SeqCst);
+            if seq <= replay_after && kind != RT_DEL_SUB {
+                return;
+            }
+            match (kind, value) {
+                (RT_SET, Some(value)) => self.memtable_insert(key.into_owned(), MemValue::Scalar(CompactStr::from(value.as_ref()), seq)),
+                (RT_DEL_POINT, _) => self.memtable_insert(key.into_owned(), MemValue::PointTomb(seq)),
+                (RT_DEL_SUB, _) => self.subtomb_insert(key.into_owned(), seq),
+                _ => {}
+            }
+        })?;
+        Ok((records, bytes))
+    }
+}
+
+impl GroupCommitWAL {
+    // Everything up to durable_seq was already on disk when the store opened. A log from
+    // before group frames takes the new magic before any is appended to it, so a version
+    // that can't read them passes over the whole log rather than only its newer frames.
+    fn new(path: &Path, durable_seq: u64, options: &StoreOptions, events: EventSink, clock: Arc<StoreClock>) -> io::Result<Self> {
+        if path.exists() {
+            let mut data = fs::read(path)?;
+            if data.starts_with(WAL_MAGIC_V2) {
+                data[..WAL_MAGIC.len()].copy_from_slice(WAL_MAGIC);
+                write_atomic(path, &data)?;
+            }
+        }
+        Ok(Self::with_log(path, None, true, durable_seq, options, events, clock))
+    }
+    
+    // A log kept in memory under path's name, synced by the flusher only if there is one
+    fn in_memory(path: &Path, flusher: bool, options: &StoreOptions, events: EventSink, clock: Arc<StoreClock>) -> Self {
+        Self::with_log(path, Some(Mutex::new(Vec::new())), flusher, 0, options, events, clock)
+    }
+    
+    fn with_log(path: &Path, memory: Option<Mutex<Vec<u8>>>, flusher: bool, durable_seq: u64, options: &StoreOptions, events: EventSink,
+                clock: Arc<StoreClock>) -> Self {
+        let flush_bytes = options.wal_flush_bytes.unwrap_or(WAL_FLUSH_BYTES).max(1);
+        GroupCommitWAL {
+            path: path.to_path_buf(),
+            memory,
+            flusher,
+            buffer: Mutex::new(WalBuffer::default()),
+            // sync_interval: Duration::from_millis(GROUP_COMMIT_MS),
+            shutdown: Arc::new((Mutex::new(false), Condvar::new())),
+            durable_seq: AtomicU64::new(durable_seq),
+            durable_signal: (Mutex::new(()), Condvar::new()),
+            last_pass: Mutex::new(clock.monotonic()),
+            clock,
+            paused: AtomicBool::new(false),
+            degraded: AtomicBool::new(false),
+            flush_bytes,
+            max_unflushed: options.max_unflushed_bytes.unwrap_or(WAL_MAX_UNFLUSHED).max(flush_bytes),
+            flush_wanted: AtomicBool::new(false),
+            drained: Condvar::new(),
+            throttled: AtomicU64::new(0),
+            throttle_wait_us: AtomicU64::new(0),
+            syncs: AtomicU64::new(0),
+            sync_time_us: AtomicU64::new(0),
+            io_hook: HookSlot(options.io_hook.clone()),
+            events,
+        }
+    }
+    
+    fn append(&self, entry: &WALEntry) -> io::Result<()> {
+        let mut buffer = self.reserve()?;
+        buffer.push(entry);
+        self.wake_if_full(buffer);
+        Ok(())
+    }
+    
+    // For a write that holds only the store's read lock: the seq is taken under the buffer
+    // lock, so entries still arrive in seq order. Every entry gets the one seq.
+    fn append_next(&self, seq: &AtomicU64, entries: &[WALEntry]) -> io::Result<u64> {
+        let mut buffer = self.reserve()?;
+        let next = seq.fetch_add(1, Ordering::SeqCst) + 1;
+        for entry in entries {
+            buffer.push(&WALEntry { seq: next, ..*entry });
+        }
+        self.wake_if_full(buffer);
+        Ok(next)
+    }
+    
+    // The buffer, once it holds less than max_unflushed. The wait is for the flusher, so a
+    // writer never does the IO itself while it runs; with the flusher paused or stopped the
+    // writer syncs instead, and a degraded store, whose syncs are failing, doesn't wait at all.
+    // A failed sync here is the flusher's to retry and report: the writer carries on.
+    fn reserve(&self) -> io::Result<MutexGuard<'_, WalBuffer>> {
+        check_lock_order(LockRank::WalBuffer);
+        let mut buffer = self.buffer.lock().map_err(|e| io::Error::other(format!("WAL buffer lock poisoned: {}", e)))?;
+        if buffer.data.len() < self.max_unflushed || self.degraded.load(Ordering::SeqCst) {
+            return Ok(buffer);
+        }
+        
+        let started = Instant::now();
+        while buffer.data.len() >= self.max_unflushed && !self.degraded.load(Ordering::SeqCst) {
+            drop(buffer);
+            let flusher_running = self.wake_flusher();
+            if !flusher_running {
+                let _ = self.sync_now();
+            }
+            buffer = self.buffer.lock().map_err(|e| io::Error::other(format!("WAL buffer lock poisoned: {}", e)))?;
+            if flusher_running {
+                buffer = self.drained.wait_timeout_while(buffer, Duration::from_millis(GROUP_COMMIT_MS), |buffer| buffer.data.len() >= self.max_unflushed)
+                    .map_err(|e| io::Error::other(format!("WAL buffer lock poisoned: {}", e)))?.0;
+            }
+        }
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        self.throttle_wait_us.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        Ok(buffer)
+    }
+    
+    // Past flush_bytes, ask the flusher for a pass now rather than at its next interval.
+    // Without a flusher the append is synced now, reporting a failure itself.
+    fn wake_if_full(&self, buffer: MutexGuard<'_, WalBuffer>) {
+        let full = buffer.data.len() >= self.flush_bytes;
+        drop(buffer);
+        if !self.flusher {
+            if let Err(error) = self.sync_now() {
+                self.events.emit(StoreEvent::WalSyncFailed { error, retry_in: None });
+            }
+            return;
+        }
+        if full && !self.flush_wanted.load(Ordering::SeqCst) {
+            self.wake_flusher();
+        }
+    }
+    
+    // Whether the flusher is there to answer: false once the store is shutting down or while
+    // it is paused. Never called with the buffer locked, as Drop syncs with the shutdown
+    // lock held.
+    fn wake_flusher(&self) -> bool {
+        self.flush_wanted.store(true, Ordering::SeqCst);
+        let (lock, cvar) = &*self.shutdown;
+        let shutdown = lock.lock().unwrap_or_else(|e| e.into_inner());
+        cvar.notify_all();
+        self.flusher && !*shutdown && !self.paused.load(Ordering::SeqCst)
+    }
+    
+    fn sync_now(&self) -> io::Result<()> {
+        check_lock_order(LockRank::WalBuffer);
+        let mut buffer = self.buffer.lock().map_err(|e| io::Error::other(format!("WAL buffer lock poisoned: {}", e)))?;
+        // Entries are appended under the store's write lock or take their seq here, so they
+        // arrive in seq order
+        // A degraded store has to see a sync through before it takes writes again, so one
+        // goes ahead with nothing to write
+        let last_seq = buffer.last_seq;
+        if last_seq.is_none() && !self.degraded.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        
+        self.write_buffered(&mut buffer, &[])?;
+        if let Some(last_seq) = last_seq {
+            self.mark_durable(last_seq);
+        }
+        Ok(())
+    }
+    
+    // Refuse writes from here on, telling the event handler the first time
+    fn degrade(&self, error: &io::Error) {
+        if !self.degraded.swap(true, Ordering::SeqCst) {
+            self.events.emit(StoreEvent::StorageDegraded { error: io::Error::new(error.kind(), error.to_string()) });
+        }
+    }
+    
+    // Write a pre-encoded frame behind whatever is still buffered and sync before returning
+    fn append_frame(&self, frame: &[u8], last_seq: u64) -> io::Result<()> {
+        check_lock_order(LockRank::WalBuffer);
+        let mut buffer = self.buffer.lock().map_err(|e| io::Error::other(format!("WAL buffer lock poisoned: {}", e)))?;
+        self.write_buffered(&mut buffer, frame)?;
+        self.mark_durable(last_seq);
+        Ok(())
+    }
+    
+    // Syncs hold the buffer lock, so every seq up to this one is on disk now
+    fn mark_durable(&self, seq: u64) {
+        self.durable_seq.fetch_max(seq, Ordering::SeqCst);
+        let (lock, cvar) = &self.durable_signal;
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        cvar.notify_all();
+    }
+    
+    fn wait_durable(&self, seq: u64) -> io::Result<()> {
+        let (lock, cvar) = &self.durable_signal;
+        let guard = lock.lock().map_err(|e| io::Error::other(format!("WAL durable lock poisoned: {}", e)))?;
+        let _guard = cvar.wait_while(guard, |_| self.durable_seq.load(Ordering::SeqCst) < seq)
+            .map_err(|e| io::Error::other(format!("WAL durable lock poisoned: {}", e)))?;
+        Ok(())
+    }
+    
+    // Write and fsync the buffered entries followed by frame. The buffer is only emptied once
+    // that succeeds. A failure degrades the store and a success ends that.
+    fn write_buffered(&self, buffer: &mut WalBuffer, frame: &[u8]) -> io::Result<()> {
+        if let Err(e) = self.append_to_log(buffer, frame) {
+            self.degrade(&e);
+            return Err(e);
+        }
+        
+        buffer.clear();
+        self.drained.notify_all();
+        if self.degraded.swap(false, Ordering::SeqCst) {
+            self.events.emit(StoreEvent::StorageRecovered);
+        }
+        Ok(())
+    }
+    
+    // On failure the file is cut back to where it was, so a retry doesn't leave a torn record
+    // in front of the entries it writes
+    fn append_to_log(&self, buffer: &WalBuffer, frame: &[u8]) -> io::Result<()> {
+        self.io_hook.check(WriteSite::Wal, &self.path)?;
+        if let Some(memory) = &self.memory {
+            let mut log = memory.lock().map_err(|e| io::Error::other(format!("WAL log lock poisoned: {}", e)))?;
+            if log.is_empty() {
+                log.extend_from_slice(WAL_MAGIC);
+            }
+            buffer.encode_group(&mut log);
+            log.extend_from_slice(frame);
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        create_durable(&self.path, WAL_MAGIC)?;
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        let start = file.metadata()?.len();
+        
+        let mut data = Vec::new();
+        // Only a log an older version created empty
+        if start == 0 {
+            data.extend_from_slice(WAL_MAGIC);
+        }
+        buffer.encode_group(&mut data);
+        data.extend_from_slice(frame);
+        
+        let started = Instant::now();
+        let written = file.write_all(&data).and_then(|_| file.sync_all());
+        if let Err(e) = written {
+            let _ = file.set_len(start);
+            return Err(e);
+        }
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        self.sync_time_us.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+    
+    // Replace the log with one holding only these subtree deletes, for when every other record
+    // in it is in a segment and nothing is buffered. Written beside it and renamed over it, so
+    // a crash leaves either the old log or the new one.
+    fn checkpoint(&self, subtombs: &HashMap<String, u64>) -> io::Result<()> {
+        check_lock_order(LockRank::WalBuffer);
+        let _buffer = self.buffer.lock().map_err(|e| io::Error::other(format!("WAL buffer lock poisoned: {}", e)))?;
+        let mut tombs: Vec<(&String, &u64)> = subtombs.iter().collect();
+        tombs.sort_by_key(|(_, seq)| **seq);
+        
+        let mut group = WalBuffer::default();
+        for (prefix, seq) in tombs {
+            group.push(&WALEntry { seq: *seq, kind: RT_DEL_SUB, key: prefix, value: None });
+        }
+        let mut data = WAL_MAGIC.to_vec();
+        group.encode_group(&mut data);
+        
+        match &self.memory {
+            Some(memory) => {
+                *memory.lock().map_err(|e| io::Error::other(format!("WAL log lock poisoned: {}", e)))? = data;
+                Ok(())
+            }
+            None => write_atomic(&self.path, &data),
+        }
+    }
+    
+    // Read the records synced so far, as open's replay does, returning the log's size
+    fn read(&self, lossy: bool, apply: impl FnMut(u8, u64, Cow<'_, str>, Option<Cow<'_, str>>)) -> Result<u64> {
+        let Some(memory) = &self.memory else {
+            return read_wal(&self.path, lossy, apply);
+        };
+        let log = memory.lock().map_err(|e| WalDbError::Locked(format!("WAL log lock poisoned: {}", e)))?.clone();
+        let Some(records) = log.get(WAL_MAGIC.len()..) else {
+            return Ok(0);
+        };
+        let bytes = read_wal_records(records, lossy, apply)
+            .map_err(|(offset, bad)| bad.error(&self.path, WAL_MAGIC.len() as u64 + offset))?;
+        Ok(WAL_MAGIC.len() as u64 + bytes)
+    }
+    
+    // Bytes in the log, not counting the buffer
+    fn len(&self) -> io::Result<u64> {
+        if let Some(memory) = &self.memory {
+            return Ok(memory.lock().map_err(|e| io::Error::other(format!("WAL log lock poisoned: {}", e)))?.len() as u64);
+        }
+        match fs::metadata(&self.path) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl WALEntry<'_> {
+    // Length and record, framed by the group it is synced in: seq, kind, key length and key,
+    // then value length and value if any
+    fn encode(&self, data: &mut Vec<u8>) {
+        let mut record = Vec::new();
+        record.extend_from_slice(&self.seq.to_le_bytes());
+        record.push(self.kind);
+        record.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        record.extend_from_slice(self.key.as_bytes());
+        
+        if let Some(val) = self.value {
+            record.extend_from_slice(&(val.len() as u32).to_le_bytes());
+            record.extend_from_slice(val.as_bytes());
+        }
+        
+        data.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        data.extend_from_slice(&record);
+    }
+}
+
+impl Segment {
+    // The block whose keys take in key's place, by binary search of the index. None if key
+    // sorts before the first.
+    fn block_for(&self, key: &str) -> Option<usize> {
+        match self.index.search(key.as_bytes(), self.collation) {
+            Ok(block) => Some(block),
+            Err(block) => block.checked_sub(1),
+        }
+    }
+    
+    // Files from before the collation word are in byte order. A newer file whose footer is
+    // lost is salvaged in the fallback order, the one the store was opened with.
+    fn open(path: &Path, id: u64, fallback: Collation) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        
+        // Read header
+        let mut magic_buf = [0u8; 7];
+        file.read_exact(&mut magic_buf)?;
+        let version = magic_buf.strip_prefix(MAGIC_PREFIX)
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.parse::<u32>().ok());
+        let version = match version {
+            Some(version) if version > FORMAT_VERSION => {
+                return Err(WalDbError::NewerFormat { file: path.to_path_buf(), version });
+            }
+            Some(version @ 3..) => version,
+            _ => {
+                return Err(WalDbError::Corruption {
+                    file: path.to_path_buf(),
+                    offset: 0,
+                    detail: "Bad magic".to_string(),
+                });
+            }
+        };
+        
+        let mut segment = Segment {
+            id,
+            path: path.to_path_buf(),
+            seq_high: 0,
+            bloom: None,
+            index: SegmentIndex::default(),
+            index_start: MAGIC.len() as u64,
+            front_coded: version >= FRONT_CODED_VERSION,
+            collation: if version >= COLLATION_VERSION { fallback } else { Collation::Binary },
+            archive_of: None,
+            value_refs: false,
+            salvaged: false,
+            bounds: None,
+            backing: SegmentBacking::File(Mutex::new(None)),
+        };
+        
+        if !segment.read_footer(&mut file, file_len, version)? {
+            segment.salvage(&mut file)?;
+        }
+        
+        Ok(segment)
+    }
+    
+    // Read size bytes at offset through the shared handle. Positional reads leave no cursor
+    // for threads to race on, so one handle serves them all.
+    fn read_at(&self, offset: u64, size: usize, events: &EventSink) -> io::Result<Vec<u8>> {
+        let reader = match &self.backing {
+            SegmentBacking::File(reader) => reader,
+            SegmentBacking::InMemory(bytes) => return Self::slice_at(bytes, offset, size).map(<[u8]>::to_vec),
+        };
+        let file = {
+            let mut reader = reader.lock().map_err(|e| io::Error::other(format!("Segment reader lock poisoned: {}", e)))?;
+            match &*reader {
+                Some(file) => file.clone(),
+                None => {
+                    let file = Arc::new(File::open(&self.path)?);
+                    *reader = Some(file.clone());
+                    events.emit(StoreEvent::SegmentReaderOpened { path: self.path.clone() });
+                    file
+                }
+            }
+        };
+        
+        let mut data = vec![0u8; size];
+        read_exact_at(&file, &mut data, offset)?;
+        Ok(data)
+    }
+    
+    // size bytes at offset, failing as a short read of a file would
+    fn slice_at(bytes: &[u8], offset: u64, size: usize) -> io::Result<&[u8]> {
+        usize::try_from(offset).ok()
+            .and_then(|start| bytes.get(start..start.checked_add(size)?))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"))
+    }
+    
+    // The segment front to back, for a pass that reads every block once
+    fn sequential(&self) -> io::Result<Box<dyn ReadSeek + '_>> {
+        Ok(match &self.backing {
+            SegmentBacking::File(_) => Box::new(BufReader::with_capacity(64 * 1024, File::open(&self.path)?)),
+            SegmentBacking::InMemory(bytes) => Box::new(io::Cursor::new(bytes.as_slice())),
+        })
+    }
+    
+    fn contents(&self) -> io::Result<Cow<'_, [u8]>> {
+        match &self.backing {
+            SegmentBacking::File(_) => fs::read(&self.path).map(Cow::Owned),
+            SegmentBacking::InMemory(bytes) => Ok(Cow::Borrowed(bytes)),
+        }
+    }
+    
+    fn file_len(&self) -> io::Result<u64> {
+        match &self.backing {
+            SegmentBacking::File(_) => Ok(fs::metadata(&self.path)?.len()),
+            SegmentBacking::InMemory(bytes) => Ok(bytes.len() as u64),
+        }
+    }
+    
+    // Whether key lies between the segment's smallest and largest keys. Without bounds any
+    // key might be here.
+    fn may_hold(&self, key: &str) -> bool {
+        self.bounds.as_ref().is_none_or(|(min, max)| {
+            self.collation.compare(key.as_bytes(), min.as_bytes()).is_ge() && self.collation.compare(key.as_bytes(), max.as_bytes()).is_le()
+        })
+    }
+    
+    // Whether any key in [start, end) might be here
+    fn overlaps(&self, start: &str, end: &str) -> bool {
+        self.bounds.as_ref().is_none_or(|(min, max)| {
+            self.collation.compare(max.as_bytes(), start.as_bytes()).is_ge() && self.collation.compare(min.as_bytes(), end.as_bytes()).is_lt()
+        })
+    }
+    
+    // Offset and length of the block an index entry points at
+    fn block_span(&self, idx: usize) -> (u64, usize) {
+        let offset = self.index.entries[idx].block_off;
+        let end = self.index.entries.get(idx + 1).map_or(self.index_start, |next| next.block_off);
+        (offset, (end - offset) as usize)
+    }
+    
+    // Load the index, bloom and key bounds the footer points at. False if the footer or index
+    // doesn't hold together, as when a crash cut finish() short. Newer footers put the
+    // collation code just before the classic 32 bytes, the flags word before that, and the
+    // length of the largest key, which comes just before the footer, before that.
+    fn read_footer(&mut self, file: &mut File, file_len: u64, version: u32) -> io::Result<bool> {
+        let footer_len = match version {
+            BOUNDS_VERSION.. => 44,
+            FLAGS_VERSION => 40,
+            COLLATION_VERSION => 36,
+            _ => 32,
+        };
+        if file_len < MAGIC.len() as u64 + footer_len {
+            return Ok(false);
+        }
+        
+        // Read footer from end
+        file.seek(SeekFrom::End(-(footer_len as i64)))?;
+        let mut footer = [0u8; 44];
+        let footer = &mut footer[..footer_len as usize];
+        file.read_exact(footer)?;
+        let u32_at = |at: usize| u32::from_le_bytes(footer[at..at + 4].try_into().expect("4 bytes"));
+        let flags = if version >= FLAGS_VERSION { u32_at(footer_len as usize - 40) } else { 0 };
+        let max_key_len = if version >= BOUNDS_VERSION { u32_at(0) as u64 } else { 0 };
+        let collation = if version >= COLLATION_VERSION {
+            match Collation::from_code(u32_at(footer_len as usize - 36)) {
+                Some(collation) => collation,
+                None => return Ok(false),
+            }
+        } else {
+            Collation::Binary
+        };
+        let footer = &footer[footer_len as usize - 32..];
+        let tail_len = footer_len + max_key_len;  // Everything after the bloom
+        
+        // seq_low stored in footer but not currently used
+        // let mut seq_low_bytes = [0u8; 8];
+        // seq_low_bytes.copy_from_slice(&footer[0..8]);
+        // let seq_low = u64::from_le_bytes(seq_low_bytes);
+        
+        let mut seq_high_bytes = [0u8; 8];
+        seq_high_bytes.copy_from_slice(&footer[8..16]);
+        let seq_high = u64::from_le_bytes(seq_high_bytes);
+        
+        // key_count stored in footer but not currently used
+        // let mut key_count_bytes = [0u8; 4];
+        // key_count_bytes.copy_from_slice(&footer[16..20]);
+        // let key_count = u32::from_le_bytes(key_count_bytes) as usize;
+        
+        let mut index_size_bytes = [0u8; 4];
+        index_size_bytes.copy_from_slice(&footer[20..24]);
+        let index_size = u32::from_le_bytes(index_size_bytes) as usize;
+        
+        let mut bloom_size_bytes = [0u8; 4];
+        bloom_size_bytes.copy_from_slice(&footer[24..28]);
+        let bloom_size = u32::from_le_bytes(bloom_size_bytes) as usize;
+        
+        let mut hash_count_bytes = [0u8; 4];
+        hash_count_bytes.copy_from_slice(&footer[28..32]);
+        let hash_count = u32::from_le_bytes(hash_count_bytes) as usize;
+        
+        // Calculate index start position; it can't reach back into the header
+        let index_start = match file_len.checked_sub(tail_len + index_size as u64 + bloom_size as u64) {
+            Some(start) if start >= MAGIC.len() as u64 => start,
+            _ => return Ok(false),
+        };
+        if bloom_size > 0 && !(1..=64).contains(&hash_count) {
+            return Ok(false);
+        }
+        
+        // Read bloom filter
+        let bloom = if bloom_size > 0 {
+            file.seek(SeekFrom::End(-(tail_len as i64 + bloom_size as i64)))?;
+            let mut bloom_data = vec![0u8; bloom_size];
+            file.read_exact(&mut bloom_data)?;
+            
+            Some(BloomFilter {
+                bits: bloom_data,
+                bit_count: bloom_size * 8,
+                hash_count,
+            })
+        } else {
+            None
+        };
+        
+        // Read index
+        file.seek(SeekFrom::Start(index_start))?;
+        let mut index_data = vec![0u8; index_size];
+        file.read_exact(&mut index_data)?;
+        
+        let Some(index) = SegmentIndex::parse(index_data, collation) else {
+            return Ok(false);
+        };
+        
+        // Blocks start right after the header and end where the index begins
+        let blocks_fit = match (index.entries.first(), index.entries.last()) {
+            (Some(first), Some(last)) => first.block_off == MAGIC.len() as u64 && last.block_off < index_start,
+            _ => index_start == MAGIC.len() as u64,
+        };
+        if !blocks_fit {
+            return Ok(false);
+        }
+        
+        // The smallest key is the first block's, which the index holds
+        let bounds = if version >= BOUNDS_VERSION && !index.is_empty() {
+            let mut max_key = vec![0u8; max_key_len as usize];
+            file.seek(SeekFrom::End(-(tail_len as i64)))?;
+            file.read_exact(&mut max_key)?;
+            let (Ok(min), Ok(max)) = (std::str::from_utf8(index.key(0)), String::from_utf8(max_key)) else {
+                return Ok(false);
+            };
+            if collation.compare(index.key(index.len() - 1), max.as_bytes()).is_gt() {
+                return Ok(false);
+            }
+            Some((min.to_string(), max))
+        } else {
+            None
+        };
+        
+        self.seq_high = seq_high;
+        self.bloom = bloom;
+        self.index = index;
+        self.collation = collation;
+        self.value_refs = flags & FOOTER_VALUE_REFS != 0;
+        self.index_start = index_start;  // Store for block boundary calculation
+        self.bounds = bounds;
+        Ok(true)
+    }
+    
+    // Rebuild the index and bloom from the records after the header, stopping at the first
+    // record that is cut off or out of place. Every record moves the scan forward by at
+    // least its header, so even a garbage file ends the scan at its length.
+    fn salvage(&mut self, file: &mut File) -> Result<()> {
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut data)?;
+        
+        let header = MAGIC.len();
+        let mut reader = BlockReader { data: &data[header..], base: header as u64, pos: 0, front_coded: self.front_coded, key: Vec::new() };
+        let mut bloom = BloomFilter::new(10000, 7);
+        let mut last_key: Vec<u8> = Vec::new();
+        let mut data_end = header;
+        
+        loop {
+            let offset = (header + reader.pos) as u64;
+            let Some(record) = reader.next_record() else { break };
+            let Ok(key) = std::str::from_utf8(record.key) else { break };
+            if !matches!(record.rec_type, RT_SET | RT_SET_REF | RT_DEL_POINT) || (!self.index.is_empty() && self.collation.compare(key.as_bytes(), &last_key).is_le()) {
+                break;
+            }
+            self.value_refs |= record.rec_type == RT_SET_REF;
+            
+            // Index records that carry their whole key, about a block apart
+            let block_full = self.index.entries.last().is_none_or(|entry| offset - entry.block_off >= BLOCK_SIZE as u64);
+            if record.shared == 0 && block_full {
+                self.index.push(key.as_bytes(), offset);
+            }
+            bloom.add(key);
+            self.seq_high = self.seq_high.max(record.seq);
+            last_key.clear();
+            last_key.extend_from_slice(key.as_bytes());
+            data_end = header + reader.pos;
+        }
+        
+        if self.index.is_empty() {
+            return Err(WalDbError::Corruption {
+                file: self.path.clone(),
+                offset: header as u64,
+                detail: "Segment footer is damaged and no records could be salvaged".to_string(),
+            });
+        }
+        
+        self.bloom = Some(bloom);
+        self.index_start = data_end as u64;
+        self.salvaged = true;
+        // Every key kept was checked as UTF-8 above
+        self.bounds = String::from_utf8(self.index.key(0).to_vec()).ok().zip(String::from_utf8(last_key).ok());
+        Ok(())
+    }
+    
+    // Walk the file's bytes afresh rather than trusting the parsed index, so a bad index
+    // entry shows up against the records it should point at
+    fn dump(&self, mut out: impl Write, options: DumpOptions) -> Result<usize> {
+        let data = self.contents()?;
+        let header = MAGIC.len();
+        let index_start = self.index_start as usize;
+        let show = |bytes: &[u8]| if options.hex {
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        } else {
+            format!("{:?}", String::from_utf8_lossy(bytes))
+        };
+        let mut anomalies = 0;
+        let mut flag = |out: &mut dyn Write, message: String| {
+            anomalies += 1;
+            writeln!(out, "  !! {}", message)
+        };
+        
+        writeln!(out, "segment {}", self.path.file_name().map_or_else(String::new, |n| n.to_string_lossy().into_owned()))?;
+        writeln!(out, "header: {}{}", String::from_utf8_lossy(&data[..header]), if self.front_coded { " (front coded)" } else { "" })?;
+        if self.salvaged {
+            writeln!(out, "footer: damaged, index and bloom rebuilt from the records")?;
+        } else {
+            let footer = &data[data.len() - 32..];
+            let u64_at = |at: usize| u64::from_le_bytes(footer[at..at + 8].try_into().expect("8 bytes"));
+            let u32_at = |at: usize| u32::from_le_bytes(footer[at..at + 4].try_into().expect("4 bytes"));
+            writeln!(out, "footer: seq_low={} seq_high={} key_count={} index_bytes={} bloom_bytes={} hash_count={} collation={:?}{}{}",
+                u64_at(0), u64_at(8), u32_at(16), u32_at(20), u32_at(24), u32_at(28), self.collation,
+                if self.value_refs { " value_refs" } else { "" },
+                self.bounds.as_ref().map_or_else(String::new, |(_, max)| format!(" max_key={}", show(max.as_bytes()))))?;
+        }
+        match &self.bloom {
+            Some(bloom) => {
+                let set: u32 = bloom.bits.iter().map(|b| b.count_ones()).sum();
+                writeln!(out, "bloom: {} bits, {} hashes, {} set ({:.1}%)",
+                    bloom.bit_count, bloom.hash_count, set, set as f64 * 100.0 / bloom.bit_count as f64)?;
+            }
+            None => writeln!(out, "bloom: none")?,
+        }
+        
+        let mut reader = BlockReader { data: &data[header..index_start], base: header as u64, pos: 0, front_coded: self.front_coded, key: Vec::new() };
+        let mut record_keys: HashMap<u64, Vec<u8>> = HashMap::new();
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut next_block = 0;
+        while reader.pos < reader.data.len() {
+            let offset = (header + reader.pos) as u64;
+            while self.index.entries.get(next_block).is_some_and(|entry| entry.block_off <= offset) {
+                let (block_off, len) = self.block_span(next_block);
+                writeln!(out, "block {} @{} ({} bytes)", next_block, block_off, len)?;
+                next_block += 1;
+            }
+            
+            let Some(record) = reader.next_record() else {
+                flag(&mut out, format!("unreadable record at @{}, {} bytes before the index skipped", offset, index_start as u64 - offset))?;
+                break;
+            };
+            let (seq, rec_type, shared, key, value) = (record.seq, record.rec_type, record.shared, record.key.to_vec(), record.value.to_vec());
+            let end = (header + reader.pos) as u64;
+            
+            let kind = match rec_type {
+                RT_SET => "SET".to_string(),
+                RT_SET_REF => "REF".to_string(),
+                RT_DEL_POINT => "DEL".to_string(),
+                other => format!("TYPE({})", other),
+            };
+            write!(out, "  @{} seq={} {} shared={} key={} value_len={}", offset, seq, kind, shared, show(&key), value.len())?;
+            if options.values {
+                write!(out, " value={}", show(&value))?;
+            }
+            writeln!(out)?;
+            
+            if !matches!(rec_type, RT_SET | RT_SET_REF | RT_DEL_POINT) {
+                flag(&mut out, format!("unknown record type {}", rec_type))?;
+            }
+            if let Some(last) = last_key.as_ref().filter(|last| self.collation.compare(&key, last).is_le()) {
+                flag(&mut out, format!("key out of order: {} after {}", show(&key), show(last)))?;
+            }
+            if let Some(boundary) = self.index.entries.get(next_block).map(|entry| entry.block_off).filter(|&b| b < end) {
+                flag(&mut out, format!("record runs {} bytes past the block boundary at @{}", end - boundary, boundary))?;
+            }
+            match (std::str::from_utf8(&key), &self.bloom) {
+                (Err(_), _) => flag(&mut out, "key is not valid UTF-8".to_string())?,
+                (Ok(text), Some(bloom)) if !bloom.might_contain(text) => flag(&mut out, "key missing from the bloom filter".to_string())?,
+                _ => {}
+            }
+            
+            record_keys.insert(offset, key.clone());
+            last_key = Some(key);
+        }
+        
+        if let (Some((_, max)), Some(last)) = (self.bounds.as_ref().filter(|_| !self.salvaged), &last_key) {
+            if max.as_bytes() != last.as_slice() {
+                flag(&mut out, format!("footer's largest key {} isn't the last record's {}", show(max.as_bytes()), show(last)))?;
+            }
+        }
+        
+        writeln!(out, "index: {} entries, ending blocks at @{}", self.index.len(), index_start)?;
+        for (i, entry) in self.index.entries.iter().enumerate() {
+            let key = self.index.key(i);
+            writeln!(out, "  [{}] @{} key={}", i, entry.block_off, show(key))?;
+            match record_keys.get(&entry.block_off) {
+                None => flag(&mut out, format!("index entry {} points at @{}, which doesn't start a record", i, entry.block_off))?,
+                Some(record_key) if record_key.as_slice() != key => {
+                    flag(&mut out, format!("index entry {} key doesn't match the record's {}", i, show(record_key)))?
+                }
+                Some(_) => {}
+            }
+        }
+        
+        writeln!(out, "summary: {} records, {} blocks, {} anomalies", record_keys.len(), self.index.len(), anomalies)?;
+        Ok(anomalies)
+    }
+}
+
+impl SegmentIndex {
+    // Entry table over index bytes read from a footer. None if an entry runs past the end
+    // or the keys (in the segment's collation) and offsets don't both ascend.
+    fn parse(data: Vec<u8>, collation: Collation) -> Option<Self> {
+        let mut entries: Vec<IndexEntry> = Vec::new();
+        let mut pos = 0;
+        
+        while pos < data.len() {
+            let header = data.get(pos..pos + 12)?;
+            let key_len = u32::from_le_bytes(header[0..4].try_into().ok()?);
+            let block_off = u64::from_le_bytes(header[4..12].try_into().ok()?);
+            pos += 12;
+            
+            let entry = IndexEntry { key_off: pos as u32, key_len, block_off };
+            pos = pos.checked_add(key_len as usize).filter(|&end| end <= data.len())?;
+            
+            if let Some(last) = entries.last() {
+                let key = &data[entry.key_off as usize..pos];
+                if last.block_off >= block_off || collation.compare(Self::slice(&data, last), key).is_ge() {
+                    return None;
+                }
+            }
+            entries.push(entry);
+        }
+        
+        Some(SegmentIndex { data, entries })
+    }
+    
+    fn push(&mut self, key: &[u8], block_off: u64) {
+        self.data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(&block_off.to_le_bytes());
+        self.entries.push(IndexEntry { key_off: self.data.len() as u32, key_len: key.len() as u32, block_off });
+        self.data.extend_from_slice(key);
+    }
+    
+    fn slice<'a>(data: &'a [u8], entry: &IndexEntry) -> &'a [u8] {
+        &data[entry.key_off as usize..entry.key_off as usize + entry.key_len as usize]
+    }
+    
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+    
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    
+    fn key(&self, idx: usize) -> &[u8] {
+        Self::slice(&self.data, &self.entries[idx])
+    }
+    
+    // Same contract as slice::binary_search, comparing keys in the segment's collation
+    fn search(&self, key: &[u8], collation: Collation) -> std::result::Result<usize, usize> {
+        self.entries.binary_search_by(|entry| collation.compare(Self::slice(&self.data, entry), key))
+    }
+}
+
+struct SegmentWriter {
+    file: SegmentSink,
+    path: PathBuf,
+    seq_low: u64,
+    seq_high: u64,
+    key_count: usize,
+    current_block: Vec<u8>,
+    last_key: Vec<u8>,  // Previous key in current_block, the base for front coding
+    first_key: Option<String>,  // Keys come in collation order, so this and the last are the bounds
+    index: SegmentIndex,
+    bloom: BloomFilter,
+    collation: Collation,  // Order the caller adds keys in, recorded in the footer
+    value_refs: bool,  // An RT_SET_REF was added, flagged in the footer
+    written: u64,
+    io_hook: HookSlot,  // Set for memtable flushes only
+}
+
+// Where a SegmentWriter's bytes go
+enum SegmentSink {
+    File(AtomicFile),  // Renamed into place by finish
+    InMemory(Vec<u8>),  // Store::open_in_memory: handed to the segment as its backing
+}
+
+impl Write for SegmentSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SegmentSink::File(file) => file.write(buf),
+            SegmentSink::InMemory(bytes) => bytes.write(buf),
+        }
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SegmentSink::File(file) => file.flush(),
+            SegmentSink::InMemory(_) => Ok(()),
+        }
+    }
+}
+
+impl SegmentWriter {
+    fn new(path: &Path, collation: Collation) -> io::Result<Self> {
+        Self::with_sink(SegmentSink::File(AtomicFile::create(path)?), path, collation)
+    }
+    
+    // A segment that only ever exists in memory, named path all the same
+    fn in_memory(path: &Path, collation: Collation) -> io::Result<Self> {
+        Self::with_sink(SegmentSink::InMemory(Vec::new()), path, collation)
+    }
+    
+    fn with_sink(file: SegmentSink, path: &Path, collation: Collation) -> io::Result<Self> {
+        let mut writer = SegmentWriter {
+            file,
+            path: path.to_path_buf(),
+            seq_low: u64::MAX,
+            seq_high: 0,
+            key_count: 0,
+            current_block: Vec::new(),
+            last_key: Vec::new(),
+            first_key: None,
+            index: SegmentIndex::default(),
+            bloom: BloomFilter::new(10000, 7),  // Fixed params for now
+            collation,
+            value_refs: false,
+            written: 0,
+            io_hook: HookSlot::default(),
+        };
+        
+        writer.file.write_all(MAGIC)?;
+        writer.written = MAGIC.len() as u64;
+        
+        Ok(writer)
+    }
+    
+    fn add(&mut self, rec_type: u8, key: &str, value: Option<&str>, seq: u64) -> io::Result<()> {
+        self.bloom.add(key);
+        self.value_refs |= rec_type == RT_SET_REF;
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_string());
+        }
+        
+        if seq < self.seq_low {
+            self.seq_low = seq;
+        }
+        if seq > self.seq_high {
+            self.seq_high = seq;
+        }
+        
+        let value = value.unwrap_or("");
+        let mut shared = common_prefix_len(&self.last_key, key.as_bytes());
+        if self.current_block.len() + Self::record_len(key.len() - shared, value.len()) > BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        
+        // The first record of a block holds its full key, which the index points at
+        if self.current_block.is_empty() {
+            shared = 0;
+            self.index.push(key.as_bytes(), self.written);
+        }
+        
+        // Record: seq, type, shared prefix length, suffix length, value length, suffix, value
+        let suffix = &key.as_bytes()[shared..];
+        self.current_block.extend_from_slice(&seq.to_le_bytes());
+        self.current_block.push(rec_type);
+        self.current_block.extend_from_slice(&(shared as u32).to_le_bytes());
+        self.current_block.extend_from_slice(&(suffix.len() as u32).to_le_bytes());
+        self.current_block.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.current_block.extend_from_slice(suffix);
+        self.current_block.extend_from_slice(value.as_bytes());
+        
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key.as_bytes());
+        self.key_count += 1;
+        
+        Ok(())
+    }
+    
+    fn record_len(suffix_len: usize, value_len: usize) -> usize {
+        8 + 1 + 4 + 4 + 4 + suffix_len + value_len
+    }
+    
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.current_block.is_empty() {
+            return Ok(());
+        }
+        
+        self.io_hook.check(WriteSite::Segment, &self.path)?;
+        self.file.write_all(&self.current_block)?;
+        self.written += self.current_block.len() as u64;
+        self.current_block.clear();
+        
+        Ok(())
+    }
+    
+    fn finish(mut self, id: u64) -> io::Result<Segment> {
+        self.flush_block()?;
+        
+        let index_start = self.written;
+        
+        // Write index, already in its on-disk layout
+        self.io_hook.check(WriteSite::Segment, &self.path)?;
+        self.file.write_all(&self.index.data)?;
+        
+        // Write bloom filter
+        self.file.write_all(&self.bloom.bits)?;
+        
+        // Write the largest key, then the footer. The smallest is already in the index.
+        self.file.write_all(&self.last_key)?;
+        let mut footer = Vec::new();
+        let flags = if self.value_refs { FOOTER_VALUE_REFS } else { 0 };
+        footer.extend_from_slice(&(self.last_key.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&flags.to_le_bytes());
+        footer.extend_from_slice(&self.collation.code().to_le_bytes());
+        footer.extend_from_slice(&self.seq_low.to_le_bytes());
+        footer.extend_from_slice(&self.seq_high.to_le_bytes());
+        footer.extend_from_slice(&(self.key_count as u32).to_le_bytes());
+        footer.extend_from_slice(&(self.index.data.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&(self.bloom.bits.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&(self.bloom.hash_count as u32).to_le_bytes());
+        self.file.write_all(&footer)?;
+        
+        // Only now does the file appear under its name
+        let backing = match self.file {
+            SegmentSink::File(file) => {
+                file.commit()?;
+                SegmentBacking::File(Mutex::new(None))
+            }
+            SegmentSink::InMemory(bytes) => SegmentBacking::InMemory(Arc::new(bytes)),
+        };
+        
+        Ok(Segment {
+            id,
+            path: self.path,
+            // seq_low: self.seq_low,
+            seq_high: self.seq_high,
+            // key_count: self.key_count,
+            bloom: Some(self.bloom),
+            index: self.index,
+            index_start,
+            front_coded: true,
+            collation: self.collation,
+            archive_of: None,
+            value_refs: self.value_refs,
+            salvaged: false,
+            bounds: self.first_key.map(|first| (first, String::from_utf8_lossy(&self.last_key).into_owned())),
+            backing,
+        })
+    }
+}
+
+// One record parsed out of a segment block, with its full key rebuilt if front coded
+struct BlockRecord<'a> {
+    seq: u64,
+    rec_type: u8,
+    shared: usize,  // Key bytes taken from the previous record
+    key: &'a [u8],
+    value: &'a [u8],
+    offset: u64,  // Where the record starts in its segment file
+}
+
+impl BlockRecord<'_> {
+    // The key as text. Keys are only ever written from &str, so one that isn't UTF-8 is
+    // corruption.
+    fn key_text(&self, seg: &Segment, lossy: bool) -> Result<Cow<'_, str>> {
+        read_text(self.key, lossy).ok_or_else(|| WalDbError::Corruption {
+            file: seg.path.clone(),
+            offset: self.offset,
+            detail: format!("Key of the record at offset {} of {} is not valid UTF-8", self.offset, seg.path.display()),
+        })
+    }
+    
+    fn value_text(&self, seg: &Segment, lossy: bool) -> Result<Cow<'_, str>> {
+        read_text(self.value, lossy).ok_or_else(|| WalDbError::InvalidUtf8 {
+            path: String::from_utf8_lossy(self.key).into_owned(),
+            file: seg.path.clone(),
+            offset: self.offset,
+        })
+    }
+}
+
+// Walks the records of one block. Front-coded keys are rebuilt in a buffer reused across
+// records; older blocks hand out key slices straight from the block.
+struct BlockReader<'a> {
+    data: &'a [u8],
+    base: u64,  // File offset of data
+    pos: usize,
+    front_coded: bool,
+    key: Vec<u8>,
+}
+
+impl<'a> BlockReader<'a> {
+    fn new(seg: &Segment, data: &'a [u8], base: u64) -> Self {
+        BlockReader { data, base, pos: 0, front_coded: seg.front_coded, key: Vec::new() }
+    }
+    
+    // Stops at the end of the block or at the first record that doesn't fit in it
+    fn next_record(&mut self) -> Option<BlockRecord<'_>> {
+        let data = self.data;
+        let read_u32 = |pos: usize| data.get(pos..pos + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+        
+        let seq = u64::from_le_bytes(data.get(self.pos..self.pos + 8)?.try_into().ok()?);
+        let rec_type = *data.get(self.pos + 8)?;
+        
+        if self.front_coded {
+            let shared = read_u32(self.pos + 9)?;
+            let slen = read_u32(self.pos + 13)?;
+            let vlen = read_u32(self.pos + 17)?;
+            let start = self.pos + 21;
+            let suffix = data.get(start..start + slen)?;
+            let value = data.get(start + slen..start + slen + vlen)?;
+            if shared > self.key.len() {
+                return None;
+            }
+            
+            let offset = self.base + self.pos as u64;
+            self.pos = start + slen + vlen;
+            self.key.truncate(shared);
+            self.key.extend_from_slice(suffix);
+            Some(BlockRecord { seq, rec_type, shared, key: &self.key, value, offset })
+        } else {
+            let klen = read_u32(self.pos + 9)?;
+            let vlen = read_u32(self.pos + 13)?;
+            let start = self.pos + 17;
+            let key = data.get(start..start + klen)?;
+            let value = data.get(start + klen..start + klen + vlen)?;
+            
+            let offset = self.base + self.pos as u64;
+            self.pos = start + klen + vlen;
+            Some(BlockRecord { seq, rec_type, shared: 0, key, value, offset })
+        }
+    }
+}
+
+impl BloomFilter {
+    fn new(bit_count: usize, hash_count: usize) -> Self {
+        BloomFilter {
+            bits: vec![0u8; bit_count.div_ceil(8)],
+            bit_count,
+            hash_count,
+        }
+    }
+    
+    fn add(&mut self, key: &str) {
+        for i in 0..self.hash_count {
+            let hash = xxhash(key.as_bytes(), i as u64) as usize % self.bit_count;
+            self.bits[hash / 8] |= 1 << (hash % 8);
+        }
+    }
+    
+    fn might_contain(&self, key: &str) -> bool {
+        for i in 0..self.hash_count {
+            let hash = xxhash(key.as_bytes(), i as u64) as usize % self.bit_count;
+            if self.bits[hash / 8] & (1 << (hash % 8)) == 0 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl EventSink {
+    fn emit(&self, event: StoreEvent) {
+        if let Some(on_event) = &self.0 {
+            on_event(event);
+        }
+    }
+    
+    // For events on hot paths, built only when someone is listening
+    fn emit_with(&self, event: impl FnOnce() -> StoreEvent) {
+        if let Some(on_event) = &self.0 {
+            on_event(event());
+        }
+    }
+}
+
+impl HookSlot {
+    fn check(&self, site: WriteSite, path: &Path) -> io::Result<()> {
+        match &self.0 {
+            Some(hook) => hook(site, path),
+            None => Ok(()),
+        }
+    }
+}
+
+impl IoThrottle<'_> {
+    fn consume(&mut self, bytes: u64) -> io::Result<()> {
+        self.bytes += bytes;
+        let Some(rate) = self.bytes_per_sec else {
+            return Ok(());
+        };
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        match due.checked_sub(self.started.elapsed()) {
+            Some(ahead) => self.pause(ahead),
+            None => Ok(()),
+        }
+    }
+    
+    fn pause(&self, duration: Duration) -> io::Result<()> {
+        let Some((lock, cvar)) = self.shutdown else {
+            thread::sleep(duration);
+            return Ok(());
+        };
+        let shutdown = lock.lock().map_err(|e| io::Error::other(format!("Compaction shutdown lock poisoned: {}", e)))?;
+        let (shutdown, _) = cvar.wait_timeout_while(shutdown, duration, |shutdown| !*shutdown)
+            .map_err(|e| io::Error::other(format!("Compaction shutdown lock poisoned: {}", e)))?;
+        if *shutdown {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "Compaction stopped by shutdown"));
+        }
+        Ok(())
+    }
+    
+    fn check_shutdown(&self) -> io::Result<()> {
+        self.pause(Duration::ZERO)
+    }
+}
+
+impl BlockCache {
+    fn new(max_size: usize, events: EventSink) -> Self {
+        let probation_size = max_size * CACHE_PROBATION_PERCENT / 100;
+        BlockCache {
+            cache: RwLock::new(HashMap::new()),
+            size: RwLock::new(0),
+            max_size: max_size - probation_size,
+            probation: Mutex::new(Probation { max_size: probation_size, ..Default::default() }),
+            pinned: RwLock::new(HashMap::new()),
+            pinned_size: AtomicUsize::new(0),
+            loading: Mutex::new(HashMap::new()),
+            events,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+    
+    // A block in either region, or pinned. A second read of one on probation promotes it.
+    fn cached(&self, key: &BlockKey) -> io::Result<Option<Arc<Vec<u8>>>> {
+        if self.pinned_size.load(Ordering::Relaxed) > 0 {
+            let pinned = self.pinned.read().map_err(|e| io::Error::other(format!("Cache pin lock poisoned: {}", e)))?;
+            if let Some(data) = pinned.get(key) {
+                return Ok(Some(data.clone()));
+            }
+        }
+        let cache = self.cache.read().map_err(|e| io::Error::other(format!("Cache read lock poisoned: {}", e)))?;
+        if let Some(data) = cache.get(key) {
+            return Ok(Some(data.clone()));
+        }
+        drop(cache);
+        
+        let promoted = self.probation.lock().map_err(|e| io::Error::other(format!("Cache probation lock poisoned: {}", e)))?
+            .take(key);
+        if let Some(data) = &promoted {
+            self.admit(*key, data.clone())?;
+        }
+        Ok(promoted)
+    }
+    
+    // Concurrent misses on one block share a single disk read: the first takes the block's
+    // load slot and reads, the rest wait on the slot and take what it read. A failed read
+    // leaves the slot empty, so the next waiter tries the disk itself.
+    fn get_or_load(&self, seg: &Segment, offset: u64, size: usize, access: BlockAccess) -> io::Result<Arc<Vec<u8>>> {
+        let key = (seg.id, offset);
+        if let Some(data) = self.cached(&key)? {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(data);
+        }
+        
+        let slot = self.loading.lock().map_err(|e| io::Error::other(format!("Cache loading lock poisoned: {}", e)))?
+            .entry(key).or_default().clone();
+        let mut loaded = slot.lock().map_err(|e| io::Error::other(format!("Cache slot lock poisoned: {}", e)))?;
+        if let Some(data) = &*loaded {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(data.clone());
+        }
+        // The last load may have finished and left the slot between our two lookups
+        if let Some(data) = self.cached(&key)? {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(data);
+        }
+        
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let data = Arc::new(seg.read_at(offset, size, &self.events)?);
+        self.events.emit_with(|| StoreEvent::BlockLoaded { path: seg.path.clone(), offset, bytes: size });
+        *loaded = Some(data.clone());
+        
+        match access {
+            BlockAccess::Point => self.admit(key, data.clone())?,
+            BlockAccess::Scan => self.probation.lock().map_err(|e| io::Error::other(format!("Cache probation lock poisoned: {}", e)))?
+                .insert(key, data.clone()),
+        }
+        
+        // Later misses find the block in the cache, or load it afresh once it is evicted
+        self.loading.lock().map_err(|e| io::Error::other(format!("Cache loading lock poisoned: {}", e)))?.remove(&key);
+        Ok(data)
+    }
+    
+    // Whether a read of the block would hit, without promoting it
+    fn contains(&self, key: &BlockKey) -> io::Result<bool> {
+        Ok(self.pinned.read().map_err(|e| io::Error::other(format!("Cache pin lock poisoned: {}", e)))?.contains_key(key)
+            || self.cache.read().map_err(|e| io::Error::other(format!("Cache read lock poisoned: {}", e)))?.contains_key(key)
+            || self.probation.lock().map_err(|e| io::Error::other(format!("Cache probation lock poisoned: {}", e)))?.blocks.contains_key(key))
+    }
+    
+    // Add a block warmup read to the main region, or pin it
+    fn preload(&self, key: BlockKey, data: Arc<Vec<u8>>, pin: bool) -> io::Result<()> {
+        if !pin {
+            return self.admit(key, data);
+        }
+        let len = data.len();
+        if let Some(old) = self.pinned.write().map_err(|e| io::Error::other(format!("Cache pin lock poisoned: {}", e)))?.insert(key, data) {
+            self.pinned_size.fetch_sub(old.len(), Ordering::Relaxed);
+        }
+        self.pinned_size.fetch_add(len, Ordering::Relaxed);
+        Ok(())
+    }
+    
+    // Drop the pinned blocks of the segments gone names
+    fn unpin(&self, gone: impl Fn(u64) -> bool) -> io::Result<()> {
+        if self.pinned_size.load(Ordering::Relaxed) == 0 {
+            return Ok(());
+        }
+        let mut pinned = self.pinned.write().map_err(|e| io::Error::other(format!("Cache pin lock poisoned: {}", e)))?;
+        pinned.retain(|&(id, _), data| {
+            let keep = !gone(id);
+            if !keep {
+                self.pinned_size.fetch_sub(data.len(), Ordering::Relaxed);
+            }
+            keep
+        });
+        Ok(())
+    }
+    
+    // Bytes held in all, and pinned
+    fn resident_bytes(&self) -> (usize, usize) {
+        let pinned = self.pinned_size.load(Ordering::Relaxed);
+        let main = self.size.read().map(|size| *size).unwrap_or(0);
+        let probation = self.probation.lock().map(|probation| probation.size).unwrap_or(0);
+        (main + probation + pinned, pinned)
+    }
+    
+    // Add a block to the main region
+    fn admit(&self, key: BlockKey, data: Arc<Vec<u8>>) -> io::Result<()> {
+        let mut cache = self.cache.write().map_err(|e| io::Error::other(format!("Cache write lock poisoned: {}", e)))?;
+        let mut size = self.size.write().map_err(|e| io::Error::other(format!("Cache size lock poisoned: {}", e)))?;
+        
+        *size += data.len();
+        if let Some(old) = cache.insert(key, data) {
+            *size -= old.len();
+        }
+        
+        // Simple eviction if over limit
+        while *size > self.max_size && !cache.is_empty() {
+            if let Some((k, v)) = cache.iter().next() {
+                let k = *k;
+                let v_size = v.len();
+                cache.remove(&k);
+                *size -= v_size;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Probation {
+    fn insert(&mut self, key: BlockKey, data: Arc<Vec<u8>>) {
+        self.size += data.len();
+        if let Some(old) = self.blocks.insert(key, data) {
+            self.size -= old.len();
+        }
+        self.order.push_back(key);
+        while self.size > self.max_size {
+            let Some(oldest) = self.order.pop_front() else { break };
+            if let Some(old) = self.blocks.remove(&oldest) {
+                self.size -= old.len();
+            }
+        }
+    }
+    
+    fn take(&mut self, key: &BlockKey) -> Option<Arc<Vec<u8>>> {
+        let data = self.blocks.remove(key)?;
+        self.size -= data.len();
+        
+        // Promotions leave their keys behind in order; drop them before they pile up
+        if self.order.len() > 2 * self.blocks.len() + 64 {
+            let blocks = &self.blocks;
+            self.order.retain(|key| blocks.contains_key(key));
+        }
+        Some(data)
+    }
+}
+
+impl ValueLog {
+    // A log with nothing in it, whose file the first value stored creates
+    fn new(path: &Path) -> Self {
+        ValueLog {
+            path: path.to_path_buf(),
+            file: None,
+            index: HashMap::new(),
+            len: 0,
+            unsynced: false,
+            marking: None,
+        }
+    }
+    
+    // Index the entries of an existing log. A crash can leave the last append cut short; the
+    // file is truncated back to the last whole entry, which no segment can refer to yet.
+    fn load(path: &Path) -> Result<Self> {
+        let mut log = Self::new(path);
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(log),
+            Err(e) => return Err(e.into()),
+        };
+        if !bytes.starts_with(VALUE_LOG_MAGIC) {
+            return Err(WalDbError::Corruption { file: path.to_path_buf(), offset: 0, detail: "Bad value log magic".to_string() });
+        }
+        
+        // Entry: content hash, value length, value, crc of all three
+        let mut pos = VALUE_LOG_MAGIC.len();
+        while let Some(header) = bytes.get(pos..pos + VALUE_ENTRY_HEADER as usize) {
+            let hash = u64::from_le_bytes(header[0..8].try_into().expect("8-byte slice"));
+            let len = u32::from_le_bytes(header[8..12].try_into().expect("4-byte slice"));
+            let value_at = pos + VALUE_ENTRY_HEADER as usize;
+            let Some(crc) = bytes.get(value_at + len as usize..value_at + len as usize + 4) else { break };
+            if crc32(&bytes[pos..value_at + len as usize]) != u32::from_le_bytes(crc.try_into().expect("4-byte slice")) {
+                break;
+            }
+            log.index.insert(hash, (value_at as u64, len));
+            pos = value_at + len as usize + 4;
+        }
+        
+        let file = OpenOptions::new().read(true).append(true).open(path)?;
+        if pos < bytes.len() {
+            file.set_len(pos as u64)?;
+        }
+        log.file = Some(Arc::new(file));
+        log.len = pos as u64;
+        Ok(log)
+    }
+    
+    // The hash to refer to value by, appending it unless an identical value is already here.
+    // None if the hash already names different bytes.
+    fn store(&mut self, value: &str, cache: &ValueCache) -> Result<Option<u64>> {
+        let hash = xxhash(value.as_bytes(), 0);
+        if let Some(marking) = &mut self.marking {
+            marking.insert(hash);
+        }
+        if let Some((file, offset, len)) = self.locate(hash) {
+            let same = match cache.get(hash)? {
+                Some(existing) => *existing == *value,
+                // Compared as bytes, so an entry that isn't UTF-8 just doesn't match
+                None => {
+                    let same = Self::read(&file, offset, len)? == value.as_bytes();
+                    if same {
+                        cache.insert(hash, Arc::from(value))?;
+                    }
+                    same
+                }
+            };
+            return Ok(same.then_some(hash));
+        }
+        
+        let file = match &self.file {
+            Some(file) => file.clone(),
+            None => {
+                write_atomic(&self.path, VALUE_LOG_MAGIC)?;
+                let file = Arc::new(OpenOptions::new().read(true).append(true).open(&self.path)?);
+                self.len = VALUE_LOG_MAGIC.len() as u64;
+                self.file.insert(file).clone()
+            }
+        };
+        let mut entry = Vec::with_capacity(VALUE_ENTRY_HEADER as usize + value.len() + 4);
+        entry.extend_from_slice(&hash.to_le_bytes());
+        entry.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        entry.extend_from_slice(value.as_bytes());
+        entry.extend_from_slice(&crc32(&entry).to_le_bytes());
+        // A torn entry would hide every later one from load, so a failed append is cut off
+        if let Err(e) = (&*file).write_all(&entry) {
+            let _ = file.set_len(self.len);
+            return Err(e.into());
+        }
+        
+        self.index.insert(hash, (self.len + VALUE_ENTRY_HEADER, value.len() as u32));
+        self.len += entry.len() as u64;
+        self.unsynced = true;
+        Ok(Some(hash))
+    }
+    
+    fn locate(&self, hash: u64) -> Option<(Arc<File>, u64, u32)> {
+        let (offset, len) = *self.index.get(&hash)?;
+        Some((self.file.clone()?, offset, len))
+    }
+    
+    fn read(file: &File, offset: u64, len: u32) -> io::Result<Vec<u8>> {
+        let mut value = vec![0u8; len as usize];
+        read_exact_at(file, &mut value, offset)?;
+        Ok(value)
+    }
+    
+    fn sync(&mut self) -> io::Result<()> {
+        if let (true, Some(file)) = (self.unsynced, &self.file) {
+            file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+    
+    // Rewrite the log with only the values keep accepts and swap it in, returning the bytes
+    // it shrank by. Readers holding the old handle keep reading the old file.
+    fn retain(&mut self, keep: impl Fn(u64) -> bool) -> Result<u64> {
+        let Some(file) = self.file.clone() else { return Ok(0) };
+        if self.index.keys().all(|&hash| keep(hash)) {
+            return Ok(0);
+        }
+        
+        // Keep file order so the copy reads the old log front to back
+        let mut live: Vec<(u64, u64, u32)> = self.index.iter()
+            .filter(|(&hash, _)| keep(hash))
+            .map(|(&hash, &(offset, len))| (hash, offset, len))
+            .collect();
+        live.sort_by_key(|&(_, offset, _)| offset);
+        
+        let mut index = HashMap::new();
+        let mut len = VALUE_LOG_MAGIC.len() as u64;
+        {
+            let mut writer = io::BufWriter::new(AtomicFile::create(&self.path)?);
+            writer.write_all(VALUE_LOG_MAGIC)?;
+            for (hash, offset, value_len) in live {
+                // The whole entry, crc and all, copies over unchanged
+                let mut entry = vec![0u8; (VALUE_ENTRY_HEADER + value_len as u64 + 4) as usize];
+                read_exact_at(&file, &mut entry, offset - VALUE_ENTRY_HEADER)?;
+                writer.write_all(&entry)?;
+                index.insert(hash, (len + VALUE_ENTRY_HEADER, value_len));
+                len += entry.len() as u64;
+            }
+            writer.into_inner().map_err(|e| e.into_error())?.commit()?;
+        }
+        
+        let shrunk = self.len - len;
+        self.file = Some(Arc::new(OpenOptions::new().read(true).append(true).open(&self.path)?));
+        self.index = index;
+        self.len = len;
+        self.unsynced = false;
+        Ok(shrunk)
+    }
+}
+
+impl ReadAmplification {
+    fn record(&self, trace: &ReadTrace) {
+        let level = &self.levels[trace.level as usize];
+        level.gets.fetch_add(1, Ordering::Relaxed);
+        for (counters, cost) in level.costs.iter().zip([trace.probed, trace.bloom_negatives, trace.blocks]) {
+            if cost > 0 {
+                counters.sum.fetch_add(cost, Ordering::Relaxed);
+                counters.buckets[read_cost_bucket(cost)].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    
+    fn report(&self) -> ReadAmplificationReport {
+        let levels = ReadLevel::ALL.into_iter().zip(&self.levels).filter_map(|(level, counters)| {
+            let gets = counters.gets.load(Ordering::Relaxed);
+            if gets == 0 {
+                return None;
+            }
+            let [segments_probed, bloom_negatives, blocks_read] = counters.costs.each_ref().map(|cost| cost.summarize(gets));
+            Some(LevelReads { level, gets, segments_probed, bloom_negatives, blocks_read })
+        }).collect();
+        ReadAmplificationReport { levels }
+    }
+}
+
+impl CostCounters {
+    // Read while gets go on recording, so the counts may be a get or two apart; p99 then
+    // falls back to the highest bucket with any
+    fn summarize(&self, gets: u64) -> ReadCost {
+        let buckets = self.buckets.each_ref().map(|bucket| bucket.load(Ordering::Relaxed));
+        let rank = gets - gets / 100;  // Gets at or under the 99th percentile
+        let mut seen = gets.saturating_sub(buckets.iter().sum());
+        let mut p99 = 0;
+        for (bucket, &count) in buckets.iter().enumerate().skip(1).filter(|(_, &count)| count > 0) {
+            if seen >= rank {
+                break;
+            }
+            seen += count;
+            p99 = read_cost_floor(bucket);
+        }
+        ReadCost { mean: self.sum.load(Ordering::Relaxed) as f64 / gets as f64, p99 }
+    }
+}
+
+impl Default for ReadTrace {
+    fn default() -> Self {
+        ReadTrace { level: ReadLevel::NotFound, probed: 0, bloom_negatives: 0, blocks: 0 }
+    }
+}
+
+impl StoreClock {
+    fn new(source: Option<Arc<dyn Clock>>, events: EventSink) -> Self {
+        StoreClock {
+            source: source.unwrap_or_else(|| Arc::new(SystemClock)),
+            seen: AtomicU64::new(0),
+            behind: AtomicBool::new(false),
+            events,
+        }
+    }
+    
+    // The wall time to the microsecond, or the latest one seen if the clock has since stepped back
+    fn now(&self) -> SystemTime {
+        let wall = self.source.wall();
+        let micros = wall.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+        let seen = self.seen.fetch_max(micros, Ordering::SeqCst);
+        if micros >= seen {
+            self.behind.store(false, Ordering::Relaxed);
+            return UNIX_EPOCH + Duration::from_micros(micros);
+        }
+        let last_seen = UNIX_EPOCH + Duration::from_micros(seen);
+        if seen - micros > CLOCK_STEP_TOLERANCE.as_micros() as u64 && !self.behind.swap(true, Ordering::Relaxed) {
+            self.events.emit(StoreEvent::ClockWentBackwards { wall, last_seen });
+        }
+        last_seen
+    }
+    
+    fn monotonic(&self) -> Instant {
+        self.source.monotonic()
+    }
+    
+    // Take up the manifest's clock line at open, and check the clock against it
+    fn resume(&self, secs: u64) {
+        self.seen.fetch_max(secs.saturating_mul(1_000_000), Ordering::SeqCst);
+        self.now();
+    }
+}
+
+impl fmt::Debug for StoreClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StoreClock({}us)", self.seen.load(Ordering::Relaxed))
+    }
+}
+
+impl QueueHeads {
+    fn table(&self) -> MutexGuard<'_, HashMap<String, String>> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    
+    fn head(&self, prefix: &str) -> Option<String> {
+        if !self.any.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.table().get(prefix).cloned()
+    }
+    
+    // After pop_min found key first under prefix
+    fn advance(&self, prefix: &str, key: &str) {
+        self.table().insert(prefix.to_string(), key.to_string());
+        self.any.store(true, Ordering::SeqCst);
+    }
+    
+    // A set of key: any head it sorts before under one of its prefixes moves back to it
+    fn lower(&self, key: &str, collation: Collation) {
+        if !self.any.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut table = self.table();
+        for (i, _) in key.match_indices('/') {
+            if let Some(head) = table.get_mut(&key[..=i]) {
+                if collation.compare(key.as_bytes(), head.as_bytes()).is_lt() {
+                    *head = key.to_string();
+                }
+            }
+        }
+    }
+}
+
+impl Validators {
+    // Poisoning is ignored: the table only changes by whole inserts and removes
+    fn table(&self) -> MutexGuard<'_, BTreeMap<String, Arc<ValidatorFn>>> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for Validators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.table().keys()).finish()
+    }
+}
+
+impl Quotas {
+    // Poisoning is ignored: the table only changes by whole updates, so it is never left torn
+    fn table(&self) -> MutexGuard<'_, BTreeMap<String, Quota>> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    
+    // Count every quota afresh on its next use, after writes that weren't charged to them
+    fn recount_all(&self) {
+        if self.any.load(Ordering::SeqCst) {
+            for quota in self.table().values_mut() {
+                quota.used = None;
+            }
+        }
+    }
+}
+
+impl QuotaCharge<'_> {
+    // The write went ahead, so the bytes stay taken
+    fn commit(mut self) {
+        self.charged.clear();
+    }
+}
+
+impl Drop for QuotaCharge<'_> {
+    fn drop(&mut self) {
+        if self.charged.is_empty() {
+            return;
+        }
+        let mut table = self.quotas.table();
+        for (prefix, bytes) in &self.charged {
+            let Some(quota) = table.get_mut(prefix) else { continue };
+            quota.used = match (quota.used, bytes) {
+                (Some(used), Some(bytes)) => Some(used.saturating_add_signed(-bytes)),
+                _ => None,
+            };
+        }
+    }
+}
+
+impl WriteAccounting {
+    fn new(roots: &[String], limit: Option<WriteRateLimit>) -> Result<Option<Self>> {
+        let invalid = |message: String| -> WalDbError { io::Error::new(io::ErrorKind::InvalidInput, message).into() };
+        if roots.is_empty() {
+            return match limit {
+                Some(_) => Err(invalid("write_rate_limit needs accounting_roots to count writes in".to_string())),
+                None => Ok(None),
+            };
+        }
+        let window = limit.as_ref().map_or(Duration::from_secs(1), |limit| limit.window);
+        if window.is_zero() {
+            return Err(invalid("write_rate_limit window must be longer than zero".to_string()));
+        }
+        let mut segments = Vec::new();
+        for root in roots {
+            let root = root.strip_suffix('/').unwrap_or(root);
+            if root.is_empty() || root.split('/').any(str::is_empty) {
+                return Err(invalid(format!("Accounting root {:?} has an empty path segment", root)));
+            }
+            segments.push(root.split('/').map(str::to_string).collect());
+        }
+        let overflow = roots.iter()
+            .map(|root| BucketCounters { name: OnceLock::from(Box::from(root.as_str())), ..Default::default() })
+            .collect();
+        Ok(Some(WriteAccounting {
+            roots: segments,
+            slots: (0..ACCOUNTING_BUCKETS).map(|_| BucketCounters::default()).collect(),
+            overflow,
+            limit_ops: limit.as_ref().map_or(0, |limit| (limit.ops_per_sec as f64 * window.as_secs_f64()) as u64),
+            limit,
+            slice_nanos: (window.as_nanos() / WINDOW_SLICES as u128).max(1) as u64,
+            started: Instant::now(),
+        }))
+    }
+    
+    // The first root key is under, and the prefix of key naming its bucket there
+    fn bucket<'k>(&self, key: &'k str) -> Option<(usize, &'k str)> {
+        'roots: for (index, root) in self.roots.iter().enumerate() {
+            let mut end = 0;
+            let mut parts = key.split('/');
+            for pattern in root {
+                let part = parts.next().filter(|part| !part.is_empty() && (pattern == "*" || part == pattern));
+                let Some(part) = part else { continue 'roots };
+                end += part.len() + 1;
+            }
+            return Some((index, &key[..end - 1]));
+        }
+        None
+    }
+    
+    // Linear probing from the bucket's hash, claiming the first free slot on its first write.
+    // xxhash has no final mix, so names differing in their last few bytes can share its low
+    // bits; the slot comes from the high bits of a multiply instead.
+    fn counters(&self, root: usize, bucket: &str) -> &BucketCounters {
+        let mask = self.slots.len() - 1;
+        let start = (xxhash(bucket.as_bytes(), 0).wrapping_mul(0x9e3779b97f4a7c15) >> (64 - self.slots.len().trailing_zeros())) as usize;
+        for probe in 0..ACCOUNTING_PROBES {
+            let slot = &self.slots[(start + probe) & mask];
+            let name = slot.name.get_or_init(|| Box::from(bucket));
+            if **name == *bucket {
+                return slot;
+            }
+        }
+        &self.overflow[root]
+    }
+    
+    fn record(&self, key: &str, bytes: u64) {
+        let Some((root, bucket)) = self.bucket(key) else { return };
+        let counters = self.counters(root, bucket);
+        counters.ops.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        
+        let Some(limit) = &self.limit else { return };
+        let tick = self.tick();
+        let _ = counters.slices[tick as usize % WINDOW_SLICES].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |slice| {
+            Some(if slice >> SLICE_COUNT_BITS == tick { slice + 1 } else { tick << SLICE_COUNT_BITS | 1 })
+        });
+        if counters.window_ops(tick) <= self.limit_ops {
+            return;
+        }
+        let alerted = counters.alerted.load(Ordering::Relaxed);
+        if alerted != tick && counters.alerted.compare_exchange(alerted, tick, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            (limit.on_limit_exceeded)(counters.name.get().map_or("", |name| name), counters.stats(Some(tick)));
+        }
+    }
+    
+    // Tenths of the window since open, from 1 so a slice never written is never current
+    fn tick(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64 / self.slice_nanos + 1
+    }
+    
+    fn stats(&self) -> BTreeMap<String, PrefixWriteStats> {
+        let tick = self.limit.as_ref().map(|_| self.tick());
+        self.slots.iter().chain(&self.overflow)
+            .filter_map(|counters| Some((counters.name.get()?, counters.stats(tick))))
+            .filter(|(_, stats)| stats.ops > 0 || stats.window_ops > 0)
+            .map(|(name, stats)| (name.to_string(), stats))
+            .collect()
+    }
+    
+    fn reset(&self) {
+        for counters in self.slots.iter().chain(&self.overflow) {
+            counters.ops.store(0, Ordering::Relaxed);
+            counters.bytes.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl fmt::Debug for WriteAccounting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteAccounting")
+            .field("roots", &self.roots)
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+impl BucketCounters {
+    // Writes in the slices of the window ending at tick
+    fn window_ops(&self, tick: u64) -> u64 {
+        self.slices.iter()
+            .map(|slice| slice.load(Ordering::Relaxed))
+            .filter(|slice| tick - (slice >> SLICE_COUNT_BITS).min(tick) < WINDOW_SLICES as u64)
+            .map(|slice| slice & ((1 << SLICE_COUNT_BITS) - 1))
+            .sum()
+    }
+    
+    fn stats(&self, tick: Option<u64>) -> PrefixWriteStats {
+        PrefixWriteStats {
+            ops: self.ops.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            window_ops: tick.map_or(0, |tick| self.window_ops(tick)),
+        }
+    }
+}
+
+impl NegativeCache {
+    fn new(capacity: usize) -> Self {
+        NegativeCache { capacity, keys: HashMap::new(), order: BTreeMap::new(), tick: 0 }
+    }
+    
+    // Whether key is cached, marking it most recently used if so
+    fn touch(&mut self, key: &str) -> bool {
+        let Some(tick) = self.keys.get_mut(key) else { return false };
+        self.tick += 1;
+        if let Some(key) = self.order.remove(tick) {
+            self.order.insert(self.tick, key);
+        }
+        *tick = self.tick;
+        true
+    }
+    
+    fn insert(&mut self, key: &str) {
+        if self.capacity == 0 || self.touch(key) {
+            return;
+        }
+        while self.keys.len() >= self.capacity {
+            let Some((_, evict)) = self.order.pop_first() else { break };
+            self.keys.remove(&evict);
+        }
+        self.tick += 1;
+        self.keys.insert(key.to_string(), self.tick);
+        self.order.insert(self.tick, key.to_string());
+    }
+    
+    fn remove(&mut self, key: &str) {
+        if let Some(tick) = self.keys.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+    
+    fn remove_prefix(&mut self, prefix: &str) {
+        let order = &mut self.order;
+        self.keys.retain(|key, tick| !key.starts_with(prefix) || order.remove(tick).is_none());
+    }
+}
+
+impl ValueCache {
+    fn new(max_size: usize, lossy: bool) -> Self {
+        ValueCache { values: RwLock::new((HashMap::new(), 0)), max_size, lossy }
+    }
+    
+    // The value an RT_SET_REF record names by its hex content hash, read from the log on a miss
+    fn resolve(&self, log: &Mutex<ValueLog>, hex: &[u8]) -> Result<String> {
+        let hash = parse_value_ref(hex);
+        if let Some(value) = hash.map(|hash| self.get(hash)).transpose()?.flatten() {
+            return Ok(value.to_string());
+        }
+        
+        // The handle stays readable if reclaim_values swaps the file in the meantime
+        let (hash, file, offset, len, path) = {
+            let log = log.lock().map_err(|e| WalDbError::Locked(format!("Value log lock poisoned: {}", e)))?;
+            let corruption = |detail: String| WalDbError::Corruption { file: log.path.clone(), offset: 0, detail };
+            let hash = hash.ok_or_else(|| corruption(format!("Bad value reference {:?}", String::from_utf8_lossy(hex))))?;
+            let (file, offset, len) = log.locate(hash)
+                .ok_or_else(|| corruption(format!("Value {:016x} is missing from the value log", hash)))?;
+            (hash, file, offset, len, log.path.clone())
+        };
+        // Values reach the log only from &str, so one that isn't UTF-8 is corruption
+        let bytes = ValueLog::read(&file, offset, len)?;
+        let value: Arc<str> = read_text(&bytes, self.lossy).ok_or_else(|| WalDbError::Corruption {
+            file: path,
+            offset,
+            detail: format!("Value {:016x} at offset {} of the value log is not valid UTF-8", hash, offset),
+        })?.into();
+        self.insert(hash, value.clone())?;
+        Ok(value.to_string())
+    }
+    
+    fn get(&self, hash: u64) -> io::Result<Option<Arc<str>>> {
+        let values = self.values.read().map_err(|e| io::Error::other(format!("Value cache lock poisoned: {}", e)))?;
+        Ok(values.0.get(&hash).cloned())
+    }
+    
+    fn insert(&self, hash: u64, value: Arc<str>) -> io::Result<()> {
+        let mut values = self.values.write().map_err(|e| io::Error::other(format!("Value cache lock poisoned: {}", e)))?;
+        let (map, size) = &mut *values;
+        if let Some(old) = map.insert(hash, value.clone()) {
+            *size -= old.len();
+        }
+        *size += value.len();
+        
+        // Same simple eviction as the block cache
+        while *size > self.max_size {
+            let Some(&evict) = map.keys().find(|&&k| k != hash) else { break };
+            if let Some(old) = map.remove(&evict) {
+                *size -= old.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VectorSidecar {
+    fn new(path: Option<PathBuf>) -> Self {
+        VectorSidecar {
+            path,
+            file: None,
+            dims: 0,
+            data: Vec::new(),
+            rows: Vec::new(),
+            index: BTreeMap::new(),
+            max_seq: 0,
+        }
+    }
+    
+    // Returns None when the file is missing or fails validation, so the caller rebuilds it
+    fn load(path: &Path) -> io::Result<Option<Self>> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        
+        if bytes.len() < VECTOR_MAGIC.len() + 4 || &bytes[..VECTOR_MAGIC.len()] != VECTOR_MAGIC {
+            return Ok(None);
+        }
+        
+        let mut sidecar = VectorSidecar::new(Some(path.to_path_buf()));
+        let mut pos = VECTOR_MAGIC.len();
+        sidecar.dims = u32::from_le_bytes(bytes[pos..pos + 4].try_into().expect("4-byte slice")) as usize;
+        pos += 4;
+        
+        while pos < bytes.len() {
+            // Record: kind, seq, path len, path, [unit flag, dims x f32], crc
+            let start = pos;
+            if pos + 13 > bytes.len() {
+                return Ok(None);
+            }
+            let kind = bytes[pos];
+            let seq = u64::from_le_bytes(bytes[pos + 1..pos + 9].try_into().expect("8-byte slice"));
+            let plen = u32::from_le_bytes(bytes[pos + 9..pos + 13].try_into().expect("4-byte slice")) as usize;
+            pos += 13;
+            
+            let body_len = match kind {
+                VR_PUT => 1 + sidecar.dims * 4,
+                VR_DEL => 0,
+                _ => return Ok(None),
+            };
+            if pos + plen + body_len + 4 > bytes.len() {
+                return Ok(None);
+            }
+            
+            let row_path = match std::str::from_utf8(&bytes[pos..pos + plen]) {
+                Ok(p) => p.to_string(),
+                Err(_) => return Ok(None),
+            };
+            pos += plen;
+            
+            let body = &bytes[pos..pos + body_len];
+            pos += body_len;
+            
+            let crc = u32::from_le_bytes(bytes[pos..pos + 4].try_into().expect("4-byte slice"));
+            if crc32(&bytes[start..pos]) != crc {
+                return Ok(None);
+            }
+            pos += 4;
+            
+            if kind == VR_PUT {
+                let vector: Vec<f32> = body[1..].chunks_exact(4)
+                    .map(|c| f32::from_le_bytes(c.try_into().expect("4-byte chunk")))
+                    .collect();
+                sidecar.push_row(row_path, &vector, body[0] != 0, seq);
+            } else {
+                sidecar.index.remove(&row_path);
+                sidecar.max_seq = sidecar.max_seq.max(seq);
+            }
+        }
+        
+        Ok(Some(sidecar))
+    }
+    
+    // Bring the sidecar up to date with writes it may have missed before a crash. Everything
+    // since the last flush is still in the memtable and subtombs, and seqs make this idempotent.
+    fn catch_up(&mut self, inner: &StoreInner) -> io::Result<()> {
+        for (key, value) in mem_range(&inner.shards(), Bound::Unbounded, Bound::Unbounded) {
+            match value {
+                MemValue::Scalar(val, seq) => self.apply(&key.key, Some(val), *seq)?,
+                MemValue::PointTomb(seq) => self.apply(&key.key, None, *seq)?,
+            }
+        }
+        for (prefix, seq) in &inner.subtombs {
+            self.remove_prefix(prefix, *seq)?;
+        }
+        Ok(())
+    }
+    
+    fn get(&self, path: &str) -> Option<(&[f32], bool)> {
+        self.index.get(path).map(|&row| (self.row(row), self.rows[row].unit))
+    }
+    
+    fn row(&self, row: usize) -> &[f32] {
+        &self.data[row * self.dims..(row + 1) * self.dims]
+    }
+    
+    // Mirror a primary write: vector values get a row, anything else drops the path's row
+    fn apply(&mut self, path: &str, value: Option<&str>, seq: u64) -> io::Result<()> {
+        if self.index.get(path).is_some_and(|&row| self.rows[row].seq >= seq) {
+            return Ok(());
+        }
+        
+        match value.and_then(decode_vector) {
+            Some(Ok(vector)) if !vector.data.is_empty() && (self.dims == 0 || vector.data.len() == self.dims) => {
+                if self.dims == 0 {
+                    self.dims = vector.data.len();
+                    self.rewrite()?;
+                }
+                self.append(VR_PUT, path, Some((&vector.data, vector.unit)), seq)?;
+                self.push_row(path.to_string(), &vector.data, vector.unit, seq);
+            }
+            // Other widths stay in the primary records only and are parsed at query time
+            _ => {
+                if self.index.contains_key(path) {
+                    self.append(VR_DEL, path, None, seq)?;
+                    self.index.remove(path);
+                }
+            }
+        }
+        
+        self.maybe_compact()
+    }
+    
+    fn remove_prefix(&mut self, prefix: &str, seq: u64) -> io::Result<()> {
+        let end = prefix_end(prefix);
+        let doomed: Vec<String> = self.index
+            .range::<str, _>((Bound::Included(prefix), Bound::Excluded(end.as_str())))
+            .filter(|(_, &row)| self.rows[row].seq <= seq)
+            .map(|(path, _)| path.clone())
+            .collect();
+        
+        for path in doomed {
+            self.append(VR_DEL, &path, None, seq)?;
+            self.index.remove(&path);
+        }
+        
+        self.maybe_compact()
+    }
+    
+    fn push_row(&mut self, path: String, vector: &[f32], unit: bool, seq: u64) {
+        self.data.extend_from_slice(vector);
+        self.index.insert(path, self.rows.len());
+        self.rows.push(VectorRow { seq, unit });
+        self.max_seq = self.max_seq.max(seq);
+    }
+    
+    fn append(&mut self, kind: u8, path: &str, vector: Option<(&[f32], bool)>, seq: u64) -> io::Result<()> {
+        if self.path.is_none() {
+            self.max_seq = self.max_seq.max(seq);
+            return Ok(());
+        }
+        if self.file.is_none() {
+            self.rewrite()?;
+        }
+        let record = Self::encode_record(kind, path, vector, seq);
+        self.file.as_mut().expect("sidecar file opened by rewrite").write_all(&record)?;
+        self.max_seq = self.max_seq.max(seq);
+        Ok(())
+    }
+    
+    fn encode_record(kind: u8, path: &str, vector: Option<(&[f32], bool)>, seq: u64) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.push(kind);
+        record.extend_from_slice(&seq.to_le_bytes());
+        record.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        record.extend_from_slice(path.as_bytes());
+        if let Some((vector, unit)) = vector {
+            record.push(unit as u8);
+            for f in vector {
+                record.extend_from_slice(&f.to_le_bytes());
+            }
+        }
+        let crc = crc32(&record);
+        record.extend_from_slice(&crc.to_le_bytes());
+        record
+    }
+    
+    fn maybe_compact(&mut self) -> io::Result<()> {
+        let dead = self.rows.len() - self.index.len();
+        if dead >= VECTOR_COMPACT_MIN && dead > self.index.len() {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+    
+    // Stop writing vectors.dat and remove it, so open rebuilds it from the primary records.
+    // The rows stay in memory for this process's searches.
+    fn detach(&mut self) {
+        if let Some(path) = self.path.take() {
+            self.file = None;
+            let _ = fs::remove_file(path);
+        }
+    }
+    
+    // Write only the live rows to a fresh file and swap it in
+    fn rewrite(&mut self) -> io::Result<()> {
+        let mut data = Vec::with_capacity(self.index.len() * self.dims);
+        let mut rows = Vec::with_capacity(self.index.len());
+        let mut index = BTreeMap::new();
+        
+        {
+            let mut writer = self.path.as_deref().map(AtomicFile::create).transpose()?.map(io::BufWriter::new);
+            if let Some(writer) = &mut writer {
+                writer.write_all(VECTOR_MAGIC)?;
+                writer.write_all(&(self.dims as u32).to_le_bytes())?;
+            }
+            
+            // Keep file order so scans stay sequential
+            let mut live: Vec<(&String, usize)> = self.index.iter().map(|(p, &r)| (p, r)).collect();
+            live.sort_by_key(|&(_, row)| row);
+            
+            for (path, row) in live {
+                let VectorRow { seq, unit } = self.rows[row];
+                if let Some(writer) = &mut writer {
+                    writer.write_all(&Self::encode_record(VR_PUT, path, Some((self.row(row), unit)), seq))?;
+                }
+                data.extend_from_slice(self.row(row));
+                index.insert(path.clone(), rows.len());
+                rows.push(VectorRow { seq, unit });
+            }
+            
+            if let Some(writer) = writer {
+                writer.into_inner().map_err(|e| e.into_error())?.commit()?;
+            }
+        }
+        
+        if let Some(path) = &self.path {
+            self.file = Some(OpenOptions::new().append(true).open(path)?);
+        }
+        self.data = data;
+        self.rows = rows;
+        self.index = index;
+        Ok(())
+    }
+    
+    fn sync(&self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SegmentRegistry {
+    fn new(in_memory: bool) -> Self {
+        SegmentRegistry { in_memory, ..Default::default() }
+    }
+    
+    // Poisoning is ignored: the lists only change by whole pushes and removals
+    fn pending(&self) -> MutexGuard<'_, Vec<Arc<Segment>>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    
+    // Queue segments taken out of the store for deletion, and delete any nothing else holds
+    fn retire(&self, segments: Vec<Arc<Segment>>) -> io::Result<()> {
+        self.pending().extend(segments);
+        self.sweep()
+    }
+    
+    // Delete the file of each pending segment the registry holds the last reference to,
+    // closing it first. The rest wait for the next sweep: the compaction thread's, compact's,
+    // close's, or the one a RangeIter or Snapshot runs as it drops.
+    fn sweep(&self) -> io::Result<()> {
+        let unheld: Vec<PathBuf> = {
+            let mut pending = self.pending();
+            let (unheld, held): (Vec<_>, Vec<_>) = std::mem::take(&mut *pending).into_iter()
+                .partition(|seg| Arc::strong_count(seg) == 1);
+            *pending = held;
+            unheld.into_iter().map(|seg| seg.path.clone()).collect()
+        };
+        
+        let mut failed = self.unlink_failed.lock().unwrap_or_else(|e| e.into_inner());
+        failed.extend(unheld);
+        let mut first_err = None;
+        failed.retain(|path| match self.unlink(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                first_err.get_or_insert(e);
+                true
+            }
+            _ => false,
+        });
+        first_err.map_or(Ok(()), Err)
+    }
+    
+    // The segments still waiting, which a scan may still be reading
+    fn waiting(&self) -> Vec<Arc<Segment>> {
+        self.pending().clone()
+    }
+    
+    // Delete a segment file nothing reads: a failed write's output, or one the manifest says
+    // is gone. Nothing to do for a store keeping its segments in memory.
+    fn unlink(&self, path: &Path) -> io::Result<()> {
+        match self.in_memory {
+            true => Ok(()),
+            false => fs::remove_file(path),
+        }
+    }
+}
+
+impl SegmentDirs {
+    fn new(store: &Path, data: &[PathBuf], placement: SegmentPlacement) -> io::Result<Self> {
+        if let SegmentPlacement::Tiered { l0, cold } = placement {
+            if let Some(i) = [l0, cold].into_iter().flatten().find(|&i| i >= data.len()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Placement names data_dirs[{}] but only {} are given", i, data.len())));
+            }
+        }
+        Ok(SegmentDirs { store: store.to_path_buf(), data: data.to_vec(), placement })
+    }
+    
+    // Where a new segment of level goes: its path, and its name as the manifest records it
+    fn place(&self, level: usize, filename: String) -> io::Result<(PathBuf, String)> {
+        let dir = match self.placement {
+            SegmentPlacement::StoreDir => None,
+            SegmentPlacement::Tiered { l0, .. } if level == 0 => l0,
+            SegmentPlacement::Tiered { cold, .. } => cold,
+            SegmentPlacement::Balanced => {
+                let mut least = (segment_bytes_in(&self.store)?, None);
+                for (i, dir) in self.data.iter().enumerate() {
+                    least = least.min((segment_bytes_in(dir)?, Some(i)));
+                }
+                least.1
+            }
+        };
+        Ok(match dir {
+            Some(i) => (self.data[i].join(&filename), format!("d{}/{}", i, filename)),
+            None => (self.store.join(&filename), filename),
+        })
+    }
+    
+    // The file a manifest name refers to: where it was recorded if it is still there, or
+    // else the first directory holding a file of that name
+    fn resolve(&self, recorded: &str) -> PathBuf {
+        let (hint, filename) = match recorded.split_once('/') {
+            Some((dir, filename)) => (dir.strip_prefix('d').and_then(|i| i.parse::<usize>().ok()).and_then(|i| self.data.get(i)), filename),
+            None => (Some(&self.store), recorded),
+        };
+        hint.into_iter()
+            .chain(std::iter::once(&self.store))
+            .chain(self.data.iter())
+            .map(|dir| dir.join(filename))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| hint.unwrap_or(&self.store).join(filename))
+    }
+}
+
+// The file names of segments, as a compaction's manifest entry lists its inputs
+fn segment_file_names(segments: &[Arc<Segment>]) -> Vec<String> {
+    segments.iter()
+        .filter_map(|seg| seg.path.file_name().and_then(|name| name.to_str()).map(str::to_string))
+        .collect()
+}
+
+// The number in a segment file name Manifest::new_file_name gave out. Older names
+// (l0_<seq>.seg and the like) have none.
+fn segment_file_number(name: &str) -> Option<u64> {
+    let digits = name.strip_suffix(".seg")?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// Bytes of .seg files directly in dir
+fn segment_bytes_in(dir: &Path) -> io::Result<u64> {
+    let mut bytes = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.path().extension().is_some_and(|ext| ext == "seg") {
+            bytes += entry.metadata()?.len();
+        }
+    }
+    Ok(bytes)
+}
+
+// Where the counters of a MetricsSnapshot live, apart from the memtable's size and the seq,
+// which take the store lock
+struct MetricsSources {
+    wal: Arc<GroupCommitWAL>,
+    cache: Arc<BlockCache>,
+    flushed_seq: Arc<AtomicU64>,
+    compaction_io: Arc<(AtomicU64, AtomicU64)>,
+    compaction_timing: Arc<(AtomicU64, AtomicU64)>,
+    flush_timing: Arc<(AtomicU64, AtomicU64)>,
+}
+
+impl MetricsSources {
+    fn sample(&self, memtable_bytes: u64, seq: u64) -> MetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let micros = |counter: &AtomicU64| Duration::from_micros(counter.load(Ordering::Relaxed));
+        MetricsSnapshot {
+            seq,
+            durable_seq: load(&self.wal.durable_seq),
+            flushed_seq: load(&self.flushed_seq),
+            memtable_bytes,
+            flushes: load(&self.flush_timing.0),
+            flush_time: micros(&self.flush_timing.1),
+            compactions: load(&self.compaction_timing.0),
+            compaction_time: micros(&self.compaction_timing.1),
+            compaction_bytes_written: load(&self.compaction_io.1),
+            wal_syncs: load(&self.wal.syncs),
+            wal_sync_time: micros(&self.wal.sync_time_us),
+            cache_hits: load(&self.cache.hits),
+            cache_misses: load(&self.cache.misses),
+        }
+    }
+}
+
+// stats.ring: a header, then a fixed number of STATS_RECORD slots written round and round.
+// Each sample carries a running number and a CRC, so a reader orders them by number and
+// skips a slot a write was caught halfway through. Nothing is fsynced: the ring is history,
+// not data, and a ring that doesn't match is started afresh.
+struct StatsRing {
+    file: File,
+    slots: u64,
+    next: u64,  // Number of the next sample, which goes in slot next % slots
+}
+
+impl StatsRing {
+    fn open(path: &Path, bytes: u64) -> io::Result<Self> {
+        let slots = (bytes.saturating_sub(STATS_RING_HEADER as u64) / STATS_RECORD as u64).max(1);
+        let len = STATS_RING_HEADER as u64 + slots * STATS_RECORD as u64;
+        let next = match fs::metadata(path) {
+            Ok(metadata) if metadata.len() == len => Self::read(path).ok()
+                .map(|samples| samples.iter().map(|(number, _)| number + 1).max().unwrap_or(1)),
+            _ => None,
+        };
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let next = match next {
+            Some(next) => next,
+            None => {
+                let mut header = [0u8; STATS_RING_HEADER];
+                header[..8].copy_from_slice(STATS_RING_MAGIC);
+                header[8..12].copy_from_slice(&(STATS_RECORD as u32).to_le_bytes());
+                file.set_len(0)?;
+                file.set_len(len)?;
+                (&file).write_all(&header)?;
+                1
+            }
+        };
+        Ok(StatsRing { file, slots, next })
+    }
+    
+    fn append(&mut self, at: SystemTime, metrics: &MetricsSnapshot) -> io::Result<()> {
+        let micros = |d: Duration| d.as_micros() as u64;
+        let fields = [
+            self.next,
+            at.duration_since(UNIX_EPOCH).map_or(0, micros),
+            metrics.seq,
+            metrics.durable_seq,
+            metrics.flushed_seq,
+            metrics.memtable_bytes,
+            metrics.flushes,
+            micros(metrics.flush_time),
+            metrics.compactions,
+            micros(metrics.compaction_time),
+            metrics.compaction_bytes_written,
+            metrics.wal_syncs,
+            micros(metrics.wal_sync_time),
+            metrics.cache_hits,
+            metrics.cache_misses,
+        ];
+        let mut record = [0u8; STATS_RECORD];
+        for (i, field) in fields.iter().enumerate() {
+            record[i * 8..i * 8 + 8].copy_from_slice(&field.to_le_bytes());
+        }
+        let crc = crc32(&record[..120]);
+        record[120..124].copy_from_slice(&crc.to_le_bytes());
+        
+        let slot = self.next % self.slots;
+        self.file.seek(SeekFrom::Start(STATS_RING_HEADER as u64 + slot * STATS_RECORD as u64))?;
+        self.file.write_all(&record)?;
+        self.next += 1;
+        Ok(())
+    }
+    
+    // Every whole sample in the ring with its number, in slot order
+    fn read(path: &Path) -> io::Result<Vec<(u64, TimestampedSnapshot)>> {
+        let data = fs::read(path)?;
+        if data.len() < STATS_RING_HEADER || &data[..8] != STATS_RING_MAGIC
+            || data[8..12] != (STATS_RECORD as u32).to_le_bytes() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a stats ring", path.display())));
+        }
+        let mut samples = Vec::new();
+        for record in data[STATS_RING_HEADER..].chunks_exact(STATS_RECORD) {
+            if crc32(&record[..120]).to_le_bytes() != record[120..124] {
+                continue;
+            }
+            let field = |i: usize| u64::from_le_bytes(record[i * 8..i * 8 + 8].try_into().expect("8 bytes"));
+            let number = field(0);
+            if number == 0 {
+                continue;
+            }
+            let metrics = MetricsSnapshot {
+                seq: field(2),
+                durable_seq: field(3),
+                flushed_seq: field(4),
+                memtable_bytes: field(5),
+                flushes: field(6),
+                flush_time: Duration::from_micros(field(7)),
+                compactions: field(8),
+                compaction_time: Duration::from_micros(field(9)),
+                compaction_bytes_written: field(10),
+                wal_syncs: field(11),
+                wal_sync_time: Duration::from_micros(field(12)),
+                cache_hits: field(13),
+                cache_misses: field(14),
+            };
+            samples.push((number, TimestampedSnapshot { at: UNIX_EPOCH + Duration::from_micros(field(1)), metrics }));
+        }
+        Ok(samples)
+    }
+}
+
+impl StoreIdentity {
+    // A fresh identity, created at now. Whole seconds, as the file keeps it, so it compares
+    // equal to a reload.
+    fn generate(now: SystemTime) -> Self {
+        let now = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        StoreIdentity {
+            id: StoreId::generate(),
+            created: UNIX_EPOCH + Duration::from_secs(now),
+            format_version: FORMAT_VERSION,
+        }
+    }
+    
+    // Reads dir's IDENTITY, or writes a new one if there is none
+    fn load_or_create(dir: &Path, now: SystemTime) -> Result<(Self, bool)> {
+        let path = dir.join(IDENTITY_FILE);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate(now);
+                identity.write(&path)?;
+                return Ok((identity, true));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        
+        // Format: a header line, then "key value" lines. Keys this build doesn't know are
+        // skipped, so a newer one can add some without bumping the format.
+        let mut lines = text.lines();
+        let (mut id, mut created, mut format_version) = (None, None, None);
+        if lines.next() == Some(IDENTITY_HEADER) {
+            for line in lines {
+                match line.split_once(' ') {
+                    Some(("id", value)) => id = StoreId::parse(value),
+                    Some(("created", value)) => created = value.parse::<u64>().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                    Some(("format", value)) => format_version = value.parse::<u32>().ok(),
+                    _ => {}
+                }
+            }
+        }
+        match (id, created, format_version) {
+            (_, _, Some(version)) if version > FORMAT_VERSION => Err(WalDbError::NewerFormat { file: path, version }),
+            (Some(id), Some(created), Some(format_version)) => Ok((StoreIdentity { id, created, format_version }, false)),
+            _ => Err(WalDbError::Corruption {
+                detail: format!("{} is not a valid identity file", path.display()),
+                file: path,
+                offset: 0,
+            }),
+        }
+    }
+    
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let created = self.created.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let text = format!("{}\nid {}\ncreated {}\nformat {}\n", IDENTITY_HEADER, self.id, created, self.format_version);
+        write_atomic(path, text.as_bytes())
+    }
+}
+
+impl StoreId {
+    // std has no random number generator, but each RandomState is keyed from the OS's
+    // randomness, which is all a hasher needs to turn the clock into 64 unguessable bits
+    fn generate() -> Self {
+        use std::hash::{BuildHasher, Hasher};
+        let mut bits = 0u128;
+        for _ in 0..2 {
+            let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+            hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+            hasher.write_u32(std::process::id());
+            bits = bits << 64 | hasher.finish() as u128;
+        }
+        // Version 4, variant 1, as UUID tools expect of a random id
+        StoreId(bits & !(0xf << 76) & !(0x3 << 62) | 0x4 << 76 | 0x2 << 62)
+    }
+    
+    fn parse(text: &str) -> Option<Self> {
+        let hex: String = text.chars().filter(|&c| c != '-').collect();
+        if hex.len() != 32 {
+            return None;
+        }
+        u128::from_str_radix(&hex, 16).ok().map(StoreId)
+    }
+}
+
+impl ManifestEntry {
+    // The file name without the data directory, as compaction inputs and drops name it
+    fn file_name(&self) -> &str {
+        self.filename.rsplit('/').next().unwrap_or(&self.filename)
+    }
+}
+
+impl Manifest {
+    // Kept in memory only, as though every line were written and synced
+    fn in_memory() -> Self {
+        Manifest {
+            path: None,
+            entries: Vec::new(),
+            sealed: Vec::new(),
+            history_floor: 0,
+            next_file: 1,
+            subtombs: HashMap::new(),
+            high_water: 0,
+            clock: 0,
+            dropped: Vec::new(),
+            file: None,
+        }
+    }
+    
+    fn load(path: &Path) -> io::Result<Self> {
+        let mut manifest = Manifest { path: Some(path.to_path_buf()), ..Self::in_memory() };
+        
+        if !path.exists() {
+            return Ok(manifest);
+        }
+        
+        let file = File::open(path)?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        
+        while reader.read_line(&mut line)? > 0 {
+            // Simple format: seq_high|level|filename, archives adding |prefix. Seals and
+            // unseals are seal|prefix and unseal|prefix. Prefixes are percent-escaped. A
+            // segment in one of the data_dirs is named d{index}/filename. A compaction
+            // output's entry is followed by from|filename|input|input..., and drop|filename
+            // removes a segment by hand (Store::drop_segment). floor|seq raises the history
+            // floor, and file|number records that segment file numbers below it are taken.
+            // keys|filename|min|max gives a segment's smallest and largest keys, escaped;
+            // older manifests have none, and their segments are searched for any key.
+            // subtomb|seq|prefix is a subtree delete a flush persisted, escaped. seq|seq says
+            // seqs up to it were handed out, as of a flush or a run of WAL syncs. clock|secs
+            // is the latest wall time the store had read as of a flush.
+            let parts: Vec<&str> = line.trim().split('|').collect();
+            match parts[..] {
+                ["seal", prefix] => manifest.sealed.push(percent_unescape(prefix)),
+                ["floor", seq] => {
+                    if let Ok(seq) = seq.parse::<u64>() {
+                        manifest.history_floor = manifest.history_floor.max(seq);
+                    }
+                }
+                ["seq", seq] => {
+                    if let Ok(seq) = seq.parse::<u64>() {
+                        manifest.high_water = manifest.high_water.max(seq);
+                    }
+                }
+                ["clock", secs] => {
+                    if let Ok(secs) = secs.parse::<u64>() {
+                        manifest.clock = manifest.clock.max(secs);
+                    }
+                }
+                ["file", next] => {
+                    if let Ok(next) = next.parse::<u64>() {
+                        manifest.next_file = manifest.next_file.max(next);
+                    }
+                }
+                ["subtomb", seq, prefix] => {
+                    if let Ok(seq) = seq.parse::<u64>() {
+                        let listed = manifest.subtombs.entry(percent_unescape(prefix)).or_insert(seq);
+                        *listed = (*listed).max(seq);
+                    }
+                }
+                ["unseal", prefix] => {
+                    let prefix = percent_unescape(prefix);
+                    manifest.sealed.retain(|sealed| *sealed != prefix);
+                }
+                ["from", filename, ref inputs @ ..] => {
+                    if let Some(entry) = manifest.entries.iter_mut().rev().find(|entry| entry.filename == filename) {
+                        entry.inputs = inputs.iter().map(|input| input.to_string()).collect();
+                    }
+                }
+                ["keys", filename, min, max] => {
+                    if let Some(entry) = manifest.entries.iter_mut().rev().find(|entry| entry.filename == filename) {
+                        entry.bounds = Some((percent_unescape(min), percent_unescape(max)));
+                    }
+                }
+                ["drop", name] => manifest.forget_entry(name),
+                [seq_high, level, filename, ref archive_of @ ..] if archive_of.len() <= 1 => {
+                    if let (Ok(seq_high), Ok(level)) = (seq_high.parse::<u64>(), level.parse::<usize>()) {
+                        // Listed again by hand once compaction had replaced it, the file is as
+                        // new as its latest entry. Listed twice while live, two segments were
+                        // written to the one file and the first is lost.
+                        let name = filename.rsplit('/').next().unwrap_or(filename);
+                        if let Some(i) = manifest.entries.iter().position(|entry| entry.file_name() == name) {
+                            if !manifest.superseded(i) {
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                                    "{} lists segment {} twice, so one of the segments written to it was overwritten",
+                                    path.display(), name
+                                )));
+                            }
+                        }
+                        manifest.entries.retain(|entry| entry.file_name() != name);
+                        manifest.dropped.retain(|dropped| dropped.rsplit('/').next() != Some(name));
+                        if let Some(number) = segment_file_number(name) {
+                            manifest.next_file = manifest.next_file.max(number + 1);
+                        }
+                        manifest.entries.push(ManifestEntry {
+                            seq_high,
+                            level,
+                            filename: filename.to_string(),
+                            archive_of: archive_of.first().map(|prefix| percent_unescape(prefix)),
+                            inputs: Vec::new(),
+                            bounds: None,
+                        });
+                    }
+                }
+                _ => {}
+            }
+            line.clear();
+        }
+        
+        Ok(manifest)
+    }
+    
+    // Whether a later compaction output covers entry i, so its file was deleted on purpose.
+    // Outputs name their inputs; ones from before they did cover every lower level entry no
+    // newer than they are. Archives are only ever replaced by a later seal of their prefix or
+    // one above it.
+    fn superseded(&self, i: usize) -> bool {
+        let entry = &self.entries[i];
+        self.entries[i + 1..].iter().any(|later| match (&entry.archive_of, &later.archive_of) {
+            (None, None) if !later.inputs.is_empty() => later.inputs.iter().any(|input| input == entry.file_name()),
+            (None, None) => later.level > entry.level && later.seq_high >= entry.seq_high,
+            (Some(prefix), Some(later_prefix)) => later.seq_high > entry.seq_high && prefix.starts_with(later_prefix.as_str()),
+            _ => false,
+        })
+    }
+    
+    // Write the entry without an fsync; a later sync makes it and every entry before it durable.
+    // A file listed already would be one segment overwriting another.
+    fn append(&mut self, entry: ManifestEntry) -> io::Result<()> {
+        self.append_all(vec![entry])
+    }
+    
+    // Append and fsync, for entries whose inputs are deleted once this returns. One write
+    // for all of them, as append writes an entry with its from line.
+    fn add_entries(&mut self, entries: Vec<ManifestEntry>) -> io::Result<()> {
+        self.append_all(entries)?;
+        self.sync()
+    }
+    
+    fn append_all(&mut self, entries: Vec<ManifestEntry>) -> io::Result<()> {
+        let mut lines = String::new();
+        for (i, entry) in entries.iter().enumerate() {
+            if self.entries.iter().chain(&entries[..i]).any(|listed| listed.file_name() == entry.file_name()) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Segment {} is already in the manifest", entry.file_name())));
+            }
+            match &entry.archive_of {
+                Some(prefix) => lines.push_str(&format!("{}|{}|{}|{}\n", entry.seq_high, entry.level, entry.filename, percent_escape(prefix))),
+                None => lines.push_str(&format!("{}|{}|{}\n", entry.seq_high, entry.level, entry.filename)),
+            }
+            // Losing the from line to a crash only leaves superseded() to go by seq, and losing
+            // the keys line only leaves reads searching the segment for every key
+            if !entry.inputs.is_empty() {
+                lines.push_str(&format!("from|{}|{}\n", entry.filename, entry.inputs.join("|")));
+            }
+            if let Some((min, max)) = &entry.bounds {
+                lines.push_str(&format!("keys|{}|{}|{}\n", entry.filename, percent_escape(min), percent_escape(max)));
+            }
+        }
+        self.write_line(&lines)?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+    
+    // A name for a new segment file, whatever its level: NNNNNN.seg from a counter no file
+    // has used. The counter's line isn't fsynced, as the entry naming the file will be, and
+    // until it is the file is an orphan whose number can be handed out again.
+    fn new_file_name(&mut self) -> io::Result<String> {
+        let number = self.next_file;
+        self.write_line(&format!("file|{}\n", number + 1))?;
+        self.next_file = number + 1;
+        Ok(format!("{:06}.seg", number))
+    }
+    
+    // Record that the segment file name is gone from the store, and fsync
+    fn drop_segment(&mut self, name: &str) -> io::Result<()> {
+        self.write_line(&format!("drop|{}\n", name))?;
+        self.sync()?;
+        self.forget_entry(name);
+        Ok(())
+    }
+    
+    // Take the segment file name out of the entries, and keep it among the dropped files
+    fn forget_entry(&mut self, name: &str) {
+        if let Some(i) = self.entries.iter().position(|entry| entry.file_name() == name) {
+            let entry = self.entries.remove(i);
+            self.dropped.push(entry.filename);
+        }
+    }
+    
+    // Record that reads as of seqs before floor can no longer be answered, unless an earlier
+    // line already said so. Not fsynced: callers sync before the versions it accounts for go.
+    fn raise_floor(&mut self, floor: u64) -> io::Result<()> {
+        if floor > self.history_floor {
+            self.write_line(&format!("floor|{}\n", floor))?;
+            self.history_floor = floor;
+        }
+        Ok(())
+    }
+    
+    // Record that the store has read the wall clock at `at`, to the second, unless an earlier
+    // line says as late, and return whether it did. Not fsynced, as with raise_floor.
+    fn raise_clock(&mut self, at: SystemTime) -> io::Result<bool> {
+        let secs = at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        if secs <= self.clock {
+            return Ok(false);
+        }
+        self.write_line(&format!("clock|{}\n", secs))?;
+        self.clock = secs;
+        Ok(true)
+    }
+    
+    // Record that seqs up to seq were handed out, unless an earlier line already said so, and
+    // return whether it did. Not fsynced, as with raise_floor.
+    fn raise_high_water(&mut self, seq: u64) -> io::Result<bool> {
+        if seq <= self.high_water {
+            return Ok(false);
+        }
+        self.write_line(&format!("seq|{}\n", seq))?;
+        self.high_water = seq;
+        Ok(true)
+    }
+    
+    // Record the subtree deletes not listed yet, or only with an older seq, and return whether
+    // there were any. Not fsynced: the WAL holds them until a checkpoint.
+    fn add_subtombs(&mut self, subtombs: &HashMap<String, u64>) -> io::Result<bool> {
+        let new: Vec<(&String, u64)> = subtombs.iter()
+            .filter(|(prefix, &seq)| self.subtombs.get(prefix.as_str()).is_none_or(|&listed| listed < seq))
+            .map(|(prefix, &seq)| (prefix, seq))
+            .collect();
+        if new.is_empty() {
+            return Ok(false);
+        }
+        let lines: String = new.iter().map(|(prefix, seq)| format!("subtomb|{}|{}\n", seq, percent_escape(prefix))).collect();
+        self.write_line(&lines)?;
+        self.subtombs.extend(new.into_iter().map(|(prefix, seq)| (prefix.clone(), seq)));
+        Ok(true)
+    }
+    
+    // Record that prefix now refuses writes, or accepts them again, and fsync
+    fn set_sealed(&mut self, prefix: &str, sealed: bool) -> io::Result<()> {
+        self.write_line(&format!("{}|{}\n", if sealed { "seal" } else { "unseal" }, percent_escape(prefix)))?;
+        self.sync()?;
+        self.sealed.retain(|other| other != prefix);
+        if sealed {
+            self.sealed.push(prefix.to_string());
+        }
+        Ok(())
+    }
+    
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                // The file's directory entry has to survive a crash before any entry in it can
+                create_durable(path, b"")?;
+                self.file.insert(OpenOptions::new().append(true).open(path)?)
+            }
+        };
+        
+        // One write per line, so a crash can cut off the last line but never interleave two
+        file.write_all(line.as_bytes())
+    }
+    
+    fn sync(&mut self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Collation {
+    /// Order two keys as a store with this collation does
+    pub fn compare(self, a: &[u8], b: &[u8]) -> cmp::Ordering {
+        match self {
+            Collation::Binary => a.cmp(b),
+            Collation::NumericAware => {
+                let mut a_segments = a.split(|&b| b == b'/');
+                let mut b_segments = b.split(|&b| b == b'/');
+                loop {
+                    match (a_segments.next(), b_segments.next()) {
+                        (Some(x), Some(y)) => {
+                            let order = natural_cmp(x, y).then_with(|| x.cmp(y));
+                            if order.is_ne() {
+                                return order;
+                            }
+                        }
+                        (x, y) => return x.is_some().cmp(&y.is_some()),
+                    }
+                }
+            }
+        }
+    }
+    
+    // As recorded in a segment footer
+    fn code(self) -> u32 {
+        match self {
+            Collation::Binary => 0,
+            Collation::NumericAware => 1,
+        }
+    }
+    
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Collation::Binary),
+            1 => Some(Collation::NumericAware),
+            _ => None,
+        }
+    }
+}
+
+// Helper functions
+
+// Natural order within one path segment: runs of digits compare by value, everything else by
+// byte. Runs differing only in leading zeros compare equal, for the caller to break the tie.
+fn natural_cmp(a: &[u8], b: &[u8]) -> cmp::Ordering {
+    fn value(run: &[u8]) -> &[u8] {
+        let zeros = run.iter().take_while(|&&c| c == b'0').count();
+        &run[zeros..]
+    }
+    let digits = |s: &[u8], from: usize| from + s[from..].iter().take_while(|c| c.is_ascii_digit()).count();
+    
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let (i_end, j_end) = (digits(a, i), digits(b, j));
+            let (x, y) = (value(&a[i..i_end]), value(&b[j..j_end]));
+            let order = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+            if order.is_ne() {
+                return order;
+            }
+            (i, j) = (i_end, j_end);
+        } else {
+            let order = a[i].cmp(&b[j]);
+            if order.is_ne() {
+                return order;
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j))
+}
+
+// Write and fsync a seq line for seq, if the manifest has none as high
+fn mark_high_water(manifest: &Mutex<Manifest>, unsynced: &AtomicBool, seq: u64) -> Result<()> {
+    let raised = lock_ranked(LockRank::Manifest, || manifest.lock().map_err(|e| WalDbError::Locked(format!("Manifest lock poisoned: {}", e))))?
+        .raise_high_water(seq)?;
+    if raised {
+        unsynced.store(true, Ordering::Release);
+    }
+    sync_manifest(manifest, unsynced)
+}
+
+// Fsync flushes' manifest entries, if any are waiting. The flag is cleared under the manifest
+// lock, so a flush setting it again meanwhile is never lost.
+fn sync_manifest(manifest: &Mutex<Manifest>, unsynced: &AtomicBool) -> Result<()> {
+    if !unsynced.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    let mut manifest = lock_ranked(LockRank::Manifest, || manifest.lock().map_err(|e| WalDbError::Locked(format!("Manifest lock poisoned: {}", e))))?;
+    if unsynced.swap(false, Ordering::AcqRel) {
+        if let Err(e) = manifest.sync() {
+            unsynced.store(true, Ordering::Release);
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+// A new or renamed file's directory entry is only durable once the directory itself is
+// synced. Unix only: Windows can't open a directory as a File, and NTFS journals the entry.
+// Some filesystems (FUSE, some network mounts) refuse fsync on a directory; there is nothing
+// more to do on those.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    match File::open(dir)?.sync_all() {
+        Err(e) if matches!(e.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported) => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// A temporary file of ours: a segment or one of known, with TMP_SUFFIX once or more
+fn is_leftover_tmp(name: &str, known: &[&str]) -> bool {
+    let base = name.trim_end_matches(TMP_SUFFIX);
+    base != name && (base.ends_with(".seg") || known.contains(&base))
+}
+
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => sync_dir(dir),
+        None => Ok(()),
+    }
+}
+
+// path with TMP_SUFFIX added, beside it in the same directory so the rename can't cross filesystems
+fn tmp_path_for(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(TMP_SUFFIX);
+    PathBuf::from(name)
+}
+
+// A file written under a temporary name and renamed over path by commit, after syncing it,
+// and the directory synced after that. A crash leaves path whole, or as it was before.
+// Dropped without a commit, the temporary file is removed; one left by a crash is removed
+// by the next open.
+struct AtomicFile {
+    file: File,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    fn create(path: &Path) -> io::Result<Self> {
+        let tmp_path = tmp_path_for(path);
+        let file = File::create(&tmp_path)?;
+        Ok(AtomicFile { file, path: path.to_path_buf(), tmp_path, committed: false })
+    }
+    
+    fn commit(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        self.committed = true;
+        sync_parent(&self.path)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(data)?;
+    file.commit()
+}
+
+// Appended-to files (the WAL, manifest and value log) start out holding just their header,
+// written atomically, so no append can land in a file whose name a crash could still lose
+fn create_durable(path: &Path, header: &[u8]) -> io::Result<()> {
+    match path.exists() {
+        true => Ok(()),
+        false => write_atomic(path, header),
+    }
+}
+
+// Aggregator behind one subscribe_coalesced receiver. Runs until the store drops its sender
+// or a batch finds the receiver gone.
+fn coalesce(changes: mpsc::Receiver<Change>, batches: mpsc::Sender<CoalescedBatch>, window: Duration, clock: &StoreClock) {
+    while let Ok(first) = changes.recv() {
+        let mut batch = CoalescedBatch::default();
+        batch.absorb(first);
+        let closes = clock.monotonic() + window;
+        let mut store_open = true;
+        
+        loop {
+            let left = closes.saturating_duration_since(clock.monotonic());
+            if left.is_zero() || batch.len() >= COALESCE_MAX_ENTRIES {
+                break;
+            }
+            match changes.recv_timeout(left) {
+                Ok(change) => batch.absorb(change),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    store_open = false;
+                    break;
+                }
+            }
+        }
+        
+        if batches.send(batch).is_err() || !store_open {
+            return;
+        }
+    }
+}
+
+// Segment::sequential's reader, over a file or a store's in-memory bytes
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+// Fill buf from offset without moving the file's cursor
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+// seek_read does move the cursor, but every read passes its own offset so none depends on it
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Keys are length-prefixed with a u32 in both the WAL and segment records
+fn validate_key(key: &str) -> Result<()> {
+    if key.len() > u32::MAX as usize {
+        return Err(WalDbError::InvalidKey { reason: "Key exceeds maximum length".to_string() });
+    }
+    Ok(())
+}
+
+// An empty prefix, or a pattern of nothing but '*' after one, would delete every key. A
+// caller that means it calls clear_all, so an empty variable can't wipe the store.
+fn reject_delete_all(pattern: &str, wildcards: bool) -> Result<()> {
+    let prefix = match wildcards {
+        true => &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())],
+        false => pattern,
+    };
+    if prefix.chars().all(|c| c == '/') && pattern[prefix.len()..].chars().all(|c| c == '*') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Deleting {:?} would delete every key; call clear_all to empty the store", pattern)).into());
+    }
+    Ok(())
+}
+
+fn reject_reserved(key: &str) -> Result<()> {
+    if key.starts_with(NAMESPACE_MARKER) {
+        return Err(WalDbError::InvalidKey { reason: "Keys starting with \\0 are reserved for namespaces".to_string() });
+    }
+    if is_system_key(key) {
+        return Err(WalDbError::InvalidKey { reason: "Keys starting with \\u{1} are reserved for the store's own use".to_string() });
+    }
+    Ok(())
+}
+
+// Keys every scan, pattern, subscription and export leaves out unless asked for them
+fn is_system_key(key: &str) -> bool {
+    key.starts_with(SYSTEM_MARKER)
+}
+
+// "\0" + name + "/", with every byte outside [A-Za-z0-9_.-] written as %XX so the prefix
+// holds no '/' or wildcard and two names never share one
+fn namespace_prefix(name: &str) -> String {
+    format!("{}{}/", NAMESPACE_MARKER, percent_escape(name))
+}
+
+// StoreOptions::max_segment_size, or in L2 CompactionOptions::target_segment_bytes if smaller
+fn segment_cut(level: usize, max_segment_size: u64, compaction: &CompactionOptions) -> u64 {
+    match level {
+        2 => compaction.target_segment_bytes.unwrap_or(L2_SEGMENT_BYTES).min(max_segment_size),
+        _ => max_segment_size,
+    }
+}
+
+// Whether two segments' key bounds share a key, taking unknown bounds to cover every key
+fn bounds_overlap(a: &Option<(String, String)>, b: &Option<(String, String)>, collation: Collation) -> bool {
+    match (a, b) {
+        (Some((a_min, a_max)), Some((b_min, b_max))) => {
+            collation.compare(a_min.as_bytes(), b_max.as_bytes()).is_le() && collation.compare(b_min.as_bytes(), a_max.as_bytes()).is_le()
+        }
+        _ => true,
+    }
+}
+
+fn percent_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'-') {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", b));
+        }
+    }
+    escaped
+}
+
+fn percent_unescape(escaped: &str) -> String {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        match (b, tail.get(..2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// prefix as seal_prefix and unseal_prefix match it, with a trailing '/'
+fn sealed_prefix(prefix: &str) -> Result<String> {
+    if prefix.trim_end_matches('/').is_empty() {
+        return Err(WalDbError::InvalidKey { reason: "Cannot seal the root".to_string() });
+    }
+    Ok(if prefix.ends_with('/') { prefix.to_string() } else { format!("{}/", prefix) })
+}
+
+// prefix as set_quota matches it, with a trailing '/'
+fn quota_prefix(prefix: &str) -> Result<String> {
+    if prefix.trim_end_matches('/').is_empty() {
+        return Err(WalDbError::InvalidKey { reason: "Cannot set a quota on the root".to_string() });
+    }
+    Ok(if prefix.ends_with('/') { prefix.to_string() } else { format!("{}/", prefix) })
+}
+
+// prefix as push and pop_min use it, with a trailing '/'
+fn queue_prefix(prefix: &str) -> Result<String> {
+    if prefix.trim_end_matches('/').is_empty() {
+        return Err(WalDbError::InvalidKey { reason: "A queue needs a prefix, not the root".to_string() });
+    }
+    Ok(if prefix.ends_with('/') { prefix.to_string() } else { format!("{}/", prefix) })
+}
+
+// The key push gives the item numbered n under prefix, which ends in '/'
+fn queue_key(prefix: &str, n: u64) -> String {
+    format!("{}{:020}", prefix, n)
+}
+
+// prefix as register_validator keys it, with its trailing '/'
+fn validator_prefix(prefix: &str) -> Result<String> {
+    if prefix.trim_end_matches('/').is_empty() {
+        return Err(WalDbError::InvalidKey { reason: "Cannot register a validator on the root".to_string() });
+    }
+    Ok(if prefix.ends_with('/') { prefix.to_string() } else { format!("{}/", prefix) })
+}
+
+// The CostCounters bucket a get costing this much goes in
+fn read_cost_bucket(cost: u64) -> usize {
+    match cost {
+        0..16 => cost as usize,
+        _ => (12 + cost.ilog2() as usize).min(READ_COST_BUCKETS - 1),
+    }
+}
+
+// The smallest cost read_cost_bucket puts in bucket
+fn read_cost_floor(bucket: usize) -> u64 {
+    match bucket {
+        0..16 => bucket as u64,
+        _ => 1 << (bucket - 12),
+    }
+}
+
+// The quotas over key, from the outermost in
+fn quotas_over<'a>(table: &'a BTreeMap<String, Quota>, key: &'a str) -> impl Iterator<Item = &'a str> {
+    key.match_indices('/').map(|(i, _)| &key[..=i]).filter(|prefix| table.contains_key(*prefix))
+}
+
+// The system key holding prefix's quota, relative to SYSTEM_PREFIX
+fn quota_key(prefix: &str) -> String {
+    format!("{}{}", QUOTA_KEYS, percent_escape(prefix))
+}
+
+fn parent_path(path: &str) -> Option<String> {
+    parent_of(path).map(str::to_string)
+}
+
+// parent_path without the copy
+fn parent_of(path: &str) -> Option<&str> {
+    path.rfind('/').filter(|&idx| idx > 0).map(|idx| &path[..idx])
+}
+
+// Unicode-aware case folding, including the multi-char folds to_lowercase leaves alone
+fn fold_case(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        match c {
+            'ß' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            _ => folded.push(c),
+        }
+    }
+    folded
+}
+
+// Exclusive upper bound covering every key that starts with prefix
+fn prefix_end(prefix: &str) -> String {
+    format!("{}{}", prefix, char::MAX)
+}
+
+fn join_path(root: &str, relative: &str) -> String {
+    if root.is_empty() {
+        relative.to_string()
+    } else {
+        format!("{}/{}", root, relative)
+    }
+}
+
+// Quote s as a JSON string. Beyond what JSON requires, DEL, the JS line separators and
+// everything outside the BMP are escaped too, so the output is plain BMP text any parser takes.
+fn push_export_line(out: &mut String, key: &str, value: &str) {
+    out.push_str("{\"key\":");
+    push_json_string(out, key);
+    out.push_str(",\"value\":");
+    push_json_string(out, value);
+    out.push_str("}\n");
+}
+
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' || c == '\u{2028}' || c == '\u{2029}' => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            }
+            c if (c as u32) > 0xFFFF => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    out.push_str(&format!("\\u{:04x}", unit));
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// The fields of a one-line JSON object whose values are all strings, in order, as
+// export_diff writes them. None for anything else.
+fn parse_json_fields(line: &str) -> Option<Vec<(String, String)>> {
+    let mut rest = line.trim().strip_prefix('{')?.trim_start();
+    let mut fields = Vec::new();
+    if let Some(after) = rest.strip_prefix('}') {
+        return after.trim().is_empty().then_some(fields);
+    }
+    loop {
+        let (name, after) = parse_json_string(rest)?;
+        let (value, after) = parse_json_string(after.trim_start().strip_prefix(':')?.trim_start())?;
+        fields.push((name, value));
+        let after = after.trim_start();
+        match after.strip_prefix(',') {
+            Some(next) => rest = next.trim_start(),
+            None => return after.strip_prefix('}')?.trim().is_empty().then_some(fields),
         }
-        self.file.write_all(&index_data)?;
-        
-        // Write bloom filter
-        self.file.write_all(&self.bloom.bits)?;
-        
-        // Write footer
-        let mut footer = Vec::new();
-        footer.extend_from_slice(&self.seq_low.to_le_bytes());
-        footer.extend_from_slice(&self.seq_high.to_le_bytes());
-        footer.extend_from_slice(&(self.key_count as u32).to_le_bytes());
-        footer.extend_from_slice(&(index_data.len() as u32).to_le_bytes());
-        footer.extend_from_slice(&(self.bloom.bits.len() as u32).to_le_bytes());
-        footer.extend_from_slice(&(self.bloom.hash_count as u32).to_le_bytes());
-        self.file.write_all(&footer)?;
-        
-        self.file.sync_all()?;
-        
-        Ok(Segment {
-            path: self.path,
-            // seq_low: self.seq_low,
-            seq_high: self.seq_high,
-            // key_count: self.key_count,
-            bloom: Some(self.bloom),
-            index: self.index,
-            index_start,
-        })
     }
 }
 
-impl BloomFilter {
-    fn new(bit_count: usize, hash_count: usize) -> Self {
-        BloomFilter {
-            bits: vec![0u8; (bit_count + 7) / 8],
-            bit_count,
-            hash_count,
+// Read a snapshot from export_snapshot, handing each chunk's entries to apply once its CRC
+// and running count check out, then checking the trailer. The digest chains over each line's
+// bytes as read, before any unescaping.
+fn read_snapshot(mut reader: impl BufRead, mut apply: impl FnMut(usize, Vec<(String, String)>) -> Result<()>) -> Result<SnapshotInfo> {
+    let bad = |chunk: Option<usize>, detail: String| WalDbError::SnapshotCorrupt { chunk, detail };
+    let number = |fields: &[(String, String)], i: usize, name: &str, chunk: Option<usize>| -> Result<u64> {
+        match fields.get(i) {
+            Some((field, value)) if field == name => value.parse().map_err(|_| bad(chunk, format!("{} is {:?}, not a number", name, value))),
+            _ => Err(bad(chunk, format!("expected {} as field {}", name, i + 1))),
         }
+    };
+    let mut raw = Vec::new();
+    let mut next_line = |raw: &mut Vec<u8>| -> Result<bool> {
+        raw.clear();
+        Ok(reader.read_until(b'\n', raw)? > 0)
+    };
+    
+    if !next_line(&mut raw)? {
+        return Err(bad(None, "it is empty".to_string()));
     }
+    let header = std::str::from_utf8(&raw).ok().and_then(parse_json_fields).unwrap_or_default();
+    let mut info = match header.as_slice() {
+        [(snapshot, version), (id, store_id), (prefix_name, prefix), ..] if snapshot == "snapshot" && id == "store_id" && prefix_name == "prefix" => {
+            if version != SNAPSHOT_VERSION {
+                return Err(bad(None, format!("format {:?} is not one this build reads", version)));
+            }
+            SnapshotInfo {
+                store_id: StoreId(u128::from_str_radix(&store_id.replace('-', ""), 16).map_err(|_| bad(None, format!("store id {:?} is not a UUID", store_id)))?),
+                prefix: prefix.clone(),
+                seq_floor: number(&header, 3, "seq_floor", None)?,
+                seq_ceiling: number(&header, 4, "seq_ceiling", None)?,
+                chunks: 0,
+                entries: 0,
+                digest: 0,
+            }
+        }
+        _ => return Err(bad(None, "the first line is not a snapshot header".to_string())),
+    };
+    info.digest = xxhash(&raw, 0);
     
-    fn add(&mut self, key: &str) {
-        for i in 0..self.hash_count {
-            let hash = xxhash(key.as_bytes(), i as u64) as usize % self.bit_count;
-            self.bits[hash / 8] |= 1 << (hash % 8);
+    let mut chunk_bytes = Vec::new();
+    let mut entries = Vec::new();
+    loop {
+        let chunk = info.chunks;
+        if !next_line(&mut raw)? {
+            // A chunk cut off partway is that chunk's damage; one cut at a boundary, the trailer's
+            return Err(match entries.is_empty() {
+                true => bad(None, "it ends before its trailer".to_string()),
+                false => bad(Some(chunk), "the file ends partway through it".to_string()),
+            });
+        }
+        let fields = std::str::from_utf8(&raw).ok().and_then(parse_json_fields).ok_or_else(|| bad(Some(chunk), "a line is not an export line".to_string()))?;
+        match fields.first().map(|(name, _)| name.as_str()) {
+            Some("key") => match fields.as_slice() {
+                [(_, key), (value_name, value)] if value_name == "value" && key.starts_with(info.prefix.as_str()) => {
+                    chunk_bytes.extend_from_slice(&raw);
+                    info.digest = xxhash(&raw, info.digest);
+                    entries.push((key.clone(), value.clone()));
+                }
+                _ => return Err(bad(Some(chunk), format!("{:?} is not an entry under the snapshot's prefix", String::from_utf8_lossy(&raw).trim_end()))),
+            },
+            Some("chunk") => {
+                if number(&fields, 0, "chunk", Some(chunk))? != chunk as u64 {
+                    return Err(bad(Some(chunk), format!("it is numbered {}", fields[0].1)));
+                }
+                let running = number(&fields, 1, "entries", Some(chunk))?;
+                if running != (info.entries + entries.len()) as u64 {
+                    return Err(bad(Some(chunk), format!("it holds {} entries where its count says {}", entries.len(), running - info.entries as u64)));
+                }
+                let crc = match fields.get(2) {
+                    Some((name, crc)) if name == "crc" => u32::from_str_radix(crc, 16).ok(),
+                    _ => None,
+                };
+                if crc != Some(crc32(&chunk_bytes)) {
+                    return Err(bad(Some(chunk), "its CRC does not match its entries".to_string()));
+                }
+                info.digest = xxhash(&raw, info.digest);
+                info.entries += entries.len();
+                info.chunks += 1;
+                apply(chunk, std::mem::take(&mut entries))?;
+                chunk_bytes.clear();
+            }
+            Some("end") if entries.is_empty() => {
+                let total = number(&fields, 0, "end", None)?;
+                let chunks = number(&fields, 1, "chunks", None)?;
+                if total != info.entries as u64 || chunks != info.chunks as u64 {
+                    return Err(bad(None, format!("the trailer counts {} entries in {} chunks, the file holds {} in {}", total, chunks, info.entries, info.chunks)));
+                }
+                match fields.get(2) {
+                    Some((name, digest)) if name == "digest" && u64::from_str_radix(digest, 16).ok() == Some(info.digest) => {}
+                    _ => return Err(bad(None, "the digest does not match the file".to_string())),
+                }
+                if next_line(&mut raw)? {
+                    return Err(bad(None, "there is more after the trailer".to_string()));
+                }
+                return Ok(info);
+            }
+            _ => return Err(bad(Some(chunk), format!("unexpected line {:?}", String::from_utf8_lossy(&raw).trim_end()))),
         }
     }
+}
+
+// The JSON string literal s starts with, unescaped, and the text after it
+fn parse_json_string(s: &str) -> Option<(String, &str)> {
+    fn hex4(chars: &mut std::str::CharIndices) -> Option<u32> {
+        let hex: String = (0..4).map(|_| chars.next().map(|(_, c)| c)).collect::<Option<_>>()?;
+        u32::from_str_radix(&hex, 16).ok()
+    }
     
-    fn might_contain(&self, key: &str) -> bool {
-        for i in 0..self.hash_count {
-            let hash = xxhash(key.as_bytes(), i as u64) as usize % self.bit_count;
-            if self.bits[hash / 8] & (1 << (hash % 8)) == 0 {
-                return false;
+    let body = s.strip_prefix('"')?;
+    let mut chars = body.char_indices();
+    let mut out = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &body[i + 1..])),
+            '\\' => match chars.next()?.1 {
+                escape @ ('"' | '\\' | '/') => out.push(escape),
+                'b' => out.push('\u{8}'),
+                'f' => out.push('\u{c}'),
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                'u' => {
+                    let unit = hex4(&mut chars)?;
+                    // Chars past U+FFFF come as a surrogate pair
+                    let code = if (0xD800..0xDC00).contains(&unit) {
+                        if chars.next()?.1 != '\\' || chars.next()?.1 != 'u' {
+                            return None;
+                        }
+                        let low = hex4(&mut chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return None;
+                        }
+                        0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                    } else {
+                        unit
+                    };
+                    out.push(char::from_u32(code)?);
+                }
+                _ => return None,
+            },
+            c if (c as u32) < 0x20 => return None,
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+// Whether a stored string reads back as the same JSON literal: true, false, null, an i64
+// in its shortest form, or a float whose shortest form is itself. "01", "1.50", "1e5"
+// and "-0" all stay strings, so an unquoted value always reproduces the stored text.
+fn is_canonical_json_literal(s: &str) -> bool {
+    match s {
+        "true" | "false" | "null" => return true,
+        _ => {}
+    }
+    if let Ok(n) = s.parse::<i64>() {
+        return n.to_string() == s;
+    }
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) || (digits.starts_with('0') && !digits.starts_with("0.")) {
+        return false;  // JSON wants a leading digit and no leading zeros; Rust accepts ".5", "inf"
+    }
+    s.parse::<f64>().is_ok_and(|f| f.is_finite() && s.contains('.') && f.to_string() == s)
+}
+
+/// The key for a path of segments, each one level of the tree whatever it holds. '%', '/'
+/// and the reserved leading characters \0 and \u{1} are written %25, %2F, %00 and %01, so an
+/// id holding '/' can't add a level or land on another path's key, and path_segments gives
+/// the segments back exactly. Empty segments stay empty. InvalidKey without a segment, or
+/// for one empty one, which would be the empty key.
+pub fn path_key(segments: &[&str]) -> Result<String> {
+    if segments.is_empty() || segments == [""] {
+        return Err(WalDbError::InvalidKey { reason: "A path needs a segment that isn't empty, or more than one".to_string() });
+    }
+    let mut key = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        if i > 0 {
+            key.push('/');
+        }
+        for c in segment.chars() {
+            match c {
+                '%' | '/' | '\0' | '\u{1}' => key.push_str(&format!("%{:02X}", c as u32)),
+                c => key.push(c),
             }
         }
-        true
     }
+    Ok(key)
 }
 
-impl BlockCache {
-    fn new(max_size: usize) -> Self {
-        BlockCache {
-            cache: RwLock::new(HashMap::new()),
-            size: RwLock::new(0),
-            max_size,
+/// The segments of key, undoing path_key. Other '%' sequences are left alone, so a key not
+/// made by path_key just splits at each '/'.
+pub fn path_segments(key: &str) -> Vec<String> {
+    key.split('/').map(|segment| {
+        let mut unescaped = String::with_capacity(segment.len());
+        let mut rest = segment;
+        while let Some(i) = rest.find('%') {
+            unescaped.push_str(&rest[..i]);
+            let c = match rest.get(i + 1..i + 3) {
+                Some("25") => '%',
+                Some("2F") => '/',
+                Some("00") => '\0',
+                Some("01") => '\u{1}',
+                _ => {
+                    unescaped.push('%');
+                    rest = &rest[i + 1..];
+                    continue;
+                }
+            };
+            unescaped.push(c);
+            rest = &rest[i + 3..];
+        }
+        unescaped.push_str(rest);
+        unescaped
+    }).collect()
+}
+
+// Vectors are stored as "v:c1,c2,..." or, when normalized on write, "vn:<original norm>:c1,c2,..."
+struct StoredVector {
+    data: Vec<f32>,
+    unit: bool,  // Already unit length, so cosine reduces to a dot product
+}
+
+/// Encode a vector in the value format read by get_vector and vector search
+pub fn encode_vector(vector: &[f32], normalize: bool) -> String {
+    let join = |v: &mut dyn Iterator<Item = f32>| v.map(|f| f.to_string()).collect::<Vec<_>>().join(",");
+    if !normalize {
+        return format!("v:{}", join(&mut vector.iter().copied()));
+    }
+    let norm = lane_sum(vector, vector, |x, y| x * y).sqrt();
+    let scale = if norm == 0.0 { 0.0 } else { 1.0 / norm };
+    format!("vn:{}:{}", norm, join(&mut vector.iter().map(|f| f * scale)))
+}
+
+fn decode_vector(value: &str) -> Option<Result<StoredVector, std::num::ParseFloatError>> {
+    let (body, unit) = match value.strip_prefix("vn:") {
+        Some(rest) => (rest.split_once(':')?.1, true),
+        None => (value.strip_prefix("v:")?, false),
+    };
+    Some(body.split(',').map(str::parse).collect::<Result<Vec<f32>, _>>().map(|data| StoredVector { data, unit }))
+}
+
+const VECTOR_LANES: usize = 8;
+
+// Sum f(a[i], b[i]) over independent lanes so the compiler can vectorize the loop
+fn lane_sum(a: &[f32], b: &[f32], f: impl Fn(f32, f32) -> f32) -> f32 {
+    let mut acc = [0.0f32; VECTOR_LANES];
+    let chunks_a = a.chunks_exact(VECTOR_LANES);
+    let chunks_b = b.chunks_exact(VECTOR_LANES);
+    let tail: f32 = chunks_a.remainder().iter().zip(chunks_b.remainder()).map(|(x, y)| f(*x, *y)).sum();
+    for (ca, cb) in chunks_a.zip(chunks_b) {
+        for ((lane, x), y) in acc.iter_mut().zip(ca).zip(cb) {
+            *lane += f(*x, *y);
         }
     }
+    acc.iter().sum::<f32>() + tail
+}
+
+fn parse_value_ref(hex: &[u8]) -> Option<u64> {
+    u64::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+// Hand every record of the WAL at path to apply in log order, batches and group commits op
+// by op: kind, seq, key, and the value of a set. Stops at the first torn or corrupt frame.
+// Returns how many bytes of the log it read, nothing if it is missing or not a WAL. A log
+// from before group frames reads the same way, as can one that has both. A frame whose CRC
+// checks out but whose text isn't UTF-8 fails it, unless lossy.
+fn read_wal(path: &Path, lossy: bool, apply: impl FnMut(u8, u64, Cow<'_, str>, Option<Cow<'_, str>>)) -> Result<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
     
-    fn get_or_load(&self, path: &Path, offset: u64, size: usize) -> io::Result<Arc<Vec<u8>>> {
-        let key = (path.to_path_buf(), offset);
-        
-        {
-            let cache = self.cache.read().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Cache read lock poisoned: {}", e)))?;
-            if let Some(data) = cache.get(&key) {
-                return Ok(data.clone());
-            }
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file);
+    
+    let mut magic_buf = [0u8; 4];
+    if reader.read_exact(&mut magic_buf).is_err() {
+        return Ok(0);
+    }
+    
+    if magic_buf != WAL_MAGIC && magic_buf != WAL_MAGIC_V2 {
+        return Ok(0);
+    }
+    
+    let bytes = read_wal_records(reader, lossy, apply)
+        .map_err(|(offset, bad)| bad.error(path, WAL_MAGIC.len() as u64 + offset))?;
+    Ok(WAL_MAGIC.len() as u64 + bytes)
+}
+
+// read_wal past the magic. Fails with where the frame holding text that isn't UTF-8 starts.
+fn read_wal_records(mut reader: impl Read, lossy: bool, mut apply: impl FnMut(u8, u64, Cow<'_, str>, Option<Cow<'_, str>>))
+                    -> std::result::Result<u64, (u64, BadText)> {
+    let mut bytes = 0;
+    loop {
+        let mut len_buf = [0u8; 4];
+        if reader.read_exact(&mut len_buf).is_err() {
+            break;
         }
         
-        // Load from disk
-        let mut file = File::open(path)?;
-        file.seek(SeekFrom::Start(offset))?;
-        
-        let mut data = vec![0u8; size];
-        file.read_exact(&mut data)?;
-        
-        let data = Arc::new(data);
+        let len = u32::from_le_bytes(len_buf) as usize;
+        let mut record = vec![0u8; len];
         
-        // Add to cache
-        let mut cache = self.cache.write().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Cache write lock poisoned: {}", e)))?;
-        let mut size = self.size.write().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Cache size lock poisoned: {}", e)))?;
+        if reader.read_exact(&mut record).is_err() {
+            break;
+        }
         
-        *size += data.len();
-        cache.insert(key, data.clone());
+        let mut crc_buf = [0u8; 4];
+        if reader.read_exact(&mut crc_buf).is_err() {
+            break;
+        }
         
-        // Simple eviction if over limit
-        while *size > self.max_size && !cache.is_empty() {
-            if let Some((k, v)) = cache.iter().next() {
-                let k = k.clone();
-                let v_size = v.len();
-                cache.remove(&k);
-                *size -= v_size;
-            }
+        let expected_crc = u32::from_le_bytes(crc_buf);
+        if crc32(&record) != expected_crc {
+            break;
         }
         
-        Ok(data)
+        apply_wal_record(&record, lossy, &mut apply).map_err(|bad| (bytes, bad))?;
+        bytes += len as u64 + 8;
     }
+    
+    Ok(bytes)
 }
 
-impl Manifest {
-    fn load(path: &Path) -> io::Result<Self> {
-        let mut manifest = Manifest {
-            path: path.to_path_buf(),
-            entries: Vec::new(),
-        };
-        
-        if !path.exists() {
-            return Ok(manifest);
+// Text in a WAL record that isn't UTF-8: a key, or the value of the key given
+#[derive(Debug, PartialEq)]
+enum BadText {
+    Key,
+    Value(String),
+}
+
+impl BadText {
+    // Keys are only ever written from &str, so a bad one is corruption
+    fn error(self, file: &Path, offset: u64) -> WalDbError {
+        match self {
+            BadText::Key => WalDbError::Corruption {
+                file: file.to_path_buf(),
+                offset,
+                detail: format!("Key of the WAL record at offset {} of {} is not valid UTF-8", offset, file.display()),
+            },
+            BadText::Value(path) => WalDbError::InvalidUtf8 { path, file: file.to_path_buf(), offset },
         }
-        
-        let file = File::open(path)?;
-        let mut reader = BufReader::new(file);
-        let mut line = String::new();
-        
-        while reader.read_line(&mut line)? > 0 {
-            // Simple format: seq_high|level|filename
-            let parts: Vec<&str> = line.trim().split('|').collect();
-            if parts.len() == 3 {
-                if let Ok(seq_high) = parts[0].parse::<u64>() {
-                    if let Ok(level) = parts[1].parse::<usize>() {
-                        manifest.entries.push(ManifestEntry {
-                            seq_high,
-                            level,
-                            filename: parts[2].to_string(),
-                        });
-                    }
-                }
-            }
-            line.clear();
+    }
+}
+
+// A WAL op's key and value as text, under StoreOptions::lossy_reads with replacement characters
+fn wal_text<'a>(key: &'a [u8], value: Option<&'a [u8]>, lossy: bool) -> std::result::Result<(Cow<'a, str>, Option<Cow<'a, str>>), BadText> {
+    let key = read_text(key, lossy).ok_or(BadText::Key)?;
+    let value = value.map(|value| read_text(value, lossy).ok_or_else(|| BadText::Value(key.to_string()))).transpose()?;
+    Ok((key, value))
+}
+
+// Hand the records of a CRC-checked frame to apply: the one it holds, or every op of a batch
+// or group commit. Stops at the first op whose text isn't UTF-8, unless lossy.
+fn apply_wal_record(record: &[u8], lossy: bool, apply: &mut impl FnMut(u8, u64, Cow<'_, str>, Option<Cow<'_, str>>))
+                    -> std::result::Result<(), BadText> {
+    if record.len() < 13 {
+        return Ok(());
+    }
+    
+    let mut seq_bytes = [0u8; 8];
+    seq_bytes.copy_from_slice(&record[0..8]);
+    let seq = u64::from_le_bytes(seq_bytes);
+    
+    let kind = record[8];
+    
+    let mut klen_bytes = [0u8; 4];
+    klen_bytes.copy_from_slice(&record[9..13]);
+    let klen = u32::from_le_bytes(klen_bytes) as usize;
+    
+    if record.len() < 13 + klen {
+        return Ok(());
+    }
+    
+    if kind == RT_BATCH {
+        for (kind, offset, key, value) in batch_ops(&record[13 + klen..]) {
+            let (key, value) = wal_text(key, value, lossy)?;
+            apply(kind, seq + offset, key, value);
         }
-        
-        Ok(manifest)
+        return Ok(());
     }
     
-    fn add_entry(&mut self, entry: ManifestEntry) -> io::Result<()> {
-        self.entries.push(entry.clone());
-        
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.path)?;
-        
-        writeln!(file, "{}|{}|{}", entry.seq_high, entry.level, entry.filename)?;
-        file.sync_all()?;
-        
-        Ok(())
+    if kind == RT_GROUP {
+        if let Some(count) = record.get(13..GROUP_HEADER) {
+            let count = u32::from_le_bytes([count[0], count[1], count[2], count[3]]) as usize;
+            for entry in group_records(&record[GROUP_HEADER..]).take(count) {
+                apply_wal_record(entry, lossy, apply)?;
+            }
+        }
+        return Ok(());
     }
+    
+    let value = match kind {
+        RT_SET if record.len() >= 17 + klen => {
+            let mut vlen_bytes = [0u8; 4];
+            vlen_bytes.copy_from_slice(&record[13 + klen..17 + klen]);
+            let vlen = u32::from_le_bytes(vlen_bytes) as usize;
+            record.get(17 + klen..17 + klen + vlen)
+        }
+        _ => None,
+    };
+    let (key, value) = wal_text(&record[13..13 + klen], value, lossy)?;
+    apply(kind, seq, key, value);
+    Ok(())
 }
 
-// Helper functions
-fn parent_path(path: &str) -> Option<String> {
-    if let Some(idx) = path.rfind('/') {
-        if idx > 0 {
-            Some(path[..idx].to_string())
+// Split the entries of a group commit, or of the WAL's still-unsynced buffer, into records
+fn group_records(entries: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let len = entries.get(pos..pos + 4)?;
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        let record = entries.get(pos + 4..pos + 4 + len)?;
+        pos += 4 + len;
+        Some(record)
+    })
+}
+
+// Decode the ops in a WriteBatch payload as (kind, seq offset, key, value), as text. A
+// WriteBatch is only ever built from &str; the WAL checks what it reads back with batch_ops.
+fn batch_records(payload: &[u8]) -> impl Iterator<Item = (u8, u64, Cow<'_, str>, Option<Cow<'_, str>>)> {
+    batch_ops(payload).map(|(kind, offset, key, value)| (kind, offset, String::from_utf8_lossy(key), value.map(String::from_utf8_lossy)))
+}
+
+// The ops in a WriteBatch payload as bytes
+fn batch_ops(payload: &[u8]) -> impl Iterator<Item = (u8, u64, &[u8], Option<&[u8]>)> {
+    let read_u32 = |pos: usize| payload.get(pos..pos + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let mut pos = 0;
+    
+    std::iter::from_fn(move || {
+        let kind = *payload.get(pos)?;
+        let offset = read_u32(pos + 1)? as u64;
+        let klen = read_u32(pos + 5)? as usize;
+        let key = payload.get(pos + 9..pos + 9 + klen)?;
+        pos += 9 + klen;
+        
+        let value = if kind == RT_SET {
+            let vlen = read_u32(pos)? as usize;
+            let value = payload.get(pos + 4..pos + 4 + vlen)?;
+            pos += 4 + vlen;
+            Some(value)
         } else {
             None
-        }
-    } else {
-        None
+        };
+        
+        Some((kind, offset, key, value))
+    })
+}
+
+// bytes as text: borrowed when they are UTF-8, with replacement characters under
+// StoreOptions::lossy_reads, None otherwise
+fn read_text(bytes: &[u8], lossy: bool) -> Option<Cow<'_, str>> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Some(Cow::Borrowed(text)),
+        Err(_) if lossy => Some(String::from_utf8_lossy(bytes)),
+        Err(_) => None,
     }
 }
 
-fn crc32(data: &[u8]) -> u32 {
-    let mut crc = 0xffffffff;
-    for &byte in data {
-        crc ^= byte as u32;
-        for _ in 0..8 {
+// CRC-32 (IEEE), a byte at a time through a table built at compile time
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
             crc = if crc & 1 != 0 {
                 (crc >> 1) ^ 0xedb88320
             } else {
                 crc >> 1
             };
+            bit += 1;
         }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffff;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
     }
     crc ^ 0xffffffff
 }
@@ -2216,7 +12710,7 @@ fn xxhash(data: &[u8], seed: u64) -> u64 {
             val |= (b as u64) << (i * 8);
         }
         h = h.wrapping_mul(0x9e3779b97f4a7c15).wrapping_add(val);
-        h = (h << 31) | (h >> 33);
+        h = h.rotate_left(31);
     }
     h
 }
@@ -2239,21 +12733,19 @@ mod tests {
         store.set("obj/b", "n:2", false).unwrap();
         store.set("obj/c/d", "n:3", false).unwrap();
         
-        // Before flush - should reconstruct
-        let result = store.get("obj").unwrap();
-        assert!(result.is_some(), "Should reconstruct object before flush");
+        // Core returns flat entries only - the parent itself has no value
+        assert!(store.get("obj").unwrap().is_none());
+        assert_eq!(store.get("obj/c/d").unwrap(), Some("n:3".to_string()));
         
         // Flush to segments
         store.flush().unwrap();
-        
-        // After flush - should still reconstruct
-        let result2 = store.get("obj").unwrap();
-        assert!(result2.is_some(), "Should reconstruct object after flush");
+        assert!(store.get("obj").unwrap().is_none());
+        assert_eq!(store.get("obj/a").unwrap(), Some("n:1".to_string()));
         
         // Reopen store
         let store2 = Store::open(dir.path()).unwrap();
-        let result3 = store2.get("obj").unwrap();
-        assert!(result3.is_some(), "Should reconstruct object after reopen");
+        assert_eq!(store2.get("obj/b").unwrap(), Some("n:2".to_string()));
+        assert_eq!(store2.get("obj/c/d").unwrap(), Some("n:3".to_string()));
     }
     
     #[test]
@@ -2267,12 +12759,10 @@ mod tests {
             ("new/b".to_string(), "2".to_string()),
         ], Some("new")).unwrap();
         
-        let result = store.get("new").unwrap();
-        assert!(result.is_some(), "Should reconstruct new object");
+        assert_eq!(store.get("new/a").unwrap(), Some("1".to_string()));
         
         store.flush().unwrap();
-        let result2 = store.get("new").unwrap();
-        assert!(result2.is_some(), "Should reconstruct after flush");
+        assert_eq!(store.get("new/b").unwrap(), Some("2".to_string()));
         
         // Scenario 2: Replace existing scalar with object
         store.set("scalar", "oldvalue", false).unwrap();
@@ -2284,12 +12774,12 @@ mod tests {
         ], Some("scalar")).unwrap();
         
         // Should have replaced the scalar
-        assert!(store.get("scalar").unwrap().is_some());
+        assert!(store.get("scalar").unwrap().is_none());
         assert!(store.get("scalar/a").unwrap().is_some());
         
         store.flush().unwrap();
         let store2 = Store::open(dir.path()).unwrap();
-        assert!(store2.get("scalar").unwrap().is_some(), "Should reconstruct after reopen");
+        assert!(store2.get("scalar").unwrap().is_none(), "Scalar should stay replaced after reopen");
         assert!(store2.get("scalar/a").unwrap().is_some());
         
         // Scenario 3: Replace existing object with new object
@@ -2313,7 +12803,7 @@ mod tests {
         
         store.flush().unwrap();
         let store3 = Store::open(dir.path()).unwrap();
-        assert!(store3.get("existing").unwrap().is_some(), "Should reconstruct");
+        assert!(store3.get("existing/new2").unwrap().is_some());
         assert!(store3.get("existing/old1").unwrap().is_none());
         assert!(store3.get("existing/new1").unwrap().is_some());
     }
@@ -2332,10 +12822,9 @@ mod tests {
             ("root/settings/theme".to_string(), "dark".to_string()),
         ], Some("root")).unwrap();
         
-        // Should reconstruct at all levels
-        assert!(store.get("root").unwrap().is_some());
-        assert!(store.get("root/users").unwrap().is_some());
-        assert!(store.get("root/users/alice").unwrap().is_some());
+        // Leaves are readable at every depth
+        assert!(store.get("root/users/alice/name").unwrap().is_some());
+        assert!(store.get("root/settings/theme").unwrap().is_some());
         
         store.flush().unwrap();
         
@@ -2354,9 +12843,8 @@ mod tests {
         store.flush().unwrap();
         let store2 = Store::open(dir.path()).unwrap();
         
-        // Everything should reconstruct properly
-        assert!(store2.get("root").unwrap().is_some());
-        assert!(store2.get("root/users/alice").unwrap().is_some());
+        // Replaced subtree should survive reopen
+        assert_eq!(store2.get("root/users/alice/name").unwrap(), Some("Alicia".to_string()));
         assert!(store2.get("root/users/alice/email").unwrap().is_some());
         assert!(store2.get("root/users/alice/age").unwrap().is_none());
     }
@@ -2382,127 +12870,225 @@ mod tests {
     }
     
     #[test]
-    #[ignore] // This test requires special setup
-    fn test_all_layers_reconstruction() {
-        use std::sync::Arc;
-        use std::path::PathBuf;
-        
-        // Test setup
+    fn test_system_keys_hidden() {
         let dir = tempdir().unwrap();
-        let path = dir.path();
-        
-        println!("\n=== Testing object reconstruction across all layers ===\n");
-        
-        // Test 1: Core Store layer
-        println!("1. Testing Core Store layer:");
-        {
-            let store = Store::open(path).unwrap();
-            
-            // Simulate what JS does: setMany with replaceAt for new object
-            store.set_many(vec![
-                ("obj/a".to_string(), "n:1".to_string()),
-                ("obj/b".to_string(), "n:2".to_string()),
-            ], Some("obj")).unwrap();
-            
-            let result_before = store.get("obj").unwrap();
-            println!("  Core before flush: {:?}", result_before);
-            assert!(result_before.is_some(), "Core should reconstruct before flush");
-            
-            store.flush().unwrap();
-            
-            let result_after = store.get("obj").unwrap();
-            println!("  Core after flush: {:?}", result_after);
-            assert!(result_after.is_some(), "Core should reconstruct after flush");
-        }
+        let store = Store::open(dir.path()).unwrap();
+        let changes = store.subscribe_coalesced("", Duration::from_millis(10)).unwrap();
         
-        // Clean directory for next test
-        std::fs::remove_dir_all(path).unwrap();
-        std::fs::create_dir_all(path).unwrap();
+        // One system key in a segment, one in the memtable, around a user key
+        store.system_set("index/a", "1").unwrap();
+        store.set("users/alice", "Alice", false).unwrap();
+        store.flush().unwrap();
+        store.system_set("index/b", "2").unwrap();
+        assert_eq!(store.system_get("index/a").unwrap(), Some("1".to_string()));
+        assert_eq!(store.system_get("index/b").unwrap(), Some("2".to_string()));
+        
+        let user = vec![("users/alice".to_string(), "Alice".to_string())];
+        assert_eq!(store.get_range("", &prefix_end("")).unwrap(), user);
+        assert_eq!(store.get_range_limit("", &prefix_end(""), 10).unwrap(), user);
+        assert_eq!(store.scan_prefix("", 10).unwrap(), user);
+        assert!(store.scan_prefix(SYSTEM_PREFIX, 10).unwrap().is_empty());
+        assert_eq!(store.range_iter("", &prefix_end("")).unwrap().collect::<Result<Vec<_>>>().unwrap(), user);
+        assert!(store.scan_prefix_iter(SYSTEM_PREFIX).unwrap().next().is_none());
+        assert_eq!(store.get_pattern("*").unwrap(), user);
+        assert!(store.get_pattern(&format!("{}*", SYSTEM_PREFIX)).unwrap().is_empty());
+        assert_eq!(store.get_subtree_json("").unwrap(), Some("{\"users\":{\"alice\":\"Alice\"}}".to_string()));
+        assert_eq!(store.get_range_raw("", &prefix_end("")).unwrap().len(), 1);
+        assert_eq!(store.sync_digest("", SyncGranularity::Children).unwrap().len(), 1);
+        assert!(store.list_namespaces().unwrap().is_empty());
+        let search = SearchOptions { pattern: "*".to_string(), filters: None, vector: None, text: None, scoring: None, limit: None, order_by: None };
+        assert!(store.search(search).unwrap().iter().all(|(group, _)| !is_system_key(group)));
+        let batch = changes.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(batch.changes.keys().collect::<Vec<_>>(), vec!["users/alice"]);
+        
+        // Export leaves them out unless asked
+        let mut out = Vec::new();
+        assert_eq!(store.export(&mut out, ExportOptions::default()).unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap(), "{\"key\":\"users/alice\",\"value\":\"Alice\"}\n");
+        let mut out = Vec::new();
+        assert_eq!(store.export(&mut out, ExportOptions { include_system: true }).unwrap(), 3);
+        assert!(String::from_utf8(out).unwrap().starts_with("{\"key\":\"\\u0001sys/index/a\",\"value\":\"1\"}\n"));
+        
+        // User writes can't reach them, whatever the method
+        let key = format!("{}index/a", SYSTEM_PREFIX);
+        let rejected = |result: Result<()>| matches!(result, Err(WalDbError::InvalidKey { .. }));
+        assert!(rejected(store.set(&key, "x", false).map(|_| ())));
+        assert!(rejected(store.set(&format!("{}anything", SYSTEM_MARKER), "x", false).map(|_| ())));
+        assert!(rejected(store.set_many(vec![(key.clone(), "x".to_string())], None).map(|_| ())));
+        assert!(rejected(store.increment(&key, 1).map(|_| ())));
+        assert!(rejected(store.delete(&key).map(|_| ())));
+        assert!(rejected(store.delete_subtree(SYSTEM_PREFIX).map(|_| ())));
+        assert!(rejected(store.move_subtree(SYSTEM_PREFIX, "stolen").map(|_| ())));
+        let mut batch = WriteBatch::new();
+        batch.put(&key, "x");
+        assert!(rejected(store.write(batch).map(|_| ())));
+        assert_eq!(store.system_get("index/a").unwrap(), Some("1".to_string()));
+        
+        store.system_delete("index/a").unwrap();
+        assert_eq!(store.system_get("index/a").unwrap(), None);
+    }
+    
+    #[test]
+    fn test_manifest_file_numbers() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("manifest.log");
+        let entry = |filename: &str| ManifestEntry { seq_high: 1, level: 0, filename: filename.to_string(), archive_of: None, inputs: Vec::new(), bounds: None };
+        
+        let mut manifest = Manifest::load(&path).unwrap();
+        let first = manifest.new_file_name().unwrap();
+        assert_eq!(first, "000001.seg");
+        manifest.add_entries(vec![entry(&first)]).unwrap();
+        assert_eq!(manifest.append(entry(&first)).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        
+        // A number handed out but never listed stays taken
+        assert_eq!(manifest.new_file_name().unwrap(), "000002.seg");
+        manifest.sync().unwrap();
+        drop(manifest);
+        let mut manifest = Manifest::load(&path).unwrap();
+        assert_eq!(manifest.new_file_name().unwrap(), "000003.seg");
+        
+        // So is every number an entry names, counter line or not
+        manifest.add_entries(vec![entry("d1/000040.seg")]).unwrap();
+        drop(manifest);
+        let lines: String = std::fs::read_to_string(&path).unwrap().lines().filter(|line| !line.starts_with("file|")).map(|line| format!("{}\n", line)).collect();
+        std::fs::write(&path, lines).unwrap();
+        assert_eq!(Manifest::load(&path).unwrap().new_file_name().unwrap(), "000041.seg");
+        assert_eq!(segment_file_number("l0_0000000042.seg"), None);
+    }
+    
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_lock_order_checks() {
+        let dir = tempdir().unwrap();
+        let store = Store::open(dir.path()).unwrap();
         
-        // Test 2: Async wrapper layer
-        println!("\n2. Testing Async wrapper layer:");
+        // In order, and with the inner lock, a flush and the manifest taken and released
         {
-            // Import async runtime inline
-            use std::sync::Arc;
-            
-            // Create a tokio runtime for testing
-            let runtime = tokio::runtime::Runtime::new().unwrap();
-            
-            runtime.block_on(async {
-                // Include the async wrapper module
-                mod async_store {
-                    include!("async_wrapper.rs");
-                }
-                use async_store::AsyncStore;
-                
-                let store = AsyncStore::open(path).await.unwrap();
-                
-                // Same operation through async layer
-                store.set_many(vec![
-                    ("obj/a".to_string(), "n:1".to_string()),
-                    ("obj/b".to_string(), "n:2".to_string()),
-                ], Some("obj")).await.unwrap();
-                
-                let result_before = store.get("obj").await.unwrap();
-                println!("  Async before flush: {:?}", result_before);
-                assert!(result_before.is_some(), "Async should reconstruct before flush");
-                
-                store.flush().await.unwrap();
-                
-                let result_after = store.get("obj").await.unwrap();
-                println!("  Async after flush: {:?}", result_after);
-                assert!(result_after.is_some(), "Async should reconstruct after flush");
-            });
+            let _admin = store.admin_lock().unwrap();
+            let _inner = store.read_inner().unwrap();
+            let _manifest = store.manifest_locked().unwrap();
         }
+        store.set("a", "1", false).unwrap();
+        store.flush().unwrap();
         
-        // Clean directory for next test
-        std::fs::remove_dir_all(path).unwrap();
-        std::fs::create_dir_all(path).unwrap();
-        
-        // Test 3: What the Node binding does (simulated)
-        println!("\n3. Testing Node binding simulation:");
-        {
-            // This simulates exactly what the Node.js binding does
-            let runtime = tokio::runtime::Runtime::new().unwrap();
-            
-            runtime.block_on(async {
-                mod async_store {
-                    include!("../../../async_wrapper.rs");
-                }
-                use async_store::AsyncStore;
-                
-                let store = AsyncStore::open(path).await.unwrap();
-                
-                // Node.js passes flattened entries with type prefixes
-                let mut entries = std::collections::HashMap::new();
-                entries.insert("obj/a".to_string(), "n:1".to_string());
-                entries.insert("obj/b".to_string(), "n:2".to_string());
-                
-                // Convert to Vec like the binding does
-                let entries_vec: Vec<(String, String)> = entries.into_iter().collect();
-                
-                // Call with replaceAt like JS does
-                store.set_many(entries_vec, Some("obj")).await.unwrap();
-                
-                let result_before = store.get("obj").await.unwrap();
-                println!("  Binding simulation before flush: {:?}", result_before);
-                assert!(result_before.is_some(), "Binding sim should reconstruct before flush");
-                
-                store.flush().await.unwrap();
-                
-                let result_after = store.get("obj").await.unwrap();
-                println!("  Binding simulation after flush: {:?}", result_after);
-                assert!(result_after.is_some(), "Binding sim should reconstruct after flush");
-                
-                // Also test reopening (like Node does)
-                drop(store);
-                let store2 = AsyncStore::open(path).await.unwrap();
-                let result_reopen = store2.get("obj").await.unwrap();
-                println!("  Binding simulation after reopen: {:?}", result_reopen);
-                assert!(result_reopen.is_some(), "Binding sim should reconstruct after reopen");
-            });
-        }
+        // Out of order, or a second read of the inner lock, panics before blocking
+        let inverted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _inner = store.read_inner().unwrap();
+            let _admin = store.admin_lock();
+        }));
+        assert!(inverted.is_err());
+        let recursive = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _inner = store.read_inner().unwrap();
+            let _again = store.read_inner();
+        }));
+        assert!(recursive.is_err());
+        
+        // Guards dropped while unwinding leave nothing behind, and try_admin may go under Inner
+        let inner = store.read_inner().unwrap();
+        assert!(store.try_admin().unwrap().is_some());
+        drop(inner);
+        HELD_LOCKS.with(|held| assert!(held.borrow().is_empty()));
+    }
+    
+    #[test]
+    fn test_wal_group_frame() {
+        // The table gives the standard CRC-32 check value
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+        
+        // One frame and CRC for the whole group, and its records come back in order
+        let mut buffer = WalBuffer::default();
+        buffer.push(&WALEntry { seq: 7, kind: RT_SET, key: "a", value: Some("1") });
+        buffer.push(&WALEntry { seq: 8, kind: RT_DEL_POINT, key: "b", value: None });
+        buffer.push(&WALEntry { seq: 9, kind: RT_DEL_SUB, key: "c/", value: None });
+        let mut frame = Vec::new();
+        buffer.encode_group(&mut frame);
+        assert_eq!(frame.len(), 4 + GROUP_HEADER + buffer.data.len() + 4);
+        
+        let mut seen = Vec::new();
+        let bytes = read_wal_records(frame.as_slice(), false, |kind, seq, key, value| {
+            seen.push((kind, seq, key.into_owned(), value.map(Cow::into_owned)));
+        });
+        assert_eq!(bytes, Ok(frame.len() as u64));
+        assert_eq!(seen, vec![
+            (RT_SET, 7, "a".to_string(), Some("1".to_string())),
+            (RT_DEL_POINT, 8, "b".to_string(), None),
+            (RT_DEL_SUB, 9, "c/".to_string(), None),
+        ]);
+        
+        // A flipped bit anywhere drops the whole group
+        frame[30] ^= 1;
+        assert_eq!(read_wal_records(frame.as_slice(), false, |_, _, _, _| panic!("corrupt group replayed")), Ok(0));
+    }
+    
+    #[test]
+    fn test_wal_text_not_utf8() {
+        // A group behind a good one, with a byte of its text made invalid and its CRC redone
+        let frame_with = |bad: &str| {
+            let (mut frame, mut start) = (Vec::new(), 0);
+            for (seq, key, value) in [(1, "ok", "fine"), (2, "key/b", "value")] {
+                let mut buffer = WalBuffer::default();
+                buffer.push(&WALEntry { seq, kind: RT_SET, key, value: Some(value) });
+                start = frame.len();
+                buffer.encode_group(&mut frame);
+            }
+            let at = frame.windows(bad.len()).rposition(|w| w == bad.as_bytes()).unwrap();
+            frame[at] = 0xff;
+            let end = frame.len() - 4;
+            let crc = crc32(&frame[start + 4..end]);
+            frame[end..].copy_from_slice(&crc.to_le_bytes());
+            (frame, start as u64)
+        };
+        let replay = |frame: &[u8], lossy: bool| {
+            let mut seen = Vec::new();
+            let result = read_wal_records(frame, lossy, |_, _, key, value| seen.push((key.into_owned(), value.map(Cow::into_owned))));
+            (result, seen)
+        };
         
-        println!("\n✅ All layers passed!");
+        // Strict reads stop at the frame, naming where it starts
+        let (frame, start) = frame_with("value");
+        let (result, seen) = replay(&frame, false);
+        assert_eq!(result, Err((start, BadText::Value("key/b".to_string()))));
+        assert_eq!(seen, vec![("ok".to_string(), Some("fine".to_string()))]);
+        let error = BadText::Value("key/b".to_string()).error(Path::new("wal.log"), start + 4);
+        assert!(matches!(error, WalDbError::InvalidUtf8 { ref path, offset, .. } if path == "key/b" && offset == start + 4));
+        
+        let (frame, start) = frame_with("key/b");
+        assert_eq!(replay(&frame, false).0, Err((start, BadText::Key)));
+        assert!(matches!(BadText::Key.error(Path::new("wal.log"), 9), WalDbError::Corruption { offset: 9, .. }));
+        
+        // Lossy reads go on with a replacement character
+        let (result, seen) = replay(&frame, true);
+        assert_eq!(result, Ok(frame.len() as u64));
+        assert_eq!(seen[1], ("\u{fffd}ey/b".to_string(), Some("value".to_string())));
+    }
+    
+    #[test]
+    fn test_poisoned_locks() {
+        let dir = tempdir().unwrap();
+        let handle = StoreHandle::new(Store::open(dir.path()).unwrap());
+        handle.set("a", "1", false).unwrap();
+        
+        // A panic holding the slot fails calls through it rather than panicking them too
+        let slot = Arc::clone(&handle.slot);
+        std::thread::spawn(move || {
+            let _slot = slot.write().unwrap();
+            panic!("poisoning the store slot");
+        }).join().unwrap_err();
+        assert!(matches!(handle.get("a"), Err(WalDbError::Locked(_))));
+        assert_eq!(handle.current().get("a").unwrap().as_deref(), Some("1"));
+        
+        // Submission order only keeps a map under its lock, so it carries on
+        let order = SubmissionOrder::new();
+        let poisoner = Arc::clone(&order);
+        std::thread::spawn(move || {
+            let _state = poisoner.state.lock().unwrap();
+            panic!("poisoning the submission order");
+        }).join().unwrap_err();
+        let first = order.submit(Access::Write);
+        let second = order.submit(Access::Read);
+        first.wait();
+        drop(first);
+        second.wait();
     }
-}
\ No newline at end of file
+}
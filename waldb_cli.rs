@@ -1,32 +1,551 @@
 // Interactive CLI for WalDB Store
-// Provides a shell interface to test all features
+// Provides a shell interface to test all features, plus one-shot commands for scripting:
+//   waldb-cli [dir] [command args...] [--json] [--quiet] [--limit N] [--replace]
+//   waldb-cli [dir] clear-all --yes
+//   waldb-cli [dir] dump-seg <file> [--values] [--hex]
+//   waldb-cli [dir] bench [writes|reads|scan|mixed] [--ops N] [--keyspace existing|synthetic] [--prefix P] [--threads T]
+//   waldb-cli [dir] warmup [prefix...] [--level N]... [--max-bytes N]
+//   waldb-cli [dir] stats --history <window>
+//   waldb-cli [dir] reads-report [prefix]
+//   waldb-cli [dir] snapshot [prefix] > file, import <file> [chunk], verify-snapshot <file>
 
-use std::io::{self, Write};
-use std::path::Path;
-use std::time::Instant;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use waldb::Store;
+use waldb::{CacheStats, CopyMode, DumpOptions, ExportOptions, HealthCheckOptions, HealthReport, HealthStatus, ImportOptions, JsonOptions, L0Stats, MetricsSnapshot, ReadAmplificationReport, ReadCost, SnapshotInfo, SnapshotOptions, Store, SubtreeJson, TimestampedSnapshot, WarmupOptions, WarmupReport};
+
+// One-shot exit codes
+const EXIT_OK: i32 = 0;
+const EXIT_NOT_FOUND: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+// bench
+const BENCH_PREFIX: &str = "waldb-bench";  // Bench writes go in a subtree of this per run, deleted after
+const BENCH_OPS: usize = 10_000;  // Per mode, across all threads
+const BENCH_SAMPLE: usize = 10_000;  // Keys reads and scans pick from
+const BENCH_SAMPLE_WALK: usize = 1_000_000;  // Existing keys walked to sample them from
+const BENCH_SCAN_LENGTH: usize = 100;  // Entries each scan op reads
+const BENCH_VALUE_BYTES: usize = 100;
+const HISTOGRAM_SUB_BUCKETS: u64 = 8;  // Per power of two of nanoseconds
+
+#[derive(Debug, Default)]
+struct CliOptions {
+    json: bool,
+    quiet: bool,
+    limit: Option<usize>,
+    replace: bool,
+    yes: bool,  // Confirms clear-all
+    dump: DumpOptions,
+    export: ExportOptions,
+}
 
 fn main() -> io::Result<()> {
-    println!("🗄️ WalDB CLI v0.1.0");
-    println!("Type 'help' for commands, 'quit' to exit\n");
+    let mut opts = CliOptions::default();
+    let mut positional = Vec::new();
+    
+    // The store path may not be valid UTF-8, so arguments stay OsStrings until parsed
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str().unwrap_or_default() {
+            "--json" => opts.json = true,
+            "--quiet" | "-q" => opts.quiet = true,
+            "--replace" => opts.replace = true,
+            "--yes" => opts.yes = true,
+            "--values" => opts.dump.values = true,
+            "--hex" => opts.dump.hex = true,
+            "--include-system" => opts.export.include_system = true,
+            "--limit" => match args.next().and_then(|n| n.to_str()?.parse().ok()) {
+                Some(n) => opts.limit = Some(n),
+                None => {
+                    eprintln!("--limit needs a number");
+                    process::exit(EXIT_ERROR);
+                }
+            },
+            "--help" | "-h" => {
+                print_usage();
+                return Ok(());
+            }
+            _ => positional.push(arg),
+        }
+    }
+    
+    // Open or create store (defaults to ./waldb_data)
+    let store_path = positional.first().map_or_else(|| PathBuf::from("./waldb_data"), PathBuf::from);
     
-    // Open or create store in current directory
-    let store_path = "./waldb_data";
-    println!("Opening store at: {}", store_path);
-    let store = Store::open(Path::new(store_path))?;
-    println!("Store ready!\n");
+    if positional.len() > 1 {
+        let command: Vec<String> = match positional.drain(1..).map(OsString::into_string).collect() {
+            Ok(command) => command,
+            Err(arg) => {
+                eprintln!("✗ Command arguments must be valid UTF-8: {}", arg.to_string_lossy());
+                process::exit(EXIT_ERROR);
+            }
+        };
+        // Reads the file directly, so it works on a store that is in use or can't be opened
+        match command.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            ["dump-seg", file] => process::exit(dump_segment(&store_path.join(file), &opts)),
+            ["stats", "--history", window] => process::exit(stats_history(&store_path, window, &opts)),
+            ["verify-snapshot", file] => process::exit(verify_snapshot(Path::new(file), &opts)),
+            _ => {}
+        }
+        let store = match Store::open(&store_path) {
+            Ok(store) => store,
+            Err(e) => {
+                eprintln!("✗ Error opening store at {}: {}", store_path.display(), e);
+                process::exit(EXIT_ERROR);
+            }
+        };
+        let code = run_command(&store, &command, &opts);
+        drop(store);
+        process::exit(code);
+    }
+    
+    if !opts.quiet {
+        println!("🗄️ WalDB CLI v0.1.0");
+        println!("Type 'help' for commands, 'quit' to exit\n");
+        println!("Opening store at: {}", store_path.display());
+    }
+    let store = Store::open(&store_path)?;
+    if !opts.quiet {
+        println!("Store ready!\n");
+    }
+    
+    repl(&store, &opts)
+}
+
+// Run a single command from the command line, returning the process exit code
+fn run_command(store: &Store, command: &[String], opts: &CliOptions) -> i32 {
+    let args: Vec<&str> = command.iter().map(String::as_str).collect();
+    let limit = opts.limit.unwrap_or(usize::MAX);
+    
+    let result = match args.as_slice() {
+        // A trailing slash asks for the whole subtree as one JSON document
+        ["get", key] if key.ends_with('/') => match store.get_subtree_json_with_options(key, JsonOptions::default()) {
+            Ok(Some(tree)) => {
+                if !opts.quiet {
+                    warn_conflicts(&tree);
+                }
+                if opts.json {
+                    println!("{}", tree.json);
+                } else {
+                    println!("{}", pretty_json(&tree.json));
+                }
+                Ok(EXIT_OK)
+            }
+            Ok(None) => Ok(EXIT_NOT_FOUND),
+            Err(e) => Err(e),
+        },
+        ["get", key] => match store.get(key) {
+            Ok(Some(value)) => {
+                if opts.json {
+                    println!("{}", json_entry(key, &value));
+                } else {
+                    println!("{}", value);
+                }
+                Ok(EXIT_OK)
+            }
+            Ok(None) => Ok(EXIT_NOT_FOUND),
+            Err(e) => Err(e),
+        },
+        ["asof", seq, "get", key] => {
+            let Ok(seq) = seq.parse::<u64>() else {
+                eprintln!("asof needs a seq number");
+                return EXIT_ERROR;
+            };
+            match store.as_of(seq).and_then(|snapshot| snapshot.get(key)) {
+                Ok(Some(value)) => {
+                    if opts.json {
+                        println!("{}", json_entry(key, &value));
+                    } else {
+                        println!("{}", value);
+                    }
+                    Ok(EXIT_OK)
+                }
+                Ok(None) => Ok(EXIT_NOT_FOUND),
+                Err(e) => Err(e),
+            }
+        }
+        ["set", key, value @ ..] if !value.is_empty() => {
+            let value = value.join(" ");
+            store.set(key, &value, opts.replace).map(|_| {
+                if !opts.quiet {
+                    println!("✓ Set '{}' = '{}'", key, value);
+                }
+                EXIT_OK
+            })
+        }
+        ["incr", key, delta @ ..] if delta.len() <= 1 => {
+            let Ok(delta) = delta.first().map_or(Ok(1), |d| d.parse::<i64>()) else {
+                eprintln!("incr needs an integer delta");
+                return EXIT_ERROR;
+            };
+            store.increment(key, delta).map(|value| {
+                if opts.json {
+                    println!("{}", json_entry(key, &value.to_string()));
+                } else {
+                    println!("{}", value);
+                }
+                EXIT_OK
+            })
+        }
+        ["mv", from, to] => store.move_subtree(from, to).map(|moved| {
+            if !opts.quiet {
+                println!("✓ Moved {} keys from '{}' to '{}'", moved, from, to);
+            }
+            EXIT_OK
+        }),
+        ["cp", from, to, mode @ ..] if mode.len() <= 1 => {
+            let Some(mode) = parse_copy_mode(mode.first().copied()) else {
+                eprintln!("cp mode must be fail, skip or replace");
+                return EXIT_ERROR;
+            };
+            store.copy_subtree(from, to, mode).map(|copied| {
+                if !opts.quiet {
+                    println!("✓ Copied {} keys from '{}' to '{}'", copied, from, to);
+                }
+                EXIT_OK
+            })
+        }
+        ["delete", key] => store.delete(key).map(|_| {
+            if !opts.quiet {
+                println!("✓ Deleted '{}'", key);
+            }
+            EXIT_OK
+        }),
+        ["clear-all"] if !opts.yes => {
+            eprintln!("clear-all deletes every key in the store; add --yes to go ahead");
+            return EXIT_ERROR;
+        }
+        ["clear-all"] => store.clear_all().map(|_| {
+            if !opts.quiet {
+                println!("✓ Cleared the store");
+            }
+            EXIT_OK
+        }),
+        ["scan", prefix] => store.scan_prefix(prefix, limit).map(|r| print_entries(&r, opts)),
+        ["range", start, end] => store.get_range_limit(start, end, limit).map(|r| print_entries(&r, opts)),
+        ["pattern", pattern] => store.get_pattern(pattern)
+            .map(|r| print_entries(&r[..r.len().min(limit)], opts)),
+        // Export is always JSON lines so values round-trip exactly
+        ["export"] => store.export(io::stdout().lock(), opts.export).map(|_| EXIT_OK),
+        ["snapshot", prefix @ ..] if prefix.len() <= 1 => {
+            let options = SnapshotOptions { prefix: prefix.first().unwrap_or(&"").to_string(), ..Default::default() };
+            store.export_snapshot(io::stdout().lock(), &options).map(|_| EXIT_OK)
+        }
+        // A failed import names the damaged chunk; once the file is repaired, pass it to resume there
+        ["import", file, resume @ ..] if resume.len() <= 1 => {
+            let Some(resume_from_chunk) = resume.first().map_or(Some(0), |chunk| chunk.parse().ok()) else {
+                eprintln!("import's chunk must be a number");
+                return EXIT_ERROR;
+            };
+            File::open(file).map_err(Into::into)
+                .and_then(|f| store.import(BufReader::new(f), ImportOptions { resume_from_chunk }))
+                .map(|report| {
+                    if !opts.quiet {
+                        println!("✓ Imported {} entries in {} of {} chunks", report.entries_applied, report.chunks_applied, report.snapshot.chunks);
+                    }
+                    EXIT_OK
+                })
+        }
+        ["stats"] => seq_info(store).and_then(|seqs| Ok((seqs, store.l0_stats()?))).map(|(seqs, l0_stats)| {
+            let (l0, l1, l2) = store.segment_counts();
+            if opts.json {
+                let (seq, durable, flushed) = seqs;
+                println!("{{\"l0\":{},\"l1\":{},\"l2\":{},\"l0_pressure\":\"{}\",\"store_id\":\"{}\",\"format_version\":{},\"seq\":{},\"durable_seq\":{},\"flushed_seq\":{}}}",
+                    l0, l1, l2, l0_stats.pressure, store.store_id(), store.format_version(), seq, durable, flushed);
+            } else {
+                print_stats(store, seqs, &l0_stats);
+            }
+            EXIT_OK
+        }),
+        ["bench", bench_args @ ..] => return run_benchmark(store, bench_args, opts),
+        // Run alone, the cache it fills goes with the process, but the OS page cache stays warm
+        ["warmup", warmup_args @ ..] => match parse_warmup_args(warmup_args) {
+            Ok(options) => store.warmup(options).map(|report| {
+                if opts.json {
+                    println!("{{\"bytes_loaded\":{},\"blocks_loaded\":{},\"bytes_pinned\":{},\"complete\":{}}}",
+                        report.bytes_loaded, report.blocks_loaded, report.bytes_pinned, report.complete);
+                } else if !opts.quiet {
+                    print_warmup(&report);
+                }
+                EXIT_OK
+            }),
+            Err(message) => {
+                eprintln!("{}", message);
+                return EXIT_ERROR;
+            }
+        },
+        // Counts start at open, so run alone it first gets each key of a sample from under prefix
+        ["reads-report", prefix @ ..] if prefix.len() <= 1 => sample_keys(store, prefix.first().unwrap_or(&"")).and_then(|keys| {
+            for key in &keys {
+                store.get(key)?;
+            }
+            print_read_amplification(&store.read_amplification_report(), opts);
+            Ok(EXIT_OK)
+        }),
+        // Exits 2 unless every check passed, for supervisors that only look at the code
+        ["health"] => store.health_check(HealthCheckOptions { write_probe: true, ..Default::default() }).map(|report| {
+            print_health(&report, opts);
+            if report.healthy() { EXIT_OK } else { EXIT_ERROR }
+        }),
+        _ => {
+            eprintln!("Unknown or incomplete command: {}", args.join(" "));
+            print_usage();
+            return EXIT_ERROR;
+        }
+    };
     
+    result.unwrap_or_else(|e| {
+        eprintln!("✗ Error: {}", e);
+        EXIT_ERROR
+    })
+}
+
+// Exit 0 for a snapshot whose every chunk and trailer check out, 2 otherwise
+fn verify_snapshot(path: &Path, opts: &CliOptions) -> i32 {
+    match Store::verify_export_file(path) {
+        Ok(info) => {
+            if opts.json {
+                print_snapshot_json(&info);
+            } else if !opts.quiet {
+                println!("✓ {} entries in {} chunks under {:?}, seqs {} to {} of store {}",
+                    info.entries, info.chunks, info.prefix, info.seq_floor, info.seq_ceiling, info.store_id);
+            }
+            EXIT_OK
+        }
+        Err(e) => {
+            eprintln!("✗ Error: {}", e);
+            EXIT_ERROR
+        }
+    }
+}
+
+fn print_snapshot_json(info: &SnapshotInfo) {
+    println!("{{\"store_id\":\"{}\",\"prefix\":{},\"seq_floor\":{},\"seq_ceiling\":{},\"chunks\":{},\"entries\":{},\"digest\":\"{:016x}\"}}",
+        info.store_id, json_string(&info.prefix), info.seq_floor, info.seq_ceiling, info.chunks, info.entries, info.digest);
+}
+
+// Exit 0 for a clean segment, 2 if anything was flagged or the file couldn't be read
+fn dump_segment(path: &Path, opts: &CliOptions) -> i32 {
+    match Store::dump_segment_file(path, io::stdout().lock(), opts.dump) {
+        Ok(0) => EXIT_OK,
+        Ok(anomalies) => {
+            eprintln!("✗ {} anomalies in {}", anomalies, path.display());
+            EXIT_ERROR
+        }
+        Err(e) => {
+            eprintln!("✗ Error: {}", e);
+            EXIT_ERROR
+        }
+    }
+}
+
+// The samples stats.ring holds from the last window (30s, 15m, 1h, 2d), one row each with
+// what changed since the row before
+fn stats_history(dir: &Path, window: &str, opts: &CliOptions) -> i32 {
+    let Some(window) = parse_window(window) else {
+        eprintln!("--history needs a window like 30s, 15m, 1h or 2d");
+        return EXIT_ERROR;
+    };
+    let since = SystemTime::now().checked_sub(window).unwrap_or(UNIX_EPOCH);
+    let history = match Store::read_stats_history(dir, since) {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("✗ Error: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+    if opts.json {
+        for sample in &history {
+            println!("{}", json_sample(sample));
+        }
+        return EXIT_OK;
+    }
+    if history.is_empty() {
+        if !opts.quiet {
+            println!("No samples; open the store with StoreOptions::stats_interval to record them");
+        }
+        return EXIT_OK;
+    }
+    
+    println!("{:<8}  {:>9}  {:>8}  {:>7}  {:>9}  {:>8}  {:>10}  {:>6}  {:>8}  {:>6}",
+        "UTC", "mem KiB", "writes", "flushes", "flush ms", "compacts", "compact ms", "syncs", "sync avg", "hit %");
+    let mut previous: Option<&MetricsSnapshot> = None;
+    for sample in &history {
+        let m = &sample.metrics;
+        // Counters restart from 0 when the store is reopened
+        let delta = |now: u64, before: fn(&MetricsSnapshot) -> u64| match previous {
+            Some(p) if before(p) <= now => Some(now - before(p)),
+            Some(_) => Some(now),
+            None => None,
+        };
+        let millis = |now: Duration, before: fn(&MetricsSnapshot) -> Duration| match previous {
+            Some(p) if before(p) <= now => Some((now - before(p)).as_secs_f64() * 1e3),
+            Some(_) => Some(now.as_secs_f64() * 1e3),
+            None => None,
+        };
+        let count = |n: Option<u64>| n.map_or("-".to_string(), |n| n.to_string());
+        let ms = |n: Option<f64>| n.map_or("-".to_string(), |n| format!("{:.1}", n));
+        let syncs = delta(m.wal_syncs, |p| p.wal_syncs);
+        let sync_avg = millis(m.wal_sync_time, |p| p.wal_sync_time).zip(syncs).map(|(ms, n)| if n == 0 { 0.0 } else { ms / n as f64 });
+        let hit_rate = delta(m.cache_hits, |p| p.cache_hits).zip(delta(m.cache_misses, |p| p.cache_misses))
+            .map_or("-".to_string(), |(hits, misses)| match hits + misses {
+                0 => "-".to_string(),
+                total => format!("{:.1}", hits as f64 * 100.0 / total as f64),
+            });
+        println!("{:<8}  {:>9}  {:>8}  {:>7}  {:>9}  {:>8}  {:>10}  {:>6}  {:>8}  {:>6}",
+            utc_time_of_day(sample.at), m.memtable_bytes / 1024, count(delta(m.seq, |p| p.seq)),
+            count(delta(m.flushes, |p| p.flushes)), ms(millis(m.flush_time, |p| p.flush_time)),
+            count(delta(m.compactions, |p| p.compactions)), ms(millis(m.compaction_time, |p| p.compaction_time)),
+            count(syncs), ms(sync_avg), hit_rate);
+        previous = Some(m);
+    }
+    EXIT_OK
+}
+
+// A number of seconds, minutes, hours or days: 30s, 15m, 1h, 2d
+fn parse_window(window: &str) -> Option<Duration> {
+    let unit = match window.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let count: u64 = window[..window.len() - 1].parse().ok()?;
+    Some(Duration::from_secs(count.checked_mul(unit)?))
+}
+
+// HH:MM:SS in UTC; std has no time zones
+fn utc_time_of_day(at: SystemTime) -> String {
+    let secs = at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) % (24 * 60 * 60);
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn json_sample(sample: &TimestampedSnapshot) -> String {
+    let m = &sample.metrics;
+    let micros = |d: Duration| d.as_micros();
+    format!("{{\"at_ms\":{},\"seq\":{},\"durable_seq\":{},\"flushed_seq\":{},\"memtable_bytes\":{},\"flushes\":{},\"flush_us\":{},\"compactions\":{},\"compaction_us\":{},\"compaction_bytes_written\":{},\"wal_syncs\":{},\"wal_sync_us\":{},\"cache_hits\":{},\"cache_misses\":{}}}",
+        sample.at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis()), m.seq, m.durable_seq, m.flushed_seq, m.memtable_bytes,
+        m.flushes, micros(m.flush_time), m.compactions, micros(m.compaction_time), m.compaction_bytes_written,
+        m.wal_syncs, micros(m.wal_sync_time), m.cache_hits, m.cache_misses)
+}
+
+fn print_entries(entries: &[(String, String)], opts: &CliOptions) -> i32 {
+    for (k, v) in entries {
+        if opts.json {
+            println!("{}", json_entry(k, v));
+        } else {
+            println!("{} = {}", k, v);
+        }
+    }
+    EXIT_OK
+}
+
+fn print_health(report: &HealthReport, opts: &CliOptions) {
+    if opts.json {
+        let checks: Vec<String> = report.checks.iter().map(|check| {
+            let (status, detail) = match &check.status {
+                HealthStatus::Passed => ("passed", String::new()),
+                HealthStatus::Failed(detail) => ("failed", format!(",\"detail\":{}", json_string(detail))),
+                HealthStatus::Skipped => ("skipped", String::new()),
+            };
+            format!("{{\"name\":\"{}\",\"status\":\"{}\",\"ms\":{:.3}{}}}", check.name, status, check.duration.as_secs_f64() * 1000.0, detail)
+        }).collect();
+        println!("{{\"healthy\":{},\"checks\":[{}]}}", report.healthy(), checks.join(","));
+        return;
+    }
+    for check in &report.checks {
+        let ms = check.duration.as_secs_f64() * 1000.0;
+        match &check.status {
+            HealthStatus::Passed => println!("✓ {} ({:.2}ms)", check.name, ms),
+            HealthStatus::Failed(detail) => println!("✗ {} ({:.2}ms): {}", check.name, ms, detail),
+            HealthStatus::Skipped => println!("- {} skipped, out of time", check.name),
+        }
+    }
+}
+
+fn print_read_amplification(report: &ReadAmplificationReport, opts: &CliOptions) {
+    if opts.json {
+        let cost = |cost: &ReadCost| format!("{{\"mean\":{:.3},\"p99\":{}}}", cost.mean, cost.p99);
+        let levels: Vec<String> = report.levels.iter().map(|level| {
+            format!("{{\"level\":\"{}\",\"gets\":{},\"segments_probed\":{},\"bloom_negatives\":{},\"blocks_read\":{}}}",
+                level.level, level.gets, cost(&level.segments_probed), cost(&level.bloom_negatives), cost(&level.blocks_read))
+        }).collect();
+        println!("{{\"levels\":[{}]}}", levels.join(","));
+        return;
+    }
+    if report.levels.is_empty() {
+        println!("No gets since open");
+        return;
+    }
+    let cost = |cost: &ReadCost| format!("{:.2} / {}", cost.mean, cost.p99);
+    println!("Gets by the level answering them, costs as mean / p99:");
+    println!("  {:<10} {:>10}  {:<16} {:<16} blocks read", "level", "gets", "segments probed", "bloom negatives");
+    for level in &report.levels {
+        println!("  {:<10} {:>10}  {:<16} {:<16} {}", level.level.to_string(), level.gets,
+            cost(&level.segments_probed), cost(&level.bloom_negatives), cost(&level.blocks_read));
+    }
+}
+
+fn warn_conflicts(tree: &SubtreeJson) {
+    for path in &tree.conflicts {
+        eprintln!("⚠ '{}' holds a value and has children; showing the children", path);
+    }
+}
+
+// cp's optional last argument, fail when left out
+fn parse_copy_mode(mode: Option<&str>) -> Option<CopyMode> {
+    match mode {
+        None | Some("fail") => Some(CopyMode::Fail),
+        Some("skip") => Some(CopyMode::Skip),
+        Some("replace") => Some(CopyMode::Replace),
+        Some(_) => None,
+    }
+}
+
+fn json_entry(key: &str, value: &str) -> String {
+    format!("{{\"key\":{},\"value\":{}}}", json_string(key), json_string(value))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn repl(store: &Store, opts: &CliOptions) -> io::Result<()> {
     let mut input = String::new();
     
     loop {
-        print!("waldb> ");
-        io::stdout().flush()?;
+        if !opts.quiet {
+            print!("waldb> ");
+            io::stdout().flush()?;
+        }
         
         input.clear();
-        io::stdin().read_line(&mut input)?;
+        if io::stdin().read_line(&mut input)? == 0 {
+            // End of piped input
+            break;
+        }
         
-        let parts: Vec<&str> = input.trim().split_whitespace().collect();
+        let parts: Vec<&str> = input.split_whitespace().collect();
         if parts.is_empty() {
             continue;
         }
@@ -61,22 +580,13 @@ fn main() -> io::Result<()> {
                 let key = parts[1];
                 
                 if key.ends_with('/') {
-                    // It's a prefix query - use get_pattern to list all keys
-                    let pattern = format!("{}*", key);
-                    match store.get_pattern(&pattern) {
-                        Ok(results) => {
-                            if results.is_empty() {
-                                println!("(no keys found)");
-                            } else {
-                                println!("Found {} keys:", results.len());
-                                for (k, v) in results.iter().take(20) {
-                                    println!("  {} = {}", k, truncate(v, 50));
-                                }
-                                if results.len() > 20 {
-                                    println!("  ... and {} more", results.len() - 20);
-                                }
-                            }
+                    // It's a subtree query - show it as one JSON document
+                    match store.get_subtree_json_with_options(key, JsonOptions::default()) {
+                        Ok(Some(tree)) => {
+                            warn_conflicts(&tree);
+                            println!("{}", pretty_json(&tree.json));
                         }
+                        Ok(None) => println!("(no keys found)"),
                         Err(e) => println!("✗ Error: {}", e),
                     }
                 } else {
@@ -89,6 +599,64 @@ fn main() -> io::Result<()> {
                 }
             }
             
+            "asof" => {
+                let seq = parts.get(1).and_then(|seq| seq.parse::<u64>().ok());
+                let (Some(seq), Some(&"get"), Some(key), 4) = (seq, parts.get(2), parts.get(3), parts.len()) else {
+                    println!("Usage: asof <seq> get <key>");
+                    continue;
+                };
+                match store.as_of(seq).and_then(|snapshot| snapshot.get(key)) {
+                    Ok(Some(value)) => println!("{}", value),
+                    Ok(None) => println!("(not found)"),
+                    Err(e) => println!("✗ Error: {}", e),
+                }
+            }
+            
+            "incr" => {
+                if parts.len() < 2 || parts.len() > 3 {
+                    println!("Usage: incr <key> [delta]");
+                    continue;
+                }
+                let key = parts[1];
+                let Ok(delta) = parts.get(2).map_or(Ok(1), |d| d.parse::<i64>()) else {
+                    println!("✗ Delta must be an integer");
+                    continue;
+                };
+                
+                match store.increment(key, delta) {
+                    Ok(value) => println!("{}", value),
+                    Err(e) => println!("✗ Error: {}", e),
+                }
+            }
+            
+            "mv" | "move" => {
+                if parts.len() != 3 {
+                    println!("Usage: mv <from> <to>");
+                    continue;
+                }
+                
+                match store.move_subtree(parts[1], parts[2]) {
+                    Ok(moved) => println!("✓ Moved {} keys from '{}' to '{}'", moved, parts[1], parts[2]),
+                    Err(e) => println!("✗ Error: {}", e),
+                }
+            }
+            
+            "cp" | "copy" => {
+                let mode = match parts.len() {
+                    3 | 4 => parse_copy_mode(parts.get(3).copied()),
+                    _ => None,
+                };
+                let Some(mode) = mode else {
+                    println!("Usage: cp <from> <to> [fail|skip|replace]");
+                    continue;
+                };
+                
+                match store.copy_subtree(parts[1], parts[2], mode) {
+                    Ok(copied) => println!("✓ Copied {} keys from '{}' to '{}'", copied, parts[1], parts[2]),
+                    Err(e) => println!("✗ Error: {}", e),
+                }
+            }
+            
             "delete" | "del" | "d" => {
                 if parts.len() < 2 {
                     println!("Usage: delete <key>");
@@ -115,6 +683,19 @@ fn main() -> io::Result<()> {
                 }
             }
             
+            "clear-all" => {
+                if parts.get(1) != Some(&"--yes") {
+                    println!("Usage: clear-all --yes");
+                    println!("  Deletes every key in the store");
+                    continue;
+                }
+                
+                match store.clear_all() {
+                    Ok(_) => println!("✓ Cleared the store"),
+                    Err(e) => println!("✗ Error: {}", e),
+                }
+            }
+            
             "pattern" | "p" => {
                 if parts.len() < 2 {
                     println!("Usage: pattern <pattern>");
@@ -150,6 +731,19 @@ fn main() -> io::Result<()> {
                 }
             }
             
+            "delete-prefix" | "delpre" => {
+                if parts.len() < 2 {
+                    println!("Usage: delete-prefix <prefix>");
+                    continue;
+                }
+                let prefix = parts[1];
+                
+                match store.delete_prefix(prefix) {
+                    Ok(count) => println!("✓ Deleted {} keys starting with '{}'", count, prefix),
+                    Err(e) => println!("✗ Error: {}", e),
+                }
+            }
+            
             "range" | "r" => {
                 if parts.len() < 3 {
                     println!("Usage: range <start> <end> [limit]");
@@ -196,16 +790,49 @@ fn main() -> io::Result<()> {
                 }
             }
             
+            "dump-seg" => {
+                if parts.len() != 2 {
+                    println!("Usage: dump-seg <file>");
+                    continue;
+                }
+                
+                match store.dump_segment(parts[1], io::stdout().lock(), DumpOptions { values: true, hex: false }) {
+                    Ok(0) => {}
+                    Ok(anomalies) => println!("✗ {} anomalies", anomalies),
+                    Err(e) => println!("✗ Error: {}", e),
+                }
+            }
+            
             "stats" => {
-                let (l0, l1, l2) = store.segment_counts();
-                println!("Segment counts:");
-                println!("  L0: {} segments", l0);
-                println!("  L1: {} segments", l1);
-                println!("  L2: {} segments", l2);
+                match seq_info(store).and_then(|seqs| Ok((seqs, store.l0_stats()?))) {
+                    Ok((seqs, l0_stats)) => print_stats(store, seqs, &l0_stats),
+                    Err(e) => println!("✗ Error: {}", e),
+                }
+            }
+            
+            "reads-report" => {
+                print_read_amplification(&store.read_amplification_report(), opts);
+            }
+            
+            "health" => {
+                match store.health_check(HealthCheckOptions { write_probe: true, ..Default::default() }) {
+                    Ok(report) => print_health(&report, opts),
+                    Err(e) => println!("✗ Error: {}", e),
+                }
             }
             
             "bench" => {
-                run_benchmark(&store);
+                run_benchmark(store, &parts[1..], opts);
+            }
+            
+            "warmup" => {
+                match parse_warmup_args(&parts[1..]) {
+                    Ok(options) => match store.warmup(options) {
+                        Ok(report) => print_warmup(&report),
+                        Err(e) => println!("✗ Error: {}", e),
+                    },
+                    Err(message) => println!("{}", message),
+                }
             }
             
             "load" => {
@@ -231,7 +858,7 @@ fn main() -> io::Result<()> {
                     continue;
                 }
                 let prefix = parts[1];
-                print_tree(&store, prefix, 0, 3);
+                print_tree(store, prefix, 0, 3);
             }
             
             "clear" => {
@@ -240,7 +867,9 @@ fn main() -> io::Result<()> {
             }
             
             "quit" | "exit" | "q" => {
-                println!("Goodbye!");
+                if !opts.quiet {
+                    println!("Goodbye!");
+                }
                 break;
             }
             
@@ -249,28 +878,80 @@ fn main() -> io::Result<()> {
             }
         }
         
-        let elapsed = start.elapsed();
-        if elapsed.as_millis() > 0 {
-            println!("({}ms)", elapsed.as_millis());
+        if !opts.quiet {
+            let elapsed = start.elapsed();
+            if elapsed.as_millis() > 0 {
+                println!("({}ms)", elapsed.as_millis());
+            }
+            println!();
         }
-        println!();
     }
     
     Ok(())
 }
 
+fn print_usage() {
+    println!("Usage: waldb-cli [dir] [command] [options]");
+    println!();
+    println!("  With no command, starts the interactive shell (reads piped stdin too).");
+    println!();
+    println!("  Commands:");
+    println!("    get <key>                     - Print value (exit 1 if not found), key/ for the subtree as JSON");
+    println!("    asof <seq> get <key>          - Print the value as of a past seq (exit 2 if too old to know)");
+    println!("    set <key> <value>             - Set a value (--replace to replace subtree)");
+    println!("    incr <key> [delta]            - Add delta (default 1) to an integer and print it");
+    println!("    mv <from> <to>                - Move a value and its subtree, replacing <to>");
+    println!("    cp <from> <to> [mode]         - Copy a value and its subtree; mode fail (default), skip or replace");
+    println!("    delete <key>                  - Delete a key");
+    println!("    clear-all --yes               - Delete every key in the store");
+    println!("    scan <prefix>                 - List keys with prefix");
+    println!("    range <start> <end>           - List keys in range");
+    println!("    pattern <pattern>             - List keys matching pattern");
+    println!("    export                        - Dump all entries as JSON lines");
+    println!("    snapshot [prefix]             - Dump entries as a snapshot file, checksummed per 10,000-entry chunk");
+    println!("    import <file> [chunk]         - Apply a snapshot file, from chunk on to resume a failed import");
+    println!("    verify-snapshot <file>        - Check a snapshot file's checksums (store not opened)");
+    println!("    stats                         - Show segment statistics");
+    println!("    stats --history <window>      - Samples stats.ring recorded in the last 30s, 15m, 1h, 2d... (store not opened)");
+    println!("    health                        - Run the storage self-test (exit 2 if any check fails)");
+    println!("    reads-report [prefix]         - Get a sample of keys, then show what each level's gets cost");
+    println!("    dump-seg <file>               - Describe a segment file record by record (store not opened)");
+    println!("    bench [mode]                  - Time writes, reads, scan or mixed on this store, all four by default");
+    println!("    warmup [prefix...]            - Read segment blocks ahead of use; warms the OS page cache from here");
+    println!();
+    println!("  Options:");
+    println!("    --json                        - Machine-readable output");
+    println!("    --quiet                       - Suppress banners and confirmations");
+    println!("    --limit N                     - Cap scan/range/pattern results");
+    println!("    --values, --hex               - dump-seg: print values, print keys and values as hex");
+    println!("    --include-system              - export: also dump the store's internal keys");
+    println!("    --yes                         - clear-all: confirm deleting everything");
+    println!("    --ops N, --threads T          - bench: operations per mode (default {}), and threads sharing them", BENCH_OPS);
+    println!("    --keyspace existing|synthetic - bench: read keys sampled from the store (default) or written for the run");
+    println!("    --prefix P                    - bench: sample existing keys from under P only");
+    println!("    --level N, --max-bytes N      - warmup: only level N's segments (repeatable), and a byte budget");
+    println!();
+    println!("  Exit codes: 0 ok, 1 not found, 2 error");
+}
+
 fn print_help() {
     println!("Available commands:");
     println!();
     println!("  Basic Operations:");
     println!("    set <key> <value> [replace]  - Set a key-value pair");
     println!("    get <key>                     - Get value by key (append / for subtree)");
+    println!("    asof <seq> get <key>          - Get the value a key had as of a past seq");
+    println!("    incr <key> [delta]            - Atomically add to an integer value");
+    println!("    mv <from> <to>                - Atomically move a subtree, replacing <to>");
+    println!("    cp <from> <to> [mode]         - Copy a subtree; existing keys fail (default), skip or replace");
     println!("    delete <key>                  - Delete a key");
     println!("    delete-subtree <prefix>       - Delete entire subtree");
+    println!("    clear-all --yes               - Delete every key in the store");
     println!();
     println!("  Pattern Matching:");
     println!("    pattern <pattern>             - Find keys matching pattern (* and ? wildcards)");
     println!("    delete-pattern <pattern>      - Delete keys matching pattern");
+    println!("    delete-prefix <prefix>        - Delete keys starting with prefix");
     println!();
     println!("  Range Queries:");
     println!("    range <start> <end> [limit]   - Get keys in range");
@@ -279,7 +960,11 @@ fn print_help() {
     println!("  Management:");
     println!("    flush                         - Flush memtable to disk");
     println!("    stats                         - Show segment statistics");
-    println!("    bench                         - Run performance benchmark");
+    println!("    health                        - Run the storage self-test, probe write included");
+    println!("    reads-report                  - Show what gets cost since open, by the level answering them");
+    println!("    dump-seg <file>               - Describe a segment file with its values");
+    println!("    bench [mode] [options]        - Time writes, reads, scan or mixed (see --help)");
+    println!("    warmup [prefix...] [options]  - Load segment blocks into the block cache (see --help)");
     println!("    load <prefix> [count]         - Load test data");
     println!("    tree <prefix>                 - Show tree structure");
     println!();
@@ -362,21 +1047,17 @@ fn print_tree(store: &Store, prefix: &str, indent: usize, max_depth: usize) {
     let pattern = if prefix.ends_with('/') {
         format!("{}*", prefix)
     } else {
-        format!("{}", prefix)
+        prefix.to_string()
     };
     
     if let Ok(results) = store.get_pattern(&pattern) {
-        let mut paths: Vec<_> = results.into_iter().map(|(k, v)| (k, v)).collect();
+        let mut paths: Vec<_> = results.into_iter().collect();
         paths.sort_by(|a, b| a.0.cmp(&b.0));
         
         let mut seen = std::collections::HashSet::new();
         
         for (key, value) in paths {
-            let relative = if key.starts_with(prefix) {
-                &key[prefix.len()..]
-            } else {
-                &key
-            };
+            let relative = key.strip_prefix(prefix).unwrap_or(&key);
             
             let parts: Vec<&str> = relative.split('/').filter(|s| !s.is_empty()).collect();
             if parts.is_empty() {
@@ -410,32 +1091,395 @@ fn print_tree(store: &Store, prefix: &str, indent: usize, max_depth: usize) {
     }
 }
 
-fn run_benchmark(store: &Store) {
-    println!("Running benchmark...");
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BenchMode {
+    Writes,
+    Reads,
+    Scan,
+    Mixed,  // Four reads to each write
+}
+
+#[derive(Debug)]
+struct BenchOptions {
+    modes: Vec<BenchMode>,
+    ops: usize,
+    threads: usize,
+    existing: bool,  // Read keys already in the store, rather than ones the run writes
+    prefix: String,
+}
+
+// Latencies in fixed buckets, eight per power of two of nanoseconds, so a percentile is
+// within an eighth of the true value without keeping every sample
+#[derive(Debug, Clone)]
+struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum_nanos: u128,
+    max_nanos: u64,
+}
+
+impl BenchMode {
+    fn name(self) -> &'static str {
+        match self {
+            BenchMode::Writes => "writes",
+            BenchMode::Reads => "reads",
+            BenchMode::Scan => "scan",
+            BenchMode::Mixed => "mixed",
+        }
+    }
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram { counts: vec![0; Self::bucket(u64::MAX) + 1], total: 0, sum_nanos: 0, max_nanos: 0 }
+    }
+    
+    // Below 8ns a bucket per nanosecond, then eight to each power of two
+    fn bucket(nanos: u64) -> usize {
+        if nanos < HISTOGRAM_SUB_BUCKETS {
+            return nanos as usize;
+        }
+        let exp = 63 - nanos.leading_zeros() as u64;
+        ((exp - 2) * HISTOGRAM_SUB_BUCKETS + ((nanos >> (exp - 3)) & (HISTOGRAM_SUB_BUCKETS - 1))) as usize
+    }
+    
+    // The largest value bucket holds
+    fn upper_bound(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < HISTOGRAM_SUB_BUCKETS {
+            return bucket;
+        }
+        let exp = bucket / HISTOGRAM_SUB_BUCKETS + 2;
+        let lower = (HISTOGRAM_SUB_BUCKETS + bucket % HISTOGRAM_SUB_BUCKETS) << (exp - 3);
+        lower + ((1 << (exp - 3)) - 1)
+    }
+    
+    fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[Self::bucket(nanos)] += 1;
+        self.total += 1;
+        self.sum_nanos += nanos as u128;
+        self.max_nanos = self.max_nanos.max(nanos);
+    }
+    
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.total += other.total;
+        self.sum_nanos += other.sum_nanos;
+        self.max_nanos = self.max_nanos.max(other.max_nanos);
+    }
+    
+    // The upper bound of the bucket holding the p-th percentile sample, never past the maximum
+    fn percentile(&self, p: f64) -> Duration {
+        let rank = ((p / 100.0 * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(Self::upper_bound(bucket).min(self.max_nanos));
+            }
+        }
+        Duration::ZERO
+    }
+    
+    fn mean(&self) -> Duration {
+        match self.total {
+            0 => Duration::ZERO,
+            total => Duration::from_nanos((self.sum_nanos / total as u128) as u64),
+        }
+    }
+}
+
+// xorshift64, enough to spread bench reads without a rand dependency
+struct BenchRng(u64);
+
+impl BenchRng {
+    fn new(thread: usize) -> Self {
+        let clock = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        BenchRng((clock ^ (thread as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15)) | 1)
+    }
     
-    // Write benchmark
-    let start = Instant::now();
-    for i in 0..1000 {
-        let key = format!("bench/key{:04}", i);
-        let value = format!("value{}", i);
-        let _ = store.set(&key, &value, false);
-    }
-    let write_elapsed = start.elapsed();
-    let write_ops = 1000.0 / write_elapsed.as_secs_f64();
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+// Prefixes to warm, with --level N for each level to take (all by default) and --max-bytes N
+// for the budget (the whole block cache by default)
+fn parse_warmup_args(args: &[&str]) -> Result<WarmupOptions, String> {
+    let mut options = WarmupOptions { max_bytes: u64::MAX, ..Default::default() };
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--level" => match args.next().and_then(|n| n.parse::<usize>().ok()).filter(|&n| n <= 3) {
+                Some(level) => options.levels.push(level),
+                None => return Err("--level needs a level from 0 to 3".to_string()),
+            },
+            "--max-bytes" => match args.next().and_then(|n| n.parse::<u64>().ok()) {
+                Some(bytes) => options.max_bytes = bytes,
+                None => return Err("--max-bytes needs a number".to_string()),
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown warmup argument: {}", arg)),
+            prefix => options.prefixes.push(prefix.to_string()),
+        }
+    }
+    Ok(options)
+}
+
+// Assigned, durable and flushed seqs. The last two are read first, so a write landing
+// meanwhile can't put them past the first.
+fn seq_info(store: &Store) -> waldb::Result<(u64, u64, u64)> {
+    let (durable, flushed) = (store.durable_seq(), store.flushed_seq());
+    Ok((store.seq()?, durable, flushed))
+}
+
+fn print_stats(store: &Store, (seq, durable, flushed): (u64, u64, u64), l0_stats: &L0Stats) {
+    let (l0, l1, l2) = store.segment_counts();
+    println!("Store {} (format {})", store.store_id(), store.format_version());
+    println!("Segment counts:");
+    println!("  L0: {} segments ({} pressure)", l0, l0_stats.pressure);
+    println!("  L1: {} segments", l1);
+    println!("  L2: {} segments", l2);
+    println!("Seqs:");
+    println!("  Assigned: {}", seq);
+    println!("  Durable:  {}", durable);
+    println!("  Flushed:  {}", flushed);
+}
+
+fn print_warmup(report: &WarmupReport) {
+    println!("✓ Loaded {} blocks, {} bytes ({} pinned){}", report.blocks_loaded, report.bytes_loaded, report.bytes_pinned,
+        if report.complete { "" } else { ", stopped at the byte budget" });
+}
+
+fn parse_bench_args(args: &[&str]) -> Result<BenchOptions, String> {
+    let mut options = BenchOptions { modes: Vec::new(), ops: BENCH_OPS, threads: 1, existing: true, prefix: String::new() };
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        let mut number = |flag: &str| args.next().and_then(|n| n.parse::<usize>().ok()).filter(|&n| n > 0)
+            .ok_or_else(|| format!("{} needs a positive number", flag));
+        match arg {
+            "writes" => options.modes.push(BenchMode::Writes),
+            "reads" => options.modes.push(BenchMode::Reads),
+            "scan" => options.modes.push(BenchMode::Scan),
+            "mixed" => options.modes.push(BenchMode::Mixed),
+            "--ops" => options.ops = number("--ops")?,
+            "--threads" => options.threads = number("--threads")?,
+            "--keyspace" => options.existing = match args.next() {
+                Some(&"existing") => true,
+                Some(&"synthetic") => false,
+                _ => return Err("--keyspace needs existing or synthetic".to_string()),
+            },
+            "--prefix" => match args.next() {
+                Some(prefix) => options.prefix = prefix.to_string(),
+                None => return Err("--prefix needs a key prefix".to_string()),
+            },
+            _ => return Err(format!("Unknown bench argument: {}", arg)),
+        }
+    }
+    if options.modes.is_empty() {
+        options.modes = vec![BenchMode::Writes, BenchMode::Reads, BenchMode::Scan, BenchMode::Mixed];
+    }
+    Ok(options)
+}
+
+// Run each mode against the open store and report it, returning the exit code. Writes go
+// under a subtree of BENCH_PREFIX named for this run, which is deleted afterwards whether or
+// not the run succeeded.
+fn run_benchmark(store: &Store, args: &[&str], opts: &CliOptions) -> i32 {
+    let options = match parse_bench_args(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("✗ {}", message);
+            return EXIT_ERROR;
+        }
+    };
+    let run = format!("{}/{}-{}", BENCH_PREFIX, process::id(), SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis()));
     
-    // Read benchmark
-    let start = Instant::now();
-    for i in 0..1000 {
-        let key = format!("bench/key{:04}", i);
-        let _ = store.get(&key);
-    }
-    let read_elapsed = start.elapsed();
-    let read_ops = 1000.0 / read_elapsed.as_secs_f64();
+    let result = bench_modes(store, &options, &run, opts);
+    let cleaned = store.delete_subtree(&run);
+    let code = match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("✗ Error: {}", e);
+            EXIT_ERROR
+        }
+    };
+    if let Err(e) = cleaned {
+        eprintln!("✗ Couldn't delete the bench keys under {}/: {}", run, e);
+        return EXIT_ERROR;
+    }
+    code
+}
+
+fn bench_modes(store: &Store, options: &BenchOptions, run: &str, opts: &CliOptions) -> waldb::Result<i32> {
+    let reads = options.modes.iter().any(|&mode| mode != BenchMode::Writes);
+    let keys = match (reads, options.existing) {
+        (false, _) => Vec::new(),
+        (true, true) => sample_keys(store, &options.prefix)?,
+        (true, false) => synthetic_keys(store, run)?,
+    };
+    if reads && keys.is_empty() {
+        eprintln!("✗ No keys under '{}' to read; try --keyspace synthetic", options.prefix);
+        return Ok(EXIT_NOT_FOUND);
+    }
+    let scan_end = match options.existing {
+        true => format!("{}{}", options.prefix, char::MAX),
+        false => format!("{}/data/{}", run, char::MAX),
+    };
+    
+    for &mode in &options.modes {
+        let cache_before = store.cache_stats();
+        let start = Instant::now();
+        let histograms = thread::scope(|scope| {
+            let workers: Vec<_> = (0..options.threads).map(|thread| {
+                let ops = options.ops / options.threads + usize::from(thread < options.ops % options.threads);
+                let (keys, scan_end) = (&keys, &scan_end);
+                scope.spawn(move || -> waldb::Result<Histogram> {
+                    let mut histogram = Histogram::new();
+                    let mut rng = BenchRng::new(thread);
+                    let value = "x".repeat(BENCH_VALUE_BYTES);
+                    for i in 0..ops {
+                        let write = match mode {
+                            BenchMode::Writes => true,
+                            BenchMode::Mixed => rng.below(5) == 0,
+                            BenchMode::Reads | BenchMode::Scan => false,
+                        };
+                        let op_start = Instant::now();
+                        if write {
+                            store.set(&format!("{}/{}/{}/{:08}", run, mode.name(), thread, i), &value, false)?;
+                        } else if mode == BenchMode::Scan {
+                            for entry in store.range_iter(&keys[rng.below(keys.len())], scan_end)?.take(BENCH_SCAN_LENGTH) {
+                                entry?;
+                            }
+                        } else {
+                            store.get(&keys[rng.below(keys.len())])?;
+                        }
+                        histogram.record(op_start.elapsed());
+                    }
+                    Ok(histogram)
+                })
+            }).collect();
+            workers.into_iter().map(|worker| worker.join().expect("Bench thread panicked")).collect::<waldb::Result<Vec<_>>>()
+        })?;
+        let elapsed = start.elapsed();
+        let cache_after = store.cache_stats();
+        
+        let mut latency = Histogram::new();
+        for histogram in &histograms {
+            latency.merge(histogram);
+        }
+        let cache = CacheStats { hits: cache_after.hits - cache_before.hits, misses: cache_after.misses - cache_before.misses, ..cache_after };
+        print_bench(mode, options, elapsed, &latency, cache, opts);
+    }
+    Ok(EXIT_OK)
+}
+
+// Up to BENCH_SAMPLE keys under prefix, picked evenly from the first BENCH_SAMPLE_WALK by
+// reservoir sampling, leaving out any an earlier bench run left behind
+fn sample_keys(store: &Store, prefix: &str) -> waldb::Result<Vec<String>> {
+    let mut rng = BenchRng::new(0);
+    let mut keys = Vec::new();
+    let bench = format!("{}/", BENCH_PREFIX);
+    let entries = store.scan_prefix_iter(prefix)?.filter(|entry| !matches!(entry, Ok((key, _)) if key.starts_with(&bench)));
+    for (seen, entry) in entries.take(BENCH_SAMPLE_WALK).enumerate() {
+        let (key, _) = entry?;
+        if keys.len() < BENCH_SAMPLE {
+            keys.push(key);
+        } else if let Some(slot) = keys.get_mut(rng.below(seen + 1)) {
+            *slot = key;
+        }
+    }
+    Ok(keys)
+}
+
+// BENCH_SAMPLE keys written under the run and flushed, so reads of them go to a segment
+fn synthetic_keys(store: &Store, run: &str) -> waldb::Result<Vec<String>> {
+    let keys: Vec<String> = (0..BENCH_SAMPLE).map(|i| format!("{}/data/{:08}", run, i)).collect();
+    let value = "x".repeat(BENCH_VALUE_BYTES);
+    for chunk in keys.chunks(1000) {
+        store.set_many(chunk.iter().map(|key| (key.clone(), value.clone())).collect(), None)?;
+    }
+    store.flush()?;
+    Ok(keys)
+}
+
+fn print_bench(mode: BenchMode, options: &BenchOptions, elapsed: Duration, latency: &Histogram, cache: CacheStats, opts: &CliOptions) {
+    let ops_per_sec = latency.total as f64 / elapsed.as_secs_f64();
+    let micros = |d: Duration| d.as_secs_f64() * 1e6;
+    if opts.json {
+        println!("{{\"mode\":\"{}\",\"ops\":{},\"threads\":{},\"seconds\":{:.6},\"ops_per_sec\":{:.1},\"latency_us\":{{\"p50\":{:.1},\"p95\":{:.1},\"p99\":{:.1},\"max\":{:.1},\"mean\":{:.1}}},\"cache\":{{\"hits\":{},\"misses\":{},\"hit_rate\":{:.4}}}}}",
+            mode.name(), latency.total, options.threads, elapsed.as_secs_f64(), ops_per_sec,
+            micros(latency.percentile(50.0)), micros(latency.percentile(95.0)), micros(latency.percentile(99.0)),
+            micros(Duration::from_nanos(latency.max_nanos)), micros(latency.mean()),
+            cache.hits, cache.misses, cache.hit_rate());
+        return;
+    }
+    println!("{}: {} ops on {} thread{} in {} = {:.0} ops/sec", mode.name(), latency.total, options.threads,
+        if options.threads == 1 { "" } else { "s" }, format_latency(elapsed), ops_per_sec);
+    println!("  latency p50 {}  p95 {}  p99 {}  max {}", format_latency(latency.percentile(50.0)),
+        format_latency(latency.percentile(95.0)), format_latency(latency.percentile(99.0)), format_latency(Duration::from_nanos(latency.max_nanos)));
+    println!("  block cache: {} hits, {} misses ({:.1}% hit rate)", cache.hits, cache.misses, cache.hit_rate() * 100.0);
+}
+
+fn format_latency(d: Duration) -> String {
+    match d.as_secs_f64() {
+        secs if secs >= 1.0 => format!("{:.2}s", secs),
+        secs if secs >= 1e-3 => format!("{:.2}ms", secs * 1e3),
+        secs => format!("{:.1}µs", secs * 1e6),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
     
-    // Clean up
-    let _ = store.delete_pattern("bench/*");
+    #[test]
+    fn histogram_buckets_are_contiguous() {
+        assert_eq!(Histogram::bucket(0), 0);
+        assert_eq!(Histogram::bucket(7), 7);
+        for bucket in 0..Histogram::bucket(u64::MAX) {
+            let upper = Histogram::upper_bound(bucket);
+            assert_eq!(Histogram::bucket(upper), bucket);
+            assert_eq!(Histogram::bucket(upper + 1), bucket + 1);
+        }
+        assert_eq!(Histogram::upper_bound(Histogram::bucket(u64::MAX)), u64::MAX);
+    }
     
-    println!("Results:");
-    println!("  Writes: 1000 ops in {:?} = {:.0} ops/sec", write_elapsed, write_ops);
-    println!("  Reads:  1000 ops in {:?} = {:.0} ops/sec", read_elapsed, read_ops);
-}
\ No newline at end of file
+    #[test]
+    fn histogram_percentiles_within_an_eighth() {
+        let mut histogram = Histogram::new();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        for (p, exact) in [(50.0, 500.0), (95.0, 950.0), (99.0, 990.0)] {
+            let reported = histogram.percentile(p).as_secs_f64() * 1e6;
+            assert!(reported >= exact && reported <= exact * 1.125, "p{} = {}µs", p, reported);
+        }
+        assert_eq!(histogram.percentile(100.0), Duration::from_micros(1000));
+        assert_eq!(histogram.mean(), Duration::from_nanos(500_500));
+        
+        // Merged threads count as one run, and the maximum caps the top bucket
+        let mut other = Histogram::new();
+        other.record(Duration::from_millis(5));
+        histogram.merge(&other);
+        assert_eq!(histogram.total, 1001);
+        assert_eq!(histogram.percentile(100.0), Duration::from_millis(5));
+        assert_eq!(Histogram::new().percentile(99.0), Duration::ZERO);
+    }
+    
+    #[test]
+    fn bench_args() {
+        let options = parse_bench_args(&["reads", "--ops", "50", "--keyspace", "synthetic", "--threads", "4"]).unwrap();
+        assert_eq!(options.modes, vec![BenchMode::Reads]);
+        assert_eq!((options.ops, options.threads, options.existing), (50, 4, false));
+        assert_eq!(parse_bench_args(&[]).unwrap().modes.len(), 4);
+        assert!(parse_bench_args(&["--ops", "0"]).is_err());
+        assert!(parse_bench_args(&["--keyspace", "cold"]).is_err());
+        assert!(parse_bench_args(&["sideways"]).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+// C API for embedding WalDB outside Rust, built with the capi feature:
+//   cargo rustc --release --lib --features capi --crate-type cdylib
+// The matching declarations are in bindings/c/waldb.h.
+//
+// Strings go in and come out as UTF-8 pointer and length pairs, never NUL-terminated except
+// for error messages. The library keeps no pointer the caller passes in, and the caller owns
+// two kinds of thing it gets back: a handle, freed with waldb_close, and a value from
+// waldb_get_owned, freed with waldb_free. Every function catches panics at the boundary and
+// reports them as WALDB_ERROR.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_void, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+use crate::{Result, Store, WalDbError};
+
+/// The call succeeded
+pub const WALDB_OK: i32 = 0;
+/// The key has no value
+pub const WALDB_NOT_FOUND: i32 = 1;
+/// The caller's buffer is too short; the length needed was written to value_len
+pub const WALDB_BUFFER_TOO_SMALL: i32 = 2;
+/// The call failed; waldb_last_error_message says why
+pub const WALDB_ERROR: i32 = -1;
+
+/// An open store, from waldb_open until waldb_close. May be used from several threads at
+/// once, but must not be closed while a call on it is still running.
+pub struct WaldbHandle {
+    store: Store,
+}
+
+/// Called by waldb_scan_prefix once per entry. The key and value are only valid during the
+/// call. Return 0 to go on, anything else to stop the scan there.
+pub type WaldbScanCallback = unsafe extern "C" fn(context: *mut c_void, key: *const c_char, key_len: usize,
+                                                  value: *const c_char, value_len: usize) -> i32;
+
+thread_local! {
+    // Each thread's last error per handle, the null handle standing for waldb_open
+    static LAST_ERRORS: RefCell<HashMap<usize, CString>> = RefCell::new(HashMap::new());
+}
+
+fn set_last_error(handle: *const WaldbHandle, message: String) {
+    // A message can't carry a NUL through a C string, so any inside it is dropped
+    let message = CString::new(message.replace('\0', "")).expect("NULs were removed");
+    LAST_ERRORS.with(|errors| errors.borrow_mut().insert(handle as usize, message));
+}
+
+fn clear_last_error(handle: *const WaldbHandle) {
+    LAST_ERRORS.with(|errors| errors.borrow_mut().remove(&(handle as usize)));
+}
+
+// Run f, turning an error or a panic into WALDB_ERROR with its message kept for the caller
+fn guard(handle: *const WaldbHandle, f: impl FnOnce() -> std::result::Result<i32, String>) -> i32 {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(code)) => code,
+        Ok(Err(message)) => {
+            set_last_error(handle, message);
+            WALDB_ERROR
+        }
+        Err(payload) => {
+            let detail = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            set_last_error(handle, format!("panic in waldb: {}", detail));
+            WALDB_ERROR
+        }
+    }
+}
+
+fn store_error(what: &str, error: WalDbError) -> String {
+    format!("{} failed: {}", what, error)
+}
+
+// The store behind handle, or an error for a null one
+unsafe fn store<'a>(handle: *const WaldbHandle) -> std::result::Result<&'a Store, String> {
+    match handle.as_ref() {
+        Some(handle) => Ok(&handle.store),
+        None => Err("handle is null".to_string()),
+    }
+}
+
+// A UTF-8 string the caller passed as pointer and length. Null is only allowed when empty.
+unsafe fn str_arg<'a>(name: &str, data: *const c_char, len: usize) -> std::result::Result<&'a str, String> {
+    if data.is_null() {
+        return match len {
+            0 => Ok(""),
+            _ => Err(format!("{} is null", name)),
+        };
+    }
+    let bytes = std::slice::from_raw_parts(data as *const u8, len);
+    std::str::from_utf8(bytes).map_err(|e| format!("{} is not UTF-8: {}", name, e))
+}
+
+// Write through an optional out pointer
+unsafe fn put<T>(out: *mut T, value: T) {
+    if !out.is_null() {
+        out.write(value);
+    }
+}
+
+fn seq_result(what: &str, result: Result<u64>, seq_out: *mut u64) -> std::result::Result<i32, String> {
+    let seq = result.map_err(|e| store_error(what, e))?;
+    unsafe { put(seq_out, seq) };
+    Ok(WALDB_OK)
+}
+
+/// Open the store in the directory at path, creating it if needed. Returns null on failure,
+/// with the reason in waldb_last_error_message(NULL).
+///
+/// # Safety
+/// path must point to path_len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn waldb_open(path: *const c_char, path_len: usize) -> *mut WaldbHandle {
+    let mut handle = ptr::null_mut();
+    guard(ptr::null(), || {
+        let path = str_arg("path", path, path_len)?;
+        let store = Store::open(Path::new(path)).map_err(|e| store_error("Open", e))?;
+        handle = Box::into_raw(Box::new(WaldbHandle { store }));
+        Ok(WALDB_OK)
+    });
+    if !handle.is_null() {
+        clear_last_error(ptr::null());
+    }
+    handle
+}
+
+/// Close a store from waldb_open, syncing what it buffered. Null is ignored.
+///
+/// # Safety
+/// handle must come from waldb_open, be closed only once, and not be in use on another
+/// thread.
+#[no_mangle]
+pub unsafe extern "C" fn waldb_close(handle: *mut WaldbHandle) {
+    if handle.is_null() {
+        return;
+    }
+    guard(ptr::null(), || {
+        drop(Box::from_raw(handle));
+        Ok(WALDB_OK)
+    });
+    LAST_ERRORS.with(|errors| errors.borrow_mut().remove(&(handle as usize)));
+}
+
+/// Set key to value, with the tree rules of Store::set. The write's seq goes to seq_out if
+/// it isn't null.
+///
+/// # Safety
+/// handle must be open; key and value must point to key_len and value_len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn waldb_set(handle: *const WaldbHandle, key: *const c_char, key_len: usize,
+                                   value: *const c_char, value_len: usize, seq_out: *mut u64) -> i32 {
+    guard(handle, || {
+        let store = store(handle)?;
+        let key = str_arg("key", key, key_len)?;
+        let value = str_arg("value", value, value_len)?;
+        seq_result("Set", store.set(key, value, false), seq_out)
+    })
+}
+
+/// Copy key's value into the caller's buffer. value_len gets the value's length; if that is
+/// more than buf_len nothing is copied and WALDB_BUFFER_TOO_SMALL is returned, so the
+/// caller can retry with a buffer that size. WALDB_NOT_FOUND if the key has no value.
+///
+/// # Safety
+/// handle must be open; key must point to key_len readable bytes, buf to buf_len writable
+/// ones (or be null with buf_len 0), and value_len must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn waldb_get(handle: *const WaldbHandle, key: *const c_char, key_len: usize,
+                                   buf: *mut c_char, buf_len: usize, value_len: *mut usize) -> i32 {
+    guard(handle, || {
+        let store = store(handle)?;
+        let key = str_arg("key", key, key_len)?;
+        if value_len.is_null() {
+            return Err("value_len is null".to_string());
+        }
+        let Some(value) = store.get(key).map_err(|e| store_error("Get", e))? else {
+            return Ok(WALDB_NOT_FOUND);
+        };
+        value_len.write(value.len());
+        if value.len() > buf_len {
+            return Ok(WALDB_BUFFER_TOO_SMALL);
+        }
+        if !value.is_empty() {
+            ptr::copy_nonoverlapping(value.as_ptr(), buf as *mut u8, value.len());
+        }
+        Ok(WALDB_OK)
+    })
+}
+
+/// Hand back key's value in a buffer the library allocates: value_out and value_len get its
+/// pointer and length, and the caller frees it with waldb_free(value, value_len). An empty
+/// value still comes with a pointer to free. WALDB_NOT_FOUND if the key has no value, with
+/// value_out set to null.
+///
+/// # Safety
+/// handle must be open; key must point to key_len readable bytes, value_out and value_len
+/// must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn waldb_get_owned(handle: *const WaldbHandle, key: *const c_char, key_len: usize,
+                                         value_out: *mut *mut c_char, value_len: *mut usize) -> i32 {
+    guard(handle, || {
+        let store = store(handle)?;
+        let key = str_arg("key", key, key_len)?;
+        if value_out.is_null() || value_len.is_null() {
+            return Err("value_out or value_len is null".to_string());
+        }
+        value_out.write(ptr::null_mut());
+        let Some(value) = store.get(key).map_err(|e| store_error("Get", e))? else {
+            return Ok(WALDB_NOT_FOUND);
+        };
+        let value: Box<[u8]> = value.into_bytes().into_boxed_slice();
+        value_len.write(value.len());
+        value_out.write(Box::into_raw(value) as *mut c_char);
+        Ok(WALDB_OK)
+    })
+}
+
+/// Free a value from waldb_get_owned, passing the length it came with. Null is ignored.
+///
+/// # Safety
+/// value and len must be exactly as waldb_get_owned returned them, freed only once.
+#[no_mangle]
+pub unsafe extern "C" fn waldb_free(value: *mut c_char, len: usize) {
+    if value.is_null() {
+        return;
+    }
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(value as *mut u8, len)));
+}
+
+/// Delete key's value, leaving what is under it. The seq goes to seq_out if it isn't null.
+///
+/// # Safety
+/// handle must be open; key must point to key_len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn waldb_delete(handle: *const WaldbHandle, key: *const c_char, key_len: usize, seq_out: *mut u64) -> i32 {
+    guard(handle, || {
+        let store = store(handle)?;
+        let key = str_arg("key", key, key_len)?;
+        seq_result("Delete", store.delete(key), seq_out)
+    })
+}
+
+/// Delete everything under prefix/ with one subtree tombstone. The seq goes to seq_out if it
+/// isn't null.
+///
+/// # Safety
+/// handle must be open; prefix must point to prefix_len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn waldb_delete_subtree(handle: *const WaldbHandle, prefix: *const c_char, prefix_len: usize,
+                                              seq_out: *mut u64) -> i32 {
+    guard(handle, || {
+        let store = store(handle)?;
+        let prefix = str_arg("prefix", prefix, prefix_len)?;
+        seq_result("Delete subtree", store.delete_subtree(prefix), seq_out)
+    })
+}
+
+/// Call callback with context for each live entry under prefix in key order, at most limit
+/// of them (SIZE_MAX for all), until it returns nonzero. The entries are read as the scan
+/// goes, from the store as it was when it started, so the callback may write to the store.
+/// How many entries it was called with goes to count_out if that isn't null.
+///
+/// # Safety
+/// handle must be open; prefix must point to prefix_len readable bytes; callback must be
+/// safe to call with context.
+#[no_mangle]
+pub unsafe extern "C" fn waldb_scan_prefix(handle: *const WaldbHandle, prefix: *const c_char, prefix_len: usize, limit: usize,
+                                           callback: Option<WaldbScanCallback>, context: *mut c_void, count_out: *mut usize) -> i32 {
+    guard(handle, || {
+        let store = store(handle)?;
+        let prefix = str_arg("prefix", prefix, prefix_len)?;
+        let Some(callback) = callback else {
+            return Err("callback is null".to_string());
+        };
+        let mut count = 0;
+        for entry in store.scan_prefix_iter(prefix).map_err(|e| store_error("Scan", e))?.take(limit) {
+            let (key, value) = entry.map_err(|e| store_error("Scan", e))?;
+            count += 1;
+            if callback(context, key.as_ptr() as *const c_char, key.len(), value.as_ptr() as *const c_char, value.len()) != 0 {
+                break;
+            }
+        }
+        put(count_out, count);
+        Ok(WALDB_OK)
+    })
+}
+
+/// The message of the last call on handle that returned WALDB_ERROR on this thread, or of
+/// the last failed waldb_open for a null handle. Its length, without the NUL that ends it,
+/// goes to len_out if that isn't null. Null if there is none. The library owns the string:
+/// it stays valid on this thread until the next failure on the same handle or its close.
+///
+/// # Safety
+/// len_out must be writable or null.
+#[no_mangle]
+pub unsafe extern "C" fn waldb_last_error_message(handle: *const WaldbHandle, len_out: *mut usize) -> *const c_char {
+    let message = LAST_ERRORS.with(|errors| errors.borrow().get(&(handle as usize)).map(|m| (m.as_ptr(), m.as_bytes().len())));
+    match message {
+        Some((message, len)) => {
+            put(len_out, len);
+            message
+        }
+        None => {
+            put(len_out, 0);
+            ptr::null()
+        }
+    }
+}